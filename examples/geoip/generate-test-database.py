@@ -0,0 +1,88 @@
+#!/usr/bin/env python3
+"""Writes test-country.mmdb, a tiny IPv4 database in the MaxMind DB format,
+which contains the countries of a few networks for the GeoIP tests:
+
+- 2.0.0.0/8: DE (EU)
+- 8.0.0.0/8: US (NA)
+"""
+import ipaddress
+import struct
+import time
+
+NETWORKS = {
+    "2.0.0.0/8": {"continent": {"code": "EU"}, "country": {"iso_code": "DE"}},
+    "8.0.0.0/8": {"continent": {"code": "NA"}, "country": {"iso_code": "US"}},
+}
+
+
+def control(type_, size):
+    if type_ <= 7:
+        first, extended = type_ << 5, b""
+    else:
+        first, extended = 0, bytes([type_ - 7])
+    assert size < 29
+    return bytes([first | size]) + extended
+
+
+def encode(value):
+    if isinstance(value, dict):
+        return control(7, len(value)) + b"".join(encode(k) + encode(v) for k, v in value.items())
+    if isinstance(value, list):
+        return control(11, len(value)) + b"".join(encode(it) for it in value)
+    if isinstance(value, str):
+        data = value.encode()
+        return control(2, len(data)) + data
+    if isinstance(value, int):
+        data = value.to_bytes((value.bit_length() + 7) // 8, "big")
+        type_ = 5 if value < 2**16 else 6 if value < 2**32 else 9
+        return control(type_, len(data)) + data
+    raise TypeError(value)
+
+
+# Each node is a pair of records, a record is the index of another node or None
+nodes = [[None, None]]
+leaves = {}
+data = b""
+for network, record in NETWORKS.items():
+    network = ipaddress.ip_network(network)
+    leaves[network] = len(data)
+    data += encode(record)
+    bits = int(network.network_address)
+    node = 0
+    for depth in range(network.prefixlen):
+        bit = (bits >> (31 - depth)) & 1
+        if depth == network.prefixlen - 1:
+            nodes[node][bit] = ("data", leaves[network])
+        else:
+            if nodes[node][bit] is None:
+                nodes.append([None, None])
+                nodes[node][bit] = len(nodes) - 1
+            node = nodes[node][bit]
+
+node_count = len(nodes)
+
+
+def record_value(record):
+    if record is None:
+        return node_count
+    if isinstance(record, tuple):
+        return node_count + 16 + record[1]
+    return record
+
+
+tree = b"".join(
+    b"".join(struct.pack(">I", record_value(record))[1:] for record in node) for node in nodes
+)
+metadata = {
+    "binary_format_major_version": 2,
+    "binary_format_minor_version": 0,
+    "build_epoch": int(time.time()),
+    "database_type": "GeoLite2-Country",
+    "description": {"en": "GeoIP test database"},
+    "ip_version": 4,
+    "languages": ["en"],
+    "node_count": node_count,
+    "record_size": 24,
+}
+with open("test-country.mmdb", "wb") as file:
+    file.write(tree + bytes(16) + data + b"\xab\xcd\xefMaxMind.com" + encode(metadata))
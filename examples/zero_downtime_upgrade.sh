@@ -0,0 +1,70 @@
+#!/usr/bin/env bash
+# Replaces a running arlb instance with a new one while requests are sent
+# continuously and fails if any request fails during the swap.
+#
+# Usage: ./zero_downtime_upgrade.sh [path to arlb binary]
+set -euo pipefail
+
+ARLB=${1:-target/release/another-rust-load-balancer}
+HTTP_PORT=18080
+BACKEND_PORT=18081
+WORK_DIR=$(mktemp -d)
+trap 'kill $(jobs -p) 2>/dev/null || true; rm -rf "$WORK_DIR"' EXIT
+
+cat > "$WORK_DIR/config.toml" <<EOF
+http_address = "127.0.0.1:$HTTP_PORT"
+https_address = "127.0.0.1:18443"
+reuse_port = true
+drain_timeout_sec = 10
+
+[[backend_pools]]
+matcher = "Host('localhost:$HTTP_PORT')"
+addresses = ["127.0.0.1:$BACKEND_PORT"]
+schemes = ["HTTP"]
+strategy = { RoundRobin = {} }
+EOF
+
+wait_for_port() {
+  for _ in $(seq 50); do
+    curl -s -o /dev/null "http://localhost:$1/" && return 0
+    sleep 0.1
+  done
+  echo "Port $1 did not open" >&2
+  exit 1
+}
+
+python3 -m http.server --bind 127.0.0.1 "$BACKEND_PORT" --directory "$WORK_DIR" > /dev/null 2>&1 &
+wait_for_port "$BACKEND_PORT"
+
+"$ARLB" --config "$WORK_DIR/config.toml" > "$WORK_DIR/old.log" 2>&1 &
+OLD_PID=$!
+wait_for_port "$HTTP_PORT"
+
+# Send requests until the file "stop" exists and count failed ones
+(
+  failures=0
+  requests=0
+  while [ ! -e "$WORK_DIR/stop" ]; do
+    requests=$((requests + 1))
+    curl -sf -o /dev/null "http://localhost:$HTTP_PORT/config.toml" || failures=$((failures + 1))
+  done
+  echo "$requests $failures" > "$WORK_DIR/result"
+) &
+CLIENT_PID=$!
+sleep 1
+
+echo "Starting new instance"
+"$ARLB" --config "$WORK_DIR/config.toml" > "$WORK_DIR/new.log" 2>&1 &
+sleep 1
+
+echo "Draining old instance"
+kill -USR2 "$OLD_PID"
+wait "$OLD_PID"
+echo "Old instance exited"
+sleep 1
+
+touch "$WORK_DIR/stop"
+wait "$CLIENT_PID"
+read -r requests failures < "$WORK_DIR/result"
+echo "$requests requests, $failures failed"
+[ "$failures" -eq 0 ]
@@ -1,16 +1,48 @@
 use crate::error_response::{bad_request, not_found};
+#[cfg(feature = "acme")]
 use acme_lib::order::NewOrder;
+#[cfg(feature = "acme")]
 use acme_lib::persist::FilePersist;
-use acme_lib::{create_rsa_key, Certificate, Directory, DirectoryUrl, Error};
+#[cfg(feature = "acme")]
+use acme_lib::{create_rsa_key, Account, Certificate, Directory, DirectoryUrl, Error};
 use hyper::{Body, Request, Response, StatusCode};
-use std::{
-  path::Path,
-  sync::{Arc, Mutex},
-};
+#[cfg(feature = "acme")]
+use log::warn;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "acme")]
+use std::{path::Path, time::Duration};
+#[cfg(feature = "acme")]
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+#[cfg(feature = "acme")]
 use tokio_util::either::Either;
+#[cfg(feature = "acme")]
 use tokio_util::either::Either::{Left, Right};
 
+/// ACME certificates are renewed once they are valid for this many days or
+/// less.
+#[cfg(feature = "acme")]
+pub const RENEWAL_DAYS: i64 = 30;
+
+/// The delay before a failed renewal is retried.
+#[cfg(feature = "acme")]
+const RENEWAL_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Returns how long a certificate, which is valid for `valid_days_left`, can be
+/// used until it has to be renewed.
+#[cfg(feature = "acme")]
+pub fn renewal_delay(valid_days_left: i64) -> Duration {
+  if needs_renewal(valid_days_left) {
+    RENEWAL_RETRY_DELAY
+  } else {
+    Duration::from_secs((valid_days_left - RENEWAL_DAYS) as u64 * 24 * 60 * 60)
+  }
+}
+
+#[cfg(feature = "acme")]
+fn needs_renewal(valid_days_left: i64) -> bool {
+  valid_days_left <= RENEWAL_DAYS
+}
+
 struct OpenChallenge {
   token: String,
   proof: String,
@@ -20,6 +52,7 @@ pub struct AcmeHandler {
   challenges: Arc<Mutex<Vec<OpenChallenge>>>,
 }
 
+#[cfg(feature = "acme")]
 type ChallengeSender = UnboundedSender<Either<(String, String), Result<Certificate, Error>>>;
 
 impl AcmeHandler {
@@ -29,6 +62,7 @@ impl AcmeHandler {
     }
   }
 
+  #[cfg_attr(not(feature = "acme"), allow(dead_code))]
   fn add_challenge(&self, token: &str, proof: String) {
     let challenge = OpenChallenge {
       token: token.to_string(),
@@ -43,6 +77,7 @@ impl AcmeHandler {
     challenges.iter().find(|c| c.token == token).map(|c| c.proof.clone())
   }
 
+  #[cfg_attr(not(feature = "acme"), allow(dead_code))]
   fn remove_challenge(&self, token: &str) {
     let mut challenges = self.challenges.lock().unwrap();
     challenges
@@ -51,6 +86,7 @@ impl AcmeHandler {
       .map(|i| challenges.remove(i));
   }
 
+  #[cfg(feature = "acme")]
   fn start_challenge_handler(ord_new: NewOrder<FilePersist>, cs: ChallengeSender) {
     // TODO maybe add own async implementation of the acme lib so we can use an async fn instead of a thread
     fn generate_and_validate_challenge(
@@ -83,8 +119,13 @@ impl AcmeHandler {
     });
   }
 
+  /// Returns the certificate for `primary_name` from `persist_dir`, ordering a
+  /// new one if it doesn't exist or [needs to be renewed](RENEWAL_DAYS). If the
+  /// renewal fails, the existing certificate is used as long as it is valid.
+  #[cfg(feature = "acme")]
   pub async fn initiate_challenge<P: AsRef<Path>>(
     &self,
+    directory_url: Option<&str>,
     staging: bool,
     persist_dir: P,
     email: &str,
@@ -92,21 +133,36 @@ impl AcmeHandler {
   ) -> Result<Certificate, Error> {
     std::fs::create_dir_all(&persist_dir).map_err(|e| Error::Other(e.to_string()))?;
     let persist = FilePersist::new(&persist_dir);
-    let dir_url = if staging {
-      DirectoryUrl::LetsEncryptStaging
-    } else {
-      DirectoryUrl::LetsEncrypt
+    let dir_url = match directory_url {
+      Some(directory_url) => DirectoryUrl::Other(directory_url),
+      None if staging => DirectoryUrl::LetsEncryptStaging,
+      None => DirectoryUrl::LetsEncrypt,
     };
     let dir = Directory::from_url(persist, dir_url)?;
     let acc = dir.account(email)?;
 
-    let existing_cert = acc.certificate(primary_name)?;
-    if let Some(cert) = existing_cert {
-      if cert.valid_days_left() > 0 {
-        return Ok(cert);
-      }
+    match acc.certificate(primary_name)? {
+      Some(cert) if !needs_renewal(cert.valid_days_left()) => Ok(cert),
+      existing_cert => match self.order_certificate(&acc, primary_name).await {
+        Ok(cert) => Ok(cert),
+        Err(e) => match existing_cert {
+          Some(cert) if cert.valid_days_left() > 0 => {
+            warn!(
+              "Could not renew ACME certificate for '{}', keeping the existing certificate, which is valid for {} days, due to: {}",
+              primary_name,
+              cert.valid_days_left(),
+              e
+            );
+            Ok(cert)
+          }
+          _ => Err(e),
+        },
+      },
     }
+  }
 
+  #[cfg(feature = "acme")]
+  async fn order_certificate(&self, acc: &Account<FilePersist>, primary_name: &str) -> Result<Certificate, Error> {
     let ord_new = acc.new_order(primary_name, &[])?;
     let (cs, mut cr) = unbounded_channel();
     AcmeHandler::start_challenge_handler(ord_new, cs);
@@ -152,6 +208,22 @@ mod tests {
   use super::*;
   use hyper::body;
 
+  #[cfg(feature = "acme")]
+  #[test]
+  fn test_renewal_delay_of_valid_certificate() {
+    // Let's Encrypt certificates are valid for 90 days
+    assert_eq!(renewal_delay(90), Duration::from_secs(60 * 24 * 60 * 60));
+    assert_eq!(renewal_delay(RENEWAL_DAYS + 1), Duration::from_secs(24 * 60 * 60));
+  }
+
+  #[cfg(feature = "acme")]
+  #[test]
+  fn test_renewal_delay_retries_due_certificate() {
+    assert_eq!(renewal_delay(RENEWAL_DAYS), RENEWAL_RETRY_DELAY);
+    assert_eq!(renewal_delay(0), RENEWAL_RETRY_DELAY);
+    assert_eq!(renewal_delay(-5), RENEWAL_RETRY_DELAY);
+  }
+
   #[test]
   fn test_is_challenge_only_matches_acme_challenge() {
     let valid_req = Request::builder()
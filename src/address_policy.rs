@@ -0,0 +1,270 @@
+use crate::{http_client::backend_uri, metrics::METRICS};
+use hyper::http::uri::PathAndQuery;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::{
+  collections::HashMap,
+  fmt,
+  net::{IpAddr, SocketAddr},
+  str::FromStr,
+  sync::Mutex,
+};
+
+/// A range of IP addresses in CIDR notation, like `10.0.0.0/8`. A single
+/// address is a range of its own.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IpRange {
+  network: IpAddr,
+  prefix_len: u8,
+}
+
+impl IpRange {
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    match (self.network, canonical(ip)) {
+      (IpAddr::V4(network), IpAddr::V4(ip)) => {
+        same_prefix(u32::from(network).into(), u32::from(ip).into(), 32 - self.prefix_len)
+      }
+      (IpAddr::V6(network), IpAddr::V6(ip)) => same_prefix(network.into(), ip.into(), 128 - self.prefix_len),
+      _ => false,
+    }
+  }
+}
+
+fn same_prefix(network: u128, ip: u128, host_bits: u8) -> bool {
+  let prefix = |it: u128| it.checked_shr(host_bits as u32).unwrap_or(0);
+  prefix(network) == prefix(ip)
+}
+
+/// IPv4-mapped addresses like `::ffff:127.0.0.1` are treated like the IPv4
+/// address, so they can not bypass the ranges of IPv4 addresses.
+fn canonical(ip: IpAddr) -> IpAddr {
+  match ip {
+    IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(v6.to_ipv4().unwrap()),
+    ip => ip,
+  }
+}
+
+impl FromStr for IpRange {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("Invalid network '{}', expected CIDR notation like '10.0.0.0/8'", s);
+    let (network, prefix_len) = match s.split_once('/') {
+      Some((network, prefix_len)) => (network, Some(prefix_len)),
+      None => (s, None),
+    };
+    let network = network.parse::<IpAddr>().map_err(|_| invalid())?;
+    let bits = if network.is_ipv4() { 32 } else { 128 };
+    let prefix_len = match prefix_len {
+      Some(prefix_len) => prefix_len
+        .parse::<u8>()
+        .ok()
+        .filter(|it| *it <= bits)
+        .ok_or_else(invalid)?,
+      None => bits,
+    };
+    Ok(IpRange { network, prefix_len })
+  }
+}
+
+impl fmt::Display for IpRange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.network, self.prefix_len)
+  }
+}
+
+/// The addresses, which backend servers may not resolve to by default: the
+/// loopback interface, link-local addresses and the metadata services of
+/// cloud providers (`169.254.169.254` is link-local as well).
+static DENIED: Lazy<Vec<IpRange>> = Lazy::new(|| {
+  [
+    "0.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "100.100.100.200/32",
+    "::/128",
+    "::1/128",
+    "fe80::/10",
+    "fd00:ec2::254/128",
+  ]
+  .iter()
+  .map(|it| it.parse().unwrap())
+  .collect()
+});
+
+/// Which addresses backend servers, whose addresses are not configured
+/// literally, may have: all but the denied ones, unless they belong to one of
+/// the `allowed` ranges.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AddressPolicy {
+  allowed: Vec<IpRange>,
+}
+
+impl AddressPolicy {
+  pub fn new(allowed: Vec<IpRange>) -> AddressPolicy {
+    AddressPolicy { allowed }
+  }
+
+  pub fn permits(&self, ip: IpAddr) -> bool {
+    self.allowed.iter().any(|it| it.contains(ip)) || !DENIED.iter().any(|it| it.contains(ip))
+  }
+}
+
+/// The IP address of a backend server `address`, unless it has a host name (or
+/// is a unix domain socket or builtin backend server).
+pub fn literal_ip(address: &str) -> Option<IpAddr> {
+  let uri = backend_uri(address, PathAndQuery::from_static("/")).ok()?;
+  let host = uri.host()?;
+  host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+#[derive(Debug, Default)]
+struct Resolved {
+  permitted: Vec<SocketAddr>,
+  forbidden: Vec<SocketAddr>,
+}
+
+/// Applies the [`AddressPolicy`] of a backend pool to the addresses, which the
+/// host names of its backend servers resolve to, whenever they are resolved.
+#[derive(Debug)]
+pub struct AddressFilter {
+  pool: String,
+  policy: AddressPolicy,
+  /// The last resolution of each backend server.
+  resolved: Mutex<HashMap<String, Resolved>>,
+}
+
+impl AddressFilter {
+  pub fn new(pool: String, policy: AddressPolicy) -> AddressFilter {
+    AddressFilter {
+      pool,
+      policy,
+      resolved: Mutex::default(),
+    }
+  }
+
+  pub fn policy(&self) -> &AddressPolicy {
+    &self.policy
+  }
+
+  /// Skips the forbidden `addresses`, which the backend server `address`
+  /// resolved to. If none are left, the addresses permitted last time are
+  /// kept, so a bad DNS answer does not take the backend server down.
+  pub fn filter(&self, address: &str, addresses: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, String> {
+    let (permitted, forbidden): (Vec<_>, Vec<_>) = addresses.into_iter().partition(|it| self.policy.permits(it.ip()));
+    let mut resolved = self.resolved.lock().unwrap();
+    let last = resolved.entry(address.to_string()).or_default();
+    if !forbidden.is_empty() {
+      METRICS.add(
+        "arlb_forbidden_backend_addresses_total",
+        &[("pool", &self.pool)],
+        forbidden.len() as u64,
+      );
+      // Only changed answers are logged, not each connection
+      if forbidden != last.forbidden {
+        warn!(
+          "Skipped the addresses {:?} of '{}' in pool '{}', which are not allowed",
+          forbidden, address, self.pool
+        );
+      }
+    }
+    last.forbidden = forbidden;
+    if permitted.is_empty() {
+      return if last.permitted.is_empty() {
+        Err(format!(
+          "'{}' only resolves to addresses, which are not allowed",
+          address
+        ))
+      } else {
+        Ok(last.permitted.clone())
+      };
+    }
+    last.permitted = permitted.clone();
+    Ok(permitted)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn addresses(addresses: &[&str]) -> Vec<SocketAddr> {
+    addresses.iter().map(|it| it.parse().unwrap()).collect()
+  }
+
+  #[test]
+  fn test_ip_range() {
+    let range: IpRange = "10.1.0.0/16".parse().unwrap();
+    assert!(range.contains("10.1.2.3".parse().unwrap()));
+    assert!(range.contains("::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!range.contains("10.2.0.1".parse().unwrap()));
+    assert!(!range.contains("::1".parse().unwrap()));
+    let single: IpRange = "fd00::1".parse().unwrap();
+    assert_eq!(single.to_string(), "fd00::1/128");
+    assert!(single.contains("fd00::1".parse().unwrap()));
+    assert!(!single.contains("fd00::2".parse().unwrap()));
+    assert!("0.0.0.0/0"
+      .parse::<IpRange>()
+      .unwrap()
+      .contains("203.0.113.7".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+    assert!("backend/8".parse::<IpRange>().is_err());
+  }
+
+  #[test]
+  fn test_default_policy_denies_loopback_link_local_and_metadata_addresses() {
+    let policy = AddressPolicy::default();
+    for denied in &[
+      "127.0.0.1",
+      "169.254.169.254",
+      "fd00:ec2::254",
+      "::1",
+      "::ffff:127.0.0.1",
+      "0.0.0.0",
+    ] {
+      assert!(!policy.permits(denied.parse().unwrap()), "{}", denied);
+    }
+    for permitted in &["10.0.0.1", "192.168.1.1", "203.0.113.7", "2001:db8::1"] {
+      assert!(policy.permits(permitted.parse().unwrap()), "{}", permitted);
+    }
+    let allowed = AddressPolicy::new(vec!["127.0.0.0/8".parse().unwrap()]);
+    assert!(allowed.permits("127.0.0.1".parse().unwrap()));
+    assert!(!allowed.permits("169.254.169.254".parse().unwrap()));
+  }
+
+  #[test]
+  fn test_filter_skips_forbidden_resolved_addresses() {
+    // given: the answers of a resolver for the backend server
+    let filter = AddressFilter::new("filtered".into(), AddressPolicy::default());
+    let mixed = addresses(&["10.0.0.1:80", "169.254.169.254:80", "10.0.0.2:80", "127.0.0.1:80"]);
+    let only_forbidden = addresses(&["169.254.169.254:80"]);
+    let changed = addresses(&["10.0.0.3:80"]);
+
+    // when:
+    let first = filter.filter("backend:80", mixed);
+    let hijacked = filter.filter("backend:80", only_forbidden.clone());
+    let refreshed = filter.filter("backend:80", changed);
+    let other = filter.filter("other:80", only_forbidden);
+
+    // then: a refresh, which leaves no address, keeps the previous ones
+    assert_eq!(first, Ok(addresses(&["10.0.0.1:80", "10.0.0.2:80"])));
+    assert_eq!(hijacked, Ok(addresses(&["10.0.0.1:80", "10.0.0.2:80"])));
+    assert_eq!(refreshed, Ok(addresses(&["10.0.0.3:80"])));
+    assert_eq!(
+      other,
+      Err("'other:80' only resolves to addresses, which are not allowed".to_string())
+    );
+    assert_eq!(
+      METRICS.counter("arlb_forbidden_backend_addresses_total", &[("pool", "filtered")]),
+      4
+    );
+  }
+
+  #[test]
+  fn test_literal_ip() {
+    assert_eq!(literal_ip("127.0.0.1:8080"), Some("127.0.0.1".parse().unwrap()));
+    assert_eq!(literal_ip("[::1]:8080"), Some("::1".parse().unwrap()));
+    assert_eq!(literal_ip("backend:8080"), None);
+    assert_eq!(literal_ip("unix:/run/backend.sock"), None);
+  }
+}
@@ -0,0 +1,256 @@
+use crate::{configuration::RuntimeConfig, server::BackendPool};
+use arc_swap::ArcSwap;
+use hyper::{header::CONTENT_TYPE, Body, Response, StatusCode};
+use log::info;
+use once_cell::sync::Lazy;
+use serde_json::json;
+use std::{
+  collections::HashMap,
+  net::IpAddr,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+/// How long a soft drain lasts by default, before it turns into a hard drain.
+pub const DEFAULT_MAX_DRAIN: Duration = Duration::from_secs(300);
+
+/// Clients, which sent no request within this time, are no longer counted as
+/// pinned sessions of a draining backend server.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrainMode {
+  /// The backend server receives no new sessions, but clients pinned to it
+  /// (like by a sticky cookie) keep using it until the drain ends.
+  Soft,
+  /// The backend server receives no requests at all.
+  Hard,
+}
+
+impl DrainMode {
+  fn name(self) -> &'static str {
+    match self {
+      DrainMode::Soft => "soft",
+      DrainMode::Hard => "hard",
+    }
+  }
+}
+
+/// A backend server, which was drained through the admin API or by a
+/// maintenance window.
+#[derive(Debug)]
+struct Drain {
+  mode: DrainMode,
+  /// When a soft drain turns into a hard drain.
+  until: Instant,
+  /// When each pinned client sent its last request.
+  sessions: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Drain {
+  fn mode(&self, now: Instant) -> DrainMode {
+    if self.mode == DrainMode::Soft && now < self.until {
+      DrainMode::Soft
+    } else {
+      DrainMode::Hard
+    }
+  }
+
+  fn pinned_sessions(&self, now: Instant) -> usize {
+    let mut sessions = self.sessions.lock().unwrap();
+    sessions.retain(|_, last_seen| now.duration_since(*last_seen) < SESSION_IDLE_TIMEOUT);
+    sessions.len()
+  }
+}
+
+type Drains = HashMap<(String, String), Arc<Drain>>;
+
+/// The drained backend servers by pool name and address. They are read on
+/// every request and rarely change, so they are replaced as a whole. Drains
+/// survive reloads of the configuration, but not restarts.
+static DRAINS: Lazy<ArcSwap<Drains>> = Lazy::new(Default::default);
+
+/// Serializes changes of [`DRAINS`].
+static CHANGES: Lazy<Mutex<()>> = Lazy::new(Default::default);
+
+/// How the backend server `address` of the pool `pool` is drained, if it is.
+pub fn mode(pool: &str, address: &str) -> Option<DrainMode> {
+  let drains = DRAINS.load();
+  if drains.is_empty() {
+    return None;
+  }
+  drains
+    .get(&(pool.to_string(), address.to_string()))
+    .map(|drain| drain.mode(Instant::now()))
+}
+
+/// Counts the client `ip` as a session, which is pinned to the soft draining
+/// backend server `address`.
+pub fn record_pinned_session(pool: &str, address: &str, ip: IpAddr) {
+  if let Some(drain) = DRAINS.load().get(&(pool.to_string(), address.to_string())) {
+    drain.sessions.lock().unwrap().insert(ip, Instant::now());
+  }
+}
+
+fn change(f: impl FnOnce(&mut Drains)) {
+  let _change = CHANGES.lock().unwrap();
+  let mut drains = Drains::clone(&DRAINS.load());
+  f(&mut drains);
+  DRAINS.store(Arc::new(drains));
+}
+
+/// Drains the backend server `address` of the pool `pool`, replacing an
+/// earlier drain of it.
+pub fn start(pool: &str, address: &str, mode: DrainMode, max_drain: Duration) {
+  start_drain(pool, address, mode, max_drain);
+}
+
+fn start_drain(pool: &str, address: &str, mode: DrainMode, max_drain: Duration) -> Arc<Drain> {
+  let drain = Arc::new(Drain {
+    mode,
+    until: Instant::now() + max_drain,
+    sessions: Mutex::default(),
+  });
+  change(|drains| {
+    drains.insert((pool.to_string(), address.to_string()), drain.clone());
+  });
+  info!(
+    "Draining backend server {} of pool {} ({} drain)",
+    address,
+    pool,
+    mode.name()
+  );
+  drain
+}
+
+/// Stops draining the backend server `address` of the pool `pool`. Returns
+/// whether it was drained.
+pub fn stop(pool: &str, address: &str) -> bool {
+  let key = (pool.to_string(), address.to_string());
+  if !DRAINS.load().contains_key(&key) {
+    return false;
+  }
+  change(|drains| {
+    drains.remove(&key);
+  });
+  info!("Stopped draining backend server {} of pool {}", address, pool);
+  true
+}
+
+fn json_response(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, "application/json")
+    .body(Body::from(value.to_string()))
+    .unwrap()
+}
+
+fn describe(address: &str, drain: &Drain, now: Instant) -> serde_json::Value {
+  let mode = drain.mode(now);
+  json!({
+    "address": address,
+    "mode": mode.name(),
+    "remaining_sec": match mode {
+      DrainMode::Soft => Some(drain.until.duration_since(now).as_secs()),
+      DrainMode::Hard => None,
+    },
+    "pinned_sessions": match mode {
+      DrainMode::Soft => drain.pinned_sessions(now),
+      DrainMode::Hard => 0,
+    },
+  })
+}
+
+fn find_pool<'c>(config: &'c RuntimeConfig, name: &str) -> Option<&'c BackendPool> {
+  config
+    .shared_data
+    .backend_pools
+    .iter()
+    .find(|it| it.name == name)
+    .map(|it| it.as_ref())
+}
+
+type DrainResult = Result<Response<Body>, (StatusCode, String)>;
+
+/// Handles `GET /pools/{pool}/drains`.
+pub fn list(pool: &str) -> Response<Body> {
+  let now = Instant::now();
+  let drains = DRAINS
+    .load()
+    .iter()
+    .filter(|((drained_pool, _), _)| drained_pool == pool)
+    .map(|((_, address), drain)| describe(address, drain, now))
+    .collect::<Vec<_>>();
+  json_response(StatusCode::OK, json!(drains))
+}
+
+/// Handles `PUT /pools/{pool}/drains/{address}`, which accepts the query
+/// parameters `mode` (`soft` or `hard`) and `max_drain_sec`.
+pub fn drain(config: &RuntimeConfig, pool_name: &str, address: &str, query: &str) -> DrainResult {
+  let mut mode = DrainMode::Soft;
+  let mut max_drain = DEFAULT_MAX_DRAIN;
+  for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+    match (key.as_ref(), value.as_ref()) {
+      ("mode", "soft") => mode = DrainMode::Soft,
+      ("mode", "hard") => mode = DrainMode::Hard,
+      ("max_drain_sec", value) => match value.parse() {
+        Ok(seconds) => max_drain = Duration::from_secs(seconds),
+        Err(_) => return Err((StatusCode::BAD_REQUEST, format!("Invalid max_drain_sec '{}'\n", value))),
+      },
+      (key, value) => {
+        return Err((
+          StatusCode::BAD_REQUEST,
+          format!("Unsupported parameter {}={}\n", key, value),
+        ))
+      }
+    }
+  }
+  let pool =
+    find_pool(config, pool_name).ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown pool '{}'\n", pool_name)))?;
+  if pool.addresses.iter().all(|(it, _)| it != address) {
+    return Err((
+      StatusCode::NOT_FOUND,
+      format!("The address '{}' is not part of the pool\n", address),
+    ));
+  }
+  let drain = start_drain(pool_name, address, mode, max_drain);
+  Ok(json_response(StatusCode::OK, describe(address, &drain, Instant::now())))
+}
+
+/// Handles `DELETE /pools/{pool}/drains/{address}`, after which the backend
+/// server receives new requests again.
+pub fn undrain(pool: &str, address: &str) -> DrainResult {
+  if !stop(pool, address) {
+    return Err((
+      StatusCode::NOT_FOUND,
+      format!("The backend server '{}' is not drained\n", address),
+    ));
+  }
+  Ok(
+    Response::builder()
+      .status(StatusCode::NO_CONTENT)
+      .body(Body::empty())
+      .unwrap(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_soft_drain_turns_into_hard_drain() {
+    let now = Instant::now();
+    let drain = Drain {
+      mode: DrainMode::Soft,
+      until: now + Duration::from_secs(10),
+      sessions: Mutex::default(),
+    };
+    drain.sessions.lock().unwrap().insert("10.0.0.1".parse().unwrap(), now);
+
+    assert_eq!(drain.mode(now), DrainMode::Soft);
+    assert_eq!(drain.pinned_sessions(now), 1);
+    assert_eq!(drain.pinned_sessions(now + SESSION_IDLE_TIMEOUT), 0);
+    assert_eq!(drain.mode(now + Duration::from_secs(10)), DrainMode::Hard);
+  }
+}
@@ -1,6 +1,11 @@
-use std::{collections::HashMap, iter::FromIterator, ops::Deref, str::FromStr};
-
-use hyper::{header::HOST, Body, Method, Request};
+use std::{cmp, collections::HashMap, iter::FromIterator, ops::Deref, str::FromStr, sync::Mutex};
+
+use crate::{geoip::GeoInfo, utils::split_once};
+use hyper::{
+  header::{HeaderName, COOKIE, HOST},
+  Body, Method, Request,
+};
+use once_cell::sync::Lazy;
 use pom::parser::*;
 use regex::Regex;
 
@@ -23,9 +28,19 @@ impl PartialEq for ComparableRegex {
   }
 }
 
+/// The compiled regexes of all matchers, so reloading the configuration does
+/// not compile the unchanged ones again.
+static REGEX_CACHE: Lazy<Mutex<HashMap<String, Regex>>> = Lazy::new(Mutex::default);
+
 impl ComparableRegex {
   pub fn new(regex: &str) -> Result<ComparableRegex, regex::Error> {
-    Ok(ComparableRegex(Regex::new(regex)?))
+    let mut cache = REGEX_CACHE.lock().unwrap();
+    if let Some(compiled) = cache.get(regex) {
+      return Ok(ComparableRegex(compiled.clone()));
+    }
+    let compiled = Regex::new(regex)?;
+    cache.insert(regex.to_string(), compiled.clone());
+    Ok(ComparableRegex(compiled))
   }
 }
 
@@ -37,18 +52,57 @@ pub enum BackendPoolMatcher {
   Path(String),
   PathRegexp(ComparableRegex),
   Query(String, String),
+  /// Matches clients from one of the countries, see [`GeoInfo::country`].
+  Country(Vec<String>),
+  /// Matches clients from one of the continents, see [`GeoInfo::continent`].
+  Continent(Vec<String>),
+  /// Matches requests with a header of the name, whose value matches.
+  Header(HeaderName, ValueMatcher),
+  /// Matches requests with a cookie of the name, whose value matches.
+  Cookie(String, ValueMatcher),
   And(Box<BackendPoolMatcher>, Box<BackendPoolMatcher>),
   Or(Box<BackendPoolMatcher>, Box<BackendPoolMatcher>),
 }
 
-impl From<String> for BackendPoolMatcher {
-  fn from(str: String) -> Self {
+/// How the value of a header or cookie is compared.
+#[derive(Debug, PartialEq)]
+pub enum ValueMatcher {
+  /// Any value matches, so the header or cookie only has to be present.
+  Present,
+  Exact(String),
+  Prefix(String),
+  Regexp(Box<ComparableRegex>),
+}
+
+impl ValueMatcher {
+  fn matches(&self, value: &str) -> bool {
+    match self {
+      ValueMatcher::Present => true,
+      ValueMatcher::Exact(expected) => value == expected,
+      ValueMatcher::Prefix(prefix) => value.starts_with(prefix.as_str()),
+      ValueMatcher::Regexp(regex) => regex.is_match(value),
+    }
+  }
+}
+
+impl FromStr for BackendPoolMatcher {
+  type Err = pom::Error;
+
+  /// Parses a matcher expression, which fails for invalid expressions and
+  /// regexes, so they are rejected when the configuration is loaded.
+  fn from_str(str: &str) -> Result<Self, Self::Err> {
     let chars: Vec<char> = str.chars().collect();
-    let result = parser().parse(&chars).unwrap();
+    let result = parser().parse(&chars);
     result
   }
 }
 
+impl From<String> for BackendPoolMatcher {
+  fn from(str: String) -> Self {
+    str.parse().unwrap()
+  }
+}
+
 impl BackendPoolMatcher {
   /// Returns true if the BackendPoolMatcher is statisfied by the given request
   ///
@@ -58,7 +112,7 @@ impl BackendPoolMatcher {
   ///
   /// # Examples
   ///
-  /// ```
+  /// ```ignore
   /// let request = Request::builder().uri("https://google.de").body(Body::empty());
   /// let matcher = BackendPoolMatcher::Host("google.de".into());
   ///
@@ -66,12 +120,10 @@ impl BackendPoolMatcher {
   /// ```
   pub fn matches(&self, request: &Request<Body>) -> bool {
     match self {
-      BackendPoolMatcher::Host(host) => request.headers().get(HOST).map(|h| h == host).unwrap_or(false),
-      BackendPoolMatcher::HostRegexp(host_regex) => request
-        .headers()
-        .get(HOST)
-        .and_then(|h| Some(host_regex.is_match(h.to_str().ok()?)))
-        .unwrap_or(false),
+      BackendPoolMatcher::Host(host) => request_host(request).map(|h| h == host).unwrap_or(false),
+      BackendPoolMatcher::HostRegexp(host_regex) => {
+        request_host(request).map(|h| host_regex.is_match(h)).unwrap_or(false)
+      }
       BackendPoolMatcher::Method(method) => request.method() == method,
       BackendPoolMatcher::Path(path) => request.uri().path() == path,
       BackendPoolMatcher::PathRegexp(path_regex) => path_regex.is_match(request.uri().path()),
@@ -87,17 +139,90 @@ impl BackendPoolMatcher {
           .map(|sent_value| sent_value == value)
           .unwrap_or(false)
       }
-      BackendPoolMatcher::And(left, right) => left.matches(request) && right.matches(request),
+      BackendPoolMatcher::Country(countries) => {
+        let country = request.extensions().get::<GeoInfo>().and_then(|it| it.country.as_ref());
+        country.map(|it| contains_ignore_case(countries, it)).unwrap_or(false)
+      }
+      BackendPoolMatcher::Continent(continents) => {
+        let continent = request
+          .extensions()
+          .get::<GeoInfo>()
+          .and_then(|it| it.continent.as_ref());
+        continent
+          .map(|it| contains_ignore_case(continents, it))
+          .unwrap_or(false)
+      }
+      BackendPoolMatcher::Header(name, value) => match value {
+        ValueMatcher::Present => request.headers().contains_key(name),
+        value => request
+          .headers()
+          .get_all(name)
+          .iter()
+          .any(|it| it.to_str().map(|it| value.matches(it)).unwrap_or(false)),
+      },
+      BackendPoolMatcher::Cookie(name, value) => request_cookie(request, name)
+        .map(|it| value.matches(it))
+        .unwrap_or(false),
+      BackendPoolMatcher::And(left, right) => {
+        // Headers and cookies are only inspected once the other conditions passed
+        let (first, second) = if left.inspects_headers() && !right.inspects_headers() {
+          (right, left)
+        } else {
+          (left, right)
+        };
+        first.matches(request) && second.matches(request)
+      }
       BackendPoolMatcher::Or(left, right) => left.matches(request) || right.matches(request),
     }
   }
+
+  /// How many conditions a request has to satisfy. Of multiple matching
+  /// pools the most specific one is chosen, where alternatives (`||`) only
+  /// count as specific as the least specific of them.
+  pub fn specificity(&self) -> usize {
+    match self {
+      BackendPoolMatcher::And(left, right) => left.specificity() + right.specificity(),
+      BackendPoolMatcher::Or(left, right) => cmp::min(left.specificity(), right.specificity()),
+      _ => 1,
+    }
+  }
+
+  fn inspects_headers(&self) -> bool {
+    matches!(self, BackendPoolMatcher::Header(..) | BackendPoolMatcher::Cookie(..))
+  }
+}
+
+/// The value of the first cookie called `name`, without allocating.
+fn request_cookie<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+  request
+    .headers()
+    .get_all(COOKIE)
+    .iter()
+    .filter_map(|it| it.to_str().ok())
+    .flat_map(|it| it.split(';'))
+    .filter_map(|it| split_once(it.trim(), '='))
+    .find(|(key, _)| *key == name)
+    .map(|(_, value)| value)
+}
+
+/// The `Host` header or the `:authority` of HTTP/2 requests, which usually do
+/// not have a `Host` header.
+fn request_host(request: &Request<Body>) -> Option<&str> {
+  match request.headers().get(HOST) {
+    Some(host) => host.to_str().ok(),
+    None => request.uri().authority().map(|it| it.as_str()),
+  }
+}
+
+fn contains_ignore_case(values: &[String], value: &str) -> bool {
+  values.iter().any(|it| it.eq_ignore_ascii_case(value))
 }
 
 /// A PEG parser for generating BackendPoolMatcher rules
 ///
 /// # Examples:
 ///
-/// ```
+/// ```text
 /// "Host('google.de')"
 /// "HostRegexp('^(www\.)?google.de$')"
 /// "Host('google.de') && Path('/admin')"
@@ -105,6 +230,11 @@ impl BackendPoolMatcher {
 /// "Host('google.de') && Query('admin', 'true')"
 /// "Host('google.de') && Method('GET')"
 /// "Host('google.de') && ( Path('/admin') || Path('/moderator') )"
+/// "Host('google.de') && Country('DE', 'AT', 'CH')"
+/// "Continent('EU')"
+/// "Header('X-Beta-User', 'true') || Cookie('beta')"
+/// "HeaderRegexp('User-Agent', '(?i)bot')"
+/// "CookiePrefix('session', 'beta-')"
 /// ```
 fn parser<'a>() -> Parser<'a, char, BackendPoolMatcher> {
   space() * top_level_expression() - end()
@@ -145,6 +275,43 @@ fn query<'a>() -> Parser<'a, char, (String, String)> {
   tag("Query(") * string() - space() - sym(',') - space() + string() - sym(')')
 }
 
+fn strings<'a>() -> Parser<'a, char, Vec<String>> {
+  list(string(), space() * sym(',') * space())
+}
+
+fn country<'a>() -> Parser<'a, char, Vec<String>> {
+  tag("Country(") * space() * strings() - space() - sym(')')
+}
+
+fn continent<'a>() -> Parser<'a, char, Vec<String>> {
+  tag("Continent(") * space() * strings() - space() - sym(')')
+}
+
+/// `<kind>('name')` and `<kind>('name', 'value')` compare the whole value,
+/// `<kind>Prefix('name', 'prefix')` its start and `<kind>Regexp('name', 'regex')`
+/// search it for the regex.
+fn value_matcher<'a>(kind: &'static str) -> Parser<'a, char, (String, ValueMatcher)> {
+  let optional_value = (space() * sym(',') * space() * string()).opt();
+  let exact = tag(kind) * sym('(') * space() * string() + optional_value - space() - sym(')');
+  let exact = exact.map(|(name, value)| (name, value.map_or(ValueMatcher::Present, ValueMatcher::Exact)));
+  let prefix =
+    tag(kind) * tag("Prefix(") * space() * string() - space() - sym(',') - space() + string() - space() - sym(')');
+  let prefix = prefix.map(|(name, prefix)| (name, ValueMatcher::Prefix(prefix)));
+  let regexp =
+    tag(kind) * tag("Regexp(") * space() * string() - space() - sym(',') - space() + string() - space() - sym(')');
+  let regexp =
+    regexp.convert(|(name, regex)| ComparableRegex::new(&regex).map(|it| (name, ValueMatcher::Regexp(Box::new(it)))));
+  exact | prefix | regexp
+}
+
+fn header<'a>() -> Parser<'a, char, (HeaderName, ValueMatcher)> {
+  value_matcher("Header").convert(|(name, value)| HeaderName::from_str(&name).map(|name| (name, value)))
+}
+
+fn cookie<'a>() -> Parser<'a, char, (String, ValueMatcher)> {
+  value_matcher("Cookie")
+}
+
 fn and<'a>() -> Parser<'a, char, (BackendPoolMatcher, BackendPoolMatcher)> {
   call(value) - space() - tag("&&") - space() + call(value)
 }
@@ -164,6 +331,10 @@ fn value<'a>() -> Parser<'a, char, BackendPoolMatcher> {
     | path().map(BackendPoolMatcher::Path)
     | path_regexp().map(BackendPoolMatcher::PathRegexp)
     | query().map(|(key, value)| BackendPoolMatcher::Query(key, value))
+    | country().map(BackendPoolMatcher::Country)
+    | continent().map(BackendPoolMatcher::Continent)
+    | header().map(|(name, value)| BackendPoolMatcher::Header(name, value))
+    | cookie().map(|(name, value)| BackendPoolMatcher::Cookie(name, value))
     | (sym('(') * space() * (chained_expression() | call(value)) - space() - sym(')'))
 }
 
@@ -179,6 +350,7 @@ fn top_level_expression<'a>() -> Parser<'a, char, BackendPoolMatcher> {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use hyper::Version;
 
   fn to_char_vec(str: &'static str) -> Vec<char> {
     str.to_string().chars().collect()
@@ -275,6 +447,35 @@ mod tests {
     );
   }
 
+  #[test]
+  fn parse_country() {
+    let input = to_char_vec("Country('DE', 'FR') && Continent( 'EU' )");
+
+    let left = Box::new(BackendPoolMatcher::Country(vec!["DE".into(), "FR".into()]));
+    let right = Box::new(BackendPoolMatcher::Continent(vec!["EU".into()]));
+
+    assert_eq!(parser().parse(&input), Ok(BackendPoolMatcher::And(left, right)));
+  }
+
+  #[test]
+  fn matches_country() {
+    let request = |country: Option<&str>| {
+      let mut request = Request::builder().body(Body::empty()).unwrap();
+      request.extensions_mut().insert(GeoInfo {
+        country: country.map(String::from),
+        continent: None,
+      });
+      request
+    };
+
+    let matcher = BackendPoolMatcher::Country(vec!["DE".into(), "fr".into()]);
+
+    assert!(matcher.matches(&request(Some("FR"))));
+    assert!(!matcher.matches(&request(Some("US"))));
+    assert!(!matcher.matches(&request(None)));
+    assert!(!matcher.matches(&Request::new(Body::empty())));
+  }
+
   #[test]
   fn matches_host() {
     let request = Request::builder()
@@ -286,6 +487,18 @@ mod tests {
     assert_eq!(matcher.matches(&request), true);
   }
 
+  #[test]
+  fn matches_http2_authority() {
+    let request = Request::builder()
+      .version(Version::HTTP_2)
+      .uri("https://google.de/helloworld.Greeter/SayHello")
+      .body(Body::empty())
+      .unwrap();
+
+    assert!(BackendPoolMatcher::Host("google.de".into()).matches(&request));
+    assert!(!BackendPoolMatcher::Host("youtube.de".into()).matches(&request));
+  }
+
   #[test]
   fn matches_host_regex() {
     let request_1 = Request::builder()
@@ -398,4 +611,81 @@ mod tests {
     assert_eq!(matcher.matches(&request_1), true);
     assert_eq!(matcher.matches(&request_2), true);
   }
+
+  #[test]
+  fn parse_header_and_cookie() {
+    let input = to_char_vec("Header('X-Beta-User', 'true') && CookieRegexp('session', '^beta-')");
+
+    let left = Box::new(BackendPoolMatcher::Header(
+      HeaderName::from_static("x-beta-user"),
+      ValueMatcher::Exact("true".into()),
+    ));
+    let right = Box::new(BackendPoolMatcher::Cookie(
+      "session".into(),
+      ValueMatcher::Regexp(Box::new(ComparableRegex::new("^beta-").unwrap())),
+    ));
+
+    assert_eq!(parser().parse(&input), Ok(BackendPoolMatcher::And(left, right)));
+  }
+
+  #[test]
+  fn rejects_invalid_regex() {
+    assert!("HeaderRegexp('User-Agent', '(bot')"
+      .parse::<BackendPoolMatcher>()
+      .is_err());
+    assert!("Header('Invalid Name')".parse::<BackendPoolMatcher>().is_err());
+  }
+
+  #[test]
+  fn matches_header_exact() {
+    let request = |value: &str| {
+      Request::builder()
+        .header("X-Beta-User", value)
+        .body(Body::empty())
+        .unwrap()
+    };
+    let matcher = BackendPoolMatcher::from(String::from("Header('X-Beta-User', 'true')"));
+
+    assert!(matcher.matches(&request("true")));
+    assert!(!matcher.matches(&request("false")));
+    assert!(!matcher.matches(&Request::new(Body::empty())));
+  }
+
+  #[test]
+  fn matches_cookie_presence() {
+    let request = |cookie: &str| Request::builder().header(COOKIE, cookie).body(Body::empty()).unwrap();
+    let matcher = BackendPoolMatcher::from(String::from("Cookie('beta')"));
+
+    assert!(matcher.matches(&request("session=abc; beta=")));
+    assert!(matcher.matches(&request("beta=1")));
+    assert!(!matcher.matches(&request("session=abc; notbeta=1")));
+    assert!(!matcher.matches(&Request::new(Body::empty())));
+  }
+
+  #[test]
+  fn matches_user_agent_regex() {
+    let request = |user_agent: &str| {
+      Request::builder()
+        .header("User-Agent", user_agent)
+        .body(Body::empty())
+        .unwrap()
+    };
+    let matcher = BackendPoolMatcher::from(String::from("HeaderRegexp('User-Agent', '(?i)(bot|crawler)')"));
+
+    assert!(matcher.matches(&request("Mozilla/5.0 (compatible; Googlebot/2.1)")));
+    assert!(matcher.matches(&request("Some-Crawler/1.0")));
+    assert!(!matcher.matches(&request("Mozilla/5.0 (X11; Linux x86_64) Firefox/85.0")));
+  }
+
+  #[test]
+  fn specificity_counts_required_conditions() {
+    let specificity = |expression: &str| BackendPoolMatcher::from(expression.to_string()).specificity();
+
+    assert_eq!(specificity("Host('a')"), 1);
+    assert_eq!(specificity("Host('a') && Header('X-Beta-User', 'true')"), 2);
+    assert_eq!(
+      specificity("Host('a') && (Path('/') || (Path('/b') && Cookie('c')))"),
+      2
+    );
+  }
 }
@@ -0,0 +1,185 @@
+use crate::metrics::METRICS;
+use once_cell::sync::Lazy;
+use std::{
+  collections::HashMap,
+  io,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  task::{Context, Poll},
+  time::Duration,
+};
+use tokio::{
+  io::{AsyncRead, ReadBuf},
+  time::{interval, Instant},
+};
+
+/// How long the bytes of a backend server are summed up before its
+/// throughput is sampled.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// The bytes sent by each backend server, across all pools, routes and
+/// connections, which use the same address.
+pub static BACKEND_BANDWIDTH: Lazy<BackendBandwidth> = Lazy::new(BackendBandwidth::default);
+
+#[derive(Debug, Default)]
+pub struct BackendBandwidth {
+  meters: Mutex<HashMap<String, Arc<Meter>>>,
+}
+
+/// Counts the bytes of one backend server, which are sampled once per
+/// [`WINDOW`].
+#[derive(Debug, Default)]
+pub struct Meter {
+  /// The bytes since the last sample.
+  bytes: AtomicU64,
+  /// The bytes per second of the last window.
+  throughput: AtomicU64,
+}
+
+impl Meter {
+  pub fn record(&self, bytes: usize) {
+    self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+}
+
+impl BackendBandwidth {
+  /// The meter shared by all connections to `backend`.
+  pub fn meter(&self, backend: &str) -> Arc<Meter> {
+    let mut meters = self.meters.lock().unwrap();
+    meters.entry(backend.to_string()).or_default().clone()
+  }
+
+  /// The bytes per second, which `backend` sent during the last window.
+  pub fn throughput(&self, backend: &str) -> u64 {
+    let meters = self.meters.lock().unwrap();
+    meters
+      .get(backend)
+      .map(|it| it.throughput.load(Ordering::Relaxed))
+      .unwrap_or_default()
+  }
+
+  /// Whether `backend` used up its budget of `max_bytes_per_sec` during the
+  /// last window, so it should not receive new connections.
+  pub fn exceeds(&self, backend: &str, max_bytes_per_sec: Option<u64>) -> bool {
+    max_bytes_per_sec
+      .map(|max| self.throughput(backend) >= max)
+      .unwrap_or(false)
+  }
+
+  /// Turns the bytes counted during `elapsed` into the throughput of each
+  /// backend server. Meters, which are no longer used by any connection and
+  /// counted nothing, are removed.
+  pub(crate) fn sample(&self, elapsed: Duration) {
+    let mut meters = self.meters.lock().unwrap();
+    meters.retain(|backend, meter| {
+      let bytes = meter.bytes.swap(0, Ordering::Relaxed);
+      let throughput = (bytes as f64 / elapsed.as_secs_f64()) as u64;
+      meter.throughput.store(throughput, Ordering::Relaxed);
+      METRICS.set_gauge(
+        "arlb_backend_throughput_bytes_per_second",
+        &[("backend", backend)],
+        throughput as i64,
+      );
+      throughput > 0 || Arc::strong_count(meter) > 1
+    });
+  }
+}
+
+/// Samples the throughput of the backend servers once per [`WINDOW`].
+pub async fn sample_backend_bandwidth() {
+  let mut interval = interval(WINDOW);
+  let mut sampled_at = Instant::now();
+  loop {
+    interval.tick().await;
+    let now = Instant::now();
+    BACKEND_BANDWIDTH.sample(now.duration_since(sampled_at).max(Duration::from_millis(1)));
+    sampled_at = now;
+  }
+}
+
+/// Counts the bytes read from `inner` with a [`Meter`].
+pub struct Metered<R> {
+  inner: R,
+  meter: Option<Arc<Meter>>,
+}
+
+impl<R> Metered<R> {
+  /// Reads from `inner`, counting the bytes with `meter`, unless it is `None`.
+  pub fn new(inner: R, meter: Option<Arc<Meter>>) -> Metered<R> {
+    Metered { inner, meter }
+  }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Metered<R> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let this = &mut *self;
+    let before = buf.filled().len();
+    let result = futures::ready!(Pin::new(&mut this.inner).poll_read(cx, buf));
+    if let Some(meter) = &this.meter {
+      meter.record(buf.filled().len() - before);
+    }
+    Poll::Ready(result)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  #[test]
+  fn test_throughput_is_sampled_per_window() {
+    // given:
+    let bandwidth = BackendBandwidth::default();
+    let meter = bandwidth.meter("127.0.0.1:7001");
+
+    // when:
+    meter.record(3000);
+    bandwidth.meter("127.0.0.1:7001").record(1000);
+    let unsampled = bandwidth.throughput("127.0.0.1:7001");
+    bandwidth.sample(Duration::from_secs(2));
+
+    // then:
+    assert_eq!(unsampled, 0);
+    assert_eq!(bandwidth.throughput("127.0.0.1:7001"), 2000);
+    assert!(bandwidth.exceeds("127.0.0.1:7001", Some(2000)));
+    assert!(!bandwidth.exceeds("127.0.0.1:7001", Some(2001)));
+    assert!(!bandwidth.exceeds("127.0.0.1:7001", None));
+    assert_eq!(
+      METRICS.gauge(
+        "arlb_backend_throughput_bytes_per_second",
+        &[("backend", "127.0.0.1:7001")]
+      ),
+      2000
+    );
+
+    // when: the backend server is idle and no longer connected
+    drop(meter);
+    bandwidth.sample(Duration::from_secs(1));
+
+    // then:
+    assert_eq!(bandwidth.throughput("127.0.0.1:7001"), 0);
+    assert!(bandwidth.meters.lock().unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_metered_reads_are_counted() {
+    // given:
+    let (mut client, server) = tokio::io::duplex(1024);
+    let meter = Arc::new(Meter::default());
+    let mut metered = Metered::new(server, Some(meter.clone()));
+
+    // when:
+    client.write_all(&[7; 300]).await.unwrap();
+    drop(client);
+    let mut received = Vec::new();
+    metered.read_to_end(&mut received).await.unwrap();
+
+    // then:
+    assert_eq!(received.len(), 300);
+    assert_eq!(meter.bytes.load(Ordering::Relaxed), 300);
+  }
+}
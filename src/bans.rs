@@ -0,0 +1,475 @@
+use crate::metrics::METRICS;
+use arc_swap::ArcSwap;
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use linked_hash_map::LinkedHashMap;
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+  net::{IpAddr, SocketAddr},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+  },
+  time::{Duration, Instant},
+};
+use tokio::{io::AsyncReadExt, net::TcpStream, time::timeout};
+
+/// Bans clients, which repeatedly violate limits, for a while. Each violation
+/// adds its score to the client IP address and once the scores within a
+/// `window` reach the `threshold`, the client is banned for `duration`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BanConfig {
+  pub threshold: u64,
+  pub window: Duration,
+  pub duration: Duration,
+  /// Bounds the memory of the ban list and of the tracked offenders each.
+  pub max_bans: usize,
+  pub mode: BanMode,
+  /// The number of connections held open at the same time in `tarpit` mode,
+  /// further connections of banned clients are closed.
+  pub max_tarpits: usize,
+  pub scores: ViolationScores,
+}
+
+/// What happens to connections of banned clients.
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum BanMode {
+  /// Connections are closed right after they were accepted.
+  #[default]
+  Close,
+  /// Connections are held open, while their bytes are read and discarded
+  /// slowly, to waste the resources of the client.
+  Tarpit,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct ViolationScores {
+  #[serde(default = "default_rate_limited_score")]
+  pub rate_limited: u64,
+  #[serde(default = "default_invalid_request_score")]
+  pub invalid_request: u64,
+  #[serde(default = "default_authentication_failed_score")]
+  pub authentication_failed: u64,
+}
+
+fn default_rate_limited_score() -> u64 {
+  1
+}
+
+fn default_invalid_request_score() -> u64 {
+  5
+}
+
+fn default_authentication_failed_score() -> u64 {
+  2
+}
+
+impl Default for ViolationScores {
+  fn default() -> Self {
+    ViolationScores {
+      rate_limited: default_rate_limited_score(),
+      invalid_request: default_invalid_request_score(),
+      authentication_failed: default_authentication_failed_score(),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Violation {
+  /// A request was rejected by the [`RateLimiter`](crate::middleware::rate_limiter::RateLimiter).
+  RateLimited,
+  /// A request was rejected by the [request validation](crate::request_validation).
+  InvalidRequest,
+  /// A request was sent with invalid credentials.
+  AuthenticationFailed,
+}
+
+impl Violation {
+  /// The `reason` label of `arlb_bans_total`.
+  fn reason(self) -> &'static str {
+    match self {
+      Violation::RateLimited => "rate_limited",
+      Violation::InvalidRequest => "invalid_request",
+      Violation::AuthenticationFailed => "authentication_failed",
+    }
+  }
+
+  fn score(self, scores: &ViolationScores) -> u64 {
+    match self {
+      Violation::RateLimited => scores.rate_limited,
+      Violation::InvalidRequest => scores.invalid_request,
+      Violation::AuthenticationFailed => scores.authentication_failed,
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct State {
+  /// The score and start of the window of each offender, oldest first.
+  scores: LinkedHashMap<IpAddr, (u64, Instant)>,
+  /// When the ban of each client ends, the oldest ban first.
+  bans: LinkedHashMap<IpAddr, Instant>,
+}
+
+impl State {
+  fn remove_expired_bans(&mut self, now: Instant) {
+    let expired = self
+      .bans
+      .iter()
+      .filter(|(_, until)| **until <= now)
+      .map(|(ip, _)| *ip)
+      .collect::<Vec<_>>();
+    for ip in expired {
+      self.bans.remove(&ip);
+    }
+  }
+}
+
+/// Tracks the violations of clients and the resulting bans. Without a
+/// configuration nothing is tracked and nobody is banned.
+pub struct Offenders {
+  config: ArcSwap<Option<BanConfig>>,
+  state: Mutex<State>,
+  tarpits: AtomicUsize,
+}
+
+impl Default for Offenders {
+  fn default() -> Self {
+    Offenders {
+      config: ArcSwap::from_pointee(None),
+      state: Mutex::default(),
+      tarpits: AtomicUsize::new(0),
+    }
+  }
+}
+
+pub static OFFENDERS: Lazy<Offenders> = Lazy::new(Offenders::default);
+
+impl Offenders {
+  /// Applies a new configuration, existing bans are kept unless banning is
+  /// disabled.
+  pub fn configure(&self, config: Option<BanConfig>) {
+    if config.is_none() {
+      *self.state.lock().unwrap() = State::default();
+    }
+    self.config.store(config.into());
+  }
+
+  /// Adds the score of `violation` to the client `ip` and bans it, once it
+  /// reached the threshold.
+  pub fn record(&self, ip: IpAddr, violation: Violation) {
+    let config = self.config.load();
+    let config = match &**config {
+      Some(config) => config,
+      None => return,
+    };
+    let score = violation.score(&config.scores);
+    if score == 0 {
+      return;
+    }
+    let now = Instant::now();
+    let mut state = self.state.lock().unwrap();
+    if matches!(state.bans.get(&ip), Some(until) if *until > now) {
+      return;
+    }
+    // Remove and reinsert to keep the oldest offender first
+    let (total, since) = match state.scores.remove(&ip) {
+      Some((total, since)) if now.duration_since(since) <= config.window => (total.saturating_add(score), since),
+      _ => (score, now),
+    };
+    if total < config.threshold {
+      state.scores.insert(ip, (total, since));
+      while state.scores.len() > config.max_bans {
+        state.scores.pop_front();
+      }
+      return;
+    }
+    state.remove_expired_bans(now);
+    state.bans.remove(&ip);
+    state.bans.insert(ip, now + config.duration);
+    while state.bans.len() > config.max_bans {
+      state.bans.pop_front();
+    }
+    METRICS.increment("arlb_bans_total", &[("reason", violation.reason())]);
+    warn!(
+      "Banned {} for {:?}, because of repeated violations ({})",
+      ip,
+      config.duration,
+      violation.reason()
+    );
+  }
+
+  /// How connections of the client `ip` are handled, if it is banned.
+  pub fn ban_of(&self, ip: IpAddr) -> Option<BanMode> {
+    let mode = (**self.config.load()).as_ref()?.mode;
+    let mut state = self.state.lock().unwrap();
+    match state.bans.get(&ip) {
+      Some(until) if *until > Instant::now() => Some(mode),
+      Some(_) => {
+        state.bans.remove(&ip);
+        None
+      }
+      None => None,
+    }
+  }
+
+  /// The banned clients and the remaining time of their bans.
+  pub fn bans(&self) -> Vec<(IpAddr, Duration)> {
+    let now = Instant::now();
+    let mut state = self.state.lock().unwrap();
+    state.remove_expired_bans(now);
+    state.bans.iter().map(|(ip, until)| (*ip, *until - now)).collect()
+  }
+
+  /// Lifts the ban of the client `ip` and forgets its violations. Returns
+  /// whether it was banned.
+  pub fn unban(&self, ip: IpAddr) -> bool {
+    let now = Instant::now();
+    let mut state = self.state.lock().unwrap();
+    state.scores.remove(&ip);
+    matches!(state.bans.remove(&ip), Some(until) if until > now)
+  }
+}
+
+/// How often a tarpitted connection reads a single byte.
+const TARPIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns the connection `socket`, unless its client is banned. Connections
+/// of banned clients are closed or tarpitted right after they were accepted.
+pub fn admit(socket: TcpStream, peer: SocketAddr) -> Option<TcpStream> {
+  admit_by(&OFFENDERS, socket, peer, TARPIT_INTERVAL)
+}
+
+fn admit_by(
+  offenders: &'static Offenders,
+  socket: TcpStream,
+  peer: SocketAddr,
+  interval: Duration,
+) -> Option<TcpStream> {
+  let mode = match offenders.ban_of(peer.ip()) {
+    Some(mode) => mode,
+    None => return Some(socket),
+  };
+  let max_tarpits = (**offenders.config.load()).as_ref().map_or(0, |it| it.max_tarpits);
+  let tarpitted = mode == BanMode::Tarpit
+    && offenders
+      .tarpits
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |it| {
+        if it < max_tarpits {
+          Some(it + 1)
+        } else {
+          None
+        }
+      })
+      .is_ok();
+  if tarpitted {
+    METRICS.increment("arlb_banned_connections_total", &[("action", "tarpitted")]);
+    debug!("Tarpitting connection of banned client {}", peer);
+    tokio::spawn(async move {
+      tarpit(offenders, socket, peer.ip(), interval).await;
+      offenders.tarpits.fetch_sub(1, Ordering::SeqCst);
+    });
+  } else {
+    METRICS.increment("arlb_banned_connections_total", &[("action", "closed")]);
+    debug!("Closed connection of banned client {}", peer);
+  }
+  None
+}
+
+/// Reads and discards at most one byte of `socket` per `interval`, until the
+/// client closes the connection or its ban ends.
+async fn tarpit(offenders: &Offenders, mut socket: TcpStream, ip: IpAddr, interval: Duration) {
+  let mut byte = [0; 1];
+  while offenders.ban_of(ip).is_some() {
+    match timeout(interval, socket.read(&mut byte)).await {
+      Ok(Ok(0)) | Ok(Err(_)) => return,
+      Ok(Ok(_)) => tokio::time::sleep(interval).await,
+      Err(_) => {}
+    }
+  }
+}
+
+/// Handles `GET /bans` and `DELETE /bans/{ip}`.
+pub fn handle_request(request: &Request<Body>) -> Response<Body> {
+  let path = request.uri().path();
+  match (request.method(), path.strip_prefix("/bans/")) {
+    (&Method::GET, None) if path == "/bans" => {
+      let bans = OFFENDERS
+        .bans()
+        .into_iter()
+        .map(|(ip, remaining)| json!({"ip": ip.to_string(), "remaining_sec": remaining.as_secs()}))
+        .collect::<Vec<_>>();
+      Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "bans": bans }).to_string()))
+        .unwrap()
+    }
+    (&Method::DELETE, Some(ip)) => match ip.parse() {
+      Ok(ip) if OFFENDERS.unban(ip) => {
+        info!("Lifted the ban of {}", ip);
+        status(StatusCode::NO_CONTENT)
+      }
+      Ok(_) => status(StatusCode::NOT_FOUND),
+      Err(_) => status(StatusCode::BAD_REQUEST),
+    },
+    (&Method::GET, _) | (&Method::DELETE, _) => status(StatusCode::NOT_FOUND),
+    _ => status(StatusCode::METHOD_NOT_ALLOWED),
+  }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+  Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+  };
+
+  fn config(threshold: u64, duration: Duration, mode: BanMode) -> BanConfig {
+    BanConfig {
+      threshold,
+      window: Duration::from_secs(60),
+      duration,
+      max_bans: 2,
+      mode,
+      max_tarpits: 1,
+      scores: ViolationScores::default(),
+    }
+  }
+
+  fn offenders(config: BanConfig) -> &'static Offenders {
+    let offenders = Box::leak(Box::new(Offenders::default()));
+    offenders.configure(Some(config));
+    offenders
+  }
+
+  fn ip(last: u8) -> IpAddr {
+    IpAddr::from([10, 0, 0, last])
+  }
+
+  /// A connection accepted by the load balancer and the client side of it.
+  async fn connection() -> (TcpStream, SocketAddr, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (socket, peer) = listener.accept().await.unwrap();
+    (socket, peer, client)
+  }
+
+  #[test]
+  fn test_clients_are_banned_once_they_reach_the_threshold() {
+    // given:
+    let offenders = offenders(config(5, Duration::from_secs(60), BanMode::Close));
+
+    // when:
+    for _ in 0..4 {
+      offenders.record(ip(1), Violation::RateLimited);
+    }
+    offenders.record(ip(2), Violation::InvalidRequest);
+
+    // then:
+    assert_eq!(offenders.ban_of(ip(1)), None);
+    assert_eq!(offenders.ban_of(ip(2)), Some(BanMode::Close));
+    offenders.record(ip(1), Violation::RateLimited);
+    assert_eq!(offenders.ban_of(ip(1)), Some(BanMode::Close));
+  }
+
+  #[test]
+  fn test_bans_expire() {
+    // given:
+    let offenders = offenders(config(1, Duration::from_millis(50), BanMode::Close));
+    offenders.record(ip(1), Violation::AuthenticationFailed);
+    assert_eq!(offenders.ban_of(ip(1)), Some(BanMode::Close));
+
+    // when:
+    std::thread::sleep(Duration::from_millis(100));
+
+    // then:
+    assert_eq!(offenders.ban_of(ip(1)), None);
+    assert!(offenders.bans().is_empty());
+  }
+
+  #[test]
+  fn test_ban_list_is_capped() {
+    // given:
+    let offenders = offenders(config(1, Duration::from_secs(60), BanMode::Close));
+
+    // when:
+    for last in 1..=3 {
+      offenders.record(ip(last), Violation::InvalidRequest);
+    }
+
+    // then: the oldest ban was dropped
+    let banned = offenders.bans().into_iter().map(|(ip, _)| ip).collect::<Vec<_>>();
+    assert_eq!(banned, vec![ip(2), ip(3)]);
+  }
+
+  #[test]
+  fn test_unban_forgets_the_client() {
+    // given:
+    let offenders = offenders(config(1, Duration::from_secs(60), BanMode::Close));
+    offenders.record(ip(1), Violation::InvalidRequest);
+
+    // when:
+    let unbanned = offenders.unban(ip(1));
+
+    // then:
+    assert!(unbanned);
+    assert_eq!(offenders.ban_of(ip(1)), None);
+    assert!(!offenders.unban(ip(1)));
+  }
+
+  #[test]
+  fn test_nothing_is_tracked_without_config() {
+    let offenders = Box::leak(Box::new(Offenders::default()));
+
+    offenders.record(ip(1), Violation::InvalidRequest);
+
+    assert!(offenders.bans().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_connections_of_banned_clients_are_closed() {
+    // given:
+    let offenders = offenders(config(1, Duration::from_secs(60), BanMode::Close));
+    let (socket, peer, mut client) = connection().await;
+    offenders.record(peer.ip(), Violation::InvalidRequest);
+
+    // when:
+    let admitted = admit_by(offenders, socket, peer, Duration::from_millis(10));
+
+    // then:
+    assert!(admitted.is_none());
+    assert_eq!(client.read(&mut [0; 1]).await.unwrap(), 0);
+  }
+
+  #[tokio::test]
+  async fn test_connections_of_banned_clients_are_tarpitted_until_the_ban_ends() {
+    // given:
+    let offenders = offenders(config(1, Duration::from_millis(300), BanMode::Tarpit));
+    let (socket, peer, mut client) = connection().await;
+    let (second_socket, _, mut second_client) = connection().await;
+    offenders.record(peer.ip(), Violation::InvalidRequest);
+
+    // when:
+    assert!(admit_by(offenders, socket, peer, Duration::from_millis(10)).is_none());
+    assert!(admit_by(offenders, second_socket, peer, Duration::from_millis(10)).is_none());
+    client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+
+    // then: the connection is held open, but the second one exceeded max_tarpits
+    let read = timeout(Duration::from_millis(100), client.read(&mut [0; 1])).await;
+    assert!(read.is_err(), "{:?}", read);
+    assert_eq!(second_client.read(&mut [0; 1]).await.unwrap(), 0);
+    let read = timeout(Duration::from_secs(1), client.read(&mut [0; 1])).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(offenders.tarpits.load(Ordering::SeqCst), 0);
+  }
+}
@@ -0,0 +1,476 @@
+//! The `bench` subcommand, which drives clients through an embedded
+//! [`LoadBalancer`].
+
+use another_rust_load_balancer::{self_signed, Config, Error, LoadBalancer};
+use futures::future::{join_all, try_join_all};
+use hyper::{
+  client::conn::{handshake, SendRequest},
+  server::conn::Http,
+  service::service_fn,
+  Body, Request, Response,
+};
+use openssl::x509::X509;
+use std::{
+  convert::Infallible,
+  fmt, fs, io,
+  net::SocketAddr,
+  path::Path,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{
+  rustls::{Certificate, ClientConfig, PrivateKey, ServerConfig},
+  webpki::DNSNameRef,
+  TlsAcceptor, TlsConnector,
+};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BenchMode {
+  /// Each connection sends HTTP requests one after another, whose responses
+  /// contain the payload.
+  Requests,
+  /// Each connection sends the payload over and over through a TCP router
+  /// to a backend server, which echoes it.
+  Stream,
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+  pub mode: BenchMode,
+  pub connections: usize,
+  /// The size of each response, or of each write in the stream mode.
+  pub payload_size: usize,
+  pub duration: Duration,
+  /// Whether clients connect via TLS. In the stream mode the TLS session is
+  /// relayed to the backend server like with TLS passthrough.
+  pub tls: bool,
+}
+
+#[derive(Debug)]
+pub struct BenchReport {
+  pub mode: BenchMode,
+  pub connections: usize,
+  /// How long it took to establish all connections at once.
+  pub connection_setup: Duration,
+  /// The requests or, in the stream mode, the writes, which were completed.
+  pub exchanges: u64,
+  /// The payload bytes received by the clients.
+  pub bytes: u64,
+  pub errors: u64,
+  pub elapsed: Duration,
+  /// The sorted latencies of all requests, empty in the stream mode.
+  pub latencies: Vec<Duration>,
+}
+
+impl BenchReport {
+  pub fn bytes_per_second(&self) -> f64 {
+    self.bytes as f64 / self.elapsed.as_secs_f64()
+  }
+
+  pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+    let index = ((self.latencies.len() as f64 * percentile).ceil() as usize).checked_sub(1)?;
+    self.latencies.get(index).copied()
+  }
+}
+
+impl fmt::Display for BenchReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let seconds = self.elapsed.as_secs_f64();
+    writeln!(f, "Mode:             {:?}", self.mode)?;
+    writeln!(
+      f,
+      "Connection setup: {} connections in {:?} ({:.0} connections/s)",
+      self.connections,
+      self.connection_setup,
+      self.connections as f64 / self.connection_setup.as_secs_f64()
+    )?;
+    writeln!(
+      f,
+      "Throughput:       {} exchanges in {:.1}s ({:.0}/s), {:.2} MiB/s",
+      self.exchanges,
+      seconds,
+      self.exchanges as f64 / seconds,
+      self.bytes_per_second() / (1024.0 * 1024.0)
+    )?;
+    if let (Some(p50), Some(p99)) = (self.percentile(0.5), self.percentile(0.99)) {
+      writeln!(f, "Latency:          p50 {:?}, p99 {:?}", p50, p99)?;
+    }
+    write!(f, "Errors:           {}", self.errors)
+  }
+}
+
+/// Starts a backend server and the load balancer in this process, both on
+/// ephemeral ports of the loopback interface, and drives `connections`
+/// clients through the load balancer for the `duration`.
+pub async fn run(options: &BenchOptions) -> Result<BenchReport, Error> {
+  static RUNS: AtomicUsize = AtomicUsize::new(0);
+  let run = RUNS.fetch_add(1, Ordering::Relaxed);
+  let dir = std::env::temp_dir().join(format!("arlb-bench-{}-{}", std::process::id(), run));
+  fs::create_dir_all(&dir)?;
+  let result = run_in(&dir, options).await;
+  let _ = fs::remove_dir_all(&dir);
+  result
+}
+
+async fn run_in(dir: &Path, options: &BenchOptions) -> Result<BenchReport, Error> {
+  let (certificate, key) = self_signed("localhost").map_err(|e| Error::Tls(e.into()))?;
+  let tls = Tls {
+    certificate: certificate.clone(),
+    key: PrivateKey(
+      key
+        .rsa()
+        .and_then(|it| it.private_key_to_der())
+        .map_err(io::Error::from)?,
+    ),
+  };
+  fs::write(
+    dir.join("bench.cer"),
+    X509::from_der(&certificate.0)
+      .and_then(|it| it.to_pem())
+      .map_err(io::Error::from)?,
+  )?;
+  fs::write(
+    dir.join("bench.key"),
+    key
+      .rsa()
+      .and_then(|it| it.private_key_to_pem())
+      .map_err(io::Error::from)?,
+  )?;
+
+  let payload = vec![b'x'; options.payload_size];
+  let backend = match options.mode {
+    BenchMode::Requests => start_http_backend(payload.clone()).await?,
+    BenchMode::Stream => start_echo_backend(options.tls.then(|| tls.server_config()).transpose()?).await?,
+  };
+  let config = Config::parse(
+    &format!(
+      r#"
+      http_address = "127.0.0.1:0"
+      https_address = "127.0.0.1:0"
+      drain_timeout_sec = 1
+
+      # The clients keep their connections for the whole bench
+      [keep_alive]
+      max_requests = 0
+      max_age_sec = 0
+
+      [[backend_pools]]
+      matcher = "Host('localhost')"
+      addresses = ["{backend}"]
+      schemes = ["HTTP", "HTTPS"]
+      strategy = {{ RoundRobin = {{}} }}
+
+      [[tcp_router_services]]
+      listen_address = "127.0.0.1:0"
+      [[tcp_router_services.routes]]
+      addresses = ["{backend}"]
+      strategy = {{ RoundRobin = {{}} }}
+
+      [certificates]
+      "localhost" = {{ Local = {{ certificate_path = "bench.cer", private_key_path = "bench.key" }} }}
+      "#,
+      backend = backend,
+    ),
+    dir,
+  )?;
+  let load_balancer = LoadBalancer::from_config(config).await?;
+  let bound_addresses = load_balancer.bound_addresses();
+  let shutdown = CancellationToken::new();
+  let running = tokio::spawn(load_balancer.run_with_shutdown(shutdown.clone()));
+  let listener = match (options.mode, options.tls) {
+    (BenchMode::Requests, false) => "http_address",
+    (BenchMode::Requests, true) => "https_address",
+    (BenchMode::Stream, _) => "tcp_router_services[0]",
+  };
+  let proxy_address = bound_addresses
+    .await
+    .and_then(|addresses| addresses.into_iter().find(|it| it.listener == listener))
+    .map(|it| it.address)
+    .ok_or_else(|| io::Error::other("The load balancer stopped before its listeners were bound"))?;
+
+  let client = Client {
+    proxy_address,
+    tls: options.tls.then(|| Arc::new(tls.client_config())),
+  };
+  let start = Instant::now();
+  let connections = try_join_all((0..options.connections).map(|_| client.connect(options.mode))).await?;
+  let connection_setup = start.elapsed();
+
+  let start = Instant::now();
+  let deadline = start + options.duration;
+  // Each connection is driven by its own task, so the clients scale across the worker threads
+  let payload = Arc::new(payload);
+  let results = join_all(
+    connections
+      .into_iter()
+      .map(|connection| tokio::spawn(connection.drive(payload.clone(), deadline))),
+  )
+  .await;
+  let elapsed = start.elapsed();
+
+  let mut report = BenchReport {
+    mode: options.mode,
+    connections: options.connections,
+    connection_setup,
+    exchanges: 0,
+    bytes: 0,
+    errors: 0,
+    elapsed,
+    latencies: Vec::new(),
+  };
+  for result in results {
+    let result = result.map_err(io::Error::other)?;
+    report.exchanges += result.exchanges;
+    report.bytes += result.bytes;
+    report.errors += result.errors;
+    report.latencies.extend(result.latencies);
+  }
+  report.latencies.sort();
+  shutdown.cancel();
+  running.await.map_err(io::Error::other)??;
+  Ok(report)
+}
+
+/// Responds to all requests with the `payload`.
+async fn start_http_backend(payload: Vec<u8>) -> io::Result<String> {
+  let listener = TcpListener::bind("127.0.0.1:0").await?;
+  let address = listener.local_addr()?.to_string();
+  let payload = hyper::body::Bytes::from(payload);
+  tokio::spawn(async move {
+    while let Ok((socket, _)) = listener.accept().await {
+      let payload = payload.clone();
+      tokio::spawn(Http::new().serve_connection(
+        socket,
+        service_fn(move |request: Request<Body>| {
+          let payload = payload.clone();
+          async move {
+            let _ = hyper::body::to_bytes(request.into_body()).await;
+            Ok::<_, Infallible>(Response::new(Body::from(payload)))
+          }
+        }),
+      ));
+    }
+  });
+  Ok(address)
+}
+
+/// Sends back everything it receives, after terminating TLS if there is a
+/// `server_config`.
+async fn start_echo_backend(server_config: Option<ServerConfig>) -> io::Result<String> {
+  let listener = TcpListener::bind("127.0.0.1:0").await?;
+  let address = listener.local_addr()?.to_string();
+  let acceptor = server_config.map(|it| TlsAcceptor::from(Arc::new(it)));
+  tokio::spawn(async move {
+    while let Ok((socket, _)) = listener.accept().await {
+      let acceptor = acceptor.clone();
+      tokio::spawn(async move {
+        let _ = match acceptor {
+          Some(acceptor) => match acceptor.accept(socket).await {
+            Ok(tls_stream) => echo(tls_stream).await,
+            Err(e) => Err(e),
+          },
+          None => echo(socket).await,
+        };
+      });
+    }
+  });
+  Ok(address)
+}
+
+async fn echo<S: AsyncRead + AsyncWrite>(stream: S) -> io::Result<()> {
+  let (mut read, mut write) = tokio::io::split(stream);
+  tokio::io::copy(&mut read, &mut write).await?;
+  write.shutdown().await
+}
+
+/// The certificate of `localhost`, which clients trust.
+struct Tls {
+  certificate: Certificate,
+  key: PrivateKey,
+}
+
+impl Tls {
+  fn server_config(&self) -> io::Result<ServerConfig> {
+    let mut server_config = ServerConfig::new(tokio_rustls::rustls::NoClientAuth::new());
+    server_config
+      .set_single_cert(vec![self.certificate.clone()], self.key.clone())
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(server_config)
+  }
+
+  fn client_config(&self) -> ClientConfig {
+    let mut client_config = ClientConfig::new();
+    client_config.root_store.add(&self.certificate).unwrap();
+    client_config
+  }
+}
+
+trait ClientStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> ClientStream for S {}
+
+struct Client {
+  proxy_address: SocketAddr,
+  tls: Option<Arc<ClientConfig>>,
+}
+
+enum Connection {
+  Requests(SendRequest<Body>),
+  Stream(Pin<Box<dyn ClientStream>>),
+}
+
+#[derive(Default)]
+struct ConnectionResult {
+  exchanges: u64,
+  bytes: u64,
+  errors: u64,
+  latencies: Vec<Duration>,
+}
+
+impl Client {
+  async fn connect(&self, mode: BenchMode) -> io::Result<Connection> {
+    let socket = TcpStream::connect(self.proxy_address).await?;
+    socket.set_nodelay(true)?;
+    let stream: Pin<Box<dyn ClientStream>> = match &self.tls {
+      Some(client_config) => {
+        let server_name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        Box::pin(
+          TlsConnector::from(client_config.clone())
+            .connect(server_name, socket)
+            .await?,
+        )
+      }
+      None => Box::pin(socket),
+    };
+    match mode {
+      BenchMode::Requests => {
+        let (sender, connection) = handshake(stream).await.map_err(io::Error::other)?;
+        tokio::spawn(connection);
+        Ok(Connection::Requests(sender))
+      }
+      BenchMode::Stream => Ok(Connection::Stream(stream)),
+    }
+  }
+}
+
+impl Connection {
+  async fn drive(self, payload: Arc<Vec<u8>>, deadline: Instant) -> ConnectionResult {
+    match self {
+      Connection::Requests(sender) => send_requests(sender, deadline).await,
+      Connection::Stream(stream) => stream_payload(stream, &payload, deadline).await,
+    }
+  }
+}
+
+async fn send_requests(mut sender: SendRequest<Body>, deadline: Instant) -> ConnectionResult {
+  let mut result = ConnectionResult::default();
+  while Instant::now() < deadline {
+    let start = Instant::now();
+    let request = Request::builder()
+      .header("host", "localhost")
+      .body(Body::empty())
+      .unwrap();
+    let response = match sender.send_request(request).await {
+      Ok(response) if response.status().is_success() => response,
+      // The connection can not be used anymore
+      Ok(_) | Err(_) => {
+        result.errors += 1;
+        break;
+      }
+    };
+    match hyper::body::to_bytes(response.into_body()).await {
+      Ok(body) => {
+        result.exchanges += 1;
+        result.bytes += body.len() as u64;
+        result.latencies.push(start.elapsed());
+      }
+      Err(_) => {
+        result.errors += 1;
+        break;
+      }
+    }
+  }
+  result
+}
+
+/// Writes the `payload` until the `deadline` while reading the echo, so the
+/// load balancer relays in both directions at once.
+async fn stream_payload(stream: Pin<Box<dyn ClientStream>>, payload: &[u8], deadline: Instant) -> ConnectionResult {
+  let (mut read, mut write) = tokio::io::split(stream);
+  let writes = async {
+    let mut writes = 0;
+    while Instant::now() < deadline {
+      write.write_all(payload).await?;
+      writes += 1;
+    }
+    write.shutdown().await?;
+    Ok::<_, io::Error>(writes)
+  };
+  let reads = async {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut bytes = 0;
+    loop {
+      match read.read(&mut buffer).await? {
+        0 => return Ok::<_, io::Error>(bytes),
+        read => bytes += read as u64,
+      }
+    }
+  };
+  let (writes, bytes) = futures::join!(writes, reads);
+  ConnectionResult {
+    exchanges: *writes.as_ref().unwrap_or(&0),
+    bytes: *bytes.as_ref().unwrap_or(&0),
+    errors: u64::from(writes.is_err()) + u64::from(bytes.is_err()),
+    latencies: Vec::new(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use once_cell::sync::Lazy;
+  use tokio::sync::Mutex;
+
+  async fn mini_bench(mode: BenchMode, tls: bool) -> BenchReport {
+    // Only one load balancer can run per process at a time
+    static EXCLUSIVE: Lazy<Mutex<()>> = Lazy::new(Mutex::default);
+    let _exclusive = EXCLUSIVE.lock().await;
+    run(&BenchOptions {
+      mode,
+      connections: 2,
+      payload_size: 1024,
+      duration: Duration::from_secs(2),
+      tls,
+    })
+    .await
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_requests_bench() {
+    let report = mini_bench(BenchMode::Requests, false).await;
+
+    assert!(report.exchanges > 0, "{}", report);
+    assert_eq!(report.bytes, report.exchanges * 1024);
+    assert_eq!(report.errors, 0);
+    assert!(report.percentile(0.5).unwrap() <= report.percentile(0.99).unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_tls_stream_bench() {
+    let report = mini_bench(BenchMode::Stream, true).await;
+
+    assert!(report.bytes_per_second() > 0.0, "{}", report);
+    assert_eq!(report.errors, 0);
+    assert!(report.percentile(0.5).is_none());
+  }
+}
@@ -0,0 +1,150 @@
+use hyper::{
+  body::to_bytes,
+  header::{HeaderValue, CONTENT_TYPE},
+  server::conn::Http,
+  service::service_fn,
+  Body, Request, Response,
+};
+use std::fmt;
+use tokio::io::{duplex, DuplexStream};
+
+/// The prefix of backend addresses, which refer to a backend server built into
+/// the load balancer, like `builtin:echo`.
+pub const BUILTIN_ADDRESS_PREFIX: &str = "builtin:";
+
+/// How many bytes are buffered in each direction of a connection to a builtin
+/// backend server.
+const BUFFER_LEN: usize = 64 * 1024;
+
+/// Backend servers, which run inside the load balancer, so the routing, TLS,
+/// header rewriting and limits can be debugged without any external service.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuiltinBackend {
+  /// Sends back whatever it receives. HTTP requests are answered with the
+  /// request, including its headers and body.
+  Echo,
+  /// Reads and discards whatever it receives, but never responds.
+  Sink,
+}
+
+/// Inserted into the extensions of responses of builtin backend servers, so
+/// the access log can mark them as synthetic.
+#[derive(Debug, Clone, Copy)]
+pub struct Synthetic(pub BuiltinBackend);
+
+impl fmt::Display for BuiltinBackend {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+impl BuiltinBackend {
+  /// The builtin backend server of `address`, unless it is another address or
+  /// an unknown builtin backend server.
+  pub fn from_address(address: &str) -> Option<BuiltinBackend> {
+    BuiltinBackend::from_name(address.strip_prefix(BUILTIN_ADDRESS_PREFIX)?)
+  }
+
+  pub fn from_name(name: &str) -> Option<BuiltinBackend> {
+    match name {
+      "echo" => Some(BuiltinBackend::Echo),
+      "sink" => Some(BuiltinBackend::Sink),
+      _ => None,
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      BuiltinBackend::Echo => "echo",
+      BuiltinBackend::Sink => "sink",
+    }
+  }
+
+  /// Opens an in-process connection, which behaves like this backend server
+  /// on the level of bytes, for relayed TCP connections.
+  pub fn connect(self) -> DuplexStream {
+    let (connection, backend) = duplex(BUFFER_LEN);
+    let (mut reader, mut writer) = tokio::io::split(backend);
+    tokio::spawn(async move {
+      // The connection is closed once the client closed its side
+      let _ = match self {
+        BuiltinBackend::Echo => tokio::io::copy(&mut reader, &mut writer).await,
+        BuiltinBackend::Sink => tokio::io::copy(&mut reader, &mut tokio::io::sink()).await,
+      };
+    });
+    connection
+  }
+
+  /// Like [`connect`](BuiltinBackend::connect), but the echo backend server
+  /// speaks HTTP, so the hyper client of a backend pool can use it.
+  pub fn connect_http(self) -> DuplexStream {
+    match self {
+      BuiltinBackend::Echo => {
+        let (connection, backend) = duplex(BUFFER_LEN);
+        tokio::spawn(Http::new().serve_connection(backend, service_fn(echo)));
+        connection
+      }
+      BuiltinBackend::Sink => self.connect(),
+    }
+  }
+}
+
+/// Responds with the head and the body of the `request` as text.
+async fn echo(request: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+  let (parts, body) = request.into_parts();
+  let mut echoed = format!("{} {} {:?}\r\n", parts.method, parts.uri, parts.version);
+  for (name, value) in &parts.headers {
+    echoed.push_str(&format!("{}: {}\r\n", name, String::from_utf8_lossy(value.as_bytes())));
+  }
+  echoed.push_str("\r\n");
+  let mut echoed = echoed.into_bytes();
+  echoed.extend_from_slice(&to_bytes(body).await?);
+  let mut response = Response::new(Body::from(echoed));
+  response
+    .headers_mut()
+    .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+  Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::FutureExt;
+  use std::time::Duration;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  #[tokio::test]
+  async fn test_echo_sends_back_what_it_receives() {
+    // given:
+    let mut connection = BuiltinBackend::from_address("builtin:echo").unwrap().connect();
+
+    // when:
+    connection.write_all(b"ping").await.unwrap();
+    let mut received = [0; 4];
+    connection.read_exact(&mut received).await.unwrap();
+
+    // then:
+    assert_eq!(&received, b"ping");
+  }
+
+  #[tokio::test]
+  async fn test_sink_never_responds() {
+    // given:
+    let mut connection = BuiltinBackend::from_address("builtin:sink").unwrap().connect();
+
+    // when:
+    connection.write_all(b"ping").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let mut received = [0; 4];
+
+    // then:
+    assert!(connection.read(&mut received).now_or_never().is_none());
+  }
+
+  #[test]
+  fn test_unknown_builtin_backends() {
+    assert_eq!(BuiltinBackend::from_address("builtin:echo"), Some(BuiltinBackend::Echo));
+    assert_eq!(BuiltinBackend::from_address("builtin:void"), None);
+    assert_eq!(BuiltinBackend::from_address("127.0.0.1:8080"), None);
+  }
+}
@@ -0,0 +1,386 @@
+use serde::Deserialize;
+use std::{
+  collections::VecDeque,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConcurrencyLimitConfig {
+  /// How many requests can be forwarded to the backend servers of a pool at
+  /// the same time.
+  pub max_connections: usize,
+  /// How many requests can wait for a free slot, further requests are
+  /// rejected immediately.
+  pub queue_length: usize,
+  pub queue_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+  QueueFull,
+  Timeout,
+}
+
+impl Rejection {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Rejection::QueueFull => "queue_full",
+      Rejection::Timeout => "timeout",
+    }
+  }
+}
+
+/// The lane, in which a request waits for a slot of a [`ConcurrencyLimiter`].
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+  High,
+  #[default]
+  Low,
+}
+
+/// How many released slots in a row are handed to high priority requests at
+/// most, while low priority requests are waiting as well. Low priority
+/// requests are delayed, but not starved, by a steady stream of high priority
+/// requests.
+const HIGH_PRIORITY_BURST: usize = 4;
+
+/// Limits the number of concurrent requests. Requests, which exceed the limit,
+/// wait in a queue until a slot is released. High priority requests are
+/// served before low priority ones (see [`HIGH_PRIORITY_BURST`]), requests of
+/// the same priority in FIFO order.
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+  config: ConcurrencyLimitConfig,
+  state: Arc<Mutex<State>>,
+  queued: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct State {
+  /// The number of free slots. Slots are only free, while nobody waits.
+  available: usize,
+  high: VecDeque<oneshot::Sender<Permit>>,
+  low: VecDeque<oneshot::Sender<Permit>>,
+  /// How many slots were handed to high priority requests in a row.
+  high_streak: usize,
+}
+
+impl State {
+  /// The request, which receives the next released slot.
+  fn next_waiter(&mut self) -> Option<oneshot::Sender<Permit>> {
+    // Requests, which timed out, do not wait anymore
+    self.high.retain(|it| !it.is_closed());
+    self.low.retain(|it| !it.is_closed());
+    if self.low.is_empty() || self.high_streak < HIGH_PRIORITY_BURST {
+      if let Some(waiter) = self.high.pop_front() {
+        self.high_streak += 1;
+        return Some(waiter);
+      }
+    }
+    self.high_streak = 0;
+    self.low.pop_front()
+  }
+}
+
+/// Occupies a slot and hands it to the next waiting request when dropped.
+#[derive(Debug)]
+struct Permit(Option<Arc<Mutex<State>>>);
+
+impl Drop for Permit {
+  fn drop(&mut self) {
+    let mut state = match self.0.take() {
+      Some(state) => state,
+      None => return,
+    };
+    loop {
+      let waiter = {
+        let mut locked = state.lock().unwrap();
+        match locked.next_waiter() {
+          Some(waiter) => waiter,
+          None => {
+            locked.available += 1;
+            return;
+          }
+        }
+      };
+      // The waiter may time out right now, then the slot goes to the next one
+      match waiter.send(Permit(Some(state))) {
+        Ok(()) => return,
+        Err(mut permit) => state = permit.0.take().unwrap(),
+      }
+    }
+  }
+}
+
+/// A slot of a [`ConcurrencyLimiter`], which is released when dropped.
+#[derive(Debug)]
+pub struct Slot {
+  _permit: Permit,
+  /// How long the request waited in the queue.
+  pub queued_for: Option<Duration>,
+}
+
+impl ConcurrencyLimiter {
+  pub fn new(config: ConcurrencyLimitConfig) -> ConcurrencyLimiter {
+    ConcurrencyLimiter {
+      state: Arc::new(Mutex::new(State {
+        available: config.max_connections,
+        high: VecDeque::new(),
+        low: VecDeque::new(),
+        high_streak: 0,
+      })),
+      config,
+      queued: AtomicUsize::new(0),
+    }
+  }
+
+  /// The number of requests waiting for a slot.
+  #[cfg(test)]
+  pub fn queued(&self) -> usize {
+    self.queued.load(Ordering::SeqCst)
+  }
+
+  #[cfg(test)]
+  pub async fn acquire(&self) -> Result<Slot, Rejection> {
+    match self.try_acquire() {
+      Some(slot) => Ok(slot),
+      None => self.enqueue(Priority::Low).await,
+    }
+  }
+
+  /// Returns a slot, if one is free right now. Released slots are handed to
+  /// queued requests first, so this can not overtake the queue.
+  pub fn try_acquire(&self) -> Option<Slot> {
+    let mut state = self.state.lock().unwrap();
+    if state.available == 0 {
+      return None;
+    }
+    state.available -= 1;
+    Some(Slot {
+      _permit: Permit(Some(self.state.clone())),
+      queued_for: None,
+    })
+  }
+
+  /// Waits in the lane of `priority` for a free slot.
+  pub async fn enqueue(&self, priority: Priority) -> Result<Slot, Rejection> {
+    let queue_length = self.config.queue_length;
+    self
+      .queued
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+        Some(queued + 1).filter(|it| *it <= queue_length)
+      })
+      .map_err(|_| Rejection::QueueFull)?;
+    let start = Instant::now();
+    let receiver = {
+      let mut state = self.state.lock().unwrap();
+      if state.available > 0 {
+        // A slot was released since `try_acquire`
+        state.available -= 1;
+        None
+      } else {
+        let (sender, receiver) = oneshot::channel();
+        let lane = match priority {
+          Priority::High => &mut state.high,
+          Priority::Low => &mut state.low,
+        };
+        // Drop the requests, which timed out, while no slot was released
+        lane.retain(|it| !it.is_closed());
+        lane.push_back(sender);
+        Some(receiver)
+      }
+    };
+    let permit = match receiver {
+      Some(receiver) => tokio::time::timeout(self.config.queue_timeout, receiver).await,
+      None => Ok(Ok(Permit(Some(self.state.clone())))),
+    };
+    self.queued.fetch_sub(1, Ordering::SeqCst);
+    match permit {
+      Ok(Ok(permit)) => Ok(Slot {
+        _permit: permit,
+        queued_for: Some(start.elapsed()),
+      }),
+      // Waiters are only dropped without a slot together with the limiter
+      Ok(Err(_)) | Err(_) => Err(Rejection::Timeout),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  fn limiter(max_connections: usize, queue_length: usize, queue_timeout_ms: u64) -> Arc<ConcurrencyLimiter> {
+    Arc::new(ConcurrencyLimiter::new(ConcurrencyLimitConfig {
+      max_connections,
+      queue_length,
+      queue_timeout: Duration::from_millis(queue_timeout_ms),
+    }))
+  }
+
+  #[tokio::test]
+  async fn test_released_slot_wakes_queued_request() {
+    // given:
+    let limiter = limiter(1, 1, 5000);
+    let slot = limiter.acquire().await.unwrap();
+    assert_eq!(slot.queued_for, None);
+    let queued = tokio::spawn({
+      let limiter = limiter.clone();
+      async move { limiter.acquire().await.map(|it| it.queued_for) }
+    });
+    while limiter.queued() == 0 {
+      tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    // when:
+    drop(slot);
+
+    // then:
+    let queued_for = queued.await.unwrap().unwrap();
+    assert!(queued_for.is_some());
+    assert_eq!(limiter.queued(), 0);
+  }
+
+  #[tokio::test]
+  async fn test_queue_full() {
+    // given:
+    let limiter = limiter(1, 1, 5000);
+    let _slot = limiter.acquire().await.unwrap();
+    let _queued = tokio::spawn({
+      let limiter = limiter.clone();
+      async move { limiter.acquire().await.map(|_| ()) }
+    });
+    while limiter.queued() == 0 {
+      tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    // when:
+    let result = limiter.acquire().await;
+
+    // then:
+    assert_eq!(result.unwrap_err(), Rejection::QueueFull);
+  }
+
+  #[tokio::test]
+  async fn test_queue_timeout() {
+    // given:
+    let limiter = limiter(1, 1, 10);
+    let _slot = limiter.acquire().await.unwrap();
+
+    // when:
+    let result = limiter.acquire().await;
+
+    // then:
+    assert_eq!(result.unwrap_err(), Rejection::Timeout);
+    assert_eq!(limiter.queued(), 0);
+  }
+
+  #[tokio::test]
+  async fn test_queued_requests_are_served_in_order() {
+    // given:
+    let limiter = limiter(1, 3, 5000);
+    let slot = limiter.acquire().await.unwrap();
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut queued = Vec::new();
+    for id in 0..3 {
+      queued.push(tokio::spawn({
+        let limiter = limiter.clone();
+        let order = order.clone();
+        async move {
+          let _slot = limiter.acquire().await.unwrap();
+          order.lock().unwrap().push(id);
+        }
+      }));
+      while limiter.queued() <= id {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+      }
+    }
+
+    // when:
+    drop(slot);
+    for task in queued {
+      task.await.unwrap();
+    }
+
+    // then:
+    assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+  }
+
+  /// Queues a request of each priority in `priorities` one after another. The
+  /// requests hold their slot until the next request is served.
+  async fn served_order(limiter: Arc<ConcurrencyLimiter>, priorities: &[Priority]) -> Vec<usize> {
+    let slot = limiter.acquire().await.unwrap();
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut queued = Vec::new();
+    for (id, priority) in priorities.iter().copied().enumerate() {
+      queued.push(tokio::spawn({
+        let limiter = limiter.clone();
+        let order = order.clone();
+        async move {
+          let _slot = limiter.enqueue(priority).await.unwrap();
+          order.lock().unwrap().push(id);
+        }
+      }));
+      while limiter.queued() <= id {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+      }
+    }
+    drop(slot);
+    for task in queued {
+      task.await.unwrap();
+    }
+    let order = order.lock().unwrap().clone();
+    order
+  }
+
+  #[tokio::test]
+  async fn test_high_priority_requests_are_served_first() {
+    // given:
+    let limiter = limiter(1, 10, 5000);
+
+    // when:
+    let order = served_order(limiter, &[Priority::Low, Priority::High, Priority::Low, Priority::High]).await;
+
+    // then:
+    assert_eq!(order, vec![1, 3, 0, 2]);
+  }
+
+  #[tokio::test]
+  async fn test_low_priority_requests_are_not_starved() {
+    // given:
+    let limiter = limiter(1, 10, 5000);
+    let mut priorities = vec![Priority::Low];
+    priorities.extend(vec![Priority::High; HIGH_PRIORITY_BURST + 1]);
+
+    // when:
+    let order = served_order(limiter, &priorities).await;
+
+    // then: the low priority request is served after a burst of high priority requests
+    let mut expected: Vec<usize> = (1..=HIGH_PRIORITY_BURST).collect();
+    expected.push(0);
+    expected.push(HIGH_PRIORITY_BURST + 1);
+    assert_eq!(order, expected);
+  }
+
+  #[tokio::test]
+  async fn test_timed_out_requests_pass_on_their_slot() {
+    // given: a queued request, which times out
+    let limiter = limiter(1, 2, 20);
+    let slot = limiter.acquire().await.unwrap();
+    let timed_out = limiter.enqueue(Priority::High).await;
+
+    // when:
+    drop(slot);
+
+    // then:
+    assert_eq!(timed_out.unwrap_err(), Rejection::Timeout);
+    assert!(limiter.try_acquire().is_some());
+  }
+}
@@ -0,0 +1,438 @@
+use crate::{
+  events,
+  listeners::{PeerCredentials, RemoteAddress},
+  tls::TlsInfo,
+};
+use futures::task::AtomicWaker;
+use hyper::{header::CONTENT_TYPE, server::accept::Accept, Body, Method, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+use pin_project::pin_project;
+use serde_json::{json, Value};
+use std::{
+  collections::HashMap,
+  io,
+  net::SocketAddr,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    Arc, Mutex,
+  },
+  task::{Context, Poll},
+  time::Instant,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// The live client connections of all listeners, for diagnosing stuck
+/// connections via the admin API.
+pub static CONNECTIONS: Lazy<Connections> = Lazy::new(Connections::default);
+
+/// What a connection is doing right now.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+  /// The first bytes (like the ClientHello) are read to select a route.
+  Handshaking,
+  /// A backend server is being connected to or, for HTTP, the head of its
+  /// response is awaited.
+  Connecting,
+  /// Data is relayed between the client and the backend server.
+  Piping,
+  /// One side finished sending, so the connection is about to close.
+  Draining,
+}
+
+impl Phase {
+  const ALL: [Phase; 4] = [Phase::Handshaking, Phase::Connecting, Phase::Piping, Phase::Draining];
+
+  fn name(self) -> &'static str {
+    match self {
+      Phase::Handshaking => "handshaking",
+      Phase::Connecting => "connecting",
+      Phase::Piping => "piping",
+      Phase::Draining => "draining",
+    }
+  }
+}
+
+/// The state of a live connection. It is only updated by the task serving the
+/// connection via atomics, so there is no lock shared by all connections.
+pub struct LiveConnection {
+  pub id: u64,
+  /// Like `http`, the `name` of one of the `listeners` or `tcp_router`.
+  listener: String,
+  peer: SocketAddr,
+  opened_at: Instant,
+  phase: AtomicU8,
+  backend: Mutex<Option<String>>,
+  bytes_received: AtomicU64,
+  bytes_sent: AtomicU64,
+  /// The milliseconds after `opened_at`, when the connection last read or
+  /// wrote something.
+  last_read_ms: AtomicU64,
+  last_write_ms: AtomicU64,
+  killed: AtomicBool,
+  /// Wakes the task reading from the connection once it is killed.
+  waker: AtomicWaker,
+}
+
+impl LiveConnection {
+  pub fn phase(&self) -> Phase {
+    Phase::ALL[self.phase.load(Ordering::Relaxed) as usize]
+  }
+
+  pub fn set_phase(&self, phase: Phase) {
+    // A connection never leaves the draining phase
+    if self.phase() != Phase::Draining {
+      self.phase.store(phase as u8, Ordering::Relaxed);
+    }
+  }
+
+  /// Records, that the connection is being connected to `backend`.
+  pub fn connecting(&self, backend: &str) {
+    *self.backend.lock().unwrap() = Some(backend.to_string());
+    self.set_phase(Phase::Connecting);
+  }
+
+  fn elapsed_ms(&self) -> u64 {
+    self.opened_at.elapsed().as_millis() as u64
+  }
+
+  fn kill(&self) {
+    self.killed.store(true, Ordering::SeqCst);
+    self.waker.wake();
+  }
+
+  fn is_killed(&self) -> bool {
+    self.killed.load(Ordering::SeqCst)
+  }
+
+  fn summary(&self) -> Value {
+    json!({
+      "id": self.id,
+      "listener": self.listener,
+      "peer": self.peer.to_string(),
+      "backend": *self.backend.lock().unwrap(),
+      "phase": self.phase().name(),
+    })
+  }
+
+  fn detail(&self) -> Value {
+    let elapsed = self.elapsed_ms();
+    let mut detail = self.summary();
+    let since = |last: &AtomicU64| elapsed.saturating_sub(last.load(Ordering::Relaxed));
+    detail["bytes_received"] = self.bytes_received.load(Ordering::Relaxed).into();
+    detail["bytes_sent"] = self.bytes_sent.load(Ordering::Relaxed).into();
+    detail["age_ms"] = elapsed.into();
+    detail["last_read_ms_ago"] = since(&self.last_read_ms).into();
+    detail["last_write_ms_ago"] = since(&self.last_write_ms).into();
+    detail
+  }
+}
+
+/// The registry of live connections. Only registering and deregistering a
+/// connection locks it, the state of a connection is read on demand.
+#[derive(Default)]
+pub struct Connections {
+  live: Mutex<HashMap<u64, Arc<LiveConnection>>>,
+}
+
+impl Connections {
+  /// Registers `stream` with a new connection id. It is deregistered once the
+  /// returned stream is dropped.
+  pub fn track<IO>(&'static self, stream: IO, listener: String, peer: SocketAddr, phase: Phase) -> Tracked<IO> {
+    let connection = Arc::new(LiveConnection {
+      id: events::next_connection_id(),
+      listener,
+      peer,
+      opened_at: Instant::now(),
+      phase: AtomicU8::new(phase as u8),
+      backend: Mutex::new(None),
+      bytes_received: AtomicU64::new(0),
+      bytes_sent: AtomicU64::new(0),
+      last_read_ms: AtomicU64::new(0),
+      last_write_ms: AtomicU64::new(0),
+      killed: AtomicBool::new(false),
+      waker: AtomicWaker::new(),
+    });
+    self.live.lock().unwrap().insert(connection.id, connection.clone());
+    Tracked {
+      inner: stream,
+      connection,
+      registry: self,
+    }
+  }
+
+  pub fn get(&self, id: u64) -> Option<Arc<LiveConnection>> {
+    self.live.lock().unwrap().get(&id).cloned()
+  }
+
+  /// All live connections, oldest first.
+  pub fn list(&self) -> Vec<Arc<LiveConnection>> {
+    let mut connections = self.live.lock().unwrap().values().cloned().collect::<Vec<_>>();
+    connections.sort_by_key(|it| it.id);
+    connections
+  }
+
+  /// Closes the connection forcibly: further reads and writes fail, so the
+  /// task serving it closes it and its backend connection. Returns whether the
+  /// connection was live.
+  pub fn kill(&self, id: u64) -> bool {
+    match self.get(id) {
+      Some(connection) => {
+        connection.kill();
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+/// A client connection, which is registered in [`CONNECTIONS`] while it is
+/// open.
+pub struct Tracked<IO> {
+  inner: IO,
+  connection: Arc<LiveConnection>,
+  registry: &'static Connections,
+}
+
+impl<IO> Tracked<IO> {
+  pub fn connection(&self) -> &Arc<LiveConnection> {
+    &self.connection
+  }
+
+  pub fn get_ref(&self) -> &IO {
+    &self.inner
+  }
+}
+
+impl<IO> Drop for Tracked<IO> {
+  fn drop(&mut self) {
+    self.registry.live.lock().unwrap().remove(&self.connection.id);
+  }
+}
+
+fn killed() -> io::Error {
+  io::Error::new(
+    io::ErrorKind::ConnectionAborted,
+    "The connection was closed via the admin API",
+  )
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Tracked<IO> {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let this = self.get_mut();
+    let connection = &this.connection;
+    connection.waker.register(cx.waker());
+    if connection.is_killed() {
+      return Poll::Ready(Err(killed()));
+    }
+    let filled = buf.filled().len();
+    let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+    if let Poll::Ready(Ok(())) = result {
+      let read = buf.filled().len() - filled;
+      if read > 0 {
+        connection.bytes_received.fetch_add(read as u64, Ordering::Relaxed);
+        connection
+          .last_read_ms
+          .store(connection.elapsed_ms(), Ordering::Relaxed);
+      } else if buf.remaining() > 0 {
+        connection.set_phase(Phase::Draining);
+      }
+    }
+    result
+  }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Tracked<IO> {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+    let this = self.get_mut();
+    if this.connection.is_killed() {
+      return Poll::Ready(Err(killed()));
+    }
+    let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+    if let Poll::Ready(Ok(written)) = result {
+      let connection = &this.connection;
+      connection.bytes_sent.fetch_add(written as u64, Ordering::Relaxed);
+      connection
+        .last_write_ms
+        .store(connection.elapsed_ms(), Ordering::Relaxed);
+    }
+    result
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    let this = self.get_mut();
+    this.connection.set_phase(Phase::Draining);
+    Pin::new(&mut this.inner).poll_shutdown(cx)
+  }
+}
+
+impl<IO: RemoteAddress> RemoteAddress for Tracked<IO> {
+  fn remote_addr(&self) -> io::Result<SocketAddr> {
+    self.inner.remote_addr()
+  }
+
+  fn tls_info(&self) -> Option<TlsInfo> {
+    self.inner.tls_info()
+  }
+
+  fn connection(&self) -> Option<Arc<LiveConnection>> {
+    Some(self.connection.clone())
+  }
+
+  fn peer_credentials(&self) -> Option<PeerCredentials> {
+    self.inner.peer_credentials()
+  }
+}
+
+/// Registers the connections of `inner` in [`CONNECTIONS`]. Connections with
+/// a TLS session are labeled `https` instead of the `listener`, like the TLS
+/// connections of the HTTP port.
+#[pin_project]
+pub struct TrackingAcceptor<I> {
+  #[pin]
+  inner: I,
+  listener: Option<String>,
+  default_label: String,
+}
+
+impl<I> TrackingAcceptor<I> {
+  pub fn new(inner: I, listener: Option<String>, default_label: String) -> TrackingAcceptor<I> {
+    TrackingAcceptor {
+      inner,
+      listener,
+      default_label,
+    }
+  }
+}
+
+impl<I> Accept for TrackingAcceptor<I>
+where
+  I: Accept,
+  I::Conn: RemoteAddress,
+{
+  type Conn = Tracked<I::Conn>;
+  type Error = I::Error;
+
+  fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+    let this = self.project();
+    let listener = &*this.listener;
+    let default_label = &*this.default_label;
+    this.inner.poll_accept(cx).map(|connection| {
+      connection.map(|connection| {
+        connection.map(|it| {
+          let label = match listener {
+            Some(listener) => listener.clone(),
+            None if it.tls_info().is_some() => "https".to_string(),
+            None => default_label.clone(),
+          };
+          let peer = it.remote_addr().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+          // The TLS handshake already completed, the connection waits for requests
+          CONNECTIONS.track(it, label, peer, Phase::Piping)
+        })
+      })
+    })
+  }
+}
+
+/// Handles `GET /connections`, `GET /connections/{id}` and
+/// `DELETE /connections/{id}`.
+pub fn handle_request(request: &Request<Body>) -> Response<Body> {
+  let path = request.uri().path();
+  let id = match path.strip_prefix("/connections") {
+    Some("") | Some("/") => None,
+    Some(id) => match id.strip_prefix('/').and_then(|it| it.parse::<u64>().ok()) {
+      Some(id) => Some(id),
+      None => return status(StatusCode::NOT_FOUND),
+    },
+    None => return status(StatusCode::NOT_FOUND),
+  };
+  match (request.method(), id) {
+    (&Method::GET, None) => {
+      let connections = CONNECTIONS.list().iter().map(|it| it.summary()).collect::<Vec<_>>();
+      json_response(json!({ "connections": connections }))
+    }
+    (&Method::GET, Some(id)) => match CONNECTIONS.get(id) {
+      Some(connection) => json_response(connection.detail()),
+      None => status(StatusCode::NOT_FOUND),
+    },
+    (&Method::DELETE, Some(id)) => {
+      if CONNECTIONS.kill(id) {
+        status(StatusCode::NO_CONTENT)
+      } else {
+        status(StatusCode::NOT_FOUND)
+      }
+    }
+    _ => status(StatusCode::METHOD_NOT_ALLOWED),
+  }
+}
+
+fn json_response(value: Value) -> Response<Body> {
+  Response::builder()
+    .header(CONTENT_TYPE, "application/json")
+    .body(Body::from(value.to_string()))
+    .unwrap()
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+  Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  #[tokio::test]
+  async fn test_tracked_connection_counts_bytes_and_can_be_killed() {
+    // given:
+    let (client, mut peer) = tokio::io::duplex(64);
+    let peer_address = "10.0.0.1:1234".parse().unwrap();
+    let mut tracked = CONNECTIONS.track(client, "tcp_router".into(), peer_address, Phase::Handshaking);
+    let id = tracked.connection().id;
+
+    // when:
+    peer.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    tracked.read_exact(&mut ping).await.unwrap();
+    tracked.connection().connecting("127.0.0.1:8080");
+    tracked.connection().set_phase(Phase::Piping);
+    tracked.write_all(b"pong!").await.unwrap();
+    let detail = CONNECTIONS.get(id).unwrap().detail();
+    let killed = CONNECTIONS.kill(id);
+    let read_after_kill = tracked.read(&mut ping).await;
+    drop(tracked);
+
+    // then:
+    assert_eq!(detail["listener"], "tcp_router");
+    assert_eq!(detail["peer"], "10.0.0.1:1234");
+    assert_eq!(detail["backend"], "127.0.0.1:8080");
+    assert_eq!(detail["phase"], "piping");
+    assert_eq!(detail["bytes_received"], 4);
+    assert_eq!(detail["bytes_sent"], 5);
+    assert!(killed);
+    assert_eq!(read_after_kill.unwrap_err().kind(), io::ErrorKind::ConnectionAborted);
+    assert!(CONNECTIONS.get(id).is_none());
+    assert!(!CONNECTIONS.kill(id));
+  }
+
+  #[tokio::test]
+  async fn test_connection_drains_once_the_client_finished_sending() {
+    // given:
+    let (client, peer) = tokio::io::duplex(64);
+    let mut tracked = CONNECTIONS.track(client, "http".into(), "10.0.0.1:1".parse().unwrap(), Phase::Piping);
+
+    // when:
+    drop(peer);
+    let read = tracked.read(&mut [0; 4]).await.unwrap();
+
+    // then:
+    assert_eq!(read, 0);
+    assert_eq!(tracked.connection().phase(), Phase::Draining);
+    tracked.connection().set_phase(Phase::Piping);
+    assert_eq!(tracked.connection().phase(), Phase::Draining);
+  }
+}
@@ -0,0 +1,424 @@
+use crate::{
+  builtin_backends::BUILTIN_ADDRESS_PREFIX,
+  configuration::RuntimeConfig,
+  http_client::{backend_uri, UNIX_ADDRESS_PREFIX},
+  metrics::METRICS,
+};
+use arc_swap::ArcSwap;
+use futures::Future;
+use hyper::{
+  client::connect::dns::{GaiResolver, Name},
+  http::uri::PathAndQuery,
+  service::Service,
+};
+use linked_hash_map::LinkedHashMap;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use std::{
+  collections::HashSet,
+  net::{IpAddr, SocketAddr},
+  pin::Pin,
+  str::FromStr,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+  time::{Duration, Instant},
+};
+use tokio::time::timeout;
+
+/// How the addresses of backend servers are cached. The system resolver does
+/// not report the TTL of records, so the TTL is configured instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DnsConfig {
+  /// How long resolved addresses are used, before they are resolved again.
+  pub ttl: Duration,
+  /// How long a failed resolution is cached.
+  pub negative_ttl: Duration,
+  /// How long a resolution may take until it fails.
+  pub timeout: Duration,
+  /// Bounds the memory of the cache, the oldest entries are removed first.
+  pub max_entries: usize,
+}
+
+impl Default for DnsConfig {
+  fn default() -> Self {
+    DnsConfig {
+      ttl: Duration::from_secs(30),
+      negative_ttl: Duration::from_secs(5),
+      timeout: Duration::from_secs(5),
+      max_entries: 10_000,
+    }
+  }
+}
+
+#[derive(Debug)]
+struct Entry {
+  /// The addresses of the last successful resolution.
+  addresses: Option<Vec<SocketAddr>>,
+  /// Why the last resolution failed, if there are no addresses.
+  error: String,
+  expires: Instant,
+  /// Whether the expired addresses are being resolved again.
+  refreshing: bool,
+}
+
+/// The result of looking up a name in the cache.
+#[derive(Debug, PartialEq)]
+enum Cached {
+  Fresh(Vec<SocketAddr>),
+  /// The last resolution failed recently.
+  Failed(String),
+  /// The addresses expired. If `true`, the caller has to resolve them again.
+  Stale(Vec<SocketAddr>, bool),
+  Missing,
+}
+
+/// Caches the resolved addresses of backend servers, so requests don't wait
+/// for DNS: expired addresses are still used, while they are resolved again in
+/// the background, and are kept if resolving them fails.
+pub struct DnsCache {
+  config: ArcSwap<DnsConfig>,
+  entries: Mutex<LinkedHashMap<String, Entry>>,
+}
+
+impl Default for DnsCache {
+  fn default() -> Self {
+    DnsCache {
+      config: ArcSwap::from_pointee(DnsConfig::default()),
+      entries: Mutex::default(),
+    }
+  }
+}
+
+pub static DNS_CACHE: Lazy<Arc<DnsCache>> = Lazy::new(Arc::default);
+
+impl DnsCache {
+  /// Applies a new configuration, cached addresses are kept.
+  pub fn configure(&self, config: DnsConfig) {
+    self.config.store(Arc::new(config));
+  }
+
+  fn lookup(&self, name: &str, now: Instant) -> Cached {
+    let mut entries = self.entries.lock().unwrap();
+    let entry = match entries.get_mut(name) {
+      Some(entry) => entry,
+      None => return Cached::Missing,
+    };
+    match &entry.addresses {
+      Some(addresses) if entry.expires > now => Cached::Fresh(addresses.clone()),
+      None if entry.expires > now => Cached::Failed(entry.error.clone()),
+      Some(addresses) => {
+        let refresh = !entry.refreshing;
+        entry.refreshing = true;
+        Cached::Stale(addresses.clone(), refresh)
+      }
+      None => Cached::Missing,
+    }
+  }
+
+  fn contains(&self, name: &str) -> bool {
+    self.entries.lock().unwrap().contains_key(name)
+  }
+
+  fn store(&self, name: &str, result: &Result<Vec<SocketAddr>, String>, now: Instant) {
+    let config = self.config.load();
+    let mut entries = self.entries.lock().unwrap();
+    let previous = entries.remove(name).and_then(|it| it.addresses);
+    let entry = match (result, previous) {
+      (Ok(addresses), _) => Entry {
+        addresses: Some(addresses.clone()),
+        error: String::new(),
+        expires: now + config.ttl,
+        refreshing: false,
+      },
+      (Err(e), Some(previous)) => {
+        warn!("Could not resolve {} again, using its previous addresses: {}", name, e);
+        Entry {
+          addresses: Some(previous),
+          error: String::new(),
+          expires: now + config.negative_ttl,
+          refreshing: false,
+        }
+      }
+      (Err(e), None) => Entry {
+        addresses: None,
+        error: e.clone(),
+        expires: now + config.negative_ttl,
+        refreshing: false,
+      },
+    };
+    entries.insert(name.to_string(), entry);
+    while entries.len() > config.max_entries {
+      entries.pop_front();
+    }
+  }
+}
+
+/// Resolves `name` via `resolver` and caches the result.
+async fn resolve<R>(resolver: &mut R, cache: &DnsCache, name: Name) -> Result<Vec<SocketAddr>, String>
+where
+  R: Service<Name>,
+  R::Response: Iterator<Item = SocketAddr>,
+  R::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+  let resolution_timeout = cache.config.load().timeout;
+  let start = Instant::now();
+  let result = match timeout(resolution_timeout, resolver.call(name.clone())).await {
+    Ok(Ok(addresses)) => {
+      let addresses = addresses.collect::<Vec<_>>();
+      if addresses.is_empty() {
+        Err(format!("{} has no addresses", name))
+      } else {
+        Ok(addresses)
+      }
+    }
+    Ok(Err(e)) => Err(e.into().to_string()),
+    Err(_) => Err(format!("Resolving {} timed out after {:?}", name, resolution_timeout)),
+  };
+  let label = if result.is_ok() { "success" } else { "failure" };
+  METRICS.observe(
+    "arlb_dns_resolution_seconds",
+    &[("result", label)],
+    start.elapsed().as_secs_f64(),
+  );
+  if let Err(e) = &result {
+    debug!("Could not resolve {} due to: {}", name, e);
+  }
+  cache.store(name.as_str(), &result, Instant::now());
+  result
+}
+
+/// Serves the addresses of backend servers from the [`DnsCache`] and only
+/// waits for the `inner` resolver, if a name is resolved for the first time.
+#[derive(Clone)]
+pub struct CachingResolver<R = GaiResolver> {
+  inner: R,
+  cache: Arc<DnsCache>,
+}
+
+impl<R> CachingResolver<R> {
+  pub fn new(inner: R) -> CachingResolver<R> {
+    CachingResolver {
+      inner,
+      cache: DNS_CACHE.clone(),
+    }
+  }
+
+  #[cfg(test)]
+  fn with_cache(inner: R, cache: Arc<DnsCache>) -> CachingResolver<R> {
+    CachingResolver { inner, cache }
+  }
+}
+
+impl<R> std::fmt::Debug for CachingResolver<R> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("CachingResolver").finish()
+  }
+}
+
+impl<R> Service<Name> for CachingResolver<R>
+where
+  R: Service<Name> + Clone + Send + 'static,
+  R::Response: Iterator<Item = SocketAddr>,
+  R::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+  R::Future: Send + 'static,
+{
+  type Response = std::vec::IntoIter<SocketAddr>;
+
+  type Error = Box<dyn std::error::Error + Send + Sync>;
+
+  // let's allow this complex type. A refactor would make it more complicated due to the used trait types
+  #[allow(clippy::type_complexity)]
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx).map_err(|e| e.into())
+  }
+
+  fn call(&mut self, name: Name) -> Self::Future {
+    let mut inner = self.inner.clone();
+    let cache = self.cache.clone();
+    Box::pin(async move {
+      let (result, addresses) = match cache.lookup(name.as_str(), Instant::now()) {
+        Cached::Fresh(addresses) => ("hit", Ok(addresses)),
+        Cached::Failed(e) => ("negative_hit", Err(e)),
+        Cached::Stale(addresses, refresh) => {
+          if refresh {
+            tokio::spawn(async move { resolve(&mut inner, &cache, name).await });
+          }
+          ("stale", Ok(addresses))
+        }
+        Cached::Missing => ("miss", resolve(&mut inner, &cache, name).await),
+      };
+      METRICS.increment("arlb_dns_lookups_total", &[("result", result)]);
+      Ok(addresses?.into_iter())
+    })
+  }
+}
+
+/// The host name of a backend server address, unless it is an IP address, a
+/// unix domain socket or a builtin backend server.
+fn host_name(address: &str) -> Option<Name> {
+  if address.starts_with(UNIX_ADDRESS_PREFIX) || address.starts_with(BUILTIN_ADDRESS_PREFIX) {
+    return None;
+  }
+  let uri = backend_uri(address, PathAndQuery::from_static("/")).ok()?;
+  let host = uri.host()?;
+  if host
+    .trim_start_matches('[')
+    .trim_end_matches(']')
+    .parse::<IpAddr>()
+    .is_ok()
+  {
+    return None;
+  }
+  Name::from_str(host).ok()
+}
+
+/// Resolves the host names of all backend servers, which are not cached yet,
+/// in the background, so their first requests don't wait for DNS either.
+pub fn prefetch(config: &RuntimeConfig) {
+  let names = config
+    .shared_data
+    .backend_pools
+    .iter()
+    .flat_map(|pool| pool.addresses.iter())
+    .filter_map(|(address, _)| host_name(address))
+    .filter(|name| !DNS_CACHE.contains(name.as_str()))
+    .collect::<HashSet<_>>();
+  for name in names {
+    tokio::spawn(async move { resolve(&mut GaiResolver::new(), &DNS_CACHE, name).await });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+  };
+
+  /// Resolves every name to the current addresses, if there are any, and
+  /// counts the resolutions.
+  #[derive(Clone, Default)]
+  struct ChangingResolver {
+    addresses: Arc<Mutex<Vec<SocketAddr>>>,
+    resolutions: Arc<AtomicUsize>,
+  }
+
+  impl ChangingResolver {
+    fn answer(&self, addresses: &[&str]) {
+      *self.addresses.lock().unwrap() = addresses.iter().map(|it| it.parse().unwrap()).collect();
+    }
+  }
+
+  impl Service<Name> for ChangingResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = futures::future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _name: Name) -> Self::Future {
+      self.resolutions.fetch_add(1, Ordering::Relaxed);
+      let addresses = self.addresses.lock().unwrap().clone();
+      futures::future::ready(if addresses.is_empty() {
+        Err(io::Error::new(io::ErrorKind::NotFound, "NXDOMAIN"))
+      } else {
+        Ok(addresses.into_iter())
+      })
+    }
+  }
+
+  fn cache(ttl: Duration) -> Arc<DnsCache> {
+    let cache = Arc::new(DnsCache::default());
+    cache.configure(DnsConfig {
+      ttl,
+      negative_ttl: ttl,
+      ..Default::default()
+    });
+    cache
+  }
+
+  async fn resolve_backend(resolver: &mut CachingResolver<ChangingResolver>) -> Result<Vec<SocketAddr>, String> {
+    match resolver.call(Name::from_str("backend").unwrap()).await {
+      Ok(addresses) => Ok(addresses.collect()),
+      Err(e) => Err(e.to_string()),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_changed_answers_are_used_after_the_ttl() {
+    // given:
+    let inner = ChangingResolver::default();
+    let mut resolver = CachingResolver::with_cache(inner.clone(), cache(Duration::from_millis(100)));
+    inner.answer(&["10.0.0.1:0"]);
+    let first = resolve_backend(&mut resolver).await;
+
+    // when:
+    inner.answer(&["10.0.0.2:0"]);
+    let cached = resolve_backend(&mut resolver).await;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let stale = resolve_backend(&mut resolver).await;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let refreshed = resolve_backend(&mut resolver).await;
+
+    // then: the hot path never waits for the resolution of expired addresses
+    let old = vec!["10.0.0.1:0".parse().unwrap()];
+    assert_eq!(first, Ok(old.clone()));
+    assert_eq!(cached, Ok(old.clone()));
+    assert_eq!(stale, Ok(old));
+    assert_eq!(refreshed, Ok(vec!["10.0.0.2:0".parse().unwrap()]));
+    assert_eq!(inner.resolutions.load(Ordering::Relaxed), 2);
+  }
+
+  #[tokio::test]
+  async fn test_cached_addresses_are_kept_if_resolution_fails() {
+    // given:
+    let inner = ChangingResolver::default();
+    let mut resolver = CachingResolver::with_cache(inner.clone(), cache(Duration::from_millis(50)));
+    inner.answer(&["10.0.0.1:0"]);
+    resolve_backend(&mut resolver).await.unwrap();
+
+    // when:
+    inner.answer(&[]);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    resolve_backend(&mut resolver).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let result = resolve_backend(&mut resolver).await;
+
+    // then:
+    assert_eq!(result, Ok(vec!["10.0.0.1:0".parse().unwrap()]));
+    assert_eq!(inner.resolutions.load(Ordering::Relaxed), 2);
+  }
+
+  #[tokio::test]
+  async fn test_failures_are_cached_briefly() {
+    // given:
+    let inner = ChangingResolver::default();
+    let mut resolver = CachingResolver::with_cache(inner.clone(), cache(Duration::from_millis(50)));
+
+    // when:
+    let first = resolve_backend(&mut resolver).await;
+    inner.answer(&["10.0.0.1:0"]);
+    let cached = resolve_backend(&mut resolver).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let resolved = resolve_backend(&mut resolver).await;
+
+    // then:
+    assert_eq!(first, Err("NXDOMAIN".to_string()));
+    assert_eq!(cached, Err("NXDOMAIN".to_string()));
+    assert_eq!(resolved, Ok(vec!["10.0.0.1:0".parse().unwrap()]));
+    assert_eq!(inner.resolutions.load(Ordering::Relaxed), 2);
+  }
+
+  #[test]
+  fn test_host_name() {
+    assert_eq!(host_name("backend:8080").unwrap().as_str(), "backend");
+    assert!(host_name("127.0.0.1:8080").is_none());
+    assert!(host_name("[::1]:8080").is_none());
+    assert!(host_name("unix:/run/backend.sock").is_none());
+  }
+}
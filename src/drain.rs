@@ -0,0 +1,357 @@
+use hyper::service::Service;
+use log::{info, warn};
+use std::{
+  net::SocketAddr,
+  sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+  task::{Context, Poll},
+  time::Duration,
+};
+use tokio::{sync::watch, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+/// How often the remaining connections are logged while draining.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a draining listener checks, whether another process took over
+/// its address.
+#[cfg(target_os = "linux")]
+const HANDOVER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The open client connections of the HTTP, HTTPS and unix socket listeners.
+pub static CLIENT_CONNECTIONS: ConnectionCounter = ConnectionCounter::new();
+
+/// Whether draining was requested via [`signal`].
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+pub struct ConnectionCounter(AtomicUsize);
+
+impl ConnectionCounter {
+  const fn new() -> ConnectionCounter {
+    ConnectionCounter(AtomicUsize::new(0))
+  }
+
+  /// Counts a connection as open, until the returned value is dropped.
+  fn open(&'static self) -> OpenConnection {
+    self.0.fetch_add(1, Ordering::Relaxed);
+    OpenConnection(self)
+  }
+
+  pub fn get(&self) -> usize {
+    self.0.load(Ordering::Relaxed)
+  }
+}
+
+struct OpenConnection(&'static ConnectionCounter);
+
+impl Drop for OpenConnection {
+  fn drop(&mut self) {
+    (self.0).0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// A service, whose connection is counted as one of the [`CLIENT_CONNECTIONS`]
+/// as long as it is served. Hyper drops the service of a connection once the
+/// connection is closed.
+pub struct Counted<S> {
+  service: S,
+  _connection: OpenConnection,
+}
+
+impl<S> Counted<S> {
+  pub fn new(service: S) -> Counted<S> {
+    Counted {
+      service,
+      _connection: CLIENT_CONNECTIONS.open(),
+    }
+  }
+}
+
+impl<S: Service<R>, R> Service<R> for Counted<S> {
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = S::Future;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: R) -> Self::Future {
+    self.service.call(request)
+  }
+}
+
+/// Whether the process is draining its connections.
+pub fn is_draining() -> bool {
+  DRAINING.load(Ordering::Relaxed)
+}
+
+/// Ends draining after a load balancer stopped, so the next one in this
+/// process starts out accepting connections.
+#[cfg(any(test, feature = "test-util"))]
+pub(crate) fn reset() {
+  DRAINING.store(false, Ordering::Relaxed);
+}
+
+/// Returns a receiver, whose value becomes `true` once the process should stop
+/// accepting new connections and drain existing ones. This is triggered by
+/// cancelling `shutdown`, by `SIGUSR2` on unix platforms and by Ctrl-C or
+/// Ctrl-Break on Windows.
+pub fn signal(shutdown: CancellationToken) -> watch::Receiver<bool> {
+  let (sender, receiver) = watch::channel(false);
+  tokio::spawn(async move {
+    let reason = tokio::select! {
+      result = wait_for_drain_signal() => match result {
+        Ok(signal) => signal,
+        Err(e) => {
+          warn!("Could not listen for drain signals: {}", e);
+          shutdown.cancelled().await;
+          "Shutdown requested"
+        }
+      },
+      _ = shutdown.cancelled() => "Shutdown requested",
+    };
+    info!("{}, draining {} connections", reason, CLIENT_CONNECTIONS.get());
+    DRAINING.store(true, Ordering::Relaxed);
+    let _ = sender.send(true);
+    // Keep the channel open, otherwise receivers could not tell a closed channel from a drain
+    sender.closed().await;
+  });
+  receiver
+}
+
+/// Resolves with a description of the signal, which requested a drain.
+#[cfg(unix)]
+async fn wait_for_drain_signal() -> std::io::Result<&'static str> {
+  use tokio::signal::unix::{signal, SignalKind};
+  signal(SignalKind::user_defined2())?.recv().await;
+  Ok("Received SIGUSR2")
+}
+
+/// Windows has no user defined signals, so the console events are used, which
+/// would otherwise terminate the process right away.
+#[cfg(windows)]
+async fn wait_for_drain_signal() -> std::io::Result<&'static str> {
+  use tokio::signal::windows::{ctrl_break, ctrl_c};
+  let mut ctrl_c = ctrl_c()?;
+  let mut ctrl_break = ctrl_break()?;
+  tokio::select! {
+    _ = ctrl_c.recv() => Ok("Received Ctrl-C"),
+    _ = ctrl_break.recv() => Ok("Received Ctrl-Break"),
+  }
+}
+
+#[cfg(not(any(unix, windows)))]
+async fn wait_for_drain_signal() -> std::io::Result<&'static str> {
+  futures::future::pending().await
+}
+
+/// Resolves once draining was requested via `receiver`.
+pub async fn drained(mut receiver: watch::Receiver<bool>) {
+  while !*receiver.borrow() {
+    if receiver.changed().await.is_err() {
+      return futures::future::pending().await;
+    }
+  }
+}
+
+/// Resolves once draining was requested via `receiver`. A listener, which
+/// shares its `address` with other processes via `reuse_port`, keeps
+/// accepting until another process listens on the `address` as well (besides
+/// the `own` sockets of this process), because the kernel assigns connections
+/// to the listener until it is closed and connections, which are not accepted
+/// by then, are reset. This is only detected on Linux, other platforms stop
+/// accepting right away.
+pub async fn handed_over(receiver: watch::Receiver<bool>, shared: Option<(SocketAddr, usize)>) {
+  drained(receiver).await;
+  #[cfg(target_os = "linux")]
+  if let Some((address, own)) = shared {
+    let mut logged = false;
+    loop {
+      match listening_sockets(address) {
+        Ok(listening) if listening > own => break,
+        Ok(_) => {}
+        Err(e) => {
+          warn!(
+            "Could not look up the listeners of {}, closing it right away: {}",
+            address, e
+          );
+          return;
+        }
+      }
+      if !logged {
+        info!(
+          "Accepting connections of {} until another process listens on it",
+          address
+        );
+        logged = true;
+      }
+      tokio::time::sleep(HANDOVER_INTERVAL).await;
+    }
+    info!("Another process listens on {}, closing the listener", address);
+  }
+  #[cfg(not(target_os = "linux"))]
+  let _ = shared;
+}
+
+/// The number of TCP sockets of all processes, which listen on `address`.
+#[cfg(target_os = "linux")]
+fn listening_sockets(address: SocketAddr) -> std::io::Result<usize> {
+  const LISTEN: &str = "0A";
+  let table = if address.is_ipv4() {
+    "/proc/net/tcp"
+  } else {
+    "/proc/net/tcp6"
+  };
+  let sockets = std::fs::read_to_string(table)?;
+  Ok(
+    sockets
+      .lines()
+      .skip(1)
+      .filter(|line| {
+        // sl local_address rem_address st ...
+        let mut fields = line.split_whitespace().skip(1);
+        let local_address = fields.next().and_then(parse_proc_address);
+        let state = fields.nth(1);
+        state == Some(LISTEN) && local_address.is_some_and(|it| it.ip() == address.ip() && it.port() == address.port())
+      })
+      .count(),
+  )
+}
+
+/// Parses an address of `/proc/net/tcp` or `/proc/net/tcp6`, like
+/// `0100007F:1F90` for `127.0.0.1:8080`. The IP address consists of 32 bit
+/// words in the byte order of the host.
+#[cfg(any(target_os = "linux", test))]
+fn parse_proc_address(address: &str) -> Option<SocketAddr> {
+  use std::{convert::TryFrom, net::IpAddr};
+  let (ip, port) = address.split_once(':')?;
+  let port = u16::from_str_radix(port, 16).ok()?;
+  let mut octets = Vec::with_capacity(16);
+  for i in (0..ip.len()).step_by(8) {
+    let word = u32::from_str_radix(ip.get(i..i + 8)?, 16).ok()?;
+    octets.extend_from_slice(&word.to_ne_bytes());
+  }
+  let ip = match octets.len() {
+    4 => IpAddr::from(<[u8; 4]>::try_from(octets.as_slice()).ok()?),
+    16 => IpAddr::from(<[u8; 16]>::try_from(octets.as_slice()).ok()?),
+    _ => return None,
+  };
+  Some(SocketAddr::new(ip, port))
+}
+
+/// Resolves once draining was requested via `receiver` and `drain_timeout`
+/// elapsed afterwards. Meanwhile the remaining connections are logged
+/// periodically, so operators can follow the progress of the drain.
+pub async fn timeout(receiver: watch::Receiver<bool>, drain_timeout: Duration) {
+  drained(receiver).await;
+  let deadline = Instant::now() + drain_timeout;
+  loop {
+    let now = Instant::now();
+    if now >= deadline {
+      return;
+    }
+    let remaining = deadline - now;
+    info!(
+      "{} connections remaining to drain, closing them in {}s",
+      CLIENT_CONNECTIONS.get(),
+      remaining.as_secs()
+    );
+    tokio::time::sleep(remaining.min(PROGRESS_INTERVAL)).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::FutureExt;
+
+  #[tokio::test]
+  async fn test_drained() {
+    // given:
+    let (sender, receiver) = watch::channel(false);
+    let mut drained = Box::pin(drained(receiver));
+
+    // when:
+    let before = (&mut drained).now_or_never();
+    sender.send(true).unwrap();
+    let after = tokio::time::timeout(Duration::from_secs(1), drained).await;
+
+    // then:
+    assert!(before.is_none());
+    assert!(after.is_ok());
+  }
+
+  #[test]
+  fn test_parse_proc_address() {
+    // given:
+    let ipv4 = "0100007F:1F90";
+    let ipv6 = "00000000000000000000000001000000:01BB";
+
+    // when:
+    let ipv4 = parse_proc_address(ipv4);
+    let ipv6 = parse_proc_address(ipv6);
+
+    // then:
+    assert_eq!(ipv4, Some("127.0.0.1:8080".parse().unwrap()));
+    assert_eq!(ipv6, Some("[::1]:443".parse().unwrap()));
+    assert_eq!(parse_proc_address("0100007F"), None);
+  }
+
+  #[test]
+  fn test_connection_counter() {
+    // given:
+    static COUNTER: ConnectionCounter = ConnectionCounter::new();
+    let first = COUNTER.open();
+    let second = COUNTER.open();
+
+    // when:
+    drop(first);
+
+    // then:
+    assert_eq!(COUNTER.get(), 1);
+    drop(second);
+    assert_eq!(COUNTER.get(), 0);
+  }
+}
+
+/// Console events reach all processes attached to the console, including the
+/// test runner, so the test raising one is ignored by default.
+#[cfg(all(test, windows))]
+mod windows_tests {
+  use super::*;
+  use futures::FutureExt;
+
+  const CTRL_C_EVENT: u32 = 0;
+
+  extern "system" {
+    fn GenerateConsoleCtrlEvent(ctrl_event: u32, process_group_id: u32) -> i32;
+  }
+
+  #[tokio::test]
+  async fn test_console_handlers_are_installed() {
+    // given:
+    let mut signal = Box::pin(wait_for_drain_signal());
+
+    // when:
+    let installed = (&mut signal).now_or_never();
+
+    // then: pending, rather than failing to install the handlers
+    assert!(installed.is_none());
+  }
+
+  #[tokio::test]
+  #[ignore]
+  async fn test_ctrl_c_drains() {
+    // given:
+    let receiver = signal(CancellationToken::new());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // when:
+    let raised = unsafe { GenerateConsoleCtrlEvent(CTRL_C_EVENT, 0) };
+    let drained = tokio::time::timeout(Duration::from_secs(5), drained(receiver)).await;
+
+    // then:
+    assert_ne!(raised, 0);
+    assert!(drained.is_ok());
+    assert!(is_draining());
+  }
+}
@@ -0,0 +1,526 @@
+use crate::{
+  address_policy::literal_ip,
+  backend_drains,
+  configuration::{apply_dynamic_backends, RuntimeConfig},
+  http_client::{backend_uri, BackendTls},
+  server::{superseded_pools, BackendPool},
+  state_file,
+};
+use arc_swap::ArcSwap;
+use hyper::{header::CONTENT_TYPE, http::uri::PathAndQuery, Body, Method, Request, Response, StatusCode};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::BTreeMap, num::NonZeroU32, sync::Mutex, time::Instant};
+
+/// A backend server, which was registered through the admin API instead of
+/// the configuration file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DynamicBackend {
+  pub address: String,
+  /// The weight of weighted strategies, like the `weights` of the strategy in
+  /// the configuration file.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub weight: Option<NonZeroU32>,
+  #[serde(default)]
+  pub backup: bool,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub tls: Option<BackendTls>,
+  /// When the backend server was registered (or restored), which starts its
+  /// slow start.
+  #[serde(skip, default = "Instant::now")]
+  pub registered_at: Instant,
+}
+
+/// The dynamic backend servers by pool name and id.
+pub type Registry = BTreeMap<String, BTreeMap<String, DynamicBackend>>;
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(Default::default);
+
+/// Serializes changes, so a change can be rolled back if the configuration
+/// can not be applied.
+static CHANGES: Lazy<tokio::sync::Mutex<()>> = Lazy::new(Default::default);
+
+/// The dynamic backend servers of the pool `name` by id.
+pub fn of_pool(name: &str) -> BTreeMap<String, DynamicBackend> {
+  REGISTRY.lock().unwrap().get(name).cloned().unwrap_or_default()
+}
+
+pub fn all() -> Registry {
+  REGISTRY.lock().unwrap().clone()
+}
+
+/// Adds the dynamic backend servers of `registry`, for example the ones of
+/// the state file.
+pub fn restore(registry: Registry) {
+  let mut current = REGISTRY.lock().unwrap();
+  for (pool, backends) in registry {
+    current.entry(pool).or_default().extend(backends);
+  }
+}
+
+/// Sets or removes the backend server `id` of `pool` and returns the previous one.
+fn set(pool: &str, id: &str, backend: Option<DynamicBackend>) -> Option<DynamicBackend> {
+  let mut registry = REGISTRY.lock().unwrap();
+  match backend {
+    Some(backend) => registry
+      .entry(pool.to_string())
+      .or_default()
+      .insert(id.to_string(), backend),
+    None => {
+      let backends = registry.get_mut(pool)?;
+      let previous = backends.remove(id);
+      if backends.is_empty() {
+        registry.remove(pool);
+      }
+      previous
+    }
+  }
+}
+
+/// Handles `GET /pools/{pool}/backends` and `PUT` or `DELETE` of
+/// `/pools/{pool}/backends/{id}`, as well as the [`backend_drains`] of a pool.
+pub async fn handle_request(request: Request<Body>, config: &ArcSwap<RuntimeConfig>) -> Response<Body> {
+  let segments = request
+    .uri()
+    .path()
+    .trim_start_matches("/pools/")
+    .split('/')
+    .map(|it| percent_decode_str(it).decode_utf8_lossy().into_owned())
+    .collect::<Vec<_>>();
+  let method = request.method().clone();
+  let result = match (&method, segments.as_slice()) {
+    (&Method::GET, [pool, backends]) if backends == "backends" => Ok(list(pool, &config.load())),
+    (&Method::PUT, [pool, backends, id]) if backends == "backends" => match parse_backend(request).await {
+      Ok(backend) => register(config, pool, id, backend).await,
+      Err(e) => Err(e),
+    },
+    (&Method::DELETE, [pool, backends, id]) if backends == "backends" => deregister(config, pool, id).await,
+    (&Method::GET, [pool, drains]) if drains == "drains" => Ok(backend_drains::list(pool)),
+    (&Method::PUT, [pool, drains, address]) if drains == "drains" => {
+      let query = request.uri().query().unwrap_or_default();
+      backend_drains::drain(&config.load(), pool, address, query)
+    }
+    (&Method::DELETE, [pool, drains, address]) if drains == "drains" => backend_drains::undrain(pool, address),
+    (&Method::PUT, [pool, maintenance]) | (&Method::DELETE, [pool, maintenance]) if maintenance == "maintenance" => {
+      set_maintenance(&config.load(), pool, method == Method::PUT)
+    }
+    _ => Err((StatusCode::NOT_FOUND, String::new())),
+  };
+  result.unwrap_or_else(|(status, message)| Response::builder().status(status).body(Body::from(message)).unwrap())
+}
+
+type ChangeResult = Result<Response<Body>, (StatusCode, String)>;
+
+async fn parse_backend(request: Request<Body>) -> Result<DynamicBackend, (StatusCode, String)> {
+  let body = hyper::body::to_bytes(request.into_body())
+    .await
+    .map_err(|e| (StatusCode::BAD_REQUEST, format!("{}\n", e)))?;
+  let backend: DynamicBackend =
+    serde_json::from_slice(&body).map_err(|e| (StatusCode::BAD_REQUEST, format!("{}\n", e)))?;
+  backend_uri(&backend.address, PathAndQuery::from_static("/")).map_err(|e| {
+    (
+      StatusCode::BAD_REQUEST,
+      format!("Invalid address '{}': {}\n", backend.address, e),
+    )
+  })?;
+  let tls_without_name = backend.tls.as_ref().filter(|it| it.server_name.is_none()).is_some();
+  if tls_without_name && literal_ip(&backend.address).is_some() {
+    return Err((
+      StatusCode::BAD_REQUEST,
+      format!("The TLS settings of '{}' require a server_name\n", backend.address),
+    ));
+  }
+  Ok(backend)
+}
+
+fn json_response(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header(CONTENT_TYPE, "application/json")
+    .body(Body::from(value.to_string()))
+    .unwrap()
+}
+
+/// Whether the backend server receives requests. The configuration file wins,
+/// so a backend server, which is configured there as well, is `overridden`.
+fn state(pool: Option<&BackendPool>, backend: &DynamicBackend) -> &'static str {
+  match pool {
+    None => "unknown_pool",
+    Some(pool) if pool.dynamic_addresses.contains(&backend.address) => "active",
+    Some(_) => "overridden",
+  }
+}
+
+fn find_pool<'c>(config: &'c RuntimeConfig, name: &str) -> Option<&'c BackendPool> {
+  config
+    .shared_data
+    .backend_pools
+    .iter()
+    .find(|it| it.name == name)
+    .map(|it| it.as_ref())
+}
+
+/// Toggles the maintenance mode of a pool until a reload of the
+/// configuration changes its `maintenance`.
+fn set_maintenance(config: &RuntimeConfig, pool_name: &str, enabled: bool) -> ChangeResult {
+  let pool =
+    find_pool(config, pool_name).ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown pool '{}'\n", pool_name)))?;
+  pool.set_maintenance(enabled);
+  for pool in superseded_pools(pool_name) {
+    pool.set_maintenance(enabled);
+  }
+  if enabled {
+    info!("Enabled maintenance mode of pool '{}'", pool_name);
+  } else {
+    info!("Disabled maintenance mode of pool '{}'", pool_name);
+  }
+  Ok(
+    Response::builder()
+      .status(StatusCode::NO_CONTENT)
+      .body(Body::empty())
+      .unwrap(),
+  )
+}
+
+fn describe(pool: Option<&BackendPool>, id: &str, backend: &DynamicBackend) -> serde_json::Value {
+  json!({
+    "id": id,
+    "address": backend.address,
+    "weight": backend.weight,
+    "backup": backend.backup,
+    "tls": backend.tls,
+    "state": state(pool, backend),
+  })
+}
+
+fn list(pool_name: &str, config: &RuntimeConfig) -> Response<Body> {
+  let pool = find_pool(config, pool_name);
+  let backends = of_pool(pool_name)
+    .iter()
+    .map(|(id, backend)| describe(pool, id, backend))
+    .collect::<Vec<_>>();
+  json_response(StatusCode::OK, json!(backends))
+}
+
+async fn register(
+  config: &ArcSwap<RuntimeConfig>,
+  pool_name: &str,
+  id: &str,
+  mut backend: DynamicBackend,
+) -> ChangeResult {
+  let _change = CHANGES.lock().await;
+  // Replacing the settings of a backend server does not start its slow start again
+  if let Some(previous) = of_pool(pool_name).get(id).filter(|it| it.address == backend.address) {
+    backend.registered_at = previous.registered_at;
+  }
+  {
+    let current = config.load();
+    let pool = find_pool(&current, pool_name)
+      .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown pool '{}'\n", pool_name)))?;
+    let configured = pool
+      .addresses
+      .iter()
+      .any(|(address, _)| *address == backend.address && !pool.dynamic_addresses.contains(address));
+    let registered = of_pool(pool_name)
+      .iter()
+      .any(|(other_id, other)| other_id != id && other.address == backend.address);
+    if configured || registered {
+      return Err((
+        StatusCode::CONFLICT,
+        format!("The address '{}' is already part of the pool\n", backend.address),
+      ));
+    }
+    if let Some(ip) = literal_ip(&backend.address).filter(|it| !pool.address_filter.policy().permits(*it)) {
+      return Err((
+        StatusCode::FORBIDDEN,
+        format!("The address '{}' is not allowed in the pool\n", ip),
+      ));
+    }
+  }
+  let previous = set(pool_name, id, Some(backend.clone()));
+  if let Err(e) = apply(config).await {
+    set(pool_name, id, previous);
+    return Err(e);
+  }
+  info!(
+    "Registered backend server {} with the address {} in pool {}",
+    id, backend.address, pool_name
+  );
+  let status = match previous {
+    Some(_) => StatusCode::OK,
+    None => StatusCode::CREATED,
+  };
+  let current = config.load();
+  Ok(json_response(
+    status,
+    describe(find_pool(&current, pool_name), id, &backend),
+  ))
+}
+
+async fn deregister(config: &ArcSwap<RuntimeConfig>, pool_name: &str, id: &str) -> ChangeResult {
+  let _change = CHANGES.lock().await;
+  let previous =
+    set(pool_name, id, None).ok_or_else(|| (StatusCode::NOT_FOUND, format!("Unknown backend server '{}'\n", id)))?;
+  if let Err(e) = apply(config).await {
+    set(pool_name, id, Some(previous));
+    return Err(e);
+  }
+  info!("Deregistered backend server {} of pool {}", id, pool_name);
+  Ok(
+    Response::builder()
+      .status(StatusCode::NO_CONTENT)
+      .body(Body::empty())
+      .unwrap(),
+  )
+}
+
+/// Rebuilds the backend pools with the changed backend servers and persists
+/// them in the state file, so they survive a restart.
+async fn apply(config: &ArcSwap<RuntimeConfig>) -> Result<(), (StatusCode, String)> {
+  apply_dynamic_backends(config).await.map_err(|e| {
+    (
+      StatusCode::INTERNAL_SERVER_ERROR,
+      format!("Could not apply the configuration due to: {}\n", e),
+    )
+  })?;
+  if let Err(e) = state_file::save(&config.load()) {
+    warn!("Could not write the state file due to: {}", e);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use crate::configuration::read_initial_config;
+  use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+  /// Writes a configuration with the pool `name`, which matches the host
+  /// `{name}.localhost`. Tests use distinct pool names, because dynamic
+  /// backend servers are registered globally.
+  pub fn write_config(name: &str, addresses: &[&str]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("arlb-dynamic-{}-{}.toml", name, std::process::id()));
+    let config = format!(
+      r#"
+      status_token = "s3cr3t"
+
+      [[backend_pools]]
+      name = "{name}"
+      matcher = "Host('{name}.localhost')"
+      addresses = {addresses:?}
+      schemes = ["HTTP"]
+      strategy = {{ RoundRobin = {{}} }}
+      allowed_networks = ["127.0.0.0/8"]
+      "#,
+      name = name,
+      addresses = addresses,
+    );
+    fs::write(&path, config).unwrap();
+    path
+  }
+
+  fn put_request(path: &str, body: &str) -> Request<Body> {
+    Request::put(path).body(Body::from(body.to_string())).unwrap()
+  }
+
+  async fn json_body(response: Response<Body>) -> serde_json::Value {
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+  }
+
+  fn pool_addresses(config: &ArcSwap<RuntimeConfig>) -> Vec<String> {
+    let config = config.load();
+    config.shared_data.backend_pools[0]
+      .addresses
+      .iter()
+      .map(|(address, _)| address.clone())
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn test_configured_address_is_rejected() {
+    // given:
+    let path = write_config("dynamic-conflict", &["127.0.0.1:8081"]);
+    let config = read_initial_config(&path).await.unwrap();
+
+    // when:
+    let request = put_request("/pools/dynamic-conflict/backends/a", r#"{"address": "127.0.0.1:8081"}"#);
+    let configured = handle_request(request, &config).await;
+    let request = put_request("/pools/unknown/backends/a", r#"{"address": "127.0.0.1:8082"}"#);
+    let unknown_pool = handle_request(request, &config).await;
+    let request = put_request(
+      "/pools/dynamic-conflict/backends/a",
+      r#"{"address": "127.0.0.1:8082", "port": 8082}"#,
+    );
+    let unsupported_field = handle_request(request, &config).await;
+    let request = put_request(
+      "/pools/dynamic-conflict/backends/a",
+      r#"{"address": "127.0.0.1:8082", "tls": {}}"#,
+    );
+    let tls_without_name = handle_request(request, &config).await;
+    fs::remove_file(&path).unwrap();
+
+    // then:
+    assert_eq!(configured.status(), StatusCode::CONFLICT);
+    assert_eq!(unknown_pool.status(), StatusCode::NOT_FOUND);
+    assert_eq!(unsupported_field.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(tls_without_name.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(pool_addresses(&config), vec!["127.0.0.1:8081"]);
+  }
+
+  #[tokio::test]
+  async fn test_weight_and_tls_settings_are_applied() {
+    // given:
+    let path = write_config("dynamic-settings", &["127.0.0.1:8081"]);
+    let mut config = fs::read_to_string(&path).unwrap();
+    config.push_str("[backend_pools.dynamic_backends]\nslow_start_sec = 10\ngrace_period_sec = 5\n");
+    fs::write(&path, config).unwrap();
+    let config = read_initial_config(&path).await.unwrap();
+
+    // when:
+    let request = put_request(
+      "/pools/dynamic-settings/backends/a",
+      r#"{"address": "127.0.0.1:8082", "weight": 3, "tls": {"server_name": "a.internal"}}"#,
+    );
+    let registered = handle_request(request, &config).await;
+    fs::remove_file(&path).unwrap();
+
+    // then:
+    assert_eq!(registered.status(), StatusCode::CREATED);
+    let registered = json_body(registered).await;
+    assert_eq!(registered["weight"], 3);
+    assert_eq!(registered["tls"]["server_name"], "a.internal");
+    let pool = Arc::clone(&config.load().shared_data.backend_pools[0]);
+    assert!(pool.registered_at.contains_key("127.0.0.1:8082"));
+    assert_eq!(pool.slow_start, Duration::from_secs(10));
+    assert_eq!(pool.grace_period, Duration::from_secs(5));
+    let health_tls = pool.health_config.backends["127.0.0.1:8082"].tls.clone().unwrap();
+    assert_eq!(health_tls.server_name.as_deref(), Some("a.internal"));
+  }
+
+  #[tokio::test]
+  async fn test_replacing_a_backend_keeps_its_slow_start() {
+    // given:
+    let path = write_config("dynamic-replace", &["127.0.0.1:8081"]);
+    let config = read_initial_config(&path).await.unwrap();
+    let request = put_request("/pools/dynamic-replace/backends/a", r#"{"address": "127.0.0.1:8082"}"#);
+    handle_request(request, &config).await;
+    let registered_at = of_pool("dynamic-replace")["a"].registered_at;
+
+    // when:
+    let request = put_request(
+      "/pools/dynamic-replace/backends/a",
+      r#"{"address": "127.0.0.1:8082", "weight": 2}"#,
+    );
+    let replaced = handle_request(request, &config).await;
+    let request = put_request("/pools/dynamic-replace/backends/a", r#"{"address": "127.0.0.1:8083"}"#);
+    let moved = handle_request(request, &config).await;
+    fs::remove_file(&path).unwrap();
+
+    // then: only a new address starts a new slow start
+    assert_eq!(replaced.status(), StatusCode::OK);
+    assert_eq!(moved.status(), StatusCode::OK);
+    let pool = Arc::clone(&config.load().shared_data.backend_pools[0]);
+    assert!(pool.registered_at["127.0.0.1:8083"] > registered_at);
+  }
+
+  #[tokio::test]
+  async fn test_forbidden_address_is_rejected() {
+    // given: a pool, which only allows the loopback interface
+    let path = write_config("dynamic-forbidden", &["127.0.0.1:8081"]);
+    let config = read_initial_config(&path).await.unwrap();
+
+    // when:
+    let request = put_request(
+      "/pools/dynamic-forbidden/backends/a",
+      r#"{"address": "169.254.169.254:80"}"#,
+    );
+    let metadata = handle_request(request, &config).await;
+    let request = put_request(
+      "/pools/dynamic-forbidden/backends/b",
+      r#"{"address": "127.0.0.2:8082"}"#,
+    );
+    let allowed = handle_request(request, &config).await;
+    fs::remove_file(&path).unwrap();
+
+    // then:
+    assert_eq!(metadata.status(), StatusCode::FORBIDDEN);
+    assert_eq!(allowed.status(), StatusCode::CREATED);
+    assert_eq!(pool_addresses(&config), vec!["127.0.0.1:8081", "127.0.0.2:8082"]);
+  }
+
+  #[tokio::test]
+  async fn test_maintenance_is_toggled_until_the_configuration_changes_it() {
+    // given:
+    let path = write_config("dynamic-maintenance", &["127.0.0.1:8081"]);
+    let config = read_initial_config(&path).await.unwrap();
+    let in_maintenance = |config: &ArcSwap<RuntimeConfig>| config.load().shared_data.backend_pools[0].in_maintenance();
+    let request = |method: Method, pool: &str| {
+      let uri = format!("/pools/{}/maintenance", pool);
+      Request::builder().method(method).uri(uri).body(Body::empty()).unwrap()
+    };
+
+    // when:
+    let enabled = handle_request(request(Method::PUT, "dynamic-maintenance"), &config).await;
+    apply_dynamic_backends(&config).await.unwrap();
+    let kept_after_reload = in_maintenance(&config);
+    let disabled = handle_request(request(Method::DELETE, "dynamic-maintenance"), &config).await;
+    let disabled_after_delete = !in_maintenance(&config);
+    let mut file = fs::read_to_string(&path).unwrap();
+    file.push_str("maintenance = true\n");
+    fs::write(&path, file).unwrap();
+    apply_dynamic_backends(&config).await.unwrap();
+    let unknown_pool = handle_request(request(Method::PUT, "unknown"), &config).await;
+    fs::remove_file(&path).unwrap();
+
+    // then: the configuration file wins, once it changes the maintenance of the pool
+    assert_eq!(enabled.status(), StatusCode::NO_CONTENT);
+    assert!(kept_after_reload);
+    assert_eq!(disabled.status(), StatusCode::NO_CONTENT);
+    assert!(disabled_after_delete);
+    assert!(in_maintenance(&config));
+    assert_eq!(unknown_pool.status(), StatusCode::NOT_FOUND);
+  }
+
+  #[tokio::test]
+  async fn test_configuration_file_wins_after_reload() {
+    // given: a dynamic backend server, which is added to the configuration file afterwards
+    let path = write_config("dynamic-reload", &["127.0.0.1:8081"]);
+    let config = read_initial_config(&path).await.unwrap();
+    let request = put_request(
+      "/pools/dynamic-reload/backends/a",
+      r#"{"address": "127.0.0.1:8082", "backup": true}"#,
+    );
+    let registered = handle_request(request, &config).await;
+    assert_eq!(registered.status(), StatusCode::CREATED);
+    assert_eq!(pool_addresses(&config), vec!["127.0.0.1:8081", "127.0.0.1:8082"]);
+    assert!(config.load().shared_data.backend_pools[0]
+      .backup_addresses
+      .contains("127.0.0.1:8082"));
+    write_config("dynamic-reload", &["127.0.0.1:8081", "127.0.0.1:8082"]);
+
+    // when:
+    apply_dynamic_backends(&config).await.unwrap();
+    let listed = handle_request(
+      Request::get("/pools/dynamic-reload/backends")
+        .body(Body::empty())
+        .unwrap(),
+      &config,
+    )
+    .await;
+    fs::remove_file(&path).unwrap();
+
+    // then: the address is only part of the pool once and no longer a backup server
+    assert_eq!(pool_addresses(&config), vec!["127.0.0.1:8081", "127.0.0.1:8082"]);
+    let pool = Arc::clone(&config.load().shared_data.backend_pools[0]);
+    assert!(pool.backup_addresses.is_empty());
+    assert!(pool.dynamic_addresses.is_empty());
+    let listed = json_body(listed).await;
+    assert_eq!(listed[0]["id"], "a");
+    assert_eq!(listed[0]["state"], "overridden");
+  }
+}
@@ -0,0 +1,39 @@
+use std::{io, net::SocketAddr};
+use thiserror::Error;
+
+/// The ways the load balancer as a whole can fail. Errors of the individual
+/// components are mostly [`io::Error`]s, which are wrapped into the variant of
+/// the component when they reach the top level, so callers can tell the
+/// failures apart.
+#[derive(Debug, Error)]
+pub enum Error {
+  /// The configuration could not be read or is invalid.
+  #[error("Could not load configuration due to: {0}")]
+  Config(#[source] io::Error),
+  /// Changes to the configuration can no longer be observed.
+  #[error("Could not watch configuration due to: {0}")]
+  ConfigWatch(#[source] io::Error),
+  /// The certificates or keys for the TLS handshake could not be loaded.
+  #[error("Invalid TLS configuration: {0}")]
+  Tls(#[source] io::Error),
+  #[error("Could not listen on {address} due to: {source}")]
+  Listen {
+    address: String,
+    #[source]
+    source: io::Error,
+  },
+  /// A listener failed while accepting or serving connections.
+  #[error("Failed to serve requests: {0}")]
+  Serve(#[from] hyper::Error),
+  #[error(transparent)]
+  Io(#[from] io::Error),
+}
+
+impl Error {
+  pub fn listen(address: SocketAddr, source: io::Error) -> Error {
+    Error::Listen {
+      address: address.to_string(),
+      source,
+    }
+  }
+}
@@ -54,6 +54,27 @@ pub fn request_entity_to_large() -> Response<Body> {
     .unwrap()
 }
 
+pub fn gateway_timeout() -> Response<Body> {
+  Response::builder()
+    .status(StatusCode::GATEWAY_TIMEOUT)
+    .body(Body::from("504 - Gateway Timeout"))
+    .unwrap()
+}
+
+pub fn misdirected_request() -> Response<Body> {
+  Response::builder()
+    .status(StatusCode::MISDIRECTED_REQUEST)
+    .body(Body::from("421 - Misdirected Request"))
+    .unwrap()
+}
+
+pub fn service_unavailable() -> Response<Body> {
+  Response::builder()
+    .status(StatusCode::SERVICE_UNAVAILABLE)
+    .body(Body::from("503 - Service Unavailable"))
+    .unwrap()
+}
+
 pub fn too_many_requests() -> Response<Body> {
   Response::builder()
     .status(StatusCode::TOO_MANY_REQUESTS)
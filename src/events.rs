@@ -0,0 +1,297 @@
+use crate::{connections::LiveConnection, drain, metrics::METRICS, server::Scheme, tls::TlsInfo};
+use hyper::{Method, Request, StatusCode, Uri};
+use once_cell::sync::Lazy;
+use std::{
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// How many events are buffered for each subscriber. Subscribers, which fall
+/// further behind, lose the oldest events.
+const CAPACITY: usize = 1024;
+
+/// The events of all listeners, for applications embedding the load balancer.
+pub static EVENTS: Lazy<EventBus> = Lazy::new(|| EventBus {
+  sender: broadcast::channel(CAPACITY).0,
+});
+
+/// Client connections are identified by a counter, starting at 1.
+static CONNECTION_IDS: AtomicU64 = AtomicU64::new(1);
+
+/// Takes the id of a newly accepted client connection.
+pub fn next_connection_id() -> u64 {
+  CONNECTION_IDS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Something, which happened to a client connection. All events of one
+/// connection carry its id, so subscribers can correlate them.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+  ConnectionAccepted {
+    connection: u64,
+    /// The scheme of the listener, which accepted the connection.
+    listener: Scheme,
+    peer: SocketAddr,
+  },
+  TlsHandshakeCompleted {
+    connection: u64,
+    /// The server name sent by the client via SNI.
+    sni: Option<String>,
+    /// The negotiated protocol version, like `TLSv1.3`.
+    protocol: Option<String>,
+  },
+  BackendSelected {
+    connection: u64,
+    pool: String,
+    backend: String,
+    /// The name of the load balancing strategy of the pool.
+    strategy: &'static str,
+  },
+  RequestCompleted {
+    summary: RequestSummary,
+  },
+  ConnectionClosed {
+    connection: u64,
+    reason: CloseReason,
+    stats: ConnectionStats,
+  },
+}
+
+/// A request, once the head of its response was sent, like in the access log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestSummary {
+  /// `0` for requests, which were not received on a listener.
+  pub connection: u64,
+  pub method: Method,
+  pub host: String,
+  pub uri: Uri,
+  pub status: StatusCode,
+  pub duration: Duration,
+  /// The address of the backend server, unless the load balancer responded
+  /// itself.
+  pub backend: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+  /// The client or the load balancer closed the connection, for example after
+  /// `Connection: close` or an idle timeout.
+  Closed,
+  /// The connection was closed while the load balancer was draining.
+  Drained,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionStats {
+  /// The requests received on the connection.
+  pub requests: u64,
+  /// How long the connection was open.
+  pub duration: Duration,
+}
+
+/// Broadcasts [`Event`]s to all subscribers without ever waiting for them.
+pub struct EventBus {
+  sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+  /// Sends the event created by `event` to all subscribers. Without any
+  /// subscriber, the event is not even created.
+  pub fn emit(&self, event: impl FnOnce() -> Event) {
+    if self.sender.receiver_count() > 0 {
+      let _ = self.sender.send(event());
+    }
+  }
+
+  pub fn subscribe(&self) -> EventSubscriber {
+    EventSubscriber {
+      receiver: self.sender.subscribe(),
+      lagged: 0,
+    }
+  }
+}
+
+/// Receives the events emitted after it subscribed.
+pub struct EventSubscriber {
+  receiver: broadcast::Receiver<Event>,
+  lagged: u64,
+}
+
+impl EventSubscriber {
+  /// Waits for the next event. Events, which were lost because this subscriber
+  /// fell behind, are skipped and counted in [`lagged`](EventSubscriber::lagged).
+  pub async fn recv(&mut self) -> Event {
+    loop {
+      match self.receiver.recv().await {
+        Ok(event) => return event,
+        Err(RecvError::Lagged(lost)) => {
+          self.lagged += lost;
+          METRICS.add("arlb_events_lagged_total", &[], lost);
+        }
+        // The sender is static, so it is never dropped
+        Err(RecvError::Closed) => return futures::future::pending().await,
+      }
+    }
+  }
+
+  /// How many events this subscriber lost so far.
+  pub fn lagged(&self) -> u64 {
+    self.lagged
+  }
+}
+
+/// Identifies the connection of a request in its extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionId(pub u64);
+
+impl ConnectionId {
+  pub fn of<B>(request: &Request<B>) -> u64 {
+    request
+      .extensions()
+      .get::<ConnectionId>()
+      .map(|it| it.0)
+      .unwrap_or_default()
+  }
+}
+
+/// Emits the events of a client connection, which is served by the wrapped
+/// service. Hyper drops the service of a connection once it is closed.
+pub struct Observed<S> {
+  service: S,
+  connection: u64,
+  live: Option<Arc<LiveConnection>>,
+  opened_at: Instant,
+  requests: u64,
+}
+
+impl<S> Observed<S> {
+  /// Uses the id of the `live` connection, if it is registered in
+  /// [`CONNECTIONS`](crate::connections::CONNECTIONS).
+  pub fn new(
+    service: S,
+    listener: Scheme,
+    peer: SocketAddr,
+    tls_info: Option<&TlsInfo>,
+    live: Option<Arc<LiveConnection>>,
+  ) -> Observed<S> {
+    let connection = live.as_ref().map_or_else(next_connection_id, |it| it.id);
+    EVENTS.emit(|| Event::ConnectionAccepted {
+      connection,
+      listener,
+      peer,
+    });
+    if let Some(tls_info) = tls_info {
+      EVENTS.emit(|| Event::TlsHandshakeCompleted {
+        connection,
+        sni: tls_info.server_name.clone(),
+        protocol: tls_info.protocol_version.clone(),
+      });
+    }
+    Observed {
+      service,
+      connection,
+      live,
+      opened_at: Instant::now(),
+      requests: 0,
+    }
+  }
+}
+
+impl<S> Drop for Observed<S> {
+  fn drop(&mut self) {
+    let connection = self.connection;
+    let stats = ConnectionStats {
+      requests: self.requests,
+      duration: self.opened_at.elapsed(),
+    };
+    EVENTS.emit(|| Event::ConnectionClosed {
+      connection,
+      reason: if drain::is_draining() {
+        CloseReason::Drained
+      } else {
+        CloseReason::Closed
+      },
+      stats,
+    });
+  }
+}
+
+impl<S: hyper::service::Service<Request<B>>, B> hyper::service::Service<Request<B>> for Observed<S> {
+  type Response = S::Response;
+  type Error = S::Error;
+  type Future = S::Future;
+
+  fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(cx)
+  }
+
+  fn call(&mut self, mut request: Request<B>) -> Self::Future {
+    self.requests += 1;
+    request.extensions_mut().insert(ConnectionId(self.connection));
+    if let Some(live) = &self.live {
+      request.extensions_mut().insert(live.clone());
+    }
+    self.service.call(request)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::FutureExt;
+
+  fn accepted(id: u64) -> Event {
+    Event::ConnectionAccepted {
+      connection: id,
+      listener: Scheme::HTTP,
+      peer: "127.0.0.1:1".parse().unwrap(),
+    }
+  }
+
+  #[test]
+  fn test_events_are_not_created_without_subscribers() {
+    // given:
+    let bus = EventBus {
+      sender: broadcast::channel(4).0,
+    };
+    let mut created = false;
+
+    // when:
+    bus.emit(|| {
+      created = true;
+      accepted(1)
+    });
+
+    // then:
+    assert!(!created);
+  }
+
+  #[tokio::test]
+  async fn test_lagging_subscribers_lose_events() {
+    // given:
+    let bus = EventBus {
+      sender: broadcast::channel(4).0,
+    };
+    let mut subscriber = bus.subscribe();
+
+    // when: the subscriber falls 2 events behind
+    for id in 1..=6 {
+      bus.emit(|| accepted(id));
+    }
+    let first = subscriber.recv().await;
+
+    // then:
+    assert_eq!(first, accepted(3));
+    assert_eq!(subscriber.lagged(), 2);
+    for id in 4..=6 {
+      assert_eq!(subscriber.recv().now_or_never(), Some(accepted(id)));
+    }
+    assert_eq!(subscriber.recv().now_or_never(), None);
+  }
+}
@@ -0,0 +1,110 @@
+use futures::stream::poll_fn;
+use hyper::{
+  body::HttpBody,
+  header::{HeaderValue, CONNECTION, CONTENT_LENGTH, EXPECT},
+  Body, Request, Response, Version,
+};
+use std::{
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::Poll,
+};
+
+/// The body of a request, which expects `100 Continue`, as long as nobody
+/// read it.
+///
+/// hyper invites the client to send the body with `100 Continue` as soon as
+/// the body is read, which is when it is forwarded to a backend server, but
+/// also when it is dropped before the response was written. The latter would
+/// make clients upload their body for a response of the load balancer itself,
+/// like a redirect or `503 Service Unavailable`, only to discard it. So the
+/// body is kept until the response was written.
+pub struct UnreadBody(Arc<Mutex<Option<Body>>>);
+
+/// Whether the client of `request` waits for `100 Continue` before it sends
+/// the body.
+fn expects_continue(request: &Request<Body>) -> bool {
+  request.version() == Version::HTTP_11
+    && !request.body().is_end_stream()
+    && request
+      .headers()
+      .get(EXPECT)
+      .map(|it| it.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+      .unwrap_or(false)
+}
+
+/// Replaces the body of `request`, if it expects `100 Continue`, so it can be
+/// told afterwards, whether it was read. The content length is kept in the
+/// headers, but trailers of the request are dropped.
+pub fn track(request: Request<Body>) -> (Request<Body>, Option<UnreadBody>) {
+  if !expects_continue(&request) {
+    return (request, None);
+  }
+  let (parts, body) = request.into_parts();
+  let unread = Arc::new(Mutex::new(Some(body)));
+  let slot = unread.clone();
+  let mut body = None;
+  let stream = poll_fn(move |cx| {
+    if body.is_none() {
+      body = slot.lock().unwrap().take();
+    }
+    match &mut body {
+      Some(body) => Pin::new(body).poll_data(cx),
+      None => Poll::Ready(None),
+    }
+  });
+  (
+    Request::from_parts(parts, Body::wrap_stream(stream)),
+    Some(UnreadBody(unread)),
+  )
+}
+
+impl UnreadBody {
+  /// Keeps the request body until `response` was written, unless it was read.
+  /// The connection is closed afterwards, because the client did not send the
+  /// body, which would precede the next request.
+  pub fn withhold(self, response: Response<Body>) -> Response<Body> {
+    let mut unread = match self.0.lock().unwrap().take() {
+      Some(unread) => Some(unread),
+      None => return response,
+    };
+    let (mut parts, mut body) = response.into_parts();
+    parts.headers.insert(CONNECTION, HeaderValue::from_static("close"));
+    if let Some(len) = body.size_hint().exact() {
+      parts.headers.entry(CONTENT_LENGTH).or_insert_with(|| len.into());
+    }
+    let stream = poll_fn(move |cx| {
+      let chunk = futures::ready!(Pin::new(&mut body).poll_data(cx));
+      if chunk.is_none() {
+        unread.take();
+      }
+      Poll::Ready(chunk)
+    });
+    Response::from_parts(parts, Body::wrap_stream(stream))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_expects_continue() {
+    let request = |version: Version, expect: &str, body: &'static str| {
+      Request::put("/upload")
+        .version(version)
+        .header(EXPECT, expect)
+        .body(Body::from(body))
+        .unwrap()
+    };
+
+    assert!(expects_continue(&request(Version::HTTP_11, "100-Continue", "upload")));
+    assert!(!expects_continue(&request(Version::HTTP_11, "100-continue", "")));
+    assert!(!expects_continue(&request(Version::HTTP_10, "100-continue", "upload")));
+    assert!(!expects_continue(&request(
+      Version::HTTP_11,
+      "something-else",
+      "upload"
+    )));
+  }
+}
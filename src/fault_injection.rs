@@ -0,0 +1,307 @@
+use crate::{
+  metrics::METRICS,
+  middleware::{request_id::RequestIdentifier, Context, Middleware, MiddlewareChain},
+  static_response::LocalResponse,
+};
+use async_trait::async_trait;
+use hyper::{
+  body::{Bytes, HttpBody},
+  header::CONTENT_TYPE,
+  Body, Method, Request, Response, StatusCode,
+};
+use log::info;
+use rand::Rng;
+use serde_json::json;
+use std::{
+  sync::atomic::{AtomicBool, Ordering},
+  time::Duration,
+};
+
+/// Whether the configured faults are injected. The admin API pauses them
+/// without a reload, for example when an experiment went wrong.
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+pub fn enabled() -> bool {
+  ENABLED.load(Ordering::Relaxed)
+}
+
+/// Adds a delay between `min` and `max` to `percent` of the requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DelayFault {
+  pub percent: f64,
+  pub min: Duration,
+  pub max: Duration,
+}
+
+/// Answers `percent` of the requests with `status` instead of forwarding them.
+/// Connections of TCP routes are reset instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AbortFault {
+  pub percent: f64,
+  pub status: StatusCode,
+}
+
+/// Sends `percent` of the responses with at most `bytes_per_sec`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThrottleFault {
+  pub percent: f64,
+  pub bytes_per_sec: u64,
+}
+
+/// The faults injected into the requests of a pool or the connections of a
+/// TCP route, to test how clients behave behind a degraded load balancer.
+/// Faults with 0 percent are not configured at all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultInjectionConfig {
+  pub delay: Option<DelayFault>,
+  pub abort: Option<AbortFault>,
+  pub throttle: Option<ThrottleFault>,
+}
+
+/// The faults decided for one request or connection.
+#[derive(Debug, Default, PartialEq)]
+pub struct Faults {
+  pub delay: Option<Duration>,
+  pub abort: Option<StatusCode>,
+  pub throttle: Option<u64>,
+}
+
+fn hits<R: Rng>(rng: &mut R, percent: f64) -> bool {
+  rng.gen_bool((percent / 100.0).min(1.0))
+}
+
+impl FaultInjectionConfig {
+  /// Decides independently, which of the faults apply.
+  pub fn decide<R: Rng>(&self, rng: &mut R) -> Faults {
+    Faults {
+      delay: self
+        .delay
+        .as_ref()
+        .filter(|it| hits(rng, it.percent))
+        .map(|it| rng.gen_range(it.min..=it.max)),
+      abort: self
+        .abort
+        .as_ref()
+        .filter(|it| hits(rng, it.percent))
+        .map(|it| it.status),
+      throttle: self
+        .throttle
+        .as_ref()
+        .filter(|it| hits(rng, it.percent))
+        .map(|it| it.bytes_per_sec),
+    }
+  }
+}
+
+/// Injects the faults of a pool. It is the last middleware of the pool, so
+/// the faults apply to each attempt to forward a request, including retries.
+#[derive(Debug)]
+pub struct FaultInjection {
+  pool: String,
+  config: FaultInjectionConfig,
+}
+
+impl FaultInjection {
+  pub fn new(pool: String, config: FaultInjectionConfig) -> FaultInjection {
+    FaultInjection { pool, config }
+  }
+
+  fn record(&self, fault: &str) {
+    METRICS.increment("arlb_injected_faults_total", &[("pool", &self.pool), ("fault", fault)]);
+  }
+}
+
+#[async_trait]
+impl Middleware for FaultInjection {
+  async fn forward_request(
+    &self,
+    request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    if !enabled() {
+      return chain.forward_request(request, context).await;
+    }
+    let faults = self.config.decide(&mut rand::thread_rng());
+    if faults == Faults::default() {
+      return chain.forward_request(request, context).await;
+    }
+    let request_id = request
+      .extensions()
+      .get::<RequestIdentifier>()
+      .map(ToString::to_string)
+      .unwrap_or_else(|| "-".to_string());
+    if let Some(delay) = faults.delay {
+      self.record("delay");
+      info!(
+        "Injected a delay of {}ms into request {} of pool '{}'",
+        delay.as_millis(),
+        request_id,
+        self.pool
+      );
+      tokio::time::sleep(delay).await;
+    }
+    if let Some(status) = faults.abort {
+      self.record("abort");
+      info!(
+        "Injected an abort with status {} into request {} of pool '{}'",
+        status.as_u16(),
+        request_id,
+        self.pool
+      );
+      let mut response = Response::builder().status(status).body(Body::empty()).unwrap();
+      response.extensions_mut().insert(LocalResponse);
+      return response;
+    }
+    let response = chain.forward_request(request, context).await;
+    match faults.throttle {
+      Some(bytes_per_sec) => {
+        self.record("throttle");
+        info!(
+          "Injected a throttle of {} bytes per second into the response of request {} of pool '{}'",
+          bytes_per_sec, request_id, self.pool
+        );
+        throttle(response, bytes_per_sec)
+      }
+      None => response,
+    }
+  }
+}
+
+/// Sends the body of `response` in pieces of a tenth of a second at
+/// `bytes_per_sec`.
+fn throttle(response: Response<Body>, bytes_per_sec: u64) -> Response<Body> {
+  let (parts, body) = response.into_parts();
+  let piece_len = (bytes_per_sec / 10).max(1) as usize;
+  let body = futures::stream::unfold((body, Bytes::new()), move |(mut body, mut pending)| async move {
+    if pending.is_empty() {
+      match body.data().await? {
+        Ok(chunk) => pending = chunk,
+        Err(e) => return Some((Err(e), (body, pending))),
+      }
+    }
+    let piece = pending.split_to(piece_len.min(pending.len()));
+    tokio::time::sleep(Duration::from_secs_f64(piece.len() as f64 / bytes_per_sec as f64)).await;
+    Some((Ok(piece), (body, pending)))
+  });
+  Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// Handles `GET`, `PUT` (resumes) and `DELETE` (pauses) `/fault_injection`.
+pub fn handle_request(request: &Request<Body>) -> Response<Body> {
+  match *request.method() {
+    Method::GET => {}
+    Method::PUT => {
+      ENABLED.store(true, Ordering::Relaxed);
+      info!("Resumed fault injection");
+    }
+    Method::DELETE => {
+      ENABLED.store(false, Ordering::Relaxed);
+      info!("Paused fault injection");
+    }
+    _ => {
+      return Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .body(Body::empty())
+        .unwrap()
+    }
+  }
+  Response::builder()
+    .header(CONTENT_TYPE, "application/json")
+    .body(Body::from(json!({ "enabled": enabled() }).to_string()))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::{rngs::StdRng, SeedableRng};
+
+  const SAMPLES: usize = 10_000;
+
+  fn faults(delay: Option<DelayFault>, abort: Option<AbortFault>) -> FaultInjectionConfig {
+    FaultInjectionConfig {
+      delay,
+      abort,
+      throttle: None,
+    }
+  }
+
+  #[test]
+  fn test_delays_are_spread_between_min_and_max() {
+    // given:
+    let config = faults(
+      Some(DelayFault {
+        percent: 50.0,
+        min: Duration::from_millis(100),
+        max: Duration::from_millis(300),
+      }),
+      None,
+    );
+    let mut rng = StdRng::seed_from_u64(7);
+
+    // when:
+    let delays = (0..SAMPLES)
+      .filter_map(|_| config.decide(&mut rng).delay)
+      .collect::<Vec<_>>();
+
+    // then:
+    assert!((4_500..5_500).contains(&delays.len()), "{}", delays.len());
+    assert!(delays
+      .iter()
+      .all(|it| *it >= Duration::from_millis(100) && *it <= Duration::from_millis(300)));
+    let mean = delays.iter().sum::<Duration>() / delays.len() as u32;
+    assert!(
+      mean > Duration::from_millis(190) && mean < Duration::from_millis(210),
+      "{:?}",
+      mean
+    );
+    let below_150 = delays.iter().filter(|it| **it < Duration::from_millis(150)).count();
+    assert!((1_000..1_500).contains(&below_150), "{}", below_150);
+  }
+
+  #[test]
+  fn test_aborts_match_their_percentage() {
+    // given:
+    let config = faults(
+      None,
+      Some(AbortFault {
+        percent: 20.0,
+        status: StatusCode::SERVICE_UNAVAILABLE,
+      }),
+    );
+    let mut rng = StdRng::seed_from_u64(7);
+
+    // when:
+    let aborts = (0..SAMPLES)
+      .filter(|_| config.decide(&mut rng).abort == Some(StatusCode::SERVICE_UNAVAILABLE))
+      .count();
+
+    // then:
+    assert!((1_800..2_200).contains(&aborts), "{}", aborts);
+  }
+
+  #[test]
+  fn test_no_faults_without_configured_faults() {
+    let config = faults(None, None);
+    let mut rng = StdRng::seed_from_u64(7);
+
+    assert!((0..SAMPLES).all(|_| config.decide(&mut rng) == Faults::default()));
+  }
+
+  #[tokio::test]
+  async fn test_throttled_body_is_complete() {
+    // given:
+    let response = Response::new(Body::from(vec![b'x'; 300]));
+
+    // when:
+    let start = tokio::time::Instant::now();
+    let body = hyper::body::to_bytes(throttle(response, 1000).into_body())
+      .await
+      .unwrap();
+
+    // then: 300 bytes take 300ms at 1000 bytes per second
+    assert_eq!(body.len(), 300);
+    assert!(start.elapsed() >= Duration::from_millis(250), "{:?}", start.elapsed());
+  }
+}
@@ -0,0 +1,130 @@
+use crate::metrics::METRICS;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::{
+  collections::HashMap,
+  io,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+/// Running out of file descriptors affects every connection at once, so it is
+/// only logged once per interval and operation.
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+static WARNINGS: Lazy<Mutex<HashMap<&'static str, RateLimitedWarning>>> = Lazy::new(Default::default);
+
+/// Whether the `error` means that the process (`EMFILE`) or the whole system
+/// (`ENFILE`) has no file descriptors left.
+pub(crate) fn is_exhausted(error: &io::Error) -> bool {
+  matches!(error.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+/// Counts the `error` by `arlb_fd_exhaustion_total{operation}` and logs a
+/// warning, unless one was logged for the `operation` recently. Other errors
+/// are ignored.
+pub(crate) fn record_exhaustion(operation: &'static str, error: &io::Error) {
+  if !is_exhausted(error) {
+    return;
+  }
+  METRICS.increment("arlb_fd_exhaustion_total", &[("operation", operation)]);
+  let suppressed = WARNINGS
+    .lock()
+    .unwrap()
+    .entry(operation)
+    .or_default()
+    .register(Instant::now());
+  if let Some(suppressed) = suppressed {
+    warn!(
+      "No file descriptors are left to {} ({} more times in the last {:?}): {}",
+      operation, suppressed, WARNING_INTERVAL, error
+    );
+  }
+}
+
+/// Logs the number of open file descriptors and the limit of the process, so
+/// a limit, which is too low for the expected number of connections, is
+/// noticed before it is reached.
+pub(crate) fn log_limit() {
+  #[cfg(target_os = "linux")]
+  {
+    let mut limit = libc::rlimit {
+      rlim_cur: 0,
+      rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+      warn!(
+        "Could not read the file descriptor limit: {}",
+        io::Error::last_os_error()
+      );
+      return;
+    }
+    let open = std::fs::read_dir("/proc/self/fd").map(|it| it.count()).unwrap_or(0);
+    info!(
+      "{} file descriptors are open, the limit is {} (at most {})",
+      open, limit.rlim_cur, limit.rlim_max
+    );
+  }
+}
+
+#[derive(Default)]
+struct RateLimitedWarning {
+  last: Option<Instant>,
+  suppressed: u64,
+}
+
+impl RateLimitedWarning {
+  /// Returns the number of suppressed warnings since the last one, if a
+  /// warning should be logged `now`.
+  fn register(&mut self, now: Instant) -> Option<u64> {
+    match self.last {
+      Some(last) if now.duration_since(last) < WARNING_INTERVAL => {
+        self.suppressed += 1;
+        None
+      }
+      _ => {
+        self.last = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_warnings_are_rate_limited() {
+    // given:
+    let mut warning = RateLimitedWarning::default();
+    let start = Instant::now();
+
+    // when:
+    let first = warning.register(start);
+    let second = warning.register(start + Duration::from_secs(1));
+    let third = warning.register(start + Duration::from_secs(2));
+    let after_interval = warning.register(start + WARNING_INTERVAL);
+
+    // then:
+    assert_eq!(first, Some(0));
+    assert_eq!(second, None);
+    assert_eq!(third, None);
+    assert_eq!(after_interval, Some(2));
+  }
+
+  #[test]
+  fn test_only_exhaustion_is_recorded() {
+    // given:
+    let exhaustion = || METRICS.counter("arlb_fd_exhaustion_total", &[("operation", "test")]);
+    let before = exhaustion();
+
+    // when:
+    record_exhaustion("test", &io::Error::from_raw_os_error(libc::EMFILE));
+    record_exhaustion("test", &io::Error::from_raw_os_error(libc::ENFILE));
+    record_exhaustion("test", &io::Error::from(io::ErrorKind::ConnectionRefused));
+
+    // then:
+    assert_eq!(exhaustion(), before + 2);
+  }
+}
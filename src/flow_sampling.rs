@@ -0,0 +1,377 @@
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+use log::info;
+use serde_json::json;
+use std::{
+  fmt::{self, Write},
+  io,
+  pin::Pin,
+  sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+  task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// The target of the log entries of sampled reads.
+pub const FLOW_LOG_TARGET: &str = "another_rust_load_balancer::flow";
+
+/// How the relayed connections (like of TCP routers) are sampled. Sampling is
+/// disabled by default and can be enabled briefly via the admin API.
+pub static FLOW_SAMPLING: FlowSampling = FlowSampling::new();
+
+/// Connections are identified by a counter in log entries.
+static CONNECTION_IDS: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingConfig {
+  /// Samples one in `every` reads of a connection.
+  pub every: usize,
+  /// The most bytes, which are logged of one read.
+  pub bytes: usize,
+  /// The most bytes, which are logged of one connection and direction.
+  pub max_bytes: usize,
+}
+
+impl Default for SamplingConfig {
+  fn default() -> Self {
+    SamplingConfig {
+      every: 100,
+      bytes: 64,
+      max_bytes: 4096,
+    }
+  }
+}
+
+/// The current [`SamplingConfig`]. It is stored in atomics, so reads with
+/// disabled sampling only pay for a single atomic load.
+pub struct FlowSampling {
+  /// `0` while sampling is disabled.
+  every: AtomicUsize,
+  bytes: AtomicUsize,
+  max_bytes: AtomicUsize,
+}
+
+impl FlowSampling {
+  const fn new() -> FlowSampling {
+    FlowSampling {
+      every: AtomicUsize::new(0),
+      bytes: AtomicUsize::new(0),
+      max_bytes: AtomicUsize::new(0),
+    }
+  }
+
+  /// The current configuration, if sampling is enabled.
+  pub fn get(&self) -> Option<SamplingConfig> {
+    match self.every.load(Ordering::Relaxed) {
+      0 => None,
+      every => Some(SamplingConfig {
+        every,
+        bytes: self.bytes.load(Ordering::Relaxed),
+        max_bytes: self.max_bytes.load(Ordering::Relaxed),
+      }),
+    }
+  }
+
+  /// Enables sampling with `config`, or disables it with `None`.
+  pub fn set(&self, config: Option<SamplingConfig>) {
+    match config {
+      Some(config) => {
+        self.bytes.store(config.bytes, Ordering::Relaxed);
+        self.max_bytes.store(config.max_bytes, Ordering::Relaxed);
+        self.every.store(config.every.max(1), Ordering::Relaxed);
+      }
+      None => self.every.store(0, Ordering::Relaxed),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+  /// From the client to the backend server.
+  Upstream,
+  /// From the backend server to the client.
+  Downstream,
+}
+
+impl fmt::Display for Direction {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Direction::Upstream => write!(f, "client -> backend"),
+      Direction::Downstream => write!(f, "backend -> client"),
+    }
+  }
+}
+
+/// Decides which reads of one direction of a connection are sampled.
+#[derive(Debug)]
+struct Sampler {
+  connection_id: u64,
+  direction: Direction,
+  /// The reads to skip until the next one is sampled.
+  skipped_reads: usize,
+  sampled_bytes: usize,
+}
+
+impl Sampler {
+  /// Returns the part of `data`, which is logged, if this read is sampled.
+  fn sample<'d>(&mut self, data: &'d [u8], config: SamplingConfig) -> Option<&'d [u8]> {
+    if self.skipped_reads > 0 {
+      self.skipped_reads -= 1;
+      return None;
+    }
+    self.skipped_reads = config.every - 1;
+    if self.sampled_bytes >= config.max_bytes {
+      return None;
+    }
+    let len = data.len().min(config.bytes).min(config.max_bytes - self.sampled_bytes);
+    self.sampled_bytes += len;
+    Some(&data[..len])
+  }
+}
+
+/// Formats `data` like `hexdump -C`, 16 bytes per line.
+fn hex_dump(data: &[u8]) -> String {
+  let mut dump = String::new();
+  for (index, line) in data.chunks(16).enumerate() {
+    let _ = write!(dump, "\n{:08x} ", index * 16);
+    for (column, byte) in line.iter().enumerate() {
+      let separator = if column == 8 { "  " } else { " " };
+      let _ = write!(dump, "{}{:02x}", separator, byte);
+    }
+    let padding = (16 - line.len()) * 3 + usize::from(line.len() <= 8);
+    let _ = write!(dump, "{:padding$}  |", "", padding = padding);
+    dump.extend(line.iter().map(|&byte| {
+      if byte.is_ascii_graphic() || byte == b' ' {
+        byte as char
+      } else {
+        '.'
+      }
+    }));
+    dump.push('|');
+  }
+  dump
+}
+
+/// Logs samples of the data read from `inner` according to [`FLOW_SAMPLING`].
+pub struct Sampled<R> {
+  inner: R,
+  sampling: &'static FlowSampling,
+  sampler: Sampler,
+}
+
+impl<R> Sampled<R> {
+  fn new(inner: R, sampling: &'static FlowSampling, connection_id: u64, direction: Direction) -> Sampled<R> {
+    Sampled {
+      inner,
+      sampling,
+      sampler: Sampler {
+        connection_id,
+        direction,
+        skipped_reads: 0,
+        sampled_bytes: 0,
+      },
+    }
+  }
+}
+
+/// Wraps both directions of a connection, so they are logged with the same id.
+pub fn sample_connection<U, D>(upstream: U, downstream: D) -> (Sampled<U>, Sampled<D>) {
+  let connection_id = CONNECTION_IDS.fetch_add(1, Ordering::Relaxed);
+  (
+    Sampled::new(upstream, &FLOW_SAMPLING, connection_id, Direction::Upstream),
+    Sampled::new(downstream, &FLOW_SAMPLING, connection_id, Direction::Downstream),
+  )
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Sampled<R> {
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let filled = buf.filled().len();
+    let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+    if let Some(config) = self.sampling.get() {
+      let data = &buf.filled()[filled..];
+      if !data.is_empty() {
+        let sampler = &mut self.sampler;
+        if let Some(sample) = sampler.sample(data, config) {
+          info!(
+            target: FLOW_LOG_TARGET,
+            "Connection {} ({}) read {} bytes, sampled {}:{}",
+            sampler.connection_id,
+            sampler.direction,
+            data.len(),
+            sample.len(),
+            hex_dump(sample)
+          );
+        }
+      }
+    }
+    result
+  }
+}
+
+fn describe(config: Option<SamplingConfig>) -> serde_json::Value {
+  match config {
+    Some(config) => json!({
+      "enabled": true,
+      "every": config.every,
+      "bytes": config.bytes,
+      "max_bytes": config.max_bytes,
+    }),
+    None => json!({ "enabled": false }),
+  }
+}
+
+/// Handles `GET`, `PUT` and `DELETE` of `/flow_sampling`. `PUT` enables
+/// sampling and accepts the query parameters `every`, `bytes` and `max_bytes`.
+pub fn handle_request(request: &Request<Body>) -> Response<Body> {
+  let result = match *request.method() {
+    Method::GET => Ok(FLOW_SAMPLING.get()),
+    Method::PUT => parse_config(request.uri().query().unwrap_or_default()).map(|config| {
+      FLOW_SAMPLING.set(Some(config));
+      info!(
+        "Enabled flow sampling of one in {} reads, up to {} bytes per read and {} bytes per connection",
+        config.every, config.bytes, config.max_bytes
+      );
+      Some(config)
+    }),
+    Method::DELETE => {
+      FLOW_SAMPLING.set(None);
+      info!("Disabled flow sampling");
+      Ok(None)
+    }
+    _ => Err((StatusCode::METHOD_NOT_ALLOWED, String::new())),
+  };
+  match result {
+    Ok(config) => Response::builder()
+      .header(CONTENT_TYPE, "application/json")
+      .body(Body::from(describe(config).to_string()))
+      .unwrap(),
+    Err((status, message)) => Response::builder().status(status).body(Body::from(message)).unwrap(),
+  }
+}
+
+fn parse_config(query: &str) -> Result<SamplingConfig, (StatusCode, String)> {
+  let mut config = SamplingConfig::default();
+  for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+    let field = match key.as_ref() {
+      "every" => &mut config.every,
+      "bytes" => &mut config.bytes,
+      "max_bytes" => &mut config.max_bytes,
+      key => {
+        return Err((
+          StatusCode::BAD_REQUEST,
+          format!("Unsupported parameter {}={}\n", key, value),
+        ))
+      }
+    };
+    *field = match value.parse() {
+      Ok(value) if value > 0 => value,
+      _ => return Err((StatusCode::BAD_REQUEST, format!("Invalid {} '{}'\n", key, value))),
+    };
+  }
+  Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::FutureExt;
+  use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+  };
+  use tokio::io::AsyncReadExt;
+
+  /// Counts the allocations of each thread, so tests running in parallel do
+  /// not affect each other.
+  struct CountingAllocator;
+
+  thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+  }
+
+  unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+      let _ = ALLOCATIONS.try_with(|it| it.set(it.get() + 1));
+      System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+      System.dealloc(ptr, layout)
+    }
+  }
+
+  #[global_allocator]
+  static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+  fn sampler() -> Sampler {
+    Sampler {
+      connection_id: 1,
+      direction: Direction::Upstream,
+      skipped_reads: 0,
+      sampled_bytes: 0,
+    }
+  }
+
+  #[test]
+  fn test_sampler_honors_connection_cap() {
+    // given:
+    let config = SamplingConfig {
+      every: 2,
+      bytes: 16,
+      max_bytes: 40,
+    };
+    let mut sampler = sampler();
+    let data = [b'a'; 100];
+
+    // when:
+    let sampled = (0..10)
+      .map(|_| sampler.sample(&data, config).map(<[u8]>::len))
+      .collect::<Vec<_>>();
+
+    // then: 16 + 16 + 8 bytes of the reads 1, 3 and 5
+    assert_eq!(
+      sampled,
+      vec![Some(16), None, Some(16), None, Some(8), None, None, None, None, None]
+    );
+    assert_eq!(sampler.sampled_bytes, 40);
+  }
+
+  #[test]
+  fn test_hex_dump() {
+    assert_eq!(
+      hex_dump(b"GET / HTTP/1.1\r\nHost"),
+      "\n00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|\
+       \n00000010  48 6f 73 74                                       |Host|"
+    );
+  }
+
+  #[test]
+  fn test_disabled_sampling_does_not_allocate() {
+    // given:
+    static DISABLED: FlowSampling = FlowSampling::new();
+    let data = [0xffu8; 4096];
+    let mut reader = Sampled::new(&data[..], &DISABLED, 1, Direction::Downstream);
+    let mut buffer = [0; 1024];
+
+    // when:
+    let before = ALLOCATIONS.with(|it| it.get());
+    let read = reader.read(&mut buffer).now_or_never().unwrap().unwrap();
+    let after = ALLOCATIONS.with(|it| it.get());
+
+    // then:
+    assert_eq!(read, 1024);
+    assert_eq!(after, before);
+  }
+
+  #[test]
+  fn test_parse_config() {
+    assert_eq!(
+      parse_config("every=10&max_bytes=100"),
+      Ok(SamplingConfig {
+        every: 10,
+        bytes: 64,
+        max_bytes: 100,
+      })
+    );
+    assert!(parse_config("every=0").is_err());
+    assert!(parse_config("rate=1").is_err());
+  }
+}
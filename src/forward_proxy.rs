@@ -0,0 +1,499 @@
+use crate::{
+  address_policy::AddressPolicy,
+  dns::CachingResolver,
+  file_descriptors,
+  listeners::{bind_with_backlog, AcceptRetry},
+  logging::ACCESS_LOG_TARGET,
+  metrics::METRICS,
+  middleware::local_authentication::verify_basic_credentials,
+  tls_passthrough::splice,
+};
+use hyper::{
+  client::connect::dns::{GaiResolver, Name},
+  header::{PROXY_AUTHENTICATE, PROXY_AUTHORIZATION},
+  server::conn::Http,
+  service::{service_fn, Service},
+  Body, Method, Request, Response, StatusCode,
+};
+use log::{debug, info};
+use std::{
+  collections::HashMap,
+  convert::Infallible,
+  io,
+  net::{IpAddr, SocketAddr},
+  str::FromStr,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use tokio::{
+  net::{TcpListener, TcpStream},
+  time::timeout,
+};
+
+/// A listener for the `CONNECT` method of HTTP forward proxies, which tunnels
+/// connections of clients to allowed destinations, like an egress proxy.
+#[derive(Debug)]
+pub struct ForwardProxyService {
+  pub listen_address: SocketAddr,
+  /// Destinations, which none of these match, are denied.
+  pub allowed_targets: Vec<TargetPattern>,
+  /// Whether destinations may resolve to addresses of private networks (like
+  /// `10.0.0.0/8` or the loopback interface). Otherwise clients could reach
+  /// internal servers through the tunnel.
+  pub allow_private_networks: bool,
+  /// Even with `allow_private_networks` destinations must not resolve to the
+  /// loopback interface, link-local or cloud metadata addresses, unless the
+  /// policy allows them.
+  pub address_policy: AddressPolicy,
+  /// The bcrypt hashes of the passwords by user. Clients only need to
+  /// authenticate, if there are any.
+  pub users: HashMap<String, String>,
+  pub connect_timeout: Duration,
+  /// Limits the bytes per second of each direction of a tunnel.
+  pub max_connection_bytes_per_sec: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TargetPattern {
+  /// A host name or IP address, or a wildcard like `*.example.com` (matching
+  /// a single label).
+  pub host: String,
+  /// Matches all ports if `None`.
+  pub port: Option<u16>,
+}
+
+impl TargetPattern {
+  fn matches(&self, host: &str, port: u16) -> bool {
+    let host_matches = match self.host.strip_prefix("*.") {
+      Some(domain) => matches!(host.split_once('.'), Some((_, parent)) if parent.eq_ignore_ascii_case(domain)),
+      None => self.host.eq_ignore_ascii_case(host),
+    };
+    host_matches && self.port.iter().all(|it| *it == port)
+  }
+}
+
+impl FromStr for TargetPattern {
+  type Err = String;
+
+  /// Parses patterns like `example.com:443`, `*.example.com:443` or
+  /// `example.com:*`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (host, port) = s
+      .rsplit_once(':')
+      .ok_or_else(|| format!("Expected 'host:port' in '{}'", s))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let domain = host.strip_prefix("*.").unwrap_or(host);
+    if domain.is_empty() {
+      return Err(format!("The host of '{}' is empty", s));
+    }
+    if domain.contains('*') {
+      return Err(format!(
+        "Only wildcards like '*.example.com' are supported, got '{}'",
+        s
+      ));
+    }
+    let port = match port {
+      "*" => None,
+      port => Some(port.parse().map_err(|_| format!("Invalid port in '{}'", s))?),
+    };
+    Ok(TargetPattern {
+      host: host.to_string(),
+      port,
+    })
+  }
+}
+
+pub struct ForwardProxy {
+  listener: TcpListener,
+  service: Arc<ForwardProxyService>,
+}
+
+impl ForwardProxy {
+  pub async fn bind(service: Arc<ForwardProxyService>, backlog: u32) -> io::Result<ForwardProxy> {
+    let listener = bind_with_backlog(service.listen_address, backlog)?;
+    info!("Started listening for CONNECT requests on {}", listener.local_addr()?);
+    Ok(ForwardProxy { listener, service })
+  }
+
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.listener.local_addr()
+  }
+
+  /// Accepts connections and serves each of them in a separate task.
+  pub async fn run(self) -> io::Result<()> {
+    let mut retry = AcceptRetry::new();
+    loop {
+      let (socket, peer) = retry.accept(|| self.listener.accept()).await?;
+      let service = self.service.clone();
+      tokio::spawn(async move {
+        let handler = service_fn(move |request| {
+          let service = service.clone();
+          async move { Ok::<_, Infallible>(handle(&service, request, peer).await) }
+        });
+        let connection = Http::new()
+          .http1_only(true)
+          .serve_connection(socket, handler)
+          .with_upgrades();
+        if let Err(e) = connection.await {
+          debug!("Could not serve CONNECT requests of {}: {}", peer, e);
+        }
+      });
+    }
+  }
+}
+
+/// Connects to the target of the CONNECT `request` and tunnels the connection
+/// of the client to it, once the response was sent.
+async fn handle(service: &ForwardProxyService, request: Request<Body>, peer: SocketAddr) -> Response<Body> {
+  let start = Instant::now();
+  let method = request.method().clone();
+  let target = request
+    .uri()
+    .authority()
+    .map(ToString::to_string)
+    .unwrap_or_else(|| "-".to_string());
+  let (response, destination) = match connect(service, &request).await {
+    Ok(destination) => {
+      let address = destination.peer_addr().ok();
+      let max_connection_bytes_per_sec = service.max_connection_bytes_per_sec;
+      tokio::spawn(async move {
+        let result = match hyper::upgrade::on(request).await {
+          Ok(upgraded) => splice(upgraded, destination, max_connection_bytes_per_sec, None)
+            .await
+            .map(drop),
+          Err(e) => Err(io::Error::other(e)),
+        };
+        if let Err(e) = result {
+          debug!("Could not tunnel connection of {}: {}", peer, e);
+        }
+      });
+      METRICS.increment("arlb_forward_proxy_requests_total", &[("result", "tunneled")]);
+      (Response::new(Body::empty()), address)
+    }
+    Err((result, response)) => {
+      METRICS.increment("arlb_forward_proxy_requests_total", &[("result", result)]);
+      (response, None)
+    }
+  };
+  info!(
+    target: ACCESS_LOG_TARGET,
+    "{} {} \"{} {} HTTP/1.1\" {} {}ms{}",
+    peer.ip(),
+    target,
+    method,
+    target,
+    response.status().as_u16(),
+    start.elapsed().as_millis(),
+    destination.map(|it| format!(" {}", it)).unwrap_or_default()
+  );
+  response
+}
+
+fn error(status: StatusCode) -> Response<Body> {
+  Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+/// The label of `arlb_forward_proxy_requests_total` and the response of a
+/// request, which is not tunneled.
+type Rejection = (&'static str, Response<Body>);
+
+/// Connects to the target of the CONNECT `request`, if it is allowed.
+async fn connect(service: &ForwardProxyService, request: &Request<Body>) -> Result<TcpStream, Rejection> {
+  if request.method() != Method::CONNECT {
+    return Err(("invalid", error(StatusCode::METHOD_NOT_ALLOWED)));
+  }
+  if !service.users.is_empty() && !authenticate(service, request).await {
+    let response = Response::builder()
+      .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+      .header(PROXY_AUTHENTICATE, "Basic realm=\"Another Rust Load Balancer\"")
+      .body(Body::empty())
+      .unwrap();
+    return Err(("unauthorized", response));
+  }
+  let (host, port) = match request.uri().authority() {
+    Some(authority) => match authority.port_u16() {
+      Some(port) => (authority.host().trim_start_matches('[').trim_end_matches(']'), port),
+      None => return Err(("invalid", error(StatusCode::BAD_REQUEST))),
+    },
+    None => return Err(("invalid", error(StatusCode::BAD_REQUEST))),
+  };
+  if !service.allowed_targets.iter().any(|it| it.matches(host, port)) {
+    debug!("Denied CONNECT to {}:{}, which is not allowed", host, port);
+    return Err(("denied", error(StatusCode::FORBIDDEN)));
+  }
+  let addresses = match resolve(host, port).await {
+    Ok(addresses) if !addresses.is_empty() => addresses,
+    Ok(_) => return Err(("failed", error(StatusCode::BAD_GATEWAY))),
+    Err(e) => {
+      debug!("Could not resolve {} due to: {}", host, e);
+      return Err(("failed", error(StatusCode::BAD_GATEWAY)));
+    }
+  };
+  let addresses = addresses
+    .into_iter()
+    .filter(|it| service.address_policy.permits(it.ip()) && (service.allow_private_networks || !is_private(it.ip())))
+    .collect::<Vec<_>>();
+  if addresses.is_empty() {
+    debug!(
+      "Denied CONNECT to {}:{}, which resolves to a private network or forbidden address",
+      host, port
+    );
+    return Err(("denied", error(StatusCode::FORBIDDEN)));
+  }
+  for address in addresses {
+    match timeout(service.connect_timeout, TcpStream::connect(address)).await {
+      Ok(Ok(destination)) => return Ok(destination),
+      Ok(Err(e)) => {
+        file_descriptors::record_exhaustion("connect", &e);
+        debug!("Could not connect to {}: {}", address, e)
+      }
+      Err(_) => debug!("Could not connect to {} within {:?}", address, service.connect_timeout),
+    }
+  }
+  Err(("failed", error(StatusCode::BAD_GATEWAY)))
+}
+
+async fn authenticate(service: &ForwardProxyService, request: &Request<Body>) -> bool {
+  match request
+    .headers()
+    .get(PROXY_AUTHORIZATION)
+    .and_then(|it| it.to_str().ok())
+  {
+    Some(authorization) => verify_basic_credentials(&service.users, authorization).await.is_some(),
+    None => false,
+  }
+}
+
+/// Resolves `host` through the [`DnsCache`](crate::dns::DnsCache) shared with
+/// the backend servers, unless it is an IP address.
+async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+  if let Ok(ip) = host.parse::<IpAddr>() {
+    return Ok(vec![SocketAddr::new(ip, port)]);
+  }
+  let name = Name::from_str(host).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  let mut resolver = CachingResolver::new(GaiResolver::new());
+  futures::future::poll_fn(|cx| resolver.poll_ready(cx))
+    .await
+    .map_err(io::Error::other)?;
+  let addresses = resolver.call(name).await.map_err(io::Error::other)?;
+  Ok(addresses.map(|it| SocketAddr::new(it.ip(), port)).collect())
+}
+
+/// Whether `ip` belongs to a private network, the loopback interface or
+/// another range, which is not reachable on the internet.
+fn is_private(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => {
+      let octets = ip.octets();
+      ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // The shared address space of carrier-grade NAT (100.64.0.0/10)
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+    }
+    IpAddr::V6(ip) => {
+      let segments = ip.segments();
+      ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local (fc00::/7) and link-local (fe80::/10) addresses
+        || segments[0] & 0xfe00 == 0xfc00
+        || segments[0] & 0xffc0 == 0xfe80
+        // IPv4-mapped addresses like ::ffff:10.0.0.1
+        || (segments[..6] == [0, 0, 0, 0, 0, 0xffff] && is_private(IpAddr::V4(ip.to_ipv4().unwrap())))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tls::{tests::test_configs, TlsConfig};
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio_rustls::{rustls::ServerConfig, webpki::DNSNameRef, TlsAcceptor, TlsConnector};
+
+  /// Terminates TLS and responds with its `name`.
+  async fn start_tls_server(server_config: ServerConfig, name: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+    tokio::spawn(async move {
+      loop {
+        let (socket, _) = listener.accept().await.unwrap();
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+          let mut tls_stream = tls_acceptor.accept(socket).await.unwrap();
+          tls_stream.write_all(name.as_bytes()).await.unwrap();
+          tls_stream.shutdown().await.unwrap();
+        });
+      }
+    });
+    address
+  }
+
+  fn service(allowed_targets: &[&str]) -> ForwardProxyService {
+    ForwardProxyService {
+      listen_address: "127.0.0.1:0".parse().unwrap(),
+      allowed_targets: allowed_targets.iter().map(|it| it.parse().unwrap()).collect(),
+      // the test servers listen on the loopback interface
+      allow_private_networks: true,
+      address_policy: AddressPolicy::new(vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]),
+      users: HashMap::new(),
+      connect_timeout: Duration::from_secs(1),
+      max_connection_bytes_per_sec: None,
+    }
+  }
+
+  async fn start_proxy(service: ForwardProxyService) -> SocketAddr {
+    let proxy = ForwardProxy::bind(Arc::new(service), 1024).await.unwrap();
+    let address = proxy.local_addr().unwrap();
+    tokio::spawn(proxy.run());
+    address
+  }
+
+  /// Sends a CONNECT request for `target` and returns the connection with the
+  /// head of the response.
+  async fn send_connect(proxy: SocketAddr, target: &str, headers: &str) -> (TcpStream, String) {
+    let mut socket = TcpStream::connect(proxy).await.unwrap();
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n{}\r\n", target, target, headers);
+    socket.write_all(request.as_bytes()).await.unwrap();
+    let mut head = Vec::new();
+    // Reads byte by byte, so nothing of the tunnel is consumed
+    while !head.ends_with(b"\r\n\r\n") {
+      let mut byte = [0];
+      if socket.read(&mut byte).await.unwrap() == 0 {
+        break;
+      }
+      head.push(byte[0]);
+    }
+    (socket, String::from_utf8(head).unwrap())
+  }
+
+  #[tokio::test]
+  async fn test_tunnels_tls_connection_to_allowed_target() {
+    // given:
+    let (server_config, client_config) = test_configs(&TlsConfig::default());
+    let server = start_tls_server(server_config, "tunneled").await;
+    let target = format!("localhost:{}", server.port());
+    let proxy = start_proxy(service(&[&target])).await;
+
+    // when:
+    let (socket, head) = send_connect(proxy, &target, "").await;
+    let connector = TlsConnector::from(Arc::new(client_config));
+    let server_name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut tls_stream = connector.connect(server_name, socket).await.unwrap();
+    let mut response = String::new();
+    tls_stream.read_to_string(&mut response).await.unwrap();
+
+    // then:
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+    assert_eq!(response, "tunneled");
+  }
+
+  #[tokio::test]
+  async fn test_only_allowed_targets_can_be_reached() {
+    // given:
+    let (server_config, _) = test_configs(&TlsConfig::default());
+    let server = start_tls_server(server_config, "tunneled").await;
+    let proxy = start_proxy(service(&["example.com:443", "localhost:1"])).await;
+    let mut private = service(&[&server.to_string()]);
+    private.allow_private_networks = false;
+    let private_proxy = start_proxy(private).await;
+
+    // when:
+    let (_, other_target) = send_connect(proxy, &server.to_string(), "").await;
+    let (_, unreachable) = send_connect(proxy, "localhost:1", "").await;
+    let (_, private_target) = send_connect(private_proxy, &server.to_string(), "").await;
+
+    // then:
+    assert!(other_target.starts_with("HTTP/1.1 403 "), "{}", other_target);
+    assert!(unreachable.starts_with("HTTP/1.1 502 "), "{}", unreachable);
+    assert!(private_target.starts_with("HTTP/1.1 403 "), "{}", private_target);
+  }
+
+  #[tokio::test]
+  async fn test_metadata_addresses_are_denied_despite_private_networks() {
+    // given:
+    let mut service = service(&["169.254.169.254:80", "[::ffff:169.254.169.254]:80"]);
+    service.address_policy = AddressPolicy::default();
+    let proxy = start_proxy(service).await;
+
+    // when:
+    let (_, metadata) = send_connect(proxy, "169.254.169.254:80", "").await;
+    let (_, mapped) = send_connect(proxy, "[::ffff:169.254.169.254]:80", "").await;
+
+    // then:
+    assert!(metadata.starts_with("HTTP/1.1 403 "), "{}", metadata);
+    assert!(mapped.starts_with("HTTP/1.1 403 "), "{}", mapped);
+  }
+
+  #[tokio::test]
+  async fn test_proxy_authentication() {
+    // given:
+    let (server_config, _) = test_configs(&TlsConfig::default());
+    let server = start_tls_server(server_config, "tunneled").await;
+    let mut service = service(&[&server.to_string()]);
+    service.users = vec![("tyrion".to_string(), bcrypt::hash("foo", 4).unwrap())]
+      .into_iter()
+      .collect();
+    let proxy = start_proxy(service).await;
+
+    // when:
+    let (_, anonymous) = send_connect(proxy, &server.to_string(), "").await;
+    let (_, wrong_password) = send_connect(
+      proxy,
+      &server.to_string(),
+      "Proxy-Authorization: Basic dHlyaW9uOmJhcg==\r\n",
+    )
+    .await;
+    let (_, authenticated) = send_connect(
+      proxy,
+      &server.to_string(),
+      "Proxy-Authorization: Basic dHlyaW9uOmZvbw==\r\n",
+    )
+    .await;
+
+    // then:
+    assert!(anonymous.starts_with("HTTP/1.1 407 "), "{}", anonymous);
+    assert!(anonymous.to_lowercase().contains("proxy-authenticate: basic"));
+    assert!(wrong_password.starts_with("HTTP/1.1 407 "), "{}", wrong_password);
+    assert!(authenticated.starts_with("HTTP/1.1 200 "), "{}", authenticated);
+  }
+
+  #[test]
+  fn test_target_patterns() {
+    let pattern = |s: &str| s.parse::<TargetPattern>().unwrap();
+
+    assert!(pattern("example.com:443").matches("EXAMPLE.com", 443));
+    assert!(!pattern("example.com:443").matches("example.com", 80));
+    assert!(pattern("example.com:*").matches("example.com", 80));
+    assert!(pattern("*.example.com:443").matches("api.example.com", 443));
+    assert!(!pattern("*.example.com:443").matches("example.com", 443));
+    assert!(!pattern("*.example.com:443").matches("a.b.example.com", 443));
+    assert!(pattern("[::1]:443").matches("::1", 443));
+    assert!(":443".parse::<TargetPattern>().is_err());
+    assert!("example.com".parse::<TargetPattern>().is_err());
+    assert!("*.example.*:443".parse::<TargetPattern>().is_err());
+    assert!("example.com:http".parse::<TargetPattern>().is_err());
+  }
+
+  #[test]
+  fn test_private_addresses() {
+    let private = |ip: &str| is_private(ip.parse().unwrap());
+
+    assert!(private("10.1.2.3"));
+    assert!(private("172.16.0.1"));
+    assert!(private("192.168.1.1"));
+    assert!(private("127.0.0.1"));
+    assert!(private("169.254.169.254"));
+    assert!(private("100.64.0.1"));
+    assert!(private("::1"));
+    assert!(private("fd00::1"));
+    assert!(private("fe80::1"));
+    assert!(private("::ffff:10.0.0.1"));
+    assert!(!private("93.184.216.34"));
+    assert!(!private("2606:2800:220:1:248:1893:25c8:1946"));
+    assert!(!private("::ffff:93.184.216.34"));
+  }
+}
@@ -0,0 +1,102 @@
+#[cfg(feature = "geoip")]
+use log::debug;
+#[cfg(feature = "geoip")]
+use maxminddb::{geoip2, MaxMindDBError, Mmap, Reader};
+use std::{io, net::IpAddr, path::Path};
+
+/// The location of a client, which is attached to its requests for routing and
+/// the [`Headers`](crate::middleware::headers::Headers) middleware.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GeoInfo {
+  /// The ISO 3166-1 alpha-2 code of the country, like `DE`.
+  pub country: Option<String>,
+  /// The two letter code of the continent, like `EU`.
+  pub continent: Option<String>,
+}
+
+/// A memory mapped MaxMind database (like GeoLite2 Country), which maps IP
+/// addresses to their [`GeoInfo`].
+#[derive(Debug)]
+pub struct GeoIp {
+  #[cfg(feature = "geoip")]
+  reader: Reader<Mmap>,
+}
+
+impl GeoIp {
+  #[cfg(feature = "geoip")]
+  pub fn open<P: AsRef<Path>>(path: P) -> io::Result<GeoIp> {
+    let path = path.as_ref();
+    let reader = Reader::open_mmap(path).map_err(|e| {
+      io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Could not open GeoIP database '{}' due to: {}", path.display(), e),
+      )
+    })?;
+    Ok(GeoIp { reader })
+  }
+
+  #[cfg(not(feature = "geoip"))]
+  pub fn open<P: AsRef<Path>>(path: P) -> io::Result<GeoIp> {
+    Err(io::Error::new(
+      io::ErrorKind::Other,
+      format!(
+        "Could not open GeoIP database '{}', because GeoIP support is not enabled in this build",
+        path.as_ref().display()
+      ),
+    ))
+  }
+
+  /// Looks up the location of `address`. Addresses, which are not part of the
+  /// database (like private addresses), have an empty [`GeoInfo`].
+  #[cfg(feature = "geoip")]
+  pub fn lookup(&self, address: IpAddr) -> GeoInfo {
+    match self.reader.lookup::<geoip2::Country>(address) {
+      Ok(country) => GeoInfo {
+        country: country.country.and_then(|it| it.iso_code).map(str::to_string),
+        continent: country.continent.and_then(|it| it.code).map(str::to_string),
+      },
+      Err(MaxMindDBError::AddressNotFoundError(_)) => GeoInfo::default(),
+      Err(e) => {
+        debug!("Could not look up the location of {} due to: {}", address, e);
+        GeoInfo::default()
+      }
+    }
+  }
+
+  #[cfg(not(feature = "geoip"))]
+  pub fn lookup(&self, _address: IpAddr) -> GeoInfo {
+    GeoInfo::default()
+  }
+}
+
+#[cfg(all(test, feature = "geoip"))]
+pub mod tests {
+  use super::*;
+
+  /// A database generated by `examples/geoip/generate-test-database.py`.
+  pub fn test_database() -> GeoIp {
+    GeoIp::open(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/geoip/test-country.mmdb")).unwrap()
+  }
+
+  #[test]
+  fn test_lookup_country() {
+    let geoip = test_database();
+
+    assert_eq!(
+      geoip.lookup("2.16.0.1".parse().unwrap()),
+      GeoInfo {
+        country: Some("DE".into()),
+        continent: Some("EU".into()),
+      }
+    );
+    assert_eq!(geoip.lookup("8.8.8.8".parse().unwrap()).country, Some("US".into()));
+  }
+
+  #[test]
+  fn test_lookup_private_address() {
+    let geoip = test_database();
+
+    assert_eq!(geoip.lookup("192.168.0.1".parse().unwrap()), GeoInfo::default());
+    assert_eq!(geoip.lookup("::1".parse().unwrap()), GeoInfo::default());
+  }
+}
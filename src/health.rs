@@ -1,25 +1,152 @@
-use crate::server::BackendPool;
-use arc_swap::{access::Access, ArcSwap};
-use futures::future::join_all;
+use crate::{
+  builtin_backends::BUILTIN_ADDRESS_PREFIX,
+  http_client::{backend_uri, BackendConnector, BackendTls, TLS_CONFIG},
+  logging,
+  server::{superseded_pools, BackendPool},
+};
+use arc_swap::access::Access;
+use futures::future::{join_all, Either};
 use hyper::{
-  client::HttpConnector,
-  http::uri::{self, Authority},
-  Client, StatusCode, Uri,
+  body::{Bytes, HttpBody},
+  header::HOST,
+  http::uri::PathAndQuery,
+  service::Service,
+  Body, Request, StatusCode, Uri,
 };
-use hyper_timeout::TimeoutConnector;
 use log::info;
+use once_cell::sync::Lazy;
 use serde::Deserialize;
 use std::time::Duration;
-use std::time::SystemTime;
-use std::{convert::TryFrom, ops::Deref};
-use std::{fmt, sync::Arc};
-use tokio::time::interval;
+use std::time::Instant;
+use std::{collections::HashMap, convert::TryFrom, ops::Deref};
+use std::{fmt, sync::Arc, sync::Mutex};
+use tokio::{
+  io::{AsyncRead, AsyncWrite},
+  sync::Notify,
+};
+use tokio_rustls::{webpki::DNSNameRef, TlsConnector};
+
+/// The most bytes of a response body, which are searched for the `expected_body`.
+const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// The result of the last health check by pool name and address, for the admin API.
+static LAST_CHECKS: Lazy<Mutex<HashMap<(String, String), LastCheck>>> = Lazy::new(Default::default);
+
+/// Notifies the requests waiting for a backend server, whenever the health
+/// checks consider one available again.
+pub static BACKEND_AVAILABLE: Lazy<Notify> = Lazy::new(Notify::new);
+
 /* Contains the user preferences regarding health checks */
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct HealthConfig {
   pub slow_threshold: i64,
   pub timeout: u64,
   pub path: String,
+  /// The statuses of a healthy response, any success status if empty.
+  pub expected_statuses: Vec<StatusCode>,
+  /// A text, which the body of a healthy response has to contain.
+  pub expected_body: Option<String>,
+  /// The `Host` header of the requests, instead of the address of the backend server.
+  pub host: Option<String>,
+  /// Whether the requests are sent via TLS.
+  pub tls: bool,
+  /// How many checks in a row an unresponsive backend server has to pass to
+  /// receive requests again.
+  pub rise: u32,
+  /// How many checks in a row a backend server has to fail to be unresponsive.
+  pub fall: u32,
+  /// By how much the interval grows with each failed check of an
+  /// unresponsive backend server, 1 checks it at the normal interval.
+  pub backoff_multiplier: u32,
+  /// The interval of unresponsive backend servers grows up to this.
+  pub max_backoff_interval: Duration,
+  /// Overrides for single backend servers by address.
+  pub backends: HashMap<String, BackendHealthConfig>,
+}
+
+/// How the health of a backend server is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum HealthCheckKind {
+  /// A `GET` request of the `path`.
+  Http,
+  /// Only establishing a connection, for backend servers, which do not speak HTTP.
+  Tcp,
+}
+
+/// The health check settings of a single backend server. Settings, which are
+/// not set, fall back to the ones of its pool and the global `health_interval`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BackendHealthConfig {
+  pub kind: Option<HealthCheckKind>,
+  pub path: Option<PathAndQuery>,
+  /// The status of a healthy response, instead of any success status.
+  pub expected_status: Option<StatusCode>,
+  pub interval: Option<Duration>,
+  pub timeout: Option<u64>,
+  /// Sends the requests via TLS, like the requests to dynamic backend servers
+  /// with TLS settings.
+  pub tls: Option<BackendTls>,
+}
+
+/// The health check of a single backend server with its overrides applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HealthCheck {
+  kind: HealthCheckKind,
+  path: String,
+  expected_statuses: Vec<StatusCode>,
+  expected_body: Option<String>,
+  host: Option<String>,
+  tls: bool,
+  /// The server name of the TLS handshake, instead of the host.
+  server_name: Option<String>,
+  interval: Duration,
+  slow_threshold: i64,
+  timeout: u64,
+  rise: u32,
+  fall: u32,
+  backoff_multiplier: u32,
+  max_backoff_interval: Duration,
+}
+
+impl HealthConfig {
+  fn check_for(&self, address: &str, default_interval: Duration) -> HealthCheck {
+    let overrides = self.backends.get(address).cloned().unwrap_or_default();
+    HealthCheck {
+      kind: overrides.kind.unwrap_or(HealthCheckKind::Http),
+      path: overrides.path.map_or_else(|| self.path.clone(), |it| it.to_string()),
+      expected_statuses: overrides
+        .expected_status
+        .map_or_else(|| self.expected_statuses.clone(), |it| vec![it]),
+      expected_body: self.expected_body.clone(),
+      host: self.host.clone(),
+      tls: self.tls || overrides.tls.is_some(),
+      server_name: overrides.tls.and_then(|it| it.server_name),
+      interval: overrides.interval.unwrap_or(default_interval),
+      slow_threshold: self.slow_threshold,
+      timeout: overrides.timeout.unwrap_or(self.timeout),
+      rise: self.rise,
+      fall: self.fall,
+      backoff_multiplier: self.backoff_multiplier,
+      max_backoff_interval: self.max_backoff_interval,
+    }
+  }
+}
+
+impl HealthCheck {
+  /// The interval until the next check, after a check of a backend server,
+  /// which was checked `current` after its previous check. The interval of
+  /// an unresponsive backend server grows with each failed check, but it is
+  /// back to normal as soon as it passes one.
+  fn backoff(&self, current: Duration, passed: bool, unresponsive: bool) -> Duration {
+    if passed || !unresponsive || self.backoff_multiplier <= 1 {
+      return self.interval;
+    }
+    let cap = self.max_backoff_interval.max(self.interval);
+    current
+      .max(self.interval)
+      .saturating_mul(self.backoff_multiplier)
+      .min(cap)
+  }
 }
 /* Healthiness of a backend server */
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -39,87 +166,637 @@ impl fmt::Display for Healthiness {
     }
   }
 }
+/// The outcome of a single health check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CheckResult {
+  healthiness: Healthiness,
+  /// The status of the response, if the backend server responded via HTTP.
+  status: Option<StatusCode>,
+  latency: Duration,
+}
+
+/// The last health check of a backend server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastCheck {
+  pub passed: bool,
+  pub status: Option<StatusCode>,
+  pub latency: Duration,
+}
+
+/// The last health check of the backend server at `address` of the pool named
+/// `pool`, if it was checked yet.
+pub fn last_check(pool: &str, address: &str) -> Option<LastCheck> {
+  let key = (pool.to_string(), address.to_string());
+  LAST_CHECKS.lock().unwrap().get(&key).copied()
+}
+
+/// Whether the backend server at `address` is health checked at all. An
+/// interval of 0 deactivates the health checks of a backend server, builtin
+/// backend servers are always available.
+pub fn is_health_checked(config: &HealthConfig, address: &str, default_interval: Duration) -> bool {
+  config.check_for(address, default_interval).interval != Duration::from_secs(0)
+    && !address.starts_with(BUILTIN_ADDRESS_PREFIX)
+}
+
+/// The checks, which a backend server passed or failed in a row.
+#[derive(Debug, Default, Clone, Copy)]
+struct Streak {
+  passed: u32,
+  failed: u32,
+}
+
+impl Streak {
+  /// Counts the `result` of a check and returns the new healthiness, if it
+  /// changes. A backend server only becomes unresponsive after `fall` failed
+  /// checks in a row and only recovers after `rise` passed checks in a row.
+  fn apply(&mut self, current: &Healthiness, result: Healthiness, rise: u32, fall: u32) -> Option<Healthiness> {
+    let failed = matches!(result, Healthiness::Unresponsive(_));
+    if failed {
+      self.failed += 1;
+      self.passed = 0;
+    } else {
+      self.passed += 1;
+      self.failed = 0;
+    }
+    let changes = match (matches!(current, Healthiness::Unresponsive(_)), failed) {
+      (false, true) => self.failed >= fall,
+      (true, false) => self.passed >= rise,
+      // Like between healthy and slow or between different statuses
+      _ => true,
+    };
+    Some(result).filter(|it| changes && it != current)
+  }
+}
+
+/// When the next check of a backend server is due: a `fraction` of its
+/// interval after `since`.
+#[derive(Debug, Clone, Copy)]
+struct Scheduled {
+  since: Instant,
+  fraction: f64,
+}
+
+impl Scheduled {
+  /// The first check of a backend server is delayed by up to the `jitter`
+  /// fraction of its interval, so the checks of load balancers, which
+  /// started at the same time, do not hit shared backend servers at once.
+  fn first(now: Instant, jitter: f64, random: f64) -> Scheduled {
+    Scheduled {
+      since: now,
+      fraction: jitter * random,
+    }
+  }
+
+  /// The following checks are up to the `jitter` fraction of the interval
+  /// earlier or later, so they do not align again.
+  fn after_check(now: Instant, jitter: f64, random: f64) -> Scheduled {
+    Scheduled {
+      since: now,
+      fraction: 1.0 + jitter * (2.0 * random - 1.0),
+    }
+  }
+
+  fn due_at(&self, interval: Duration) -> Instant {
+    self.since + interval.mul_f64(self.fraction)
+  }
+}
+
 /* Start loop to regularly contact backend to investigate the healthiness of each server.
 The healthiness is noted in the backend_pool vector  */
-pub async fn watch_health<A, G, H, J>(backend_pools: A, interval_duration: H)
+pub async fn watch_health<A, G, H, J, K, L>(backend_pools: A, interval_duration: H, jitter: K)
 where
   A: Access<Vec<Arc<BackendPool>>, Guard = G> + Send + Sync + 'static,
   G: Deref<Target = Vec<Arc<BackendPool>>> + Send + Sync,
   H: Access<Duration, Guard = J>,
   J: Deref<Target = Duration>,
+  K: Access<f64, Guard = L>,
+  L: Deref<Target = f64>,
 {
+  // When each backend server is checked next, by pool name and address
+  let mut schedules = HashMap::new();
+  let mut streaks: HashMap<(String, String), Streak> = HashMap::new();
+  // The grown intervals of unresponsive backend servers
+  let mut backoffs: HashMap<(String, String), Duration> = HashMap::new();
   loop {
-    // set and start interval timer
-    let interval_duration = *interval_duration.load().deref();
-    if interval_duration == Duration::from_secs(0) {
-      tokio::time::sleep(Duration::from_secs(5)).await;
-      continue;
-    }
-    let mut interval_timer = interval(interval_duration);
-    interval_timer.tick().await;
-    // create and perform server checks
+    let default_interval = *interval_duration.load().deref();
+    let jitter = *jitter.load().deref();
     let loaded_pools = backend_pools.load();
+    let now = Instant::now();
     let mut checks = Vec::new();
+    let mut next_check_at: Option<Instant> = None;
+    let mut checked = HashMap::new();
     for pool in loaded_pools.iter() {
       for (server_address, healthiness) in &pool.addresses {
-        let future = check_server_health_once(server_address.clone(), healthiness, &pool.health_config);
-        checks.push(future);
+        if !is_health_checked(&pool.health_config, server_address, default_interval) {
+          continue;
+        }
+        let check = pool.health_config.check_for(server_address, default_interval);
+        let key = (pool.name.clone(), server_address.clone());
+        let interval = backoffs.get(&key).copied().unwrap_or(check.interval);
+        let mut scheduled = schedules
+          .get(&key)
+          .copied()
+          .unwrap_or_else(|| Scheduled::first(now, jitter, rand::random()));
+        if scheduled.due_at(interval) <= now {
+          scheduled = Scheduled::after_check(now, jitter, rand::random());
+          let future = check_server_health_once(server_address.clone(), check.clone(), backend_connector(pool));
+          let key = key.clone();
+          let check = check.clone();
+          checks.push(async move { (key, healthiness, check, interval, scheduled, future.await) });
+        }
+        let check_at = scheduled.due_at(interval);
+        next_check_at = Some(next_check_at.map_or(check_at, |it| it.min(check_at)));
+        checked.insert(key, scheduled);
       }
     }
-    join_all(checks).await;
-    /*  Yes tick is called twice in one loop on purpose.
-    Since we are recreating interval_timer on every loop,
-    the first tick, marks the starting point, resuming immediately.
-    */
-    interval_timer.tick().await;
+    for (key, healthiness, check, interval, scheduled, result) in join_all(checks).await {
+      let passed = !matches!(result.healthiness, Healthiness::Unresponsive(_));
+      let last_check = LastCheck {
+        passed,
+        status: result.status,
+        latency: result.latency,
+      };
+      LAST_CHECKS.lock().unwrap().insert(key.clone(), last_check);
+      let streak = streaks.entry(key.clone()).or_default();
+      if let Some(new_healthiness) = streak.apply(&healthiness.load(), result.healthiness, check.rise, check.fall) {
+        logging::with_fields(&[("pool", &key.0), ("backend", &key.1)], || {
+          info!("New healthiness: {}", new_healthiness)
+        });
+        let available = !matches!(new_healthiness, Healthiness::Unresponsive(_));
+        healthiness.store(Arc::new(new_healthiness));
+        if available {
+          BACKEND_AVAILABLE.notify_waiters();
+        }
+      }
+      // Connections accepted before a reload still use the previous pools
+      for pool in superseded_pools(&key.0) {
+        if let Some((_, superseded)) = pool.addresses.iter().find(|(address, _)| *address == key.1) {
+          superseded.store(healthiness.load_full());
+        }
+      }
+      let unresponsive = matches!(**healthiness.load(), Healthiness::Unresponsive(_));
+      let backoff = check.backoff(interval, passed, unresponsive);
+      if backoff != interval {
+        logging::with_fields(&[("pool", &key.0), ("backend", &key.1)], || {
+          info!("Health check interval is now {:?}", backoff)
+        });
+      }
+      if backoff == check.interval {
+        backoffs.remove(&key);
+      } else {
+        backoffs.insert(key.clone(), backoff);
+      }
+      // The interval may have shrunk
+      let check_at = scheduled.due_at(backoff);
+      next_check_at = Some(next_check_at.map_or(check_at, |it| it.min(check_at)));
+    }
+    // Backend servers, which were removed, are forgotten
+    streaks.retain(|key, _| checked.contains_key(key));
+    backoffs.retain(|key, _| checked.contains_key(key));
+    LAST_CHECKS.lock().unwrap().retain(|key, _| checked.contains_key(key));
+    schedules = checked;
+    drop(loaded_pools);
+    match next_check_at {
+      Some(next_check_at) => tokio::time::sleep_until(next_check_at.into()).await,
+      None => tokio::time::sleep(Duration::from_secs(5)).await,
+    }
   }
 }
-/* Contacts one server and sets health value if changed */
+/* Contacts one server */
 async fn check_server_health_once(
   server_address: String,
-  healthiness: &ArcSwap<Healthiness>,
-  health_config: &HealthConfig,
-) {
-  let uri = uri::Uri::builder()
-    .scheme("http")
-    .path_and_query(&health_config.path)
-    .authority(Authority::from_maybe_shared(server_address.clone()).unwrap())
-    .build()
-    .unwrap();
+  check: HealthCheck,
+  connector: BackendConnector,
+) -> CheckResult {
+  let path_and_query = PathAndQuery::from_maybe_shared(check.path.clone()).unwrap();
+  let uri = backend_uri(&server_address, path_and_query).unwrap();
 
-  let previous_healthiness = healthiness.load();
-  let result = contact_server(uri, health_config.slow_threshold, health_config.timeout).await;
+  match check.kind {
+    HealthCheckKind::Http => contact_server(uri, &check, connector).await,
+    HealthCheckKind::Tcp => connect_to_server(uri, &check, connector).await,
+  }
+}
+
+/// Health checks connect to the backend servers of a pool like requests do.
+fn backend_connector(pool: &BackendPool) -> BackendConnector {
+  let mut backend_connector = BackendConnector::new();
+  backend_connector.set_local_address(pool.source_address);
+  backend_connector.set_socket_mark(pool.socket_mark);
+  backend_connector.set_dscp(pool.dscp);
+  backend_connector.set_tcp_fast_open(pool.tcp_fast_open);
+  backend_connector.set_upstream_proxy(pool.upstream_proxy.clone());
+  backend_connector
+}
 
-  if previous_healthiness.as_ref() != &result {
-    info!("new healthiness for {}: {}", &server_address, &result);
-    healthiness.store(Arc::new(result));
+/// Classifies a backend server, which responded in time.
+fn responded_after(elapsed: Duration, slow_threshold: i64) -> Healthiness {
+  let response_time = i64::try_from(elapsed.as_millis()).unwrap_or(i64::MAX);
+  if response_time > slow_threshold {
+    Healthiness::Slow(response_time)
+  } else {
+    Healthiness::Healthy
   }
 }
+
 /* Returns the healthiness of the given server by performing a network request  */
-async fn contact_server(server_address: Uri, slow_threshold: i64, timeout: u64) -> Healthiness {
-  let http_connector = HttpConnector::new();
-  let mut connector = TimeoutConnector::new(http_connector);
-  connector.set_connect_timeout(Some(Duration::from_millis(timeout)));
-  connector.set_read_timeout(Some(Duration::from_millis(timeout)));
-  connector.set_write_timeout(Some(Duration::from_millis(timeout)));
-  let client = Client::builder().build::<_, hyper::Body>(connector);
-
-  let before_request = SystemTime::now();
-  // Await the response...
-  if let Ok(response) = client.get(server_address).await {
-    if response.status().is_success() {
-      // elapsed() only fails when system time is later than "self"
-      let time_to_respond = before_request.elapsed().unwrap().as_millis();
-      let response_time = i64::try_from(time_to_respond);
-      if response_time.unwrap() > slow_threshold {
-        Healthiness::Slow(response_time.unwrap())
-      } else {
-        Healthiness::Healthy
+async fn contact_server(server_address: Uri, check: &HealthCheck, connector: BackendConnector) -> CheckResult {
+  let before_request = Instant::now();
+  let timeout = Duration::from_millis(check.timeout);
+  let exchange = exchange(server_address, check, connector);
+  let response = tokio::time::timeout(timeout, exchange).await;
+  let latency = before_request.elapsed();
+  let (status, body) = match response {
+    Ok(Ok(response)) => response,
+    _ => {
+      return CheckResult {
+        healthiness: Healthiness::Unresponsive(None),
+        status: None,
+        latency,
       }
-    } else {
-      Healthiness::Unresponsive(Some(response.status()))
     }
+  };
+  let expected_status = if check.expected_statuses.is_empty() {
+    status.is_success()
+  } else {
+    check.expected_statuses.contains(&status)
+  };
+  let expected_body = match &check.expected_body {
+    Some(expected_body) => String::from_utf8_lossy(&body).contains(expected_body.as_str()),
+    None => true,
+  };
+  let healthiness = if expected_status && expected_body {
+    responded_after(latency, check.slow_threshold)
+  } else {
+    Healthiness::Unresponsive(Some(status))
+  };
+  CheckResult {
+    healthiness,
+    status: Some(status),
+    latency,
+  }
+}
+
+/// Sends the `GET` request of a health check via HTTP/1.1 and returns the
+/// status and up to [`MAX_BODY_LEN`] bytes of the body of the response.
+async fn exchange(
+  server_address: Uri,
+  check: &HealthCheck,
+  mut connector: BackendConnector,
+) -> Result<(StatusCode, Bytes), Box<dyn std::error::Error + Send + Sync>> {
+  let host = match &check.host {
+    Some(host) => host.clone(),
+    None => server_address.authority().map(|it| it.to_string()).unwrap_or_default(),
+  };
+  let path = server_address.path_and_query().map(|it| it.as_str()).unwrap_or("/");
+  let request = Request::get(path).header(HOST, host.as_str()).body(Body::empty())?;
+  let read_body = check.expected_body.is_some();
+  let stream = connector.call(server_address.clone()).await?;
+  if check.tls {
+    // The certificate has to be valid for the host, without its port
+    let server_name = match (&check.server_name, host.rfind(':')) {
+      (Some(server_name), _) => server_name.as_str(),
+      (None, Some(index)) if !host[index..].contains(']') => &host[..index],
+      (None, _) => host.as_str(),
+    };
+    let server_name = DNSNameRef::try_from_ascii_str(server_name)?;
+    let stream = TlsConnector::from(TLS_CONFIG.clone())
+      .connect(server_name, stream)
+      .await?;
+    Ok(send(stream, request, read_body).await?)
   } else {
-    Healthiness::Unresponsive(None)
+    Ok(send(stream, request, read_body).await?)
+  }
+}
+
+async fn send<S>(stream: S, request: Request<Body>, read_body: bool) -> Result<(StatusCode, Bytes), hyper::Error>
+where
+  S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+  let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+  let response = async {
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    let mut body = response.into_body();
+    let mut bytes = Vec::new();
+    while read_body && bytes.len() < MAX_BODY_LEN {
+      match body.data().await {
+        Some(chunk) => bytes.extend_from_slice(&chunk?),
+        None => break,
+      }
+    }
+    Ok((status, Bytes::from(bytes)))
+  };
+  // The connection is only driven until the response was read, so it is
+  // closed right afterwards
+  futures::pin_mut!(response);
+  futures::pin_mut!(connection);
+  match futures::future::select(response, connection).await {
+    Either::Left((response, _)) => response,
+    Either::Right((_, response)) => response.await,
+  }
+}
+
+/// Returns the healthiness of the given server by establishing a connection.
+async fn connect_to_server(server_address: Uri, check: &HealthCheck, mut connector: BackendConnector) -> CheckResult {
+  let before_connect = Instant::now();
+  let timeout = Duration::from_millis(check.timeout);
+  let healthiness = match tokio::time::timeout(timeout, connector.call(server_address)).await {
+    Ok(Ok(_)) => responded_after(before_connect.elapsed(), check.slow_threshold),
+    _ => Healthiness::Unresponsive(None),
+  };
+  CheckResult {
+    healthiness,
+    status: None,
+    latency: before_connect.elapsed(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{toml_addresses, MockBackend, MockResponse, TestConnection, TestProxy};
+  use hyper::{
+    service::{make_service_fn, service_fn},
+    Response, Server,
+  };
+  use rand::{rngs::StdRng, Rng, SeedableRng};
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use tokio::net::TcpListener;
+
+  fn health_config(backends: &[(&str, BackendHealthConfig)]) -> HealthConfig {
+    HealthConfig {
+      slow_threshold: 300,
+      timeout: 500,
+      path: "/".into(),
+      expected_statuses: Vec::new(),
+      expected_body: None,
+      host: None,
+      tls: false,
+      rise: 1,
+      fall: 1,
+      backoff_multiplier: 1,
+      max_backoff_interval: Duration::from_secs(300),
+      backends: backends
+        .iter()
+        .map(|(address, config)| (address.to_string(), config.clone()))
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn test_overrides_fall_back_to_pool_and_global_settings() {
+    // given:
+    let overrides = BackendHealthConfig {
+      kind: Some(HealthCheckKind::Tcp),
+      interval: Some(Duration::from_secs(2)),
+      ..Default::default()
+    };
+    let config = health_config(&[("127.0.0.1:8081", overrides)]);
+
+    // when:
+    let overridden = config.check_for("127.0.0.1:8081", Duration::from_secs(10));
+    let default = config.check_for("127.0.0.1:8082", Duration::from_secs(10));
+
+    // then:
+    assert_eq!(overridden.kind, HealthCheckKind::Tcp);
+    assert_eq!(overridden.interval, Duration::from_secs(2));
+    assert_eq!(overridden.timeout, 500);
+    assert_eq!(default.kind, HealthCheckKind::Http);
+    assert_eq!(default.interval, Duration::from_secs(10));
+  }
+
+  #[test]
+  fn test_interval_of_unresponsive_backends_backs_off() {
+    // given:
+    let mut config = health_config(&[]);
+    config.backoff_multiplier = 2;
+    config.max_backoff_interval = Duration::from_secs(30);
+    let check = config.check_for("127.0.0.1:8081", Duration::from_secs(10));
+    let secs = Duration::from_secs;
+
+    // when:
+    let failing = check.backoff(secs(10), false, false);
+    let unresponsive = check.backoff(secs(10), false, true);
+    let longer = check.backoff(secs(20), false, true);
+    let capped = check.backoff(secs(30), false, true);
+    let recovering = check.backoff(secs(30), true, true);
+
+    // then: backends, which are not unresponsive yet, are checked normally
+    assert_eq!(failing, secs(10));
+    assert_eq!(unresponsive, secs(20));
+    assert_eq!(longer, secs(30));
+    assert_eq!(capped, secs(30));
+    assert_eq!(recovering, secs(10));
+  }
+
+  #[test]
+  fn test_jitter_spreads_check_times() {
+    // given: 100 backend servers, which are first seen at the same time
+    let now = Instant::now();
+    let interval = Duration::from_secs(10);
+    let mut rng = StdRng::seed_from_u64(7);
+    let spread = |times: &[Instant]| {
+      let first = times.iter().min().unwrap();
+      *times.iter().max().unwrap() - *first
+    };
+
+    // when:
+    let first = (0..100)
+      .map(|_| Scheduled::first(now, 0.2, rng.gen()).due_at(interval))
+      .collect::<Vec<_>>();
+    let second = first
+      .iter()
+      .map(|it| Scheduled::after_check(*it, 0.2, rng.gen()).due_at(interval))
+      .collect::<Vec<_>>();
+    let aligned = Scheduled::first(now, 0.0, rng.gen()).due_at(interval);
+
+    // then: the checks are spread over 2 of the 10 seconds and do not align again
+    assert!(first.iter().all(|it| *it >= now && *it < now + Duration::from_secs(2)));
+    assert!(spread(&first) > Duration::from_millis(1500), "{:?}", spread(&first));
+    let intervals = first.iter().zip(&second).map(|(a, b)| *b - *a).collect::<Vec<_>>();
+    assert!(intervals
+      .iter()
+      .all(|it| *it >= Duration::from_secs(8) && *it <= Duration::from_secs(12)));
+    assert!(spread(&second) > spread(&first), "{:?}", spread(&second));
+    assert_eq!(aligned, now);
+    assert_eq!(
+      Scheduled::after_check(now, 0.0, rng.gen()).due_at(interval),
+      now + interval
+    );
+  }
+
+  #[tokio::test]
+  async fn test_tcp_check() {
+    // given: a backend server, which accepts connections, but does not speak HTTP
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let check = health_config(&[]).check_for(&address, Duration::from_secs(10));
+    let check = HealthCheck {
+      kind: HealthCheckKind::Tcp,
+      ..check
+    };
+    let uri = backend_uri(&address, PathAndQuery::from_static("/")).unwrap();
+
+    // when:
+    let accepting = connect_to_server(uri.clone(), &check, BackendConnector::new()).await;
+    drop(listener);
+    let closed = connect_to_server(uri, &check, BackendConnector::new()).await;
+
+    // then:
+    assert_eq!(accepting.healthiness, Healthiness::Healthy);
+    assert_eq!(closed.healthiness, Healthiness::Unresponsive(None));
+  }
+
+  /// A backend server, which responds with `200 OK` and the body `ready`
+  /// while `healthy` is set and with `500 Internal Server Error` otherwise.
+  /// The host header of the requests are recorded in `hosts`.
+  async fn start_flipping_backend(healthy: Arc<AtomicBool>, hosts: Arc<Mutex<Vec<String>>>) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let service = make_service_fn(move |_| {
+      let healthy = healthy.clone();
+      let hosts = hosts.clone();
+      async move {
+        Ok::<_, hyper::Error>(service_fn(move |request: Request<Body>| {
+          let host = request.headers().get(HOST).map(|it| it.to_str().unwrap().to_string());
+          hosts.lock().unwrap().extend(host);
+          let response = if healthy.load(Ordering::Relaxed) {
+            Response::new(Body::from("status: ready"))
+          } else {
+            let mut response = Response::new(Body::from("status: starting"));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+          };
+          async move { Ok::<_, hyper::Error>(response) }
+        }))
+      }
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+    address
+  }
+
+  #[tokio::test]
+  async fn test_transitions_follow_rise_and_fall_thresholds() {
+    // given:
+    let healthy = Arc::new(AtomicBool::new(true));
+    let hosts = Arc::new(Mutex::new(Vec::new()));
+    let address = start_flipping_backend(healthy.clone(), hosts.clone()).await;
+    let config = HealthConfig {
+      rise: 2,
+      fall: 3,
+      backoff_multiplier: 1,
+      max_backoff_interval: Duration::from_secs(300),
+      expected_body: Some("ready".into()),
+      host: Some("app.localhost".into()),
+      ..health_config(&[])
+    };
+    let check = config.check_for(&address, Duration::from_secs(10));
+    let mut healthiness = Healthiness::Healthy;
+    let mut streak = Streak::default();
+
+    // when: the backend server fails 3 checks and then passes 2 checks
+    let mut transitions = Vec::new();
+    for passing in [false, false, false, true, true, true] {
+      healthy.store(passing, Ordering::Relaxed);
+      let result = check_server_health_once(address.clone(), check.clone(), BackendConnector::new()).await;
+      if let Some(new_healthiness) = streak.apply(&healthiness, result.healthiness, check.rise, check.fall) {
+        healthiness = new_healthiness;
+      }
+      transitions.push(healthiness.clone());
+    }
+
+    // then:
+    let unresponsive = Healthiness::Unresponsive(Some(StatusCode::INTERNAL_SERVER_ERROR));
+    assert_eq!(
+      transitions,
+      vec![
+        Healthiness::Healthy,
+        Healthiness::Healthy,
+        unresponsive.clone(),
+        unresponsive,
+        Healthiness::Healthy,
+        Healthiness::Healthy,
+      ]
+    );
+    assert!(hosts.lock().unwrap().iter().all(|it| it == "app.localhost"));
+  }
+
+  #[tokio::test]
+  async fn test_expected_status_and_body() {
+    // given:
+    let address = start_flipping_backend(Arc::new(AtomicBool::new(false)), Default::default()).await;
+    let check = |config: HealthConfig| config.check_for(&address, Duration::from_secs(10));
+    let result = |check: HealthCheck| check_server_health_once(address.clone(), check, BackendConnector::new());
+
+    // when:
+    let any_success = result(check(health_config(&[]))).await;
+    let expected_error = result(check(HealthConfig {
+      expected_statuses: vec![StatusCode::INTERNAL_SERVER_ERROR],
+      ..health_config(&[])
+    }))
+    .await;
+    let unexpected_body = result(check(HealthConfig {
+      expected_statuses: vec![StatusCode::INTERNAL_SERVER_ERROR],
+      expected_body: Some("ready".into()),
+      ..health_config(&[])
+    }))
+    .await;
+    let tls = result(check(HealthConfig {
+      tls: true,
+      host: Some("localhost".into()),
+      ..health_config(&[])
+    }))
+    .await;
+
+    // then:
+    let server_error = Healthiness::Unresponsive(Some(StatusCode::INTERNAL_SERVER_ERROR));
+    assert_eq!(any_success.healthiness, server_error);
+    assert_eq!(any_success.status, Some(StatusCode::INTERNAL_SERVER_ERROR));
+    assert_eq!(expected_error.healthiness, Healthiness::Healthy);
+    assert_eq!(unexpected_body.healthiness, server_error);
+    // the backend server does not speak TLS
+    assert_eq!(tls.healthiness, Healthiness::Unresponsive(None));
+  }
+
+  #[tokio::test]
+  async fn failing_backend_receives_no_client_requests() {
+    // given:
+    let healthy = MockBackend::http(MockResponse::ok("healthy")).await.unwrap();
+    let failing = MockBackend::http(MockResponse::ok("failing").with_status(StatusCode::SERVICE_UNAVAILABLE))
+      .await
+      .unwrap();
+    let backends = [healthy, failing];
+    let proxy = TestProxy::start(&format!(
+      r#"
+      health_interval = {{ check_every = 1 }}
+
+      [[backend_pools]]
+      matcher = "Host('localhost')"
+      addresses = {}
+      schemes = ["HTTP"]
+      strategy = {{ RoundRobin = {{}} }}
+      "#,
+      toml_addresses(&backends)
+    ))
+    .await
+    .unwrap();
+    let mut connection = TestConnection::plain(proxy.http_address()).await.unwrap();
+
+    // when: the first health check declared the failing backend server unresponsive
+    let healthy_only = vec![("healthy".to_string(), 4)].into_iter().collect();
+    let mut distribution = HashMap::new();
+    for _ in 0..50 {
+      distribution = connection.distribution("localhost", "/", 4).await.unwrap();
+      if distribution == healthy_only {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // then:
+    assert_eq!(distribution, healthy_only);
+    let distribution = connection.distribution("localhost", "/", 10).await.unwrap();
+    assert_eq!(distribution, vec![("healthy".to_string(), 10)].into_iter().collect());
+    proxy.stop().await.unwrap();
   }
 }
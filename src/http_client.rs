@@ -1,38 +1,947 @@
 use std::{
+  collections::HashMap,
   io,
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
   pin::Pin,
-  sync::Arc,
+  sync::{Arc, Mutex},
   task::{Context, Poll},
+  time::{Duration, Instant},
 };
 
-use crate::load_balancing::LoadBalancingStrategy;
-use futures::Future;
+use crate::{
+  address_policy::AddressFilter,
+  bandwidth::{Meter, BACKEND_BANDWIDTH},
+  builtin_backends::{BuiltinBackend, Synthetic, BUILTIN_ADDRESS_PREFIX},
+  dns::CachingResolver,
+  file_descriptors, http_connect,
+  load_balancing::LoadBalancingStrategy,
+  metrics::METRICS,
+  prewarm::WarmConnections,
+  socks5,
+  telemetry::ConnectSpan,
+  upstream_proxy::{ProxyError, ProxyProtocol, Target, UpstreamProxy},
+};
+use futures::{stream::FuturesUnordered, Future, StreamExt};
 use hyper::{
-  client::{connect::Connection, HttpConnector},
-  http::uri::Uri,
+  client::connect::{
+    dns::{GaiResolver, Name},
+    Connected, Connection,
+  },
+  http::{
+    self,
+    uri::{PathAndQuery, Uri},
+  },
   service::Service,
 };
+use once_cell::sync::Lazy;
 use pin_project::{pin_project, pinned_drop};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+#[cfg(target_os = "linux")]
+use std::{
+  mem::ManuallyDrop,
+  os::unix::io::{AsRawFd, FromRawFd, RawFd},
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::{
-  io::{AsyncRead, AsyncWrite},
-  net::TcpStream,
+  io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+  net::{TcpSocket, TcpStream},
+  select,
+  time::sleep,
 };
+use tokio_rustls::{
+  client::TlsStream,
+  rustls::{ClientConfig, RootCertStore},
+  webpki::DNSNameRef,
+  TlsConnector,
+};
+
+/// Connections to backend servers via TLS trust the root certificates of the
+/// operating system.
+pub static TLS_CONFIG: Lazy<Arc<ClientConfig>> = Lazy::new(|| {
+  let mut config = ClientConfig::new();
+  config.root_store = rustls_native_certs::load_native_certs().unwrap_or_else(|(store, e)| {
+    log::warn!(
+      "Could not load the root certificates for TLS connections to backend servers: {}",
+      e
+    );
+    store.unwrap_or_else(RootCertStore::empty)
+  });
+  Arc::new(config)
+});
+
+/// The prefix of backend addresses, which refer to a unix domain socket.
+pub const UNIX_ADDRESS_PREFIX: &str = "unix:";
+
+/// URI scheme of builtin backend servers, whose name is the host of the URI.
+const BUILTIN_SCHEME: &str = "builtin";
+
+/// URI scheme of backend servers listening on a unix domain socket. The path of
+/// the socket is hex encoded in the host of the URI, because an authority can
+/// not contain slashes.
+const UNIX_SCHEME: &str = "unix";
+
+/// Builds the URI of a request to the backend server with the given address.
+/// The address is either a TCP address like `127.0.0.1:8080`, the path of a
+/// unix domain socket like `unix:/run/app.sock` or a builtin backend server
+/// like `builtin:echo`.
+pub fn backend_uri(backend_address: &str, path_and_query: PathAndQuery) -> Result<Uri, http::Error> {
+  if let Some(name) = backend_address.strip_prefix(BUILTIN_ADDRESS_PREFIX) {
+    return Uri::builder()
+      .scheme(BUILTIN_SCHEME)
+      .authority(name)
+      .path_and_query(path_and_query)
+      .build();
+  }
+  match backend_address.strip_prefix(UNIX_ADDRESS_PREFIX) {
+    Some(path) => {
+      let host = path.bytes().map(|byte| format!("{:02x}", byte)).collect::<String>();
+      Uri::builder()
+        .scheme(UNIX_SCHEME)
+        .authority(host.as_str())
+        .path_and_query(path_and_query)
+        .build()
+    }
+    None => Uri::builder()
+      .scheme("http")
+      .authority(backend_address)
+      .path_and_query(path_and_query)
+      .build(),
+  }
+}
+
+/// The inverse of [`backend_uri`], returns the address of the backend server
+/// to which the given URI refers.
+pub fn backend_address(uri: &Uri) -> Option<String> {
+  if let Some(backend) = builtin_backend(uri) {
+    return Some(format!("{}{}", BUILTIN_ADDRESS_PREFIX, backend));
+  }
+  match unix_socket_path(uri) {
+    Some(path) => Some(format!("{}{}", UNIX_ADDRESS_PREFIX, path.display())),
+    None => uri.authority().map(|authority| authority.to_string()),
+  }
+}
+
+fn builtin_backend(uri: &Uri) -> Option<BuiltinBackend> {
+  if uri.scheme_str() != Some(BUILTIN_SCHEME) {
+    return None;
+  }
+  BuiltinBackend::from_name(uri.host()?)
+}
+
+/// Connects to the backend server at `address` for relaying a TCP connection.
+pub async fn connect_relay_backend(address: &str) -> io::Result<BackendStream> {
+  match BuiltinBackend::from_address(address) {
+    Some(backend) => Ok(BackendStream::Builtin(backend, backend.connect())),
+    None => Ok(BackendStream::Tcp(TcpStream::connect(address).await?)),
+  }
+}
+
+fn unix_socket_path(uri: &Uri) -> Option<PathBuf> {
+  if uri.scheme_str() != Some(UNIX_SCHEME) {
+    return None;
+  }
+  let host = uri.host()?;
+  let bytes = (0..host.len())
+    .step_by(2)
+    .map(|index| u8::from_str_radix(host.get(index..index + 2)?, 16).ok())
+    .collect::<Option<Vec<_>>>()?;
+  String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// A connection to a backend server, either via TCP (optionally with TLS), via
+/// a unix domain socket or to a builtin backend server.
+pub enum BackendStream {
+  Tcp(TcpStream),
+  Tls(Box<TlsStream<TcpStream>>),
+  #[cfg(unix)]
+  Unix(UnixStream),
+  Builtin(BuiltinBackend, DuplexStream),
+}
+
+impl AsyncRead for BackendStream {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      BackendStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+      BackendStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+      #[cfg(unix)]
+      BackendStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+      BackendStream::Builtin(_, stream) => Pin::new(stream).poll_read(cx, buf),
+    }
+  }
+}
+
+impl AsyncWrite for BackendStream {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+    match self.get_mut() {
+      BackendStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+      BackendStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+      #[cfg(unix)]
+      BackendStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+      BackendStream::Builtin(_, stream) => Pin::new(stream).poll_write(cx, buf),
+    }
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    match self.get_mut() {
+      BackendStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+      BackendStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+      #[cfg(unix)]
+      BackendStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+      BackendStream::Builtin(_, stream) => Pin::new(stream).poll_flush(cx),
+    }
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    match self.get_mut() {
+      BackendStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+      BackendStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+      #[cfg(unix)]
+      BackendStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+      BackendStream::Builtin(_, stream) => Pin::new(stream).poll_shutdown(cx),
+    }
+  }
+}
+
+impl Connection for BackendStream {
+  fn connected(&self) -> Connected {
+    match self {
+      BackendStream::Tcp(stream) => stream.connected(),
+      BackendStream::Tls(stream) => stream.get_ref().0.connected(),
+      #[cfg(unix)]
+      BackendStream::Unix(_) => Connected::new(),
+      BackendStream::Builtin(backend, _) => Connected::new().extra(Synthetic(*backend)),
+    }
+  }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+  Ipv4,
+  Ipv6,
+}
+
+impl IpFamily {
+  fn of(address: &SocketAddr) -> IpFamily {
+    match address {
+      SocketAddr::V4(_) => IpFamily::Ipv4,
+      SocketAddr::V6(_) => IpFamily::Ipv6,
+    }
+  }
+}
+
+/// Sorts the resolved addresses of a backend server, so the preferred family
+/// comes first. The family of the first address is tried first when racing
+/// connection attempts, see [`race_connections`].
+#[derive(Clone, Debug)]
+pub struct PreferredFamilyResolver<R = GaiResolver> {
+  inner: R,
+  preferred_family: Option<IpFamily>,
+}
+
+impl<R> PreferredFamilyResolver<R> {
+  pub fn new(inner: R, preferred_family: Option<IpFamily>) -> PreferredFamilyResolver<R> {
+    PreferredFamilyResolver {
+      inner,
+      preferred_family,
+    }
+  }
+}
+
+impl<R> Service<Name> for PreferredFamilyResolver<R>
+where
+  R: Service<Name>,
+  R::Response: Iterator<Item = SocketAddr>,
+  R::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+  R::Future: Send + 'static,
+{
+  type Response = std::vec::IntoIter<SocketAddr>;
+
+  type Error = Box<dyn std::error::Error + Send + Sync>;
+
+  // let's allow this complex type. A refactor would make it more complicated due to the used trait types
+  #[allow(clippy::type_complexity)]
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx).map_err(|e| e.into())
+  }
+
+  fn call(&mut self, name: Name) -> Self::Future {
+    let addresses = self.inner.call(name);
+    let preferred_family = self.preferred_family;
+    Box::pin(async move {
+      let mut addresses = addresses.await.map_err(|e| e.into())?.collect::<Vec<_>>();
+      if let Some(preferred_family) = preferred_family {
+        // The sort is stable, so the order within each family is kept
+        addresses.sort_by_key(|address| IpFamily::of(address) != preferred_family);
+      }
+      Ok(addresses.into_iter())
+    })
+  }
+}
+
+/// The delay between connection attempts recommended by RFC 8305.
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to backend servers via TCP or via unix domain sockets, depending on
+/// the URI built by [`backend_uri`].
+#[derive(Clone, Debug)]
+pub struct BackendConnector {
+  resolver: PreferredFamilyResolver<CachingResolver>,
+  socket_options: SocketOptions,
+  happy_eyeballs_delay: Option<Duration>,
+  upstream_proxy: Option<UpstreamProxy>,
+  address_filter: Option<Arc<AddressFilter>>,
+  warm_connections: Option<Arc<WarmConnections>>,
+  /// The TLS settings of the backend servers, which are connected to via TLS,
+  /// by address.
+  tls_backends: Arc<HashMap<String, BackendTls>>,
+}
+
+/// Connects to a backend server via TLS. Its certificate has to be signed by
+/// one of the root certificates of the operating system and be valid for the
+/// `server_name` or, without one, for the host of its address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendTls {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub server_name: Option<String>,
+}
+
+/// Options, which are applied to the socket of each TCP connection before
+/// connecting.
+#[derive(Clone, Copy, Debug, Default)]
+struct SocketOptions {
+  local_address: Option<IpAddr>,
+  mark: Option<u32>,
+  dscp: Option<u8>,
+  keepalive: Option<TcpKeepalive>,
+  fast_open: bool,
+}
+
+/// TCP keepalive probes detect peers, which silently disappeared (for example
+/// due to a pulled cable), so their connections are closed instead of being
+/// held open indefinitely.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpKeepalive {
+  /// How long a connection has to be idle before the first probe is sent.
+  pub idle: Duration,
+  /// The time between unanswered probes.
+  pub interval: Duration,
+  /// How many unanswered probes close the connection.
+  pub probes: u32,
+}
+
+impl BackendConnector {
+  pub fn new() -> BackendConnector {
+    BackendConnector::with_preferred_family(None)
+  }
+
+  /// If a backend server resolves to IPv4 and IPv6 addresses, the
+  /// `preferred_family` is tried first. Without a preference the order of the
+  /// resolver is kept.
+  pub fn with_preferred_family(preferred_family: Option<IpFamily>) -> BackendConnector {
+    BackendConnector {
+      resolver: PreferredFamilyResolver::new(CachingResolver::new(GaiResolver::new()), preferred_family),
+      socket_options: SocketOptions::default(),
+      happy_eyeballs_delay: Some(HAPPY_EYEBALLS_DELAY),
+      upstream_proxy: None,
+      address_filter: None,
+      warm_connections: None,
+      tls_backends: Arc::new(HashMap::new()),
+    }
+  }
+
+  /// Starts connecting to the next address, if connecting to the previous
+  /// address takes longer than `delay`. Without a delay all addresses are
+  /// tried one after another.
+  pub fn set_happy_eyeballs_delay(&mut self, delay: Option<Duration>) {
+    self.happy_eyeballs_delay = delay;
+  }
+
+  /// Binds TCP connections to the given local address before connecting, so
+  /// they originate from a specific IP on multi-homed hosts.
+  pub fn set_local_address(&mut self, address: Option<IpAddr>) {
+    self.socket_options.local_address = address;
+  }
+
+  /// Sets `SO_MARK` on TCP connections, so they can be routed by policy
+  /// routing rules. This is only supported on Linux.
+  pub fn set_socket_mark(&mut self, mark: Option<u32>) {
+    self.socket_options.mark = mark;
+  }
+
+  /// Sets the DSCP of TCP connections, so routers can prioritize their
+  /// packets. This is only supported on Linux.
+  pub fn set_dscp(&mut self, dscp: Option<u8>) {
+    self.socket_options.dscp = dscp;
+  }
+
+  /// Enables TCP keepalive on TCP connections, so connections to backend
+  /// servers, which died silently, are closed. This is only supported on
+  /// Linux.
+  pub fn set_tcp_keepalive(&mut self, keepalive: Option<TcpKeepalive>) {
+    self.socket_options.keepalive = keepalive;
+  }
+
+  /// Enables TCP Fast Open on TCP connections, so the first request to a
+  /// backend server, which was connected to before, is sent within the SYN.
+  /// This is only supported on Linux.
+  pub fn set_tcp_fast_open(&mut self, fast_open: bool) {
+    self.socket_options.fast_open = fast_open;
+  }
+
+  /// Establishes TCP connections through the given SOCKS5 proxy. The socket
+  /// options apply to the connection to the proxy.
+  pub fn set_upstream_proxy(&mut self, proxy: Option<UpstreamProxy>) {
+    self.upstream_proxy = proxy;
+  }
+
+  /// Only connects to the addresses, which host names resolve to, if the
+  /// `filter` permits them. IP addresses are connected to regardless.
+  pub fn set_address_filter(&mut self, filter: Option<Arc<AddressFilter>>) {
+    self.address_filter = filter;
+  }
+
+  /// Hands out the `warm` connections to TCP backend servers, before dialing
+  /// new ones.
+  pub fn set_warm_connections(&mut self, warm: Option<Arc<WarmConnections>>) {
+    self.warm_connections = warm;
+  }
+
+  /// Speaks TLS to the backend servers with these addresses, after the TCP
+  /// connection (or the one through the upstream proxy) is established.
+  pub fn set_tls_backends(&mut self, backends: HashMap<String, BackendTls>) {
+    self.tls_backends = Arc::new(backends);
+  }
+}
+
+/// Performs the TLS handshake with the backend server at `host`.
+async fn connect_tls(
+  tls: &BackendTls,
+  host: &str,
+  stream: TcpStream,
+) -> Result<TlsStream<TcpStream>, Box<dyn std::error::Error + Send + Sync>> {
+  let server_name = tls
+    .server_name
+    .as_deref()
+    .unwrap_or_else(|| host.trim_start_matches('[').trim_end_matches(']'));
+  let server_name = DNSNameRef::try_from_ascii_str(server_name)
+    .map_err(|e| format!("Invalid TLS server name '{}': {}", server_name, e))?;
+  Ok(
+    TlsConnector::from(TLS_CONFIG.clone())
+      .connect(server_name, stream)
+      .await?,
+  )
+}
+
+/// Alternates between the address families, starting with the family of the
+/// first address (RFC 8305 section 4).
+fn interleave_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+  let first_family = match addresses.first() {
+    Some(address) => IpFamily::of(address),
+    None => return addresses,
+  };
+  let (preferred, other): (Vec<_>, Vec<_>) = addresses
+    .into_iter()
+    .partition(|address| IpFamily::of(address) == first_family);
+  let mut preferred = preferred.into_iter();
+  let mut other = other.into_iter();
+  let mut interleaved = Vec::new();
+  loop {
+    match (preferred.next(), other.next()) {
+      (None, None) => return interleaved,
+      (first, second) => interleaved.extend(first.into_iter().chain(second)),
+    }
+  }
+}
+
+/// Connects to the first of the `addresses`, which accepts the connection
+/// (Happy Eyeballs, RFC 8305). A new attempt is started whenever the previous
+/// attempt fails or does not succeed within `delay`. Once a connection is
+/// established, all other attempts are cancelled.
+async fn race_connections(
+  addresses: Vec<SocketAddr>,
+  delay: Option<Duration>,
+  options: SocketOptions,
+) -> Result<TcpStream, io::Error> {
+  let mut pending = interleave_families(addresses).into_iter();
+  let mut attempts = FuturesUnordered::new();
+  let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to");
+  loop {
+    match pending.next() {
+      Some(address) => attempts.push(connect(address, options)),
+      None if attempts.is_empty() => return Err(last_error),
+      None => {}
+    }
+    let next_attempt = match delay {
+      Some(delay) if pending.len() > 0 => futures::future::Either::Left(sleep(delay)),
+      _ => futures::future::Either::Right(futures::future::pending()),
+    };
+    select! {
+      Some(result) = attempts.next() => match result {
+        Ok(stream) => return Ok(stream),
+        Err(e) => last_error = e,
+      },
+      _ = next_attempt => {}
+    }
+  }
+}
+
+async fn connect(address: SocketAddr, options: SocketOptions) -> Result<TcpStream, io::Error> {
+  let socket = match address {
+    SocketAddr::V4(_) => TcpSocket::new_v4(),
+    SocketAddr::V6(_) => TcpSocket::new_v6(),
+  }
+  .inspect_err(|e| file_descriptors::record_exhaustion("connect", e))?;
+  // A local address can only be bound for addresses of the same family
+  if let Some(local_address) = options.local_address.filter(|it| it.is_ipv4() == address.is_ipv4()) {
+    socket.bind(SocketAddr::new(local_address, 0))?;
+  }
+  #[cfg(target_os = "linux")]
+  if let Some(mark) = options.mark {
+    set_mark(socket.as_raw_fd(), mark)?;
+  }
+  #[cfg(target_os = "linux")]
+  if let Some(dscp) = options.dscp {
+    set_dscp(socket.as_raw_fd(), address.is_ipv6(), dscp)?;
+  }
+  #[cfg(target_os = "linux")]
+  if let Some(keepalive) = &options.keepalive {
+    set_tcp_keepalive(socket.as_raw_fd(), keepalive)?;
+  }
+  // The connect returns right away and the handshake is completed by the
+  // first write, which carries the request, if the backend server sent a
+  // cookie before
+  #[cfg(target_os = "linux")]
+  if options.fast_open {
+    set_tcp_option(socket.as_raw_fd(), libc::TCP_FASTOPEN_CONNECT, 1)?;
+  }
+  socket
+    .connect(address)
+    .await
+    .map_err(|e| io::Error::new(e.kind(), format!("Could not connect to '{}' due to: {}", address, e)))
+}
+
+/// Checks that TCP sockets can be bound to the given local `address`, which
+/// fails if no network interface of this host is assigned to it.
+pub fn check_local_address(address: IpAddr) -> Result<(), io::Error> {
+  let address = SocketAddr::new(address, 0);
+  let domain = match address {
+    SocketAddr::V4(_) => Domain::ipv4(),
+    SocketAddr::V6(_) => Domain::ipv6(),
+  };
+  Socket::new(domain, Type::stream(), Some(Protocol::tcp()))
+    .and_then(|socket| socket.bind(&SockAddr::from(address)))
+    .map_err(|e| {
+      io::Error::new(
+        e.kind(),
+        format!("Source address '{}' is not available on this host: {}", address.ip(), e),
+      )
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(fd: RawFd, mark: u32) -> Result<(), io::Error> {
+  let result = unsafe {
+    libc::setsockopt(
+      fd,
+      libc::SOL_SOCKET,
+      libc::SO_MARK,
+      &mark as *const u32 as *const libc::c_void,
+      std::mem::size_of::<u32>() as libc::socklen_t,
+    )
+  };
+  if result == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+/// Sets the DSCP in the upper 6 bits of the `IP_TOS` (or `IPV6_TCLASS`) of the
+/// socket `fd`. Connections accepted by a listening socket inherit it.
+#[cfg(target_os = "linux")]
+pub fn set_dscp(fd: RawFd, ipv6: bool, dscp: u8) -> Result<(), io::Error> {
+  let (level, option) = if ipv6 {
+    (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+  } else {
+    (libc::IPPROTO_IP, libc::IP_TOS)
+  };
+  let tos = libc::c_int::from(dscp) << 2;
+  let result = unsafe {
+    libc::setsockopt(
+      fd,
+      level,
+      option,
+      &tos as *const libc::c_int as *const libc::c_void,
+      std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+    )
+  };
+  if result == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+/// Enables `SO_KEEPALIVE` on the socket `fd` and configures its probes.
+/// Connections accepted by a listening socket inherit these options.
+#[cfg(target_os = "linux")]
+pub fn set_tcp_keepalive(fd: RawFd, keepalive: &TcpKeepalive) -> Result<(), io::Error> {
+  // The socket is only borrowed, so it must not be closed on drop
+  let socket = ManuallyDrop::new(unsafe { Socket::from_raw_fd(fd) });
+  socket.set_keepalive(Some(keepalive.idle))?;
+  let interval = keepalive.interval.as_secs() as libc::c_int;
+  set_tcp_option(fd, libc::TCP_KEEPINTVL, interval)?;
+  set_tcp_option(fd, libc::TCP_KEEPCNT, keepalive.probes as libc::c_int)
+}
+
+/// The number of connections with TCP Fast Open, which a listening socket
+/// accepts before their handshake completed. Further ones fall back to a
+/// regular handshake, which limits the effect of SYN floods with valid
+/// cookies.
+#[cfg(target_os = "linux")]
+const TCP_FAST_OPEN_QUEUE_LENGTH: libc::c_int = 256;
+
+/// Enables TCP Fast Open on the listening socket `fd`, so clients, which
+/// received a cookie, can send their request within the SYN.
+#[cfg(target_os = "linux")]
+pub fn set_tcp_fast_open(fd: RawFd) -> Result<(), io::Error> {
+  set_tcp_option(fd, libc::TCP_FASTOPEN, TCP_FAST_OPEN_QUEUE_LENGTH)
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_option(fd: RawFd, option: libc::c_int, value: libc::c_int) -> Result<(), io::Error> {
+  let result = unsafe {
+    libc::setsockopt(
+      fd,
+      libc::IPPROTO_TCP,
+      option,
+      &value as *const libc::c_int as *const libc::c_void,
+      std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+    )
+  };
+  if result == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+/// Checks that TCP sockets can be marked with `SO_MARK`, which requires the
+/// capability `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+pub fn check_socket_mark(mark: u32) -> Result<(), io::Error> {
+  let socket = Socket::new(Domain::ipv4(), Type::stream(), Some(Protocol::tcp()))?;
+  set_mark(socket.as_raw_fd(), mark)
+    .map_err(|e| io::Error::new(e.kind(), format!("Could not set the socket mark {}: {}", mark, e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check_socket_mark(_mark: u32) -> Result<(), io::Error> {
+  Err(io::Error::new(
+    io::ErrorKind::InvalidInput,
+    "socket_mark is only supported on Linux",
+  ))
+}
+
+/// Checks that the kernel supports TCP Fast Open for listening and connecting
+/// sockets (since Linux 4.11).
+#[cfg(target_os = "linux")]
+pub fn check_tcp_fast_open() -> Result<(), io::Error> {
+  let socket = Socket::new(Domain::ipv4(), Type::stream(), Some(Protocol::tcp()))?;
+  set_tcp_fast_open(socket.as_raw_fd())
+    .and_then(|_| set_tcp_option(socket.as_raw_fd(), libc::TCP_FASTOPEN_CONNECT, 1))
+    .map_err(|e| io::Error::new(e.kind(), format!("TCP Fast Open is not supported by the kernel: {}", e)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check_tcp_fast_open() -> Result<(), io::Error> {
+  Err(io::Error::new(
+    io::ErrorKind::InvalidInput,
+    "TCP Fast Open is only supported on Linux",
+  ))
+}
+
+/// Checks that the `dscp` fits into the 6 bits of the DSCP field.
+#[cfg(target_os = "linux")]
+pub fn check_dscp(dscp: u8) -> Result<(), io::Error> {
+  if dscp > 63 {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      format!("The dscp {} is not within 0 and 63", dscp),
+    ));
+  }
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn check_dscp(_dscp: u8) -> Result<(), io::Error> {
+  Err(io::Error::new(
+    io::ErrorKind::InvalidInput,
+    "dscp is only supported on Linux",
+  ))
+}
+
+impl Service<Uri> for BackendConnector {
+  type Response = BackendStream;
+
+  type Error = Box<dyn std::error::Error + Send + Sync>;
+
+  // let's allow this complex type. A refactor would make it more complicated due to the used trait types
+  #[allow(clippy::type_complexity)]
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.resolver.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Uri) -> Self::Future {
+    if let Some(backend) = builtin_backend(&req) {
+      return Box::pin(async move { Ok(BackendStream::Builtin(backend, backend.connect_http())) });
+    }
+    if let Some(path) = unix_socket_path(&req) {
+      return Box::pin(connect_unix(path));
+    }
+    let mut resolver = self.resolver.clone();
+    let delay = self.happy_eyeballs_delay;
+    let options = self.socket_options;
+    let upstream_proxy = self.upstream_proxy.clone();
+    let address_filter = self.address_filter.clone();
+    let warm_connections = self.warm_connections.clone();
+    let tls = backend_address(&req).and_then(|address| self.tls_backends.get(&address).cloned());
+    let host = req.host().unwrap_or_default().to_string();
+    let connect: Self::Future = Box::pin(async move {
+      let host = req.host().ok_or("The URI of the backend server has no host")?;
+      let port = req.port_u16().unwrap_or(80);
+      let warm = warm_connections.as_ref().zip(backend_address(&req));
+      if let Some(stream) = warm.and_then(|(warm, address)| warm.take(&address)) {
+        return Ok(BackendStream::Tcp(stream));
+      }
+      let address_filter = address_filter.as_deref();
+      if let Some(proxy) = upstream_proxy {
+        // The proxy resolves hostnames itself with socks5h and http
+        let is_ip = host
+          .trim_start_matches('[')
+          .trim_end_matches(']')
+          .parse::<IpAddr>()
+          .is_ok();
+        let target = if proxy.remote_dns && !is_ip {
+          Target::Domain(host.to_string(), port)
+        } else {
+          let addresses = resolve_permitted(&mut resolver, address_filter, host, port).await?;
+          Target::Address(*addresses.first().ok_or("No address to connect to")?)
+        };
+        let stream = connect_through_proxy(&proxy, &target, &mut resolver, delay, options).await;
+        if let Err(e) = &stream {
+          METRICS.increment(
+            "arlb_upstream_proxy_failures_total",
+            &[("proxy", &proxy.address()), ("reason", e.reason())],
+          );
+        }
+        return Ok(BackendStream::Tcp(stream?));
+      }
+      let addresses = resolve_permitted(&mut resolver, address_filter, host, port).await?;
+      Ok(BackendStream::Tcp(race_connections(addresses, delay, options).await?))
+    });
+    match tls {
+      Some(tls) => Box::pin(async move {
+        match connect.await? {
+          BackendStream::Tcp(stream) => Ok(BackendStream::Tls(Box::new(connect_tls(&tls, &host, stream).await?))),
+          stream => Ok(stream),
+        }
+      }),
+      None => connect,
+    }
+  }
+}
+
+/// Resolves the `host` of a backend server, unless it is an IP address.
+async fn resolve(
+  resolver: &mut PreferredFamilyResolver<CachingResolver>,
+  host: &str,
+  port: u16,
+) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error + Send + Sync>> {
+  match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+    Ok(ip) => Ok(vec![SocketAddr::new(ip, port)]),
+    Err(_) => Ok(
+      resolver
+        .call(host.parse()?)
+        .await?
+        .map(|address| SocketAddr::new(address.ip(), port))
+        .collect(),
+    ),
+  }
+}
+
+/// Resolves the `host` of a backend server like [`resolve`], but skips the
+/// addresses, which the `filter` forbids, if it is a host name.
+async fn resolve_permitted(
+  resolver: &mut PreferredFamilyResolver<CachingResolver>,
+  filter: Option<&AddressFilter>,
+  host: &str,
+  port: u16,
+) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error + Send + Sync>> {
+  let addresses = resolve(resolver, host, port).await?;
+  match filter {
+    Some(filter)
+      if host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .is_err() =>
+    {
+      Ok(filter.filter(&format!("{}:{}", host, port), addresses)?)
+    }
+    _ => Ok(addresses),
+  }
+}
+
+/// Connects to the `proxy` and asks it to connect to the backend server.
+async fn connect_through_proxy(
+  proxy: &UpstreamProxy,
+  target: &Target,
+  resolver: &mut PreferredFamilyResolver<CachingResolver>,
+  delay: Option<Duration>,
+  options: SocketOptions,
+) -> Result<TcpStream, ProxyError> {
+  let addresses = resolve(resolver, &proxy.host, proxy.port)
+    .await
+    .map_err(|e| ProxyError::Connect(io::Error::other(e)))?;
+  let mut stream = race_connections(addresses, delay, options)
+    .await
+    .map_err(ProxyError::Connect)?;
+  match proxy.protocol {
+    ProxyProtocol::Socks5 => socks5::handshake(&mut stream, proxy, target).await?,
+    ProxyProtocol::HttpConnect => http_connect::handshake(&mut stream, proxy, target).await?,
+  }
+  Ok(stream)
+}
+
+/// Counts the open connections (including idle pooled connections) per backend
+/// server. The connections, which do not serve a request, are exposed as
+/// `arlb_backend_idle_connections{pool,backend}`.
+#[derive(Debug, Default)]
+pub struct ActiveConnections {
+  pool: String,
+  counts: Mutex<HashMap<String, ConnectionCounts>>,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionCounts {
+  open: usize,
+  /// The requests, which are forwarded to the backend server. Each of them
+  /// occupies a connection (except with HTTP/2, which multiplexes them).
+  in_flight: usize,
+}
+
+impl ActiveConnections {
+  pub fn new(pool: String) -> ActiveConnections {
+    ActiveConnections {
+      pool,
+      counts: Mutex::default(),
+    }
+  }
+
+  pub fn get(&self, address: &str) -> usize {
+    let counts = self.counts.lock().unwrap();
+    counts.get(address).map(|it| it.open).unwrap_or(0)
+  }
+
+  fn open(&self, remote: &Uri) {
+    if let Some(address) = backend_address(remote) {
+      self.update(&address, |counts| counts.open += 1);
+    }
+  }
+
+  fn close(&self, remote: &Uri) {
+    if let Some(address) = backend_address(remote) {
+      self.update(&address, |counts| counts.open = counts.open.saturating_sub(1));
+    }
+  }
+
+  /// Counts a request to the backend server at `address` as in flight, until
+  /// the returned guard is dropped.
+  pub fn start_request(self: &Arc<Self>, address: &str) -> InFlightRequest {
+    self.update(address, |counts| counts.in_flight += 1);
+    InFlightRequest {
+      connections: self.clone(),
+      address: address.to_string(),
+    }
+  }
+
+  fn update(&self, address: &str, update: impl FnOnce(&mut ConnectionCounts)) {
+    let mut counts = self.counts.lock().unwrap();
+    let entry = counts.entry(address.to_string()).or_default();
+    update(entry);
+    let idle = entry.open.saturating_sub(entry.in_flight);
+    if entry.open == 0 && entry.in_flight == 0 {
+      counts.remove(address);
+    }
+    drop(counts);
+    METRICS.set_gauge(
+      "arlb_backend_idle_connections",
+      &[("pool", &self.pool), ("backend", address)],
+      idle as i64,
+    );
+  }
+
+  /// Resolves once all connections to the backend server at `address` are
+  /// closed.
+  pub async fn closed(&self, address: &str) {
+    while self.get(address) > 0 {
+      tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+  }
+}
+
+/// A request, which is forwarded to a backend server, see
+/// [`ActiveConnections::start_request`].
+pub struct InFlightRequest {
+  connections: Arc<ActiveConnections>,
+  address: String,
+}
+
+impl Drop for InFlightRequest {
+  fn drop(&mut self) {
+    self.connections.update(&self.address, |counts| {
+      counts.in_flight = counts.in_flight.saturating_sub(1)
+    });
+  }
+}
 
 /// A wrapper around any async stream. Notifies the given strategy once the stream is closed
+/// and counts the bytes read from it towards the bandwidth of the backend server.
 #[pin_project(PinnedDrop)]
 pub struct StrategyNotifyStream<T: AsyncRead + AsyncWrite + Connection + Send> {
   #[pin]
   inner: T,
   target: Uri,
   strategy: Arc<Box<dyn LoadBalancingStrategy>>,
+  connections: Arc<ActiveConnections>,
+  meter: Option<Arc<Meter>>,
 }
 
 impl<T: AsyncRead + AsyncWrite + Connection + Send> StrategyNotifyStream<T> {
-  pub fn new(inner: T, target: Uri, strategy: Arc<Box<dyn LoadBalancingStrategy>>) -> Self {
+  pub fn new(
+    inner: T,
+    target: Uri,
+    strategy: Arc<Box<dyn LoadBalancingStrategy>>,
+    connections: Arc<ActiveConnections>,
+  ) -> Self {
+    connections.open(&target);
+    let meter = backend_address(&target).map(|address| BACKEND_BANDWIDTH.meter(&address));
     StrategyNotifyStream {
       inner,
       target,
       strategy,
+      connections,
+      meter,
     }
   }
 }
@@ -41,12 +950,19 @@ impl<T: AsyncRead + AsyncWrite + Connection + Send> StrategyNotifyStream<T> {
 impl<T: AsyncRead + AsyncWrite + Connection + Send> PinnedDrop for StrategyNotifyStream<T> {
   fn drop(self: Pin<&mut Self>) {
     self.strategy.on_tcp_close(&self.target);
+    self.connections.close(&self.target);
   }
 }
 
 impl<T: AsyncRead + AsyncWrite + Connection + Send + Sync> AsyncRead for StrategyNotifyStream<T> {
   fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<io::Result<()>> {
-    self.project().inner.poll_read(cx, buf)
+    let this = self.project();
+    let before = buf.filled().len();
+    let result = this.inner.poll_read(cx, buf);
+    if let Some(meter) = this.meter {
+      meter.record(buf.filled().len() - before);
+    }
+    result
   }
 }
 
@@ -69,23 +985,43 @@ impl<T: AsyncRead + AsyncWrite + Connection + Send + Sync> Connection for Strate
   }
 }
 
+tokio::task_local! {
+  /// How long establishing a connection to a backend server may take for the
+  /// request, which the current task forwards.
+  static CONNECT_TIMEOUT: Option<Duration>;
+}
+
+/// Limits the connections to backend servers, which the
+/// [`StrategyNotifyHttpConnector`] establishes while `future` is polled, to
+/// the `connect_timeout`. The client of a pool is shared by all its requests,
+/// so this is how a request passes its own timeout to the connector.
+pub async fn scope_connect_timeout<F: Future>(connect_timeout: Option<Duration>, future: F) -> F::Output {
+  CONNECT_TIMEOUT.scope(connect_timeout, future).await
+}
+
 #[derive(Clone, Debug)]
 pub struct StrategyNotifyHttpConnector {
-  inner: HttpConnector,
+  inner: BackendConnector,
   strategy: Arc<Box<dyn LoadBalancingStrategy>>,
+  connections: Arc<ActiveConnections>,
 }
 
 impl StrategyNotifyHttpConnector {
-  pub fn new(strategy: Arc<Box<dyn LoadBalancingStrategy>>) -> StrategyNotifyHttpConnector {
+  pub fn new(
+    inner: BackendConnector,
+    strategy: Arc<Box<dyn LoadBalancingStrategy>>,
+    connections: Arc<ActiveConnections>,
+  ) -> StrategyNotifyHttpConnector {
     StrategyNotifyHttpConnector {
-      inner: HttpConnector::new(),
+      inner,
       strategy,
+      connections,
     }
   }
 }
 
 impl Service<Uri> for StrategyNotifyHttpConnector {
-  type Response = StrategyNotifyStream<TcpStream>;
+  type Response = StrategyNotifyStream<BackendStream>;
 
   type Error = Box<dyn std::error::Error + Send + Sync>;
 
@@ -94,21 +1030,455 @@ impl Service<Uri> for StrategyNotifyHttpConnector {
   type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
   fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-    self.inner.poll_ready(cx).map_err(|e| e.into())
+    self.inner.poll_ready(cx)
   }
 
   fn call(&mut self, req: Uri) -> Self::Future {
     let mut self_ = self.clone();
     let req_ = req.clone();
+    // hyper calls the connector while the request is polled, so the scope is still set
+    let connect_timeout = CONNECT_TIMEOUT.try_with(|it| *it).ok().flatten();
+    let span = ConnectSpan::start(&req);
 
     Box::pin(async move {
-      match self_.inner.call(req).await {
+      let start = Instant::now();
+      let connect = self_.inner.call(req);
+      let connected = match connect_timeout {
+        Some(connect_timeout) => match tokio::time::timeout(connect_timeout, connect).await {
+          Ok(connected) => connected,
+          Err(_) => Err(format!("Could not connect within {:?}", connect_timeout).into()),
+        },
+        None => connect.await,
+      };
+      if let Some(span) = span {
+        span.finish(&connected);
+      }
+      match connected {
         Ok(stream) => {
+          self_.strategy.on_tcp_connect_time(&req_, start.elapsed());
           self_.strategy.on_tcp_open(&req_);
-          Ok(StrategyNotifyStream::new(stream, req_, self_.strategy))
+          Ok(StrategyNotifyStream::new(
+            stream,
+            req_,
+            self_.strategy,
+            self_.connections,
+          ))
         }
-        Err(e) => Err(e.into()),
+        Err(e) => Err(e),
       }
     })
   }
 }
+
+#[cfg(unix)]
+async fn connect_unix(path: PathBuf) -> Result<BackendStream, Box<dyn std::error::Error + Send + Sync>> {
+  Ok(BackendStream::Unix(UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: PathBuf) -> Result<BackendStream, Box<dyn std::error::Error + Send + Sync>> {
+  Err(
+    format!(
+      "Can not connect to '{}', unix domain sockets are not supported on this platform",
+      path.display()
+    )
+    .into(),
+  )
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+
+  /// The keepalive of the socket `fd` or `None` if it is disabled.
+  #[cfg(target_os = "linux")]
+  pub fn tcp_keepalive_of(fd: RawFd) -> Option<TcpKeepalive> {
+    let option = |level, option| {
+      let mut value: libc::c_int = 0;
+      let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+      let result = unsafe {
+        libc::getsockopt(
+          fd,
+          level,
+          option,
+          &mut value as *mut libc::c_int as *mut libc::c_void,
+          &mut length,
+        )
+      };
+      assert_eq!(result, 0, "{}", io::Error::last_os_error());
+      value
+    };
+    if option(libc::SOL_SOCKET, libc::SO_KEEPALIVE) == 0 {
+      return None;
+    }
+    Some(TcpKeepalive {
+      idle: Duration::from_secs(option(libc::IPPROTO_TCP, libc::TCP_KEEPIDLE) as u64),
+      interval: Duration::from_secs(option(libc::IPPROTO_TCP, libc::TCP_KEEPINTVL) as u64),
+      probes: option(libc::IPPROTO_TCP, libc::TCP_KEEPCNT) as u32,
+    })
+  }
+
+  /// The DSCP of the IPv4 socket `fd`.
+  #[cfg(target_os = "linux")]
+  pub fn dscp_of(fd: RawFd) -> u8 {
+    let mut tos: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+      libc::getsockopt(
+        fd,
+        libc::IPPROTO_IP,
+        libc::IP_TOS,
+        &mut tos as *mut libc::c_int as *mut libc::c_void,
+        &mut length,
+      )
+    };
+    assert_eq!(result, 0, "{}", io::Error::last_os_error());
+    (tos >> 2) as u8
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn test_dscp_of_backend_connections() {
+    // given:
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = backend_uri(
+      &listener.local_addr().unwrap().to_string(),
+      PathAndQuery::from_static("/"),
+    )
+    .unwrap();
+    let mut connector = BackendConnector::new();
+    connector.set_dscp(Some(46));
+
+    // when:
+    let stream = connector.call(uri.clone()).await.unwrap();
+    let default_stream = BackendConnector::new().call(uri).await.unwrap();
+
+    // then:
+    let fd = |stream: &BackendStream| match stream {
+      BackendStream::Tcp(stream) => stream.as_raw_fd(),
+      _ => panic!("Expected a TCP connection"),
+    };
+    assert_eq!(dscp_of(fd(&stream)), 46);
+    assert_eq!(dscp_of(fd(&default_stream)), 0);
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn test_tcp_keepalive_of_backend_connections() {
+    // given:
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let uri = backend_uri(
+      &listener.local_addr().unwrap().to_string(),
+      PathAndQuery::from_static("/"),
+    )
+    .unwrap();
+    let keepalive = TcpKeepalive {
+      idle: Duration::from_secs(30),
+      interval: Duration::from_secs(5),
+      probes: 3,
+    };
+    let mut connector = BackendConnector::new();
+    connector.set_tcp_keepalive(Some(keepalive));
+
+    // when:
+    let stream = connector.call(uri.clone()).await.unwrap();
+    let default_stream = BackendConnector::new().call(uri).await.unwrap();
+
+    // then:
+    let fd = |stream: &BackendStream| match stream {
+      BackendStream::Tcp(stream) => stream.as_raw_fd(),
+      _ => panic!("Expected a TCP connection"),
+    };
+    assert_eq!(tcp_keepalive_of(fd(&stream)), Some(keepalive));
+    assert_eq!(tcp_keepalive_of(fd(&default_stream)), None);
+  }
+
+  #[test]
+  fn test_backend_uri_tcp() {
+    let uri = backend_uri("127.0.0.1:8080", PathAndQuery::from_static("/path?query")).unwrap();
+
+    assert_eq!(uri, "http://127.0.0.1:8080/path?query");
+    assert_eq!(backend_address(&uri), Some("127.0.0.1:8080".into()));
+  }
+
+  #[test]
+  fn test_backend_uri_unix() {
+    let uri = backend_uri("unix:/run/app.sock", PathAndQuery::from_static("/path?query")).unwrap();
+
+    assert_eq!(uri.scheme_str(), Some("unix"));
+    assert_eq!(uri.path_and_query().unwrap(), "/path?query");
+    assert_eq!(backend_address(&uri), Some("unix:/run/app.sock".into()));
+  }
+
+  #[tokio::test]
+  async fn test_active_connections_closed() {
+    // given:
+    let connections = Arc::new(ActiveConnections::default());
+    let uri = backend_uri("127.0.0.1:8080", PathAndQuery::from_static("/")).unwrap();
+    connections.open(&uri);
+    connections.open(&uri);
+
+    // when:
+    let closed = tokio::spawn({
+      let connections = connections.clone();
+      async move { connections.closed("127.0.0.1:8080").await }
+    });
+    connections.close(&uri);
+    let count = connections.get("127.0.0.1:8080");
+    connections.close(&uri);
+
+    // then:
+    assert_eq!(count, 1);
+    assert!(tokio::time::timeout(Duration::from_secs(1), closed).await.is_ok());
+    assert_eq!(connections.get("127.0.0.1:8080"), 0);
+  }
+
+  /// Resolves every name to the same addresses.
+  #[derive(Clone)]
+  struct StaticResolver(Vec<SocketAddr>);
+
+  impl Service<Name> for StaticResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = futures::future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _name: Name) -> Self::Future {
+      futures::future::ready(Ok(self.0.clone().into_iter()))
+    }
+  }
+
+  #[tokio::test]
+  async fn test_preferred_family_resolver() {
+    // given:
+    let addresses = vec![
+      "127.0.0.1:80".parse().unwrap(),
+      "[::1]:80".parse().unwrap(),
+      "127.0.0.2:80".parse().unwrap(),
+      "[::2]:80".parse().unwrap(),
+    ];
+    let mut resolver = PreferredFamilyResolver::new(StaticResolver(addresses), Some(IpFamily::Ipv6));
+
+    // when:
+    let resolved: Vec<SocketAddr> = resolver.call("backend".parse().unwrap()).await.unwrap().collect();
+
+    // then:
+    let expected: Vec<SocketAddr> = vec![
+      "[::1]:80".parse().unwrap(),
+      "[::2]:80".parse().unwrap(),
+      "127.0.0.1:80".parse().unwrap(),
+      "127.0.0.2:80".parse().unwrap(),
+    ];
+    assert_eq!(resolved, expected);
+  }
+
+  #[test]
+  fn test_interleave_families() {
+    let addresses = ["[::1]:80", "[::2]:80", "[::3]:80", "127.0.0.1:80"]
+      .iter()
+      .map(|it| it.parse().unwrap())
+      .collect();
+
+    let interleaved: Vec<String> = interleave_families(addresses).iter().map(|it| it.to_string()).collect();
+
+    assert_eq!(interleaved, vec!["[::1]:80", "127.0.0.1:80", "[::2]:80", "[::3]:80"]);
+  }
+
+  async fn start_listener() -> u16 {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+    port
+  }
+
+  #[tokio::test]
+  async fn test_race_connections_falls_back_to_other_family() {
+    // given: the preferred IPv6 address is unroutable (discard prefix), the IPv4 address is reachable
+    let port = start_listener().await;
+    let addresses = vec![
+      format!("[100::1]:{}", port).parse().unwrap(),
+      format!("127.0.0.1:{}", port).parse().unwrap(),
+    ];
+
+    // when:
+    let start = Instant::now();
+    let stream = race_connections(addresses, Some(Duration::from_millis(50)), SocketOptions::default())
+      .await
+      .unwrap();
+
+    // then: the connection is established long before the OS connect timeout
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(stream.peer_addr().unwrap().ip(), IpAddr::from([127, 0, 0, 1]));
+  }
+
+  #[tokio::test]
+  async fn test_race_connections_within_the_same_family() {
+    // given: TEST-NET-1 is never routed, so connecting to it would hang until the OS connect timeout
+    let port = start_listener().await;
+    let addresses = vec![
+      format!("192.0.2.1:{}", port).parse().unwrap(),
+      format!("127.0.0.1:{}", port).parse().unwrap(),
+    ];
+
+    // when:
+    let start = Instant::now();
+    let stream = race_connections(addresses, Some(Duration::from_millis(50)), SocketOptions::default())
+      .await
+      .unwrap();
+
+    // then:
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(stream.peer_addr().unwrap().ip(), IpAddr::from([127, 0, 0, 1]));
+  }
+
+  #[tokio::test]
+  async fn test_race_connections_fails_if_all_attempts_fail() {
+    // given: nothing listens on port 1
+    let addresses = vec!["127.0.0.1:1".parse().unwrap(), "127.0.0.2:1".parse().unwrap()];
+
+    // when:
+    let result = race_connections(addresses, None, SocketOptions::default()).await;
+
+    // then: the error of the last attempt is returned
+    let error = result.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    assert!(error.to_string().contains("127.0.0.2:1"), "{}", error);
+  }
+
+  #[test]
+  fn test_check_local_address() {
+    assert!(check_local_address("127.0.0.1".parse().unwrap()).is_ok());
+    // TEST-NET-1 is reserved for documentation and never assigned to this host
+    let error = check_local_address("192.0.2.1".parse().unwrap()).unwrap_err();
+    assert!(error.to_string().contains("192.0.2.1"));
+  }
+
+  // The whole 127.0.0.0/8 block is assigned to the loopback interface on Linux
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn test_backend_connector_local_address() {
+    use hyper::{
+      server::conn::AddrStream,
+      service::{make_service_fn, service_fn},
+      Body, Client, Response, Server,
+    };
+    use std::convert::Infallible;
+
+    // given: a backend server, which responds with the address of the client
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend_address = listener.local_addr().unwrap();
+    let service = make_service_fn(|stream: &AddrStream| {
+      let remote_ip = stream.remote_addr().ip();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |_| async move {
+          Ok::<_, Infallible>(Response::new(Body::from(remote_ip.to_string())))
+        }))
+      }
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+    let mut connector = BackendConnector::new();
+    connector.set_local_address(Some("127.0.0.2".parse().unwrap()));
+    let client = Client::builder().build::<_, Body>(connector);
+
+    // when:
+    let response = client
+      .get(backend_uri(&backend_address.to_string(), PathAndQuery::from_static("/")).unwrap())
+      .await
+      .unwrap();
+
+    // then:
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "127.0.0.2");
+  }
+
+  #[tokio::test]
+  async fn test_backend_connector_upstream_proxy() {
+    use hyper::{
+      service::{make_service_fn, service_fn},
+      Body, Client, Response, Server,
+    };
+    use std::convert::Infallible;
+
+    // given: a proxy, which resolves any hostname to 127.0.0.1
+    let (proxy_address, targets) = crate::socks5::tests::start_proxy(Some(("user", "secret"))).await;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend_port = listener.local_addr().unwrap().port();
+    let service = make_service_fn(|_| async {
+      Ok::<_, Infallible>(service_fn(|_| async {
+        Ok::<_, Infallible>(Response::new(Body::from("proxied")))
+      }))
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+    let mut connector = BackendConnector::new();
+    let proxy = format!("socks5h://user:secret@{}", proxy_address);
+    connector.set_upstream_proxy(Some(proxy.parse().unwrap()));
+    let client = Client::builder().build::<_, Body>(connector.clone());
+
+    // when:
+    let backend_address = format!("backend.invalid:{}", backend_port);
+    let response = client
+      .get(backend_uri(&backend_address, PathAndQuery::from_static("/")).unwrap())
+      .await
+      .unwrap();
+    let closed_port = std::net::TcpListener::bind("127.0.0.1:0")
+      .unwrap()
+      .local_addr()
+      .unwrap()
+      .port();
+    let refused = connector
+      .call(backend_uri(&format!("127.0.0.1:{}", closed_port), PathAndQuery::from_static("/")).unwrap())
+      .await;
+
+    // then: the hostname was resolved by the proxy
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "proxied");
+    assert_eq!(
+      targets.lock().unwrap()[0],
+      Target::Domain("backend.invalid".into(), backend_port)
+    );
+    assert!(refused.is_err());
+    let proxy_address = proxy_address.to_string();
+    let labels = [("proxy", proxy_address.as_str()), ("reason", "refused")];
+    assert_eq!(METRICS.counter("arlb_upstream_proxy_failures_total", &labels), 1);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_backend_connector_unix() {
+    use hyper::{
+      service::{make_service_fn, service_fn},
+      Body, Client, Response, Server,
+    };
+    use std::convert::Infallible;
+    use tokio::net::UnixListener;
+
+    let path = std::env::temp_dir().join(format!("arlb-backend-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let incoming = async_stream::stream! {
+      loop {
+        yield listener.accept().await.map(|(stream, _)| stream);
+      }
+    };
+    let service = make_service_fn(|_| async {
+      Ok::<_, Infallible>(service_fn(|_| async {
+        Ok::<_, Infallible>(Response::new(Body::from("unix")))
+      }))
+    });
+    tokio::spawn(Server::builder(hyper::server::accept::from_stream(incoming)).serve(service));
+
+    let client = Client::builder().build::<_, Body>(BackendConnector::new());
+    let address = format!("{}{}", UNIX_ADDRESS_PREFIX, path.display());
+    let response = client
+      .get(backend_uri(&address, PathAndQuery::from_static("/")).unwrap())
+      .await
+      .unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(body, "unix");
+  }
+}
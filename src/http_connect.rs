@@ -0,0 +1,238 @@
+use crate::upstream_proxy::{ProxyError, Target, UpstreamProxy};
+use std::str;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// The longest response head accepted from the proxy.
+const MAX_RESPONSE_HEAD: usize = 8 * 1024;
+
+/// Asks the HTTP proxy on the already established `stream` to open a tunnel to
+/// the `target` via `CONNECT` (RFC 7231, section 4.3.6). Afterwards the stream
+/// is relayed to the target by the proxy.
+pub async fn handshake<S>(stream: &mut S, proxy: &UpstreamProxy, target: &Target) -> Result<(), ProxyError>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let authority = match target {
+    Target::Address(address) => address.to_string(),
+    Target::Domain(host, port) => format!("{}:{}", host, port),
+  };
+  let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+  if let Some(authorization) = proxy.authorization() {
+    request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+  }
+  request.push_str("\r\n");
+  stream.write_all(request.as_bytes()).await?;
+
+  let head = read_response_head(stream).await?;
+  let status = parse_status(&head)?;
+  if !(200..300).contains(&status) {
+    return Err(ProxyError::Rejected(status));
+  }
+  Ok(())
+}
+
+/// Reads one byte at a time, so none of the bytes relayed afterwards are
+/// consumed.
+async fn read_response_head<S>(stream: &mut S) -> Result<Vec<u8>, ProxyError>
+where
+  S: AsyncRead + Unpin,
+{
+  let mut head = Vec::new();
+  while !head.ends_with(b"\r\n\r\n") {
+    if head.len() >= MAX_RESPONSE_HEAD {
+      return Err(ProxyError::Handshake(
+        "the response head of the proxy is too long".into(),
+      ));
+    }
+    head.push(stream.read_u8().await?);
+  }
+  Ok(head)
+}
+
+/// The status of a response head like `HTTP/1.1 200 Connection established`.
+fn parse_status(head: &[u8]) -> Result<u16, ProxyError> {
+  let invalid = || ProxyError::Handshake("the proxy did not answer with HTTP/1.x".into());
+  let status_line = head.split(|it| *it == b'\r').next().unwrap_or_default();
+  let status_line = str::from_utf8(status_line).map_err(|_| invalid())?;
+  let mut parts = status_line.splitn(3, ' ');
+  match (parts.next(), parts.next()) {
+    (Some(version), Some(status)) if version.starts_with("HTTP/1.") => status.parse().map_err(|_| invalid()),
+    _ => Err(invalid()),
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+  };
+  use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+  };
+
+  /// Starts an HTTP proxy, which connects to `127.0.0.1` for any hostname and
+  /// requires the `authorization`, if given. Returns its address and the
+  /// authorities it was asked to connect to.
+  pub async fn start_proxy(authorization: Option<&'static str>) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let authorities = Arc::new(Mutex::new(Vec::new()));
+    let authorities_clone = authorities.clone();
+    tokio::spawn(async move {
+      loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let authorities = authorities_clone.clone();
+        tokio::spawn(async move {
+          let _ = serve(stream, authorization, authorities).await;
+        });
+      }
+    });
+    (address, authorities)
+  }
+
+  async fn serve(
+    client: TcpStream,
+    authorization: Option<&str>,
+    authorities: Arc<Mutex<Vec<String>>>,
+  ) -> io::Result<()> {
+    let mut client = BufReader::new(client);
+    let mut request_line = String::new();
+    client.read_line(&mut request_line).await?;
+    let authority = request_line.split(' ').nth(1).unwrap_or_default().to_string();
+    let mut authorized = authorization.is_none();
+    loop {
+      let mut line = String::new();
+      client.read_line(&mut line).await?;
+      if line == "\r\n" || line.is_empty() {
+        break;
+      }
+      if let (Some(expected), Some(given)) = (authorization, line.strip_prefix("Proxy-Authorization: ")) {
+        authorized |= given.trim_end() == expected;
+      }
+    }
+    authorities.lock().unwrap().push(authority.clone());
+    if !authorized {
+      return client
+        .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n")
+        .await;
+    }
+    let port = authority.rsplit(':').next().unwrap_or_default().parse().unwrap_or(0);
+    match TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port))).await {
+      Ok(mut backend) => {
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+        let (mut client_read, mut client_write) = client.get_mut().split();
+        let (mut backend_read, mut backend_write) = backend.split();
+        futures::future::try_join(
+          tokio::io::copy(&mut client_read, &mut backend_write),
+          tokio::io::copy(&mut backend_read, &mut client_write),
+        )
+        .await?;
+        Ok(())
+      }
+      Err(_) => client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await,
+    }
+  }
+
+  /// A backend server, which answers `ping` with `pong`.
+  async fn start_backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+          let mut ping = [0; 4];
+          stream.read_exact(&mut ping).await.unwrap();
+          stream.write_all(b"pong").await.unwrap();
+        });
+      }
+    });
+    address
+  }
+
+  async fn ping_through(proxy: &str, proxy_address: SocketAddr, target: &Target) -> Result<[u8; 4], ProxyError> {
+    let proxy = proxy.replace("{}", &proxy_address.to_string()).parse().unwrap();
+    let mut stream = TcpStream::connect(proxy_address).await.unwrap();
+    handshake(&mut stream, &proxy, target).await?;
+    stream.write_all(b"ping").await.unwrap();
+    let mut pong = [0; 4];
+    stream.read_exact(&mut pong).await.unwrap();
+    Ok(pong)
+  }
+
+  #[tokio::test]
+  async fn test_connect_tunnels_to_the_target() {
+    // given:
+    let (proxy_address, authorities) = start_proxy(None).await;
+    let backend_address = start_backend().await;
+
+    // when:
+    let address = ping_through("http://{}", proxy_address, &Target::Address(backend_address)).await;
+    let domain = Target::Domain("backend.internal".into(), backend_address.port());
+    let hostname = ping_through("http://{}", proxy_address, &domain).await;
+
+    // then:
+    assert_eq!(&address.unwrap(), b"pong");
+    assert_eq!(&hostname.unwrap(), b"pong");
+    assert_eq!(
+      *authorities.lock().unwrap(),
+      vec![
+        backend_address.to_string(),
+        format!("backend.internal:{}", backend_address.port())
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_connect_with_proxy_authorization() {
+    // given: the Basic credentials of user:secret
+    let (proxy_address, _) = start_proxy(Some("Basic dXNlcjpzZWNyZXQ=")).await;
+    let target = Target::Address(start_backend().await);
+
+    // when:
+    let accepted = ping_through("http://user:secret@{}", proxy_address, &target).await;
+    let rejected = ping_through("http://user:wrong@{}", proxy_address, &target).await;
+
+    // then:
+    assert_eq!(&accepted.unwrap(), b"pong");
+    let rejected = rejected.unwrap_err();
+    assert!(matches!(rejected, ProxyError::Rejected(407)), "{:?}", rejected);
+    assert_eq!(rejected.reason(), "refused");
+  }
+
+  #[tokio::test]
+  async fn test_connect_fails_if_the_proxy_can_not_connect() {
+    // given:
+    let (proxy_address, _) = start_proxy(None).await;
+    let closed_port = std::net::TcpListener::bind("127.0.0.1:0")
+      .unwrap()
+      .local_addr()
+      .unwrap()
+      .port();
+
+    // when:
+    let target = Target::Domain("closed.internal".into(), closed_port);
+    let result = ping_through("http://{}", proxy_address, &target).await;
+
+    // then:
+    let error = result.unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "The upstream proxy rejected the CONNECT with status 502"
+    );
+  }
+
+  #[test]
+  fn test_parse_status() {
+    assert_eq!(
+      parse_status(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap(),
+      200
+    );
+    assert_eq!(parse_status(b"HTTP/1.0 403\r\n\r\n").unwrap(), 403);
+    assert!(parse_status(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+  }
+}
@@ -0,0 +1,414 @@
+use crate::{
+  connections::LiveConnection,
+  listeners::{PeerCredentials, RemoteAddress},
+  tls::TlsInfo,
+};
+use futures::{Future, StreamExt};
+use hyper::{
+  body::HttpBody,
+  header::{HeaderValue, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING},
+  server::accept::Accept,
+  service::Service,
+  Body, Request, Response, Version,
+};
+use log::debug;
+use pin_project::pin_project;
+use std::{
+  io,
+  net::SocketAddr,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  task::{Context, Poll},
+  time::{Duration, Instant},
+};
+use tokio::{
+  io::{AsyncRead, AsyncWrite, ReadBuf},
+  time::{sleep, Sleep},
+};
+
+/// Limits the lifecycle of keep-alive client connections, so long-lived
+/// connections are re-established (and their requests re-routed) from time to
+/// time, for example after a reload of the configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeepAliveLimits {
+  /// How long a connection may be idle between requests.
+  pub idle_timeout: Option<Duration>,
+  /// How many requests a connection may serve.
+  pub max_requests: Option<u64>,
+  /// How long a connection may serve requests.
+  pub max_age: Option<Duration>,
+}
+
+impl Default for KeepAliveLimits {
+  fn default() -> Self {
+    KeepAliveLimits {
+      idle_timeout: Some(Duration::from_secs(75)),
+      max_requests: Some(1000),
+      max_age: Some(Duration::from_secs(3600)),
+    }
+  }
+}
+
+/// The state of one client connection, which is shared by its stream and its
+/// service.
+#[derive(Debug)]
+pub struct Lifecycle {
+  limits: KeepAliveLimits,
+  opened_at: Instant,
+  requests: AtomicU64,
+  in_flight: AtomicUsize,
+  /// When the last request in flight was done.
+  idle_since: Mutex<Instant>,
+}
+
+impl Lifecycle {
+  pub fn new(limits: KeepAliveLimits) -> Arc<Lifecycle> {
+    Arc::new(Lifecycle {
+      limits,
+      opened_at: Instant::now(),
+      requests: AtomicU64::new(0),
+      in_flight: AtomicUsize::new(0),
+      idle_since: Mutex::new(Instant::now()),
+    })
+  }
+
+  /// Counts a request as in flight until the returned value is dropped. The
+  /// flag tells whether it is the last request the connection may serve.
+  fn start_request(self: &Arc<Self>) -> (InFlight, bool) {
+    self.in_flight.fetch_add(1, Ordering::Relaxed);
+    let requests = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+    let exhausted = matches!(self.limits.max_requests, Some(max) if requests >= max);
+    let expired = matches!(self.limits.max_age, Some(max) if self.opened_at.elapsed() >= max);
+    (InFlight(self.clone()), exhausted || expired)
+  }
+
+  /// When the connection times out or `None` while a request is in flight.
+  fn idle_deadline(&self, timeout: Duration) -> Option<Instant> {
+    if self.in_flight.load(Ordering::Relaxed) == 0 {
+      Some(*self.idle_since.lock().unwrap() + timeout)
+    } else {
+      None
+    }
+  }
+}
+
+struct InFlight(Arc<Lifecycle>);
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    *self.0.idle_since.lock().unwrap() = Instant::now();
+    self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// A service, which asks the client to close its connection via
+/// `Connection: close` on the last response the connection may serve. Hyper
+/// closes the connection after sending that response, so no response is cut
+/// off.
+pub struct KeepAlive<S> {
+  service: S,
+  lifecycle: Arc<Lifecycle>,
+}
+
+impl<S> KeepAlive<S> {
+  pub fn new(service: S, lifecycle: Arc<Lifecycle>) -> KeepAlive<S> {
+    KeepAlive { service, lifecycle }
+  }
+}
+
+impl<S> Service<Request<Body>> for KeepAlive<S>
+where
+  S: Service<Request<Body>, Response = Response<Body>>,
+  S::Future: Send + 'static,
+{
+  type Response = Response<Body>;
+  type Error = S::Error;
+
+  // let's allow this complex type. A refactor would make it more complicated due to the used trait types
+  #[allow(clippy::type_complexity)]
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.service.poll_ready(cx)
+  }
+
+  fn call(&mut self, request: Request<Body>) -> Self::Future {
+    // HTTP/2 does not support `Connection: close`
+    let http1 = request.version() <= Version::HTTP_11;
+    let (in_flight, last) = self.lifecycle.start_request();
+    let response = self.service.call(request);
+    Box::pin(async move {
+      let mut response = response.await?;
+      if http1 && last {
+        response
+          .headers_mut()
+          .insert(CONNECTION, HeaderValue::from_static("close"));
+      }
+      if response.body().is_end_stream() {
+        return Ok(response);
+      }
+      // The connection is idle once the body was sent
+      let (mut parts, body) = response.into_parts();
+      if let Some(length) = body.size_hint().exact() {
+        // The wrapped body has no size, so it would be sent chunked otherwise
+        if !parts.headers.contains_key(CONTENT_LENGTH) && !parts.headers.contains_key(TRANSFER_ENCODING) {
+          parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(length));
+        }
+      }
+      let body = Body::wrap_stream(body.map(move |chunk| {
+        let _in_flight = &in_flight;
+        chunk
+      }));
+      Ok(Response::from_parts(parts, body))
+    })
+  }
+}
+
+/// A client connection, which ends (like a connection closed by the client)
+/// once no request was in flight for the `idle_timeout`.
+pub struct KeepAliveStream<IO> {
+  inner: IO,
+  lifecycle: Arc<Lifecycle>,
+  idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<IO> KeepAliveStream<IO> {
+  pub fn new(inner: IO, lifecycle: Arc<Lifecycle>) -> KeepAliveStream<IO> {
+    KeepAliveStream {
+      inner,
+      lifecycle,
+      idle: None,
+    }
+  }
+
+  pub fn lifecycle(&self) -> Arc<Lifecycle> {
+    self.lifecycle.clone()
+  }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for KeepAliveStream<IO> {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let this = self.get_mut();
+    let filled = buf.filled().len();
+    match Pin::new(&mut this.inner).poll_read(cx, buf) {
+      Poll::Ready(result) => {
+        if buf.filled().len() > filled {
+          this.idle = None;
+        }
+        Poll::Ready(result)
+      }
+      Poll::Pending => {
+        if let Some(timeout) = this.lifecycle.limits.idle_timeout {
+          // The timer keeps running while a request is in flight, so the
+          // connection is woken up to check again, once the request is done
+          let idle = this.idle.get_or_insert_with(|| Box::pin(sleep(timeout)));
+          while idle.as_mut().poll(cx).is_ready() {
+            let now = Instant::now();
+            match this.lifecycle.idle_deadline(timeout) {
+              Some(deadline) if deadline <= now => {
+                debug!("Closing a client connection, which was idle for {:?}", timeout);
+                // Reading nothing tells hyper, that the connection was closed
+                return Poll::Ready(Ok(()));
+              }
+              Some(deadline) => idle.as_mut().reset(deadline.into()),
+              None => idle.as_mut().reset((now + timeout).into()),
+            }
+          }
+        }
+        Poll::Pending
+      }
+    }
+  }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for KeepAliveStream<IO> {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+    Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+  }
+}
+
+impl<IO: RemoteAddress> RemoteAddress for KeepAliveStream<IO> {
+  fn remote_addr(&self) -> io::Result<SocketAddr> {
+    self.inner.remote_addr()
+  }
+
+  fn tls_info(&self) -> Option<TlsInfo> {
+    self.inner.tls_info()
+  }
+
+  fn connection(&self) -> Option<Arc<LiveConnection>> {
+    self.inner.connection()
+  }
+
+  fn peer_credentials(&self) -> Option<PeerCredentials> {
+    self.inner.peer_credentials()
+  }
+}
+
+/// Wraps the connections of `inner` in a [`KeepAliveStream`] with the limits
+/// returned by `limits` at the time they are accepted.
+#[pin_project]
+pub struct KeepAliveAcceptor<I, F> {
+  #[pin]
+  inner: I,
+  limits: F,
+}
+
+impl<I, F> KeepAliveAcceptor<I, F> {
+  pub fn new(inner: I, limits: F) -> KeepAliveAcceptor<I, F> {
+    KeepAliveAcceptor { inner, limits }
+  }
+}
+
+impl<I, F> Accept for KeepAliveAcceptor<I, F>
+where
+  I: Accept,
+  F: Fn() -> KeepAliveLimits,
+{
+  type Conn = KeepAliveStream<I::Conn>;
+  type Error = I::Error;
+
+  fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+    let this = self.project();
+    let limits = this.limits;
+    this.inner.poll_accept(cx).map(|connection| {
+      connection.map(|connection| connection.map(|it| KeepAliveStream::new(it, Lifecycle::new(limits()))))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::{server::conn::Http, service::service_fn};
+  use std::convert::Infallible;
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+  };
+
+  /// Starts a server, whose connections are limited by `limits`.
+  async fn start_server(limits: KeepAliveLimits) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      loop {
+        let (socket, _) = listener.accept().await.unwrap();
+        let lifecycle = Lifecycle::new(limits);
+        let service = service_fn(|_| async { Ok::<_, Infallible>(Response::new(Body::from("ok"))) });
+        let connection = Http::new().serve_connection(
+          KeepAliveStream::new(socket, lifecycle.clone()),
+          KeepAlive::new(service, lifecycle),
+        );
+        tokio::spawn(connection);
+      }
+    });
+    address
+  }
+
+  /// Reads one response with a body of `ok` and returns its head.
+  async fn read_response(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\nok") {
+      let mut buf = [0; 1024];
+      let len = stream.read(&mut buf).await.unwrap();
+      assert!(len > 0, "closed after {:?}", String::from_utf8_lossy(&response));
+      response.extend_from_slice(&buf[..len]);
+    }
+    String::from_utf8(response).unwrap().to_lowercase()
+  }
+
+  async fn is_closed(stream: &mut TcpStream) -> bool {
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut [0; 16])).await;
+    matches!(read, Ok(Ok(0)))
+  }
+
+  #[tokio::test]
+  async fn test_last_permitted_response_closes_the_connection() {
+    // given:
+    let limits = KeepAliveLimits {
+      max_requests: Some(3),
+      ..KeepAliveLimits::default()
+    };
+    let address = start_server(limits).await;
+    let mut stream = TcpStream::connect(address).await.unwrap();
+
+    // when:
+    let mut responses = Vec::new();
+    for _ in 0..3 {
+      stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+      responses.push(read_response(&mut stream).await);
+    }
+
+    // then:
+    assert!(!responses[0].contains("connection: close"), "{}", responses[0]);
+    assert!(!responses[1].contains("connection: close"), "{}", responses[1]);
+    assert!(responses[2].contains("connection: close"), "{}", responses[2]);
+    assert!(is_closed(&mut stream).await);
+  }
+
+  #[tokio::test]
+  async fn test_expired_connection_closes_after_the_response() {
+    // given:
+    let limits = KeepAliveLimits {
+      max_age: Some(Duration::from_millis(0)),
+      ..KeepAliveLimits::default()
+    };
+    let address = start_server(limits).await;
+    let mut stream = TcpStream::connect(address).await.unwrap();
+
+    // when:
+    stream
+      .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+      .await
+      .unwrap();
+    let response = read_response(&mut stream).await;
+
+    // then:
+    assert!(response.contains("connection: close"), "{}", response);
+    assert!(is_closed(&mut stream).await);
+  }
+
+  #[tokio::test]
+  async fn test_idle_connection_is_closed_after_the_idle_timeout() {
+    // given:
+    let limits = KeepAliveLimits {
+      idle_timeout: Some(Duration::from_millis(200)),
+      ..KeepAliveLimits::default()
+    };
+    let address = start_server(limits).await;
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+      .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+      .await
+      .unwrap();
+    let response = read_response(&mut stream).await;
+
+    // when:
+    let started = Instant::now();
+    let closed = is_closed(&mut stream).await;
+
+    // then:
+    assert!(!response.contains("connection: close"), "{}", response);
+    assert!(closed);
+    assert!(
+      started.elapsed() >= Duration::from_millis(150),
+      "{:?}",
+      started.elapsed()
+    );
+  }
+}
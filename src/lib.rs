@@ -0,0 +1,92 @@
+//! Another Rust Load Balancer, which can be embedded into other applications
+//! instead of running the binary. The [`LoadBalancer`] reads its configuration
+//! file like the binary (see `docs/configuration.md`), or takes a [`Config`]
+//! from another source, and runs on the Tokio runtime of the caller.
+//!
+//! ```no_run
+//! use another_rust_load_balancer::{Error, LoadBalancer};
+//! use tokio_util::sync::CancellationToken;
+//!
+//! #[tokio::main]
+//! async fn main() -> Result<(), Error> {
+//!   let load_balancer = LoadBalancer::new("config.toml").await?;
+//!   let shutdown = CancellationToken::new();
+//!   tokio::spawn({
+//!     let shutdown = shutdown.clone();
+//!     async move {
+//!       let _ = tokio::signal::ctrl_c().await;
+//!       shutdown.cancel();
+//!     }
+//!   });
+//!   // Returns once the connections were drained after ctrl-c
+//!   load_balancer.run_with_shutdown(shutdown).await
+//! }
+//! ```
+
+mod acme;
+mod address_policy;
+mod backend_drains;
+mod backend_pool_matcher;
+mod bandwidth;
+mod bans;
+mod builtin_backends;
+mod concurrency_limit;
+mod configuration;
+mod connections;
+mod dns;
+mod drain;
+mod dynamic_backends;
+mod error;
+mod error_response;
+mod events;
+mod expect_continue;
+mod fault_injection;
+mod file_descriptors;
+mod flow_sampling;
+mod forward_proxy;
+mod geoip;
+mod health;
+mod http_client;
+mod http_connect;
+mod keep_alive;
+mod listeners;
+mod load_balancer;
+mod load_balancing;
+mod logging;
+mod maintenance_windows;
+mod metrics;
+mod middleware;
+mod outlier_detection;
+mod prewarm;
+mod proxy_protocol;
+mod readiness;
+mod redirects;
+mod request_validation;
+mod retry;
+mod server;
+mod socks5;
+mod state_file;
+mod static_response;
+mod tcp_router;
+mod telemetry;
+mod tenants;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+mod throttle;
+mod tls;
+mod tls_passthrough;
+mod top_talkers;
+mod trusted_proxies;
+mod udp;
+mod upstream_proxy;
+mod utils;
+mod webhook;
+
+pub use configuration::{check_config, Config};
+pub use error::Error;
+pub use events::{CloseReason, ConnectionStats, Event, EventSubscriber, RequestSummary};
+pub use load_balancer::LoadBalancer;
+pub use logging::{initialize as initialize_logging, Logging};
+pub use readiness::BoundAddress;
+pub use server::Scheme;
+pub use tls::self_signed;
@@ -1,24 +1,72 @@
+#[cfg(target_os = "linux")]
+use crate::http_client::{set_dscp, set_tcp_fast_open, set_tcp_keepalive};
+use crate::{
+  bans,
+  connections::LiveConnection,
+  file_descriptors,
+  http_client::TcpKeepalive,
+  keep_alive::KeepAliveLimits,
+  logging,
+  metrics::METRICS,
+  server::Scheme,
+  telemetry::TELEMETRY,
+  tls::{client_hello_server_name, Certificates, ExcessHandshakes, HandshakeLimits, HandshakeRateLimit, TlsInfo},
+};
 use async_stream::stream;
 use async_trait::async_trait;
-use futures::Stream;
-use log::{error, info};
+use futures::{stream::FuturesUnordered, Future, FutureExt, Stream, StreamExt};
+use linked_hash_map::LinkedHashMap;
+use log::{debug, info, log_enabled, warn, Level};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
 use std::{
+  fmt::Display,
   io,
-  net::SocketAddr,
+  net::{IpAddr, SocketAddr},
   pin::Pin,
   sync::Arc,
   task::{Context, Poll},
+  time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+  fs::{self, Permissions},
+  net::Ipv4Addr,
+  os::unix::fs::{FileTypeExt, PermissionsExt},
+  path::PathBuf,
+};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+  io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+  net::{TcpListener, TcpStream},
+  select,
+  sync::mpsc,
+  time::{timeout, timeout_at},
 };
-use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::server::TlsStream;
-use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tokio_rustls::{
+  rustls::{ServerConfig, TLSError},
+  TlsAcceptor,
+};
 
 pub struct HyperAcceptor<'a, T> {
   acceptor: Pin<Box<dyn Stream<Item = Result<T, io::Error>> + Send + 'a>>,
+  /// The bound address of TCP listeners, which differs from the configured
+  /// one for port `0`.
+  local_addr: Option<SocketAddr>,
+}
+
+impl<T> HyperAcceptor<'_, T> {
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self.local_addr
+  }
 }
 
-impl hyper::server::accept::Accept for HyperAcceptor<'_, TcpStream> {
-  type Conn = TcpStream;
+impl hyper::server::accept::Accept for HyperAcceptor<'_, MaybeTlsStream> {
+  type Conn = MaybeTlsStream;
   type Error = io::Error;
 
   fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
@@ -35,35 +83,568 @@ impl hyper::server::accept::Accept for HyperAcceptor<'_, TlsStream<TcpStream>> {
   }
 }
 
+#[cfg(unix)]
+impl hyper::server::accept::Accept for HyperAcceptor<'_, UnixStream> {
+  type Conn = UnixStream;
+  type Error = io::Error;
+
+  fn poll_accept(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+    Pin::new(&mut self.acceptor).poll_next(cx)
+  }
+}
+
 #[async_trait]
 pub trait AcceptorProducer<T> {
   async fn produce_acceptor(self, address: SocketAddr) -> Result<HyperAcceptor<'async_trait, T>, io::Error>;
 }
 
-pub struct Http;
+/// Options of the sockets, which listen for TCP connections.
+#[derive(Debug, Clone)]
+pub struct TcpListenOptions {
+  /// If set, other processes (like a newer version of the load balancer) can
+  /// bind to the same address simultaneously.
+  pub reuse_port: bool,
+  /// If set, it determines whether an IPv6 listener also accepts IPv4
+  /// connections (as IPv4-mapped IPv6 addresses), otherwise the default of the
+  /// operating system is used.
+  pub dual_stack: Option<bool>,
+  /// Inherited by accepted connections.
+  pub tcp_keepalive: Option<TcpKeepalive>,
+  /// The DSCP of the packets sent to clients, inherited by accepted
+  /// connections.
+  pub dscp: Option<u8>,
+  /// If set, clients can send their request within the SYN via TCP Fast Open.
+  pub tcp_fast_open: bool,
+  /// The maximum number of connections, which wait to be accepted.
+  pub backlog: u32,
+  /// The number of sockets, which are bound to the same address (via
+  /// `SO_REUSEPORT`), so the kernel distributes connections across them and
+  /// each one is accepted by its own task.
+  pub acceptors: usize,
+}
 
-#[async_trait]
-impl AcceptorProducer<TcpStream> for Http {
-  async fn produce_acceptor(self, address: SocketAddr) -> Result<HyperAcceptor<'async_trait, TcpStream>, io::Error> {
-    let listener = TcpListener::bind(address).await?;
+impl Default for TcpListenOptions {
+  fn default() -> TcpListenOptions {
+    TcpListenOptions {
+      reuse_port: false,
+      dual_stack: None,
+      tcp_keepalive: None,
+      dscp: None,
+      tcp_fast_open: false,
+      backlog: 1024,
+      acceptors: 1,
+    }
+  }
+}
 
-    let incoming_stream = stream! {
+/// Binds a TCP listener with the `options`.
+pub(crate) fn bind_tcp(address: SocketAddr, options: &TcpListenOptions) -> io::Result<TcpListener> {
+  let domain = match address {
+    SocketAddr::V4(_) => Domain::ipv4(),
+    SocketAddr::V6(_) => Domain::ipv6(),
+  };
+  let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+  if let (SocketAddr::V6(_), Some(dual_stack)) = (address, options.dual_stack) {
+    socket.set_only_v6(!dual_stack)?;
+  }
+  #[cfg(unix)]
+  {
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(options.reuse_port)?;
+  }
+  #[cfg(not(unix))]
+  if options.reuse_port {
+    return Err(io::Error::new(
+      io::ErrorKind::Other,
+      "reuse_port is not supported on this platform",
+    ));
+  }
+  // Accepted connections inherit the keepalive of the listening socket
+  #[cfg(target_os = "linux")]
+  if let Some(tcp_keepalive) = &options.tcp_keepalive {
+    set_tcp_keepalive(socket.as_raw_fd(), tcp_keepalive)?;
+  }
+  #[cfg(target_os = "linux")]
+  if let Some(dscp) = options.dscp {
+    set_dscp(socket.as_raw_fd(), address.is_ipv6(), dscp)?;
+  }
+  #[cfg(target_os = "linux")]
+  if options.tcp_fast_open {
+    set_tcp_fast_open(socket.as_raw_fd())?;
+  }
+  socket.bind(&SockAddr::from(address))?;
+  socket.listen(options.backlog.min(i32::MAX as u32) as i32)?;
+  socket.set_nonblocking(true)?;
+  TcpListener::from_std(socket.into_tcp_listener())
+}
+
+/// Binds a TCP listener for the services next to the HTTP(S) listeners (like
+/// TLS passthrough services), which only share the `backlog` of the
+/// `listen_backlog`.
+pub(crate) fn bind_with_backlog(address: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+  bind_tcp(
+    address,
+    &TcpListenOptions {
+      backlog,
+      ..TcpListenOptions::default()
+    },
+  )
+}
+
+/// Binds one TCP listener per acceptor of the `options`. Several acceptors
+/// require `SO_REUSEPORT`, which is enabled for them regardless of
+/// `reuse_port`. All of them listen on the address of the first one, so port
+/// `0` works as well.
+fn bind_acceptors(address: SocketAddr, options: &TcpListenOptions) -> io::Result<Vec<TcpListener>> {
+  if options.acceptors <= 1 {
+    return Ok(vec![bind_tcp(address, options)?]);
+  }
+  let options = TcpListenOptions {
+    reuse_port: true,
+    ..options.clone()
+  };
+  let first = bind_tcp(address, &options)?;
+  let address = first.local_addr()?;
+  let mut listeners = vec![first];
+  for _ in 1..options.acceptors {
+    listeners.push(bind_tcp(address, &options)?);
+  }
+  Ok(listeners)
+}
+
+/// Yields the connections of all `listeners`, which are accepted by the
+/// streams of `accept`. A single listener is accepted directly, otherwise each
+/// stream is driven by its own task, so accepting connections and performing
+/// handshakes scales across the worker threads. The tasks end and close their
+/// listener once the returned stream is dropped.
+fn accept_all<T, S, F>(listeners: Vec<TcpListener>, accept: F) -> Pin<Box<dyn Stream<Item = io::Result<T>> + Send>>
+where
+  T: Send + Unpin + 'static,
+  S: Stream<Item = io::Result<T>> + Send + 'static,
+  F: Fn(TcpListener) -> S,
+{
+  if listeners.len() == 1 {
+    return Box::pin(accept(listeners.into_iter().next().unwrap()));
+  }
+  let (sender, mut receiver) = mpsc::channel(listeners.len());
+  for listener in listeners {
+    let accepted = accept(listener);
+    let sender = sender.clone();
+    tokio::spawn(async move {
+      let mut accepted = Box::pin(accepted);
       loop {
-        let (socket, _) = listener.accept().await?;
-        yield Ok(socket);
+        let item = select! {
+          item = accepted.next() => item,
+          _ = sender.closed() => None,
+        };
+        match item {
+          Some(item) => {
+            if sender.send(item).await.is_err() {
+              break;
+            }
+          }
+          None => break,
+        }
+      }
+    });
+  }
+  Box::pin(stream! {
+    while let Some(item) = receiver.recv().await {
+      yield item;
+    }
+  })
+}
+
+const MIN_ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Retries accepting connections after transient errors, so running out of
+/// file descriptors or memory for a moment does not stop the listener (and
+/// with it the whole load balancer). Only errors of the listening socket itself
+/// are returned.
+pub(crate) struct AcceptRetry {
+  delay: Duration,
+  /// A file descriptor, which is released to accept and close connections
+  /// while no other file descriptors are left, so clients do not wait in the
+  /// backlog for a connection that is never served.
+  #[cfg(target_os = "linux")]
+  reserve: Option<fs::File>,
+}
+
+impl AcceptRetry {
+  pub(crate) fn new() -> AcceptRetry {
+    AcceptRetry {
+      delay: MIN_ACCEPT_RETRY_DELAY,
+      #[cfg(target_os = "linux")]
+      reserve: fs::File::open("/dev/null").ok(),
+    }
+  }
+
+  /// Calls `accept` until it succeeds or fails with an unrecoverable error.
+  /// After errors like `EMFILE` the delay before the next attempt doubles up to
+  /// one second and is reset once a connection was accepted.
+  pub(crate) async fn accept<T, F, A>(&mut self, mut accept: F) -> io::Result<T>
+  where
+    F: FnMut() -> A,
+    A: Future<Output = io::Result<T>>,
+  {
+    loop {
+      let error = match accept().await {
+        Ok(accepted) => {
+          self.delay = MIN_ACCEPT_RETRY_DELAY;
+          return Ok(accepted);
+        }
+        Err(error) => error,
+      };
+      if is_fatal_accept_error(&error) {
+        return Err(error);
       }
+      if is_connection_error(&error) {
+        // The client gave up before its connection was accepted
+        debug!("Could not accept a connection: {}", error);
+        continue;
+      }
+      METRICS.increment("arlb_accept_errors_total", &[]);
+      if file_descriptors::is_exhausted(&error) {
+        // Logged at most once per interval, since every connection fails alike
+        file_descriptors::record_exhaustion("accept", &error);
+        #[cfg(target_os = "linux")]
+        self.shed_connection(&mut accept);
+      } else {
+        warn!("Could not accept a connection, retrying in {:?}: {}", self.delay, error);
+      }
+      tokio::time::sleep(self.delay).await;
+      self.delay = (self.delay * 2).min(MAX_ACCEPT_RETRY_DELAY);
+    }
+  }
+
+  /// Accepts and closes the next connection of the backlog with the reserved
+  /// file descriptor, if it is immediately available.
+  #[cfg(target_os = "linux")]
+  fn shed_connection<T, F, A>(&mut self, accept: &mut F)
+  where
+    F: FnMut() -> A,
+    A: Future<Output = io::Result<T>>,
+  {
+    if self.reserve.take().is_some() {
+      if let Some(Ok(_)) = accept().now_or_never() {
+        warn!("Closed a new connection, because no file descriptors are left");
+      }
+    }
+    self.reserve = fs::File::open("/dev/null").ok();
+  }
+}
+
+/// Whether accepting failed, because the listening socket is unusable (for
+/// example because it was closed), so retrying is pointless.
+fn is_fatal_accept_error(error: &io::Error) -> bool {
+  #[cfg(target_os = "linux")]
+  if matches!(error.raw_os_error(), Some(libc::EBADF) | Some(libc::ENOTSOCK)) {
+    return true;
+  }
+  error.kind() == io::ErrorKind::InvalidInput
+}
+
+/// Whether accepting failed, because of the connection to accept, so the next
+/// connection can be accepted right away.
+fn is_connection_error(error: &io::Error) -> bool {
+  matches!(
+    error.kind(),
+    io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+  )
+}
+
+/// What happens to connections of the HTTP port, which start with a TLS
+/// handshake, for example because a client was configured with the wrong port.
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsOnHttpPort {
+  /// Connections are not inspected, so TLS connections fail to parse as HTTP.
+  #[default]
+  Ignore,
+  /// TLS connections are closed right away.
+  Reject,
+  /// TLS connections are served like connections of the HTTPS port.
+  Terminate,
+}
+
+/// An additional listener for HTTP or HTTPS requests next to the
+/// `http_address` and the `https_address`.
+pub struct ListenerConfig {
+  /// Identifies the listener across reloads.
+  pub name: String,
+  pub address: SocketAddr,
+  /// Whether connections are TLS connections, which are served like
+  /// connections of the HTTPS port.
+  pub tls: bool,
+  /// The names of the backend pools, which serve requests of this listener.
+  /// All pools do, if there are none.
+  pub pools: Option<Vec<String>>,
+  /// The certificates of a TLS listener. Listeners without certificates use
+  /// the global ones.
+  pub certificates: Certificates,
+  pub keep_alive: KeepAliveLimits,
+}
+
+impl ListenerConfig {
+  pub fn scheme(&self) -> Scheme {
+    if self.tls {
+      Scheme::HTTPS
+    } else {
+      Scheme::HTTP
+    }
+  }
+
+  /// Whether requests of this listener may be served by the pool `name`.
+  pub fn serves(&self, name: &str) -> bool {
+    self
+      .pools
+      .as_ref()
+      .map(|pools| pools.iter().any(|it| it == name))
+      .unwrap_or(true)
+  }
+}
+
+/// Closes connections of the HTTP port, which do not start with an HTTP
+/// request line, before they reach the HTTP parser or any backend server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpPortGuard {
+  /// How many bytes the request line may have at most.
+  pub max_bytes: usize,
+  /// How long a client may take to send the request line.
+  pub timeout: Duration,
+  /// Whether rejected connections are answered with `400 Bad Request`
+  /// before they are closed.
+  pub respond_bad_request: bool,
+}
+
+/// How far the first bytes of a connection are an HTTP request line.
+#[derive(Debug, PartialEq)]
+enum RequestLine {
+  Complete,
+  /// The bytes are the start of a request line.
+  Partial,
+  Invalid,
+}
+
+/// The start of the connection preface of HTTP/2 with prior knowledge.
+const HTTP2_PREFACE_LINE: &[u8] = b"PRI * HTTP/2.0\r\n";
+
+const BAD_REQUEST: &[u8] = b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+/// How long the guard waits for more bytes, if the client sent only a part of
+/// the request line yet.
+const GUARD_PAUSE: Duration = Duration::from_millis(10);
+
+/// Checks, whether `bytes` start with a plausible request line: a method
+/// token, a space, a target, a space and `HTTP/1.x`. Empty lines before the
+/// request line are ignored like by the HTTP parser.
+fn request_line(bytes: &[u8]) -> RequestLine {
+  let start = bytes.iter().take_while(|it| matches!(it, b'\r' | b'\n')).count();
+  let bytes = &bytes[start..];
+  if bytes.starts_with(HTTP2_PREFACE_LINE) {
+    return RequestLine::Complete;
+  }
+  if bytes.len() < HTTP2_PREFACE_LINE.len() && HTTP2_PREFACE_LINE.starts_with(bytes) {
+    return RequestLine::Partial;
+  }
+  let is_token = |it: &u8| it.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(it);
+  let method_len = bytes.iter().take_while(|it| is_token(it)).count();
+  let rest = &bytes[method_len..];
+  match rest.first() {
+    None => return RequestLine::Partial,
+    Some(b' ') if method_len > 0 => {}
+    Some(_) => return RequestLine::Invalid,
+  }
+  let target_len = rest[1..].iter().take_while(|it| it.is_ascii_graphic()).count();
+  let rest = &rest[1 + target_len..];
+  match rest.first() {
+    None => return RequestLine::Partial,
+    Some(b' ') if target_len > 0 => {}
+    Some(_) => return RequestLine::Invalid,
+  }
+  let version = &rest[1..];
+  let prefix = b"HTTP/1.";
+  let compared = version.len().min(prefix.len());
+  if version[..compared] != prefix[..compared] {
+    return RequestLine::Invalid;
+  }
+  match &version[compared..] {
+    [] => RequestLine::Partial,
+    [minor, rest @ ..] if minor.is_ascii_digit() => match rest {
+      [] | [b'\r'] => RequestLine::Partial,
+      [b'\n', ..] | [b'\r', b'\n', ..] => RequestLine::Complete,
+      _ => RequestLine::Invalid,
+    },
+    _ => RequestLine::Invalid,
+  }
+}
+
+impl HttpPortGuard {
+  /// Peeks at the first bytes of `socket` (without consuming them) until they
+  /// form a request line. Returns the reason, why the connection is rejected
+  /// otherwise.
+  async fn inspect(&self, socket: &TcpStream) -> Result<(), &'static str> {
+    let deadline = Instant::now() + self.timeout;
+    let mut buffer = vec![0; self.max_bytes];
+    let mut peeked = 0;
+    loop {
+      let read = match timeout_at(deadline.into(), socket.peek(&mut buffer)).await {
+        Ok(Ok(0)) | Ok(Err(_)) => return Err("closed"),
+        Ok(Ok(read)) => read,
+        Err(_) => return Err("timeout"),
+      };
+      match request_line(&buffer[..read]) {
+        RequestLine::Complete => return Ok(()),
+        RequestLine::Invalid => return Err("not_http"),
+        RequestLine::Partial if read == buffer.len() => return Err("too_long"),
+        RequestLine::Partial => {}
+      }
+      // Peeking again returns right away, until the client sent more bytes
+      if read == peeked {
+        tokio::time::sleep(GUARD_PAUSE).await;
+        if Instant::now() >= deadline {
+          return Err("timeout");
+        }
+      }
+      peeked = read;
+    }
+  }
+
+  /// Whether the connection of `peer` starts with a request line. Other
+  /// connections are counted and answered with `400 Bad Request`, if
+  /// configured, so the caller only has to close them.
+  async fn admit(&self, socket: &mut TcpStream, peer: SocketAddr) -> bool {
+    let reason = match self.inspect(socket).await {
+      Ok(()) => return true,
+      Err(reason) => reason,
     };
+    METRICS.increment("arlb_http_port_rejected_connections_total", &[("reason", reason)]);
+    debug!("Rejected connection of {} on the HTTP port: {}", peer, reason);
+    if self.respond_bad_request && reason != "closed" {
+      let _ = timeout(GUARD_PAUSE, socket.write_all(BAD_REQUEST)).await;
+    }
+    false
+  }
+}
 
-    info!("Started listening for HTTP requests on {}", address);
+pub struct Http {
+  pub listen_options: TcpListenOptions,
+  pub tls_on_http_port: TlsOnHttpPort,
+  /// Terminates TLS connections, if `tls_on_http_port` is `terminate`.
+  pub tls_config: Option<ServerConfig>,
+  pub handshake_limits: HandshakeLimits,
+  pub guard: Option<HttpPortGuard>,
+}
+
+#[async_trait]
+impl AcceptorProducer<MaybeTlsStream> for Http {
+  async fn produce_acceptor(
+    self,
+    address: SocketAddr,
+  ) -> Result<HyperAcceptor<'async_trait, MaybeTlsStream>, io::Error> {
+    let listeners = bind_acceptors(address, &self.listen_options)?;
+    let local_addr = listeners[0].local_addr()?;
+
+    let acceptor = match (self.tls_on_http_port, self.guard) {
+      (TlsOnHttpPort::Ignore, None) => accept_all(listeners, |listener| {
+        stream! {
+          let mut retry = AcceptRetry::new();
+          loop {
+            let (socket, peer) = retry.accept(|| listener.accept()).await?;
+            if let Some(socket) = bans::admit(socket, peer) {
+              yield Ok(MaybeTlsStream::Plain(socket));
+            }
+          }
+        }
+      }),
+      (mode, guard) => {
+        let tls_acceptor = self.tls_config.map(|it| TlsAcceptor::from(Arc::new(it)));
+        let limits = self.handshake_limits;
+        accept_all(listeners, |listener| {
+          detect_tls(listener, mode, tls_acceptor.clone(), limits.clone(), guard.clone())
+        })
+      }
+    };
+
+    info!("Started listening for HTTP requests on {}", local_addr);
 
     Ok(HyperAcceptor {
-      acceptor: Box::pin(incoming_stream),
+      acceptor,
+      local_addr: Some(local_addr),
     })
   }
 }
 
+/// Whether the first bytes of a connection look like a TLS record with a
+/// handshake (`0x16`) of protocol version 3.x (`0x03`), which is true for all
+/// versions from SSL 3.0 to TLS 1.3. No HTTP request starts with `0x16`, so the
+/// first byte alone is enough to tell them apart.
+fn looks_like_tls(bytes: &[u8]) -> bool {
+  matches!(bytes, [0x16] | [0x16, 0x03, ..])
+}
+
+/// Accepts connections of `listener` and peeks at their first bytes (without
+/// consuming them) to [`reject`](TlsOnHttpPort::Reject) or
+/// [`terminate`](TlsOnHttpPort::Terminate) TLS connections. Connections, which
+/// send nothing within the handshake timeout, are treated as plain HTTP.
+/// Plain connections have to pass the `guard`, if there is one.
+fn detect_tls(
+  listener: TcpListener,
+  mode: TlsOnHttpPort,
+  tls_acceptor: Option<TlsAcceptor>,
+  limits: HandshakeLimits,
+  guard: Option<HttpPortGuard>,
+) -> impl Stream<Item = io::Result<MaybeTlsStream>> {
+  let handshake_timeout = limits.timeout;
+  // Only TLS connections are subject to the rate limit and a plain HTTP port
+  // should not close connections just because many clients connect at once
+  let limits = HandshakeLimits {
+    excess: ExcessHandshakes::Wait,
+    per_client: None,
+    ..limits
+  };
+  handshakes(listener, limits, "http", move |mut socket, peer| {
+    let tls_acceptor = tls_acceptor.clone();
+    let guard = guard.clone();
+    async move {
+      let mut first_bytes = [0; 2];
+      let read = match mode {
+        // TLS connections are left to the guard
+        TlsOnHttpPort::Ignore => 0,
+        _ => match timeout(handshake_timeout, socket.peek(&mut first_bytes)).await {
+          Ok(Ok(read)) => read,
+          Ok(Err(_)) | Err(_) => 0,
+        },
+      };
+      if !looks_like_tls(&first_bytes[..read]) {
+        if let Some(guard) = guard {
+          if !guard.admit(&mut socket, peer).await {
+            return Ok(None);
+          }
+        }
+        return Ok(Some(MaybeTlsStream::Plain(socket)));
+      }
+      match (mode, tls_acceptor) {
+        (TlsOnHttpPort::Terminate, Some(tls_acceptor)) => {
+          METRICS.increment("arlb_http_port_tls_connections_total", &[("action", "terminated")]);
+          let tls_stream = accept_tls(&tls_acceptor, socket, peer, handshake_timeout, "http").await;
+          Ok(tls_stream.map(|it| MaybeTlsStream::Tls(Box::new(it))))
+        }
+        _ => {
+          METRICS.increment("arlb_http_port_tls_connections_total", &[("action", "rejected")]);
+          debug!("Rejected TLS connection of {} on the HTTP port", peer);
+          Ok(None)
+        }
+      }
+    }
+  })
+}
+
 pub struct Https {
   pub tls_config: ServerConfig,
+  pub handshake_limits: HandshakeLimits,
+  pub listen_options: TcpListenOptions,
 }
 
 #[async_trait]
@@ -73,28 +654,414 @@ impl AcceptorProducer<TlsStream<TcpStream>> for Https {
     address: SocketAddr,
   ) -> Result<HyperAcceptor<'async_trait, TlsStream<TcpStream>>, io::Error> {
     let tls_acceptor = TlsAcceptor::from(Arc::new(self.tls_config));
-    let listener = TcpListener::bind(address).await?;
+    let listeners = bind_acceptors(address, &self.listen_options)?;
+    let local_addr = listeners[0].local_addr()?;
+
+    let limits = self.handshake_limits;
+    let acceptor = accept_all(listeners, |listener| {
+      tls_handshakes(listener, tls_acceptor.clone(), limits.clone())
+    });
+
+    info!("Started listening for HTTPS requests on {}", local_addr);
+
+    Ok(HyperAcceptor {
+      acceptor,
+      local_addr: Some(local_addr),
+    })
+  }
+}
+
+enum HandshakeEvent<T> {
+  Accepted(io::Result<(TcpStream, SocketAddr)>),
+  Completed(SocketAddr, io::Result<Option<T>>),
+}
+
+/// Accepts connections of `listener` and yields them once their TLS handshake
+/// completed. Handshakes are performed concurrently, so a slow client does not
+/// delay others. Connections exceeding the `limits` are closed before any TLS
+/// state is created for them.
+fn tls_handshakes(
+  listener: TcpListener,
+  tls_acceptor: TlsAcceptor,
+  limits: HandshakeLimits,
+) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+  let handshake_timeout = limits.timeout;
+  handshakes(listener, limits, "https", move |socket, peer| {
+    let tls_acceptor = tls_acceptor.clone();
+    async move { Ok(accept_tls(&tls_acceptor, socket, peer, handshake_timeout, "https").await) }
+  })
+}
+
+/// Performs the TLS handshake of `socket`, which has to complete within
+/// `handshake_timeout`. Failed handshakes are counted and logged with the
+/// `listener` they belong to.
+async fn accept_tls(
+  tls_acceptor: &TlsAcceptor,
+  socket: TcpStream,
+  peer: SocketAddr,
+  handshake_timeout: Duration,
+  listener: &'static str,
+) -> Option<TlsStream<TcpStream>> {
+  let started = Instant::now();
+  let deadline = started + handshake_timeout;
+  // The server name is lost once a handshake failed, so it is peeked beforehand, but only if it is logged
+  let server_name = if log_enabled!(Level::Debug) {
+    peek_server_name(&socket, deadline).await
+  } else {
+    None
+  };
+  let result = timeout_at(deadline.into(), tls_acceptor.accept(socket))
+    .await
+    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "The handshake timed out")));
+  match result {
+    Ok(tls_stream) => {
+      let duration = started.elapsed();
+      METRICS.observe(
+        "arlb_tls_handshake_duration_seconds",
+        &[("listener", listener)],
+        duration.as_secs_f64(),
+      );
+      TELEMETRY.record_handshake(peer, duration);
+      Some(tls_stream)
+    }
+    Err(e) => {
+      handshake_failed(listener, peer, server_name.as_deref(), &e);
+      None
+    }
+  }
+}
+
+async fn peek_server_name(socket: &TcpStream, deadline: Instant) -> Option<String> {
+  let mut record = [0; 1024];
+  let read = timeout_at(deadline.into(), socket.peek(&mut record)).await.ok()?.ok()?;
+  client_hello_server_name(&record[..read])
+}
+
+/// Accepts connections of `listener` and performs the `handshake` of each
+/// connection concurrently within the `limits`. Handshakes, which complete
+/// with `None`, closed their connection on purpose.
+fn handshakes<T, F, H>(
+  listener: TcpListener,
+  limits: HandshakeLimits,
+  listener_name: &'static str,
+  handshake: F,
+) -> impl Stream<Item = io::Result<T>>
+where
+  T: Unpin,
+  F: Fn(TcpStream, SocketAddr) -> H,
+  H: Future<Output = io::Result<Option<T>>>,
+{
+  stream! {
+    let mut handshakes = FuturesUnordered::new();
+    let mut rate_limiter = limits.per_client.clone().map(HandshakeRateLimiter::new);
+    let mut retry = AcceptRetry::new();
+    loop {
+      let accepting = handshakes.len() < limits.max_in_progress || limits.excess == ExcessHandshakes::Reject;
+      let event = select! {
+        accepted = retry.accept(|| listener.accept()), if accepting => HandshakeEvent::Accepted(accepted),
+        Some(completed) = handshakes.next() => completed,
+      };
+      match event {
+        HandshakeEvent::Accepted(Ok((socket, peer))) => {
+          let socket = match bans::admit(socket, peer) {
+            Some(socket) => socket,
+            None => continue,
+          };
+          if handshakes.len() >= limits.max_in_progress {
+            handshake_rejected(listener_name, peer, "overloaded");
+            continue;
+          }
+          if let Some(rate_limiter) = &mut rate_limiter {
+            if !rate_limiter.register(peer.ip()) {
+              handshake_rejected(listener_name, peer, "rate_limited");
+              continue;
+            }
+          }
+          let completed = handshake(socket, peer);
+          handshakes.push(async move { HandshakeEvent::Completed(peer, completed.await) });
+        }
+        HandshakeEvent::Accepted(Err(e)) => {
+          yield Err(e);
+          break;
+        }
+        HandshakeEvent::Completed(_, Ok(Some(stream))) => yield Ok(stream),
+        HandshakeEvent::Completed(_, Ok(None)) => {}
+        HandshakeEvent::Completed(peer, Err(e)) => handshake_failed(listener_name, peer, None, &e),
+      }
+    }
+  }
+}
+
+/// Counts the handshakes each IP address started within the current window.
+struct HandshakeRateLimiter {
+  clients: LinkedHashMap<IpAddr, (u64, Instant)>,
+  config: HandshakeRateLimit,
+}
+
+impl HandshakeRateLimiter {
+  fn new(config: HandshakeRateLimit) -> HandshakeRateLimiter {
+    HandshakeRateLimiter {
+      clients: LinkedHashMap::new(),
+      config,
+    }
+  }
+
+  /// Registers a new handshake of `client` and returns whether it is within
+  /// the limit.
+  fn register(&mut self, client: IpAddr) -> bool {
+    let now = Instant::now();
+    // The oldest windows come first, so stopping early is possible
+    while let Some((_, (_, start))) = self.clients.front() {
+      if now.duration_since(*start) < self.config.window {
+        break;
+      }
+      self.clients.pop_front();
+    }
+    let (count, _) = self.clients.entry(client).or_insert((0, now));
+    *count = count.saturating_add(1);
+    *count <= self.config.limit
+  }
+}
+
+/// Logs and counts a connection, which was closed without a handshake.
+fn handshake_rejected(listener: &'static str, peer: SocketAddr, reason: &'static str) {
+  let labels = [("listener", listener), ("reason", reason)];
+  METRICS.increment("arlb_tls_handshake_failures_total", &labels);
+  debug!("Rejected TLS handshake with {} ({})", peer, reason);
+}
+
+/// Logs and counts a failed TLS handshake. Failures caused by the client (like
+/// scanners or clients, which do not trust the certificate) are common, so they
+/// are only logged at debug level.
+fn handshake_failed(listener: &'static str, peer: SocketAddr, server_name: Option<&str>, error: &io::Error) {
+  let failure = HandshakeFailure::of(error);
+  let labels = [("listener", listener), ("reason", failure.reason())];
+  METRICS.increment("arlb_tls_handshake_failures_total", &labels);
+  let server_name = server_name.unwrap_or("-");
+  let client_ip = peer.ip();
+  logging::with_fields(
+    &[("listener", &listener), ("client_ip", &client_ip)],
+    || match failure {
+      HandshakeFailure::Io => warn!("TLS handshake for {} failed: {}", server_name, error),
+      _ => debug!(
+        "TLS handshake for {} failed ({}): {}",
+        server_name,
+        failure.reason(),
+        error
+      ),
+    },
+  )
+}
+
+/// Why a TLS handshake failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HandshakeFailure {
+  /// The client supports none of the configured cipher suites.
+  NoSharedCipher,
+  /// There is no certificate for the server name sent via SNI (or none was sent).
+  UnknownSni,
+  /// The client supports none of the configured TLS versions.
+  ProtocolVersion,
+  /// The client certificate is missing or was not signed by a trusted CA.
+  ClientCertificate,
+  /// Other protocol errors, like clients, which do not speak TLS at all.
+  Tls,
+  /// The client closed the connection.
+  Aborted,
+  Timeout,
+  Io,
+}
+
+impl HandshakeFailure {
+  fn of(error: &io::Error) -> HandshakeFailure {
+    match error.kind() {
+      io::ErrorKind::TimedOut => HandshakeFailure::Timeout,
+      io::ErrorKind::UnexpectedEof
+      | io::ErrorKind::ConnectionReset
+      | io::ErrorKind::ConnectionAborted
+      | io::ErrorKind::BrokenPipe => HandshakeFailure::Aborted,
+      // rustls reports protocol errors (like unsupported versions, no shared
+      // cipher suite or rejected certificates) as invalid data
+      io::ErrorKind::InvalidData => match error.get_ref().and_then(|it| it.downcast_ref::<TLSError>()) {
+        Some(tls_error) => HandshakeFailure::of_tls_error(tls_error),
+        None => HandshakeFailure::Tls,
+      },
+      _ => HandshakeFailure::Io,
+    }
+  }
+
+  /// rustls describes most failures only by a message, which is matched here.
+  fn of_tls_error(error: &TLSError) -> HandshakeFailure {
+    match error {
+      TLSError::PeerIncompatibleError(message) if message.contains("ciphersuites") => HandshakeFailure::NoSharedCipher,
+      TLSError::PeerIncompatibleError(message) if message.contains("TLS1") => HandshakeFailure::ProtocolVersion,
+      TLSError::PeerIncompatibleError(message) if message.contains("certificate") => {
+        HandshakeFailure::ClientCertificate
+      }
+      TLSError::General(message) if message.contains("no server certificate") => HandshakeFailure::UnknownSni,
+      TLSError::General(message) if message.contains("client rejected") => HandshakeFailure::ClientCertificate,
+      TLSError::NoCertificatesPresented | TLSError::WebPKIError(_) => HandshakeFailure::ClientCertificate,
+      _ => HandshakeFailure::Tls,
+    }
+  }
+
+  /// The `reason` label of `arlb_tls_handshake_failures_total`.
+  fn reason(&self) -> &'static str {
+    match self {
+      HandshakeFailure::NoSharedCipher => "no_shared_cipher",
+      HandshakeFailure::UnknownSni => "unknown_sni",
+      HandshakeFailure::ProtocolVersion => "protocol_version",
+      HandshakeFailure::ClientCertificate => "client_certificate",
+      HandshakeFailure::Tls => "tls",
+      HandshakeFailure::Aborted => "aborted",
+      HandshakeFailure::Timeout => "timeout",
+      HandshakeFailure::Io => "io",
+    }
+  }
+}
+
+/// Listens on a unix domain socket, for example to be reachable from a local
+/// reverse proxy.
+#[cfg(unix)]
+pub struct Unix {
+  pub path: PathBuf,
+  pub permissions: Option<u32>,
+}
+
+#[cfg(unix)]
+impl Unix {
+  pub async fn produce_acceptor(self) -> Result<HyperAcceptor<'static, UnixStream>, io::Error> {
+    remove_stale_socket(&self.path)?;
+    let listener = UnixListener::bind(&self.path)?;
+    if let Some(permissions) = self.permissions {
+      fs::set_permissions(&self.path, Permissions::from_mode(permissions))?;
+    }
 
     let incoming_stream = stream! {
+      let mut retry = AcceptRetry::new();
       loop {
-          let (socket, _) = listener.accept().await?;
-          match tls_acceptor.accept(socket).await {
-            Ok(tls_stream) => yield Ok(tls_stream),
-            Err(e) => error!("Failed to accept TLS socket: {}", e)
-          }
+        let (socket, _) = retry.accept(|| listener.accept()).await?;
+        if let Ok(credentials) = socket.peer_cred() {
+          debug!("Accepted unix socket connection from uid {} (pid {:?})", credentials.uid(), credentials.pid());
+        }
+        yield Ok(socket);
       }
     };
 
-    info!("Started listening for HTTPS requests on {}", address);
+    info!("Started listening for HTTP requests on {}", self.path.display());
 
     Ok(HyperAcceptor {
       acceptor: Box::pin(incoming_stream),
+      local_addr: None,
     })
   }
 }
 
+/// Removes a socket file left over by a previous process, which would otherwise
+/// prevent binding. It is only removed once connecting to it is refused. If
+/// another process is still listening on the socket, connecting fails for
+/// another reason, or the path is not a socket at all (like a misconfigured
+/// path of a regular file), an error is returned instead.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+  let metadata = match fs::symlink_metadata(path) {
+    Ok(metadata) => metadata,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(e),
+  };
+  if !metadata.file_type().is_socket() {
+    return Err(io::Error::new(
+      io::ErrorKind::AlreadyExists,
+      format!("'{}' already exists and is not a socket", path.display()),
+    ));
+  }
+  match std::os::unix::net::UnixStream::connect(path) {
+    Ok(_) => Err(io::Error::new(
+      io::ErrorKind::AddrInUse,
+      format!("'{}' is already in use", path.display()),
+    )),
+    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+    Err(e) => Err(e),
+  }
+}
+
+/// A connection of the HTTP port, which may have turned out to be a TLS
+/// connection, see [`TlsOnHttpPort`].
+pub enum MaybeTlsStream {
+  Plain(TcpStream),
+  Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for MaybeTlsStream {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+      MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+    }
+  }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    match self.get_mut() {
+      MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+      MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+    }
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+      MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+    }
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      MaybeTlsStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+      MaybeTlsStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+    }
+  }
+}
+
+/// Information about the client of a connection.
 pub trait RemoteAddress {
   fn remote_addr(&self) -> io::Result<SocketAddr>;
+
+  /// The details of the TLS session, if the connection is encrypted.
+  fn tls_info(&self) -> Option<TlsInfo> {
+    None
+  }
+
+  /// The entry of the connection in
+  /// [`CONNECTIONS`](crate::connections::CONNECTIONS), if it is registered.
+  fn connection(&self) -> Option<Arc<LiveConnection>> {
+    None
+  }
+
+  /// The process of the client, if the connection is local.
+  fn peer_credentials(&self) -> Option<PeerCredentials> {
+    None
+  }
+}
+
+/// The user and process of a client connected via a unix domain socket, which
+/// are written to the access log, because they share the same address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerCredentials {
+  pub uid: u32,
+  /// Not every platform reports the process.
+  pub pid: Option<i32>,
+}
+
+impl Display for PeerCredentials {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "uid:{}", self.uid)?;
+    match self.pid {
+      Some(pid) => write!(f, " pid:{}", pid),
+      None => Ok(()),
+    }
+  }
 }
 
 impl RemoteAddress for TcpStream {
@@ -108,4 +1075,856 @@ impl RemoteAddress for TlsStream<TcpStream> {
     let (stream, _) = self.get_ref();
     stream.peer_addr()
   }
+
+  fn tls_info(&self) -> Option<TlsInfo> {
+    let (_, session) = self.get_ref();
+    Some(TlsInfo::from_session(session))
+  }
+}
+
+impl RemoteAddress for MaybeTlsStream {
+  fn remote_addr(&self) -> io::Result<SocketAddr> {
+    match self {
+      MaybeTlsStream::Plain(stream) => stream.remote_addr(),
+      MaybeTlsStream::Tls(stream) => stream.remote_addr(),
+    }
+  }
+
+  fn tls_info(&self) -> Option<TlsInfo> {
+    match self {
+      MaybeTlsStream::Plain(_) => None,
+      MaybeTlsStream::Tls(stream) => stream.tls_info(),
+    }
+  }
+}
+
+/// Clients connected via a unix domain socket are local, so they are treated as
+/// coming from `127.0.0.1`.
+#[cfg(unix)]
+impl RemoteAddress for UnixStream {
+  fn remote_addr(&self) -> io::Result<SocketAddr> {
+    Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+  }
+
+  fn peer_credentials(&self) -> Option<PeerCredentials> {
+    let credentials = self.peer_cred().ok()?;
+    Some(PeerCredentials {
+      uid: credentials.uid(),
+      pid: credentials.pid(),
+    })
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+  use crate::http_client::{backend_uri, BackendConnector, BackendStream};
+  use crate::tls::{tests::test_configs, ReconfigurableCertificateResolver, TlsConfig};
+  use arc_swap::ArcSwap;
+  use hyper::{http::uri::PathAndQuery, service::Service};
+  use std::collections::HashSet;
+  use std::collections::VecDeque;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio_rustls::rustls::{
+    ciphersuite::{TLS13_AES_128_GCM_SHA256, TLS13_CHACHA20_POLY1305_SHA256},
+    ClientConfig, ProtocolVersion,
+  };
+  use tokio_rustls::{rustls::NoClientAuth, webpki::DNSNameRef, TlsConnector};
+
+  fn test_tls_acceptor() -> TlsAcceptor {
+    TlsAcceptor::from(Arc::new(ServerConfig::new(NoClientAuth::new())))
+  }
+
+  #[tokio::test]
+  async fn test_stalled_handshake_times_out_without_blocking_others() {
+    // given: a client, which never starts the handshake
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let tls_acceptor = test_tls_acceptor();
+    let limits = HandshakeLimits {
+      timeout: Duration::from_millis(200),
+      ..HandshakeLimits::default()
+    };
+    let mut incoming = Box::pin(tls_handshakes(listener, tls_acceptor, limits));
+    let timeouts = || {
+      METRICS.counter(
+        "arlb_tls_handshake_failures_total",
+        &[("listener", "https"), ("reason", "timeout")],
+      )
+    };
+    let tls_errors = || {
+      METRICS.counter(
+        "arlb_tls_handshake_failures_total",
+        &[("listener", "https"), ("reason", "tls")],
+      )
+    };
+    let (timeouts_before, tls_errors_before) = (timeouts(), tls_errors());
+    let _stalled = TcpStream::connect(address).await.unwrap();
+
+    // when: another client sends plain HTTP
+    let mut plain = TcpStream::connect(address).await.unwrap();
+    plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let next = tokio::time::timeout(Duration::from_millis(100), incoming.next()).await;
+
+    // then: the second handshake failed while the first one is still in progress
+    assert!(next.is_err());
+    assert_eq!(tls_errors(), tls_errors_before + 1);
+    assert_eq!(timeouts(), timeouts_before);
+
+    // when:
+    let next = tokio::time::timeout(Duration::from_millis(300), incoming.next()).await;
+
+    // then:
+    assert!(next.is_err());
+    assert_eq!(timeouts(), timeouts_before + 1);
+  }
+
+  #[tokio::test]
+  async fn test_excess_handshakes_are_rejected() {
+    // given: two clients, which never start the handshake
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let limits = HandshakeLimits {
+      max_in_progress: 2,
+      excess: ExcessHandshakes::Reject,
+      ..HandshakeLimits::default()
+    };
+    let incoming = tls_handshakes(listener, test_tls_acceptor(), limits);
+    tokio::spawn(async move { Box::pin(incoming).next().await });
+    let _stalled = (
+      TcpStream::connect(address).await.unwrap(),
+      TcpStream::connect(address).await.unwrap(),
+    );
+    let overloaded = || {
+      METRICS.counter(
+        "arlb_tls_handshake_failures_total",
+        &[("listener", "https"), ("reason", "overloaded")],
+      )
+    };
+    let overloaded_before = overloaded();
+
+    // when:
+    let mut third = TcpStream::connect(address).await.unwrap();
+    let read = tokio::time::timeout(Duration::from_secs(1), third.read(&mut [0; 1])).await;
+
+    // then: the connection was closed
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "{:?}", read);
+    assert_eq!(overloaded(), overloaded_before + 1);
+  }
+
+  #[test]
+  fn test_handshake_rate_limiter() {
+    let mut rate_limiter = HandshakeRateLimiter::new(HandshakeRateLimit {
+      limit: 2,
+      window: Duration::from_millis(50),
+    });
+    let client = "10.0.0.1".parse().unwrap();
+    let other_client = "10.0.0.2".parse().unwrap();
+
+    assert!(rate_limiter.register(client));
+    assert!(rate_limiter.register(client));
+    assert!(!rate_limiter.register(client));
+    assert!(rate_limiter.register(other_client));
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(rate_limiter.register(client));
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn test_accept_survives_running_out_of_file_descriptors() {
+    // given: an accept source, which runs out of file descriptors twice
+    let mut results = VecDeque::from(vec![
+      Err(io::Error::from_raw_os_error(libc::EMFILE)),
+      Ok(1),
+      Err(io::Error::from_raw_os_error(libc::ENFILE)),
+      Ok(2),
+      Ok(3),
+    ]);
+    let mut retry = AcceptRetry::new();
+    let errors = || METRICS.counter("arlb_accept_errors_total", &[]);
+    let errors_before = errors();
+    let exhaustion = || METRICS.counter("arlb_fd_exhaustion_total", &[("operation", "accept")]);
+    let exhaustion_before = exhaustion();
+
+    // when:
+    let accepted = retry
+      .accept(|| futures::future::ready(results.pop_front().unwrap()))
+      .await;
+
+    // then: the connections accepted with the reserved file descriptor were closed
+    assert_eq!(accepted.unwrap(), 3);
+    assert!(results.is_empty());
+    assert!(retry.reserve.is_some());
+    assert_eq!(retry.delay, MIN_ACCEPT_RETRY_DELAY);
+    assert!(errors() >= errors_before + 2);
+    assert!(exhaustion() >= exhaustion_before + 2);
+  }
+
+  #[tokio::test]
+  async fn test_accept_retries_transient_errors() {
+    // given:
+    let mut results = VecDeque::from(vec![
+      Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+      Err(io::Error::from_raw_os_error(12)), // ENOMEM
+      Ok(1),
+    ]);
+    let mut retry = AcceptRetry::new();
+    let start = Instant::now();
+
+    // when:
+    let accepted = retry
+      .accept(|| futures::future::ready(results.pop_front().unwrap()))
+      .await;
+
+    // then:
+    assert_eq!(accepted.unwrap(), 1);
+    assert!(start.elapsed() >= MIN_ACCEPT_RETRY_DELAY);
+  }
+
+  #[tokio::test]
+  async fn test_accept_returns_fatal_errors() {
+    // given: a closed listening socket
+    let mut results = VecDeque::from(vec![Err(io::Error::from(io::ErrorKind::InvalidInput)), Ok(1)]);
+
+    // when:
+    let accepted = AcceptRetry::new()
+      .accept(|| futures::future::ready(results.pop_front().unwrap()))
+      .await;
+
+    // then:
+    assert_eq!(accepted.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(results.len(), 1);
+  }
+
+  #[test]
+  fn test_looks_like_tls() {
+    assert!(looks_like_tls(&[0x16, 0x03]));
+    assert!(looks_like_tls(&[0x16]));
+    assert!(!looks_like_tls(&[0x16, 0x01]));
+    assert!(!looks_like_tls(b"GE"));
+    assert!(!looks_like_tls(&[]));
+  }
+
+  #[tokio::test]
+  async fn test_tls_on_http_port_is_rejected() {
+    // given:
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut incoming = Box::pin(detect_tls(
+      listener,
+      TlsOnHttpPort::Reject,
+      None,
+      HandshakeLimits::default(),
+      None,
+    ));
+    let rejected = || METRICS.counter("arlb_http_port_tls_connections_total", &[("action", "rejected")]);
+    let rejected_before = rejected();
+
+    // when: a TLS client and a plain HTTP client connect
+    let mut tls = TcpStream::connect(address).await.unwrap();
+    tls.write_all(&[0x16, 0x03, 0x01, 0x00, 0x05]).await.unwrap();
+    let mut plain = TcpStream::connect(address).await.unwrap();
+    plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let next = tokio::time::timeout(Duration::from_secs(1), incoming.next()).await;
+
+    // then: only the plain connection is served
+    let mut stream = match next {
+      Ok(Some(Ok(MaybeTlsStream::Plain(stream)))) => stream,
+      _ => panic!("Expected a plain connection"),
+    };
+    let mut request = [0; 3];
+    stream.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request, b"GET");
+    let read = tokio::time::timeout(Duration::from_secs(1), tls.read(&mut [0; 1])).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "{:?}", read);
+    assert_eq!(rejected(), rejected_before + 1);
+  }
+
+  #[tokio::test]
+  async fn test_tls_on_http_port_is_terminated() {
+    // given:
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (server_config, client_config) = test_configs(&TlsConfig::default());
+    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let mut incoming = Box::pin(detect_tls(
+      listener,
+      TlsOnHttpPort::Terminate,
+      Some(tls_acceptor),
+      HandshakeLimits::default(),
+      None,
+    ));
+
+    // when:
+    let client = tokio::spawn(async move {
+      let stream = TcpStream::connect(address).await.unwrap();
+      let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+      let mut stream = TlsConnector::from(Arc::new(client_config))
+        .connect(name, stream)
+        .await
+        .unwrap();
+      stream.write_all(b"GET").await.unwrap();
+      stream.flush().await.unwrap();
+      stream
+    });
+    let next = tokio::time::timeout(Duration::from_secs(5), incoming.next()).await;
+
+    // then:
+    let mut stream = match next {
+      Ok(Some(Ok(stream @ MaybeTlsStream::Tls(_)))) => stream,
+      _ => panic!("Expected a TLS connection"),
+    };
+    assert!(stream.tls_info().is_some());
+    let mut request = [0; 3];
+    stream.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request, b"GET");
+    client.await.unwrap();
+  }
+
+  #[test]
+  fn test_request_line() {
+    assert_eq!(request_line(b"GET / HTTP/1.1\r\nHost: a\r\n"), RequestLine::Complete);
+    assert_eq!(request_line(b"\r\nOPTIONS * HTTP/1.0\n"), RequestLine::Complete);
+    assert_eq!(request_line(b"PRI * HTTP/2.0\r\n\r\nSM"), RequestLine::Complete);
+    assert_eq!(request_line(b""), RequestLine::Partial);
+    assert_eq!(request_line(b"POST /upl"), RequestLine::Partial);
+    assert_eq!(request_line(b"GET / HTTP/1.1\r"), RequestLine::Partial);
+    assert_eq!(request_line(b"PRI * HT"), RequestLine::Partial);
+    assert_eq!(request_line(b"EHLO scanner.example\r\n"), RequestLine::Invalid);
+    assert_eq!(request_line(b"GET / HTTP/2.0\r\n"), RequestLine::Invalid);
+    assert_eq!(request_line(b"GET  / HTTP/1.1\r\n"), RequestLine::Invalid);
+    assert_eq!(request_line(&[0x16, 0x03, 0x01, 0x00, 0x05]), RequestLine::Invalid);
+  }
+
+  #[tokio::test]
+  async fn test_non_http_connections_on_http_port_are_rejected() {
+    // given:
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let guard = HttpPortGuard {
+      max_bytes: 64,
+      timeout: Duration::from_millis(500),
+      respond_bad_request: true,
+    };
+    let mut incoming = Box::pin(detect_tls(
+      listener,
+      TlsOnHttpPort::Ignore,
+      None,
+      HandshakeLimits::default(),
+      Some(guard),
+    ));
+    let rejected = || METRICS.counter("arlb_http_port_rejected_connections_total", &[("reason", "not_http")]);
+    let rejected_before = rejected();
+
+    // when: a TLS client, a client sending random bytes and a plain HTTP client connect
+    let mut tls = TcpStream::connect(address).await.unwrap();
+    tls
+      .write_all(&[0x16, 0x03, 0x01, 0x00, 0x05, 0x01, 0x00])
+      .await
+      .unwrap();
+    let mut binary = TcpStream::connect(address).await.unwrap();
+    binary.write_all(&[0x8f, 0x00, 0x42, 0xe7, 0x13, 0x37]).await.unwrap();
+    let mut plain = TcpStream::connect(address).await.unwrap();
+    plain.write_all(b"GET / HT").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    plain.write_all(b"TP/1.1\r\n\r\n").await.unwrap();
+    let next = tokio::time::timeout(Duration::from_secs(1), incoming.next()).await;
+
+    // then: only the plain connection is served, with its bytes intact
+    let mut stream = match next {
+      Ok(Some(Ok(MaybeTlsStream::Plain(stream)))) => stream,
+      _ => panic!("Expected a plain connection"),
+    };
+    let mut request = [0; 16];
+    stream.read_exact(&mut request).await.unwrap();
+    assert_eq!(&request, b"GET / HTTP/1.1\r\n");
+    for client in [&mut tls, &mut binary].iter_mut() {
+      let mut response = Vec::new();
+      let _ = tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut response)).await;
+      let response = String::from_utf8_lossy(&response);
+      assert!(
+        response.is_empty() || response.starts_with("HTTP/1.1 400"),
+        "{}",
+        response
+      );
+    }
+    assert_eq!(rejected(), rejected_before + 2);
+  }
+
+  #[tokio::test]
+  async fn test_silent_connections_on_http_port_time_out() {
+    // given:
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let guard = HttpPortGuard {
+      max_bytes: 64,
+      timeout: Duration::from_millis(100),
+      respond_bad_request: false,
+    };
+    let mut incoming = Box::pin(detect_tls(
+      listener,
+      TlsOnHttpPort::Reject,
+      None,
+      HandshakeLimits::default(),
+      Some(guard),
+    ));
+
+    // when: a client sends an incomplete request line and one sends an overlong one
+    let mut silent = TcpStream::connect(address).await.unwrap();
+    silent.write_all(b"GET /").await.unwrap();
+    let mut overlong = TcpStream::connect(address).await.unwrap();
+    overlong
+      .write_all(format!("GET /{} HTTP/1.1\r\n", "a".repeat(64)).as_bytes())
+      .await
+      .unwrap();
+    let next = tokio::time::timeout(Duration::from_millis(500), incoming.next()).await;
+
+    // then:
+    assert!(next.is_err());
+    for client in [&mut silent, &mut overlong].iter_mut() {
+      let read = tokio::time::timeout(Duration::from_secs(1), client.read(&mut [0; 1])).await;
+      assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "{:?}", read);
+    }
+  }
+
+  /// Performs a handshake of a client with `client_config` against the HTTPS
+  /// listener and returns how often it failed with `reason`.
+  async fn handshake_failures(server_config: ServerConfig, client_config: ClientConfig, reason: &str) -> u64 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let incoming = tls_handshakes(listener, tls_acceptor, HandshakeLimits::default());
+    tokio::spawn(async move { Box::pin(incoming).next().await });
+    let failures = || {
+      METRICS.counter(
+        "arlb_tls_handshake_failures_total",
+        &[("listener", "https"), ("reason", reason)],
+      )
+    };
+    let failures_before = failures();
+
+    let stream = TcpStream::connect(address).await.unwrap();
+    let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let result = TlsConnector::from(Arc::new(client_config)).connect(name, stream).await;
+    assert!(result.is_err());
+    // The server counts the failure after the client received the alert
+    for _ in 0..50 {
+      if failures() > failures_before {
+        break;
+      }
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    failures() - failures_before
+  }
+
+  #[tokio::test]
+  async fn test_unknown_sni_is_classified() {
+    // given: a server without any certificates
+    let (mut server_config, client_config) = test_configs(&TlsConfig::default());
+    let certificates = Arc::new(ArcSwap::from_pointee(Certificates::default()));
+    server_config.cert_resolver = Arc::new(ReconfigurableCertificateResolver::new(certificates));
+
+    // when:
+    let failures = handshake_failures(server_config, client_config, "unknown_sni").await;
+
+    // then:
+    assert_eq!(failures, 1);
+  }
+
+  #[tokio::test]
+  async fn test_protocol_version_is_classified() {
+    // given: a server, which only speaks TLS 1.2, and a client, which only speaks TLS 1.3
+    let tls12 = TlsConfig {
+      versions: vec![ProtocolVersion::TLSv1_2],
+      ..TlsConfig::default()
+    };
+    let (server_config, mut client_config) = test_configs(&tls12);
+    client_config.versions = vec![ProtocolVersion::TLSv1_3];
+
+    // when:
+    let failures = handshake_failures(server_config, client_config, "protocol_version").await;
+
+    // then:
+    assert_eq!(failures, 1);
+  }
+
+  #[tokio::test]
+  async fn test_no_shared_cipher_is_classified() {
+    // given:
+    let chacha = TlsConfig {
+      cipher_suites: vec![&TLS13_CHACHA20_POLY1305_SHA256],
+      ..TlsConfig::default()
+    };
+    let (server_config, mut client_config) = test_configs(&chacha);
+    client_config.ciphersuites = vec![&TLS13_AES_128_GCM_SHA256];
+
+    // when:
+    let failures = handshake_failures(server_config, client_config, "no_shared_cipher").await;
+
+    // then:
+    assert_eq!(failures, 1);
+  }
+
+  #[tokio::test]
+  async fn test_handshake_duration_is_observed() {
+    // given:
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (server_config, client_config) = test_configs(&TlsConfig::default());
+    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+    let mut incoming = Box::pin(tls_handshakes(listener, tls_acceptor, HandshakeLimits::default()));
+    let handshakes = || METRICS.histogram_count("arlb_tls_handshake_duration_seconds", &[("listener", "https")]);
+    let handshakes_before = handshakes();
+
+    // when:
+    let client = tokio::spawn(async move {
+      let stream = TcpStream::connect(address).await.unwrap();
+      let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+      TlsConnector::from(Arc::new(client_config))
+        .connect(name, stream)
+        .await
+        .unwrap()
+    });
+    let next = tokio::time::timeout(Duration::from_secs(5), incoming.next()).await;
+
+    // then:
+    assert!(matches!(next, Ok(Some(Ok(_)))));
+    assert!(handshakes() > handshakes_before);
+    client.await.unwrap();
+  }
+
+  #[test]
+  fn test_handshake_failure_reason() {
+    let reason = |kind| HandshakeFailure::of(&io::Error::new(kind, "test")).reason();
+    let tls_reason = |error| HandshakeFailure::of(&io::Error::new(io::ErrorKind::InvalidData, error)).reason();
+
+    assert_eq!(reason(io::ErrorKind::TimedOut), "timeout");
+    assert_eq!(reason(io::ErrorKind::UnexpectedEof), "aborted");
+    assert_eq!(reason(io::ErrorKind::ConnectionReset), "aborted");
+    assert_eq!(reason(io::ErrorKind::InvalidData), "tls");
+    assert_eq!(reason(io::ErrorKind::Other), "io");
+    assert_eq!(tls_reason(TLSError::NoCertificatesPresented), "client_certificate");
+    assert_eq!(tls_reason(TLSError::CorruptMessage), "tls");
+  }
+
+  fn reuse_port(reuse_port: bool) -> TcpListenOptions {
+    TcpListenOptions {
+      reuse_port,
+      ..Default::default()
+    }
+  }
+
+  fn dual_stack(dual_stack: bool) -> TcpListenOptions {
+    TcpListenOptions {
+      dual_stack: Some(dual_stack),
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn test_bind_tcp_reuse_port() {
+    let first = bind_tcp("127.0.0.1:0".parse().unwrap(), &reuse_port(true)).unwrap();
+    let address = first.local_addr().unwrap();
+
+    let second = bind_tcp(address, &reuse_port(true));
+
+    assert!(second.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_bind_tcp_without_reuse_port() {
+    let first = bind_tcp("127.0.0.1:0".parse().unwrap(), &reuse_port(false)).unwrap();
+    let address = first.local_addr().unwrap();
+
+    let second = bind_tcp(address, &reuse_port(false));
+
+    assert_eq!(second.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+  }
+
+  #[tokio::test]
+  async fn test_bind_tcp_dual_stack() {
+    let listener = bind_tcp("[::]:0".parse().unwrap(), &dual_stack(true)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let ipv4 = TcpStream::connect(("127.0.0.1", port)).await;
+    let ipv6 = TcpStream::connect(("::1", port)).await;
+
+    assert!(ipv4.is_ok());
+    assert!(ipv6.is_ok());
+  }
+
+  #[tokio::test]
+  async fn test_bind_tcp_ipv6_only() {
+    let listener = bind_tcp("[::]:0".parse().unwrap(), &dual_stack(false)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let ipv4 = TcpStream::connect(("127.0.0.1", port)).await;
+    let ipv6 = TcpStream::connect(("::1", port)).await;
+
+    assert!(ipv4.is_err());
+    assert!(ipv6.is_ok());
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn test_accepted_connections_inherit_tcp_keepalive() {
+    // given:
+    let keepalive = TcpKeepalive {
+      idle: Duration::from_secs(30),
+      interval: Duration::from_secs(5),
+      probes: 3,
+    };
+    let listener = bind_tcp(
+      "127.0.0.1:0".parse().unwrap(),
+      &TcpListenOptions {
+        tcp_keepalive: Some(keepalive),
+        ..Default::default()
+      },
+    )
+    .unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+    // when:
+    let (socket, _) = listener.accept().await.unwrap();
+
+    // then:
+    let actual = crate::http_client::tests::tcp_keepalive_of(socket.as_raw_fd());
+    assert_eq!(actual, Some(keepalive));
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn test_accepted_connections_inherit_dscp() {
+    // given:
+    let listener = bind_tcp(
+      "127.0.0.1:0".parse().unwrap(),
+      &TcpListenOptions {
+        dscp: Some(46),
+        ..Default::default()
+      },
+    )
+    .unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+    // when:
+    let (socket, _) = listener.accept().await.unwrap();
+
+    // then:
+    let actual = crate::http_client::tests::dscp_of(socket.as_raw_fd());
+    assert_eq!(actual, 46);
+  }
+
+  /// Whether the SYN of the connection `fd` carried data, which was accepted.
+  #[cfg(target_os = "linux")]
+  fn syn_data_of(fd: std::os::unix::io::RawFd) -> bool {
+    const TCPI_OPT_SYN_DATA: u8 = 32;
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut length = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let result = unsafe {
+      libc::getsockopt(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_INFO,
+        &mut info as *mut libc::tcp_info as *mut libc::c_void,
+        &mut length,
+      )
+    };
+    assert_eq!(result, 0, "{}", io::Error::last_os_error());
+    info.tcpi_options & TCPI_OPT_SYN_DATA != 0
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  #[ignore]
+  // requires `sysctl net.ipv4.tcp_fastopen=3`, run with cargo test listeners::tests::test_tcp_fast_open -- --ignored
+  async fn test_tcp_fast_open() {
+    // given:
+    let listener = bind_tcp(
+      "127.0.0.1:0".parse().unwrap(),
+      &TcpListenOptions {
+        tcp_fast_open: true,
+        ..Default::default()
+      },
+    )
+    .unwrap();
+    let uri = backend_uri(
+      &listener.local_addr().unwrap().to_string(),
+      PathAndQuery::from_static("/"),
+    )
+    .unwrap();
+    let mut connector = BackendConnector::new();
+    connector.set_tcp_fast_open(true);
+    let mut connect = |data: &'static [u8]| {
+      let connection = connector.call(uri.clone());
+      async move {
+        let mut stream = match connection.await.unwrap() {
+          BackendStream::Tcp(stream) => stream,
+          _ => panic!("Expected a TCP connection"),
+        };
+        stream.write_all(data).await.unwrap();
+        stream
+      }
+    };
+
+    // when: the first connection receives a cookie, which the second one uses
+    let _first = connect(b"first").await;
+    let (mut first, _) = listener.accept().await.unwrap();
+    let _second = connect(b"second").await;
+    let (mut second, _) = listener.accept().await.unwrap();
+
+    // then:
+    let mut buffer = [0; 6];
+    first.read_exact(&mut buffer[..5]).await.unwrap();
+    second.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"second");
+    assert!(!syn_data_of(first.as_raw_fd()));
+    assert!(syn_data_of(second.as_raw_fd()));
+  }
+
+  #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+  async fn test_connection_storm_is_spread_across_acceptors() {
+    // given:
+    let options = TcpListenOptions {
+      acceptors: 4,
+      ..Default::default()
+    };
+    let listeners = bind_acceptors("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+    let address = listeners[0].local_addr().unwrap();
+    let next_index = AtomicUsize::new(0);
+    let mut accepted = accept_all(listeners, |listener| {
+      let index = next_index.fetch_add(1, Ordering::Relaxed);
+      stream! {
+        loop {
+          yield listener.accept().await.map(|_| index);
+        }
+      }
+    });
+
+    // when:
+    let clients = futures::future::join_all((0..200).map(|_| TcpStream::connect(address))).await;
+
+    // then:
+    assert!(clients.iter().all(Result::is_ok));
+    let mut acceptors = HashSet::new();
+    for _ in 0..200 {
+      let index = timeout(Duration::from_secs(5), accepted.next()).await.unwrap();
+      acceptors.insert(index.unwrap().unwrap());
+    }
+    assert!(acceptors.len() > 1, "{:?}", acceptors);
+  }
+
+  #[tokio::test]
+  async fn test_dropped_acceptors_close_their_listeners() {
+    // given:
+    let options = TcpListenOptions {
+      acceptors: 4,
+      ..Default::default()
+    };
+    let listeners = bind_acceptors("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+    let address = listeners[0].local_addr().unwrap();
+    let accepted = accept_all(listeners, |listener| {
+      stream! {
+        loop {
+          yield listener.accept().await.map(|_| ());
+        }
+      }
+    });
+
+    // when:
+    drop(accepted);
+
+    // then:
+    let refused = async {
+      while TcpStream::connect(address).await.is_ok() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+      }
+    };
+    timeout(Duration::from_secs(5), refused).await.unwrap();
+  }
+
+  fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("arlb-{}-{}.sock", name, std::process::id()))
+  }
+
+  #[test]
+  fn test_remove_stale_socket() {
+    let path = socket_path("stale");
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    remove_stale_socket(&path).unwrap();
+
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn test_remove_stale_socket_in_use() {
+    let path = socket_path("in-use");
+    let _ = fs::remove_file(&path);
+    let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+    let result = remove_stale_socket(&path);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+  }
+
+  #[test]
+  fn test_remove_stale_socket_keeps_socket_of_other_type() {
+    let path = socket_path("datagram");
+    let _ = fs::remove_file(&path);
+    let _socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+    let result = remove_stale_socket(&path);
+    let exists = path.exists();
+    fs::remove_file(&path).unwrap();
+
+    assert_ne!(result.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    assert!(exists);
+  }
+
+  #[test]
+  fn test_remove_stale_socket_keeps_regular_file() {
+    let path = socket_path("regular-file");
+    fs::write(&path, "not a socket").unwrap();
+
+    let result = remove_stale_socket(&path);
+    let content = fs::read_to_string(&path);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(content.unwrap(), "not a socket");
+  }
+
+  #[tokio::test]
+  async fn test_unix_stream_reports_peer_credentials() {
+    let (stream, _peer) = UnixStream::pair().unwrap();
+
+    let credentials = stream.peer_credentials().unwrap();
+
+    assert_eq!(credentials.pid, Some(std::process::id() as i32));
+  }
+
+  #[test]
+  fn test_format_peer_credentials() {
+    let credentials = |pid| PeerCredentials { uid: 1000, pid };
+
+    assert_eq!(credentials(Some(4242)).to_string(), "uid:1000 pid:4242");
+    assert_eq!(credentials(None).to_string(), "uid:1000");
+  }
+
+  #[tokio::test]
+  async fn test_unix_acceptor_sets_permissions() {
+    let path = socket_path("permissions");
+    let unix = Unix {
+      path: path.clone(),
+      permissions: Some(0o600),
+    };
+
+    let _acceptor = unix.produce_acceptor().await.unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(mode & 0o777, 0o600);
+  }
 }
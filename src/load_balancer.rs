@@ -0,0 +1,678 @@
+use crate::{
+  bandwidth, bans,
+  configuration::{read_initial_config, read_initial_embedded_config, watch_config, Config, RuntimeConfig},
+  dns, drain,
+  error::Error,
+  events::{EventSubscriber, EVENTS},
+  file_descriptors, forward_proxy, health,
+  listeners::{AcceptorProducer, Http, Https, ListenerConfig, TcpListenOptions, TlsOnHttpPort},
+  load_balancing,
+  logging::Logging,
+  maintenance_windows, metrics, prewarm,
+  readiness::{BoundAddress, READINESS},
+  server::{self, Scheme},
+  state_file, tcp_router,
+  telemetry::TELEMETRY,
+  tls::{self, ReconfigurableCertificateResolver, ReconfigurableTicketer},
+  tls_passthrough, top_talkers, udp, webhook,
+};
+use arc_swap::{access::Map, ArcSwap};
+use futures::future::try_join_all;
+use log::{info, warn};
+use std::{
+  future::Future,
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+use tokio::{select, sync::watch, try_join};
+use tokio_rustls::rustls::{ResolvesServerCert, ServerConfig};
+use tokio_util::sync::CancellationToken;
+
+/// A load balancer serving the configuration file at `config_path` or a
+/// [`Config`] of the embedding application. Changes of the file are applied
+/// while it is running.
+///
+/// Metrics, bans and the dynamic backend servers are global state of the
+/// process, so only one load balancer should run per process.
+pub struct LoadBalancer {
+  /// `None` for a [`Config`], which has no file to watch.
+  config_path: Option<PathBuf>,
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  logging: Option<Logging>,
+}
+
+impl LoadBalancer {
+  /// Reads and validates the configuration file at `config_path`, without
+  /// listening for requests yet.
+  pub async fn new<P: AsRef<Path>>(config_path: P) -> Result<LoadBalancer, Error> {
+    let config_path = config_path.as_ref().to_path_buf();
+    let mut config = read_initial_config(&config_path).await?;
+    if state_file::restore_dynamic_backends(&config.load()) {
+      config = read_initial_config(&config_path).await?;
+    }
+    // From now on the listeners of this load balancer are awaited, not those of one, which stopped before
+    READINESS.start(listener_count(&config.load()));
+    Ok(LoadBalancer {
+      config_path: Some(config_path),
+      config,
+      logging: None,
+    })
+  }
+
+  /// Validates `config` like [`new`](LoadBalancer::new), without listening for
+  /// requests yet. Since there is no file, the configuration is never
+  /// reloaded and `POST /reload` of the admin API fails.
+  pub async fn from_config(config: Config) -> Result<LoadBalancer, Error> {
+    let embedded_config = Arc::new(config);
+    let mut config = read_initial_embedded_config(&embedded_config).await?;
+    if state_file::restore_dynamic_backends(&config.load()) {
+      config = read_initial_embedded_config(&embedded_config).await?;
+    }
+    READINESS.start(listener_count(&config.load()));
+    Ok(LoadBalancer {
+      config_path: None,
+      config,
+      logging: None,
+    })
+  }
+
+  /// Applies the `[logging]` of the configuration to `logging`, also after
+  /// reloads. Without it the logging is left to the embedding application.
+  pub fn with_logging(mut self, logging: Logging) -> Result<LoadBalancer, Error> {
+    logging
+      .reconfigure(&self.config.load().logging)
+      .map_err(Error::Config)?;
+    self.logging = Some(logging);
+    Ok(self)
+  }
+
+  /// Subscribes to the events of the client connections, like accepted
+  /// connections and completed requests. Subscribers, which do not keep up,
+  /// lose the oldest events instead of slowing down the load balancer.
+  pub fn events(&self) -> EventSubscriber {
+    EVENTS.subscribe()
+  }
+
+  /// Resolves to the addresses of all listeners (like the HTTP port and the
+  /// metrics) once [`run`](LoadBalancer::run) bound them, before the first
+  /// connection is accepted, which tells the actual ports of addresses with
+  /// port `0`. Resolves to `None` if the load balancer stopped before.
+  pub fn bound_addresses(&self) -> impl Future<Output = Option<Vec<BoundAddress>>> + Send + 'static {
+    READINESS.bound_addresses()
+  }
+
+  /// Serves requests until the connections were drained after `SIGUSR2` (on
+  /// unix platforms) or Ctrl-C (on Windows), or a listener fails.
+  pub async fn run(self) -> Result<(), Error> {
+    self.run_with_shutdown(CancellationToken::new()).await
+  }
+
+  /// Like [`run`](LoadBalancer::run), but additionally drains the connections
+  /// once `shutdown` is cancelled. Returns when all connections are closed or
+  /// the `drain_timeout_sec` elapsed. Connections, which are still open then,
+  /// are closed once the Tokio runtime shuts down.
+  pub async fn run_with_shutdown(self, shutdown: CancellationToken) -> Result<(), Error> {
+    let LoadBalancer {
+      config_path,
+      config,
+      logging,
+    } = self;
+    bans::OFFENDERS.configure(config.load().bans.clone());
+    top_talkers::TOP_TALKERS.configure(config.load().top_talkers.clone());
+    dns::DNS_CACHE.configure(config.load().dns.clone());
+    TELEMETRY
+      .configure(config.load().opentelemetry.as_ref())
+      .map_err(Error::Config)?;
+    metrics::METRICS.set_buckets(&config.load().histogram_buckets);
+    load_balancing::SELECTION_TIMING.configure(config.load().backend_selection_sample_rate);
+    file_descriptors::log_limit();
+    dns::prefetch(&config.load());
+    state_file::restore(&config.load());
+    let drain = drain::signal(shutdown);
+    let drain_timeout = config.load().drain_timeout;
+    let background_tasks = async {
+      try_join!(
+        watch_config_file(config_path, config.clone(), logging.as_ref()),
+        watch_health(config.clone()),
+        listen_for_udp_datagrams(config.clone()),
+        listen_for_tls_passthrough(config.clone()),
+        listen_for_tcp_routers(config.clone()),
+        listen_for_forward_proxies(config.clone()),
+        serve_metrics(config.clone()),
+        persist_state(config.clone()),
+        sample_bandwidth(),
+        schedule_maintenance(config.clone()),
+        keep_connections_warm(config.clone()),
+        deliver_events(config.clone())
+      )
+    };
+    let listeners = async {
+      try_join!(
+        listen_for_http_request(config.clone(), drain.clone()),
+        listen_for_https_request(config.clone(), drain.clone()),
+        listen_for_requests(config.clone(), drain.clone()),
+        listen_for_unix_request(config.clone(), drain.clone())
+      )
+    };
+    let result = select! {
+      result = background_tasks => result.map(|_| ()),
+      result = listeners => result.map(|_| info!("Drain complete, all connections were closed")),
+      _ = drain::timeout(drain.clone(), drain_timeout) => {
+        warn!(
+          "Drain forced after grace timeout, closing {} remaining connections, which were not drained within {:?}",
+          drain::CLIENT_CONNECTIONS.get(),
+          drain_timeout
+        );
+        Ok(())
+      }
+    };
+    READINESS.stop();
+    TELEMETRY.flush();
+    result?;
+    if let Err(e) = state_file::save(&config.load()) {
+      warn!("Could not write the state file due to: {}", e);
+    }
+    Ok(())
+  }
+}
+
+async fn watch_health(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  let backend_pools = Map::new(config.clone(), |it: &RuntimeConfig| &it.shared_data.backend_pools);
+  let health_interval = Map::new(config.clone(), |it: &RuntimeConfig| &it.health_interval);
+  let health_jitter = Map::new(config, |it: &RuntimeConfig| &it.health_jitter);
+  health::watch_health(backend_pools, health_interval, health_jitter).await;
+  Ok(())
+}
+
+async fn persist_state(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  state_file::persist(config).await;
+  Ok(())
+}
+
+async fn sample_bandwidth() -> Result<(), Error> {
+  bandwidth::sample_backend_bandwidth().await;
+  Ok(())
+}
+
+async fn schedule_maintenance(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  maintenance_windows::schedule(config).await;
+  Ok(())
+}
+
+async fn watch_config_file(
+  config_path: Option<PathBuf>,
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  logging: Option<&Logging>,
+) -> Result<(), Error> {
+  match config_path {
+    Some(config_path) => watch_config(config_path, config, logging).await,
+    None => Ok(()),
+  }
+}
+
+async fn keep_connections_warm(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  prewarm::keep_warm(config).await;
+  Ok(())
+}
+
+async fn deliver_events(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  if let Some(event_webhook) = config.load().event_webhook.clone() {
+    webhook::deliver(EVENTS.subscribe(), event_webhook).await;
+  }
+  Ok(())
+}
+
+/// The number of listeners, which [`READINESS`] waits for.
+fn listener_count(config: &RuntimeConfig) -> usize {
+  2 + config.listeners.len()
+    + config.unix_socket.is_some() as usize
+    + config.udp_services.len()
+    + config.tls_passthrough_services.len()
+    + config.tcp_router_services.len()
+    + config.forward_proxy_services.len()
+    + config.metrics_address.is_some() as usize
+}
+
+fn tcp_listen_options(config: &RuntimeConfig) -> TcpListenOptions {
+  TcpListenOptions {
+    reuse_port: config.reuse_port,
+    dual_stack: config.dual_stack,
+    tcp_keepalive: config.tcp_keepalive,
+    dscp: config.dscp,
+    tcp_fast_open: config.tcp_fast_open,
+    backlog: config.listen_backlog,
+    acceptors: config.acceptors,
+  }
+}
+
+/// The address of a TCP listener, which other processes can bind via
+/// `reuse_port`, together with the number of sockets this process binds to it.
+fn shared_address(config: &RuntimeConfig, local_addr: Option<SocketAddr>) -> Option<(SocketAddr, usize)> {
+  local_addr
+    .filter(|_| config.reuse_port)
+    .map(|it| (it, config.acceptors.max(1)))
+}
+
+pub(crate) async fn listen_for_http_request(
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  drain: watch::Receiver<bool>,
+) -> Result<(), Error> {
+  let tls_on_http_port = config.load().tls_on_http_port;
+  let tls_config = match tls_on_http_port {
+    TlsOnHttpPort::Terminate => Some(https_server_config(&config)?),
+    _ => None,
+  };
+  let http = Http {
+    listen_options: tcp_listen_options(&config.load()),
+    tls_on_http_port,
+    tls_config,
+    handshake_limits: config.load().tls.handshake_limits.clone(),
+    guard: config.load().http_port_guard.clone(),
+  };
+  let address = config.load().http_address;
+  let acceptor = http
+    .produce_acceptor(address)
+    .await
+    .map_err(|e| Error::listen(address, e))?;
+  READINESS.bound("http_address", acceptor.local_addr());
+  let drain = drain::handed_over(drain, shared_address(&config.load(), acceptor.local_addr()));
+
+  server::create(acceptor, config, Scheme::HTTP, None, drain).await
+}
+
+pub(crate) async fn listen_for_https_request(
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  drain: watch::Receiver<bool>,
+) -> Result<(), Error> {
+  let https = Https {
+    tls_config: https_server_config(&config)?,
+    handshake_limits: config.load().tls.handshake_limits.clone(),
+    listen_options: tcp_listen_options(&config.load()),
+  };
+  let address = config.load().https_address;
+  let acceptor = https
+    .produce_acceptor(address)
+    .await
+    .map_err(|e| Error::listen(address, e))?;
+  READINESS.bound("https_address", acceptor.local_addr());
+  let drain = drain::handed_over(drain, shared_address(&config.load(), acceptor.local_addr()));
+
+  server::create(acceptor, config, Scheme::HTTPS, None, drain).await
+}
+
+/// Serves the additional `listeners` of the configuration, each with its own
+/// address, pools and (for TLS listeners) certificates.
+async fn listen_for_requests(config: Arc<ArcSwap<RuntimeConfig>>, drain: watch::Receiver<bool>) -> Result<(), Error> {
+  let listeners = config.load().listeners.clone();
+  try_join_all(
+    listeners
+      .into_iter()
+      .map(|listener| listen_for_listener_request(config.clone(), listener, drain.clone())),
+  )
+  .await?;
+  Ok(())
+}
+
+async fn listen_for_listener_request(
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  listener: Arc<ListenerConfig>,
+  drain: watch::Receiver<bool>,
+) -> Result<(), Error> {
+  let listen_options = tcp_listen_options(&config.load());
+  let handshake_limits = config.load().tls.handshake_limits.clone();
+  let address = listener.address;
+  let name = listener.name.clone();
+  if listener.tls {
+    let https = Https {
+      tls_config: listener_server_config(&config, name.clone())?,
+      handshake_limits,
+      listen_options,
+    };
+    let acceptor = https
+      .produce_acceptor(address)
+      .await
+      .map_err(|e| Error::listen(address, e))?;
+    READINESS.bound(&name, acceptor.local_addr());
+    let drain = drain::handed_over(drain, shared_address(&config.load(), acceptor.local_addr()));
+    server::create(acceptor, config, listener.scheme(), Some(name), drain).await
+  } else {
+    let http = Http {
+      listen_options,
+      tls_on_http_port: TlsOnHttpPort::Ignore,
+      tls_config: None,
+      handshake_limits,
+      guard: None,
+    };
+    let acceptor = http
+      .produce_acceptor(address)
+      .await
+      .map_err(|e| Error::listen(address, e))?;
+    READINESS.bound(&name, acceptor.local_addr());
+    let drain = drain::handed_over(drain, shared_address(&config.load(), acceptor.local_addr()));
+    server::create(acceptor, config, listener.scheme(), Some(name), drain).await
+  }
+}
+
+/// The TLS configuration of the HTTPS port, whose certificates and session
+/// tickets follow reloads of the configuration.
+fn https_server_config(config: &Arc<ArcSwap<RuntimeConfig>>) -> Result<ServerConfig, Error> {
+  let certificates = Map::new(config.clone(), |it: &RuntimeConfig| &it.certificates);
+  server_config(config, ReconfigurableCertificateResolver::new(certificates))
+}
+
+/// The TLS configuration of the listener `name`. Listeners without
+/// certificates of their own use the ones of the HTTPS port.
+fn listener_server_config(config: &Arc<ArcSwap<RuntimeConfig>>, name: String) -> Result<ServerConfig, Error> {
+  let certificates = Map::new(config.clone(), move |it: &RuntimeConfig| {
+    it.listeners
+      .iter()
+      .find(|listener| listener.name == name)
+      .map(|listener| &listener.certificates)
+      .filter(|certificates| !certificates.is_empty())
+      .unwrap_or(&it.certificates)
+  });
+  server_config(config, ReconfigurableCertificateResolver::new(certificates))
+}
+
+fn server_config<R>(config: &Arc<ArcSwap<RuntimeConfig>>, cert_resolver: R) -> Result<ServerConfig, Error>
+where
+  R: ResolvesServerCert + 'static,
+{
+  let mut tls_config =
+    tls::server_config(config.load().tls_client_auth.as_ref(), &config.load().tls).map_err(Error::Tls)?;
+  tls_config.cert_resolver = Arc::new(cert_resolver);
+  let session_tickets = Map::new(config.clone(), |it: &RuntimeConfig| &it.tls.session_tickets);
+  tls_config.ticketer = Arc::new(ReconfigurableTicketer::new(session_tickets));
+  Ok(tls_config)
+}
+
+async fn listen_for_udp_datagrams(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  let udp_services = config.load().udp_services.clone();
+  try_join_all(udp_services.into_iter().enumerate().map(|(index, service)| async move {
+    let address = service.listen_address;
+    let proxy = udp::UdpProxy::bind(service)
+      .await
+      .map_err(|e| Error::listen(address, e))?;
+    READINESS.bound(&format!("udp_services[{}]", index), proxy.local_addr().ok());
+    proxy.run().await.map_err(Error::Io)
+  }))
+  .await?;
+  Ok(())
+}
+
+async fn listen_for_tls_passthrough(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  let services = config.load().tls_passthrough_services.clone();
+  let maintenance = config.load().maintenance.clone();
+  let backlog = config.load().listen_backlog;
+  try_join_all(services.into_iter().enumerate().map(|(index, service)| {
+    let maintenance = maintenance.clone();
+    async move {
+      let address = service.listen_address;
+      let proxy = tls_passthrough::TlsPassthroughProxy::bind(service, maintenance, backlog)
+        .await
+        .map_err(|e| Error::listen(address, e))?;
+      READINESS.bound(&format!("tls_passthrough_services[{}]", index), proxy.local_addr().ok());
+      proxy.run().await.map_err(Error::Io)
+    }
+  }))
+  .await?;
+  Ok(())
+}
+
+pub(crate) async fn listen_for_tcp_routers(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  let services = config.load().tcp_router_services.clone();
+  let maintenance = config.load().maintenance.clone();
+  let backlog = config.load().listen_backlog;
+  try_join_all(services.into_iter().enumerate().map(|(index, service)| {
+    let maintenance = maintenance.clone();
+    async move {
+      let address = service.listen_address;
+      let proxy = tcp_router::TcpRouterProxy::bind(service, maintenance, backlog)
+        .await
+        .map_err(|e| Error::listen(address, e))?;
+      READINESS.bound(&format!("tcp_router_services[{}]", index), proxy.local_addr().ok());
+      proxy.run().await.map_err(Error::Io)
+    }
+  }))
+  .await?;
+  Ok(())
+}
+
+async fn listen_for_forward_proxies(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  let services = config.load().forward_proxy_services.clone();
+  let backlog = config.load().listen_backlog;
+  try_join_all(services.into_iter().enumerate().map(|(index, service)| async move {
+    let address = service.listen_address;
+    let proxy = forward_proxy::ForwardProxy::bind(service, backlog)
+      .await
+      .map_err(|e| Error::listen(address, e))?;
+    READINESS.bound(&format!("forward_proxy_services[{}]", index), proxy.local_addr().ok());
+    proxy.run().await.map_err(Error::Io)
+  }))
+  .await?;
+  Ok(())
+}
+
+async fn serve_metrics(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  match config.load().metrics_address {
+    Some(address) => metrics::serve(address, config.clone()).await,
+    None => Ok(()),
+  }
+}
+
+#[cfg(unix)]
+async fn listen_for_unix_request(
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  drain: watch::Receiver<bool>,
+) -> Result<(), Error> {
+  let unix_socket = match &config.load().unix_socket {
+    Some(unix_socket) => unix_socket.clone(),
+    None => return Ok(()),
+  };
+  let unix = crate::listeners::Unix {
+    path: unix_socket.path.clone().into(),
+    permissions: unix_socket.permissions,
+  };
+  let acceptor = unix.produce_acceptor().await.map_err(|source| Error::Listen {
+    address: unix_socket.path,
+    source,
+  })?;
+  READINESS.bound("unix_socket", None);
+
+  server::create(acceptor, config, Scheme::HTTP, None, drain::drained(drain)).await
+}
+
+#[cfg(not(unix))]
+async fn listen_for_unix_request(
+  config: Arc<ArcSwap<RuntimeConfig>>,
+  _drain: watch::Receiver<bool>,
+) -> Result<(), Error> {
+  match &config.load().unix_socket {
+    Some(_) => Err(Error::Config(std::io::Error::new(
+      std::io::ErrorKind::Other,
+      "unix_socket is not supported on this platform",
+    ))),
+    None => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::exclusive;
+  use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Client, Response, Server, StatusCode,
+  };
+  use std::{convert::Infallible, net::SocketAddr, time::Duration};
+  use tokio::{net::TcpListener, time::sleep};
+
+  async fn get_ready(metrics: SocketAddr) -> StatusCode {
+    let uri = format!("http://{}/ready", metrics).parse().unwrap();
+    Client::new().get(uri).await.unwrap().status()
+  }
+
+  #[tokio::test]
+  async fn test_ready_once_the_backend_server_is_healthy() {
+    let _exclusive = exclusive().await;
+    // given: a backend server, which does not listen yet
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let path = std::env::temp_dir().join(format!("arlb-readiness-{}.toml", std::process::id()));
+    std::fs::write(
+      &path,
+      format!(
+        r#"
+        http_address = "127.0.0.1:0"
+        https_address = "127.0.0.1:0"
+        metrics_address = "127.0.0.1:0"
+        health_interval = {{ check_every = 1 }}
+
+        [[backend_pools]]
+        matcher = "Host('whoami.localhost')"
+        addresses = ["{}"]
+        schemes = ["HTTP"]
+        strategy = {{ RoundRobin = {{}} }}
+        "#,
+        backend
+      ),
+    )
+    .unwrap();
+    let load_balancer = LoadBalancer::new(&path).await.unwrap();
+    let bound_addresses = load_balancer.bound_addresses();
+    let shutdown = CancellationToken::new();
+    tokio::spawn(load_balancer.run_with_shutdown(shutdown.clone()));
+
+    // when:
+    let bound_addresses = bound_addresses.await.unwrap();
+    let metrics = bound_addresses
+      .iter()
+      .find(|it| it.listener == "metrics_address")
+      .unwrap()
+      .address;
+    let before = get_ready(metrics).await;
+    let service = make_service_fn(|_| async {
+      Ok::<_, Infallible>(service_fn(|_| async {
+        Ok::<_, Infallible>(Response::new(Body::empty()))
+      }))
+    });
+    tokio::spawn(Server::bind(&backend).serve(service));
+    let mut after = before;
+    for _ in 0..50 {
+      after = get_ready(metrics).await;
+      if after == StatusCode::OK {
+        break;
+      }
+      sleep(Duration::from_millis(100)).await;
+    }
+    shutdown.cancel();
+    std::fs::remove_file(&path).unwrap();
+
+    // then:
+    let listeners = bound_addresses
+      .iter()
+      .map(|it| it.listener.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(listeners.len(), 3, "{:?}", listeners);
+    assert!(listeners.contains(&"http_address"));
+    assert!(listeners.contains(&"https_address"));
+    assert!(bound_addresses.iter().all(|it| it.address.port() != 0));
+    assert_eq!(before, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(after, StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn test_from_config_serves_the_embedded_configuration() {
+    let _exclusive = exclusive().await;
+    // given:
+    let config = Config::parse(
+      r#"
+      http_address = "127.0.0.1:0"
+      https_address = "127.0.0.1:0"
+
+      [[backend_pools]]
+      matcher = "Host('whoami.localhost')"
+      respond = { status = 200, body = "embedded" }
+      schemes = ["HTTP"]
+      strategy = { RoundRobin = {} }
+      "#,
+      std::env::temp_dir(),
+    )
+    .unwrap();
+    let load_balancer = LoadBalancer::from_config(config).await.unwrap();
+    let bound_addresses = load_balancer.bound_addresses();
+    let shutdown = CancellationToken::new();
+    let running = tokio::spawn(load_balancer.run_with_shutdown(shutdown.clone()));
+
+    // when:
+    let http = bound_addresses
+      .await
+      .unwrap()
+      .into_iter()
+      .find(|it| it.listener == "http_address")
+      .unwrap()
+      .address;
+    let request = hyper::Request::get(format!("http://{}/", http))
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    shutdown.cancel();
+
+    // then:
+    assert_eq!(body, "embedded");
+    running.await.unwrap().unwrap();
+    assert!(Config::parse("http_address = ", std::env::temp_dir()).is_err());
+  }
+
+  #[cfg(target_os = "linux")]
+  #[tokio::test]
+  async fn test_draining_listener_accepts_until_another_process_listens() {
+    // given:
+    let proxy = crate::testing::TestProxy::start(
+      r#"
+      reuse_port = true
+      drain_timeout_sec = 10
+
+      [[backend_pools]]
+      matcher = "Host('whoami.localhost')"
+      respond = { status = 200, body = "old" }
+      schemes = ["HTTP"]
+      strategy = { RoundRobin = {} }
+      "#,
+    )
+    .await
+    .unwrap();
+    let http = proxy.http_address();
+    let https = proxy.https_address();
+    let started = std::time::Instant::now();
+    let stopped = tokio::spawn(proxy.stop());
+
+    // when: the old instance is draining, but no other process listens yet
+    sleep(Duration::from_millis(300)).await;
+    let request = hyper::Request::get(format!("http://{}/", http))
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+    // and: the new instance listens
+    let options = TcpListenOptions {
+      reuse_port: true,
+      ..TcpListenOptions::default()
+    };
+    let successor = crate::listeners::bind_tcp(http, &options).unwrap();
+    let _https_successor = crate::listeners::bind_tcp(https, &options).unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), stopped).await;
+    let _client = tokio::net::TcpStream::connect(http).await.unwrap();
+    let accepted = tokio::time::timeout(Duration::from_secs(1), successor.accept()).await;
+
+    // then:
+    assert_eq!(body, "old");
+    result.unwrap().unwrap().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert!(accepted.unwrap().is_ok());
+  }
+}
@@ -15,15 +15,27 @@ impl IPHash {
 }
 
 impl LoadBalancingStrategy for IPHash {
+  fn name(&self) -> &'static str {
+    "IPHash"
+  }
+
   fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder {
-    let mut hasher = DefaultHasher::new();
-    context.client_address.ip().hash(&mut hasher);
-    let index = (hasher.finish() % (context.backend_addresses.len() as u64)) as usize;
-    let address = &context.backend_addresses[index];
-    RequestForwarder::new(address)
+    RequestForwarder::new(hashed_backend(context))
+  }
+
+  fn pinned_backend<'l>(&self, _request: &Request<Body>, context: &Context<'l>) -> Option<&'l str> {
+    Some(hashed_backend(context))
   }
 }
 
+/// The backend server, which the IP address of the client hashes to.
+fn hashed_backend<'l>(context: &Context<'l>) -> &'l str {
+  let mut hasher = DefaultHasher::new();
+  context.client_address.ip().hash(&mut hasher);
+  let index = (hasher.finish() % (context.backend_addresses.len() as u64)) as usize;
+  context.backend_addresses[index]
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
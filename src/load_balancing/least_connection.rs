@@ -1,35 +1,41 @@
 use std::{collections::HashMap, sync::RwLock};
 
 use hyper::{Body, Request, Uri};
-use rand::{thread_rng, Rng};
 
-use super::{Context, LoadBalancingStrategy, RequestForwarder};
+use super::{tie_breaker::TieBreaker, Context, LoadBalancingStrategy, RequestForwarder};
+use crate::http_client::backend_address;
 
 #[derive(Debug)]
 pub struct LeastConnection {
   connections: RwLock<HashMap<String, usize>>,
+  tie_breaker: TieBreaker,
 }
 
 impl LeastConnection {
-  pub fn new() -> LeastConnection {
+  pub fn new(tie_breaker: TieBreaker) -> LeastConnection {
     LeastConnection {
       connections: RwLock::new(HashMap::new()),
+      tie_breaker,
     }
   }
 }
 
 impl LoadBalancingStrategy for LeastConnection {
+  fn name(&self) -> &'static str {
+    "LeastConnection"
+  }
+
   fn on_tcp_open(&self, remote: &Uri) {
-    if let Some(authority) = remote.authority() {
+    if let Some(address) = backend_address(remote) {
       let mut connections = self.connections.write().unwrap();
-      *connections.entry(authority.to_string()).or_insert(0) += 1;
+      *connections.entry(address).or_insert(0) += 1;
     }
   }
 
   fn on_tcp_close(&self, remote: &Uri) {
-    if let Some(authority) = remote.authority() {
+    if let Some(address) = backend_address(remote) {
       let mut connections = self.connections.write().unwrap();
-      *connections.entry(authority.to_string()).or_insert(1) -= 1;
+      *connections.entry(address).or_insert(1) -= 1;
     }
   }
 
@@ -37,48 +43,95 @@ impl LoadBalancingStrategy for LeastConnection {
     // ok to unwrap - only panics when we panic somewhere else :)
     let connections = self.connections.read().unwrap();
 
-    let address_indices: Vec<usize> = if connections.len() == 0 || context.backend_addresses.len() > connections.len() {
-      // if no TCP connections have been opened yet, or some backend servers are not used yet, we'll use them for the next request
-      context
-        .backend_addresses
-        .iter()
-        .enumerate()
-        .filter(|(_, address)| !connections.contains_key(**address))
-        .map(|(index, _)| index)
-        .collect()
-    } else {
-      let backend_address_map = context
-        .backend_addresses
-        .iter()
-        .enumerate()
-        .map(|(index, address)| (*address, index))
-        .collect::<HashMap<_, _>>();
-      let mut least_connections = connections.iter().collect::<Vec<_>>();
-
-      least_connections.sort_by(|a, b| a.1.cmp(b.1));
-
-      let min_connection_count = least_connections[0].1;
-      least_connections
-        .iter()
-        .take_while(|(_, connection_count)| *connection_count == min_connection_count)
-        .map(|tuple| tuple.0)
-        .map(|address| *backend_address_map.get(address.as_str()).unwrap())
-        .collect()
-    };
-
-    if address_indices.len() == 1 {
-      RequestForwarder::new(&context.backend_addresses[address_indices[0]])
-    } else {
-      let mut rng = thread_rng();
-      let index = rng.gen_range(0..address_indices.len());
-      RequestForwarder::new(&context.backend_addresses[address_indices[index]])
-    }
+    let mut address_indices: Vec<usize> =
+      if connections.is_empty() || context.backend_addresses.len() > connections.len() {
+        // if no TCP connections have been opened yet, or some backend servers are not used yet, we'll use them for the next request
+        context
+          .backend_addresses
+          .iter()
+          .enumerate()
+          .filter(|(_, address)| !connections.contains_key(**address))
+          .map(|(index, _)| index)
+          .collect()
+      } else {
+        let backend_address_map = context
+          .backend_addresses
+          .iter()
+          .enumerate()
+          .map(|(index, address)| (*address, index))
+          .collect::<HashMap<_, _>>();
+        let mut least_connections = connections.iter().collect::<Vec<_>>();
+
+        least_connections.sort_by(|a, b| a.1.cmp(b.1));
+
+        let min_connection_count = least_connections[0].1;
+        least_connections
+          .iter()
+          .take_while(|(_, connection_count)| *connection_count == min_connection_count)
+          .map(|tuple| tuple.0)
+          .map(|address| *backend_address_map.get(address.as_str()).unwrap())
+          .collect()
+      };
+
+    address_indices.sort_unstable();
+    RequestForwarder::new(context.backend_addresses[self.tie_breaker.pick(&address_indices)])
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use std::collections::HashSet;
+
+  fn select_several(strategy: &LeastConnection, count: usize) -> Vec<String> {
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+    };
+    (0..count)
+      .map(|_| strategy.select_backend(&request, &context).backend_address.to_string())
+      .collect()
+  }
+
+  #[test]
+  pub fn least_connection_breaks_ties_with_first() {
+    // given: backend servers without connections
+    let strategy = LeastConnection::new(TieBreaker::First);
+
+    // when:
+    let selected = select_several(&strategy, 3);
+
+    // then:
+    assert_eq!(selected, vec!["127.0.0.1:1"; 3]);
+  }
+
+  #[test]
+  pub fn least_connection_breaks_ties_with_round_robin() {
+    // given: backend servers without connections
+    let strategy = LeastConnection::new(TieBreaker::round_robin());
+
+    // when:
+    let selected = select_several(&strategy, 4);
+
+    // then:
+    assert_eq!(
+      selected,
+      vec!["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3", "127.0.0.1:1"]
+    );
+  }
+
+  #[test]
+  pub fn least_connection_breaks_ties_with_random() {
+    // given: backend servers without connections
+    let strategy = LeastConnection::new(TieBreaker::Random);
+
+    // when:
+    let selected = select_several(&strategy, 100);
+
+    // then: each is selected with a chance of 1 - (2/3)^100
+    assert_eq!(selected.into_iter().collect::<HashSet<_>>().len(), 3);
+  }
 
   #[test]
   pub fn least_connection_single_least_address() {
@@ -89,7 +142,7 @@ mod tests {
       backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
     };
 
-    let strategy = LeastConnection::new();
+    let strategy = LeastConnection::new(TieBreaker::default());
 
     strategy.on_tcp_open(&"127.0.0.1:1".parse().unwrap());
 
@@ -108,7 +161,7 @@ mod tests {
       backend_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
     };
 
-    let strategy = LeastConnection::new();
+    let strategy = LeastConnection::new(TieBreaker::default());
     strategy.on_tcp_open(&"127.0.0.1:1".parse().unwrap());
 
     assert_ne!(
@@ -0,0 +1,276 @@
+use std::{
+  collections::HashMap,
+  sync::RwLock,
+  time::{Duration, Instant},
+};
+
+use hyper::{Body, Request, StatusCode, Uri};
+
+use super::{tie_breaker::TieBreaker, Context, LoadBalancingStrategy, RequestForwarder};
+use crate::http_client::backend_address;
+
+/// The weight of the newest sample in the exponentially weighted moving averages.
+const SMOOTHING_FACTOR: f64 = 0.3;
+
+/// The response time, which a failed connection or a timeout counts as at
+/// least, so a backend server, which never answers, does not look fast.
+const FAILURE_PENALTY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct BackendTimes {
+  /// Moving average of the time to open a TCP connection in milliseconds.
+  connect_time: Option<f64>,
+  /// Moving average of the time until the response headers are received in milliseconds.
+  response_time: Option<f64>,
+  connections: usize,
+  /// How often the backend server was selected.
+  attempts: usize,
+}
+
+impl BackendTimes {
+  /// The expected latency weighted by the open connections, similar to
+  /// `least_time` of nginx. Lower is better.
+  fn score(&self) -> Option<f64> {
+    let latency = self.response_time.or(self.connect_time)?;
+    Some(latency * (self.connections + 1) as f64)
+  }
+}
+
+fn moving_average(average: Option<f64>, sample: Duration) -> Option<f64> {
+  let sample = sample.as_secs_f64() * 1000.0;
+  match average {
+    Some(average) => Some(average + SMOOTHING_FACTOR * (sample - average)),
+    None => Some(sample),
+  }
+}
+
+#[derive(Debug)]
+pub struct LeastTime {
+  backends: RwLock<HashMap<String, BackendTimes>>,
+  tie_breaker: TieBreaker,
+}
+
+impl LeastTime {
+  pub fn new(tie_breaker: TieBreaker) -> LeastTime {
+    LeastTime {
+      backends: RwLock::new(HashMap::new()),
+      tie_breaker,
+    }
+  }
+
+  /// Records a failed connection or a timeout of the backend server at
+  /// `address`, which took `duration`, as a penalty sample.
+  fn record_failure(&self, address: &str, duration: Duration) {
+    self.record_response_time(address, duration.max(FAILURE_PENALTY));
+  }
+
+  fn record_response_time(&self, address: &str, duration: Duration) {
+    let mut backends = self.backends.write().unwrap();
+    let times = backends.entry(address.to_string()).or_default();
+    times.response_time = moving_average(times.response_time, duration);
+  }
+}
+
+impl LoadBalancingStrategy for LeastTime {
+  fn name(&self) -> &'static str {
+    "LeastTime"
+  }
+
+  fn on_tcp_open(&self, remote: &Uri) {
+    if let Some(address) = backend_address(remote) {
+      let mut backends = self.backends.write().unwrap();
+      backends.entry(address).or_default().connections += 1;
+    }
+  }
+
+  fn on_tcp_close(&self, remote: &Uri) {
+    if let Some(address) = backend_address(remote) {
+      let mut backends = self.backends.write().unwrap();
+      let times = backends.entry(address).or_default();
+      times.connections = times.connections.saturating_sub(1);
+    }
+  }
+
+  fn on_tcp_connect_time(&self, remote: &Uri, duration: Duration) {
+    if let Some(address) = backend_address(remote) {
+      let mut backends = self.backends.write().unwrap();
+      let times = backends.entry(address).or_default();
+      times.connect_time = moving_average(times.connect_time, duration);
+    }
+  }
+
+  fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder<'l> {
+    let mut backends = self.backends.write().unwrap();
+    let (scores, attempts): (Vec<_>, Vec<_>) = context
+      .backend_addresses
+      .iter()
+      .map(|address| match backends.get(*address) {
+        Some(times) => (times.score(), times.attempts),
+        None => (None, 0),
+      })
+      .unzip();
+
+    // backend servers, which were never tried, are used first, so they get
+    // measured, otherwise the measured backend server with the lowest score
+    let untried = (0..scores.len())
+      .filter(|index| scores[*index].is_none() && attempts[*index] == 0)
+      .collect::<Vec<_>>();
+    let min_score = scores.iter().flatten().cloned().fold(f64::INFINITY, f64::min);
+    let address_indices: Vec<usize> = if !untried.is_empty() {
+      untried
+    } else if min_score.is_finite() {
+      (0..scores.len())
+        .filter(|index| scores[*index] == Some(min_score))
+        .collect()
+    } else {
+      // all backend servers are still awaiting their first measurement
+      (0..scores.len()).collect()
+    };
+
+    let address = context.backend_addresses[self.tie_breaker.pick(&address_indices)];
+    backends.entry(address.to_string()).or_default().attempts += 1;
+    drop(backends);
+    let start = Instant::now();
+    RequestForwarder::new_with_response_mapper(address, move |response| {
+      // Failed connections are answered quickly, but do not make a backend server fast
+      if matches!(response.status(), StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT) {
+        self.record_failure(address, start.elapsed());
+      } else {
+        self.record_response_time(address, start.elapsed());
+      }
+      response
+    })
+  }
+
+  fn backend_latency(&self, address: &str) -> Option<Duration> {
+    let backends = self.backends.read().unwrap();
+    let times = backends.get(address)?;
+    let latency_ms = times.response_time.or(times.connect_time)?;
+    Some(Duration::from_secs_f64(latency_ms / 1000.0))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::Response;
+
+  fn respond(strategy: &LeastTime, context: &Context, response_time: Duration) -> String {
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let forwarder = strategy.select_backend(&request, context);
+    let address = forwarder.backend_address.to_string();
+    drop(forwarder);
+    strategy.record_response_time(&address, response_time);
+    address
+  }
+
+  #[test]
+  pub fn least_time_prefers_unmeasured_addresses() {
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+    };
+    let strategy = LeastTime::new(TieBreaker::default());
+
+    strategy.on_tcp_connect_time(&"127.0.0.1:1".parse().unwrap(), Duration::from_millis(1));
+
+    assert_eq!(
+      strategy.select_backend(&request, &context).backend_address,
+      context.backend_addresses[1]
+    );
+  }
+
+  #[test]
+  pub fn least_time_routes_away_from_slow_address() {
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+    };
+    let strategy = LeastTime::new(TieBreaker::default());
+    strategy.record_response_time("127.0.0.1:1", Duration::from_millis(500));
+    strategy.record_response_time("127.0.0.1:2", Duration::from_millis(10));
+
+    for _ in 0..10 {
+      let address = respond(&strategy, &context, Duration::from_millis(10));
+      assert_eq!(address, "127.0.0.1:2");
+    }
+  }
+
+  #[test]
+  pub fn least_time_weights_open_connections() {
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+    };
+    let strategy = LeastTime::new(TieBreaker::default());
+    strategy.record_response_time("127.0.0.1:1", Duration::from_millis(30));
+    strategy.record_response_time("127.0.0.1:2", Duration::from_millis(10));
+    for _ in 0..3 {
+      strategy.on_tcp_open(&"127.0.0.1:2".parse().unwrap());
+    }
+
+    assert_eq!(
+      strategy.select_backend(&request, &context).backend_address,
+      context.backend_addresses[0]
+    );
+  }
+
+  #[test]
+  pub fn least_time_routes_away_from_failing_address() {
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+    };
+    let strategy = LeastTime::new(TieBreaker::default());
+    strategy.record_response_time("127.0.0.1:2", Duration::from_millis(10));
+    let request = Request::builder().body(Body::empty()).unwrap();
+
+    // the unmeasured address is tried once and fails to connect
+    let forwarder = strategy.select_backend(&request, &context);
+    assert_eq!(forwarder.backend_address, "127.0.0.1:1");
+    (forwarder.response_mapper)(Response::builder().status(502).body(Body::empty()).unwrap());
+    drop(forwarder);
+
+    for _ in 0..10 {
+      let address = respond(&strategy, &context, Duration::from_millis(10));
+      assert_eq!(address, "127.0.0.1:2");
+    }
+  }
+
+  #[test]
+  pub fn least_time_tries_unmeasured_address_only_once() {
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+    };
+    let strategy = LeastTime::new(TieBreaker::default());
+    strategy.record_response_time("127.0.0.1:2", Duration::from_millis(10));
+
+    // the first request to the unmeasured address is still in flight
+    let first = strategy.select_backend(&request, &context);
+    let second = strategy.select_backend(&request, &context);
+
+    assert_eq!(first.backend_address, "127.0.0.1:1");
+    assert_eq!(second.backend_address, "127.0.0.1:2");
+  }
+
+  #[test]
+  pub fn least_time_records_response_time() {
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1"],
+    };
+    let strategy = LeastTime::new(TieBreaker::default());
+
+    let forwarder = strategy.select_backend(&request, &context);
+    (forwarder.response_mapper)(Response::new(Body::empty()));
+    drop(forwarder);
+
+    let backends = strategy.backends.read().unwrap();
+    assert!(backends["127.0.0.1:1"].response_time.is_some());
+  }
+}
@@ -1,17 +1,29 @@
 use crate::{
-  http_client::StrategyNotifyHttpConnector,
+  http_client::{self, StrategyNotifyHttpConnector},
+  metrics::METRICS,
   middleware::{self, Middleware, MiddlewareChain},
   server::Scheme,
 };
 use async_trait::async_trait;
 use hyper::{Body, Client, Request, Response, Uri};
-use std::{convert::identity, fmt::Debug, net::SocketAddr};
+use std::{
+  convert::identity,
+  fmt::Debug,
+  net::SocketAddr,
+  sync::atomic::{AtomicU64, Ordering},
+  time::{Duration, Instant},
+};
 
 pub mod ip_hash;
 pub mod least_connection;
+pub mod least_time;
+pub mod peak_ewma;
 pub mod random;
 pub mod round_robin;
 pub mod sticky_cookie;
+pub mod tie_breaker;
+pub mod weighted_least_connection;
+pub mod weighted_random;
 
 /// A trait for implementing load balancing, see
 /// [`select_backend`](LoadBalancingStrategy::select_backend) for more details.
@@ -26,11 +38,37 @@ pub trait LoadBalancingStrategy: Send + Sync + std::fmt::Debug {
   /// [`StickyCookie`](sticky_cookie::StickyCookie).
   fn select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> RequestForwarder;
 
+  /// The name of this strategy in the configuration, like `RoundRobin`.
+  fn name(&self) -> &'static str;
+
   /// Called when a new TCP connection to a backend server opened.
   fn on_tcp_open(&self, _remote: &Uri) {}
 
   /// Called when an existing backend TCP connection is closed.
   fn on_tcp_close(&self, _remote: &Uri) {}
+
+  /// Called with the time it took to open a new TCP connection to a backend
+  /// server.
+  fn on_tcp_connect_time(&self, _remote: &Uri, _duration: Duration) {}
+
+  /// The latency this strategy expects from the backend server at `address`,
+  /// if it measures latencies.
+  fn backend_latency(&self, _address: &str) -> Option<Duration> {
+    None
+  }
+
+  /// The weight of the backend server at `address`, if this strategy weighs
+  /// backend servers.
+  fn backend_weight(&self, _address: &str) -> Option<u32> {
+    None
+  }
+
+  /// The backend server of `context`, which the client of `request` is
+  /// pinned to (like by a sticky cookie), if this strategy pins clients. A
+  /// soft draining backend server keeps receiving requests of these clients.
+  fn pinned_backend<'l>(&self, _request: &Request<Body>, _context: &Context<'l>) -> Option<&'l str> {
+    None
+  }
 }
 
 pub struct Context<'l> {
@@ -56,7 +94,7 @@ pub struct RequestForwarder<'l> {
 impl<'l> RequestForwarder<'l> {
   /// Constructs a new [`RequestForwarder`] which does not perform a final
   /// response transformation.
-  fn new(address: &str) -> RequestForwarder {
+  pub(crate) fn new(address: &str) -> RequestForwarder<'_> {
     RequestForwarder::new_with_response_mapper(address, identity)
   }
 
@@ -83,6 +121,11 @@ impl<'l> RequestForwarder<'l> {
     }
   }
 
+  /// The address of the selected backend server.
+  pub fn backend_address(&self) -> &str {
+    self.backend_address
+  }
+
   /// Forwards the `request` through `chain` to the backend server and applies
   /// the final response transformation of this [`RequestForwarder`].
   pub async fn forward_request_to_backend(
@@ -92,24 +135,21 @@ impl<'l> RequestForwarder<'l> {
     client_scheme: &Scheme,
     client_address: &SocketAddr,
     client: &Client<StrategyNotifyHttpConnector, Body>,
+    response_timeout: Option<Duration>,
   ) -> Response<Body> {
     let context = middleware::Context {
       client_scheme,
       client_address,
       backend_uri: self.backend_uri(&request),
       client,
+      response_timeout,
     };
     self.forward_request(request, chain, &context).await
   }
 
   fn backend_uri(&self, request: &Request<Body>) -> Uri {
     let path = request.uri().path_and_query().unwrap().clone();
-    Uri::builder()
-      .scheme("http")
-      .authority(self.backend_address)
-      .path_and_query(path)
-      .build()
-      .unwrap()
+    http_client::backend_uri(self.backend_address, path).unwrap()
   }
 }
 
@@ -127,3 +167,86 @@ impl Debug for RequestForwarder<'_> {
       .finish()
   }
 }
+
+/// The upper bounds of the buckets of `arlb_backend_selection_seconds`. A
+/// selection takes microseconds, far below the buckets of other histograms.
+pub const SELECTION_BUCKETS: [f64; 10] = [
+  0.000_001,
+  0.000_002_5,
+  0.000_005,
+  0.000_01,
+  0.000_025,
+  0.000_05,
+  0.000_1,
+  0.000_25,
+  0.000_5,
+  0.001,
+];
+
+pub const DEFAULT_SELECTION_SAMPLE_RATE: u64 = 100;
+
+pub static SELECTION_TIMING: SelectionTiming = SelectionTiming::new(DEFAULT_SELECTION_SAMPLE_RATE);
+
+/// Measures how long backend pools take to select a backend server, so the
+/// overhead of their strategies is observable. Only every `sample_rate`-th
+/// selection is timed, which keeps the cost of reading the clock off most
+/// requests.
+#[derive(Debug)]
+pub struct SelectionTiming {
+  sample_rate: AtomicU64,
+  selections: AtomicU64,
+}
+
+impl SelectionTiming {
+  pub const fn new(sample_rate: u64) -> SelectionTiming {
+    SelectionTiming {
+      sample_rate: AtomicU64::new(sample_rate),
+      selections: AtomicU64::new(0),
+    }
+  }
+
+  /// Applies a new `sample_rate` to the following selections.
+  pub fn configure(&self, sample_rate: u64) {
+    self.sample_rate.store(sample_rate.max(1), Ordering::Relaxed);
+  }
+
+  /// Calls `select` and records how long it took in
+  /// `arlb_backend_selection_seconds{pool,strategy}`, if it is sampled.
+  pub fn time<T>(&self, pool: &str, strategy: &'static str, select: impl FnOnce() -> T) -> T {
+    let selection = self.selections.fetch_add(1, Ordering::Relaxed);
+    if !selection.is_multiple_of(self.sample_rate.load(Ordering::Relaxed)) {
+      return select();
+    }
+    let start = Instant::now();
+    let selected = select();
+    METRICS.observe_with_buckets(
+      "arlb_backend_selection_seconds",
+      &[("pool", pool), ("strategy", strategy)],
+      start.elapsed().as_secs_f64(),
+      &SELECTION_BUCKETS,
+    );
+    selected
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_every_sample_rate_th_selection_is_timed() {
+    // given:
+    let timing = SelectionTiming::new(DEFAULT_SELECTION_SAMPLE_RATE);
+    timing.configure(3);
+    let labels = [("pool", "selection-timing"), ("strategy", "Random")];
+
+    // when:
+    let selected = (0..7)
+      .map(|it| timing.time("selection-timing", "Random", || it))
+      .collect::<Vec<_>>();
+
+    // then:
+    assert_eq!(selected, (0..7).collect::<Vec<_>>());
+    assert_eq!(METRICS.histogram_count("arlb_backend_selection_seconds", &labels), 3);
+  }
+}
@@ -0,0 +1,249 @@
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use hyper::{Body, Request, StatusCode};
+use rand::{seq::index::sample, thread_rng};
+
+use super::{Context, LoadBalancingStrategy, RequestForwarder};
+use crate::metrics::METRICS;
+
+#[derive(Debug, Default)]
+struct BackendLatency {
+  /// Moving average of the time until the response headers are received.
+  ewma: Option<Duration>,
+  measured_at: Option<Instant>,
+  /// Requests, which were forwarded to the backend server and did not complete yet.
+  in_flight: usize,
+}
+
+impl BackendLatency {
+  /// The average as of `now`, which decays towards zero while there are no new
+  /// measurements, so a backend server, which was slow for a while, receives
+  /// requests again to find out whether it recovered.
+  fn decayed_ewma(&self, decay: Duration, now: Instant) -> Option<Duration> {
+    let elapsed = now.saturating_duration_since(self.measured_at?);
+    Some(self.ewma?.mul_f64(decay_weight(elapsed, decay)))
+  }
+
+  /// Unmeasured backend servers cost nothing, so they are measured first.
+  fn cost(&self, decay: Duration, now: Instant) -> f64 {
+    let ewma = self.decayed_ewma(decay, now).unwrap_or_default();
+    ewma.as_secs_f64() * (self.in_flight + 1) as f64
+  }
+
+  /// Adds a `sample` to the average. Samples above the average replace it, so
+  /// a backend server, which suddenly slows down, is avoided right away.
+  fn record(&mut self, sample: Duration, decay: Duration, now: Instant) {
+    self.ewma = Some(match self.decayed_ewma(decay, now) {
+      Some(ewma) if sample < ewma => {
+        let elapsed = now.saturating_duration_since(self.measured_at.unwrap_or(now));
+        let weight = decay_weight(elapsed, decay);
+        ewma.mul_f64(weight) + sample.mul_f64(1.0 - weight)
+      }
+      _ => sample,
+    });
+    self.measured_at = Some(now);
+  }
+}
+
+/// The weight of the previous average after `elapsed`, which halves every
+/// `decay`.
+fn decay_weight(elapsed: Duration, decay: Duration) -> f64 {
+  0.5f64.powf(elapsed.as_secs_f64() / decay.as_secs_f64())
+}
+
+/// Selects the better of two random backend servers (power of two choices),
+/// comparing their peak exponentially weighted moving average latency weighted
+/// by their requests in flight.
+#[derive(Debug)]
+pub struct PeakEwma {
+  backends: Arc<Mutex<HashMap<String, BackendLatency>>>,
+  decay: Duration,
+}
+
+impl PeakEwma {
+  pub fn new(decay: Duration) -> PeakEwma {
+    PeakEwma {
+      backends: Arc::new(Mutex::new(HashMap::new())),
+      decay,
+    }
+  }
+
+  fn record_response_time(&self, address: &str, duration: Duration) {
+    let mut backends = self.backends.lock().unwrap();
+    let latency = backends.entry(address.to_string()).or_default();
+    latency.record(duration, self.decay, Instant::now());
+    let ewma_ms = latency.ewma.unwrap_or_default().as_millis() as i64;
+    METRICS.set_gauge(
+      "arlb_backend_latency_ewma_milliseconds",
+      &[("backend", address)],
+      ewma_ms,
+    );
+  }
+}
+
+/// Counts a request as in flight until it is dropped.
+struct InFlight {
+  backends: Arc<Mutex<HashMap<String, BackendLatency>>>,
+  address: String,
+}
+
+impl InFlight {
+  fn start(backends: &Arc<Mutex<HashMap<String, BackendLatency>>>, address: &str) -> InFlight {
+    backends
+      .lock()
+      .unwrap()
+      .entry(address.to_string())
+      .or_default()
+      .in_flight += 1;
+    InFlight {
+      backends: backends.clone(),
+      address: address.to_string(),
+    }
+  }
+}
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    if let Some(latency) = self.backends.lock().unwrap().get_mut(&self.address) {
+      latency.in_flight = latency.in_flight.saturating_sub(1);
+    }
+  }
+}
+
+impl LoadBalancingStrategy for PeakEwma {
+  fn name(&self) -> &'static str {
+    "PeakEwma"
+  }
+
+  fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder<'l> {
+    let addresses = context.backend_addresses;
+    let address = if addresses.len() < 2 {
+      addresses[0]
+    } else {
+      let backends = self.backends.lock().unwrap();
+      let now = Instant::now();
+      let cost = |address: &str| backends.get(address).map_or(0.0, |it| it.cost(self.decay, now));
+      let candidates = sample(&mut thread_rng(), addresses.len(), 2);
+      let (first, second) = (addresses[candidates.index(0)], addresses[candidates.index(1)]);
+      if cost(second) < cost(first) {
+        second
+      } else {
+        first
+      }
+    };
+
+    let in_flight = InFlight::start(&self.backends, address);
+    let start = Instant::now();
+    RequestForwarder::new_with_response_mapper(address, move |response| {
+      let _ = &in_flight;
+      // Failed connections are answered quickly, but do not make a backend server fast
+      if !matches!(response.status(), StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT) {
+        self.record_response_time(address, start.elapsed());
+      }
+      response
+    })
+  }
+
+  fn backend_latency(&self, address: &str) -> Option<Duration> {
+    let backends = self.backends.lock().unwrap();
+    backends.get(address)?.decayed_ewma(self.decay, Instant::now())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const DECAY: Duration = Duration::from_secs(10);
+
+  #[test]
+  pub fn peak_ewma_routes_away_from_slow_address() {
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+    };
+    let strategy = PeakEwma::new(DECAY);
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let response_time = |address: &str| match address {
+      "127.0.0.1:1" => Duration::from_millis(200),
+      _ => Duration::from_millis(10),
+    };
+
+    let mut slow = 0;
+    for _ in 0..300 {
+      let address = strategy
+        .select_backend(&request, &context)
+        .backend_address()
+        .to_string();
+      strategy.record_response_time(&address, response_time(&address));
+      if address == "127.0.0.1:1" {
+        slow += 1;
+      }
+    }
+
+    // Without latency awareness the slow address would receive a third of the requests
+    assert!(slow < 30, "{}", slow);
+  }
+
+  #[test]
+  pub fn peak_ewma_weights_requests_in_flight() {
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+    };
+    let strategy = PeakEwma::new(DECAY);
+    let request = Request::builder().body(Body::empty()).unwrap();
+    strategy.record_response_time("127.0.0.1:1", Duration::from_millis(30));
+    strategy.record_response_time("127.0.0.1:2", Duration::from_millis(10));
+    let _in_flight = (0..3)
+      .map(|_| InFlight::start(&strategy.backends, "127.0.0.1:2"))
+      .collect::<Vec<_>>();
+
+    assert_eq!(
+      strategy.select_backend(&request, &context).backend_address(),
+      "127.0.0.1:1"
+    );
+  }
+
+  #[test]
+  pub fn peak_ewma_follows_peaks_and_decays() {
+    let mut latency = BackendLatency::default();
+    let now = Instant::now();
+
+    latency.record(Duration::from_millis(10), DECAY, now);
+    latency.record(Duration::from_millis(100), DECAY, now);
+    assert_eq!(latency.decayed_ewma(DECAY, now), Some(Duration::from_millis(100)));
+
+    // stale measurements age out
+    let later = now + DECAY * 2;
+    assert_eq!(latency.decayed_ewma(DECAY, later), Some(Duration::from_millis(25)));
+    latency.record(Duration::from_millis(20), DECAY, later);
+    let ewma = latency.decayed_ewma(DECAY, later).unwrap();
+    assert!(
+      ewma >= Duration::from_millis(20) && ewma <= Duration::from_millis(25),
+      "{:?}",
+      ewma
+    );
+  }
+
+  #[test]
+  pub fn peak_ewma_counts_requests_in_flight_until_they_complete() {
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1"],
+    };
+    let strategy = PeakEwma::new(DECAY);
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let in_flight = || strategy.backends.lock().unwrap()["127.0.0.1:1"].in_flight;
+
+    let forwarder = strategy.select_backend(&request, &context);
+    assert_eq!(in_flight(), 1);
+    drop(forwarder);
+
+    assert_eq!(in_flight(), 0);
+  }
+}
@@ -14,6 +14,10 @@ impl Random {
 
 #[async_trait]
 impl LoadBalancingStrategy for Random {
+  fn name(&self) -> &'static str {
+    "Random"
+  }
+
   fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder {
     let mut rng = thread_rng();
     let index = rng.gen_range(0..context.backend_addresses.len());
@@ -16,6 +16,10 @@ impl RoundRobin {
 }
 
 impl LoadBalancingStrategy for RoundRobin {
+  fn name(&self) -> &'static str {
+    "RoundRobin"
+  }
+
   fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder {
     let mut rrc_handle = self.rrc.lock().unwrap();
     *rrc_handle = (*rrc_handle + 1) % context.backend_addresses.len() as u32;
@@ -27,6 +31,7 @@ impl LoadBalancingStrategy for RoundRobin {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::testing::{toml_addresses, MockBackend, TestConnection, TestProxy};
 
   #[test]
   pub fn round_robin_strategy_single_address() {
@@ -59,4 +64,31 @@ mod tests {
     assert_eq!(strategy.select_backend(&request, &context).backend_address, address_2);
     assert_eq!(strategy.select_backend(&request, &context).backend_address, address_1);
   }
+
+  #[tokio::test]
+  async fn round_robin_strategy_spreads_requests_evenly() {
+    // given:
+    let backends = MockBackend::http_fleet(3).await.unwrap();
+    let proxy = TestProxy::start(&format!(
+      r#"
+      [[backend_pools]]
+      matcher = "Host('localhost')"
+      addresses = {}
+      schemes = ["HTTP"]
+      strategy = {{ RoundRobin = {{}} }}
+      "#,
+      toml_addresses(&backends)
+    ))
+    .await
+    .unwrap();
+    let mut connection = TestConnection::plain(proxy.http_address()).await.unwrap();
+
+    // when:
+    let distribution = connection.distribution("localhost", "/", 6).await.unwrap();
+
+    // then:
+    let expected = (0..3).map(|it| (format!("backend-{}", it), 2)).collect();
+    assert_eq!(distribution, expected);
+    proxy.stop().await.unwrap();
+  }
 }
@@ -68,6 +68,20 @@ impl StickyCookie {
 
 #[async_trait]
 impl LoadBalancingStrategy for StickyCookie {
+  fn name(&self) -> &'static str {
+    "StickyCookie"
+  }
+
+  fn backend_weight(&self, address: &str) -> Option<u32> {
+    self.inner.backend_weight(address)
+  }
+
+  fn pinned_backend<'l>(&self, request: &Request<Body>, context: &Context<'l>) -> Option<&'l str> {
+    let cookie = self.try_parse_sticky_cookie(request)?;
+    let backend_addresses: &'l [&'l str] = context.backend_addresses;
+    backend_addresses.iter().copied().find(|it| *it == cookie.value())
+  }
+
   fn select_backend<'l>(&'l self, request: &Request<Body>, context: &'l Context) -> RequestForwarder {
     let backend_address = self
       .try_parse_sticky_cookie(&request)
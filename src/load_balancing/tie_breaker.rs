@@ -0,0 +1,32 @@
+use rand::{thread_rng, Rng};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Decides between backend servers, which a strategy considers equally good
+/// (like several backend servers without open connections).
+#[derive(Debug, Default)]
+pub enum TieBreaker {
+  /// The first one in the order of the configuration. This concentrates load
+  /// on the backend servers listed first.
+  First,
+  /// A random one.
+  #[default]
+  Random,
+  /// Cycles through the tied backend servers by keeping an internal counter.
+  RoundRobin(AtomicUsize),
+}
+
+impl TieBreaker {
+  pub fn round_robin() -> TieBreaker {
+    TieBreaker::RoundRobin(AtomicUsize::new(0))
+  }
+
+  /// Picks one of the `tied` indices of backend servers, which must not be
+  /// empty and be in the order of the configuration.
+  pub fn pick(&self, tied: &[usize]) -> usize {
+    match self {
+      TieBreaker::First => tied[0],
+      TieBreaker::Random => tied[thread_rng().gen_range(0..tied.len())],
+      TieBreaker::RoundRobin(counter) => tied[counter.fetch_add(1, Ordering::Relaxed) % tied.len()],
+    }
+  }
+}
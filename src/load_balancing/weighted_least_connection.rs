@@ -0,0 +1,140 @@
+use std::{collections::HashMap, num::NonZeroU32, sync::RwLock};
+
+use hyper::{Body, Request, Uri};
+
+use super::{tie_breaker::TieBreaker, Context, LoadBalancingStrategy, RequestForwarder};
+use crate::http_client::backend_address;
+
+/// Like [`LeastConnection`](super::least_connection::LeastConnection), but the
+/// open connections of each backend server are divided by its weight, so
+/// backend servers with more capacity receive proportionally more connections.
+#[derive(Debug)]
+pub struct WeightedLeastConnection {
+  /// Backend servers without a weight have the weight 1.
+  weights: HashMap<String, NonZeroU32>,
+  connections: RwLock<HashMap<String, usize>>,
+  tie_breaker: TieBreaker,
+}
+
+impl WeightedLeastConnection {
+  pub fn new(weights: HashMap<String, NonZeroU32>, tie_breaker: TieBreaker) -> WeightedLeastConnection {
+    WeightedLeastConnection {
+      weights,
+      connections: RwLock::new(HashMap::new()),
+      tie_breaker,
+    }
+  }
+
+  fn weight(&self, address: &str) -> f64 {
+    self.weights.get(address).map_or(1, |it| it.get()) as f64
+  }
+}
+
+impl LoadBalancingStrategy for WeightedLeastConnection {
+  fn name(&self) -> &'static str {
+    "WeightedLeastConnection"
+  }
+
+  fn backend_weight(&self, address: &str) -> Option<u32> {
+    Some(self.weights.get(address).map_or(1, |it| it.get()))
+  }
+
+  fn on_tcp_open(&self, remote: &Uri) {
+    if let Some(address) = backend_address(remote) {
+      let mut connections = self.connections.write().unwrap();
+      *connections.entry(address).or_insert(0) += 1;
+    }
+  }
+
+  fn on_tcp_close(&self, remote: &Uri) {
+    if let Some(address) = backend_address(remote) {
+      let mut connections = self.connections.write().unwrap();
+      let count = connections.entry(address).or_insert(0);
+      *count = count.saturating_sub(1);
+    }
+  }
+
+  fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder<'l> {
+    let ratios = {
+      let connections = self.connections.read().unwrap();
+      context
+        .backend_addresses
+        .iter()
+        .map(|address| *connections.get(*address).unwrap_or(&0) as f64 / self.weight(address))
+        .collect::<Vec<_>>()
+    };
+
+    let min_ratio = ratios.iter().cloned().fold(f64::INFINITY, f64::min);
+    let address_indices: Vec<usize> = (0..ratios.len()).filter(|index| ratios[*index] == min_ratio).collect();
+
+    RequestForwarder::new(context.backend_addresses[self.tie_breaker.pick(&address_indices)])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::{thread_rng, Rng};
+
+  fn weights(weights: &[(&str, u32)]) -> HashMap<String, NonZeroU32> {
+    weights
+      .iter()
+      .map(|(address, weight)| (address.to_string(), NonZeroU32::new(*weight).unwrap()))
+      .collect()
+  }
+
+  fn uri(address: &str) -> Uri {
+    format!("http://{}", address).parse().unwrap()
+  }
+
+  #[test]
+  pub fn weighted_least_connection_divides_connections_by_weight() {
+    // given:
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+    };
+    let strategy = WeightedLeastConnection::new(weights(&[("127.0.0.1:1", 2)]), TieBreaker::default());
+    strategy.on_tcp_open(&uri("127.0.0.1:1"));
+    strategy.on_tcp_open(&uri("127.0.0.1:2"));
+
+    // when:
+    let selected = strategy.select_backend(&request, &context);
+
+    // then: 1 / 2 is less than 1 / 1
+    assert_eq!(selected.backend_address, "127.0.0.1:1");
+  }
+
+  #[test]
+  pub fn weighted_least_connection_distributes_steady_load_by_weight() {
+    // given:
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2"],
+    };
+    let strategy = WeightedLeastConnection::new(
+      weights(&[("127.0.0.1:1", 3), ("127.0.0.1:2", 1)]),
+      TieBreaker::default(),
+    );
+    let mut open = Vec::new();
+
+    // when: 40 connections are open at any time, while old ones are closed and new ones opened
+    let mut rng = thread_rng();
+    for round in 0..1000 {
+      if round >= 40 {
+        let closed: String = open.swap_remove(rng.gen_range(0..open.len()));
+        strategy.on_tcp_close(&uri(&closed));
+      }
+      let address = strategy.select_backend(&request, &context).backend_address.to_string();
+      strategy.on_tcp_open(&uri(&address));
+      open.push(address);
+    }
+
+    // then:
+    let heavy = open.iter().filter(|it| *it == "127.0.0.1:1").count();
+    let light = open.len() - heavy;
+    assert!((29..=31).contains(&heavy), "heavy: {}, light: {}", heavy, light);
+  }
+}
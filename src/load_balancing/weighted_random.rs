@@ -0,0 +1,118 @@
+use std::{collections::HashMap, num::NonZeroU32};
+
+use hyper::{Body, Request};
+use rand::{thread_rng, Rng};
+
+use super::{Context, LoadBalancingStrategy, RequestForwarder};
+
+/// Like [`Random`](super::random::Random), but each backend server is selected
+/// with a probability proportional to its weight. Unlike round robin there is
+/// no shared state, which concurrent requests would contend for.
+#[derive(Debug)]
+pub struct WeightedRandom {
+  /// Backend servers without a weight have the weight 1.
+  weights: HashMap<String, NonZeroU32>,
+}
+
+impl WeightedRandom {
+  pub fn new(weights: HashMap<String, NonZeroU32>) -> WeightedRandom {
+    WeightedRandom { weights }
+  }
+
+  fn weight(&self, address: &str) -> u64 {
+    self.weights.get(address).map_or(1, |it| it.get()) as u64
+  }
+}
+
+impl LoadBalancingStrategy for WeightedRandom {
+  fn name(&self) -> &'static str {
+    "WeightedRandom"
+  }
+
+  fn backend_weight(&self, address: &str) -> Option<u32> {
+    Some(self.weights.get(address).map_or(1, |it| it.get()))
+  }
+
+  fn select_backend<'l>(&'l self, _request: &Request<Body>, context: &'l Context) -> RequestForwarder<'l> {
+    // The working backend servers change, so the table is built for each request
+    let cumulative_weights = context
+      .backend_addresses
+      .iter()
+      .scan(0, |total, address| {
+        *total += self.weight(address);
+        Some(*total)
+      })
+      .collect::<Vec<_>>();
+    let total = *cumulative_weights.last().unwrap();
+
+    let draw = thread_rng().gen_range(0..total);
+    let index = cumulative_weights.partition_point(|it| *it <= draw);
+    RequestForwarder::new(context.backend_addresses[index])
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn weights(weights: &[(&str, u32)]) -> HashMap<String, NonZeroU32> {
+    weights
+      .iter()
+      .map(|(address, weight)| (address.to_string(), NonZeroU32::new(*weight).unwrap()))
+      .collect()
+  }
+
+  #[test]
+  pub fn weighted_random_distributes_requests_by_weight() {
+    // given:
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"],
+    };
+    let strategy = WeightedRandom::new(weights(&[("127.0.0.1:1", 5), ("127.0.0.1:2", 3)]));
+
+    // when:
+    let draws = 90_000;
+    let mut selected = HashMap::new();
+    for _ in 0..draws {
+      let address = strategy.select_backend(&request, &context).backend_address;
+      *selected.entry(address).or_insert(0) += 1;
+    }
+
+    // then: the shares are 5/9, 3/9 and 1/9, a deviation of 2% is more than 10 standard deviations
+    for (address, weight) in &[("127.0.0.1:1", 5.0), ("127.0.0.1:2", 3.0), ("127.0.0.1:3", 1.0)] {
+      let share = selected[address] as f64 / draws as f64;
+      let expected = weight / 9.0;
+      assert!(
+        (share - expected).abs() < 0.02,
+        "{}: {} != {}",
+        address,
+        share,
+        expected
+      );
+    }
+  }
+
+  #[test]
+  pub fn weighted_random_selects_the_only_address() {
+    let request = Request::builder().body(Body::empty()).unwrap();
+    let context = Context {
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_addresses: &["127.0.0.1:1"],
+    };
+    let strategy = WeightedRandom::new(HashMap::new());
+
+    let selected = strategy.select_backend(&request, &context);
+
+    assert_eq!(selected.backend_address, "127.0.0.1:1");
+  }
+
+  #[test]
+  pub fn weighted_random_reports_weights() {
+    let strategy = WeightedRandom::new(weights(&[("127.0.0.1:1", 5)]));
+
+    assert_eq!(strategy.backend_weight("127.0.0.1:1"), Some(5));
+    assert_eq!(strategy.backend_weight("127.0.0.1:2"), Some(1));
+  }
+}
@@ -1,28 +1,693 @@
-use log::{info, LevelFilter};
+use crate::{
+  configuration::{LoggingConfig, RollingFileConfig},
+  metrics::METRICS,
+  utils::split_once,
+};
+use chrono::{DateTime, Local, SecondsFormat};
+use gethostname::gethostname;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{info, Level, LevelFilter, Log, Metadata, Record};
 use log4rs::{
-  append::console::ConsoleAppender,
-  config::{Appender, Logger, Root},
+  append::{
+    console::ConsoleAppender,
+    rolling_file::{
+      policy::compound::{
+        roll::fixed_window::FixedWindowRoller,
+        trigger::{size::SizeTrigger, Trigger},
+        CompoundPolicy,
+      },
+      LogFile, RollingFileAppender,
+    },
+    Append,
+  },
+  config::{Appender, Deserializers, Logger, Root},
   encode::pattern,
   Config,
 };
+use once_cell::sync::Lazy;
 use pattern::PatternEncoder;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::{
+  cell::RefCell,
+  fmt::Display,
+  fs,
+  future::Future,
+  io,
+  net::{SocketAddr, UdpSocket},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc::{sync_channel, SyncSender, TrySendError},
+    Mutex,
+  },
+  thread,
+};
+
+/// The target of access log entries, which are written once per request.
+pub const ACCESS_LOG_TARGET: &str = "access";
+
+const PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S%.9f)} {({l}):5} {t} - {m}{n}";
+const ACCESS_LOG_PATTERN: &str = "{d(%Y-%m-%d %H:%M:%S%.9f)} {m}{n}";
+/// The pattern of JSON entries, which are formatted completely beforehand.
+const JSON_PATTERN: &str = "{m}{n}";
+
+/// How log entries are written.
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+  /// A line of text per entry, which ends with its correlation fields.
+  #[default]
+  Text,
+  /// A JSON object per line with the `timestamp`, `level`, `target`,
+  /// `message` and correlation fields of the entry.
+  Json,
+}
+
+/// Correlation fields like the id of a request or the address of a backend
+/// server, which are attached to log entries instead of being formatted into
+/// their messages.
+pub type Fields = Vec<(&'static str, String)>;
+
+tokio::task_local! {
+  /// The fields of the request, which the current task serves.
+  static TASK_FIELDS: RefCell<Fields>;
+}
+
+thread_local! {
+  /// The fields of the entries, which are written within [`with_fields`].
+  static FIELDS: RefCell<Fields> = const { RefCell::new(Vec::new()) };
+}
+
+/// Attaches the `fields` to all entries, which are logged while `future` is
+/// polled. Fields can be added later on via [`add_field`].
+pub async fn scope_fields<F: Future>(fields: Fields, future: F) -> F::Output {
+  TASK_FIELDS.scope(RefCell::new(fields), future).await
+}
+
+/// Adds a field to the entries of the current task, if it runs within
+/// [`scope_fields`], like the backend server once it was selected. A field
+/// with the same key is replaced.
+pub fn add_field(key: &'static str, value: impl Display) {
+  let _ = TASK_FIELDS.try_with(|fields| {
+    let mut fields = fields.borrow_mut();
+    let value = value.to_string();
+    match fields.iter_mut().find(|(it, _)| *it == key) {
+      Some(field) => field.1 = value,
+      None => fields.push((key, value)),
+    }
+  });
+}
+
+/// Attaches the `fields` to the entries, which are logged by `log`, for
+/// entries outside of a request, like the transitions of health checks.
+pub fn with_fields<R>(fields: &[(&'static str, &dyn Display)], log: impl FnOnce() -> R) -> R {
+  let previous = FIELDS.with(|it| {
+    let mut current = it.borrow_mut();
+    let previous = current.len();
+    current.extend(fields.iter().map(|(key, value)| (*key, value.to_string())));
+    previous
+  });
+  let result = log();
+  FIELDS.with(|it| it.borrow_mut().truncate(previous));
+  result
+}
+
+fn current_fields() -> Fields {
+  let mut fields = TASK_FIELDS.try_with(|it| it.borrow().clone()).unwrap_or_default();
+  FIELDS.with(|it| fields.extend(it.borrow().iter().cloned()));
+  fields
+}
+
+/// When a rolling file is rolled besides exceeding its size. Hours and days
+/// begin in the local time zone.
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+  Hourly,
+  Daily,
+}
+
+impl Rotation {
+  /// The number of the hour or day since the epoch, which `time` is part of.
+  fn period(self, time: DateTime<Local>) -> i64 {
+    let seconds = match self {
+      Rotation::Hourly => 60 * 60,
+      Rotation::Daily => 24 * 60 * 60,
+    };
+    time.naive_local().timestamp().div_euclid(seconds)
+  }
+}
+
+/// Allows to reconfigure the logging at runtime.
+pub struct Logging {
+  handle: log4rs::Handle,
+}
+
+/// The logging, which was configured last via [`Logging::reconfigure`], so
+/// [`set_level`] can apply another level to it.
+static CONFIGURED: Lazy<Mutex<Option<(log4rs::Handle, LoggingConfig)>>> = Lazy::new(|| Mutex::new(None));
+
+/// The level, which was set via [`set_level`] and takes precedence over the
+/// configured one.
+static LEVEL_OVERRIDE: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+/// Initializes the logging with a console appender, before the configuration
+/// is read. Afterwards the logging should be configured via
+/// [`Logging::reconfigure`].
+pub fn initialize() -> Logging {
+  let config = LoggingConfig::default();
+  let directives = level_directives(&config).unwrap_or_else(|e| panic!("{}", e));
+  let log4rs_config = default_config(&directives, &config).unwrap();
+  let handle = log4rs::init_config(log4rs_config).expect("Initializing logging should not fail");
+  info!("Logging Level: {}", &directives.root);
+  Logging { handle }
+}
+
+impl Logging {
+  /// Applies the `config`, which either refers to a log4rs YAML configuration
+  /// or is used to build the default configuration.
+  pub fn reconfigure(&self, config: &LoggingConfig) -> Result<(), io::Error> {
+    let log4rs_config = match &config.config_path {
+      Some(config_path) => log4rs::config::load_config_file(config_path, Deserializers::default()).map_err(|e| {
+        io::Error::new(
+          io::ErrorKind::InvalidData,
+          format!("Could not load log4rs configuration {}: {}", config_path.display(), e),
+        )
+      })?,
+      None => default_config(&level_directives(config)?, config)?,
+    };
+    self.handle.set_config(log4rs_config);
+    *CONFIGURED.lock().unwrap() = Some((self.handle.clone(), config.clone()));
+    Ok(())
+  }
+}
+
+/// Replaces the configured level with `level` (directives like
+/// `debug,hyper=warn`) until the load balancer restarts, also across reloads.
+/// `None` restores the configured level. Fails if the level is invalid, or
+/// the logging is not configured by the load balancer or uses a log4rs
+/// configuration (with the kind `Unsupported`).
+pub fn set_level(level: Option<&str>) -> Result<(), io::Error> {
+  if let Some(level) = level {
+    parse_directives(level)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid log level: {}", level)))?;
+  }
+  let configured = CONFIGURED.lock().unwrap();
+  let (handle, config) = match &*configured {
+    Some((_, config)) if config.config_path.is_some() => {
+      return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "The level of a log4rs configuration can not be changed",
+      ))
+    }
+    Some(configured) => configured,
+    None => {
+      return Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "The logging is not configured by the load balancer",
+      ))
+    }
+  };
+  let previous = std::mem::replace(&mut *LEVEL_OVERRIDE.lock().unwrap(), level.map(str::to_string));
+  match level_directives(config).and_then(|directives| default_config(&directives, config)) {
+    Ok(log4rs_config) => {
+      handle.set_config(log4rs_config);
+      Ok(())
+    }
+    Err(e) => {
+      *LEVEL_OVERRIDE.lock().unwrap() = previous;
+      Err(e)
+    }
+  }
+}
+
+/// Handles the `/log-level` endpoint of the admin API: applies the level in
+/// the body of a `PUT` or restores the configured level on `DELETE`.
+pub async fn handle_request(request: Request<Body>) -> Response<Body> {
+  let reset = request.method() == Method::DELETE;
+  let level = match hyper::body::to_bytes(request.into_body()).await {
+    Ok(body) => String::from_utf8_lossy(&body).trim().to_string(),
+    Err(e) => {
+      return text_response(
+        StatusCode::BAD_REQUEST,
+        format!("Could not read the level due to: {}", e),
+      )
+    }
+  };
+  let level = Some(level.as_str()).filter(|_| !reset);
+  match set_level(level) {
+    Ok(()) => {
+      match level {
+        Some(level) => info!("Set the log level to {}", level),
+        None => info!("Restored the configured log level"),
+      }
+      Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+    }
+    Err(e) if e.kind() == io::ErrorKind::InvalidData => text_response(StatusCode::BAD_REQUEST, e.to_string()),
+    Err(e) => text_response(StatusCode::CONFLICT, e.to_string()),
+  }
+}
 
-pub fn initialize() -> log4rs::Handle {
-  let log_level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| "INFO".into());
-  let level_filter = parse_level_filter(&log_level).unwrap_or_else(|| panic!("Invalid log level: {}", &log_level));
+fn text_response(status: StatusCode, message: String) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .body(Body::from(format!("{}\n", message)))
+    .unwrap()
+}
+
+/// The level of the root logger and of specific log targets, parsed from a
+/// list like `info,hyper=warn`.
+#[derive(Debug, PartialEq)]
+struct Directives {
+  root: LevelFilter,
+  targets: Vec<(String, LevelFilter)>,
+}
+
+/// A level set via [`set_level`] and the environment variable `RUST_LOG`
+/// override the configured `level`. If none is set the environment variable
+/// `LOG_LEVEL` or `INFO` is used.
+fn level_directives(config: &LoggingConfig) -> Result<Directives, io::Error> {
+  let level_override = LEVEL_OVERRIDE.lock().unwrap().clone();
+  let (source, level) = match (level_override, std::env::var("RUST_LOG"), &config.level) {
+    (Some(level), _, _) => ("the admin API", level),
+    (None, Ok(level), _) => ("RUST_LOG", level),
+    (None, Err(_), Some(level)) => ("level", level.clone()),
+    (None, Err(_), None) => (
+      "LOG_LEVEL",
+      std::env::var("LOG_LEVEL").unwrap_or_else(|_| "INFO".into()),
+    ),
+  };
+  parse_directives(&level).ok_or_else(|| {
+    io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("Invalid log level in {}: {}", source, level),
+    )
+  })
+}
+
+fn parse_directives(str: &str) -> Option<Directives> {
+  let mut directives = Directives {
+    root: LevelFilter::Info,
+    targets: Vec::new(),
+  };
+  for directive in str.split(',').map(|it| it.trim()).filter(|it| !it.is_empty()) {
+    match split_once(directive, '=') {
+      Some((target, level)) => directives
+        .targets
+        .push((target.trim().to_string(), parse_level_filter(level.trim())?)),
+      None => directives.root = parse_level_filter(directive)?,
+    }
+  }
+  Some(directives)
+}
+
+/// Logs to the console or to the configured `file` and `syslog`, except for
+/// access log entries, which are written to a rolling file if `access_log` is
+/// configured.
+fn default_config(directives: &Directives, config: &LoggingConfig) -> Result<Config, io::Error> {
+  let format = config.format;
+  let pattern = match format {
+    LogFormat::Text => PATTERN,
+    LogFormat::Json => JSON_PATTERN,
+  };
+  let mut builder = Config::builder();
+  let mut root = Root::builder();
+  if let Some(file) = &config.file {
+    let file = Formatted::new(rolling_file_appender(file, pattern)?, format);
+    builder = builder.appender(Appender::builder().build("file", Box::new(file)));
+    root = root.appender("file");
+  }
+  if let Some(address) = &config.syslog {
+    let syslog = Formatted::new(SyslogAppender::new(address, format)?, format);
+    builder = builder.appender(Appender::builder().build("syslog", Box::new(syslog)));
+    root = root.appender("syslog");
+  }
+  if config.file.is_none() && config.syslog.is_none() {
+    let stdout = ConsoleAppender::builder()
+      .encoder(Box::new(PatternEncoder::new(pattern)))
+      .build();
+    let stdout = Formatted::new(stdout, format);
+    builder = builder.appender(Appender::builder().build("stdout", Box::new(stdout)));
+    root = root.appender("stdout");
+  }
+  if directives.targets.iter().all(|(target, _)| target != "ureq") {
+    builder = builder.logger(Logger::builder().build("ureq", LevelFilter::Warn));
+  }
+  for (target, level_filter) in &directives.targets {
+    builder = builder.logger(Logger::builder().build(target, *level_filter));
+  }
+  if let Some(access_log) = &config.access_log {
+    let access = AccessLogAppender::new(
+      access_log,
+      config.access_log_buffer_size,
+      config.access_log_sample_rate,
+      format,
+    )?;
+    builder = builder
+      .appender(Appender::builder().build("access", Box::new(Formatted::new(access, format))))
+      .logger(
+        Logger::builder()
+          .appender("access")
+          .additive(false)
+          .build(ACCESS_LOG_TARGET, LevelFilter::Info),
+      );
+  }
+  builder
+    .build(root.build(directives.root))
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes the entries of `inner` in the `format`, attaching the correlation
+/// fields of the entries. The pattern of `inner` has to match the format.
+#[derive(Debug)]
+struct Formatted {
+  inner: Box<dyn Append>,
+  format: LogFormat,
+}
+
+impl Formatted {
+  fn new<A: Append>(inner: A, format: LogFormat) -> Formatted {
+    Formatted {
+      inner: Box::new(inner),
+      format,
+    }
+  }
+}
+
+impl Log for Formatted {
+  fn enabled(&self, _metadata: &Metadata) -> bool {
+    true
+  }
+
+  fn log(&self, record: &Record) {
+    let fields = current_fields();
+    let message = match self.format {
+      // The access log has a format of its own
+      LogFormat::Text if fields.is_empty() || record.target() == ACCESS_LOG_TARGET => None,
+      LogFormat::Text => {
+        let fields = fields
+          .iter()
+          .map(|(key, value)| format!("{}: {}", key, value))
+          .collect::<Vec<_>>();
+        Some(format!("{} ({})", record.args(), fields.join(", ")))
+      }
+      LogFormat::Json => Some(json_entry(record, fields)),
+    };
+    // Logging must never fail
+    let _ = match message {
+      Some(message) => self.inner.append(
+        &Record::builder()
+          .args(format_args!("{}", message))
+          .metadata(record.metadata().clone())
+          .module_path(record.module_path())
+          .file(record.file())
+          .line(record.line())
+          .build(),
+      ),
+      None => self.inner.append(record),
+    };
+  }
+
+  fn flush(&self) {
+    self.inner.flush();
+  }
+}
+
+/// Collects the messages of entries, for tests which assert what is logged.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+struct Capture(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+#[cfg(test)]
+impl Log for Capture {
+  fn enabled(&self, _metadata: &Metadata) -> bool {
+    true
+  }
+
+  fn log(&self, record: &Record) {
+    self.0.lock().unwrap().push(record.args().to_string());
+  }
+
+  fn flush(&self) {}
+}
 
-  let pattern = PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S%.9f)} {({l}):5} {t} - {m}{n}");
+/// Installs a global logger, which collects the JSON entries of all targets
+/// on the warn level and above, and returns them. Entries of concurrent tests
+/// are collected as well.
+#[cfg(test)]
+pub fn capture_json_entries() -> std::sync::Arc<std::sync::Mutex<Vec<String>>> {
+  static CAPTURE: once_cell::sync::Lazy<Capture> = once_cell::sync::Lazy::new(|| {
+    let capture = Capture::default();
+    log::set_boxed_logger(Box::new(Formatted::new(capture.clone(), LogFormat::Json))).unwrap();
+    log::set_max_level(LevelFilter::Warn);
+    capture
+  });
+  CAPTURE.0.clone()
+}
 
-  let stdout = ConsoleAppender::builder().encoder(Box::new(pattern)).build();
-  let config = Config::builder()
-    .logger(Logger::builder().build("ureq", LevelFilter::Warn))
-    .appender(Appender::builder().build("stdout", Box::new(stdout)))
-    .build(Root::builder().appender("stdout").build(level_filter))
-    .unwrap();
+/// Formats the `record` as a single line JSON object.
+fn json_entry(record: &Record, fields: Fields) -> String {
+  let mut entry = Map::new();
+  let timestamp = Local::now().to_rfc3339_opts(SecondsFormat::Nanos, false);
+  entry.insert("timestamp".into(), Value::String(timestamp));
+  entry.insert("level".into(), Value::String(record.level().to_string()));
+  entry.insert("target".into(), Value::String(record.target().to_string()));
+  entry.insert("message".into(), Value::String(record.args().to_string()));
+  for (key, value) in fields {
+    entry.entry(key).or_insert(Value::String(value));
+  }
+  Value::Object(entry).to_string()
+}
+
+/// Rolls the file once it exceeds `max_size` bytes or a new period of its
+/// `rotate` began and keeps `retention` rolled files (`access.log.0` being
+/// the newest).
+fn rolling_file_appender(config: &RollingFileConfig, pattern: &str) -> Result<RollingFileAppender, io::Error> {
+  let invalid_data = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+  let roll_pattern = format!("{}.{{}}", config.path.display());
+  let roller = FixedWindowRoller::builder()
+    .build(&roll_pattern, config.retention)
+    .map_err(invalid_data)?;
+  let trigger: Box<dyn Trigger> = match config.rotate {
+    Some(rotation) => Box::new(RotationTrigger::new(config, rotation)),
+    None => Box::new(SizeTrigger::new(config.max_size)),
+  };
+  let policy = CompoundPolicy::new(trigger, Box::new(roller));
+  RollingFileAppender::builder()
+    .encoder(Box::new(PatternEncoder::new(pattern)))
+    .build(&config.path, Box::new(policy))
+}
 
-  let handle = log4rs::init_config(config).expect("Initializing logging should not fail");
-  info!("Logging Level: {}", &level_filter);
-  handle
+/// Rolls the file once it exceeds its size or a new period began since it was
+/// written last, also if that happened before a restart. Like with the size,
+/// log4rs only asks after writing an entry, so the first entry of a new period
+/// still ends up in the rolled file.
+#[derive(Debug)]
+struct RotationTrigger {
+  size: SizeTrigger,
+  rotation: Rotation,
+  /// The period in which the current file was written last.
+  period: Mutex<i64>,
+}
+
+impl RotationTrigger {
+  fn new(config: &RollingFileConfig, rotation: Rotation) -> RotationTrigger {
+    let modified = fs::metadata(&config.path).and_then(|it| it.modified());
+    let period = modified.map(DateTime::from).unwrap_or_else(|_| Local::now());
+    RotationTrigger {
+      size: SizeTrigger::new(config.max_size),
+      rotation,
+      period: Mutex::new(rotation.period(period)),
+    }
+  }
+}
+
+impl Trigger for RotationTrigger {
+  fn trigger(&self, file: &LogFile) -> anyhow::Result<bool> {
+    let now = self.rotation.period(Local::now());
+    let previous = std::mem::replace(&mut *self.period.lock().unwrap(), now);
+    Ok(previous != now || self.size.trigger(file)?)
+  }
+}
+
+/// Writes every `sample_rate`-th access log entry to a rolling file. With a
+/// `buffer_size` the entries are written by a dedicated thread, so a slow disk
+/// does not delay the requests, which are logged. If the buffer is full,
+/// entries are dropped and counted in `arlb_access_log_dropped_total`.
+#[derive(Debug)]
+struct AccessLogAppender {
+  writer: AccessLogWriter,
+  sample_rate: u64,
+  count: AtomicU64,
+  /// JSON entries contain their timestamp already.
+  format: LogFormat,
+}
+
+#[derive(Debug)]
+enum AccessLogWriter {
+  Direct(RollingFileAppender),
+  Buffered(SyncSender<String>),
+}
+
+impl AccessLogAppender {
+  fn new(
+    config: &RollingFileConfig,
+    buffer_size: Option<usize>,
+    sample_rate: Option<u64>,
+    format: LogFormat,
+  ) -> Result<AccessLogAppender, io::Error> {
+    let writer = match buffer_size {
+      Some(buffer_size) => {
+        // The entries are formatted (including their time) before they are buffered
+        let appender = rolling_file_appender(config, "{m}{n}")?;
+        let (sender, receiver) = sync_channel::<String>(buffer_size);
+        thread::Builder::new().name("access-log".into()).spawn(move || {
+          // Ends once the appender is replaced on reconfiguration
+          for entry in receiver {
+            let _ = appender.append(
+              &Record::builder()
+                .args(format_args!("{}", entry))
+                .level(Level::Info)
+                .target(ACCESS_LOG_TARGET)
+                .build(),
+            );
+          }
+        })?;
+        AccessLogWriter::Buffered(sender)
+      }
+      None => {
+        let pattern = match format {
+          LogFormat::Text => ACCESS_LOG_PATTERN,
+          LogFormat::Json => JSON_PATTERN,
+        };
+        AccessLogWriter::Direct(rolling_file_appender(config, pattern)?)
+      }
+    };
+    Ok(AccessLogAppender {
+      writer,
+      sample_rate: sample_rate.unwrap_or(1),
+      count: AtomicU64::new(0),
+      format,
+    })
+  }
+}
+
+impl Log for AccessLogAppender {
+  fn enabled(&self, _metadata: &Metadata) -> bool {
+    true
+  }
+
+  fn log(&self, record: &Record) {
+    if !self
+      .count
+      .fetch_add(1, Ordering::Relaxed)
+      .is_multiple_of(self.sample_rate)
+    {
+      return;
+    }
+    match &self.writer {
+      AccessLogWriter::Direct(appender) => {
+        let _ = appender.append(record);
+      }
+      AccessLogWriter::Buffered(sender) => {
+        let entry = match self.format {
+          LogFormat::Text => format!("{} {}", Local::now().format("%Y-%m-%d %H:%M:%S%.9f"), record.args()),
+          LogFormat::Json => record.args().to_string(),
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(entry) {
+          METRICS.increment("arlb_access_log_dropped_total", &[]);
+        }
+      }
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+/// Sends log entries in the [BSD syslog format](https://tools.ietf.org/html/rfc3164)
+/// via UDP or a unix domain socket.
+#[derive(Debug)]
+struct SyslogAppender {
+  socket: SyslogSocket,
+  hostname: String,
+  /// JSON entries contain their target already.
+  format: LogFormat,
+}
+
+#[derive(Debug)]
+enum SyslogSocket {
+  Udp(UdpSocket, SocketAddr),
+  #[cfg(unix)]
+  Unix(UnixDatagram, String),
+}
+
+/// The facility of system daemons.
+const SYSLOG_FACILITY: u8 = 3;
+
+impl SyslogAppender {
+  fn new(address: &str, format: LogFormat) -> Result<SyslogAppender, io::Error> {
+    let socket = match address.parse::<SocketAddr>() {
+      Ok(address) => {
+        let local_address = match address {
+          SocketAddr::V4(_) => "0.0.0.0:0",
+          SocketAddr::V6(_) => "[::]:0",
+        };
+        SyslogSocket::Udp(UdpSocket::bind(local_address)?, address)
+      }
+      #[cfg(unix)]
+      Err(_) => SyslogSocket::Unix(UnixDatagram::unbound()?, address.to_string()),
+      #[cfg(not(unix))]
+      Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    };
+    Ok(SyslogAppender {
+      socket,
+      hostname: gethostname().to_string_lossy().into_owned(),
+      format,
+    })
+  }
+
+  fn format(&self, record: &Record) -> String {
+    let severity = match record.level() {
+      Level::Error => 3,
+      Level::Warn => 4,
+      Level::Info => 6,
+      Level::Debug | Level::Trace => 7,
+    };
+    let header = format!(
+      "<{}>{} {} arlb[{}]:",
+      SYSLOG_FACILITY * 8 + severity,
+      Local::now().format("%b %e %H:%M:%S"),
+      self.hostname,
+      std::process::id(),
+    );
+    match self.format {
+      LogFormat::Text => format!("{} {} - {}", header, record.target(), record.args()),
+      LogFormat::Json => format!("{} {}", header, record.args()),
+    }
+  }
+}
+
+impl Log for SyslogAppender {
+  fn enabled(&self, _metadata: &Metadata) -> bool {
+    true
+  }
+
+  fn log(&self, record: &Record) {
+    let message = self.format(record);
+    // Logging must never fail, so unavailable syslog servers are ignored
+    let _ = match &self.socket {
+      SyslogSocket::Udp(socket, address) => socket.send_to(message.as_bytes(), address),
+      #[cfg(unix)]
+      SyslogSocket::Unix(socket, path) => socket.send_to(message.as_bytes(), path),
+    };
+  }
+
+  fn flush(&self) {}
 }
 
 fn parse_level_filter(str: &str) -> Option<LevelFilter> {
@@ -36,3 +701,280 @@ fn parse_level_filter(str: &str) -> Option<LevelFilter> {
     _ => None,
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_log_level_endpoint() {
+    // given:
+    let put_level = |level: &'static str| handle_request(Request::put("/log-level").body(Body::from(level)).unwrap());
+
+    // when:
+    let invalid = put_level("loud").await;
+    let unconfigured = put_level("debug,hyper=warn").await;
+
+    // then: the tests leave the logging to the test harness
+    assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(unconfigured.status(), StatusCode::CONFLICT);
+  }
+
+  #[test]
+  fn test_access_log_rolls_over() {
+    // given:
+    let dir = std::env::temp_dir().join(format!("arlb-access-log-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let config = RollingFileConfig {
+      path: dir.join("access.log"),
+      max_size: 100,
+      retention: 2,
+      rotate: None,
+    };
+    let appender = rolling_file_appender(&config, ACCESS_LOG_PATTERN).unwrap();
+
+    // when:
+    for index in 0..20 {
+      appender
+        .append(
+          &Record::builder()
+            .args(format_args!("127.0.0.1 \"GET /{} HTTP/1.1\" 200 1ms", index))
+            .level(Level::Info)
+            .target(ACCESS_LOG_TARGET)
+            .build(),
+        )
+        .unwrap();
+    }
+
+    // then:
+    let files = fs::read_dir(&dir)
+      .unwrap()
+      .map(|it| it.unwrap().file_name().into_string().unwrap())
+      .collect::<Vec<_>>();
+    fs::remove_dir_all(&dir).unwrap();
+    // The current file is only created again by the next entry after rolling
+    assert!(files.contains(&"access.log.0".to_string()));
+    assert!(files.contains(&"access.log.1".to_string()));
+    assert!(!files.contains(&"access.log.2".to_string()));
+  }
+
+  #[test]
+  fn test_file_of_a_previous_period_is_rolled() {
+    // given: a file, which was written to in the previous hour
+    let dir = std::env::temp_dir().join(format!("arlb-rotated-log-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("arlb.log");
+    fs::write(&path, "previous\n").unwrap();
+    let config = RollingFileConfig {
+      path: path.clone(),
+      max_size: 1024 * 1024,
+      retention: 1,
+      rotate: Some(Rotation::Hourly),
+    };
+    let trigger = RotationTrigger::new(&config, Rotation::Hourly);
+    *trigger.period.lock().unwrap() -= 1;
+    let roll_pattern = format!("{}.{{}}", path.display());
+    let roller = FixedWindowRoller::builder().build(&roll_pattern, 1).unwrap();
+    let appender = RollingFileAppender::builder()
+      .encoder(Box::new(PatternEncoder::new("{m}{n}")))
+      .build(
+        &path,
+        Box::new(CompoundPolicy::new(Box::new(trigger), Box::new(roller))),
+      )
+      .unwrap();
+
+    // when:
+    for message in &["current", "also current"] {
+      appender
+        .append(&Record::builder().args(format_args!("{}", message)).build())
+        .unwrap();
+    }
+
+    // then: the file was rolled once, after the first entry of the current hour
+    let rolled = fs::read_to_string(dir.join("arlb.log.0")).unwrap();
+    let current = fs::read_to_string(&path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(rolled, "previous\ncurrent\n");
+    assert_eq!(current, "also current\n");
+  }
+
+  #[test]
+  fn test_rotation_periods_begin_in_local_time() {
+    let midnight = Local::now().date().and_hms(0, 0, 0);
+
+    assert_eq!(
+      Rotation::Daily.period(midnight),
+      Rotation::Daily.period(midnight + chrono::Duration::hours(23))
+    );
+    assert_ne!(
+      Rotation::Daily.period(midnight),
+      Rotation::Daily.period(midnight - chrono::Duration::seconds(1))
+    );
+    assert_ne!(
+      Rotation::Hourly.period(midnight + chrono::Duration::minutes(59)),
+      Rotation::Hourly.period(midnight + chrono::Duration::minutes(60))
+    );
+  }
+
+  #[test]
+  fn test_buffered_access_log_with_sampling() {
+    // given:
+    let dir = std::env::temp_dir().join(format!("arlb-buffered-access-log-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let config = RollingFileConfig {
+      path: dir.join("access.log"),
+      max_size: 1024 * 1024,
+      retention: 1,
+      rotate: None,
+    };
+    let appender = AccessLogAppender::new(&config, Some(100), Some(3), LogFormat::Text).unwrap();
+
+    // when:
+    for _ in 0..9 {
+      Log::log(
+        &appender,
+        &Record::builder()
+          .args(format_args!("127.0.0.1 \"GET / HTTP/1.1\" 200 1ms"))
+          .level(Level::Info)
+          .target(ACCESS_LOG_TARGET)
+          .build(),
+      );
+    }
+
+    // then: the entries are written eventually
+    let mut content = String::new();
+    for _ in 0..100 {
+      content = fs::read_to_string(dir.join("access.log")).unwrap_or_default();
+      if content.lines().count() >= 3 {
+        break;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(content.lines().count(), 3, "{}", content);
+    assert!(content
+      .lines()
+      .all(|it| it.ends_with(" 127.0.0.1 \"GET / HTTP/1.1\" 200 1ms")));
+  }
+
+  #[test]
+  fn test_entries_contain_their_fields() {
+    // given:
+    let capture = Capture::default();
+    let text = Formatted::new(capture.clone(), LogFormat::Text);
+    let json = Formatted::new(capture.clone(), LogFormat::Json);
+    let log = |logger: &Formatted, message: &str| {
+      logger.log(
+        &Record::builder()
+          .args(format_args!("{}", message))
+          .level(Level::Warn)
+          .target("another_rust_load_balancer::health")
+          .build(),
+      )
+    };
+
+    // when:
+    log(&text, "Without fields");
+    with_fields(&[("pool", &"api"), ("backend", &"127.0.0.1:8080")], || {
+      log(&text, "New healthiness: Unresponsive");
+      log(&json, "New healthiness: Unresponsive\nsince 3 checks");
+    });
+
+    // then:
+    let entries = capture.0.lock().unwrap();
+    assert_eq!(entries[0], "Without fields");
+    assert_eq!(
+      entries[1],
+      "New healthiness: Unresponsive (pool: api, backend: 127.0.0.1:8080)"
+    );
+    assert!(!entries[2].contains('\n'), "{}", entries[2]);
+    let entry: Value = serde_json::from_str(&entries[2]).unwrap();
+    assert_eq!(entry["level"], "WARN");
+    assert_eq!(entry["target"], "another_rust_load_balancer::health");
+    assert_eq!(entry["message"], "New healthiness: Unresponsive\nsince 3 checks");
+    assert_eq!(entry["pool"], "api");
+    assert_eq!(entry["backend"], "127.0.0.1:8080");
+    assert!(entry["timestamp"].is_string());
+  }
+
+  #[test]
+  fn test_parse_directives() {
+    let directives = parse_directives("warn, hyper=info,another_rust_load_balancer::server=TRACE").unwrap();
+
+    assert_eq!(
+      directives,
+      Directives {
+        root: LevelFilter::Warn,
+        targets: vec![
+          ("hyper".into(), LevelFilter::Info),
+          ("another_rust_load_balancer::server".into(), LevelFilter::Trace),
+        ],
+      }
+    );
+  }
+
+  #[test]
+  fn test_parse_directives_without_root_level() {
+    let directives = parse_directives("hyper=debug").unwrap();
+
+    assert_eq!(directives.root, LevelFilter::Info);
+    assert_eq!(directives.targets, vec![("hyper".into(), LevelFilter::Debug)]);
+  }
+
+  #[test]
+  fn test_parse_invalid_directives() {
+    assert_eq!(parse_directives("verbose"), None);
+    assert_eq!(parse_directives("info,hyper=loud"), None);
+  }
+
+  #[test]
+  fn test_set_level_rejects_invalid_levels() {
+    let invalid = set_level(Some("info,hyper=loud"));
+
+    assert_eq!(invalid.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(*LEVEL_OVERRIDE.lock().unwrap(), None);
+  }
+
+  #[test]
+  fn test_set_level_requires_logging_configured_by_the_load_balancer() {
+    // given: the tests leave the logging to the test harness
+
+    // when:
+    let result = set_level(Some("debug"));
+
+    // then:
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Unsupported);
+    assert_eq!(*LEVEL_OVERRIDE.lock().unwrap(), None);
+  }
+
+  #[test]
+  fn test_syslog_appender_sends_via_udp() {
+    // given:
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let appender = SyslogAppender::new(&server.local_addr().unwrap().to_string(), LogFormat::Text).unwrap();
+
+    // when:
+    Log::log(
+      &appender,
+      &Record::builder()
+        .args(format_args!("Could not reload configuration"))
+        .level(Level::Warn)
+        .target("another_rust_load_balancer::configuration")
+        .build(),
+    );
+
+    // then: daemon facility (3) and warning severity (4)
+    let mut buffer = [0; 1024];
+    let read = server.recv(&mut buffer).unwrap();
+    let message = String::from_utf8_lossy(&buffer[..read]);
+    assert!(message.starts_with("<28>"), "{}", message);
+    assert!(
+      message.ends_with("]: another_rust_load_balancer::configuration - Could not reload configuration"),
+      "{}",
+      message
+    );
+  }
+}
@@ -1,29 +1,12 @@
-use arc_swap::{access::Map, ArcSwap};
-use clap::{App, Arg};
-use configuration::{read_initial_config, watch_config, RuntimeConfig};
-use listeners::{AcceptorProducer, Https};
-use server::Scheme;
-use std::{io, sync::Arc};
-use tls::ReconfigurableCertificateResolver;
-use tokio::try_join;
-use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+mod bench;
 
-mod acme;
-mod backend_pool_matcher;
-mod configuration;
-mod error_response;
-mod health;
-mod http_client;
-mod listeners;
-mod load_balancing;
-mod logging;
-mod middleware;
-mod server;
-mod tls;
-mod utils;
+use another_rust_load_balancer::{check_config, initialize_logging, Error, LoadBalancer};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use log::{error, info};
+use std::process;
+use tokio::runtime;
 
-#[tokio::main]
-pub async fn main() -> Result<(), io::Error> {
+fn main() -> Result<(), Error> {
   let matches = App::new("Another Rust Load Balancer")
     .version("1.0")
     .about("It's basically just another rust load balancer")
@@ -36,45 +19,116 @@ pub async fn main() -> Result<(), io::Error> {
         .required(true)
         .takes_value(true),
     )
+    .arg(
+      Arg::with_name("check-config")
+        .long("check-config")
+        .help("Validates the configuration and exits without listening for requests."),
+    )
+    .arg(
+      Arg::with_name("worker-threads")
+        .long("worker-threads")
+        .value_name("COUNT")
+        .help("The number of threads, which handle requests. Defaults to the number of CPU cores.")
+        .takes_value(true)
+        .validator(positive_number),
+    )
+    .arg(
+      Arg::with_name("current-thread")
+        .long("current-thread")
+        .help("Handles all requests on the main thread, which suits small deployments.")
+        .conflicts_with("worker-threads"),
+    )
+    .setting(AppSettings::SubcommandsNegateReqs)
+    .subcommand(
+      SubCommand::with_name("bench")
+        .about("Measures the load balancer with an in-process backend server and clients.")
+        .arg(
+          Arg::with_name("mode")
+            .long("mode")
+            .help("Either HTTP requests or streaming through a TCP router.")
+            .possible_values(&["requests", "stream"])
+            .default_value("requests"),
+        )
+        .arg(
+          Arg::with_name("connections")
+            .long("connections")
+            .value_name("COUNT")
+            .help("The number of concurrent client connections.")
+            .default_value("16")
+            .validator(positive_number),
+        )
+        .arg(
+          Arg::with_name("payload-size")
+            .long("payload-size")
+            .value_name("BYTES")
+            .help("The size of each response or, when streaming, of each write.")
+            .default_value("1024")
+            .validator(positive_number),
+        )
+        .arg(
+          Arg::with_name("duration")
+            .long("duration")
+            .value_name("SECONDS")
+            .help("How long the clients send requests.")
+            .default_value("10")
+            .validator(positive_number),
+        )
+        .arg(Arg::with_name("tls").long("tls").help("Clients connect via TLS.")),
+    )
     .get_matches();
-  let config_path = matches.value_of("config").unwrap().to_string();
-
-  logging::initialize();
 
-  let config = read_initial_config(&config_path).await?;
-  try_join!(
-    watch_config(config_path, config.clone()),
-    watch_health(config.clone()),
-    listen_for_http_request(config.clone()),
-    listen_for_https_request(config.clone())
-  )?;
-  Ok(())
+  let mut runtime = if matches.is_present("current-thread") {
+    runtime::Builder::new_current_thread()
+  } else {
+    runtime::Builder::new_multi_thread()
+  };
+  if let Some(worker_threads) = matches.value_of("worker-threads") {
+    runtime.worker_threads(worker_threads.parse().unwrap());
+  }
+  runtime.enable_all().build()?.block_on(run(matches))
 }
 
-async fn watch_health(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
-  let backend_pools = Map::new(config.clone(), |it: &RuntimeConfig| &it.shared_data.backend_pools);
-  let health_interval = Map::new(config, |it: &RuntimeConfig| &it.health_interval);
-  health::watch_health(backend_pools, health_interval).await;
-  Ok(())
+fn positive_number(value: String) -> Result<(), String> {
+  match value.parse::<usize>() {
+    Ok(number) if number > 0 => Ok(()),
+    _ => Err("The value must be a positive number".to_string()),
+  }
 }
 
-async fn listen_for_http_request(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
-  let http = listeners::Http;
-  let address = config.load().http_address;
-  let acceptor = http.produce_acceptor(address).await?;
+async fn run(matches: ArgMatches<'_>) -> Result<(), Error> {
+  // Benchmarks don't log, so the access log does not distort them
+  if let Some(matches) = matches.subcommand_matches("bench") {
+    let options = bench::BenchOptions {
+      mode: match matches.value_of("mode") {
+        Some("stream") => bench::BenchMode::Stream,
+        _ => bench::BenchMode::Requests,
+      },
+      connections: matches.value_of("connections").unwrap().parse().unwrap(),
+      payload_size: matches.value_of("payload-size").unwrap().parse().unwrap(),
+      duration: std::time::Duration::from_secs(matches.value_of("duration").unwrap().parse().unwrap()),
+      tls: matches.is_present("tls"),
+    };
+    println!("{}", bench::run(&options).await?);
+    return Ok(());
+  }
 
-  server::create(acceptor, config, Scheme::HTTP).await
-}
+  let config_path = matches.value_of("config").unwrap().to_string();
 
-async fn listen_for_https_request(config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), io::Error> {
-  let mut tls_config = ServerConfig::new(NoClientAuth::new());
-  let certificates = Map::new(config.clone(), |it: &RuntimeConfig| &it.certificates);
-  let cert_resolver = ReconfigurableCertificateResolver::new(certificates);
-  tls_config.cert_resolver = Arc::new(cert_resolver);
+  let logging = initialize_logging();
 
-  let https = Https { tls_config };
-  let address = config.load().https_address;
-  let acceptor = https.produce_acceptor(address).await?;
+  if matches.is_present("check-config") {
+    return match check_config(&config_path).await {
+      Ok(summary) => {
+        info!("The configuration '{}' is valid: {}", config_path, summary);
+        Ok(())
+      }
+      Err(e) => {
+        error!("{}", e);
+        process::exit(1);
+      }
+    };
+  }
 
-  server::create(acceptor, config, Scheme::HTTPS).await
+  let load_balancer = LoadBalancer::new(&config_path).await?.with_logging(logging)?;
+  load_balancer.run().await
 }
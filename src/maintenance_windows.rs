@@ -0,0 +1,402 @@
+use crate::{
+  backend_drains::{self, DrainMode},
+  configuration::RuntimeConfig,
+};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveDate, Timelike, Utc};
+use log::info;
+use once_cell::sync::Lazy;
+use std::{
+  collections::{HashMap, HashSet},
+  str::FromStr,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+/// How long the scheduler sleeps at most, so changed maintenance windows are
+/// picked up within this time after a reload.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Cron expressions match at least once within this many days (like the 29th
+/// of February), or never.
+const MAX_DAYS: i64 = 8 * 366;
+
+const WEEKDAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+const MONTHS: [&str; 12] = [
+  "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+];
+
+/// A cron expression with the five fields minute, hour, day of month, month
+/// and day of week, like `0 2 * * SUN`. Each field is `*`, a number, a range
+/// like `1-5` or a list like `1,15`, optionally with a step like `*/15`.
+/// Months and days of week can be named like `JAN` and `MON`. Like in cron, a
+/// day matches if either the day of month or the day of week matches, if both
+/// are restricted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  any_day: bool,
+  any_weekday: bool,
+}
+
+impl Cron {
+  fn matches_date(&self, date: NaiveDate) -> bool {
+    let day = bit(self.days, date.day());
+    let weekday = bit(self.weekdays, date.weekday().num_days_from_sunday());
+    let day_matches = match (self.any_day, self.any_weekday) {
+      (false, false) => day || weekday,
+      _ => day && weekday,
+    };
+    bit(self.months, date.month()) && day_matches
+  }
+
+  /// The first minute matching the expression after `after`.
+  pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let start = after.with_second(0)?.with_nanosecond(0)? + ChronoDuration::minutes(1);
+    let mut date = start.date().naive_utc();
+    for _ in 0..MAX_DAYS {
+      if self.matches_date(date) {
+        for hour in (0..24).filter(|it| bit(self.hours, *it)) {
+          for minute in (0..60).filter(|it| bit(self.minutes, *it)) {
+            let candidate = DateTime::<Utc>::from_utc(date.and_hms(hour, minute, 0), Utc);
+            if candidate >= start {
+              return Some(candidate);
+            }
+          }
+        }
+      }
+      date = date.succ_opt()?;
+    }
+    None
+  }
+}
+
+fn bit(field: u64, value: u32) -> bool {
+  field & (1 << value) != 0
+}
+
+/// Parses one field of a cron expression into a bit set of its values.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str], name_offset: u32) -> Result<u64, String> {
+  let value = |it: &str| -> Result<u32, String> {
+    let value = match names.iter().position(|name| name.eq_ignore_ascii_case(it)) {
+      Some(index) => index as u32 + name_offset,
+      None => it.parse().map_err(|_| format!("Invalid value '{}'", it))?,
+    };
+    if value < min || value > max {
+      return Err(format!("The value {} is not between {} and {}", value, min, max));
+    }
+    Ok(value)
+  };
+  let mut values = 0;
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => {
+        let step = step
+          .parse::<u32>()
+          .ok()
+          .filter(|it| *it > 0)
+          .ok_or_else(|| format!("Invalid step '{}'", step))?;
+        (range, Some(step))
+      }
+      None => (part, None),
+    };
+    let (first, last) = match range.split_once('-') {
+      _ if range == "*" => (min, max),
+      Some((first, last)) => (value(first)?, value(last)?),
+      // Like `5/10`, which starts at 5
+      None if step.is_some() => (value(range)?, max),
+      None => {
+        let value = value(range)?;
+        (value, value)
+      }
+    };
+    if first > last {
+      return Err(format!("Invalid range '{}'", range));
+    }
+    for value in (first..=last).step_by(step.unwrap_or(1) as usize) {
+      values |= 1 << value;
+    }
+  }
+  Ok(values)
+}
+
+impl FromStr for Cron {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let fields = s.split_whitespace().collect::<Vec<_>>();
+    if fields.len() != 5 {
+      return Err(format!(
+        "Invalid cron expression '{}', expected the five fields minute, hour, day of month, month and day of week",
+        s
+      ));
+    }
+    let invalid = |e: String| format!("Invalid cron expression '{}': {}", s, e);
+    let weekdays = parse_field(fields[4], 0, 7, &WEEKDAYS, 0).map_err(invalid)?;
+    Ok(Cron {
+      minutes: parse_field(fields[0], 0, 59, &[], 0).map_err(invalid)?,
+      hours: parse_field(fields[1], 0, 23, &[], 0).map_err(invalid)?,
+      days: parse_field(fields[2], 1, 31, &[], 0).map_err(invalid)?,
+      months: parse_field(fields[3], 1, 12, &MONTHS, 1).map_err(invalid)?,
+      // Both 0 and 7 are Sunday
+      weekdays: (weekdays | weekdays >> 7) & 0x7f,
+      any_day: fields[2].starts_with('*'),
+      any_weekday: fields[4].starts_with('*'),
+    })
+  }
+}
+
+/// When a maintenance window starts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+  At(DateTime<Utc>),
+  Cron(Cron),
+}
+
+impl Schedule {
+  /// The first start after `after`.
+  pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match self {
+      Schedule::At(at) => Some(*at).filter(|it| *it > after),
+      Schedule::Cron(cron) => cron.next_after(after),
+    }
+  }
+}
+
+/// Drains a backend server of a pool while the window is active, like for
+/// planned maintenance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+  pub address: String,
+  pub schedule: Schedule,
+  /// How long the backend server is drained after each start. Without it the
+  /// backend server stays drained.
+  pub duration: Option<Duration>,
+  pub mode: DrainMode,
+  /// How long a soft drain lasts, before it turns into a hard drain.
+  pub max_drain: Duration,
+}
+
+impl MaintenanceWindow {
+  /// When the window, which is active at `now`, started.
+  fn active_since(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    match self.duration {
+      Some(duration) => {
+        let duration = ChronoDuration::from_std(duration).ok()?;
+        self.schedule.next_after(now - duration).filter(|it| *it <= now)
+      }
+      None => match self.schedule {
+        Schedule::At(at) if at <= now => Some(at),
+        _ => None,
+      },
+    }
+  }
+
+  /// When the window starts or ends next.
+  fn next_transition(&self, now: DateTime<Utc>) -> Option<Transition> {
+    match self.active_since(now) {
+      Some(start) => {
+        let end = start + ChronoDuration::from_std(self.duration?).ok()?;
+        Some(Transition::End(end))
+      }
+      None => self.schedule.next_after(now).map(Transition::Start),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transition {
+  Start(DateTime<Utc>),
+  End(DateTime<Utc>),
+}
+
+impl Transition {
+  fn time(self) -> DateTime<Utc> {
+    match self {
+      Transition::Start(time) | Transition::End(time) => time,
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct Scheduler {
+  /// The backend servers drained by their maintenance window, so drains
+  /// through the admin API are left alone.
+  drained: HashSet<(String, String)>,
+  /// The next transition of each backend server, which was logged last.
+  announced: HashMap<(String, String), Transition>,
+}
+
+static SCHEDULER: Lazy<Mutex<Scheduler>> = Lazy::new(Default::default);
+
+/// Drains and restores the backend servers according to the maintenance
+/// windows of their pools. Returns when the next transition is due.
+fn apply(config: &RuntimeConfig, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+  let mut scheduler = SCHEDULER.lock().unwrap();
+  let mut drained = HashSet::new();
+  let mut announced = HashMap::new();
+  let mut next: Option<DateTime<Utc>> = None;
+  for pool in &config.shared_data.backend_pools {
+    for window in &pool.maintenance_windows {
+      let key = (pool.name.clone(), window.address.clone());
+      if window.active_since(now).is_some() && drained.insert(key.clone()) && !scheduler.drained.contains(&key) {
+        info!(
+          "The maintenance window of backend server {} of pool {} started",
+          window.address, pool.name
+        );
+        backend_drains::start(&pool.name, &window.address, window.mode, window.max_drain);
+      }
+      if let Some(transition) = window.next_transition(now) {
+        next = Some(next.map_or(transition.time(), |it| it.min(transition.time())));
+        // Only the first window of a backend server is announced
+        if announced.contains_key(&key) {
+          continue;
+        }
+        if scheduler.announced.get(&key) != Some(&transition) {
+          match transition {
+            Transition::Start(time) => info!(
+              "Backend server {} of pool {} will be drained for maintenance at {}",
+              window.address,
+              pool.name,
+              time.to_rfc3339()
+            ),
+            Transition::End(time) => info!(
+              "Backend server {} of pool {} will be restored after maintenance at {}",
+              window.address,
+              pool.name,
+              time.to_rfc3339()
+            ),
+          }
+        }
+        announced.insert(key, transition);
+      }
+    }
+  }
+  for (pool, address) in scheduler.drained.difference(&drained) {
+    info!(
+      "The maintenance window of backend server {} of pool {} ended",
+      address, pool
+    );
+    backend_drains::stop(pool, address);
+  }
+  scheduler.drained = drained;
+  scheduler.announced = announced;
+  next
+}
+
+/// Applies the maintenance windows of the backend pools until the process
+/// stops.
+pub async fn schedule(config: Arc<ArcSwap<RuntimeConfig>>) {
+  loop {
+    let now = Utc::now();
+    let wait = match apply(&config.load(), now) {
+      Some(next) => (next - now).to_std().unwrap_or_default().min(MAX_WAIT),
+      None => MAX_WAIT,
+    };
+    tokio::time::sleep(wait).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn time(rfc3339: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(rfc3339).unwrap().with_timezone(&Utc)
+  }
+
+  fn next_after(cron: &str, after: &str) -> Option<String> {
+    let cron = cron.parse::<Cron>().unwrap();
+    cron.next_after(time(after)).map(|it| it.to_rfc3339())
+  }
+
+  #[test]
+  fn test_cron_next_after() {
+    // 2026-10-14 is a Wednesday
+    assert_eq!(
+      next_after("0 2 * * SUN", "2026-10-14T12:00:00Z").as_deref(),
+      Some("2026-10-18T02:00:00+00:00")
+    );
+    assert_eq!(
+      next_after("*/15 * * * *", "2026-10-14T12:07:30Z").as_deref(),
+      Some("2026-10-14T12:15:00+00:00")
+    );
+    assert_eq!(
+      next_after("30 1-3 * * *", "2026-10-14T01:30:00Z").as_deref(),
+      Some("2026-10-14T02:30:00+00:00")
+    );
+    assert_eq!(
+      next_after("0 0 29 FEB *", "2026-10-14T00:00:00Z").as_deref(),
+      Some("2028-02-29T00:00:00+00:00")
+    );
+    // Either the 1st or a Monday
+    assert_eq!(
+      next_after("0 0 1 * 1", "2026-10-14T00:00:00Z").as_deref(),
+      Some("2026-10-19T00:00:00+00:00")
+    );
+    assert_eq!(
+      next_after("0 0 * * 7", "2026-10-14T00:00:00Z").as_deref(),
+      Some("2026-10-18T00:00:00+00:00")
+    );
+    assert_eq!(next_after("0 0 31 2 *", "2026-10-14T00:00:00Z"), None);
+  }
+
+  #[test]
+  fn test_invalid_cron_expressions() {
+    for invalid in &[
+      "* * * *",
+      "60 * * * *",
+      "* * 0 * *",
+      "*/0 * * * *",
+      "5-1 * * * *",
+      "* * * * FUNDAY",
+    ] {
+      assert!(invalid.parse::<Cron>().is_err(), "{}", invalid);
+    }
+  }
+
+  #[test]
+  fn test_window_transitions() {
+    // given: a weekly window of an hour
+    let window = MaintenanceWindow {
+      address: "127.0.0.1:8080".into(),
+      schedule: Schedule::Cron("0 2 * * SUN".parse().unwrap()),
+      duration: Some(Duration::from_secs(3600)),
+      mode: DrainMode::Soft,
+      max_drain: backend_drains::DEFAULT_MAX_DRAIN,
+    };
+    let once = MaintenanceWindow {
+      schedule: Schedule::At(time("2026-10-18T02:00:00Z")),
+      duration: None,
+      ..window.clone()
+    };
+
+    // then:
+    let before = time("2026-10-18T01:59:59Z");
+    let during = time("2026-10-18T02:30:00Z");
+    let after = time("2026-10-18T03:00:00Z");
+    assert_eq!(window.active_since(before), None);
+    assert_eq!(
+      window.next_transition(before),
+      Some(Transition::Start(time("2026-10-18T02:00:00Z")))
+    );
+    assert_eq!(window.active_since(during), Some(time("2026-10-18T02:00:00Z")));
+    assert_eq!(
+      window.next_transition(during),
+      Some(Transition::End(time("2026-10-18T03:00:00Z")))
+    );
+    assert_eq!(window.active_since(after), None);
+    assert_eq!(
+      window.next_transition(after),
+      Some(Transition::Start(time("2026-10-25T02:00:00Z")))
+    );
+    assert_eq!(once.active_since(before), None);
+    assert_eq!(once.active_since(after), Some(time("2026-10-18T02:00:00Z")));
+    assert_eq!(once.next_transition(after), None);
+  }
+}
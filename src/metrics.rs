@@ -0,0 +1,847 @@
+use crate::{
+  bans,
+  configuration::{self, RuntimeConfig},
+  connections, drain, dynamic_backends,
+  error::Error,
+  fault_injection, flow_sampling,
+  health::{self, Healthiness},
+  logging,
+  middleware::{cache, local_authentication::constant_time_eq},
+  readiness::{self, READINESS},
+  top_talkers,
+};
+use arc_swap::ArcSwap;
+use hyper::{
+  header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+  service::{make_service_fn, service_fn},
+  Body, Method, Request, Response, Server, StatusCode,
+};
+use log::info;
+use once_cell::sync::{Lazy, OnceCell};
+use serde_json::json;
+use std::{
+  collections::BTreeMap,
+  convert::Infallible,
+  fmt::Write,
+  io,
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+  },
+  time::Instant,
+};
+
+/// The metrics of this process, which are served in the Prometheus text format
+/// by [`serve`].
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// When this process started serving the metrics, which is shortly after it
+/// was started.
+static STARTED_AT: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// The default upper bounds (in seconds) of the buckets of all histograms.
+pub const DEFAULT_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct MetricKey {
+  name: &'static str,
+  labels: Vec<(&'static str, String)>,
+}
+
+impl MetricKey {
+  fn new(name: &'static str, labels: &[(&'static str, &str)]) -> MetricKey {
+    MetricKey {
+      name,
+      labels: labels.iter().map(|(key, value)| (*key, value.to_string())).collect(),
+    }
+  }
+}
+
+/// A histogram, which is updated without locking, because observations are
+/// recorded while handling requests.
+#[derive(Debug)]
+struct Histogram {
+  /// The upper bounds of the buckets.
+  bounds: Arc<[f64]>,
+  /// The number of observations per bucket (not cumulative), the last one
+  /// counts those above all bounds.
+  buckets: Vec<AtomicU64>,
+  /// The bits of the `f64` sum of all observations.
+  sum: AtomicU64,
+}
+
+impl Histogram {
+  fn new(bounds: Arc<[f64]>) -> Histogram {
+    Histogram {
+      buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+      bounds,
+      sum: AtomicU64::new(0f64.to_bits()),
+    }
+  }
+
+  fn observe(&self, value: f64) {
+    let index = self
+      .bounds
+      .iter()
+      .position(|bound| value <= *bound)
+      .unwrap_or(self.bounds.len());
+    self.buckets[index].fetch_add(1, Ordering::Relaxed);
+    let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+      Some((f64::from_bits(sum) + value).to_bits())
+    });
+  }
+
+  /// The cumulative count of each bucket, the last one is the total count.
+  fn cumulative_counts(&self) -> Vec<u64> {
+    self
+      .buckets
+      .iter()
+      .scan(0, |cumulative, count| {
+        *cumulative += count.load(Ordering::Relaxed);
+        Some(*cumulative)
+      })
+      .collect()
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+  counters: Mutex<BTreeMap<MetricKey, u64>>,
+  gauges: Mutex<BTreeMap<MetricKey, i64>>,
+  histograms: RwLock<BTreeMap<MetricKey, Arc<Histogram>>>,
+  buckets: OnceCell<Arc<[f64]>>,
+}
+
+impl Metrics {
+  pub fn increment(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+    self.add(name, labels, 1);
+  }
+
+  pub fn add(&self, name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+    let mut counters = self.counters.lock().unwrap();
+    *counters.entry(MetricKey::new(name, labels)).or_insert(0) += value;
+  }
+
+  /// Adds `delta` to a gauge, whose value can go up and down.
+  pub fn add_gauge(&self, name: &'static str, labels: &[(&'static str, &str)], delta: i64) {
+    let mut gauges = self.gauges.lock().unwrap();
+    *gauges.entry(MetricKey::new(name, labels)).or_insert(0) += delta;
+  }
+
+  /// Sets a gauge to `value`.
+  pub fn set_gauge(&self, name: &'static str, labels: &[(&'static str, &str)], value: i64) {
+    let mut gauges = self.gauges.lock().unwrap();
+    gauges.insert(MetricKey::new(name, labels), value);
+  }
+
+  /// Sets the upper bounds of the buckets of all histograms. This only takes
+  /// effect before the first observation, so all histograms use the same
+  /// buckets.
+  pub fn set_buckets(&self, buckets: &[f64]) {
+    let _ = self.buckets.set(buckets.into());
+  }
+
+  fn buckets(&self) -> Arc<[f64]> {
+    self.buckets.get_or_init(|| DEFAULT_BUCKETS[..].into()).clone()
+  }
+
+  /// Records a `value` (usually a duration in seconds) in a histogram.
+  pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+    self.observe_in(name, labels, value, || self.buckets());
+  }
+
+  /// Records a `value` in a histogram with its own `buckets`, for values far
+  /// below or above the buckets of the other histograms.
+  pub fn observe_with_buckets(
+    &self,
+    name: &'static str,
+    labels: &[(&'static str, &str)],
+    value: f64,
+    buckets: &'static [f64],
+  ) {
+    self.observe_in(name, labels, value, || buckets.into());
+  }
+
+  fn observe_in<B>(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64, buckets: B)
+  where
+    B: FnOnce() -> Arc<[f64]>,
+  {
+    let key = MetricKey::new(name, labels);
+    let histogram = self.histograms.read().unwrap().get(&key).cloned();
+    let histogram = match histogram {
+      Some(histogram) => histogram,
+      None => {
+        let mut histograms = self.histograms.write().unwrap();
+        let histogram = histograms
+          .entry(key)
+          .or_insert_with(|| Arc::new(Histogram::new(buckets())));
+        histogram.clone()
+      }
+    };
+    histogram.observe(value);
+  }
+
+  #[cfg(test)]
+  pub fn counter(&self, name: &'static str, labels: &[(&'static str, &str)]) -> u64 {
+    let counters = self.counters.lock().unwrap();
+    counters.get(&MetricKey::new(name, labels)).cloned().unwrap_or(0)
+  }
+
+  #[cfg(test)]
+  pub fn gauge(&self, name: &'static str, labels: &[(&'static str, &str)]) -> i64 {
+    let gauges = self.gauges.lock().unwrap();
+    gauges.get(&MetricKey::new(name, labels)).cloned().unwrap_or(0)
+  }
+
+  #[cfg(test)]
+  pub fn histogram_count(&self, name: &'static str, labels: &[(&'static str, &str)]) -> u64 {
+    let histograms = self.histograms.read().unwrap();
+    histograms
+      .get(&MetricKey::new(name, labels))
+      .and_then(|it| it.cumulative_counts().last().copied())
+      .unwrap_or(0)
+  }
+
+  /// Renders all metrics in the Prometheus text exposition format.
+  pub fn render(&self) -> String {
+    let mut result = String::new();
+    let mut last_name = "";
+    for (key, value) in self.counters.lock().unwrap().iter() {
+      if key.name != last_name {
+        writeln!(result, "# TYPE {} counter", key.name).unwrap();
+        last_name = key.name;
+      }
+      writeln!(result, "{}{} {}", key.name, format_labels(&key.labels, None), value).unwrap();
+    }
+    for (key, value) in self.gauges.lock().unwrap().iter() {
+      if key.name != last_name {
+        writeln!(result, "# TYPE {} gauge", key.name).unwrap();
+        last_name = key.name;
+      }
+      writeln!(result, "{}{} {}", key.name, format_labels(&key.labels, None), value).unwrap();
+    }
+    for (key, histogram) in self.histograms.read().unwrap().iter() {
+      if key.name != last_name {
+        writeln!(result, "# TYPE {} histogram", key.name).unwrap();
+        last_name = key.name;
+      }
+      let counts = histogram.cumulative_counts();
+      for (bound, cumulative) in histogram.bounds.iter().zip(counts.iter()) {
+        let labels = format_labels(&key.labels, Some(&bound.to_string()));
+        writeln!(result, "{}_bucket{} {}", key.name, labels, cumulative).unwrap();
+      }
+      // Derived from the buckets, so it matches them even during concurrent observations
+      let count = counts.last().copied().unwrap_or(0);
+      let labels = format_labels(&key.labels, Some("+Inf"));
+      writeln!(result, "{}_bucket{} {}", key.name, labels, count).unwrap();
+      let labels = format_labels(&key.labels, None);
+      let sum = f64::from_bits(histogram.sum.load(Ordering::Relaxed));
+      writeln!(result, "{}_sum{} {}", key.name, labels, sum).unwrap();
+      writeln!(result, "{}_count{} {}", key.name, labels, count).unwrap();
+    }
+    result
+  }
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+  let mut formatted = labels
+    .iter()
+    .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+    .collect::<Vec<_>>();
+  if let Some(le) = le {
+    formatted.push(format!("le=\"{}\"", le));
+  }
+  if formatted.is_empty() {
+    String::new()
+  } else {
+    format!("{{{}}}", formatted.join(","))
+  }
+}
+
+fn escape_label_value(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the metrics on `GET /metrics` and the operational endpoints (like
+/// `GET /health`) on their own listener, so they are not exposed on the ports
+/// of the proxied traffic.
+pub async fn serve(address: SocketAddr, config: Arc<ArcSwap<RuntimeConfig>>) -> Result<(), Error> {
+  Lazy::force(&STARTED_AT);
+  let service = make_service_fn(move |_| {
+    let config = config.clone();
+    async move { Ok::<_, Infallible>(service_fn(move |request| handle_request(request, config.clone()))) }
+  });
+  let server = Server::try_bind(&address)
+    .map_err(|e| Error::listen(address, io::Error::new(io::ErrorKind::AddrInUse, e)))?
+    .serve(service);
+  info!("Started listening for metrics requests on {}", server.local_addr());
+  READINESS.bound("metrics_address", Some(server.local_addr()));
+  server.await?;
+  Ok(())
+}
+
+pub(crate) async fn handle_request(
+  request: Request<Body>,
+  config: Arc<ArcSwap<RuntimeConfig>>,
+) -> Result<Response<Body>, Infallible> {
+  let response = match (request.method(), request.uri().path()) {
+    (&Method::GET, "/metrics") => Response::builder()
+      .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+      .body(Body::from(METRICS.render()))
+      .unwrap(),
+    (&Method::GET, "/health") | (&Method::GET, "/live") => Response::new(Body::from("OK\n")),
+    (&Method::GET, "/ready") => match readiness::check(&config.load()) {
+      Ok(()) => Response::new(Body::from("OK\n")),
+      Err(reason) => Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(Body::from(format!("{}\n", reason)))
+        .unwrap(),
+    },
+    (&Method::GET, "/status") => {
+      let config = config.load();
+      check_status_token(&request, &config).unwrap_or_else(|| {
+        Response::builder()
+          .header(CONTENT_TYPE, "application/json")
+          .body(Body::from(status(&config).to_string()))
+          .unwrap()
+      })
+    }
+    (&Method::GET, "/config") => {
+      let config = config.load();
+      check_status_token(&request, &config).unwrap_or_else(|| {
+        Response::builder()
+          .header(CONTENT_TYPE, "application/json")
+          .body(Body::from(effective_config(&config).to_string()))
+          .unwrap()
+      })
+    }
+    (&Method::PUT, "/log-level") | (&Method::DELETE, "/log-level") => {
+      match check_status_token(&request, &config.load()) {
+        Some(response) => response,
+        None => logging::handle_request(request).await,
+      }
+    }
+    (&Method::POST, "/cache/purge") => {
+      let query = request.uri().query().unwrap_or_default();
+      let mut host = None;
+      let mut path_prefix = String::from("/");
+      for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+          "host" => host = Some(value.into_owned()),
+          "path_prefix" => path_prefix = value.into_owned(),
+          _ => {}
+        }
+      }
+      let purged = cache::purge(host.as_deref(), &path_prefix);
+      info!("Purged {} cached responses", purged);
+      Response::new(Body::from(format!("{}\n", purged)))
+    }
+    (&Method::PUT, "/maintenance") | (&Method::DELETE, "/maintenance") => {
+      let config = config.load();
+      check_status_token(&request, &config).unwrap_or_else(|| {
+        let enabled = request.method() == Method::PUT;
+        config.maintenance.store(enabled, Ordering::Relaxed);
+        if enabled {
+          info!("Enabled maintenance mode");
+        } else {
+          info!("Disabled maintenance mode");
+        }
+        Response::builder()
+          .status(StatusCode::NO_CONTENT)
+          .body(Body::empty())
+          .unwrap()
+      })
+    }
+    (_, "/top-talkers") => {
+      check_status_token(&request, &config.load()).unwrap_or_else(|| top_talkers::handle_request(&request))
+    }
+    (_, path) if path == "/bans" || path.starts_with("/bans/") => {
+      check_status_token(&request, &config.load()).unwrap_or_else(|| bans::handle_request(&request))
+    }
+    (_, path) if path == "/connections" || path.starts_with("/connections/") => {
+      check_status_token(&request, &config.load()).unwrap_or_else(|| connections::handle_request(&request))
+    }
+    (&Method::GET, "/tenants") => {
+      let config = config.load();
+      check_status_token(&request, &config).unwrap_or_else(|| {
+        let mut tenants = config.tenants.values().map(|it| it.describe()).collect::<Vec<_>>();
+        tenants.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+        Response::builder()
+          .header(CONTENT_TYPE, "application/json")
+          .body(Body::from(json!(tenants).to_string()))
+          .unwrap()
+      })
+    }
+    (&Method::POST, "/reload") => match check_status_token(&request, &config.load()) {
+      Some(response) => response,
+      None => match configuration::request_reload().await {
+        Ok(()) => Response::builder()
+          .status(StatusCode::NO_CONTENT)
+          .body(Body::empty())
+          .unwrap(),
+        Err(e) => Response::builder()
+          .status(StatusCode::UNPROCESSABLE_ENTITY)
+          .body(Body::from(format!("Could not reload configuration due to: {}\n", e)))
+          .unwrap(),
+      },
+    },
+    (_, "/flow_sampling") => {
+      check_status_token(&request, &config.load()).unwrap_or_else(|| flow_sampling::handle_request(&request))
+    }
+    (_, "/fault_injection") => {
+      check_status_token(&request, &config.load()).unwrap_or_else(|| fault_injection::handle_request(&request))
+    }
+    (_, path) if path.starts_with("/pools/") => {
+      let denied = check_status_token(&request, &config.load());
+      match denied {
+        Some(response) => response,
+        None => dynamic_backends::handle_request(request, &config).await,
+      }
+    }
+    _ => Response::builder()
+      .status(StatusCode::NOT_FOUND)
+      .body(Body::empty())
+      .unwrap(),
+  };
+  Ok(response)
+}
+
+/// Describes the running instance: its version and uptime, the hash of the
+/// loaded configuration, the progress of a drain and the state of the backend
+/// servers.
+fn status(config: &RuntimeConfig) -> serde_json::Value {
+  let backend_pools = &config.shared_data.backend_pools;
+  let addresses = || backend_pools.iter().flat_map(|pool| pool.addresses.iter());
+  let healthy = addresses()
+    .filter(|(_, healthiness)| **healthiness.load() == Healthiness::Healthy)
+    .count();
+  let active_connections: usize = backend_pools
+    .iter()
+    .flat_map(|pool| {
+      pool
+        .addresses
+        .iter()
+        .map(move |(address, _)| pool.connections.get(address))
+    })
+    .sum();
+  let backends = backend_pools
+    .iter()
+    .flat_map(|pool| {
+      pool.addresses.iter().map(move |(address, healthiness)| {
+        json!({
+          "pool": pool.name,
+          "address": address,
+          "healthiness": healthiness.load().to_string(),
+          "dynamic": pool.dynamic_addresses.contains(address),
+          "active_connections": pool.connections.get(address),
+          "latency_ms": pool.strategy.backend_latency(address).map(|it| it.as_secs_f64() * 1000.0),
+          "last_check": health::last_check(&pool.name, address).map(|it| json!({
+            "passed": it.passed,
+            "status": it.status.map(|it| it.as_u16()),
+            "latency_ms": it.latency.as_secs_f64() * 1000.0,
+          })),
+        })
+      })
+    })
+    .collect::<Vec<_>>();
+  json!({
+    "version": env!("CARGO_PKG_VERSION"),
+    "uptime_sec": STARTED_AT.elapsed().as_secs(),
+    "config_hash": config.config_hash,
+    "maintenance": config.maintenance.load(Ordering::Relaxed),
+    "draining": drain::is_draining(),
+    "remaining_connections": if drain::is_draining() { Some(drain::CLIENT_CONNECTIONS.get()) } else { None },
+    "backend_pools": backend_pools.len(),
+    "backend_servers": addresses().count(),
+    "healthy_backend_servers": healthy,
+    "active_backend_connections": active_connections,
+    "backends": backends,
+  })
+}
+
+/// The configuration, which serves requests: the `config` file it was loaded
+/// from and the `backend_pools` as they are now, including the changes since
+/// (like dynamic backend servers or maintenance toggled via this API).
+fn effective_config(config: &RuntimeConfig) -> serde_json::Value {
+  let backend_pools = config
+    .shared_data
+    .backend_pools
+    .iter()
+    .map(|pool| {
+      let addresses = |backup: bool| {
+        let mut addresses = pool
+          .addresses
+          .iter()
+          .map(|(address, _)| address)
+          .filter(|address| pool.backup_addresses.contains(*address) == backup)
+          .collect::<Vec<_>>();
+        addresses.sort();
+        addresses
+      };
+      json!({
+        "name": pool.name,
+        "strategy": pool.strategy.name(),
+        "addresses": addresses(false),
+        "backup_addresses": addresses(true),
+        "maintenance": pool.in_maintenance(),
+      })
+    })
+    .collect::<Vec<_>>();
+  json!({
+    "config_hash": config.config_hash,
+    "config": config.source,
+    "backend_pools": backend_pools,
+  })
+}
+
+/// The status is only available without a token, if the `metrics_address` is a
+/// loopback address, so it can not be reached from other hosts. Returns the
+/// response to requests, which are not allowed to access the status.
+fn check_status_token(request: &Request<Body>, config: &RuntimeConfig) -> Option<Response<Body>> {
+  if matches!(config.metrics_address, Some(address) if address.ip().is_loopback()) {
+    return None;
+  }
+  let token = match &config.status_token {
+    Some(token) => token,
+    None => {
+      return Some(
+        Response::builder()
+          .status(StatusCode::FORBIDDEN)
+          .body(Body::from(
+            "A status_token is required for a metrics_address, which is not a loopback address\n",
+          ))
+          .unwrap(),
+      )
+    }
+  };
+  let bearer = request
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|it| it.to_str().ok())
+    .and_then(|it| it.strip_prefix("Bearer "));
+  match bearer {
+    Some(bearer) if constant_time_eq(bearer.trim().as_bytes(), token.as_bytes()) => None,
+    _ => Some(
+      Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, "Bearer")
+        .body(Body::empty())
+        .unwrap(),
+    ),
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use crate::{
+    acme::AcmeHandler,
+    configuration::{read_initial_config, watch_config},
+    server::{
+      tests::{generate_config, generate_test_pool},
+      SharedData,
+    },
+    testing::exclusive,
+  };
+
+  #[test]
+  fn test_render_counter() {
+    // given:
+    let metrics = Metrics::default();
+    metrics.increment("requests_total", &[("status", "200")]);
+    metrics.add("requests_total", &[("status", "200")], 2);
+    metrics.increment("requests_total", &[("status", "503")]);
+
+    // when:
+    let actual = metrics.render();
+
+    // then:
+    assert_eq!(
+      actual,
+      "# TYPE requests_total counter\nrequests_total{status=\"200\"} 3\nrequests_total{status=\"503\"} 1\n"
+    );
+  }
+
+  #[test]
+  fn test_render_gauge() {
+    // given:
+    let metrics = Metrics::default();
+    metrics.add_gauge("queued_requests", &[("pool", "app")], 2);
+    metrics.add_gauge("queued_requests", &[("pool", "app")], -1);
+
+    // when:
+    let actual = metrics.render();
+
+    // then:
+    assert_eq!(
+      actual,
+      "# TYPE queued_requests gauge\nqueued_requests{pool=\"app\"} 1\n"
+    );
+  }
+
+  #[test]
+  fn test_render_histogram() {
+    // given:
+    let metrics = Metrics::default();
+    metrics.observe("duration_seconds", &[], 0.003);
+    metrics.observe("duration_seconds", &[], 0.2);
+    metrics.observe("duration_seconds", &[], 20.0);
+
+    // when:
+    let actual = metrics.render();
+
+    // then:
+    assert!(actual.starts_with("# TYPE duration_seconds histogram\n"));
+    assert!(actual.contains("duration_seconds_bucket{le=\"0.005\"} 1\n"));
+    assert!(actual.contains("duration_seconds_bucket{le=\"0.25\"} 2\n"));
+    assert!(actual.contains("duration_seconds_bucket{le=\"10\"} 2\n"));
+    assert!(actual.contains("duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+    assert!(actual.contains("duration_seconds_count 3\n"));
+  }
+
+  #[test]
+  fn test_render_histogram_with_configured_buckets() {
+    // given:
+    let metrics = Metrics::default();
+    metrics.set_buckets(&[0.1, 1.0]);
+    metrics.observe("duration_seconds", &[("pool", "app")], 0.05);
+    metrics.observe("duration_seconds", &[("pool", "app")], 0.5);
+    metrics.observe("duration_seconds", &[("pool", "app")], 0.5);
+
+    // when:
+    let actual = metrics.render();
+
+    // then:
+    assert_eq!(
+      actual,
+      "# TYPE duration_seconds histogram\n\
+       duration_seconds_bucket{pool=\"app\",le=\"0.1\"} 1\n\
+       duration_seconds_bucket{pool=\"app\",le=\"1\"} 3\n\
+       duration_seconds_bucket{pool=\"app\",le=\"+Inf\"} 3\n\
+       duration_seconds_sum{pool=\"app\"} 1.05\n\
+       duration_seconds_count{pool=\"app\"} 3\n"
+    );
+  }
+
+  #[tokio::test]
+  async fn test_health_endpoint() {
+    // given:
+    let request = Request::get("/health").body(Body::empty()).unwrap();
+
+    // when:
+    let response = handle_request(request, test_config(None, None)).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), StatusCode::OK);
+  }
+
+  /// A configuration with a backend pool of two backend servers.
+  pub fn test_config(metrics_address: Option<&str>, status_token: Option<&str>) -> Arc<ArcSwap<RuntimeConfig>> {
+    let mut config = generate_config(SharedData {
+      backend_pools: vec![generate_test_pool(&["127.0.0.1:8081", "127.0.0.1:8082"])],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    config.metrics_address = metrics_address.map(|it| it.parse().unwrap());
+    config.status_token = status_token.map(String::from);
+    config.config_hash = "abc".into();
+    Arc::new(ArcSwap::from_pointee(config))
+  }
+
+  #[tokio::test]
+  async fn test_reload_endpoint() {
+    // given:
+    let path = std::env::temp_dir().join(format!("arlb-reload-{}.toml", std::process::id()));
+    let config_file = |name: &str| {
+      format!(
+        r#"
+        [[backend_pools]]
+        name = "{}"
+        matcher = "Host('whoami.localhost')"
+        addresses = ["127.0.0.1:8080"]
+        schemes = ["HTTP"]
+        strategy = {{ RoundRobin = {{}} }}
+        "#,
+        name
+      )
+    };
+    std::fs::write(&path, config_file("before")).unwrap();
+    let config = read_initial_config(&path).await.unwrap();
+    tokio::spawn(watch_config(path.clone(), config.clone(), None));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let reload = || {
+      let request = Request::post("/reload").body(Body::empty()).unwrap();
+      handle_request(request, test_config(Some("127.0.0.1:9100"), None))
+    };
+
+    // when:
+    std::fs::write(&path, "backend_pools = 1").unwrap();
+    let invalid = reload().await.unwrap();
+    std::fs::write(&path, config_file("after")).unwrap();
+    let valid = reload().await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // then:
+    assert_eq!(invalid.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(valid.status(), StatusCode::NO_CONTENT);
+    assert_eq!(config.load().shared_data.backend_pools[0].name, "after");
+  }
+
+  async fn get_status(config: Arc<ArcSwap<RuntimeConfig>>, authorization: Option<&str>) -> Response<Body> {
+    let mut request = Request::get("/status");
+    if let Some(authorization) = authorization {
+      request = request.header(AUTHORIZATION, authorization);
+    }
+    handle_request(request.body(Body::empty()).unwrap(), config)
+      .await
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_status_endpoint() {
+    // given:
+    let _exclusive = exclusive().await;
+    let config = test_config(Some("127.0.0.1:9100"), None);
+    config.load().shared_data.backend_pools[0].addresses[1]
+      .1
+      .store(Arc::new(Healthiness::Unresponsive(None)));
+
+    // when:
+    let response = get_status(config, None).await;
+
+    // then:
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(status["config_hash"], "abc");
+    assert_eq!(status["backend_servers"], 2);
+    assert_eq!(status["healthy_backend_servers"], 1);
+    assert_eq!(status["active_backend_connections"], 0);
+    assert_eq!(status["draining"], false);
+    assert!(status["remaining_connections"].is_null());
+    assert_eq!(status["backends"][1]["address"], "127.0.0.1:8082");
+    assert_eq!(status["backends"][1]["healthiness"], "Unresponsive");
+    assert!(status["backends"][1]["latency_ms"].is_null());
+  }
+
+  #[tokio::test]
+  async fn test_status_endpoint_requires_token_without_loopback_address() {
+    // given:
+    let protected = test_config(Some("0.0.0.0:9100"), Some("s3cr3t"));
+    let unprotected = test_config(Some("0.0.0.0:9100"), None);
+
+    // when:
+    let without_token = get_status(protected.clone(), None).await;
+    let wrong_token = get_status(protected.clone(), Some("Bearer s3cr3x")).await;
+    let valid_token = get_status(protected, Some("Bearer s3cr3t")).await;
+    let without_configured_token = get_status(unprotected, Some("Bearer s3cr3t")).await;
+
+    // then:
+    assert_eq!(without_token.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(wrong_token.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(valid_token.status(), StatusCode::OK);
+    assert_eq!(without_configured_token.status(), StatusCode::FORBIDDEN);
+  }
+
+  #[tokio::test]
+  async fn test_config_endpoint() {
+    // given:
+    let path = std::env::temp_dir().join(format!("arlb-config-{}.toml", std::process::id()));
+    std::fs::write(
+      &path,
+      r#"
+      metrics_address = "0.0.0.0:0"
+      status_token = "s3cr3t"
+
+      [[backend_pools]]
+      name = "api"
+      matcher = "Host('whoami.localhost')"
+      addresses = ["127.0.0.1:8080"]
+      schemes = ["HTTP"]
+      strategy = { RoundRobin = {} }
+      "#,
+    )
+    .unwrap();
+    let config = read_initial_config(&path).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let get_config = |authorization: Option<&str>| {
+      let mut request = Request::get("/config");
+      if let Some(authorization) = authorization {
+        request = request.header(AUTHORIZATION, authorization);
+      }
+      handle_request(request.body(Body::empty()).unwrap(), config.clone())
+    };
+
+    // when:
+    let without_token = get_config(None).await.unwrap();
+    let response = get_config(Some("Bearer s3cr3t")).await.unwrap();
+
+    // then:
+    assert_eq!(without_token.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let actual: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(actual["config_hash"], config.load().config_hash.as_str());
+    assert_eq!(actual["config"]["status_token"], "***");
+    assert_eq!(
+      actual["config"]["backend_pools"][0]["matcher"],
+      "Host('whoami.localhost')"
+    );
+    assert_eq!(actual["backend_pools"][0]["name"], "api");
+    assert_eq!(actual["backend_pools"][0]["addresses"][0], "127.0.0.1:8080");
+    assert_eq!(actual["backend_pools"][0]["maintenance"], false);
+  }
+
+  #[tokio::test]
+  async fn test_maintenance_endpoint() {
+    // given:
+    let config = test_config(Some("127.0.0.1:9100"), None);
+
+    // when:
+    let request = Request::put("/maintenance").body(Body::empty()).unwrap();
+    let response = handle_request(request, config.clone()).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(config.load().maintenance.load(Ordering::Relaxed));
+    let body = hyper::body::to_bytes(get_status(config.clone(), None).await.into_body())
+      .await
+      .unwrap();
+    let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(status["maintenance"], true);
+
+    // when:
+    let request = Request::delete("/maintenance").body(Body::empty()).unwrap();
+    handle_request(request, config.clone()).await.unwrap();
+
+    // then:
+    assert!(!config.load().maintenance.load(Ordering::Relaxed));
+  }
+
+  #[tokio::test]
+  async fn test_bans_endpoint() {
+    // given:
+    let config = test_config(Some("127.0.0.1:9100"), None);
+
+    // when:
+    let list = handle_request(Request::get("/bans").body(Body::empty()).unwrap(), config.clone());
+    let unknown = handle_request(
+      Request::delete("/bans/10.0.0.1").body(Body::empty()).unwrap(),
+      config.clone(),
+    );
+    let invalid = handle_request(Request::delete("/bans/10.0.0").body(Body::empty()).unwrap(), config);
+
+    // then:
+    let body = hyper::body::to_bytes(list.await.unwrap().into_body()).await.unwrap();
+    let bans: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(bans["bans"].is_array());
+    assert_eq!(unknown.await.unwrap().status(), StatusCode::NOT_FOUND);
+    assert_eq!(invalid.await.unwrap().status(), StatusCode::BAD_REQUEST);
+  }
+
+  #[test]
+  fn test_escape_label_value() {
+    assert_eq!(escape_label_value("Host('a') && \"b\""), "Host('a') && \\\"b\\\"");
+  }
+}
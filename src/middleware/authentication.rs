@@ -1,4 +1,5 @@
 use super::{Context, Middleware};
+use crate::bans::{Violation, OFFENDERS};
 use async_trait::async_trait;
 use http_auth_basic::Credentials;
 use hyper::{
@@ -22,11 +23,14 @@ impl Middleware for Authentication {
   async fn modify_request(
     &self,
     request: Request<Body>,
-    _context: &Context<'_>,
+    context: &Context<'_>,
   ) -> Result<Request<Body>, Response<Body>> {
     if self.user_authentication(request.headers()).await.is_some() {
       Ok(request)
     } else {
+      if request.headers().contains_key(AUTHORIZATION) {
+        OFFENDERS.record(context.client_address.ip(), Violation::AuthenticationFailed);
+      }
       Err(response_unauthorized())
     }
   }
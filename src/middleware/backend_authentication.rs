@@ -0,0 +1,215 @@
+use super::{Context, Middleware};
+use async_trait::async_trait;
+use hyper::{
+  header::{HeaderName, HeaderValue},
+  Body, Request, Response, Uri,
+};
+use openssl::{
+  hash::MessageDigest,
+  pkey::{PKey, Private},
+  sign::Signer,
+};
+use std::{
+  convert::TryFrom,
+  fmt,
+  time::{SystemTime, UNIX_EPOCH},
+};
+use toml::value::Table;
+
+/// Authenticates the load balancer towards the backend servers, so they can
+/// refuse requests, which did not pass it. Either a static token is sent, or
+/// an HMAC signature over the method, path and a timestamp of the request, or
+/// both.
+#[derive(Debug)]
+pub struct BackendAuthentication {
+  token_header: HeaderName,
+  token: Option<Secret<HeaderValue>>,
+  signature_header: HeaderName,
+  timestamp_header: HeaderName,
+  signing_key: Option<Secret<PKey<Private>>>,
+}
+
+/// A value, which must not end up in logs, so it is formatted as `***`.
+pub struct Secret<T>(T);
+
+impl<T> fmt::Debug for Secret<T> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "***")
+  }
+}
+
+#[async_trait]
+impl Middleware for BackendAuthentication {
+  async fn modify_request(
+    &self,
+    mut request: Request<Body>,
+    context: &Context<'_>,
+  ) -> Result<Request<Body>, Response<Body>> {
+    self.authenticate(&mut request, &context.backend_uri, SystemTime::now());
+    Ok(request)
+  }
+}
+
+impl BackendAuthentication {
+  fn authenticate(&self, request: &mut Request<Body>, backend_uri: &Uri, now: SystemTime) {
+    let method = request.method().clone();
+    let headers = request.headers_mut();
+    // Clients must not be able to pretend to be the load balancer
+    headers.remove(&self.token_header);
+    headers.remove(&self.signature_header);
+    headers.remove(&self.timestamp_header);
+    if let Some(Secret(token)) = &self.token {
+      headers.insert(self.token_header.clone(), token.clone());
+    }
+    if let Some(Secret(signing_key)) = &self.signing_key {
+      let timestamp = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs().to_string();
+      // The path as it is sent to the backend server, after it was rewritten
+      let path = backend_uri.path_and_query().map(|it| it.as_str()).unwrap_or("/");
+      let message = format!("{}\n{}\n{}", method, path, timestamp);
+      if let Some(signature) = sign(signing_key, message.as_bytes()) {
+        headers.insert(self.timestamp_header.clone(), HeaderValue::try_from(timestamp).unwrap());
+        headers.insert(self.signature_header.clone(), HeaderValue::try_from(signature).unwrap());
+      }
+    }
+  }
+}
+
+/// The lowercase hex encoded HMAC-SHA256 of `message`.
+fn sign(key: &PKey<Private>, message: &[u8]) -> Option<String> {
+  let mut signer = Signer::new(MessageDigest::sha256(), key).ok()?;
+  signer.update(message).ok()?;
+  let signature = signer.sign_to_vec().ok()?;
+  Some(signature.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Reads the secret of `key` or of the environment variable named by
+/// `{key}_env`, so it does not have to be part of the configuration file.
+fn secret(t: &Table, key: &str) -> Result<Option<String>, ()> {
+  match (t.get(key), t.get(&format!("{}_env", key))) {
+    (Some(secret), None) => Ok(Some(secret.as_str().ok_or(())?.to_string())),
+    (None, Some(variable)) => std::env::var(variable.as_str().ok_or(())?).map(Some).map_err(|_| ()),
+    (None, None) => Ok(None),
+    (Some(_), Some(_)) => Err(()),
+  }
+}
+
+impl TryFrom<Table> for BackendAuthentication {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let header = |key: &str, default: &'static str| match t.get(key) {
+      Some(header) => HeaderName::try_from(header.as_str().ok_or(())?).map_err(|_| ()),
+      None => Ok(HeaderName::from_static(default)),
+    };
+    let token = match secret(&t, "token")? {
+      Some(token) => Some(Secret(HeaderValue::try_from(token).map_err(|_| ())?)),
+      None => None,
+    };
+    let signing_key = match secret(&t, "signing_key")? {
+      Some(signing_key) if !signing_key.is_empty() => Some(Secret(PKey::hmac(signing_key.as_bytes()).map_err(|_| ())?)),
+      Some(_) => return Err(()),
+      None => None,
+    };
+    if token.is_none() && signing_key.is_none() {
+      return Err(());
+    }
+    Ok(BackendAuthentication {
+      token_header: header("token_header", "x-lb-token")?,
+      token,
+      signature_header: header("signature_header", "x-lb-signature")?,
+      timestamp_header: header("timestamp_header", "x-lb-timestamp")?,
+      signing_key,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use openssl::sha::sha256;
+  use std::time::Duration;
+  use toml::Value;
+
+  /// HMAC-SHA256 as specified by RFC 2104, like a backend server would verify
+  /// the signature.
+  fn reference_hmac(key: &[u8], message: &[u8]) -> String {
+    let mut block = [0u8; 64];
+    block[..key.len()].copy_from_slice(key);
+    let inner_key = block.iter().map(|it| it ^ 0x36).collect::<Vec<_>>();
+    let outer_key = block.iter().map(|it| it ^ 0x5c).collect::<Vec<_>>();
+    let inner = sha256(&[&inner_key[..], message].concat());
+    let outer = sha256(&[&outer_key[..], &inner[..]].concat());
+    outer.iter().map(|byte| format!("{:02x}", byte)).collect()
+  }
+
+  fn middleware(entries: &[(&str, &str)]) -> Result<BackendAuthentication, ()> {
+    let mut table = Table::new();
+    for (key, value) in entries {
+      table.insert(key.to_string(), Value::from(*value));
+    }
+    BackendAuthentication::try_from(table)
+  }
+
+  #[test]
+  fn test_signature_validates_with_reference_hmac() {
+    // given:
+    let middleware = middleware(&[("signing_key", "shared secret")]).unwrap();
+    let mut request = Request::post("/api/orders?id=1")
+      .header("x-lb-signature", "forged")
+      .body(Body::empty())
+      .unwrap();
+    let backend_uri = "http://10.0.0.1:8080/orders?id=1".parse().unwrap();
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    // when:
+    middleware.authenticate(&mut request, &backend_uri, now);
+
+    // then:
+    let headers = request.headers();
+    assert_eq!(headers["x-lb-timestamp"], "1700000000");
+    assert_eq!(
+      headers["x-lb-signature"],
+      reference_hmac(b"shared secret", b"POST\n/orders?id=1\n1700000000").as_str()
+    );
+    assert_eq!(headers.get("x-lb-token"), None);
+  }
+
+  #[test]
+  fn test_token_replaces_client_supplied_header() {
+    // given:
+    std::env::set_var("ARLB_TEST_BACKEND_TOKEN", "s3cr3t");
+    let middleware = middleware(&[("token_env", "ARLB_TEST_BACKEND_TOKEN"), ("token_header", "X-Auth")]).unwrap();
+    let mut request = Request::get("/")
+      .header("x-auth", "forged")
+      .header("x-auth", "forged again")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    middleware.authenticate(&mut request, &"http://10.0.0.1/".parse().unwrap(), SystemTime::now());
+
+    // then:
+    let values = request.headers().get_all("x-auth").iter().collect::<Vec<_>>();
+    assert_eq!(values, vec!["s3cr3t"]);
+    assert_eq!(request.headers().get("x-lb-signature"), None);
+  }
+
+  #[test]
+  fn test_secrets_are_redacted() {
+    let middleware = middleware(&[("token", "s3cr3t"), ("signing_key", "shared secret")]).unwrap();
+
+    let formatted = format!("{:?}", middleware);
+
+    assert!(!formatted.contains("s3cr3t"), "{}", formatted);
+    assert!(!formatted.contains("shared secret"), "{}", formatted);
+    assert!(formatted.contains("token: Some(***)"), "{}", formatted);
+  }
+
+  #[test]
+  fn test_invalid_configurations() {
+    assert!(middleware(&[]).is_err());
+    assert!(middleware(&[("signing_key", "")]).is_err());
+    assert!(middleware(&[("token", "a"), ("token_env", "ARLB_TEST_BACKEND_TOKEN")]).is_err());
+    assert!(middleware(&[("token_env", "ARLB_TEST_UNDEFINED_VARIABLE")]).is_err());
+  }
+}
@@ -0,0 +1,745 @@
+use super::{remove_hop_by_hop_headers, Context, Middleware, MiddlewareChain};
+use crate::{error_response::bad_gateway, metrics::METRICS, server::Scheme, static_response::LocalResponse};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use hyper::{
+  body::Bytes,
+  header::{
+    HeaderName, HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, COOKIE, EXPIRES, HOST, SET_COOKIE, VARY,
+  },
+  Body, HeaderMap, Method, Request, Response, StatusCode,
+};
+use linked_hash_map::LinkedHashMap;
+use log::debug;
+use once_cell::sync::Lazy;
+use std::{
+  convert::{TryFrom, TryInto},
+  sync::{Arc, Mutex, Weak},
+  time::{Duration, Instant},
+};
+use toml::{value::Table, Value};
+
+/// All caches of the current configuration, so they can be purged via
+/// [`purge`]. Caches of old configurations are dropped on reload.
+static CACHES: Lazy<Mutex<Vec<Weak<ResponseCache>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Caches responses to `GET` requests in memory and answers later requests
+/// for the same resource without contacting a backend server.
+#[derive(Debug)]
+pub struct Cache {
+  /// Used for responses without `Cache-Control: max-age` or `Expires`, which
+  /// are not cached without it.
+  default_ttl: Option<Duration>,
+  /// Larger responses are not cached.
+  max_entry_size: usize,
+  /// The least recently used responses are evicted once all cached responses
+  /// exceed this size.
+  max_size: usize,
+  statuses: Vec<StatusCode>,
+  /// Request headers, which are part of the cache key. Responses varying by
+  /// other headers are not cached.
+  vary: Vec<HeaderName>,
+  responses: Arc<ResponseCache>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+  scheme: Scheme,
+  host: String,
+  path_and_query: String,
+  vary: Vec<Option<HeaderValue>>,
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+  status: StatusCode,
+  headers: HeaderMap,
+  body: Bytes,
+  expires_at: Instant,
+}
+
+impl CachedResponse {
+  fn size(&self) -> usize {
+    let headers: usize = self
+      .headers
+      .iter()
+      .map(|(name, value)| name.as_str().len() + value.len())
+      .sum();
+    headers + self.body.len()
+  }
+
+  fn response(&self) -> Response<Body> {
+    let mut response = Response::new(Body::from(self.body.clone()));
+    *response.status_mut() = self.status;
+    *response.headers_mut() = self.headers.clone();
+    response.extensions_mut().insert(LocalResponse);
+    response
+  }
+}
+
+/// The cached responses in the order of their last use.
+#[derive(Debug, Default)]
+struct ResponseCache {
+  entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+  responses: LinkedHashMap<CacheKey, CachedResponse>,
+  size: usize,
+}
+
+impl Entries {
+  fn remove(&mut self, key: &CacheKey) -> Option<CachedResponse> {
+    let removed = self.responses.remove(key)?;
+    self.size -= removed.size();
+    Some(removed)
+  }
+}
+
+impl ResponseCache {
+  fn get(&self, key: &CacheKey) -> Option<Response<Body>> {
+    let mut entries = self.entries.lock().unwrap();
+    let cached = entries.responses.get_refresh(key)?;
+    if cached.expires_at > Instant::now() {
+      return Some(cached.response());
+    }
+    entries.remove(key);
+    None
+  }
+
+  fn insert(&self, key: CacheKey, response: CachedResponse, max_size: usize) {
+    let mut entries = self.entries.lock().unwrap();
+    entries.remove(&key);
+    entries.size += response.size();
+    entries.responses.insert(key, response);
+    while entries.size > max_size {
+      match entries.responses.pop_front() {
+        Some((_, evicted)) => {
+          entries.size -= evicted.size();
+          METRICS.increment("arlb_cache_evictions_total", &[]);
+        }
+        None => break,
+      }
+    }
+  }
+
+  fn purge(&self, host: Option<&str>, path_prefix: &str) -> usize {
+    let mut entries = self.entries.lock().unwrap();
+    let purged = entries
+      .responses
+      .keys()
+      .filter(|key| match host {
+        Some(host) => key.host.eq_ignore_ascii_case(host),
+        None => true,
+      })
+      .filter(|key| key.path_and_query.starts_with(path_prefix))
+      .cloned()
+      .collect::<Vec<_>>();
+    for key in &purged {
+      entries.remove(key);
+    }
+    purged.len()
+  }
+
+  #[cfg(test)]
+  fn len(&self) -> usize {
+    self.entries.lock().unwrap().responses.len()
+  }
+}
+
+/// Removes the cached responses of `host` (or all hosts), whose path starts
+/// with `path_prefix`, from all caches. Returns how many responses were
+/// removed.
+pub fn purge(host: Option<&str>, path_prefix: &str) -> usize {
+  let mut caches = CACHES.lock().unwrap();
+  caches.retain(|cache| cache.strong_count() > 0);
+  caches
+    .iter()
+    .filter_map(Weak::upgrade)
+    .map(|cache| cache.purge(host, path_prefix))
+    .sum()
+}
+
+#[async_trait]
+impl Middleware for Cache {
+  async fn forward_request(
+    &self,
+    request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    let key = match self.key(&request, *context.client_scheme) {
+      Some(key) => key,
+      None => return chain.forward_request(request, context).await,
+    };
+    if let Some(response) = self.responses.get(&key) {
+      METRICS.increment("arlb_cache_hits_total", &[]);
+      return response;
+    }
+    METRICS.increment("arlb_cache_misses_total", &[]);
+    let response = chain.forward_request(request, context).await;
+    match self.ttl(&response) {
+      Some(ttl) => self.store(key, response, ttl).await,
+      None => response,
+    }
+  }
+}
+
+impl Cache {
+  fn new(
+    default_ttl: Option<Duration>,
+    max_entry_size: usize,
+    max_size: usize,
+    statuses: Vec<StatusCode>,
+    vary: Vec<HeaderName>,
+  ) -> Cache {
+    let responses = Arc::new(ResponseCache::default());
+    CACHES.lock().unwrap().push(Arc::downgrade(&responses));
+    Cache {
+      default_ttl,
+      max_entry_size,
+      max_size,
+      statuses,
+      vary,
+      responses,
+    }
+  }
+
+  /// Only `GET` requests without credentials are cached. Requests with a
+  /// `Cookie` are only cached, if it is part of the key via `vary`.
+  fn key(&self, request: &Request<Body>, scheme: Scheme) -> Option<CacheKey> {
+    if request.method() != Method::GET || request.headers().contains_key(AUTHORIZATION) {
+      return None;
+    }
+    if request.headers().contains_key(COOKIE) && !self.vary.contains(&COOKIE) {
+      return None;
+    }
+    let host = request
+      .headers()
+      .get(HOST)
+      .and_then(|it| it.to_str().ok())
+      .or_else(|| request.uri().host())?;
+    Some(CacheKey {
+      scheme,
+      host: host.to_ascii_lowercase(),
+      path_and_query: request.uri().path_and_query()?.to_string(),
+      vary: self
+        .vary
+        .iter()
+        .map(|name| request.headers().get(name).cloned())
+        .collect(),
+    })
+  }
+
+  /// How long the `response` can be cached, if at all. Responses setting a
+  /// cookie are meant for a single client and never cached.
+  fn ttl(&self, response: &Response<Body>) -> Option<Duration> {
+    if !self.statuses.contains(&response.status()) {
+      return None;
+    }
+    let headers = response.headers();
+    if headers.contains_key(SET_COOKIE) {
+      return None;
+    }
+    for vary in comma_separated(headers, &VARY) {
+      let known = matches!(HeaderName::from_bytes(vary.as_bytes()), Ok(it) if self.vary.contains(&it));
+      if !known {
+        return None;
+      }
+    }
+    let mut max_age = None;
+    for directive in comma_separated(headers, &CACHE_CONTROL) {
+      let directive = directive.to_ascii_lowercase();
+      match directive.split_once('=') {
+        // s-maxage is meant for shared caches like this one
+        Some(("s-maxage", seconds)) => max_age = seconds.parse().ok().or(Some(0)),
+        Some(("max-age", seconds)) if max_age.is_none() => max_age = seconds.parse().ok().or(Some(0)),
+        None if ["no-store", "no-cache", "private"].contains(&directive.as_str()) => return None,
+        _ => {}
+      }
+    }
+    let ttl = match max_age {
+      Some(seconds) => Duration::from_secs(seconds),
+      None => match headers.get(EXPIRES) {
+        // Invalid dates like "0" mean already expired
+        Some(expires) => expires
+          .to_str()
+          .ok()
+          .and_then(|it| DateTime::parse_from_rfc2822(it).ok())
+          .and_then(|it| (it.with_timezone(&Utc) - Utc::now()).to_std().ok())
+          .unwrap_or_default(),
+        None => self.default_ttl?,
+      },
+    };
+    Some(ttl).filter(|it| *it > Duration::from_secs(0))
+  }
+
+  /// Reads the body of the `response` to cache it, if it does not exceed the
+  /// `max_entry_size`.
+  async fn store(&self, key: CacheKey, response: Response<Body>, ttl: Duration) -> Response<Body> {
+    let content_length = response
+      .headers()
+      .get(CONTENT_LENGTH)
+      .and_then(|it| it.to_str().ok())
+      .and_then(|it| it.parse::<usize>().ok());
+    if matches!(content_length, Some(it) if it > self.max_entry_size) {
+      return response;
+    }
+    let (parts, mut body) = response.into_parts();
+    let mut chunks = Vec::new();
+    let mut length = 0;
+    while let Some(chunk) = body.next().await {
+      let chunk = match chunk {
+        Ok(chunk) => chunk,
+        Err(e) => {
+          debug!("Could not read response body to cache it: {}", e);
+          return bad_gateway();
+        }
+      };
+      length += chunk.len();
+      chunks.push(Ok::<_, hyper::Error>(chunk));
+      if length > self.max_entry_size {
+        // Send the chunks, which were already read, followed by the rest of the body
+        let body = Body::wrap_stream(stream::iter(chunks).chain(body));
+        return Response::from_parts(parts, body);
+      }
+    }
+    let body = chunks
+      .into_iter()
+      .flatten()
+      .fold(Vec::with_capacity(length), |mut body, chunk| {
+        body.extend_from_slice(&chunk);
+        body
+      });
+    // Hop-by-hop headers belong to the connection to the backend server, not to later clients
+    let mut headers = parts.headers.clone();
+    remove_hop_by_hop_headers(&mut headers);
+    let cached = CachedResponse {
+      status: parts.status,
+      headers,
+      body: Bytes::from(body),
+      expires_at: Instant::now() + ttl,
+    };
+    let body = cached.body.clone();
+    self.responses.insert(key, cached, self.max_size);
+    Response::from_parts(parts, Body::from(body))
+  }
+}
+
+fn comma_separated<'h>(headers: &'h HeaderMap, name: &HeaderName) -> impl Iterator<Item = &'h str> {
+  headers
+    .get_all(name)
+    .iter()
+    .filter_map(|it| it.to_str().ok())
+    .flat_map(|it| it.split(','))
+    .map(str::trim)
+    .filter(|it| !it.is_empty())
+}
+
+impl TryFrom<Table> for Cache {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let get_usize = |key: &str, default: usize| match t.get(key) {
+      Some(value) => value.as_integer().and_then(|it| it.try_into().ok()).ok_or(()),
+      None => Ok(default),
+    };
+    let default_ttl = match t.get("default_ttl_sec") {
+      Some(_) => Some(Duration::from_secs(get_usize("default_ttl_sec", 0)? as u64)),
+      None => None,
+    };
+    let max_entry_size = get_usize("max_entry_size", 1024 * 1024)?;
+    let max_size = get_usize("max_size", 64 * 1024 * 1024)?;
+    let statuses = match t.get("statuses") {
+      Some(Value::Array(statuses)) => statuses
+        .iter()
+        .map(|it| {
+          it.as_integer()
+            .and_then(|it| u16::try_from(it).ok())
+            .and_then(|it| StatusCode::from_u16(it).ok())
+            .ok_or(())
+        })
+        .collect::<Result<_, _>>()?,
+      Some(_) => return Err(()),
+      None => vec![StatusCode::OK, StatusCode::MOVED_PERMANENTLY, StatusCode::NOT_FOUND],
+    };
+    let vary = match t.get("vary") {
+      Some(Value::Array(vary)) => vary
+        .iter()
+        .map(|it| {
+          it.as_str()
+            .and_then(|it| HeaderName::from_bytes(it.as_bytes()).ok())
+            .ok_or(())
+        })
+        .collect::<Result<_, _>>()?,
+      Some(_) => return Err(()),
+      None => Vec::new(),
+    };
+    Ok(Cache::new(default_ttl, max_entry_size, max_size, statuses, vary))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    http_client::{BackendConnector, StrategyNotifyHttpConnector},
+    load_balancing::random::Random,
+    metrics,
+    server::Scheme,
+  };
+  use hyper::{
+    service::{make_service_fn, service_fn},
+    Client, Server,
+  };
+  use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+  };
+
+  fn cache(toml: &str) -> Cache {
+    let table: Table = toml::from_str(toml).unwrap();
+    Cache::try_from(table).unwrap()
+  }
+
+  /// Starts a backend server, which responds with the number of requests it
+  /// received so far, the given `headers` and the `accept-encoding` of the
+  /// request as body.
+  fn start_backend(headers: &'static [(&'static str, &'static str)]) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    let service = make_service_fn(move |_| {
+      let counter = counter.clone();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+          let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+          async move {
+            let encoding = request
+              .headers()
+              .get("accept-encoding")
+              .map(|it| it.to_str().unwrap().to_string())
+              .unwrap_or_default();
+            let mut response = Response::builder();
+            for (name, value) in headers {
+              response = response.header(*name, *value);
+            }
+            Ok::<_, Infallible>(response.body(Body::from(format!("{} {}", count, encoding))).unwrap())
+          }
+        }))
+      }
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+    (address, requests)
+  }
+
+  async fn get(cache: &Cache, backend: SocketAddr, path: &str, encoding: Option<&str>) -> String {
+    let mut request = Request::builder().uri(path);
+    if let Some(encoding) = encoding {
+      request = request.header("accept-encoding", encoding);
+    }
+    body(send(cache, backend, request, &Scheme::HTTP).await).await
+  }
+
+  async fn send(
+    cache: &Cache,
+    backend: SocketAddr,
+    request: hyper::http::request::Builder,
+    scheme: &Scheme,
+  ) -> Response<Body> {
+    let request = request.header("host", "whoami.localhost");
+    let path = request.uri_ref().unwrap().to_string();
+    let connector = StrategyNotifyHttpConnector::new(
+      BackendConnector::new(),
+      Arc::new(Box::new(Random::new())),
+      Default::default(),
+    );
+    let client = Client::builder().build(connector);
+    let context = Context {
+      client_scheme: scheme,
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_uri: format!("http://{}{}", backend, path).parse().unwrap(),
+      client: &client,
+      response_timeout: None,
+    };
+    cache
+      .forward_request(request.body(Body::empty()).unwrap(), &MiddlewareChain::Empty, &context)
+      .await
+  }
+
+  async fn body(response: Response<Body>) -> String {
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_cache_hit_does_not_contact_backend() {
+    // given:
+    let (backend, requests) = start_backend(&[]);
+    let cache = cache("default_ttl_sec = 60");
+
+    // when:
+    let first = get(&cache, backend, "/data.json", None).await;
+    let second = get(&cache, backend, "/data.json", None).await;
+
+    // then:
+    assert_eq!(first, "1 ");
+    assert_eq!(second, "1 ");
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+  }
+
+  #[tokio::test]
+  async fn test_ttl_expiry() {
+    // given:
+    let (backend, _) = start_backend(&[]);
+    let mut cache = cache("");
+    cache.default_ttl = Some(Duration::from_millis(50));
+    get(&cache, backend, "/data.json", None).await;
+
+    // when:
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let response = get(&cache, backend, "/data.json", None).await;
+
+    // then:
+    assert_eq!(response, "2 ");
+  }
+
+  #[tokio::test]
+  async fn test_no_store_is_not_cached() {
+    // given:
+    let (backend, _) = start_backend(&[("cache-control", "no-store")]);
+    let cache = cache("default_ttl_sec = 60");
+    get(&cache, backend, "/data.json", None).await;
+
+    // when:
+    let response = get(&cache, backend, "/data.json", None).await;
+
+    // then:
+    assert_eq!(response, "2 ");
+  }
+
+  #[tokio::test]
+  async fn test_vary_on_accept_encoding() {
+    // given:
+    let (backend, requests) = start_backend(&[("vary", "Accept-Encoding")]);
+    let cache = cache(
+      r#"
+      default_ttl_sec = 60
+      vary = ["Accept-Encoding"]
+      "#,
+    );
+
+    // when:
+    let gzip = get(&cache, backend, "/data.json", Some("gzip")).await;
+    let br = get(&cache, backend, "/data.json", Some("br")).await;
+    let cached_gzip = get(&cache, backend, "/data.json", Some("gzip")).await;
+
+    // then:
+    assert_eq!(gzip, "1 gzip");
+    assert_eq!(br, "2 br");
+    assert_eq!(cached_gzip, "1 gzip");
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+  }
+
+  #[tokio::test]
+  async fn test_unknown_vary_is_not_cached() {
+    // given:
+    let (backend, _) = start_backend(&[("vary", "Cookie")]);
+    let cache = cache("default_ttl_sec = 60");
+    get(&cache, backend, "/data.json", None).await;
+
+    // when:
+    let response = get(&cache, backend, "/data.json", None).await;
+
+    // then:
+    assert_eq!(response, "2 ");
+  }
+
+  #[tokio::test]
+  async fn test_without_default_ttl_only_caching_headers_are_cached() {
+    // given:
+    let (backend, _) = start_backend(&[]);
+    let cache = cache("");
+    get(&cache, backend, "/data.json", None).await;
+
+    // when:
+    let response = get(&cache, backend, "/data.json", None).await;
+
+    // then:
+    assert_eq!(response, "2 ");
+  }
+
+  #[tokio::test]
+  async fn test_requests_with_cookies_are_not_cached() {
+    // given:
+    let (backend, _) = start_backend(&[]);
+    let cache = cache("default_ttl_sec = 60");
+    let request = || Request::builder().uri("/data.json").header("cookie", "session=alice");
+    send(&cache, backend, request(), &Scheme::HTTP).await;
+
+    // when:
+    let response = send(&cache, backend, request(), &Scheme::HTTP).await;
+
+    // then:
+    assert_eq!(body(response).await, "2 ");
+  }
+
+  #[tokio::test]
+  async fn test_cookies_listed_in_vary_are_part_of_the_key() {
+    // given:
+    let (backend, requests) = start_backend(&[("vary", "Cookie")]);
+    let cache = cache(
+      r#"
+      default_ttl_sec = 60
+      vary = ["Cookie"]
+      "#,
+    );
+    let request = |cookie| Request::builder().uri("/data.json").header("cookie", cookie);
+    send(&cache, backend, request("session=alice"), &Scheme::HTTP).await;
+
+    // when:
+    let alice = send(&cache, backend, request("session=alice"), &Scheme::HTTP).await;
+    let bob = send(&cache, backend, request("session=bob"), &Scheme::HTTP).await;
+
+    // then:
+    assert_eq!(body(alice).await, "1 ");
+    assert_eq!(body(bob).await, "2 ");
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+  }
+
+  #[tokio::test]
+  async fn test_set_cookie_is_not_cached() {
+    // given:
+    let (backend, _) = start_backend(&[("set-cookie", "session=alice")]);
+    let cache = cache("default_ttl_sec = 60");
+    get(&cache, backend, "/data.json", None).await;
+
+    // when:
+    let response = get(&cache, backend, "/data.json", None).await;
+
+    // then:
+    assert_eq!(response, "2 ");
+  }
+
+  #[tokio::test]
+  async fn test_schemes_are_cached_separately() {
+    // given:
+    let (backend, _) = start_backend(&[]);
+    let cache = cache("default_ttl_sec = 60");
+    let request = || Request::builder().uri("/data.json");
+    send(&cache, backend, request(), &Scheme::HTTP).await;
+
+    // when:
+    let https = send(&cache, backend, request(), &Scheme::HTTPS).await;
+    let http = send(&cache, backend, request(), &Scheme::HTTP).await;
+
+    // then:
+    assert_eq!(body(https).await, "2 ");
+    assert_eq!(body(http).await, "1 ");
+  }
+
+  #[tokio::test]
+  async fn test_hop_by_hop_headers_are_not_cached() {
+    // given:
+    let (backend, _) = start_backend(&[("connection", "x-hop"), ("x-hop", "1"), ("x-end-to-end", "1")]);
+    let cache = cache("default_ttl_sec = 60");
+    let request = || Request::builder().uri("/data.json");
+    send(&cache, backend, request(), &Scheme::HTTP).await;
+
+    // when:
+    let response = send(&cache, backend, request(), &Scheme::HTTP).await;
+
+    // then:
+    assert!(response.extensions().get::<LocalResponse>().is_some());
+    assert!(!response.headers().contains_key("connection"));
+    assert!(!response.headers().contains_key("x-hop"));
+    assert_eq!(response.headers()["x-end-to-end"], "1");
+  }
+
+  #[tokio::test]
+  async fn test_least_recently_used_is_evicted() {
+    // given: room for two responses
+    let (backend, _) = start_backend(&[]);
+    let cache = cache(
+      r#"
+      default_ttl_sec = 60
+      max_size = 120
+      "#,
+    );
+    get(&cache, backend, "/a", None).await;
+    get(&cache, backend, "/b", None).await;
+    get(&cache, backend, "/a", None).await;
+
+    // when:
+    get(&cache, backend, "/c", None).await;
+
+    // then: /b was used least recently
+    assert_eq!(cache.responses.len(), 2);
+    assert_eq!(get(&cache, backend, "/a", None).await, "1 ");
+    assert_eq!(get(&cache, backend, "/b", None).await, "4 ");
+  }
+
+  #[tokio::test]
+  async fn test_purge_by_path_prefix() {
+    // given:
+    let (backend, _) = start_backend(&[]);
+    let cache = cache("default_ttl_sec = 60");
+    get(&cache, backend, "/static/app.js", None).await;
+    get(&cache, backend, "/api/data.json", None).await;
+
+    // when:
+    let purged = purge(Some("WHOAMI.localhost"), "/static/");
+
+    // then:
+    assert!(purged >= 1);
+    assert_eq!(get(&cache, backend, "/static/app.js", None).await, "3 ");
+    assert_eq!(get(&cache, backend, "/api/data.json", None).await, "2 ");
+  }
+
+  #[tokio::test]
+  async fn test_purge_endpoint() {
+    // given:
+    let (backend, _) = start_backend(&[]);
+    let cache = cache("default_ttl_sec = 60");
+    get(&cache, backend, "/purged/index.html", None).await;
+    let request = Request::post("/cache/purge?host=whoami.localhost&path_prefix=%2Fpurged%2F")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = metrics::handle_request(request, metrics::tests::test_config(None, None))
+      .await
+      .unwrap();
+
+    // then:
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "1\n");
+    assert_eq!(get(&cache, backend, "/purged/index.html", None).await, "2 ");
+  }
+
+  #[test]
+  fn test_max_age_overrides_default_ttl() {
+    let cache = cache("default_ttl_sec = 10");
+    let response = |cache_control: &str| {
+      Response::builder()
+        .header(CACHE_CONTROL, cache_control)
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    assert_eq!(
+      cache.ttl(&response("public, max-age=30")),
+      Some(Duration::from_secs(30))
+    );
+    assert_eq!(
+      cache.ttl(&response("max-age=30, s-maxage=5")),
+      Some(Duration::from_secs(5))
+    );
+    assert_eq!(cache.ttl(&response("max-age=0")), None);
+    assert_eq!(cache.ttl(&response("private")), None);
+  }
+}
@@ -1,14 +1,19 @@
 use crate::utils::split_once;
 
 use super::{Context, Middleware, MiddlewareChain};
-use async_compression::tokio::bufread::{BrotliEncoder, DeflateEncoder, GzipEncoder};
+use async_compression::{
+  tokio::bufread::{BrotliEncoder, DeflateEncoder, GzipEncoder},
+  Level,
+};
 use async_trait::async_trait;
 use futures::TryStreamExt;
 use hyper::{
-  header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH},
+  header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY},
   Body, HeaderMap, Request, Response,
 };
 use std::{
+  cmp::Reverse,
+  convert::TryFrom,
   fmt::Display,
   io::{self, ErrorKind},
 };
@@ -17,10 +22,33 @@ use tokio_util::{
   codec::{BytesCodec, FramedRead},
   io::StreamReader,
 };
+use toml::{value::Table, Value};
 use Encoding::{BROTLI, DEFLATE, GZIP};
 
 #[derive(Debug)]
-pub struct Compression;
+pub struct Compression {
+  /// Responses with a smaller `Content-Length` are not compressed.
+  min_size: u64,
+  /// Media types of responses which are compressed, an empty list allows all
+  /// media types. Entries like `text/*` match all subtypes.
+  content_types: Vec<String>,
+  level: Level,
+  /// The supported encodings in order of preference, which decides between
+  /// encodings the client accepts equally. An empty list supports all
+  /// encodings and prefers the order of the client.
+  algorithms: Vec<Encoding>,
+}
+
+impl Default for Compression {
+  fn default() -> Self {
+    Compression {
+      min_size: 0,
+      content_types: Vec::new(),
+      level: Level::Default,
+      algorithms: Vec::new(),
+    }
+  }
+}
 
 #[async_trait]
 impl Middleware for Compression {
@@ -30,9 +58,9 @@ impl Middleware for Compression {
     chain: &MiddlewareChain,
     context: &Context<'_>,
   ) -> Response<Body> {
-    let encoding = get_preferred_encoding(request.headers());
+    let encoding = get_preferred_encoding(request.headers(), &self.algorithms);
     let response = chain.forward_request(request, context).await;
-    if let Some(encoding) = encoding.filter(|_| !response.headers().contains_key(CONTENT_ENCODING)) {
+    if let Some(encoding) = encoding.filter(|_| self.should_compress(response.headers())) {
       self.compress_response(response, &encoding)
     } else {
       response
@@ -40,16 +68,92 @@ impl Middleware for Compression {
   }
 }
 
+impl TryFrom<Table> for Compression {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let min_size = match t.get("min_size") {
+      Some(min_size) => min_size.as_integer().and_then(|it| u64::try_from(it).ok()).ok_or(())?,
+      None => 0,
+    };
+    let content_types = match t.get("content_types") {
+      Some(content_types) => content_types
+        .as_array()
+        .ok_or(())?
+        .iter()
+        .map(|it| it.as_str().map(str::to_lowercase).ok_or(()))
+        .collect::<Result<_, _>>()?,
+      None => Vec::new(),
+    };
+    let level = match t.get("level") {
+      Some(Value::Integer(level)) => Level::Precise(u32::try_from(*level).map_err(|_| ())?),
+      Some(Value::String(level)) => match level.as_str() {
+        "fastest" => Level::Fastest,
+        "best" => Level::Best,
+        "default" => Level::Default,
+        _ => return Err(()),
+      },
+      Some(_) => return Err(()),
+      None => Level::Default,
+    };
+    let algorithms = match t.get("algorithms") {
+      Some(algorithms) => algorithms
+        .as_array()
+        .ok_or(())?
+        .iter()
+        .map(|it| it.as_str().and_then(Encoding::from_str).ok_or(()))
+        .collect::<Result<_, _>>()?,
+      None => Vec::new(),
+    };
+    Ok(Compression {
+      min_size,
+      content_types,
+      level,
+      algorithms,
+    })
+  }
+}
+
 impl Compression {
+  /// Returns true if a response with the given headers should be compressed.
+  /// Responses which already have a `Content-Encoding` are never compressed.
+  fn should_compress(&self, headers: &HeaderMap) -> bool {
+    if headers.contains_key(CONTENT_ENCODING) {
+      return false;
+    }
+    let content_length = headers
+      .get(CONTENT_LENGTH)
+      .and_then(|it| it.to_str().ok())
+      .and_then(|it| it.parse::<u64>().ok());
+    if content_length.map(|it| it < self.min_size).unwrap_or(false) {
+      return false;
+    }
+    self.content_types.is_empty() || self.is_allowed_content_type(headers)
+  }
+
+  fn is_allowed_content_type(&self, headers: &HeaderMap) -> bool {
+    let media_type = match headers.get(CONTENT_TYPE).and_then(|it| it.to_str().ok()) {
+      Some(content_type) => content_type.split(';').next().unwrap_or("").trim().to_lowercase(),
+      None => return false,
+    };
+    self
+      .content_types
+      .iter()
+      .any(|allowed| match allowed.strip_suffix('*') {
+        Some(prefix) => media_type.starts_with(prefix),
+        None => *allowed == media_type,
+      })
+  }
+
   fn compress_response(&self, response: Response<Body>, encoding: &Encoding) -> Response<Body> {
     let (parts, body) = response.into_parts();
 
     let stream = StreamReader::new(body.map_err(|error| io::Error::new(ErrorKind::Other, error)));
 
     let body = match encoding {
-      BROTLI => to_body(BrotliEncoder::new(stream)),
-      DEFLATE => to_body(DeflateEncoder::new(stream)),
-      GZIP => to_body(GzipEncoder::new(stream)),
+      BROTLI => to_body(BrotliEncoder::with_quality(stream, self.level)),
+      DEFLATE => to_body(DeflateEncoder::with_quality(stream, self.level)),
+      GZIP => to_body(GzipEncoder::with_quality(stream, self.level)),
     };
     fn to_body<S>(stream: S) -> hyper::Body
     where
@@ -62,6 +166,7 @@ impl Compression {
     let headers = response.headers_mut();
     headers.insert(CONTENT_ENCODING, encoding.into());
     headers.remove(CONTENT_LENGTH);
+    headers.append(VARY, HeaderValue::from_static("accept-encoding"));
     response
   }
 }
@@ -109,8 +214,16 @@ impl Display for Encoding {
 ///
 /// Do determine which `Encoding` is preferred by the client, Quality Values (as defined in [RFC 7231, section 5.3.1: Quality Values](https://tools.ietf.org/html/rfc7231#section-5.3.1)) are used.
 ///
+/// Only the given `algorithms` are considered, unless the list is empty. If the
+/// client accepts multiple encodings equally, the first one of `algorithms` is
+/// preferred, or the first one of the client if `algorithms` is empty.
+///
 /// The special encoding `*` is not currently supported.
-fn get_preferred_encoding(headers: &HeaderMap) -> Option<Encoding> {
+fn get_preferred_encoding(headers: &HeaderMap, algorithms: &[Encoding]) -> Option<Encoding> {
+  let rank = |index: usize, encoding: &Encoding| match algorithms.iter().position(|it| it == encoding) {
+    Some(position) => position,
+    None => index,
+  };
   headers
     .get(ACCEPT_ENCODING)?
     .to_str()
@@ -118,9 +231,10 @@ fn get_preferred_encoding(headers: &HeaderMap) -> Option<Encoding> {
     .split(',')
     .map(|it| it.trim())
     .filter_map(parse_encoding_and_qvalue)
-    .rev() // max_by_key returns last of equal elements, so reverse to get the first instead
-    .max_by_key(|(_encoding, qvalue)| *qvalue)
-    .map(|(encoding, _qvalue)| encoding)
+    .filter(|(encoding, _qvalue)| algorithms.is_empty() || algorithms.contains(encoding))
+    .enumerate()
+    .max_by_key(|(index, (encoding, qvalue))| (*qvalue, Reverse(rank(*index, encoding))))
+    .map(|(_index, (encoding, _qvalue))| encoding)
 }
 
 fn parse_encoding_and_qvalue(encoding_and_qvalue: &str) -> Option<(Encoding, u32)> {
@@ -154,7 +268,7 @@ mod tests {
     let headers = HeaderMap::new();
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, None);
@@ -167,7 +281,7 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "unknown".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, None);
@@ -180,7 +294,7 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "br".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, Some(BROTLI));
@@ -193,7 +307,7 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "deflate".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, Some(DEFLATE));
@@ -206,7 +320,7 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "gzip".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, Some(GZIP));
@@ -219,7 +333,7 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "deflate, gzip".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, Some(DEFLATE));
@@ -232,7 +346,7 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "deflate;q=0.9, gzip;q=1".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, Some(GZIP));
@@ -245,7 +359,7 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "deflate;q=0.75, gzip;q=0.8".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, Some(GZIP));
@@ -258,7 +372,7 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "deflate;q=0".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, None);
@@ -271,7 +385,7 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "deflate;q=0.000".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, None);
@@ -284,9 +398,202 @@ mod tests {
     headers.insert(ACCEPT_ENCODING, "deflate;q=0, gzip;q=0.5".parse().unwrap());
 
     // when:
-    let actual = get_preferred_encoding(&headers);
+    let actual = get_preferred_encoding(&headers, &[]);
 
     // then:
     assert_eq!(actual, Some(GZIP));
   }
+
+  #[test]
+  fn test_get_preferred_encoding_algorithms_order() {
+    // given:
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, "gzip, deflate, br".parse().unwrap());
+
+    // when:
+    let actual = get_preferred_encoding(&headers, &[BROTLI, GZIP]);
+
+    // then:
+    assert_eq!(actual, Some(BROTLI));
+  }
+
+  #[test]
+  fn test_get_preferred_encoding_algorithms_qvalues() {
+    // given:
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, "gzip;q=1, br;q=0.5".parse().unwrap());
+
+    // when:
+    let actual = get_preferred_encoding(&headers, &[BROTLI, GZIP]);
+
+    // then:
+    assert_eq!(actual, Some(GZIP));
+  }
+
+  #[test]
+  fn test_get_preferred_encoding_unsupported_algorithm() {
+    // given:
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_ENCODING, "deflate".parse().unwrap());
+
+    // when:
+    let actual = get_preferred_encoding(&headers, &[BROTLI, GZIP]);
+
+    // then:
+    assert_eq!(actual, None);
+  }
+
+  #[test]
+  fn test_try_from_table() {
+    // given:
+    let table: Table = toml::from_str(
+      r#"level = "best"
+algorithms = ["br", "gzip"]"#,
+    )
+    .unwrap();
+
+    // when:
+    let actual = Compression::try_from(table).unwrap();
+
+    // then:
+    assert!(matches!(actual.level, Level::Best));
+    assert_eq!(actual.algorithms, vec![BROTLI, GZIP]);
+  }
+
+  #[test]
+  fn test_try_from_table_unknown_algorithm() {
+    // given:
+    let table: Table = toml::from_str(r#"algorithms = ["zstd"]"#).unwrap();
+
+    // when:
+    let actual = Compression::try_from(table);
+
+    // then:
+    assert!(actual.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_compress_chunked_response() {
+    // given:
+    let chunks = vec!["<html>", "<body>Hello", " World</body>", "</html>"];
+    let body = Body::wrap_stream(futures::stream::iter(
+      chunks.clone().into_iter().map(Ok::<_, io::Error>),
+    ));
+    let response = Response::builder()
+      .header(CONTENT_TYPE, "text/html")
+      .body(body)
+      .unwrap();
+
+    // when:
+    let response = Compression::default().compress_response(response, &GZIP);
+
+    // then:
+    assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+    assert_eq!(response.headers().get(CONTENT_LENGTH), None);
+    assert_eq!(response.headers().get(VARY).unwrap(), "accept-encoding");
+    let compressed = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let mut decoder = async_compression::tokio::bufread::GzipDecoder::new(&compressed[..]);
+    let mut decompressed = String::new();
+    tokio::io::AsyncReadExt::read_to_string(&mut decoder, &mut decompressed)
+      .await
+      .unwrap();
+    assert_eq!(decompressed, chunks.concat());
+  }
+
+  fn compression(min_size: u64, content_types: &[&str]) -> Compression {
+    Compression {
+      min_size,
+      content_types: content_types.iter().map(|it| it.to_string()).collect(),
+      ..Compression::default()
+    }
+  }
+
+  #[test]
+  fn test_should_compress_without_restrictions() {
+    // given:
+    let headers = HeaderMap::new();
+
+    // when:
+    let actual = Compression::default().should_compress(&headers);
+
+    // then:
+    assert!(actual);
+  }
+
+  #[test]
+  fn test_should_compress_already_encoded() {
+    // given:
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_ENCODING, "gzip".parse().unwrap());
+
+    // when:
+    let actual = Compression::default().should_compress(&headers);
+
+    // then:
+    assert!(!actual);
+  }
+
+  #[test]
+  fn test_should_compress_below_min_size() {
+    // given:
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, "1023".parse().unwrap());
+
+    // when:
+    let actual = compression(1024, &[]).should_compress(&headers);
+
+    // then:
+    assert!(!actual);
+  }
+
+  #[test]
+  fn test_should_compress_unknown_size() {
+    // given:
+    let headers = HeaderMap::new();
+
+    // when:
+    let actual = compression(1024, &[]).should_compress(&headers);
+
+    // then:
+    assert!(actual);
+  }
+
+  #[test]
+  fn test_should_compress_allowed_content_type() {
+    // given:
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, "Application/JSON; charset=utf-8".parse().unwrap());
+
+    // when:
+    let actual = compression(0, &["text/*", "application/json"]).should_compress(&headers);
+
+    // then:
+    assert!(actual);
+  }
+
+  #[test]
+  fn test_should_compress_wildcard_content_type() {
+    // given:
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, "text/html".parse().unwrap());
+
+    // when:
+    let actual = compression(0, &["text/*", "application/json"]).should_compress(&headers);
+
+    // then:
+    assert!(actual);
+  }
+
+  #[test]
+  fn test_should_compress_disallowed_content_type() {
+    // given:
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, "image/png".parse().unwrap());
+
+    // when:
+    let actual = compression(0, &["text/*", "application/json"]).should_compress(&headers);
+
+    // then:
+    assert!(!actual);
+  }
 }
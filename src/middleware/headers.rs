@@ -0,0 +1,353 @@
+use super::{Context, Middleware, MiddlewareChain};
+use crate::geoip::GeoInfo;
+use async_trait::async_trait;
+use hyper::{
+  header::{HeaderName, HeaderValue, HOST},
+  Body, HeaderMap, Request, Response,
+};
+use std::convert::TryFrom;
+use toml::{value::Table, Value};
+
+#[derive(Debug)]
+pub struct Headers {
+  request: Vec<HeaderRule>,
+  response: Vec<HeaderRule>,
+}
+
+#[async_trait]
+impl Middleware for Headers {
+  async fn forward_request(
+    &self,
+    mut request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    // The variables refer to the original request, even if a rule changes the host
+    let geo_info = request.extensions().get::<GeoInfo>().cloned().unwrap_or_default();
+    let variables = Variables {
+      client_ip: context.client_address.ip().to_string(),
+      client_country: geo_info.country.unwrap_or_default(),
+      client_continent: geo_info.continent.unwrap_or_default(),
+      host: request
+        .headers()
+        .get(HOST)
+        .and_then(|it| it.to_str().ok())
+        .unwrap_or_default()
+        .to_string(),
+    };
+    apply_rules(&self.request, request.headers_mut(), &variables);
+    let mut response = chain.forward_request(request, context).await;
+    apply_rules(&self.response, response.headers_mut(), &variables);
+    response
+  }
+}
+
+/// A single header transformation. Header names are matched case-insensitively.
+#[derive(Debug, PartialEq)]
+enum HeaderRule {
+  /// Adds the header only if it is absent.
+  Add(HeaderName, ValueTemplate),
+  /// Overrides all existing values of the header.
+  Set(HeaderName, ValueTemplate),
+  /// Removes all values of the header.
+  Remove(HeaderName),
+}
+
+/// The values, which can be referenced in a [`ValueTemplate`].
+#[derive(Debug, Default)]
+struct Variables {
+  /// `$client_ip`
+  client_ip: String,
+  /// `$client_country`, empty if unknown
+  client_country: String,
+  /// `$client_continent`, empty if unknown
+  client_continent: String,
+  /// `$host`
+  host: String,
+}
+
+/// A header value, which may contain the variables `$client_ip`,
+/// `$client_country`, `$client_continent` and `$host`.
+#[derive(Debug, PartialEq)]
+struct ValueTemplate(HeaderValue);
+
+impl ValueTemplate {
+  fn render(&self, variables: &Variables) -> Option<HeaderValue> {
+    let template = self.0.to_str().ok().filter(|it| it.contains('$'));
+    match template {
+      Some(template) => {
+        let value = template
+          .replace("$client_ip", &variables.client_ip)
+          .replace("$client_country", &variables.client_country)
+          .replace("$client_continent", &variables.client_continent)
+          .replace("$host", &variables.host);
+        HeaderValue::try_from(value).ok()
+      }
+      None => Some(self.0.clone()),
+    }
+  }
+}
+
+fn apply_rules(rules: &[HeaderRule], headers: &mut HeaderMap, variables: &Variables) {
+  for rule in rules {
+    match rule {
+      HeaderRule::Add(name, value) => {
+        if !headers.contains_key(name) {
+          if let Some(value) = value.render(variables) {
+            headers.insert(name.clone(), value);
+          }
+        }
+      }
+      HeaderRule::Set(name, value) => {
+        if let Some(value) = value.render(variables) {
+          headers.insert(name.clone(), value);
+        }
+      }
+      HeaderRule::Remove(name) => {
+        headers.remove(name);
+      }
+    }
+  }
+}
+
+impl TryFrom<Table> for Headers {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    Ok(Headers {
+      request: parse_rules(t.get("request"))?,
+      response: parse_rules(t.get("response"))?,
+    })
+  }
+}
+
+fn parse_rules(rules: Option<&Value>) -> Result<Vec<HeaderRule>, ()> {
+  match rules {
+    Some(rules) => rules.as_array().ok_or(())?.iter().map(parse_rule).collect(),
+    None => Ok(Vec::new()),
+  }
+}
+
+fn parse_rule(rule: &Value) -> Result<HeaderRule, ()> {
+  let rule = rule.as_table().ok_or(())?;
+  let header_name = |key: &str| HeaderName::try_from(rule.get(key)?.as_str()?).ok();
+  let header_value = || {
+    HeaderValue::try_from(rule.get("value")?.as_str()?)
+      .ok()
+      .map(ValueTemplate)
+  };
+
+  if let Some(name) = header_name("add") {
+    Ok(HeaderRule::Add(name, header_value().ok_or(())?))
+  } else if let Some(name) = header_name("set") {
+    Ok(HeaderRule::Set(name, header_value().ok_or(())?))
+  } else if let Some(name) = header_name("remove") {
+    Ok(HeaderRule::Remove(name))
+  } else {
+    Err(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    http_client::{BackendConnector, StrategyNotifyHttpConnector},
+    load_balancing::random::Random,
+    server::Scheme,
+  };
+  use hyper::{
+    service::{make_service_fn, service_fn},
+    Client, Server,
+  };
+  use std::{convert::Infallible, sync::Arc};
+
+  fn rules(toml: &str) -> Vec<HeaderRule> {
+    let table: Table = toml::from_str(toml).unwrap();
+    parse_rules(table.get("rules")).unwrap()
+  }
+
+  #[test]
+  fn test_parse_rules() {
+    let rules = rules(
+      r#"rules = [
+        { add = "Strict-Transport-Security", value = "max-age=63072000" },
+        { set = "Host", value = "legacy.localhost" },
+        { remove = "Server" },
+      ]"#,
+    );
+
+    assert_eq!(
+      rules,
+      vec![
+        HeaderRule::Add(
+          HeaderName::from_static("strict-transport-security"),
+          ValueTemplate(HeaderValue::from_static("max-age=63072000"))
+        ),
+        HeaderRule::Set(
+          HeaderName::from_static("host"),
+          ValueTemplate(HeaderValue::from_static("legacy.localhost"))
+        ),
+        HeaderRule::Remove(HeaderName::from_static("server")),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_parse_rules_missing_value() {
+    let table: Table = toml::from_str(r#"rules = [{ set = "Host" }]"#).unwrap();
+
+    assert_eq!(parse_rules(table.get("rules")), Err(()));
+  }
+
+  #[test]
+  fn test_apply_add_only_if_absent() {
+    let mut headers = HeaderMap::new();
+    headers.insert("x-env", "staging".parse().unwrap());
+
+    apply_rules(
+      &rules(r#"rules = [{ add = "X-Env", value = "prod" }]"#),
+      &mut headers,
+      &Variables::default(),
+    );
+    assert_eq!(headers.get("x-env").unwrap(), "staging");
+
+    headers.clear();
+    apply_rules(
+      &rules(r#"rules = [{ add = "X-Env", value = "prod" }]"#),
+      &mut headers,
+      &Variables::default(),
+    );
+    assert_eq!(headers.get("x-env").unwrap(), "prod");
+  }
+
+  #[test]
+  fn test_apply_set_overrides_all_values() {
+    let mut headers = HeaderMap::new();
+    headers.append("x-env", "staging".parse().unwrap());
+    headers.append("x-env", "dev".parse().unwrap());
+
+    apply_rules(
+      &rules(r#"rules = [{ set = "x-env", value = "prod" }]"#),
+      &mut headers,
+      &Variables::default(),
+    );
+
+    assert_eq!(headers.get_all("x-env").iter().collect::<Vec<_>>(), vec!["prod"]);
+  }
+
+  #[test]
+  fn test_apply_remove_is_case_insensitive() {
+    let mut headers = HeaderMap::new();
+    headers.insert("server", "nginx".parse().unwrap());
+
+    apply_rules(
+      &rules(r#"rules = [{ remove = "SERVER" }]"#),
+      &mut headers,
+      &Variables::default(),
+    );
+
+    assert!(headers.get("server").is_none());
+  }
+
+  #[test]
+  fn test_apply_rules_in_order() {
+    let mut headers = HeaderMap::new();
+
+    apply_rules(
+      &rules(r#"rules = [{ set = "x-env", value = "prod" }, { remove = "x-env" }, { add = "x-env", value = "dev" }]"#),
+      &mut headers,
+      &Variables::default(),
+    );
+
+    assert_eq!(headers.get("x-env").unwrap(), "dev");
+  }
+
+  #[test]
+  fn test_apply_substitutes_variables() {
+    let mut headers = HeaderMap::new();
+    let variables = Variables {
+      client_ip: "10.0.0.1".into(),
+      client_country: "DE".into(),
+      client_continent: "EU".into(),
+      host: "whoami.localhost".into(),
+    };
+
+    apply_rules(
+      &rules(
+        r#"rules = [
+          { set = "X-Client", value = "$client_ip via $host" },
+          { set = "X-Client-Country", value = "$client_country ($client_continent)" },
+        ]"#,
+      ),
+      &mut headers,
+      &variables,
+    );
+
+    assert_eq!(headers.get("x-client").unwrap(), "10.0.0.1 via whoami.localhost");
+    assert_eq!(headers.get("x-client-country").unwrap(), "DE (EU)");
+  }
+
+  #[test]
+  fn test_apply_preserves_untouched_headers() {
+    let mut headers = HeaderMap::new();
+    headers.append("accept", "text/html".parse().unwrap());
+    headers.append("x-forwarded-host", "evil.example.com".parse().unwrap());
+    headers.append("accept", "application/json".parse().unwrap());
+
+    apply_rules(
+      &rules(r#"rules = [{ remove = "X-Forwarded-Host" }]"#),
+      &mut headers,
+      &Variables::default(),
+    );
+
+    assert_eq!(
+      headers.get_all("accept").iter().collect::<Vec<_>>(),
+      vec!["text/html", "application/json"]
+    );
+    assert_eq!(headers.len(), 2);
+  }
+
+  #[tokio::test]
+  async fn test_remove_untrusted_forwarded_for() {
+    // given: a backend server, which responds with the x-forwarded-for header it received
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend_address = listener.local_addr().unwrap();
+    let service = make_service_fn(|_| async {
+      Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+        let forwarded_for = request.headers().get("x-forwarded-for").unwrap().as_bytes().to_vec();
+        Ok::<_, Infallible>(Response::new(Body::from(forwarded_for)))
+      }))
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+
+    let table: Table = toml::from_str(r#"request = [{ remove = "X-Forwarded-For" }]"#).unwrap();
+    let chain = MiddlewareChain::Entry {
+      middleware: Box::new(Headers::try_from(table).unwrap()),
+      chain: Box::new(MiddlewareChain::Empty),
+    };
+    let client = Client::builder().build(StrategyNotifyHttpConnector::new(
+      BackendConnector::new(),
+      Arc::new(Box::new(Random::new())),
+      Default::default(),
+    ));
+    let context = Context {
+      client_scheme: &Scheme::HTTP,
+      client_address: &"10.0.0.1:3000".parse().unwrap(),
+      backend_uri: format!("http://{}/", backend_address).parse().unwrap(),
+      client: &client,
+      response_timeout: None,
+    };
+    let request = Request::builder()
+      .header("x-forwarded-for", "1.2.3.4")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = chain.forward_request(request, &context).await;
+
+    // then: the spoofed address is dropped and only the client address is forwarded
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "10.0.0.1");
+  }
+}
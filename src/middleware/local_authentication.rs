@@ -0,0 +1,343 @@
+use super::{Context, Middleware};
+use crate::bans::{Violation, OFFENDERS};
+use async_trait::async_trait;
+use http_auth_basic::Credentials;
+use hyper::{
+  header::{HeaderName, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE},
+  Body, HeaderMap, Request, Response, StatusCode,
+};
+use log::{error, warn};
+use std::{
+  collections::HashMap,
+  convert::TryFrom,
+  fs, io,
+  path::{Path, PathBuf},
+};
+use toml::{value::Table, Value};
+
+/// Authenticates requests with credentials of the configuration instead of an
+/// external server: HTTP Basic Auth with the users of a htpasswd file (bcrypt
+/// only) and/or a list of bearer tokens.
+#[derive(Debug)]
+pub struct LocalAuthentication {
+  realm: String,
+  /// The bcrypt hashes of the passwords by user.
+  users: HashMap<String, String>,
+  bearer_tokens: Vec<String>,
+  /// Set to the name of the authenticated user. Values sent by the client are
+  /// always removed.
+  user_header: Option<HeaderName>,
+}
+
+/// The identity of an authenticated request.
+#[derive(Debug, PartialEq)]
+enum Principal {
+  User(String),
+  BearerToken,
+}
+
+#[async_trait]
+impl Middleware for LocalAuthentication {
+  async fn modify_request(
+    &self,
+    mut request: Request<Body>,
+    context: &Context<'_>,
+  ) -> Result<Request<Body>, Response<Body>> {
+    let principal = match self.authenticate(request.headers()).await {
+      Some(principal) => principal,
+      None => {
+        // Requests without credentials are just asked for them
+        if request.headers().contains_key(AUTHORIZATION) {
+          OFFENDERS.record(context.client_address.ip(), Violation::AuthenticationFailed);
+        }
+        return Err(self.unauthorized());
+      }
+    };
+    if let Some(user_header) = &self.user_header {
+      request.headers_mut().remove(user_header);
+      if let Principal::User(user) = principal {
+        if let Ok(user) = HeaderValue::try_from(user) {
+          request.headers_mut().insert(user_header.clone(), user);
+        }
+      }
+    }
+    Ok(request)
+  }
+}
+
+impl LocalAuthentication {
+  async fn authenticate(&self, headers: &HeaderMap) -> Option<Principal> {
+    let authorization = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = authorization.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+      let token = credentials.trim();
+      // Compare all tokens in constant time, so the time does not reveal how much of a token matched
+      let valid = self.bearer_tokens.iter().fold(false, |valid, it| {
+        constant_time_eq(it.as_bytes(), token.as_bytes()) | valid
+      });
+      return Some(Principal::BearerToken).filter(|_| valid);
+    }
+    verify_basic_credentials(&self.users, authorization)
+      .await
+      .map(Principal::User)
+  }
+
+  fn unauthorized(&self) -> Response<Body> {
+    let mut response = Response::builder().status(StatusCode::UNAUTHORIZED);
+    if !self.users.is_empty() {
+      response = response.header(WWW_AUTHENTICATE, format!("Basic realm=\"{}\"", self.realm));
+    }
+    if !self.bearer_tokens.is_empty() {
+      response = response.header(WWW_AUTHENTICATE, format!("Bearer realm=\"{}\"", self.realm));
+    }
+    response.body(Body::empty()).unwrap()
+  }
+}
+
+/// Verifies the HTTP Basic Auth `authorization` against the bcrypt hashes of
+/// the passwords of `users`. Returns the name of the authenticated user.
+pub(crate) async fn verify_basic_credentials(users: &HashMap<String, String>, authorization: &str) -> Option<String> {
+  let credentials = Credentials::from_header(authorization.to_string()).ok()?;
+  let hash = users.get(&credentials.user_id)?.clone();
+  // bcrypt is slow by design, so it must not block other requests
+  let password = credentials.password;
+  let verified = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash)).await;
+  match verified {
+    Ok(Ok(true)) => Some(credentials.user_id),
+    Ok(Ok(false)) => {
+      warn!("Invalid password for user '{}'", credentials.user_id);
+      None
+    }
+    Ok(Err(e)) => {
+      error!("Could not verify password of user '{}': {}", credentials.user_id, e);
+      None
+    }
+    Err(e) => {
+      error!("Could not verify password of user '{}': {}", credentials.user_id, e);
+      None
+    }
+  }
+}
+
+pub(crate) fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+  left.len() == right.len() && left.iter().zip(right).fold(0, |diff, (l, r)| diff | (l ^ r)) == 0
+}
+
+/// Reads the users of a htpasswd file, whose lines have the format
+/// `user:hash`. Empty lines and lines starting with `#` are ignored.
+pub(crate) fn read_htpasswd(path: &Path) -> Result<HashMap<String, String>, io::Error> {
+  let content = fs::read_to_string(path)
+    .map_err(|e| io::Error::new(e.kind(), format!("Could not read '{}': {}", path.display(), e)))?;
+  parse_htpasswd(&content).map_err(|e| {
+    io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("Invalid htpasswd file '{}': {}", path.display(), e),
+    )
+  })
+}
+
+fn parse_htpasswd(content: &str) -> Result<HashMap<String, String>, String> {
+  let mut users = HashMap::new();
+  for (index, line) in content.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let error = |message: &str| format!("line {}: {}", index + 1, message);
+    let (user, hash) = line.split_once(':').ok_or_else(|| error("expected 'user:hash'"))?;
+    if user.is_empty() {
+      return Err(error("the user is empty"));
+    }
+    if !["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|it| hash.starts_with(it)) {
+      return Err(error("only bcrypt hashes are supported (htpasswd -B)"));
+    }
+    if users.insert(user.to_string(), hash.to_string()).is_some() {
+      return Err(error(&format!("the user '{}' is defined twice", user)));
+    }
+  }
+  Ok(users)
+}
+
+impl TryFrom<Table> for LocalAuthentication {
+  type Error = io::Error;
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let invalid_data = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let users = match t.get("htpasswd_path") {
+      Some(Value::String(path)) => read_htpasswd(&PathBuf::from(path))?,
+      Some(_) => return Err(invalid_data("The htpasswd_path must be a string")),
+      None => HashMap::new(),
+    };
+    let bearer_tokens = match t.get("bearer_tokens") {
+      Some(Value::Array(tokens)) => tokens
+        .iter()
+        .map(|it| it.as_str().map(str::to_string))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid_data("The bearer_tokens must be strings"))?,
+      Some(_) => return Err(invalid_data("The bearer_tokens must be a list of strings")),
+      None => Vec::new(),
+    };
+    if users.is_empty() && bearer_tokens.is_empty() {
+      return Err(invalid_data(
+        "Either users (htpasswd_path) or bearer_tokens are required",
+      ));
+    }
+    let realm = match t.get("realm") {
+      Some(realm) => realm
+        .as_str()
+        .ok_or_else(|| invalid_data("The realm must be a string"))?,
+      None => "Another Rust Load Balancer requires authentication",
+    };
+    if realm.contains('"') {
+      return Err(invalid_data("The realm must not contain '\"'"));
+    }
+    let user_header = t
+      .get("user_header")
+      .map(|it| it.as_str().and_then(|it| HeaderName::try_from(it).ok()))
+      .map(|it| it.ok_or_else(|| invalid_data("The user_header must be a header name")))
+      .transpose()?;
+    Ok(LocalAuthentication {
+      realm: realm.to_string(),
+      users,
+      bearer_tokens,
+      user_header,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    http_client::{BackendConnector, StrategyNotifyHttpConnector},
+    load_balancing::random::Random,
+    server::Scheme,
+  };
+  use hyper::Client;
+  use std::sync::Arc;
+
+  fn authentication(bearer_tokens: &[&str]) -> LocalAuthentication {
+    LocalAuthentication {
+      realm: "Dashboards".into(),
+      users: vec![("tyrion".to_string(), bcrypt::hash("foo", 4).unwrap())]
+        .into_iter()
+        .collect(),
+      bearer_tokens: bearer_tokens.iter().map(|it| it.to_string()).collect(),
+      user_header: Some(HeaderName::from_static("x-authenticated-user")),
+    }
+  }
+
+  fn headers(authorization: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+    headers
+  }
+
+  #[tokio::test]
+  async fn test_valid_basic_credentials() {
+    // given: tyrion:foo
+    let authentication = authentication(&[]);
+
+    // when:
+    let principal = authentication.authenticate(&headers("Basic dHlyaW9uOmZvbw==")).await;
+
+    // then:
+    assert_eq!(principal, Some(Principal::User("tyrion".into())));
+  }
+
+  #[tokio::test]
+  async fn test_invalid_basic_credentials() {
+    // given: tyrion:abr and unknown:foo
+    let authentication = authentication(&[]);
+
+    // when:
+    let wrong_password = authentication.authenticate(&headers("Basic dHlyaW9uOmFicg==")).await;
+    let unknown_user = authentication.authenticate(&headers("Basic dW5rbm93bjpmb28=")).await;
+
+    // then:
+    assert_eq!(wrong_password, None);
+    assert_eq!(unknown_user, None);
+  }
+
+  #[tokio::test]
+  async fn test_valid_bearer_token() {
+    // given:
+    let authentication = authentication(&["s3cr3t", "fpKL54jvWmEGVoRdCNjG"]);
+
+    // when:
+    let valid = authentication
+      .authenticate(&headers("Bearer fpKL54jvWmEGVoRdCNjG"))
+      .await;
+    let invalid = authentication
+      .authenticate(&headers("Bearer fpKL54jvWmEGVoRdCNjX"))
+      .await;
+
+    // then:
+    assert_eq!(valid, Some(Principal::BearerToken));
+    assert_eq!(invalid, None);
+  }
+
+  #[tokio::test]
+  async fn test_authenticated_user_is_forwarded() {
+    // given: a client, which pretends to be another user
+    let authentication = authentication(&[]);
+    let client = Client::builder().build(StrategyNotifyHttpConnector::new(
+      BackendConnector::new(),
+      Arc::new(Box::new(Random::new())),
+      Default::default(),
+    ));
+    let context = Context {
+      client_scheme: &Scheme::HTTP,
+      client_address: &"127.0.0.1:3000".parse().unwrap(),
+      backend_uri: "http://127.0.0.1:1/".parse().unwrap(),
+      client: &client,
+      response_timeout: None,
+    };
+    let request = Request::builder()
+      .header(AUTHORIZATION, "Basic dHlyaW9uOmZvbw==")
+      .header("x-authenticated-user", "cersei")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let request = authentication.modify_request(request, &context).await.unwrap();
+
+    // then:
+    let users = request
+      .headers()
+      .get_all("x-authenticated-user")
+      .iter()
+      .collect::<Vec<_>>();
+    assert_eq!(users, vec!["tyrion"]);
+  }
+
+  #[test]
+  fn test_unauthorized_response() {
+    let response = authentication(&["s3cr3t"]).unauthorized();
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let challenges = response.headers().get_all(WWW_AUTHENTICATE).iter().collect::<Vec<_>>();
+    assert_eq!(
+      challenges,
+      vec!["Basic realm=\"Dashboards\"", "Bearer realm=\"Dashboards\""]
+    );
+  }
+
+  #[test]
+  fn test_parse_htpasswd() {
+    let users = parse_htpasswd("# admins\n\ntyrion:$2y$05$abc\ncersei:$2b$05$def\n").unwrap();
+
+    assert_eq!(users.len(), 2);
+    assert_eq!(users["tyrion"], "$2y$05$abc");
+  }
+
+  #[test]
+  fn test_parse_htpasswd_reports_line() {
+    let result = parse_htpasswd("tyrion:$2y$05$abc\n\ncersei:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n");
+
+    assert_eq!(
+      result.unwrap_err(),
+      "line 3: only bcrypt hashes are supported (htpasswd -B)"
+    );
+  }
+}
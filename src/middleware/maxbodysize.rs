@@ -1,10 +1,26 @@
 use super::{super::error_response, Context, Middleware};
+use crate::metrics::METRICS;
 use async_trait::async_trait;
+use futures::StreamExt;
 use hyper::{header::CONTENT_LENGTH, Body, HeaderMap, Request, Response};
+use log::warn;
+use std::convert::TryFrom;
+use thiserror::Error;
+use toml::value::Table;
+
+/// The error of a body without a Content-Length, which exceeded its limit
+/// while it was streamed.
+#[derive(Debug, Error)]
+#[error("{direction} body exceeds the limit of {limit} bytes")]
+pub struct BodyTooLarge {
+  pub direction: &'static str,
+  pub limit: u64,
+}
 
 #[derive(Debug)]
 pub struct MaxBodySize {
-  pub(crate) limit: i64,
+  pub(crate) limit: Option<u64>,
+  pub(crate) response_limit: Option<u64>,
 }
 
 #[async_trait]
@@ -14,20 +30,92 @@ impl Middleware for MaxBodySize {
     request: Request<Body>,
     _context: &Context<'_>,
   ) -> Result<Request<Body>, Response<Body>> {
+    let limit = match self.limit {
+      Some(limit) => limit,
+      None => return Ok(request),
+    };
     match get_content_length(request.headers()) {
-      Some(length) if length > self.limit => Err(error_response::request_entity_to_large()),
-      _ => Ok(request),
+      Some(length) if length > limit => {
+        warn!("Rejected request with a body of {} bytes (limit: {})", length, limit);
+        record_rejection("request");
+        Err(error_response::request_entity_to_large())
+      }
+      Some(_) => Ok(request),
+      // Without a Content-Length the size is only known while the body is forwarded
+      None => Ok(request.map(|body| limit_body(body, limit, "request"))),
+    }
+  }
+
+  async fn modify_response(&self, response: Response<Body>, _context: &Context<'_>) -> Response<Body> {
+    let limit = match self.response_limit {
+      Some(limit) => limit,
+      None => return response,
+    };
+    match get_content_length(response.headers()) {
+      Some(length) if length > limit => {
+        warn!("Rejected response with a body of {} bytes (limit: {})", length, limit);
+        record_rejection("response");
+        error_response::bad_gateway()
+      }
+      Some(_) => response,
+      None => response.map(|body| limit_body(body, limit, "response")),
     }
   }
 }
 
-fn get_content_length(headers: &HeaderMap) -> Option<i64> {
+impl TryFrom<Table> for MaxBodySize {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let parse_limit = |key: &str| match t.get(key) {
+      Some(limit) => limit
+        .as_integer()
+        .filter(|it| *it >= 0)
+        .map(|it| Some(it as u64))
+        .ok_or(()),
+      None => Ok(None),
+    };
+    Ok(MaxBodySize {
+      limit: parse_limit("limit")?,
+      response_limit: parse_limit("response_limit")?,
+    })
+  }
+}
+
+fn get_content_length(headers: &HeaderMap) -> Option<u64> {
   headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
 }
 
+/// Counts a body of the `direction` (`request` or `response`), which exceeded
+/// its limit.
+fn record_rejection(direction: &str) {
+  METRICS.increment("arlb_body_size_rejections_total", &[("direction", direction)]);
+}
+
+/// Streams the `body` until more than `limit` bytes were sent, at which point
+/// it is aborted. An aborted request body also aborts the request to the
+/// backend server, an aborted response body the connection to the client.
+fn limit_body(body: Body, limit: u64, direction: &'static str) -> Body {
+  let mut remaining = limit;
+  Body::wrap_stream(body.map(move |chunk| {
+    let chunk = chunk?;
+    if chunk.len() as u64 > remaining {
+      warn!(
+        "Aborted {} with a body exceeding the limit of {} bytes",
+        direction, limit
+      );
+      record_rejection(direction);
+      return Err(BodyTooLarge { direction, limit }.into());
+    }
+    remaining -= chunk.len() as u64;
+    Ok::<_, Box<dyn std::error::Error + Send + Sync>>(chunk)
+  }))
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
+  use std::error::Error;
 
   #[test]
   fn test_get_content_length_no_headers() {
@@ -66,4 +154,41 @@ mod tests {
     // then:
     assert_eq!(actual, Some(256));
   }
+
+  fn chunked_body(chunks: Vec<&'static str>) -> Body {
+    Body::wrap_stream(futures::stream::iter(
+      chunks.into_iter().map(Ok::<_, std::convert::Infallible>),
+    ))
+  }
+
+  #[tokio::test]
+  async fn test_limit_body_within_limit() {
+    // given:
+    let body = limit_body(chunked_body(vec!["hello ", "world"]), 11, "request");
+
+    // when:
+    let actual = hyper::body::to_bytes(body).await.unwrap();
+
+    // then:
+    assert_eq!(actual, "hello world");
+  }
+
+  #[tokio::test]
+  async fn test_limit_body_aborts_stream() {
+    // given:
+    let rejections = METRICS.counter("arlb_body_size_rejections_total", &[("direction", "response")]);
+    let body = limit_body(chunked_body(vec!["hello ", "world"]), 10, "response");
+
+    // when:
+    let actual = hyper::body::to_bytes(body).await;
+
+    // then:
+    let error = actual.unwrap_err();
+    let cause = error.source().and_then(|it| it.downcast_ref::<BodyTooLarge>());
+    assert!(cause.is_some(), "{:?}", error);
+    assert_eq!(
+      METRICS.counter("arlb_body_size_rejections_total", &[("direction", "response")]),
+      rejections + 1
+    );
+  }
 }
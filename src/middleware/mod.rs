@@ -1,17 +1,35 @@
 use crate::{
-  error_response::handle_bad_gateway, http_client::StrategyNotifyHttpConnector, server::Scheme, utils::unwrap_result,
+  error_response::{bad_request, gateway_timeout, handle_bad_gateway, request_entity_to_large},
+  http_client::StrategyNotifyHttpConnector,
+  metrics::METRICS,
+  server::Scheme,
+  static_response::LocalResponse,
+  trusted_proxies::ForwardedBy,
+  utils::unwrap_result,
 };
 use async_trait::async_trait;
 use gethostname::gethostname;
-use hyper::{header::HeaderValue, Body, Client, Request, Response, Uri};
-use std::net::SocketAddr;
+use hyper::{
+  header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, TE},
+  Body, Client, HeaderMap, Request, Response, StatusCode, Uri, Version,
+};
+use log::{debug, warn};
+use maxbodysize::BodyTooLarge;
+use std::{error::Error, io, net::SocketAddr, time::Duration};
 
 pub mod authentication;
+pub mod backend_authentication;
+pub mod cache;
 pub mod compression;
 pub mod custom_error_pages;
+pub mod headers;
 pub mod https_redirector;
+pub mod local_authentication;
 pub mod maxbodysize;
 pub mod rate_limiter;
+pub mod request_id;
+pub mod security_headers;
+pub mod tls_headers;
 
 /// A trait for implementing middlewares, see
 /// [`forward_request`](Middleware::forward_request) for more details.
@@ -87,8 +105,15 @@ pub struct Context<'l> {
   pub client_address: &'l SocketAddr,
   pub backend_uri: Uri,
   pub client: &'l Client<StrategyNotifyHttpConnector, Body>,
+  /// How long the backend server may take to send the response head, see
+  /// [`ResponseTimedOut`].
+  pub response_timeout: Option<Duration>,
 }
 
+/// Marks the `504` response sent, because the backend server did not send
+/// the response head within the `response_timeout` of its pool.
+pub struct ResponseTimedOut(pub Duration);
+
 /// A singly linked list of [`Middleware`]s.
 ///
 /// This list is used to call all middlewares of a
@@ -110,39 +135,87 @@ impl MiddlewareChain {
   /// passing it the tail of this chain as an argument to be called recursively.
   ///
   /// Once this chain is empty this function does the final request
-  /// transformation, setting all appropriate forwarding headers (like
-  /// `x-forwarded-for`) and sends it to the backend server, returning the
-  /// response.
+  /// transformation, removing hop-by-hop headers and setting all appropriate
+  /// forwarding headers (like `x-forwarded-for`) and sends it to the backend
+  /// server, returning the response without its hop-by-hop headers and
+  /// framing (see [`reframe`]).
   pub async fn forward_request(&self, request: Request<Body>, context: &Context<'_>) -> Response<Body> {
     match self {
       MiddlewareChain::Entry { middleware, chain } => middleware.forward_request(request, &chain, &context).await,
       MiddlewareChain::Empty => {
         let backend_request = backend_request(request, context);
-        unwrap_result(
-          context
-            .client
-            .request(backend_request)
-            .await
-            .map_err(handle_bad_gateway),
-        )
+        let response = context.client.request(backend_request);
+        let response = match context.response_timeout {
+          Some(response_timeout) => match tokio::time::timeout(response_timeout, response).await {
+            Ok(response) => response,
+            Err(_) => {
+              let mut response = gateway_timeout();
+              response.extensions_mut().insert(ResponseTimedOut(response_timeout));
+              return response;
+            }
+          },
+          None => response.await,
+        };
+        let response = response.map(|mut response| {
+          reframe(&mut response);
+          response
+        });
+        unwrap_result(response.map_err(|e| {
+          if let Some(cause) = request_body_too_large(&e) {
+            reject_request_body_size(cause)
+          } else if let Some(cause) = invalid_request_body(&e) {
+            reject_request_body(cause)
+          } else {
+            handle_bad_gateway(e)
+          }
+        }))
       }
     }
   }
+
+  /// Appends `middleware` as the last entry, which is called right before the
+  /// request is sent to the backend server.
+  pub fn append(self, middleware: Box<dyn Middleware>) -> MiddlewareChain {
+    match self {
+      MiddlewareChain::Empty => MiddlewareChain::Entry {
+        middleware,
+        chain: Box::new(MiddlewareChain::Empty),
+      },
+      MiddlewareChain::Entry {
+        middleware: first,
+        chain,
+      } => MiddlewareChain::Entry {
+        middleware: first,
+        chain: Box::new(chain.append(middleware)),
+      },
+    }
+  }
 }
 
 fn backend_request(request: Request<Body>, context: &Context) -> Request<Body> {
   let builder = Request::builder().uri(&context.backend_uri);
 
-  let mut builder = request
-    .headers()
+  let mut headers = request.headers().clone();
+  let accepts_trailers = accepts_trailers(&headers);
+  remove_hop_by_hop_headers(&mut headers);
+  // The chain of a trusted proxy is continued with its address instead of
+  // the one of the client named by it
+  let forwarded_by = request
+    .extensions()
+    .get::<ForwardedBy>()
+    .map_or(*context.client_address, |it| it.0);
+  let forwarded_for = headers.remove("x-forwarded-for");
+  // gRPC requires `te: trailers`, which is the only value allowed by HTTP/2
+  if accepts_trailers {
+    headers.insert(TE, HeaderValue::from_static("trailers"));
+  }
+
+  let mut builder = headers
     .iter()
     .fold(builder, |builder, (key, val)| builder.header(key, val))
     .header(
       "x-forwarded-for",
-      forwarded_for_header(
-        request.headers().get("x-forwarded-for"),
-        context.client_address.ip().to_string(),
-      ),
+      forwarded_for_header(forwarded_for.as_ref(), forwarded_by.ip().to_string()),
     )
     .header("x-real-ip", context.client_address.ip().to_string())
     .header(
@@ -164,6 +237,116 @@ fn backend_request(request: Request<Body>, context: &Context) -> Request<Body> {
   builder.body(request.into_body()).unwrap()
 }
 
+/// The reason, why the body of the request could not be read from the client,
+/// if that is why forwarding it failed. The HTTP parser decodes chunked bodies
+/// and fails on malformed framing, like an invalid chunk size, which is not the
+/// fault of the backend server.
+fn invalid_request_body(error: &hyper::Error) -> Option<&io::Error> {
+  if !error.is_user() {
+    return None;
+  }
+  let mut source = error.source();
+  while let Some(cause) = source {
+    if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+      return Some(io_error).filter(|it| it.kind() == io::ErrorKind::InvalidInput);
+    }
+    source = cause.source();
+  }
+  None
+}
+
+/// The limit, which the body of the request exceeded while it was forwarded,
+/// if that is why forwarding it failed. Like a malformed body, this is not the
+/// fault of the backend server.
+fn request_body_too_large(error: &hyper::Error) -> Option<&BodyTooLarge> {
+  let mut source = error.source();
+  while let Some(cause) = source {
+    if let Some(too_large) = cause.downcast_ref::<BodyTooLarge>() {
+      return Some(too_large);
+    }
+    source = cause.source();
+  }
+  None
+}
+
+/// Answers a request, whose body exceeded the limit of a [`MaxBodySize`]
+/// middleware, with `413 Payload Too Large`.
+///
+/// [`MaxBodySize`]: maxbodysize::MaxBodySize
+fn reject_request_body_size(cause: &BodyTooLarge) -> Response<Body> {
+  debug!("Rejected a request with 413 Payload Too Large: {}", cause);
+  let mut response = request_entity_to_large();
+  response.extensions_mut().insert(LocalResponse);
+  response
+}
+
+/// Answers a request, whose body is malformed, with `400 Bad Request`. The
+/// backend server received an incomplete body at most, whose connection is
+/// closed instead of completing the framing.
+fn reject_request_body(cause: &io::Error) -> Response<Body> {
+  warn!("Rejected a request with a malformed body: {}", cause);
+  METRICS.increment("arlb_rejected_requests_total", &[("reason", "invalid_chunked_body")]);
+  let mut response = bad_request("Malformed request body");
+  response.extensions_mut().insert(LocalResponse);
+  response
+}
+
+/// Headers which are only meaningful for a single connection and must not be
+/// forwarded by proxies, see [RFC 7230](https://tools.ietf.org/html/rfc7230#section-6.1).
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+  "connection",
+  "keep-alive",
+  "proxy-authenticate",
+  "proxy-authorization",
+  "proxy-connection",
+  "te",
+  "trailer",
+  "transfer-encoding",
+  "upgrade",
+];
+
+/// Removes all hop-by-hop headers, including the ones listed in the
+/// `connection` header.
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+  let connection_headers = headers
+    .get_all(CONNECTION)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+    .collect::<Vec<_>>();
+  for name in connection_headers {
+    headers.remove(name);
+  }
+  for name in HOP_BY_HOP_HEADERS.iter() {
+    headers.remove(*name);
+  }
+}
+
+/// Detaches the framing of the response of a backend server from the client
+/// connection, which frames the body on its own: the connection of a legacy
+/// backend server, which answers via HTTP/1.0 or ends the body by closing the
+/// connection, is kept alive towards the client. `204 No Content` responses
+/// have no body, even if the backend server announced one.
+fn reframe(response: &mut Response<Body>) {
+  remove_hop_by_hop_headers(response.headers_mut());
+  *response.version_mut() = Version::HTTP_11;
+  if response.status() == StatusCode::NO_CONTENT {
+    response.headers_mut().remove(CONTENT_LENGTH);
+  }
+}
+
+/// Whether the client accepts trailers in the response, which is the only part
+/// of the `te` header that is forwarded to the backend server.
+pub(crate) fn accepts_trailers(headers: &HeaderMap) -> bool {
+  headers
+    .get_all(TE)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(','))
+    .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+}
+
 // According to https://docs.oracle.com/en-us/iaas/Content/Balance/Reference/httpheaders.htm
 fn forwarded_for_header(existing_forwarded_for: Option<&HeaderValue>, client_ip: String) -> String {
   match existing_forwarded_for {
@@ -193,4 +376,31 @@ mod tests {
 
     assert_eq!(forwarded_for_header, "127.0.0.2, 127.0.0.1");
   }
+
+  #[test]
+  fn test_remove_hop_by_hop_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert("connection", "keep-alive, X-Custom".parse().unwrap());
+    headers.insert("keep-alive", "timeout=5".parse().unwrap());
+    headers.insert("transfer-encoding", "chunked".parse().unwrap());
+    headers.insert("upgrade", "websocket".parse().unwrap());
+    headers.insert("x-custom", "value".parse().unwrap());
+    headers.insert("x-other", "value".parse().unwrap());
+
+    remove_hop_by_hop_headers(&mut headers);
+
+    assert_eq!(headers.keys().collect::<Vec<_>>(), vec!["x-other"]);
+  }
+
+  #[test]
+  fn test_accepts_trailers() {
+    let mut headers = HeaderMap::new();
+    assert!(!accepts_trailers(&headers));
+
+    headers.insert(TE, "gzip, trailers".parse().unwrap());
+    assert!(accepts_trailers(&headers));
+
+    headers.insert(TE, "gzip".parse().unwrap());
+    assert!(!accepts_trailers(&headers));
+  }
 }
@@ -1,14 +1,23 @@
 use super::{Context, Middleware};
-use crate::error_response::too_many_requests;
+use crate::{
+  bans::{Violation, OFFENDERS},
+  error_response::too_many_requests,
+  static_response::LocalResponse,
+};
 use async_trait::async_trait;
-use hyper::{Body, Request, Response};
+use hyper::{
+  header::{HeaderValue, RETRY_AFTER},
+  Body, Request, Response,
+};
 use linked_hash_map::LinkedHashMap;
-use std::net::SocketAddr;
+use std::net::IpAddr;
 use tokio::{sync::Mutex, time::Instant};
 
+/// Limits the requests of each client IP address. A client is only allowed to
+/// send requests again after it sent no request for `window_sec` seconds.
 #[derive(Debug)]
 pub struct RateLimiter {
-  connections: Mutex<LinkedHashMap<SocketAddr, (u64, Instant)>>,
+  connections: Mutex<LinkedHashMap<IpAddr, (u64, Instant)>>,
   limit: u64,
   window_sec: u64,
 }
@@ -22,7 +31,7 @@ impl RateLimiter {
     }
   }
 
-  async fn register_request(&self, client_address: &SocketAddr) -> bool {
+  async fn register_request(&self, client_address: &IpAddr) -> bool {
     let mut connections = self.connections.lock().await;
     let now = Instant::now();
 
@@ -58,10 +67,16 @@ impl Middleware for RateLimiter {
     request: Request<Body>,
     context: &Context<'_>,
   ) -> Result<Request<Body>, Response<Body>> {
-    if self.register_request(context.client_address).await {
+    if self.register_request(&context.client_address.ip()).await {
       Ok(request)
     } else {
-      Err(too_many_requests())
+      OFFENDERS.record(context.client_address.ip(), Violation::RateLimited);
+      let mut response = too_many_requests();
+      response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(self.window_sec));
+      response.extensions_mut().insert(LocalResponse);
+      Err(response)
     }
   }
 }
@@ -0,0 +1,188 @@
+use super::{Context, Middleware, MiddlewareChain};
+use crate::logging;
+use async_trait::async_trait;
+use hyper::{
+  header::{HeaderName, HeaderValue},
+  Body, Request, Response,
+};
+use log::debug;
+use std::{convert::TryFrom, fmt::Display};
+use toml::value::Table;
+
+/// The id of a request, which is stored in the request extensions by the
+/// [`RequestId`] middleware, so that later stages can refer to it (for example
+/// when logging errors).
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestIdentifier(String);
+
+impl Display for RequestIdentifier {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+#[derive(Debug)]
+pub struct RequestId {
+  header: HeaderName,
+  trust_incoming: bool,
+  echo: bool,
+}
+
+#[async_trait]
+impl Middleware for RequestId {
+  async fn forward_request(
+    &self,
+    mut request: Request<Body>,
+    chain: &MiddlewareChain,
+    context: &Context<'_>,
+  ) -> Response<Body> {
+    let request_id = self.assign_request_id(&mut request);
+    logging::add_field("request_id", &request_id);
+    debug!(
+      "Request id {} assigned to {} {}",
+      request_id,
+      request.method(),
+      request.uri()
+    );
+    let response = chain.forward_request(request, context).await;
+    if self.echo {
+      self.echo_request_id(response, &request_id)
+    } else {
+      response
+    }
+  }
+}
+
+impl RequestId {
+  /// Reuses the incoming request id if it is trusted or generates a new one.
+  /// The id is set as header and stored in the request extensions.
+  fn assign_request_id(&self, request: &mut Request<Body>) -> RequestIdentifier {
+    let incoming = request
+      .headers()
+      .get(&self.header)
+      .filter(|_| self.trust_incoming)
+      .and_then(|value| value.to_str().ok())
+      .filter(|value| !value.is_empty())
+      .map(str::to_string);
+    let request_id = RequestIdentifier(incoming.unwrap_or_else(generate_request_id));
+
+    // Only visible ASCII characters survive to_str and generated ids are hex
+    let header_value = HeaderValue::from_str(&request_id.0).unwrap();
+    request.headers_mut().insert(self.header.clone(), header_value);
+    request.extensions_mut().insert(request_id.clone());
+    request_id
+  }
+
+  fn echo_request_id(&self, mut response: Response<Body>, request_id: &RequestIdentifier) -> Response<Body> {
+    let header_value = HeaderValue::from_str(&request_id.0).unwrap();
+    response.headers_mut().insert(self.header.clone(), header_value);
+    response
+  }
+}
+
+impl TryFrom<Table> for RequestId {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let header = match t.get("header") {
+      Some(header) => HeaderName::try_from(header.as_str().ok_or(())?).map_err(|_| ())?,
+      None => HeaderName::from_static("x-request-id"),
+    };
+    let trust_incoming = match t.get("trust_incoming") {
+      Some(trust_incoming) => trust_incoming.as_bool().ok_or(())?,
+      None => false,
+    };
+    let echo = match t.get("echo") {
+      Some(echo) => echo.as_bool().ok_or(())?,
+      None => false,
+    };
+    Ok(RequestId {
+      header,
+      trust_incoming,
+      echo,
+    })
+  }
+}
+
+/// Generates a random UUID (version 4) in its hyphenated string representation.
+fn generate_request_id() -> String {
+  let mut bytes: [u8; 16] = rand::random();
+  bytes[6] = (bytes[6] & 0x0f) | 0x40;
+  bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+  let mut uuid = String::with_capacity(36);
+  for (index, byte) in bytes.iter().enumerate() {
+    if matches!(index, 4 | 6 | 8 | 10) {
+      uuid.push('-');
+    }
+    uuid.push_str(&format!("{:02x}", byte));
+  }
+  uuid
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use toml::Value;
+
+  fn middleware(trust_incoming: bool) -> RequestId {
+    let mut table = Table::new();
+    table.insert("trust_incoming".into(), Value::Boolean(trust_incoming));
+    RequestId::try_from(table).unwrap()
+  }
+
+  #[test]
+  fn test_generate_request_id_is_uuid_v4() {
+    let request_id = generate_request_id();
+
+    assert_eq!(request_id.len(), 36);
+    assert_eq!(&request_id[14..15], "4");
+    assert_eq!(request_id.matches('-').count(), 4);
+  }
+
+  #[test]
+  fn test_assign_request_id_injects_header() {
+    let mut request = Request::builder().body(Body::empty()).unwrap();
+
+    let request_id = middleware(false).assign_request_id(&mut request);
+
+    assert_eq!(request.headers().get("x-request-id").unwrap(), request_id.0.as_str());
+    assert_eq!(request.extensions().get::<RequestIdentifier>(), Some(&request_id));
+  }
+
+  #[test]
+  fn test_assign_request_id_passes_trusted_incoming_id() {
+    let mut request = Request::builder()
+      .header("x-request-id", "abc-123")
+      .body(Body::empty())
+      .unwrap();
+
+    let request_id = middleware(true).assign_request_id(&mut request);
+
+    assert_eq!(request_id.0, "abc-123");
+    assert_eq!(request.headers().get("x-request-id").unwrap(), "abc-123");
+  }
+
+  #[test]
+  fn test_assign_request_id_replaces_untrusted_incoming_id() {
+    let mut request = Request::builder()
+      .header("x-request-id", "abc-123")
+      .body(Body::empty())
+      .unwrap();
+
+    let request_id = middleware(false).assign_request_id(&mut request);
+
+    assert_ne!(request_id.0, "abc-123");
+    assert_eq!(request.headers().get_all("x-request-id").iter().count(), 1);
+  }
+
+  #[test]
+  fn test_echo_request_id() {
+    let response = Response::builder().body(Body::empty()).unwrap();
+    let request_id = RequestIdentifier("abc-123".into());
+
+    let response = middleware(false).echo_request_id(response, &request_id);
+
+    assert_eq!(response.headers().get("x-request-id").unwrap(), "abc-123");
+  }
+}
@@ -0,0 +1,208 @@
+use super::{Context, Middleware};
+use crate::server::Scheme;
+use async_trait::async_trait;
+use hyper::{
+  header::{
+    HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, SERVER, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS,
+    X_FRAME_OPTIONS,
+  },
+  Body, Response,
+};
+use std::convert::TryFrom;
+use toml::{value::Table, Value};
+
+/// Sets security related response headers uniformly for all backend servers
+/// and removes headers, which reveal the software of the backend server.
+#[derive(Debug)]
+pub struct SecurityHeaders {
+  policies: Vec<HeaderPolicy>,
+  remove: Vec<HeaderName>,
+}
+
+#[derive(Debug, PartialEq)]
+struct HeaderPolicy {
+  name: HeaderName,
+  value: HeaderValue,
+  /// Whether the value of the backend server is replaced, otherwise it is
+  /// only set if the backend server did not set the header.
+  override_backend: bool,
+}
+
+#[async_trait]
+impl Middleware for SecurityHeaders {
+  async fn modify_response(&self, mut response: Response<Body>, context: &Context<'_>) -> Response<Body> {
+    self.secure(&mut response, context.client_scheme);
+    response
+  }
+}
+
+impl SecurityHeaders {
+  fn secure(&self, response: &mut Response<Body>, client_scheme: &Scheme) {
+    // Interim responses and upgraded connections (like WebSockets) are not documents
+    if response.status().is_informational() {
+      return;
+    }
+    let headers = response.headers_mut();
+    for header in &self.remove {
+      headers.remove(header);
+    }
+    for policy in &self.policies {
+      // Browsers ignore HSTS of plain HTTP responses, an attacker could strip it anyway
+      if policy.name == STRICT_TRANSPORT_SECURITY && *client_scheme != Scheme::HTTPS {
+        continue;
+      }
+      if policy.override_backend || !headers.contains_key(&policy.name) {
+        headers.insert(policy.name.clone(), policy.value.clone());
+      }
+    }
+  }
+}
+
+/// Parses a header policy, which is either a value, which is only set if the
+/// backend server did not set the header, a table like
+/// `{ value = "DENY", override = true }` or `false` to not set the header.
+fn parse_policy(name: HeaderName, policy: Option<&Value>, default: Option<&str>) -> Result<Option<HeaderPolicy>, ()> {
+  let (value, override_backend) = match policy {
+    None => match default {
+      Some(default) => (default, false),
+      None => return Ok(None),
+    },
+    Some(Value::Boolean(false)) => return Ok(None),
+    Some(Value::String(value)) => (value.as_str(), false),
+    Some(Value::Table(t)) => (
+      t.get("value").and_then(Value::as_str).ok_or(())?,
+      match t.get("override") {
+        Some(it) => it.as_bool().ok_or(())?,
+        None => false,
+      },
+    ),
+    Some(_) => return Err(()),
+  };
+  Ok(Some(HeaderPolicy {
+    name,
+    value: HeaderValue::from_str(value).map_err(|_| ())?,
+    override_backend,
+  }))
+}
+
+impl TryFrom<Table> for SecurityHeaders {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let policies = vec![
+      parse_policy(
+        STRICT_TRANSPORT_SECURITY,
+        t.get("strict_transport_security"),
+        Some("max-age=31536000"),
+      )?,
+      parse_policy(X_CONTENT_TYPE_OPTIONS, t.get("content_type_options"), Some("nosniff"))?,
+      parse_policy(X_FRAME_OPTIONS, t.get("frame_options"), Some("SAMEORIGIN"))?,
+      parse_policy(CONTENT_SECURITY_POLICY, t.get("content_security_policy"), None)?,
+    ];
+    let remove = match t.get("remove") {
+      Some(remove) => remove
+        .as_array()
+        .ok_or(())?
+        .iter()
+        .map(|it| it.as_str().and_then(|it| HeaderName::try_from(it).ok()).ok_or(()))
+        .collect::<Result<_, _>>()?,
+      None => vec![SERVER, HeaderName::from_static("x-powered-by")],
+    };
+    Ok(SecurityHeaders {
+      policies: policies.into_iter().flatten().collect(),
+      remove,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::StatusCode;
+
+  fn middleware(toml: &str) -> SecurityHeaders {
+    SecurityHeaders::try_from(toml::from_str::<Table>(toml).unwrap()).unwrap()
+  }
+
+  fn response(headers: &[(&str, &str)]) -> Response<Body> {
+    let mut builder = Response::builder();
+    for (name, value) in headers {
+      builder = builder.header(*name, *value);
+    }
+    builder.body(Body::empty()).unwrap()
+  }
+
+  #[test]
+  fn test_sets_default_headers() {
+    let mut response = response(&[("server", "nginx/1.18.0"), ("x-powered-by", "PHP/7.4")]);
+
+    middleware("").secure(&mut response, &Scheme::HTTPS);
+
+    let headers = response.headers();
+    assert_eq!(headers.get("strict-transport-security").unwrap(), "max-age=31536000");
+    assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+    assert_eq!(headers.get("x-frame-options").unwrap(), "SAMEORIGIN");
+    assert_eq!(headers.get("content-security-policy"), None);
+    assert_eq!(headers.get("server"), None);
+    assert_eq!(headers.get("x-powered-by"), None);
+  }
+
+  #[test]
+  fn test_fill_in_and_override() {
+    let middleware = middleware(
+      r#"
+      frame_options = "DENY"
+      content_security_policy = { value = "default-src 'self'", override = true }
+      content_type_options = false
+      remove = []
+      "#,
+    );
+    let mut response = response(&[
+      ("x-frame-options", "ALLOW-FROM https://example.com"),
+      ("content-security-policy", "default-src *"),
+      ("x-content-type-options", "custom"),
+      ("server", "nginx/1.18.0"),
+    ]);
+
+    middleware.secure(&mut response, &Scheme::HTTPS);
+
+    let headers = response.headers();
+    assert_eq!(
+      headers.get("x-frame-options").unwrap(),
+      "ALLOW-FROM https://example.com"
+    );
+    assert_eq!(headers.get("content-security-policy").unwrap(), "default-src 'self'");
+    assert_eq!(headers.get("x-content-type-options").unwrap(), "custom");
+    assert_eq!(headers.get("server").unwrap(), "nginx/1.18.0");
+  }
+
+  #[test]
+  fn test_no_hsts_on_plain_http() {
+    let middleware = middleware(r#"strict_transport_security = { value = "max-age=63072000", override = true }"#);
+    let mut response = response(&[]);
+
+    middleware.secure(&mut response, &Scheme::HTTP);
+
+    assert_eq!(response.headers().get("strict-transport-security"), None);
+    assert_eq!(response.headers().get("x-content-type-options").unwrap(), "nosniff");
+  }
+
+  #[test]
+  fn test_informational_responses_are_untouched() {
+    let mut response = response(&[("server", "nginx/1.18.0")]);
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+
+    middleware("").secure(&mut response, &Scheme::HTTPS);
+
+    assert_eq!(response.headers().len(), 1);
+  }
+
+  #[test]
+  fn test_invalid_config_is_rejected() {
+    let invalid = |toml: &str| SecurityHeaders::try_from(toml::from_str::<Table>(toml).unwrap()).is_err();
+
+    assert!(invalid("frame_options = true"));
+    assert!(invalid("frame_options = { override = true }"));
+    assert!(invalid("remove = \"Server\""));
+  }
+}
@@ -0,0 +1,343 @@
+use super::{Context, Middleware};
+use crate::tls::TlsInfo;
+use async_trait::async_trait;
+use hyper::{
+  header::{HeaderName, HeaderValue},
+  Body, Request, Response,
+};
+use log::debug;
+use openssl::base64;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{collections::HashSet, convert::TryFrom};
+use toml::value::Table;
+
+/// Characters of the PEM client certificate, which are percent-encoded, so it
+/// fits into a single header value.
+const CERTIFICATE_ESCAPES: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_');
+
+/// Forwards details of the TLS session of the client (like the SNI server name
+/// and the common name of the client certificate) to the backend server.
+#[derive(Debug)]
+pub struct TlsHeaders {
+  server_name_header: HeaderName,
+  client_common_name_header: HeaderName,
+  version_header: HeaderName,
+  cipher_header: HeaderName,
+  client_certificate_header: HeaderName,
+  /// The details to forward, the headers of the others are only removed.
+  headers: HashSet<TlsDetail>,
+  client_certificate_encoding: CertificateEncoding,
+  /// Whether the intermediate certificates sent by the client follow its
+  /// certificate.
+  client_certificate_chain: bool,
+  /// Encoded client certificates exceeding this number of bytes are not
+  /// forwarded.
+  max_client_certificate_size: usize,
+}
+
+/// How the client certificate in PEM is turned into a header value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CertificateEncoding {
+  /// Percent-encoded like `$ssl_client_escaped_cert` of nginx.
+  Url,
+  Base64,
+}
+
+impl CertificateEncoding {
+  fn parse(name: &str) -> Option<CertificateEncoding> {
+    match name {
+      "url" => Some(CertificateEncoding::Url),
+      "base64" => Some(CertificateEncoding::Base64),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum TlsDetail {
+  ServerName,
+  ClientCommonName,
+  Version,
+  Cipher,
+  ClientCertificate,
+}
+
+impl TlsDetail {
+  fn parse(name: &str) -> Option<TlsDetail> {
+    match name {
+      "server_name" => Some(TlsDetail::ServerName),
+      "client_common_name" => Some(TlsDetail::ClientCommonName),
+      "version" => Some(TlsDetail::Version),
+      "cipher" => Some(TlsDetail::Cipher),
+      "client_certificate" => Some(TlsDetail::ClientCertificate),
+      _ => None,
+    }
+  }
+}
+
+#[async_trait]
+impl Middleware for TlsHeaders {
+  async fn modify_request(
+    &self,
+    mut request: Request<Body>,
+    _context: &Context<'_>,
+  ) -> Result<Request<Body>, Response<Body>> {
+    self.set_tls_headers(&mut request);
+    Ok(request)
+  }
+}
+
+impl TlsHeaders {
+  fn set_tls_headers(&self, request: &mut Request<Body>) {
+    let tls_info = request.extensions().get::<TlsInfo>().cloned().unwrap_or_default();
+    let client_certificate = self.client_certificate(&tls_info);
+    let values = [
+      (TlsDetail::ServerName, &self.server_name_header, tls_info.server_name),
+      (
+        TlsDetail::ClientCommonName,
+        &self.client_common_name_header,
+        tls_info.client_common_name,
+      ),
+      (TlsDetail::Version, &self.version_header, tls_info.protocol_version),
+      (TlsDetail::Cipher, &self.cipher_header, tls_info.cipher_suite),
+      (
+        TlsDetail::ClientCertificate,
+        &self.client_certificate_header,
+        client_certificate,
+      ),
+    ];
+    let headers = request.headers_mut();
+    // Clients must not be able to pretend to be authenticated
+    for (_, header, _) in values.iter() {
+      headers.remove(*header);
+    }
+    for (detail, header, value) in values.iter() {
+      if !self.headers.contains(detail) {
+        continue;
+      }
+      if let Some(value) = value.as_ref().and_then(|it| HeaderValue::from_str(it).ok()) {
+        headers.insert((*header).clone(), value);
+      }
+    }
+  }
+
+  /// The encoded client certificate, unless it exceeds the
+  /// `max_client_certificate_size`.
+  fn client_certificate(&self, tls_info: &TlsInfo) -> Option<String> {
+    if !self.headers.contains(&TlsDetail::ClientCertificate) {
+      return None;
+    }
+    let mut pem = tls_info.client_certificate.clone()?;
+    if self.client_certificate_chain {
+      tls_info.client_certificate_chain.iter().for_each(|it| pem.push_str(it));
+    }
+    let encoded = match self.client_certificate_encoding {
+      CertificateEncoding::Url => utf8_percent_encode(&pem, CERTIFICATE_ESCAPES).to_string(),
+      CertificateEncoding::Base64 => base64::encode_block(pem.as_bytes()),
+    };
+    if encoded.len() > self.max_client_certificate_size {
+      debug!(
+        "Did not forward a client certificate of {} bytes, which exceeds the max_client_certificate_size",
+        encoded.len()
+      );
+      return None;
+    }
+    Some(encoded)
+  }
+}
+
+impl TryFrom<Table> for TlsHeaders {
+  type Error = ();
+
+  fn try_from(t: Table) -> Result<Self, Self::Error> {
+    let header = |key: &str, default: &'static str| match t.get(key) {
+      Some(header) => HeaderName::try_from(header.as_str().ok_or(())?).map_err(|_| ()),
+      None => Ok(HeaderName::from_static(default)),
+    };
+    let headers = match t.get("headers") {
+      Some(headers) => headers
+        .as_array()
+        .ok_or(())?
+        .iter()
+        .map(|it| it.as_str().and_then(TlsDetail::parse).ok_or(()))
+        .collect::<Result<_, _>>()?,
+      // The client certificate is large, so it is only forwarded on request
+      None => [
+        TlsDetail::ServerName,
+        TlsDetail::ClientCommonName,
+        TlsDetail::Version,
+        TlsDetail::Cipher,
+      ]
+      .iter()
+      .copied()
+      .collect(),
+    };
+    Ok(TlsHeaders {
+      server_name_header: header("server_name_header", "x-ssl-server-name")?,
+      client_common_name_header: header("client_common_name_header", "x-ssl-client-cn")?,
+      version_header: header("version_header", "x-tls-version")?,
+      cipher_header: header("cipher_header", "x-tls-cipher")?,
+      client_certificate_header: header("client_certificate_header", "x-client-cert")?,
+      headers,
+      client_certificate_encoding: match t.get("client_certificate_encoding") {
+        Some(encoding) => encoding.as_str().and_then(CertificateEncoding::parse).ok_or(())?,
+        None => CertificateEncoding::Url,
+      },
+      client_certificate_chain: match t.get("client_certificate_chain") {
+        Some(chain) => chain.as_bool().ok_or(())?,
+        None => false,
+      },
+      max_client_certificate_size: match t.get("max_client_certificate_size") {
+        Some(size) => size.as_integer().filter(|it| *it > 0).ok_or(())? as usize,
+        None => 16 * 1024,
+      },
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn middleware() -> TlsHeaders {
+    TlsHeaders::try_from(Table::new()).unwrap()
+  }
+
+  #[test]
+  fn test_sets_tls_headers() {
+    let mut request = Request::builder().body(Body::empty()).unwrap();
+    request.extensions_mut().insert(TlsInfo {
+      server_name: Some("whoami.localhost".into()),
+      client_common_name: Some("client.example.com".into()),
+      protocol_version: Some("TLSv1.3".into()),
+      cipher_suite: Some("TLS13_AES_128_GCM_SHA256".into()),
+      client_certificate: Some("-----BEGIN CERTIFICATE-----\nMIIB+w==\n-----END CERTIFICATE-----\n".into()),
+      client_certificate_chain: Vec::new(),
+    });
+
+    middleware().set_tls_headers(&mut request);
+
+    assert_eq!(request.headers().get("x-ssl-server-name").unwrap(), "whoami.localhost");
+    assert_eq!(request.headers().get("x-ssl-client-cn").unwrap(), "client.example.com");
+    assert_eq!(request.headers().get("x-tls-version").unwrap(), "TLSv1.3");
+    assert_eq!(
+      request.headers().get("x-tls-cipher").unwrap(),
+      "TLS13_AES_128_GCM_SHA256"
+    );
+    assert_eq!(request.headers().get("x-client-cert"), None);
+  }
+
+  #[test]
+  fn test_sets_selected_tls_headers() {
+    let mut table = Table::new();
+    table.insert("headers".into(), vec!["version", "client_certificate"].into());
+    let mut request = Request::builder()
+      .header("x-tls-cipher", "NULL")
+      .body(Body::empty())
+      .unwrap();
+    request.extensions_mut().insert(TlsInfo {
+      server_name: Some("whoami.localhost".into()),
+      protocol_version: Some("TLSv1.2".into()),
+      cipher_suite: Some("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256".into()),
+      client_certificate: Some("-----BEGIN CERTIFICATE-----\nMIIB+w==\n-----END CERTIFICATE-----\n".into()),
+      ..Default::default()
+    });
+
+    TlsHeaders::try_from(table).unwrap().set_tls_headers(&mut request);
+
+    let headers = request.headers();
+    assert_eq!(headers.get("x-tls-version").unwrap(), "TLSv1.2");
+    assert_eq!(
+      headers.get("x-client-cert").unwrap(),
+      "-----BEGIN%20CERTIFICATE-----%0AMIIB%2Bw%3D%3D%0A-----END%20CERTIFICATE-----%0A"
+    );
+    assert_eq!(headers.get("x-tls-cipher"), None);
+    assert_eq!(headers.get("x-ssl-server-name"), None);
+  }
+
+  #[test]
+  fn test_forwards_client_certificate_chain_in_base64() {
+    // given:
+    let mut table = Table::new();
+    table.insert("headers".into(), vec!["client_certificate"].into());
+    table.insert("client_certificate_encoding".into(), "base64".into());
+    table.insert("client_certificate_chain".into(), true.into());
+    let mut request = Request::builder().body(Body::empty()).unwrap();
+    request.extensions_mut().insert(TlsInfo {
+      client_certificate: Some("leaf\n".into()),
+      client_certificate_chain: vec!["intermediate\n".into()],
+      ..Default::default()
+    });
+
+    // when:
+    TlsHeaders::try_from(table).unwrap().set_tls_headers(&mut request);
+
+    // then: base64 of "leaf\nintermediate\n"
+    assert_eq!(
+      request.headers().get("x-client-cert").unwrap(),
+      "bGVhZgppbnRlcm1lZGlhdGUK"
+    );
+  }
+
+  #[test]
+  fn test_omits_client_certificate_exceeding_max_size() {
+    // given:
+    let mut table = Table::new();
+    table.insert("headers".into(), vec!["client_certificate"].into());
+    table.insert("max_client_certificate_size".into(), 8.into());
+    let mut request = Request::builder()
+      .header("x-client-cert", "forged")
+      .body(Body::empty())
+      .unwrap();
+    request.extensions_mut().insert(TlsInfo {
+      client_certificate: Some("-----BEGIN CERTIFICATE-----\n".into()),
+      ..Default::default()
+    });
+
+    // when:
+    TlsHeaders::try_from(table).unwrap().set_tls_headers(&mut request);
+
+    // then:
+    assert_eq!(request.headers().get("x-client-cert"), None);
+  }
+
+  #[test]
+  fn test_unknown_tls_header_is_rejected() {
+    let mut table = Table::new();
+    table.insert("headers".into(), vec!["session_id"].into());
+
+    assert!(TlsHeaders::try_from(table).is_err());
+  }
+
+  #[test]
+  fn test_removes_incoming_tls_headers() {
+    let mut request = Request::builder()
+      .header("x-ssl-client-cn", "admin")
+      .header("x-client-cert", "forged")
+      .body(Body::empty())
+      .unwrap();
+    request.extensions_mut().insert(TlsInfo {
+      server_name: Some("whoami.localhost".into()),
+      protocol_version: Some("TLSv1.3".into()),
+      ..Default::default()
+    });
+
+    middleware().set_tls_headers(&mut request);
+
+    assert_eq!(request.headers().get("x-ssl-server-name").unwrap(), "whoami.localhost");
+    assert_eq!(request.headers().get("x-ssl-client-cn"), None);
+    assert_eq!(request.headers().get("x-client-cert"), None);
+    assert_eq!(request.headers().get("x-tls-version").unwrap(), "TLSv1.3");
+  }
+
+  #[test]
+  fn test_custom_header_names() {
+    let mut table = Table::new();
+    table.insert("client_common_name_header".into(), "X-Client-CN".into());
+
+    let middleware = TlsHeaders::try_from(table).unwrap();
+
+    assert_eq!(middleware.client_common_name_header, "x-client-cn");
+    assert_eq!(middleware.server_name_header, "x-ssl-server-name");
+  }
+}
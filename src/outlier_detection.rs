@@ -0,0 +1,351 @@
+use crate::metrics::METRICS;
+use hyper::StatusCode;
+use log::{info, warn};
+use std::{
+  collections::HashMap,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutlierDetectionConfig {
+  /// A backend server is ejected if its error rate exceeds the error rate of
+  /// the whole pool by this factor.
+  pub factor: f64,
+  pub window: Duration,
+  /// The minimum number of requests a backend server needs to receive within a
+  /// window to be evaluated.
+  pub min_requests: u64,
+  pub base_ejection_time: Duration,
+  pub max_ejection_time: Duration,
+  /// The status codes of responses, which count as errors of the backend
+  /// server. An application may legitimately respond with other `5xx` codes.
+  pub failure_status_codes: Vec<StatusCode>,
+}
+
+impl OutlierDetectionConfig {
+  pub fn default_failure_status_codes() -> Vec<StatusCode> {
+    vec![
+      StatusCode::BAD_GATEWAY,
+      StatusCode::SERVICE_UNAVAILABLE,
+      StatusCode::GATEWAY_TIMEOUT,
+    ]
+  }
+}
+
+/// Ejects backend servers whose error rate (connection failures and responses
+/// with a failure status code) is significantly higher than the error rate of their peers.
+/// Every time a backend server is ejected, the ejection time grows by
+/// `base_ejection_time`.
+#[derive(Debug)]
+pub struct OutlierDetector {
+  pool: String,
+  config: OutlierDetectionConfig,
+  state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+  window_start: Instant,
+  backends: HashMap<String, BackendStats>,
+}
+
+#[derive(Debug, Default)]
+struct BackendStats {
+  requests: u64,
+  errors: u64,
+  ejected_until: Option<Instant>,
+  ejections: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ejection {
+  pub ejected_until: Option<Instant>,
+  /// How often the backend server was ejected, which determines the next
+  /// ejection time.
+  pub ejections: u32,
+}
+
+impl BackendStats {
+  fn error_rate(&self) -> f64 {
+    self.errors as f64 / self.requests as f64
+  }
+}
+
+impl OutlierDetector {
+  pub fn new(pool: String, config: OutlierDetectionConfig) -> OutlierDetector {
+    OutlierDetector {
+      pool,
+      config,
+      state: Mutex::new(State {
+        window_start: Instant::now(),
+        backends: HashMap::new(),
+      }),
+    }
+  }
+
+  /// Whether a response with the `status` counts as an error of the backend
+  /// server.
+  pub fn is_failure(&self, status: StatusCode) -> bool {
+    self.config.failure_status_codes.contains(&status)
+  }
+
+  /// Records the result of a request to the backend server at `address`.
+  pub fn record(&self, address: &str, success: bool) {
+    let mut state = self.state.lock().unwrap();
+    let stats = state.backends.entry(address.to_string()).or_default();
+    stats.requests += 1;
+    if !success {
+      stats.errors += 1;
+    }
+    if state.window_start.elapsed() >= self.config.window {
+      self.evaluate(&mut state);
+    }
+  }
+
+  pub fn is_ejected(&self, address: &str) -> bool {
+    let mut state = self.state.lock().unwrap();
+    let stats = match state.backends.get_mut(address) {
+      Some(stats) => stats,
+      None => return false,
+    };
+    match stats.ejected_until {
+      Some(ejected_until) if Instant::now() < ejected_until => true,
+      Some(_) => {
+        info!("Backend server {} is no longer ejected", address);
+        stats.ejected_until = None;
+        self.record_ejected(address, false);
+        false
+      }
+      None => false,
+    }
+  }
+
+  /// The ejections of all backend servers, which were ejected at least once
+  /// and not yet forgiven.
+  pub fn ejections(&self) -> HashMap<String, Ejection> {
+    let state = self.state.lock().unwrap();
+    state
+      .backends
+      .iter()
+      .filter(|(_, stats)| stats.ejections > 0)
+      .map(|(address, stats)| {
+        let ejection = Ejection {
+          ejected_until: stats.ejected_until,
+          ejections: stats.ejections,
+        };
+        (address.clone(), ejection)
+      })
+      .collect()
+  }
+
+  /// Restores an ejection returned by [`OutlierDetector::ejections`], for
+  /// example of a previous process.
+  pub fn restore(&self, address: &str, ejection: Ejection) {
+    let mut state = self.state.lock().unwrap();
+    let stats = state.backends.entry(address.to_string()).or_default();
+    stats.ejected_until = ejection.ejected_until;
+    stats.ejections = ejection.ejections;
+    self.record_ejected(address, ejection.ejected_until.is_some());
+  }
+
+  fn record_ejected(&self, address: &str, ejected: bool) {
+    let labels = [("pool", self.pool.as_str()), ("backend", address)];
+    METRICS.set_gauge("arlb_outlier_ejected", &labels, ejected as i64);
+  }
+
+  fn evaluate(&self, state: &mut State) {
+    let now = Instant::now();
+    let (requests, errors) = state.backends.values().fold((0, 0), |(requests, errors), it| {
+      (requests + it.requests, errors + it.errors)
+    });
+    let pool_error_rate = errors as f64 / requests as f64;
+
+    for (address, stats) in state.backends.iter_mut() {
+      let is_outlier = errors > 0
+        && stats.requests >= self.config.min_requests
+        && stats.error_rate() > self.config.factor * pool_error_rate;
+      if is_outlier {
+        stats.ejections += 1;
+        let ejection_time = (self.config.base_ejection_time * stats.ejections).min(self.config.max_ejection_time);
+        stats.ejected_until = Some(now + ejection_time);
+        METRICS.increment(
+          "arlb_outlier_ejections_total",
+          &[("pool", &self.pool), ("backend", address)],
+        );
+        self.record_ejected(address, true);
+        warn!(
+          "Ejected backend server {} for {:?}, because its error rate of {:.1}% ({} of {} requests) exceeds {} times the error rate of the pool ({:.1}%)",
+          address,
+          ejection_time,
+          stats.error_rate() * 100.0,
+          stats.errors,
+          stats.requests,
+          self.config.factor,
+          pool_error_rate * 100.0
+        );
+      } else if stats.ejected_until.is_none() {
+        // Backend servers which behave well for a whole window are forgiven an earlier offense
+        stats.ejections = stats.ejections.saturating_sub(1);
+      }
+      stats.requests = 0;
+      stats.errors = 0;
+    }
+    state.window_start = now;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn detector() -> OutlierDetector {
+    detector_of("test")
+  }
+
+  fn detector_of(pool: &str) -> OutlierDetector {
+    OutlierDetector::new(
+      pool.to_string(),
+      OutlierDetectionConfig {
+        factor: 2.0,
+        // windows are evaluated explicitly
+        window: Duration::from_secs(3600),
+        min_requests: 5,
+        base_ejection_time: Duration::from_secs(30),
+        max_ejection_time: Duration::from_secs(300),
+        failure_status_codes: OutlierDetectionConfig::default_failure_status_codes(),
+      },
+    )
+  }
+
+  fn record(detector: &OutlierDetector, address: &str, requests: u64, errors: u64) {
+    for index in 0..requests {
+      detector.record(address, index >= errors);
+    }
+  }
+
+  fn evaluate(detector: &OutlierDetector) {
+    let mut state = detector.state.lock().unwrap();
+    detector.evaluate(&mut state);
+  }
+
+  #[test]
+  fn test_ejects_outlier() {
+    // given:
+    let detector = detector();
+    record(&detector, "127.0.0.1:1", 10, 8);
+    record(&detector, "127.0.0.1:2", 10, 0);
+    record(&detector, "127.0.0.1:3", 10, 1);
+
+    // when:
+    evaluate(&detector);
+
+    // then:
+    assert!(detector.is_ejected("127.0.0.1:1"));
+    assert!(!detector.is_ejected("127.0.0.1:2"));
+    assert!(!detector.is_ejected("127.0.0.1:3"));
+  }
+
+  #[test]
+  fn test_ejections_are_counted() {
+    // given:
+    let detector = detector_of("test_ejections_are_counted");
+    let labels = |backend| [("pool", "test_ejections_are_counted"), ("backend", backend)];
+    record(&detector, "127.0.0.1:1", 10, 8);
+    record(&detector, "127.0.0.1:2", 10, 0);
+    record(&detector, "127.0.0.1:3", 10, 0);
+
+    // when:
+    evaluate(&detector);
+
+    // then:
+    assert_eq!(
+      METRICS.counter("arlb_outlier_ejections_total", &labels("127.0.0.1:1")),
+      1
+    );
+    assert_eq!(METRICS.gauge("arlb_outlier_ejected", &labels("127.0.0.1:1")), 1);
+    assert_eq!(
+      METRICS.counter("arlb_outlier_ejections_total", &labels("127.0.0.1:2")),
+      0
+    );
+
+    // when: the ejection expired
+    detector
+      .state
+      .lock()
+      .unwrap()
+      .backends
+      .get_mut("127.0.0.1:1")
+      .unwrap()
+      .ejected_until = Some(Instant::now());
+    let ejected = detector.is_ejected("127.0.0.1:1");
+
+    // then:
+    assert!(!ejected);
+    assert_eq!(METRICS.gauge("arlb_outlier_ejected", &labels("127.0.0.1:1")), 0);
+    assert_eq!(
+      METRICS.counter("arlb_outlier_ejections_total", &labels("127.0.0.1:1")),
+      1
+    );
+  }
+
+  #[test]
+  fn test_does_not_eject_if_all_peers_fail() {
+    // given:
+    let detector = detector();
+    record(&detector, "127.0.0.1:1", 10, 8);
+    record(&detector, "127.0.0.1:2", 10, 7);
+
+    // when:
+    evaluate(&detector);
+
+    // then:
+    assert!(!detector.is_ejected("127.0.0.1:1"));
+    assert!(!detector.is_ejected("127.0.0.1:2"));
+  }
+
+  #[test]
+  fn test_does_not_eject_with_few_requests() {
+    // given:
+    let detector = detector();
+    record(&detector, "127.0.0.1:1", 4, 4);
+    record(&detector, "127.0.0.1:2", 10, 0);
+
+    // when:
+    evaluate(&detector);
+
+    // then:
+    assert!(!detector.is_ejected("127.0.0.1:1"));
+  }
+
+  #[test]
+  fn test_ejection_time_grows() {
+    // given:
+    let detector = detector();
+
+    // when:
+    for _ in 0..3 {
+      record(&detector, "127.0.0.1:1", 10, 10);
+      record(&detector, "127.0.0.1:2", 10, 0);
+      record(&detector, "127.0.0.1:3", 10, 0);
+      evaluate(&detector);
+    }
+
+    // then:
+    let state = detector.state.lock().unwrap();
+    let stats = &state.backends["127.0.0.1:1"];
+    assert_eq!(stats.ejections, 3);
+    let remaining = stats.ejected_until.unwrap() - Instant::now();
+    assert!(remaining > Duration::from_secs(60) && remaining <= Duration::from_secs(90));
+  }
+
+  #[test]
+  fn test_failure_status_codes() {
+    let detector = detector();
+
+    assert!(detector.is_failure(StatusCode::BAD_GATEWAY));
+    assert!(detector.is_failure(StatusCode::GATEWAY_TIMEOUT));
+    assert!(!detector.is_failure(StatusCode::INTERNAL_SERVER_ERROR));
+    assert!(!detector.is_failure(StatusCode::TOO_MANY_REQUESTS));
+  }
+}
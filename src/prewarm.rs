@@ -0,0 +1,359 @@
+use crate::{
+  builtin_backends::BUILTIN_ADDRESS_PREFIX,
+  configuration::RuntimeConfig,
+  http_client::{backend_uri, BackendConnector, BackendStream, UNIX_ADDRESS_PREFIX},
+  metrics::METRICS,
+  server::BackendPool,
+};
+use arc_swap::ArcSwap;
+use hyper::{http::uri::PathAndQuery, service::Service};
+use log::debug;
+use once_cell::sync::Lazy;
+use std::{
+  collections::HashMap,
+  io,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, sync::Notify};
+
+/// How often the warm connections are checked and replenished, unless
+/// requests consume some of them earlier.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// The longest pause after connecting to a backend server failed repeatedly.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wakes the warmer, once a request consumed a warm connection.
+static CONSUMED: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Debug, Default)]
+struct WarmBackend {
+  idle: Vec<TcpStream>,
+  /// How often connecting failed since the last success.
+  failures: u32,
+  retry_at: Option<Instant>,
+}
+
+/// TCP connections to the backend servers of a pool, which are established
+/// ahead of requests, so requests after a quiet period do not have to wait
+/// for a connection. The backend connector of the pool hands them to the
+/// HTTP client instead of dialing.
+#[derive(Debug)]
+pub struct WarmConnections {
+  pool: String,
+  min_idle: usize,
+  /// Connects like the backend connector of the pool.
+  dialer: BackendConnector,
+  backends: Mutex<HashMap<String, WarmBackend>>,
+}
+
+impl WarmConnections {
+  pub fn new(pool: String, min_idle: usize, dialer: BackendConnector) -> WarmConnections {
+    WarmConnections {
+      pool,
+      min_idle,
+      dialer,
+      backends: Mutex::default(),
+    }
+  }
+
+  /// Takes a warm connection to the backend server at `address` for a
+  /// request, skipping the ones, which were closed meanwhile. Counts whether
+  /// the request was served a warm connection or has to dial a fresh one.
+  pub fn take(&self, address: &str) -> Option<TcpStream> {
+    let mut backends = self.backends.lock().unwrap();
+    let backend = backends.get_mut(address);
+    let (warm, idle) = match backend {
+      Some(backend) => {
+        let mut warm = None;
+        while let Some(stream) = backend.idle.pop() {
+          if is_open(&stream) {
+            warm = Some(stream);
+            break;
+          }
+        }
+        (warm, backend.idle.len())
+      }
+      None => (None, 0),
+    };
+    drop(backends);
+    self.record_idle(address, idle);
+    let connection = if warm.is_some() { "warm" } else { "fresh" };
+    METRICS.increment(
+      "arlb_backend_connects_total",
+      &[("pool", &self.pool), ("connection", connection)],
+    );
+    if warm.is_some() {
+      CONSUMED.notify_one();
+    }
+    warm
+  }
+
+  /// How many connections to `address` are missing, after the closed ones
+  /// were dropped. While connecting to it backs off, none are missing.
+  fn missing(&self, address: &str, now: Instant) -> usize {
+    let mut backends = self.backends.lock().unwrap();
+    let backend = backends.entry(address.to_string()).or_default();
+    backend.idle.retain(is_open);
+    let idle = backend.idle.len();
+    let backing_off = backend.retry_at.is_some_and(|it| it > now);
+    drop(backends);
+    self.record_idle(address, idle);
+    if backing_off {
+      0
+    } else {
+      self.min_idle.saturating_sub(idle)
+    }
+  }
+
+  fn add(&self, address: &str, stream: TcpStream) {
+    let mut backends = self.backends.lock().unwrap();
+    let backend = backends.entry(address.to_string()).or_default();
+    backend.idle.push(stream);
+    backend.failures = 0;
+    backend.retry_at = None;
+    let idle = backend.idle.len();
+    drop(backends);
+    self.record_idle(address, idle);
+  }
+
+  /// Backs off exponentially from connecting to `address` again.
+  fn failed(&self, address: &str, now: Instant, error: &str) {
+    let mut backends = self.backends.lock().unwrap();
+    let backend = backends.entry(address.to_string()).or_default();
+    backend.failures += 1;
+    let backoff = INTERVAL
+      .checked_mul(2u32.saturating_pow(backend.failures))
+      .unwrap_or(MAX_BACKOFF)
+      .min(MAX_BACKOFF);
+    backend.retry_at = Some(now + backoff);
+    debug!(
+      "Could not pre-warm a connection to {} of pool '{}', retrying in {:?}: {}",
+      address, self.pool, backoff, error
+    );
+  }
+
+  /// Closes the warm connections to `address`, like when it is unhealthy.
+  fn close(&self, address: &str) {
+    let closed = self.backends.lock().unwrap().remove(address);
+    if closed.is_some_and(|it| !it.idle.is_empty()) {
+      self.record_idle(address, 0);
+    }
+  }
+
+  async fn dial(&self, address: &str) -> Result<TcpStream, String> {
+    let uri = backend_uri(address, PathAndQuery::from_static("/")).map_err(|e| e.to_string())?;
+    let mut dialer = self.dialer.clone();
+    let connect = async {
+      futures::future::poll_fn(|cx| dialer.poll_ready(cx)).await?;
+      dialer.call(uri).await
+    };
+    match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+      Ok(Ok(BackendStream::Tcp(stream))) => Ok(stream),
+      Ok(Ok(_)) => Err("Not a TCP connection".to_string()),
+      Ok(Err(e)) => Err(e.to_string()),
+      Err(_) => Err(format!("Timed out after {:?}", CONNECT_TIMEOUT)),
+    }
+  }
+
+  fn record_idle(&self, address: &str, idle: usize) {
+    METRICS.set_gauge(
+      "arlb_warm_backend_connections",
+      &[("pool", &self.pool), ("backend", address)],
+      idle as i64,
+    );
+  }
+}
+
+/// Whether an idle connection is still usable: the backend server neither
+/// closed it nor sent something unsolicited.
+fn is_open(stream: &TcpStream) -> bool {
+  let mut byte = [0; 1];
+  matches!(stream.try_read(&mut byte), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+/// Only TCP connections are kept warm, connecting to unix domain sockets and
+/// builtin backend servers is cheap.
+fn is_tcp(address: &str) -> bool {
+  !address.starts_with(UNIX_ADDRESS_PREFIX) && !address.starts_with(BUILTIN_ADDRESS_PREFIX)
+}
+
+/// Tops up the warm connections of the backend servers of `pool`, which
+/// accept them, and closes those of the others.
+async fn replenish(pool: &BackendPool, warm: &WarmConnections) {
+  for (address, _) in pool.addresses.iter().filter(|(it, _)| is_tcp(it)) {
+    if !pool.accepts_warm_connections(address) {
+      warm.close(address);
+      continue;
+    }
+    for _ in 0..warm.missing(address, Instant::now()) {
+      match warm.dial(address).await {
+        Ok(stream) => warm.add(address, stream),
+        Err(e) => {
+          warm.failed(address, Instant::now(), &e);
+          break;
+        }
+      }
+    }
+  }
+}
+
+/// Keeps the `min_idle_connections` of all pools established.
+pub async fn keep_warm(config: Arc<ArcSwap<RuntimeConfig>>) {
+  loop {
+    let backend_pools = config.load().shared_data.backend_pools.clone();
+    for pool in &backend_pools {
+      if let Some(warm) = &pool.warm_connections {
+        replenish(pool, warm).await;
+      }
+    }
+    let _ = tokio::time::timeout(INTERVAL, CONSUMED.notified()).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{health::Healthiness, server::tests::generate_test_pool_builder};
+  use hyper::{server::conn::AddrStream, service::make_service_fn, Body, Request, Response, Server};
+  use std::{
+    convert::Infallible,
+    sync::atomic::{AtomicUsize, Ordering},
+  };
+
+  /// A backend server, which counts the connections it accepted.
+  fn start_counting_backend() -> (String, Arc<AtomicUsize>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let connects = Arc::new(AtomicUsize::new(0));
+    let counted = connects.clone();
+    let service = make_service_fn(move |_: &AddrStream| {
+      counted.fetch_add(1, Ordering::SeqCst);
+      async {
+        Ok::<_, Infallible>(hyper::service::service_fn(|_: Request<Body>| async {
+          Ok::<_, Infallible>(Response::new(Body::from("ok")))
+        }))
+      }
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+    (address, connects)
+  }
+
+  fn warm_pool(address: &str, pool: &str) -> BackendPool {
+    let mut builder = generate_test_pool_builder(&[address]);
+    builder.name(pool.to_string());
+    builder.min_idle_connections(2);
+    builder.build()
+  }
+
+  async fn request(pool: &BackendPool, address: &str) {
+    let response = pool
+      .client
+      .get(format!("http://{}/", address).parse().unwrap())
+      .await
+      .unwrap();
+    hyper::body::to_bytes(response.into_body()).await.unwrap();
+  }
+
+  fn connects(pool: &str, connection: &str) -> u64 {
+    METRICS.counter(
+      "arlb_backend_connects_total",
+      &[("pool", pool), ("connection", connection)],
+    )
+  }
+
+  #[tokio::test]
+  async fn test_connections_are_established_ahead_of_traffic() {
+    // given:
+    let (address, accepted) = start_counting_backend();
+    let pool = warm_pool(&address, "warm");
+    let warm = pool.warm_connections.as_ref().unwrap();
+
+    // when:
+    replenish(&pool, warm).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // then:
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    assert_eq!(
+      METRICS.gauge(
+        "arlb_warm_backend_connections",
+        &[("pool", "warm"), ("backend", &address)]
+      ),
+      2
+    );
+
+    // when: a request after the quiet period
+    request(&pool, &address).await;
+
+    // then: it is served a warm connection, which is replaced
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    assert_eq!(connects("warm", "warm"), 1);
+    assert_eq!(connects("warm", "fresh"), 0);
+    replenish(&pool, warm).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+  }
+
+  #[tokio::test]
+  async fn test_closed_warm_connections_are_skipped() {
+    // given: a backend server, which closes idle connections right away
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      while let Ok((stream, _)) = listener.accept().await {
+        drop(stream);
+      }
+    });
+    let pool = warm_pool(&address, "reaped");
+    let warm = pool.warm_connections.as_ref().unwrap();
+    replenish(&pool, warm).await;
+
+    // when:
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let taken = warm.take(&address);
+
+    // then:
+    assert!(taken.is_none());
+    assert_eq!(connects("reaped", "fresh"), 1);
+  }
+
+  #[tokio::test]
+  async fn test_unhealthy_backends_are_not_warmed() {
+    // given:
+    let (address, accepted) = start_counting_backend();
+    let pool = warm_pool(&address, "unhealthy");
+    let warm = pool.warm_connections.as_ref().unwrap();
+    replenish(&pool, warm).await;
+
+    // when:
+    pool.addresses[0].1.store(Arc::new(Healthiness::Unresponsive(None)));
+    replenish(&pool, warm).await;
+
+    // then: the warm connections are closed and not replaced
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    assert!(warm.take(&address).is_none());
+  }
+
+  #[tokio::test]
+  async fn test_warmer_backs_off_from_unreachable_backends() {
+    // given: a port, on which nothing listens
+    let address = {
+      let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+      listener.local_addr().unwrap().to_string()
+    };
+    let pool = warm_pool(&address, "unreachable");
+    let warm = pool.warm_connections.as_ref().unwrap();
+
+    // when:
+    replenish(&pool, warm).await;
+
+    // then:
+    let now = Instant::now();
+    assert_eq!(warm.missing(&address, now), 0);
+    assert_eq!(warm.missing(&address, now + INTERVAL * 2), 2);
+  }
+}
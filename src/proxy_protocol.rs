@@ -0,0 +1,192 @@
+use std::net::{IpAddr, SocketAddr};
+
+/// The signature, which starts each header of version 2.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The version of the PROXY protocol header, which is sent to backend servers
+/// ahead of a relayed connection, so they know the address of the client.
+/// Backend servers can not be asked which version they support, so it is
+/// configured per route.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyProtocolVersion {
+  /// The human readable header, which older backend servers understand.
+  V1,
+  /// The binary header.
+  V2,
+}
+
+/// The header of a connection from `source` (the client) to `destination`
+/// (the listener of the load balancer).
+pub fn header(version: ProxyProtocolVersion, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+  let (source_ip, destination_ip) = same_family(source.ip(), destination.ip());
+  match version {
+    ProxyProtocolVersion::V1 => {
+      let protocol = if source_ip.is_ipv4() { "TCP4" } else { "TCP6" };
+      format!(
+        "PROXY {} {} {} {} {}\r\n",
+        protocol,
+        source_ip,
+        destination_ip,
+        source.port(),
+        destination.port()
+      )
+      .into_bytes()
+    }
+    ProxyProtocolVersion::V2 => {
+      let mut header = V2_SIGNATURE.to_vec();
+      // Version 2 and the PROXY command
+      header.push(0x21);
+      match (source_ip, destination_ip) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+          // TCP over IPv4
+          header.push(0x11);
+          header.extend_from_slice(&12u16.to_be_bytes());
+          header.extend_from_slice(&source_ip.octets());
+          header.extend_from_slice(&destination_ip.octets());
+        }
+        _ => {
+          // TCP over IPv6
+          header.push(0x21);
+          header.extend_from_slice(&36u16.to_be_bytes());
+          header.extend_from_slice(&ipv6_octets(source_ip));
+          header.extend_from_slice(&ipv6_octets(destination_ip));
+        }
+      }
+      header.extend_from_slice(&source.port().to_be_bytes());
+      header.extend_from_slice(&destination.port().to_be_bytes());
+      header
+    }
+  }
+}
+
+/// Both addresses of a header have to be of the same family. Dual stack
+/// listeners see IPv4 clients as IPv4-mapped IPv6 addresses, which are sent
+/// as IPv4 addresses, unless the other address is a real IPv6 address. Then
+/// both are sent as IPv6 addresses.
+fn same_family(a: IpAddr, b: IpAddr) -> (IpAddr, IpAddr) {
+  match (to_ipv4(a), to_ipv4(b)) {
+    (Some(a), Some(b)) => (a, b),
+    _ => (IpAddr::V6(ipv6_octets(a).into()), IpAddr::V6(ipv6_octets(b).into())),
+  }
+}
+
+fn to_ipv4(ip: IpAddr) -> Option<IpAddr> {
+  match ip {
+    IpAddr::V4(_) => Some(ip),
+    IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4),
+  }
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+  match ip {
+    IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+    IpAddr::V6(ip) => ip.octets(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{
+    convert::TryInto,
+    net::{Ipv4Addr, Ipv6Addr},
+  };
+
+  /// Parses a header like a backend server would, returning the source and
+  /// destination address and the length of the header.
+  fn parse(bytes: &[u8]) -> Option<(SocketAddr, SocketAddr, usize)> {
+    if let Some(bytes) = bytes.strip_prefix(&V2_SIGNATURE[..]) {
+      let (version_command, family) = (bytes[0], bytes[1]);
+      let len = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+      let addresses = bytes.get(4..4 + len)?;
+      if version_command != 0x21 {
+        return None;
+      }
+      let (source, destination, ports) = match family {
+        0x11 if len == 12 => {
+          let source: [u8; 4] = addresses[0..4].try_into().ok()?;
+          let destination: [u8; 4] = addresses[4..8].try_into().ok()?;
+          (IpAddr::from(source), IpAddr::from(destination), &addresses[8..12])
+        }
+        0x21 if len == 36 => {
+          let source: [u8; 16] = addresses[0..16].try_into().ok()?;
+          let destination: [u8; 16] = addresses[16..32].try_into().ok()?;
+          (IpAddr::from(source), IpAddr::from(destination), &addresses[32..36])
+        }
+        _ => return None,
+      };
+      let source_port = u16::from_be_bytes([ports[0], ports[1]]);
+      let destination_port = u16::from_be_bytes([ports[2], ports[3]]);
+      return Some((
+        SocketAddr::new(source, source_port),
+        SocketAddr::new(destination, destination_port),
+        V2_SIGNATURE.len() + 4 + len,
+      ));
+    }
+    let end = bytes.windows(2).position(|it| it == b"\r\n")?;
+    let line = std::str::from_utf8(&bytes[..end]).ok()?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+    match parts.as_slice() {
+      ["PROXY", "TCP4", source, destination, source_port, destination_port]
+      | ["PROXY", "TCP6", source, destination, source_port, destination_port] => {
+        let source = SocketAddr::new(source.parse().ok()?, source_port.parse().ok()?);
+        let destination = SocketAddr::new(destination.parse().ok()?, destination_port.parse().ok()?);
+        if source.is_ipv4() != (parts[1] == "TCP4") || destination.is_ipv4() != source.is_ipv4() {
+          return None;
+        }
+        Some((source, destination, end + 2))
+      }
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn test_headers_round_trip() {
+    let ipv4 = |port| SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), port));
+    let ipv6 = |port| SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), port));
+    for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+      for (source, destination) in [(ipv4(50000), ipv4(443)), (ipv6(50000), ipv6(443))] {
+        // given:
+        let mut bytes = header(version, source, destination);
+        let len = bytes.len();
+        bytes.extend_from_slice(b"SSH-2.0");
+
+        // when:
+        let parsed = parse(&bytes);
+
+        // then:
+        assert_eq!(parsed, Some((source, destination, len)), "{:?}", version);
+      }
+    }
+  }
+
+  #[test]
+  fn test_v1_header() {
+    let header = header(
+      ProxyProtocolVersion::V1,
+      "192.0.2.1:50000".parse().unwrap(),
+      "198.51.100.1:443".parse().unwrap(),
+    );
+
+    assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.1 50000 443\r\n");
+  }
+
+  #[test]
+  fn test_mixed_families_are_sent_as_the_same_family() {
+    // given: an IPv4 client of a dual stack listener and an IPv4 client of an IPv6 address
+    let mapped = "[::ffff:192.0.2.1]:50000".parse().unwrap();
+    let ipv4 = "198.51.100.1:443".parse().unwrap();
+    let ipv6 = "[2001:db8::1]:443".parse().unwrap();
+
+    for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+      // when:
+      let unmapped = parse(&header(version, mapped, ipv4)).unwrap();
+      let both_ipv6 = parse(&header(version, ipv4, ipv6)).unwrap();
+
+      // then:
+      assert_eq!(unmapped.0, "192.0.2.1:50000".parse().unwrap());
+      assert_eq!(both_ipv6.0, "[::ffff:198.51.100.1]:443".parse().unwrap());
+      assert_eq!(both_ipv6.1, ipv6);
+    }
+  }
+}
@@ -0,0 +1,210 @@
+use crate::{
+  configuration::RuntimeConfig,
+  health::{self, Healthiness},
+  server::BackendPool,
+};
+use once_cell::sync::Lazy;
+use std::{
+  net::SocketAddr,
+  sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+/// Whether the listeners of the load balancer are bound, for the readiness
+/// endpoint of the admin API and applications embedding the load balancer.
+pub static READINESS: Lazy<Readiness> = Lazy::new(Readiness::new);
+
+/// The address a listener is bound to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoundAddress {
+  /// Like `http_address`, `https_address`, `metrics_address`, the `name` of
+  /// one of the `listeners` or `tcp_router_services[0]`.
+  pub listener: String,
+  pub address: SocketAddr,
+}
+
+/// What the readiness endpoint checks besides the listeners.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadinessConfig {
+  /// Whether each required pool needs a healthy backend server.
+  pub require_healthy_backends: bool,
+  /// The names of the required pools, all pools if `None`.
+  pub pools: Option<Vec<String>>,
+}
+
+impl Default for ReadinessConfig {
+  fn default() -> Self {
+    ReadinessConfig {
+      require_healthy_backends: true,
+      pools: None,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+enum Phase {
+  Binding,
+  Bound(Arc<Vec<BoundAddress>>),
+  Stopped,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+  listeners: usize,
+  bound: Vec<BoundAddress>,
+}
+
+pub struct Readiness {
+  pending: Mutex<Pending>,
+  sender: watch::Sender<Phase>,
+  /// Keeps the channel open, so values can be sent without other receivers.
+  receiver: watch::Receiver<Phase>,
+}
+
+impl Readiness {
+  fn new() -> Readiness {
+    let (sender, receiver) = watch::channel(Phase::Binding);
+    Readiness {
+      pending: Mutex::new(Pending::default()),
+      sender,
+      receiver,
+    }
+  }
+
+  /// Starts to wait for the given number of `listeners` to be bound.
+  pub fn start(&self, listeners: usize) {
+    *self.pending.lock().unwrap() = Pending {
+      listeners,
+      bound: Vec::new(),
+    };
+    self.send(if listeners == 0 {
+      Phase::Bound(Arc::default())
+    } else {
+      Phase::Binding
+    });
+  }
+
+  /// Records, that a listener was bound. Listeners without a socket address,
+  /// like unix domain sockets, pass `None`.
+  pub fn bound(&self, listener: &str, address: Option<SocketAddr>) {
+    let mut pending = self.pending.lock().unwrap();
+    if let Some(address) = address {
+      pending.bound.push(BoundAddress {
+        listener: listener.to_string(),
+        address,
+      });
+    }
+    pending.listeners = pending.listeners.saturating_sub(1);
+    if pending.listeners == 0 {
+      self.send(Phase::Bound(Arc::new(pending.bound.clone())));
+    }
+  }
+
+  /// Records, that the load balancer stopped, so nobody waits for its
+  /// listeners anymore.
+  pub fn stop(&self) {
+    self.send(Phase::Stopped);
+  }
+
+  fn send(&self, phase: Phase) {
+    // The channel is open, because the receiver is never dropped
+    let _ = self.sender.send(phase);
+  }
+
+  pub fn listeners_bound(&self) -> bool {
+    matches!(*self.receiver.borrow(), Phase::Bound(_))
+  }
+
+  /// Waits until all listeners are bound and returns their addresses, or
+  /// `None` if the load balancer stopped before.
+  pub async fn bound_addresses(&self) -> Option<Vec<BoundAddress>> {
+    let mut receiver = self.receiver.clone();
+    loop {
+      match &*receiver.borrow() {
+        Phase::Binding => {}
+        Phase::Bound(addresses) => return Some(addresses.to_vec()),
+        Phase::Stopped => return None,
+      }
+      receiver.changed().await.ok()?;
+    }
+  }
+}
+
+/// Whether the load balancer is ready to serve requests: all listeners are
+/// bound (which implies, that the certificates were loaded) and, if required,
+/// each required pool has a healthy backend server. Returns the reason
+/// otherwise.
+pub fn check(config: &RuntimeConfig) -> Result<(), String> {
+  if !READINESS.listeners_bound() {
+    return Err("Not all listeners are bound yet".into());
+  }
+  if !config.readiness.require_healthy_backends {
+    return Ok(());
+  }
+  let required = |pool: &BackendPool| match &config.readiness.pools {
+    Some(pools) => pools.contains(&pool.name),
+    None => true,
+  };
+  for pool in config.shared_data.backend_pools.iter().filter(|it| required(it)) {
+    if !has_healthy_backend(pool, config) {
+      return Err(format!("The pool '{}' has no healthy backend server", pool.name));
+    }
+  }
+  Ok(())
+}
+
+/// Backend servers start as healthy, so those, which are health checked, are
+/// only considered healthy once they passed a check. Pools, which send static
+/// responses or have no backend servers to check, are always ready.
+fn has_healthy_backend(pool: &BackendPool, config: &RuntimeConfig) -> bool {
+  if pool.respond.is_some() || pool.in_maintenance() || pool.addresses.is_empty() {
+    return true;
+  }
+  pool.addresses.iter().any(|(address, healthiness)| {
+    if matches!(**healthiness.load(), Healthiness::Unresponsive(_)) {
+      return false;
+    }
+    !health::is_health_checked(&pool.health_config, address, config.health_interval)
+      || matches!(health::last_check(&pool.name, address), Some(it) if it.passed)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use tokio::time::timeout;
+
+  #[tokio::test]
+  async fn test_bound_addresses_are_available_once_all_listeners_are_bound() {
+    // given:
+    let readiness = Readiness::new();
+    let first = "127.0.0.1:8080".parse().unwrap();
+    let second = "127.0.0.1:8443".parse().unwrap();
+    readiness.start(3);
+
+    // when:
+    readiness.bound("http_address", Some(first));
+    readiness.bound("unix_socket", None);
+    let before = timeout(Duration::from_millis(10), readiness.bound_addresses()).await;
+    readiness.bound("https_address", Some(second));
+    let after = readiness.bound_addresses().await;
+
+    // then:
+    assert!(before.is_err());
+    assert!(readiness.listeners_bound());
+    assert_eq!(
+      after.unwrap(),
+      vec![
+        BoundAddress {
+          listener: "http_address".into(),
+          address: first
+        },
+        BoundAddress {
+          listener: "https_address".into(),
+          address: second
+        },
+      ]
+    );
+  }
+}
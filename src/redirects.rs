@@ -0,0 +1,237 @@
+use crate::{configuration::RedirectRuleConfig, metrics::METRICS, static_response::LocalResponse};
+use hyper::{
+  header::{HeaderValue, HOST, LOCATION},
+  Body, Request, Response, StatusCode, Uri,
+};
+use log::warn;
+use regex::Regex;
+use std::{convert::TryFrom, io};
+
+/// Redirects requests, whose host and path match, before any backend pool is
+/// selected.
+#[derive(Debug)]
+pub struct RedirectRule {
+  /// Names the rule in the access log and in metrics.
+  pub id: String,
+  /// Matches requests to this host (ignoring the port), or all hosts if `None`.
+  pub host: Option<String>,
+  /// Matches the path of requests. Its captures can be referenced in the
+  /// `target` like `$1` or `${name}`.
+  pub path: Regex,
+  pub target: String,
+  pub status: StatusCode,
+  /// Whether the query of the request is appended to the target.
+  pub preserve_query: bool,
+}
+
+/// Inserted into the extensions of redirects, so the access log can name the
+/// rule.
+#[derive(Debug, Clone)]
+pub struct Redirected {
+  pub rule: String,
+}
+
+impl RedirectRule {
+  fn matches_host(&self, host: &str) -> bool {
+    self
+      .host
+      .as_ref()
+      .map(|it| it.eq_ignore_ascii_case(host))
+      .unwrap_or(true)
+  }
+
+  /// The location of the redirect of `request`, if this rule matches it.
+  fn location(&self, host: &str, uri: &Uri) -> Option<String> {
+    if !self.matches_host(host) {
+      return None;
+    }
+    let captures = self.path.captures(uri.path())?;
+    let mut location = String::new();
+    captures.expand(&self.target, &mut location);
+    if let Some(query) = uri.query().filter(|_| self.preserve_query) {
+      location.push(if location.contains('?') { '&' } else { '?' });
+      location.push_str(query);
+    }
+    Some(location)
+  }
+
+  /// Whether the redirected request would reach the load balancer and match
+  /// this rule again. Redirects to other hosts can not be detected.
+  fn loops(&self, host: &str, location: &str) -> bool {
+    let location = match location.parse::<Uri>() {
+      Ok(location) => location,
+      Err(_) => return false,
+    };
+    let same_host = location.host().map(|it| it.eq_ignore_ascii_case(host)).unwrap_or(true);
+    same_host && self.matches_host(host) && self.path.is_match(location.path())
+  }
+}
+
+/// The redirect of the first rule, which matches `request`.
+pub fn redirect(rules: &[RedirectRule], request: &Request<Body>) -> Option<Response<Body>> {
+  if rules.is_empty() {
+    return None;
+  }
+  let host = request_host(request)?;
+  rules.iter().find_map(|rule| {
+    let location = rule.location(host, request.uri())?;
+    if rule.loops(host, &location) {
+      warn!(
+        "Redirect rule '{}' was skipped, because {} would be redirected to itself",
+        rule.id,
+        request.uri()
+      );
+      METRICS.increment("arlb_redirect_loops_total", &[("rule", &rule.id)]);
+      return None;
+    }
+    let location = HeaderValue::try_from(location).ok()?;
+    METRICS.increment("arlb_redirects_total", &[("rule", &rule.id)]);
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = rule.status;
+    response.headers_mut().insert(LOCATION, location);
+    response.extensions_mut().insert(LocalResponse);
+    response.extensions_mut().insert(Redirected { rule: rule.id.clone() });
+    Some(response)
+  })
+}
+
+/// The host of `request` without the port.
+fn request_host<B>(request: &Request<B>) -> Option<&str> {
+  match request.uri().host() {
+    Some(host) => Some(host),
+    None => {
+      let host = request.headers().get(HOST)?.to_str().ok()?;
+      Some(match host.rfind(':') {
+        // The colons of IPv6 addresses are enclosed in brackets
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+      })
+    }
+  }
+}
+
+impl TryFrom<RedirectRuleConfig> for RedirectRule {
+  type Error = io::Error;
+
+  fn try_from(other: RedirectRuleConfig) -> Result<Self, Self::Error> {
+    let status = match other.status {
+      301 | 302 | 307 | 308 => StatusCode::from_u16(other.status).unwrap(),
+      _ => return Err(invalid_data("The status must be 301, 302, 307 or 308")),
+    };
+    let path = Regex::new(&other.path).map_err(|e| invalid_data(format!("Invalid path pattern: {}", e)))?;
+    Ok(RedirectRule {
+      id: other.id,
+      host: other.host,
+      path,
+      target: other.target,
+      status,
+      preserve_query: other.preserve_query,
+    })
+  }
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+  E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+  io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rule(path: &str, target: &str, preserve_query: bool) -> RedirectRule {
+    RedirectRule {
+      id: "test".into(),
+      host: Some("old.example.com".into()),
+      path: Regex::new(path).unwrap(),
+      target: target.into(),
+      status: StatusCode::PERMANENT_REDIRECT,
+      preserve_query,
+    }
+  }
+
+  fn location(rules: &[RedirectRule], host: &str, uri: &str) -> Option<String> {
+    let request = Request::get(uri).header(HOST, host).body(Body::empty()).unwrap();
+    let response = redirect(rules, &request)?;
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(response.extensions().get::<Redirected>().unwrap().rule, "test");
+    Some(response.headers()[LOCATION].to_str().unwrap().to_string())
+  }
+
+  #[test]
+  fn test_captures_are_substituted() {
+    // given:
+    let rules = [rule(
+      "^/old/(?P<section>[^/]+)/(.*)$",
+      "https://new.example.com/${section}/v2/$2",
+      false,
+    )];
+
+    // when:
+    let matching = location(&rules, "Old.Example.com:8080", "/old/docs/intro.html?lang=en");
+    let other_path = location(&rules, "old.example.com", "/new/docs/intro.html");
+    let other_host = location(&rules, "example.com", "/old/docs/intro.html");
+
+    // then:
+    assert_eq!(matching.as_deref(), Some("https://new.example.com/docs/v2/intro.html"));
+    assert_eq!(other_path, None);
+    assert_eq!(other_host, None);
+  }
+
+  #[test]
+  fn test_query_is_preserved() {
+    // given:
+    let rules = [rule("^/search$", "https://new.example.com/find?source=old", true)];
+
+    // when:
+    let with_query = location(&rules, "old.example.com", "/search?q=rust&page=2");
+    let without_query = location(&rules, "old.example.com", "/search");
+
+    // then:
+    assert_eq!(
+      with_query.as_deref(),
+      Some("https://new.example.com/find?source=old&q=rust&page=2")
+    );
+    assert_eq!(
+      without_query.as_deref(),
+      Some("https://new.example.com/find?source=old")
+    );
+  }
+
+  #[test]
+  fn test_redirect_loops_are_skipped() {
+    // given:
+    let rules = [
+      rule("^/docs(/.*)?$", "/docs/index.html", false),
+      rule("^/docs(/.*)?$", "https://old.example.com/docs/latest", false),
+      rule("^/docs/(.*)$", "https://old.example.com/documentation/$1", false),
+    ];
+
+    // when:
+    let actual = location(&rules, "old.example.com", "/docs/intro.html");
+
+    // then: the first two rules would match their own targets
+    assert_eq!(
+      actual.as_deref(),
+      Some("https://old.example.com/documentation/intro.html")
+    );
+  }
+
+  #[test]
+  fn test_invalid_rules() {
+    let config = |status: u16, path: &str| RedirectRuleConfig {
+      id: "test".into(),
+      host: None,
+      path: path.into(),
+      target: "/".into(),
+      status,
+      preserve_query: true,
+    };
+
+    assert!(RedirectRule::try_from(config(308, "^/old$")).is_ok());
+    assert!(RedirectRule::try_from(config(200, "^/old$")).is_err());
+    assert!(RedirectRule::try_from(config(301, "^/old($")).is_err());
+  }
+}
@@ -0,0 +1,276 @@
+use crate::{
+  bans::{Violation, OFFENDERS},
+  error_response::bad_request,
+  metrics::METRICS,
+};
+use hyper::{
+  header::{HeaderValue, CONNECTION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING},
+  Body, HeaderMap, Request, Response, StatusCode, Version,
+};
+use log::debug;
+use std::{collections::HashSet, net::SocketAddr};
+
+/// Why a request was rejected. Requests, which a backend server could frame
+/// differently than the load balancer, are rejected, so they can not smuggle
+/// another request past the load balancer.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Rejection {
+  /// Both `Content-Length` and `Transfer-Encoding` (CL.TE and TE.CL).
+  ContentLengthWithTransferEncoding,
+  /// A `Content-Length`, which is not a number, or several different ones.
+  InvalidContentLength,
+  /// A `Transfer-Encoding` other than a single `chunked` (TE.TE).
+  InvalidTransferEncoding,
+  /// A header value with a NUL, CR or LF byte.
+  InvalidHeaderValue,
+  /// An absolute URI as request target, like `GET http://example.com/`.
+  AbsoluteForm,
+  /// Several different `Host` headers.
+  ConflictingHost,
+  /// More headers than the `max_count` of the [`HeaderLimits`].
+  TooManyHeaders,
+  /// Headers, which are larger than the `max_size` of the [`HeaderLimits`].
+  HeadersTooLarge,
+}
+
+impl Rejection {
+  /// The `reason` label of `arlb_rejected_requests_total`.
+  pub fn reason(self) -> &'static str {
+    match self {
+      Rejection::ContentLengthWithTransferEncoding => "content_length_with_transfer_encoding",
+      Rejection::InvalidContentLength => "invalid_content_length",
+      Rejection::InvalidTransferEncoding => "invalid_transfer_encoding",
+      Rejection::InvalidHeaderValue => "invalid_header_value",
+      Rejection::AbsoluteForm => "absolute_form",
+      Rejection::ConflictingHost => "conflicting_host",
+      Rejection::TooManyHeaders => "too_many_headers",
+      Rejection::HeadersTooLarge => "headers_too_large",
+    }
+  }
+
+  /// Responds with `400 Bad Request` (or `431 Request Header Fields Too
+  /// Large`) and closes the connection, because it is unclear where the next
+  /// request would start.
+  pub fn response(self, client_address: &SocketAddr) -> Response<Body> {
+    METRICS.increment("arlb_rejected_requests_total", &[("reason", self.reason())]);
+    OFFENDERS.record(client_address.ip(), Violation::InvalidRequest);
+    debug!("Rejected request of {}: {}", client_address, self.reason());
+    let mut response = match self {
+      Rejection::TooManyHeaders | Rejection::HeadersTooLarge => Response::builder()
+        .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        .body(Body::from(format!(
+          "431 - request header fields too large ({})",
+          self.reason()
+        )))
+        .unwrap(),
+      _ => bad_request(format!("400 - bad request ({})", self.reason())),
+    };
+    response
+      .headers_mut()
+      .insert(CONNECTION, HeaderValue::from_static("close"));
+    response
+  }
+}
+
+/// Limits the headers of requests and responses, so a client can not send a
+/// header bomb to a backend server (or the other way around). The size of the
+/// headers is the sum of the lengths of their names and values.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HeaderLimits {
+  pub max_size: Option<usize>,
+  pub max_count: Option<usize>,
+}
+
+impl HeaderLimits {
+  /// Checks the `headers` of a request or response of any HTTP version.
+  pub fn check(&self, headers: &HeaderMap) -> Result<(), Rejection> {
+    if matches!(self.max_count, Some(max_count) if headers.len() > max_count) {
+      return Err(Rejection::TooManyHeaders);
+    }
+    if let Some(max_size) = self.max_size {
+      let size: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+      if size > max_size {
+        return Err(Rejection::HeadersTooLarge);
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Checks the framing and target of a HTTP/1 `request`. Duplicate, but equal
+/// `Host` headers are collapsed into one. If `allow_absolute_form` is set, the
+/// `Host` of absolute-form requests is replaced by the authority of their URI,
+/// as required by RFC 7230.
+pub fn validate_request(request: &mut Request<Body>, allow_absolute_form: bool) -> Result<(), Rejection> {
+  // HTTP/2 frames requests without these headers, the absolute URI is always set
+  if request.version() >= Version::HTTP_2 {
+    return Ok(());
+  }
+  let headers = request.headers();
+  let invalid_byte = |value: &HeaderValue| value.as_bytes().iter().any(|it| matches!(it, b'\0' | b'\r' | b'\n'));
+  if headers.values().any(invalid_byte) {
+    return Err(Rejection::InvalidHeaderValue);
+  }
+
+  let content_lengths = headers
+    .get_all(CONTENT_LENGTH)
+    .iter()
+    .map(|it| {
+      // Only digits, parsers disagree about signs and lists like `5, 5`
+      let value = it.to_str().ok()?.trim();
+      if !value.bytes().all(|it| it.is_ascii_digit()) {
+        return None;
+      }
+      value.parse::<u64>().ok()
+    })
+    .collect::<Option<HashSet<_>>>()
+    .ok_or(Rejection::InvalidContentLength)?;
+  if content_lengths.len() > 1 {
+    return Err(Rejection::InvalidContentLength);
+  }
+  let mut transfer_encodings = headers.get_all(TRANSFER_ENCODING).iter().peekable();
+  if transfer_encodings.peek().is_some() {
+    if !content_lengths.is_empty() {
+      return Err(Rejection::ContentLengthWithTransferEncoding);
+    }
+    let chunked = matches!(
+      transfer_encodings.next().map(HeaderValue::to_str),
+      Some(Ok(it)) if it.trim().eq_ignore_ascii_case("chunked")
+    );
+    if !chunked || transfer_encodings.next().is_some() {
+      return Err(Rejection::InvalidTransferEncoding);
+    }
+  }
+
+  let hosts = headers.get_all(HOST).iter().collect::<HashSet<_>>();
+  if hosts.len() > 1 {
+    return Err(Rejection::ConflictingHost);
+  }
+  let host = hosts.into_iter().next().cloned();
+
+  if request.uri().scheme().is_some() {
+    if !allow_absolute_form {
+      return Err(Rejection::AbsoluteForm);
+    }
+    let authority = request.uri().authority().map(|it| HeaderValue::from_str(it.as_str()));
+    if let Some(Ok(authority)) = authority {
+      request.headers_mut().insert(HOST, authority);
+    }
+  } else if let Some(host) = host {
+    // Replaces all duplicates
+    request.headers_mut().insert(HOST, host);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(target: &str, headers: &[(&str, &str)]) -> Request<Body> {
+    let mut request = Request::builder().uri(target);
+    for (name, value) in headers {
+      request = request.header(*name, *value);
+    }
+    request.body(Body::empty()).unwrap()
+  }
+
+  /// The target and headers of a request and the expected result.
+  type Case<'a> = (&'a str, &'a [(&'a str, &'a str)], Result<(), Rejection>);
+
+  #[test]
+  fn test_validate_request() {
+    let cases: &[Case] = &[
+      ("/", &[("host", "a"), ("content-length", "5")], Ok(())),
+      ("/", &[("host", "a"), ("transfer-encoding", "Chunked")], Ok(())),
+      (
+        "/",
+        &[("content-length", "5"), ("transfer-encoding", "chunked")],
+        Err(Rejection::ContentLengthWithTransferEncoding),
+      ),
+      (
+        "/",
+        &[("content-length", "5"), ("content-length", "6")],
+        Err(Rejection::InvalidContentLength),
+      ),
+      ("/", &[("content-length", "5, 5")], Err(Rejection::InvalidContentLength)),
+      ("/", &[("content-length", "+5")], Err(Rejection::InvalidContentLength)),
+      (
+        "/",
+        &[("transfer-encoding", "chunked"), ("transfer-encoding", "chunked")],
+        Err(Rejection::InvalidTransferEncoding),
+      ),
+      (
+        "/",
+        &[("transfer-encoding", "gzip, chunked")],
+        Err(Rejection::InvalidTransferEncoding),
+      ),
+      (
+        "/",
+        &[("transfer-encoding", "xchunked")],
+        Err(Rejection::InvalidTransferEncoding),
+      ),
+      ("/", &[("host", "a"), ("host", "a")], Ok(())),
+      ("/", &[("host", "a"), ("host", "b")], Err(Rejection::ConflictingHost)),
+      ("http://a/", &[("host", "a")], Err(Rejection::AbsoluteForm)),
+      ("*", &[("host", "a")], Ok(())),
+    ];
+    for (target, headers, expected) in cases {
+      let actual = validate_request(&mut request(target, headers), false);
+      assert_eq!(&actual, expected, "{} {:?}", target, headers);
+    }
+  }
+
+  #[test]
+  fn test_duplicate_hosts_are_collapsed() {
+    let mut request = request("/", &[("host", "a"), ("host", "a")]);
+
+    validate_request(&mut request, false).unwrap();
+
+    assert_eq!(request.headers().get_all(HOST).iter().count(), 1);
+  }
+
+  #[test]
+  fn test_allowed_absolute_form_replaces_host() {
+    let mut request = request("http://b:8080/", &[("host", "a")]);
+
+    validate_request(&mut request, true).unwrap();
+
+    assert_eq!(request.headers()[HOST], "b:8080");
+  }
+
+  #[test]
+  fn test_header_limits() {
+    let limits = HeaderLimits {
+      max_size: Some(16),
+      max_count: Some(2),
+    };
+    let check = |headers: &[(&str, &str)]| limits.check(request("/", headers).headers());
+
+    assert_eq!(check(&[("host", "a"), ("accept", "*/*")]), Ok(()));
+    // Duplicate headers count separately
+    assert_eq!(
+      check(&[("a", "1"), ("a", "2"), ("a", "3")]),
+      Err(Rejection::TooManyHeaders)
+    );
+    assert_eq!(check(&[("cookie", "0123456789a")]), Err(Rejection::HeadersTooLarge));
+    assert_eq!(
+      HeaderLimits::default().check(request("/", &[("a", "1")]).headers()),
+      Ok(())
+    );
+  }
+
+  #[test]
+  fn test_http2_requests_are_not_validated() {
+    let mut request = Request::builder()
+      .version(Version::HTTP_2)
+      .uri("https://a/")
+      .body(Body::empty())
+      .unwrap();
+
+    assert_eq!(validate_request(&mut request, false), Ok(()));
+  }
+}
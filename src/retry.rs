@@ -0,0 +1,181 @@
+use crate::{
+  connections::LiveConnection, events::ConnectionId, geoip::GeoInfo, listeners::PeerCredentials,
+  middleware::request_id::RequestIdentifier, tls::TlsInfo, trusted_proxies::ForwardedBy,
+};
+use hyper::{
+  body::{Bytes, HttpBody},
+  http::{request::Parts, Extensions},
+  Body, Method, Request, StatusCode,
+};
+use std::sync::Arc;
+
+/// Responses with these status codes are retried. Connection failures are
+/// also retried, because they result in `502 Bad Gateway`.
+const RETRY_STATUSES: [StatusCode; 3] = [
+  StatusCode::BAD_GATEWAY,
+  StatusCode::SERVICE_UNAVAILABLE,
+  StatusCode::GATEWAY_TIMEOUT,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryConfig {
+  /// How often a request is sent to another backend server at most.
+  pub max_retries: usize,
+  /// Requests with other methods are never retried.
+  pub methods: Vec<Method>,
+  /// Requests with larger bodies (in bytes) or bodies of unknown length are
+  /// sent without retries, so they do not have to be buffered.
+  pub max_body_size: u64,
+}
+
+impl RetryConfig {
+  /// The idempotent methods, which can safely be sent multiple times.
+  pub fn default_methods() -> Vec<Method> {
+    vec![Method::GET, Method::HEAD, Method::PUT, Method::DELETE, Method::OPTIONS]
+  }
+
+  pub fn applies_to(&self, request: &Request<Body>) -> bool {
+    self.max_retries > 0 && self.methods.contains(request.method()) && fits(request, self.max_body_size)
+  }
+}
+
+/// Whether the length of the body of `request` is known and at most
+/// `max_body_size`, so it can be buffered as a [`ReplayableRequest`].
+pub fn fits(request: &Request<Body>, max_body_size: u64) -> bool {
+  matches!(request.body().size_hint().exact(), Some(size) if size <= max_body_size)
+}
+
+pub fn is_retryable(status: StatusCode) -> bool {
+  RETRY_STATUSES.contains(&status)
+}
+
+/// Copies the extensions, which the load balancer attached to a request of a
+/// client, to a copy of the request. Extensions can not be cloned as a whole,
+/// so each of them is copied on its own.
+pub fn copy_extensions(from: &Extensions, to: &mut Extensions) {
+  fn copy<T: Clone + Send + Sync + 'static>(from: &Extensions, to: &mut Extensions) {
+    if let Some(extension) = from.get::<T>() {
+      to.insert(extension.clone());
+    }
+  }
+  copy::<TlsInfo>(from, to);
+  copy::<GeoInfo>(from, to);
+  copy::<ForwardedBy>(from, to);
+  copy::<ConnectionId>(from, to);
+  copy::<Arc<LiveConnection>>(from, to);
+  copy::<RequestIdentifier>(from, to);
+  copy::<PeerCredentials>(from, to);
+}
+
+/// A request, whose body is buffered, so it can be sent multiple times.
+pub struct ReplayableRequest {
+  parts: Parts,
+  body: Bytes,
+}
+
+impl ReplayableRequest {
+  pub async fn new(request: Request<Body>) -> Result<ReplayableRequest, hyper::Error> {
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    Ok(ReplayableRequest { parts, body })
+  }
+
+  pub fn request(&self) -> Request<Body> {
+    let mut request = Request::new(Body::from(self.body.clone()));
+    *request.method_mut() = self.parts.method.clone();
+    *request.uri_mut() = self.parts.uri.clone();
+    *request.version_mut() = self.parts.version;
+    *request.headers_mut() = self.parts.headers.clone();
+    copy_extensions(&self.parts.extensions, request.extensions_mut());
+    request
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(method: Method, body: Body) -> Request<Body> {
+    Request::builder().method(method).body(body).unwrap()
+  }
+
+  #[test]
+  fn test_applies_only_to_configured_methods() {
+    let config = RetryConfig {
+      max_retries: 2,
+      methods: RetryConfig::default_methods(),
+      max_body_size: 1024,
+    };
+
+    assert!(config.applies_to(&request(Method::GET, Body::empty())));
+    assert!(config.applies_to(&request(Method::PUT, Body::empty())));
+    assert!(!config.applies_to(&request(Method::POST, Body::empty())));
+    assert!(!config.applies_to(&request(Method::PATCH, Body::empty())));
+  }
+
+  #[test]
+  fn test_does_not_apply_without_retries() {
+    let config = RetryConfig {
+      max_retries: 0,
+      methods: RetryConfig::default_methods(),
+      max_body_size: 1024,
+    };
+
+    assert!(!config.applies_to(&request(Method::GET, Body::empty())));
+  }
+
+  #[test]
+  fn test_does_not_apply_to_large_or_streamed_bodies() {
+    let config = RetryConfig {
+      max_retries: 1,
+      methods: RetryConfig::default_methods(),
+      max_body_size: 4,
+    };
+    let (_sender, streamed) = Body::channel();
+
+    assert!(config.applies_to(&request(Method::PUT, Body::from("four"))));
+    assert!(!config.applies_to(&request(Method::PUT, Body::from("five!"))));
+    assert!(!config.applies_to(&request(Method::PUT, streamed)));
+  }
+
+  #[tokio::test]
+  async fn test_replayable_request() {
+    // given:
+    let mut request = Request::builder()
+      .method(Method::PUT)
+      .uri("/resource?id=1")
+      .header("content-type", "text/plain")
+      .body(Body::from("content"))
+      .unwrap();
+    request.extensions_mut().insert(TlsInfo::default());
+    request.extensions_mut().insert(GeoInfo::default());
+    request
+      .extensions_mut()
+      .insert(ForwardedBy("10.0.0.1:3000".parse().unwrap()));
+    request.extensions_mut().insert(ConnectionId(7));
+    request
+      .extensions_mut()
+      .insert(PeerCredentials { uid: 1000, pid: None });
+
+    // when:
+    let replayable = ReplayableRequest::new(request).await.unwrap();
+
+    // then:
+    for _ in 0..2 {
+      let request = replayable.request();
+      assert_eq!(request.method(), Method::PUT);
+      assert_eq!(request.uri(), "/resource?id=1");
+      assert_eq!(request.headers().get("content-type").unwrap(), "text/plain");
+      assert!(request.extensions().get::<TlsInfo>().is_some());
+      assert!(request.extensions().get::<GeoInfo>().is_some());
+      assert_eq!(
+        request.extensions().get::<ForwardedBy>(),
+        Some(&ForwardedBy("10.0.0.1:3000".parse().unwrap()))
+      );
+      assert_eq!(ConnectionId::of(&request), 7);
+      assert!(request.extensions().get::<PeerCredentials>().is_some());
+      let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+      assert_eq!(body, "content");
+    }
+  }
+}
@@ -1,72 +1,165 @@
 use crate::{
   acme::AcmeHandler,
+  address_policy::{AddressFilter, AddressPolicy},
+  backend_drains::{self, DrainMode},
   backend_pool_matcher::BackendPoolMatcher,
+  bandwidth::BACKEND_BANDWIDTH,
+  builtin_backends::Synthetic,
+  concurrency_limit::{ConcurrencyLimitConfig, ConcurrencyLimiter, Priority, Rejection, Slot},
   configuration::RuntimeConfig,
-  error_response::{bad_gateway, not_found},
-  health::{HealthConfig, Healthiness},
-  http_client::StrategyNotifyHttpConnector,
-  listeners::RemoteAddress,
-  load_balancing::{self, LoadBalancingStrategy},
-  middleware::MiddlewareChain,
+  connections::{LiveConnection, Phase, Tracked, TrackingAcceptor},
+  drain::Counted,
+  error::Error,
+  error_response::{bad_gateway, bad_request, gateway_timeout, misdirected_request, not_found, service_unavailable},
+  events::{ConnectionId, Event, Observed, RequestSummary, EVENTS},
+  expect_continue,
+  geoip::GeoInfo,
+  health::{HealthConfig, Healthiness, BACKEND_AVAILABLE},
+  http_client::{
+    self, ActiveConnections, BackendConnector, BackendTls, IpFamily, StrategyNotifyHttpConnector, TcpKeepalive,
+  },
+  keep_alive::{KeepAlive, KeepAliveAcceptor, KeepAliveLimits, KeepAliveStream},
+  listeners::{ListenerConfig, PeerCredentials, RemoteAddress},
+  load_balancing::{self, LoadBalancingStrategy, RequestForwarder, SELECTION_TIMING},
+  logging::{self, ACCESS_LOG_TARGET},
+  maintenance_windows::MaintenanceWindow,
+  metrics::METRICS,
+  middleware::{self, MiddlewareChain, ResponseTimedOut},
+  outlier_detection::{OutlierDetectionConfig, OutlierDetector},
+  prewarm::WarmConnections,
+  redirects::{self, Redirected},
+  request_validation::{validate_request, HeaderLimits},
+  retry::{self, is_retryable, ReplayableRequest, RetryConfig},
+  static_response::{LocalResponse, StaticResponse},
+  telemetry::{ConnectionSpan, RequestSpan, TELEMETRY},
+  tls::{host_matches_server_name, SniHostCheck, TlsInfo},
+  top_talkers::{Talking, TalkingAcceptor},
+  upstream_proxy::UpstreamProxy,
 };
 use arc_swap::ArcSwap;
-use futures::Future;
-use futures::TryFutureExt;
+use futures::{future::Either, Future};
+use futures::{StreamExt, TryFutureExt};
 use hyper::{
+  body::{Bytes, HttpBody},
+  client::connect::HttpInfo,
+  header::{HOST, RETRY_AFTER},
   server::accept::Accept,
   service::{make_service_fn, Service},
-  Body, Client, Request, Response, Server,
+  Body, Client, Method, Request, Response, Server, Uri, Version,
 };
-use log::debug;
+use log::{debug, info, log_enabled, warn, Level};
+use once_cell::sync::Lazy;
+use rand::{thread_rng, Rng};
 use serde::Deserialize;
 use std::{
-  collections::HashSet,
-  error::Error,
+  cmp::Reverse,
+  collections::{HashMap, HashSet},
   fmt::Display,
   io,
-  net::SocketAddr,
+  net::{IpAddr, SocketAddr},
   pin::Pin,
-  sync::Arc,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, Weak,
+  },
   task::{Context, Poll},
-  time::Duration,
+  time::{Duration, Instant},
 };
 use tokio::io::{AsyncRead, AsyncWrite};
 
-pub async fn create<'a, I, IE, IO>(
+/// Serves requests of `acceptor` until `drain` resolves. Afterwards no new
+/// connections are accepted, but existing connections are served until they
+/// are closed. Requests of an additional `listener` are only served by its
+/// pools. Each connection is served with the configuration, which was current
+/// when it was accepted, so a reload only applies to new connections.
+pub async fn create<'a, I, IE, IO, D>(
   acceptor: I,
   config: Arc<ArcSwap<RuntimeConfig>>,
   scheme: Scheme,
-) -> Result<(), io::Error>
+  listener: Option<String>,
+  drain: D,
+) -> Result<(), Error>
 where
+  D: Future<Output = ()>,
   I: Accept<Conn = IO, Error = IE>,
-  IE: Into<Box<dyn Error + Send + Sync>>,
+  IE: Into<Box<dyn std::error::Error + Send + Sync>>,
   IO: AsyncRead + AsyncWrite + Unpin + Send + RemoteAddress + 'static,
 {
-  let service = make_service_fn(move |stream: &IO| {
-    let client_address = stream.remote_addr().expect("No remote SocketAddr");
+  let limits = {
     let config = config.clone();
+    let listener = listener.clone();
+    move || keep_alive_limits(&config.load(), listener.as_deref())
+  };
+  let acceptor = TrackingAcceptor::new(acceptor, listener.clone(), scheme.to_string());
+  let acceptor = KeepAliveAcceptor::new(TalkingAcceptor::new(acceptor), limits);
+  let service = make_service_fn(move |stream: &KeepAliveStream<Talking<Tracked<IO>>>| {
+    let client_address = stream.remote_addr().expect("No remote SocketAddr");
+    let lifecycle = stream.lifecycle();
+    let tls_info = stream.tls_info();
+    let peer_credentials = stream.peer_credentials();
+    let live = stream.connection();
+    // TLS connections of the HTTP port are served like HTTPS connections
+    let scheme = if tls_info.is_some() { Scheme::HTTPS } else { scheme };
+    let config = config.load_full();
+    let geo_info = config.geoip.as_ref().map(|it| it.lookup(client_address.ip()));
+    let listener = listener.clone();
+    let span = match &listener {
+      Some(name) => TELEMETRY.connection_span(client_address, name),
+      None => TELEMETRY.connection_span(client_address, &scheme),
+    };
 
     async move {
-      Ok::<_, io::Error>(MainService {
-        client_address,
-        config,
-        scheme,
-      })
+      Ok::<_, io::Error>(Counted::new(KeepAlive::new(
+        Observed::new(
+          MainService {
+            client_address,
+            tls_info: tls_info.clone(),
+            peer_credentials,
+            geo_info,
+            config,
+            scheme,
+            listener,
+            span,
+          },
+          scheme,
+          client_address,
+          tls_info.as_ref(),
+          live,
+        ),
+        lifecycle,
+      )))
     }
   });
   Server::builder(acceptor)
     .serve(service)
-    .map_err(|e| {
-      let msg = format!("Failed to listen server: {}", e);
-      io::Error::new(io::ErrorKind::Other, msg)
-    })
+    .with_graceful_shutdown(drain)
+    .err_into()
     .await
 }
 
+/// The limits of the connections of the `listener` or, if there is none, of
+/// the HTTP, HTTPS and unix socket listeners.
+fn keep_alive_limits(config: &RuntimeConfig, listener: Option<&str>) -> KeepAliveLimits {
+  config
+    .listeners
+    .iter()
+    .find(|it| Some(it.name.as_str()) == listener)
+    .map_or(config.keep_alive, |it| it.keep_alive)
+}
+
 pub struct MainService {
   client_address: SocketAddr,
-  config: Arc<ArcSwap<RuntimeConfig>>,
+  tls_info: Option<TlsInfo>,
+  /// The client process, if it is connected via a unix domain socket.
+  peer_credentials: Option<PeerCredentials>,
+  geo_info: Option<GeoInfo>,
+  /// The configuration at the time the connection was accepted.
+  config: Arc<RuntimeConfig>,
   scheme: Scheme,
+  /// The name of the additional listener, which accepted the connection.
+  listener: Option<String>,
+  /// Only exists, if spans are exported.
+  span: Option<ConnectionSpan>,
 }
 
 impl Service<Request<Body>> for MainService {
@@ -81,54 +174,248 @@ impl Service<Request<Body>> for MainService {
     Poll::Ready(Ok(()))
   }
 
-  fn call(&mut self, request: Request<Body>) -> Self::Future {
+  fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+    if let Some(peer_credentials) = self.peer_credentials {
+      request.extensions_mut().insert(peer_credentials);
+    }
+    let client_address = self.config.trusted_proxies.apply(self.client_address, &mut request);
+    let access_log_entry = AccessLogEntry::new(&client_address, &request);
+    let connection = ConnectionId::of(&request);
+    let fields = self.log_fields(&request, &client_address);
+    let (request, unread_body) = expect_continue::track(request);
+    let response = self.handle_request(request, client_address);
+    Box::pin(logging::scope_fields(fields, async move {
+      let mut response = response.await?;
+      if let Some(unread_body) = unread_body {
+        response = unread_body.withhold(response);
+      }
+      access_log_entry.log(&response);
+      EVENTS.emit(|| Event::RequestCompleted {
+        summary: access_log_entry.summary(connection, &response),
+      });
+      Ok(response)
+    }))
+  }
+}
+
+impl MainService {
+  /// The correlation fields of the log entries of `request`.
+  fn log_fields(&self, request: &Request<Body>, client_address: &SocketAddr) -> logging::Fields {
+    let mut fields = Vec::new();
+    if let Some(connection) = request.extensions().get::<ConnectionId>() {
+      fields.push(("connection_id", connection.0.to_string()));
+    }
+    let listener = match &self.listener {
+      Some(listener) => listener.clone(),
+      None => self.scheme.to_string(),
+    };
+    fields.push(("listener", listener));
+    fields.push(("client_ip", client_address.ip().to_string()));
+    fields
+  }
+
+  /// Handles a `request` of the `client_address`, which differs from the peer
+  /// of the connection, if the request was sent by a trusted proxy.
+  fn handle_request(
+    &mut self,
+    mut request: Request<Body>,
+    client_address: SocketAddr,
+  ) -> <Self as Service<Request<Body>>>::Future {
     debug!("{:#?} {} {}", request.version(), request.method(), request.uri());
+    let config = self.config.clone();
+    if let Some(tls_info) = &self.tls_info {
+      request.extensions_mut().insert(tls_info.clone());
+    }
+    let geo_info = if client_address == self.client_address {
+      self.geo_info.clone()
+    } else {
+      config.geoip.as_ref().map(|it| it.lookup(client_address.ip()))
+    };
+    if let Some(geo_info) = geo_info {
+      request.extensions_mut().insert(geo_info);
+    }
 
-    let config = self.config.load();
     let shared_data = &config.shared_data;
 
+    let validation = config
+      .header_limits
+      .check(request.headers())
+      .and_then(|()| validate_request(&mut request, config.allow_absolute_form));
+    if let Err(rejection) = validation {
+      let response = rejection.response(&client_address);
+      return Box::pin(async move { Ok(response) });
+    }
+
+    if let Some(response) = check_sni_host(config.tls.sni_host_check, self.tls_info.as_ref(), &request) {
+      return Box::pin(async move { Ok(response) });
+    }
+
     if let Some(response) = shared_data.acme_handler.respond_to_challenge(&request) {
       return Box::pin(async move { Ok(response) });
     }
 
-    match pool_by_req(&shared_data, &request, &self.scheme) {
+    let listener = self
+      .listener
+      .as_ref()
+      .and_then(|name| config.listeners.iter().find(|it| &it.name == name));
+
+    // Challenges are still answered, so certificates can be renewed during maintenance
+    if config.maintenance.load(Ordering::Relaxed) {
+      let response = config.maintenance_response.response();
+      let response = match pool_by_req(shared_data, &request, &self.scheme, listener.map(Arc::as_ref)) {
+        Some(pool) => {
+          record_local_response(&pool, &response);
+          routed_to(&pool, response)
+        }
+        None => response,
+      };
+      return Box::pin(async move { Ok(response) });
+    }
+
+    // Redirects take precedence over backend pools matching the same requests
+    if let Some(response) = redirects::redirect(&config.redirects, &request) {
+      return Box::pin(async move { Ok(response) });
+    }
+
+    match pool_by_req(shared_data, &request, &self.scheme, listener.map(Arc::as_ref)) {
       Some(pool) => {
+        if let Some(static_response) = pool.static_response() {
+          let response = static_response.response();
+          record_local_response(&pool, &response);
+          return Box::pin(async move { Ok(routed_to(&pool, response)) });
+        }
         let client_scheme = self.scheme;
-        let client_address = self.client_address;
+        let header_limits = config.header_limits;
+        let tenant = pool.tenant.as_ref().and_then(|it| config.tenants.get(it)).cloned();
+        let mirror = mirror_pool(shared_data, &pool, &request);
+        let span = self.span.as_ref().map(|it| it.request(&mut request, &pool.name));
+        let accepts_trailers = span.is_some() && middleware::accepts_trailers(request.headers());
+
+        // The head of the request was received completely
+        let timeouts = pool.timeouts(&request);
+        let deadline = timeouts.total.map(|it| Instant::now() + it);
 
         Box::pin(async move {
-          // clone, filter, map, LoadBalancingContext:backend_addresses
-          let mut working_addresses = pool
-            .addresses
-            .iter()
-            .filter(|(_, healthiness)| healthiness.load().as_ref() == &Healthiness::Healthy)
-            .map(|(address, _)| address.as_str())
-            .collect::<Vec<_>>();
-
-          if working_addresses.is_empty() {
-            // replace healthy addresses with slow addresses
-            working_addresses = pool
-              .addresses
-              .iter()
-              .filter(|(_, healthiness)| matches!(healthiness.load().as_ref(), Healthiness::Slow(_)))
-              .map(|(address, _)| address.as_str())
-              .collect::<Vec<_>>();
-          }
-          if working_addresses.is_empty() {
-            // we don't have any working addresses, so don't call load balancer strategy and abort early
-            // middlewares are also not running
-            Ok(bad_gateway())
-          } else {
-            let context = load_balancing::Context {
-              client_address: &client_address,
-              backend_addresses: &working_addresses,
-            };
-            let backend = pool.strategy.select_backend(&request, &context);
-            let result = backend
-              .forward_request_to_backend(request, &pool.chain, &client_scheme, &client_address, &pool.client)
-              .await;
-            Ok(result)
-          }
+          let respond = async {
+            let mut working_addresses = pool.routed_addresses(&request);
+            if let Some(timeout) = pool.backend_wait_timeout.filter(|_| working_addresses.is_empty()) {
+              working_addresses = wait_for_backend(&pool, &request, timeout).await;
+            }
+            if working_addresses.is_empty() {
+              // we don't have any working addresses, so don't call load balancer strategy and abort early
+              // middlewares are also not running
+              match &pool.unavailable {
+                Some(unavailable) => {
+                  let response = unavailable.response();
+                  record_local_response(&pool, &response);
+                  Ok(response)
+                }
+                None => Ok(bad_gateway()),
+              }
+            } else {
+              let route = pool.priority_route(&request);
+              let in_flight = match route {
+                Some(route) => match route.admit(&pool.name) {
+                  Some(in_flight) => Some(in_flight),
+                  None => return Ok(route.rejection_response()),
+                },
+                None => None,
+              };
+              let slot = match &pool.concurrency_limiter {
+                Some(limiter) => match acquire_slot(
+                  limiter,
+                  pool.priority(&request),
+                  &POOL_QUEUE_METRICS,
+                  &[("pool", &pool.name)],
+                  &format!("pool '{}'", pool.name),
+                )
+                .await
+                {
+                  Ok(slot) => Some(slot),
+                  Err(rejection) => {
+                    return Ok(match route {
+                      Some(route) => {
+                        route.reject(&pool.name, rejection.as_str());
+                        route.rejection_response()
+                      }
+                      None => service_unavailable(),
+                    })
+                  }
+                },
+                None => None,
+              };
+              let request = match &mirror {
+                Some(mirror) => match mirror_request(&pool, mirror, request, client_scheme, client_address).await {
+                  Ok(request) => request,
+                  Err(e) => return Ok(bad_request(format!("Could not read request body: {}", e))),
+                },
+                None => request,
+              };
+              let response = match pool.retry.as_ref().filter(|retry| retry.applies_to(&request)) {
+                Some(retry) => match ReplayableRequest::new(request).await {
+                  Ok(request) => {
+                    forward_with_retries(
+                      &pool,
+                      retry,
+                      &request,
+                      working_addresses,
+                      &timeouts,
+                      &client_scheme,
+                      &client_address,
+                    )
+                    .await
+                  }
+                  Err(e) => bad_request(format!("Could not read request body: {}", e)),
+                },
+                None => {
+                  forward_to_backend(
+                    &pool,
+                    request,
+                    &working_addresses,
+                    &timeouts,
+                    &client_scheme,
+                    &client_address,
+                  )
+                  .await
+                  .0
+                }
+              };
+              let response = check_response_headers(&pool, &header_limits, response);
+              Ok(if slot.is_some() || in_flight.is_some() {
+                release_after_body(response, (slot, in_flight))
+              } else {
+                response
+              })
+            }
+          };
+          // The quotas of the tenant apply to all requests of its pools, regardless of the client
+          let respond = async {
+            match &tenant {
+              Some(tenant) => match tenant.admit() {
+                Ok(slot) => Ok(tenant.limit_response(respond.await?, slot)),
+                Err(rejection) => {
+                  let response = rejection.response();
+                  record_local_response(&pool, &response);
+                  Ok(response)
+                }
+              },
+              None => respond.await,
+            }
+          };
+          let respond = async {
+            match deadline {
+              Some(deadline) => respond_before(&pool, respond, deadline).await,
+              None => respond.await,
+            }
+          };
+          let response = RequestSpan::scope(span.as_ref(), respond).await;
+          response.map(|response| {
+            let response = routed_to(&pool, response);
+            match span {
+              Some(span) => span.finish(response, accepts_trailers),
+              None => response,
+            }
+          })
         })
       }
       _ => Box::pin(async { Ok(not_found()) }),
@@ -136,35 +423,1147 @@ impl Service<Request<Body>> for MainService {
   }
 }
 
-fn pool_by_req(shared_data: &SharedData, request: &Request<Body>, scheme: &Scheme) -> Option<Arc<BackendPool>> {
-  shared_data
+/// Replaces a response of a backend server, whose headers exceed the
+/// `limits`, with `502 Bad Gateway`, so they are not sent to the client.
+fn check_response_headers(pool: &BackendPool, limits: &HeaderLimits, response: Response<Body>) -> Response<Body> {
+  if response.extensions().get::<LocalResponse>().is_some() {
+    return response;
+  }
+  match limits.check(response.headers()) {
+    Ok(()) => response,
+    Err(rejection) => {
+      warn!(
+        "Rejected a response of pool '{}', because of {}",
+        pool.name,
+        rejection.reason()
+      );
+      METRICS.increment(
+        "arlb_rejected_responses_total",
+        &[("pool", &pool.name), ("reason", rejection.reason())],
+      );
+      bad_gateway()
+    }
+  }
+}
+
+/// Compares the host of HTTPS requests with the server name sent via SNI and
+/// rejects mismatches, if the `check` is strict. Requests without a host never
+/// match.
+fn check_sni_host(check: SniHostCheck, tls_info: Option<&TlsInfo>, request: &Request<Body>) -> Option<Response<Body>> {
+  if check == SniHostCheck::RouteByHost {
+    return None;
+  }
+  let server_name = tls_info?.server_name.as_deref()?;
+  let host = request
+    .headers()
+    .get(HOST)
+    .and_then(|it| it.to_str().ok())
+    .or_else(|| request.uri().host());
+  match host {
+    Some(host) if host_matches_server_name(host, server_name) => return None,
+    _ => {}
+  }
+  warn!(
+    "The host '{}' of a request does not match the SNI server name '{}'",
+    host.unwrap_or("-"),
+    server_name
+  );
+  METRICS.increment("arlb_sni_host_mismatches_total", &[]);
+  match check {
+    SniHostCheck::Strict => Some(misdirected_request()),
+    _ => None,
+  }
+}
+
+/// The names of the metrics of a [`ConcurrencyLimiter`] queue.
+struct QueueMetrics {
+  queued: &'static str,
+  seconds: &'static str,
+  rejections: &'static str,
+}
+
+const POOL_QUEUE_METRICS: QueueMetrics = QueueMetrics {
+  queued: "arlb_pool_queued_requests",
+  seconds: "arlb_pool_queue_seconds",
+  rejections: "arlb_pool_queue_rejections_total",
+};
+
+const BACKEND_QUEUE_METRICS: QueueMetrics = QueueMetrics {
+  queued: "arlb_backend_queued_requests",
+  seconds: "arlb_backend_queue_seconds",
+  rejections: "arlb_backend_queue_rejections_total",
+};
+
+/// Waits for a free slot of the `limiter` of a pool or backend server (as
+/// described by `target`). Requests, which are rejected because the queue is
+/// full or they waited too long, are counted per reason.
+async fn acquire_slot(
+  limiter: &ConcurrencyLimiter,
+  priority: Priority,
+  metrics: &QueueMetrics,
+  labels: &[(&'static str, &str)],
+  target: &str,
+) -> Result<Slot, Rejection> {
+  if let Some(slot) = limiter.try_acquire() {
+    return Ok(slot);
+  }
+  METRICS.add_gauge(metrics.queued, labels, 1);
+  let result = limiter.enqueue(priority).await;
+  METRICS.add_gauge(metrics.queued, labels, -1);
+  match result {
+    Ok(slot) => {
+      let queued_for = slot.queued_for.unwrap_or_default();
+      METRICS.observe(metrics.seconds, labels, queued_for.as_secs_f64());
+      Ok(slot)
+    }
+    Err(rejection) => {
+      warn!(
+        "Rejected request to {}, because its concurrency limit was exceeded ({})",
+        target,
+        rejection.as_str()
+      );
+      let mut labels = labels.to_vec();
+      labels.push(("reason", rejection.as_str()));
+      METRICS.increment(metrics.rejections, &labels);
+      Err(rejection)
+    }
+  }
+}
+
+type BodyChunk = Result<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Awaits the response of `respond` and sends it to the client until the
+/// `deadline`. A single timer covers the whole exchange: if it fires before
+/// the head of the response, the client receives `504 Gateway Timeout`,
+/// otherwise the body is aborted, which closes the connection to the client
+/// and drops the one to the backend server.
+async fn respond_before<F>(pool: &BackendPool, respond: F, deadline: Instant) -> Result<Response<Body>, hyper::Error>
+where
+  F: Future<Output = Result<Response<Body>, hyper::Error>>,
+{
+  let mut deadline = Box::pin(tokio::time::sleep_until(deadline.into()));
+  tokio::pin!(respond);
+  let response = match futures::future::select(respond, deadline.as_mut()).await {
+    Either::Left((response, _)) => Some(response),
+    Either::Right(_) => None,
+  };
+  let response = match response {
+    Some(response) => response?,
+    None => {
+      warn!("No response of pool '{}' within its total_timeout", pool.name);
+      METRICS.increment(
+        "arlb_pool_total_timeouts_total",
+        &[("pool", &pool.name), ("phase", "head")],
+      );
+      return Ok(gateway_timeout());
+    }
+  };
+  let (parts, mut body) = response.into_parts();
+  let pool_name = pool.name.clone();
+  let mut expired = false;
+  let body = futures::stream::poll_fn(move |cx| -> Poll<Option<BodyChunk>> {
+    if expired {
+      return Poll::Ready(None);
+    }
+    if deadline.as_mut().poll(cx).is_ready() {
+      expired = true;
+      warn!(
+        "Aborted a response body of pool '{}', because it exceeded the total_timeout",
+        pool_name
+      );
+      METRICS.increment(
+        "arlb_pool_total_timeouts_total",
+        &[("pool", &pool_name), ("phase", "body")],
+      );
+      return Poll::Ready(Some(Err(
+        io::Error::new(io::ErrorKind::TimedOut, "The total_timeout was exceeded").into(),
+      )));
+    }
+    Pin::new(&mut body).poll_data(cx).map_err(Into::into)
+  });
+  Ok(Response::from_parts(parts, Body::wrap_stream(body)))
+}
+
+/// Aborts the body of a `response` of the backend server `backend_address`,
+/// once the backend server kept it waiting for the next chunk for the
+/// `idle_timeout`. Time, which the client takes to read the body, does not
+/// count. Aborting the body closes the connection to the client and drops the
+/// one to the backend server.
+fn abort_when_idle(
+  pool: &BackendPool,
+  backend_address: &str,
+  response: Response<Body>,
+  idle_timeout: Duration,
+) -> Response<Body> {
+  let (parts, mut body) = response.into_parts();
+  let pool_name = pool.name.clone();
+  let backend_address = backend_address.to_string();
+  let mut idle = Box::pin(tokio::time::sleep(idle_timeout));
+  let mut waiting = false;
+  let mut expired = false;
+  let body = futures::stream::poll_fn(move |cx| -> Poll<Option<BodyChunk>> {
+    if expired {
+      return Poll::Ready(None);
+    }
+    if !waiting {
+      waiting = true;
+      idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+    }
+    if let Poll::Ready(chunk) = Pin::new(&mut body).poll_data(cx) {
+      waiting = false;
+      return Poll::Ready(chunk.map(|it| it.map_err(Into::into)));
+    }
+    if idle.as_mut().poll(cx).is_ready() {
+      expired = true;
+      warn!(
+        "Aborted a response body of backend server '{}', which sent nothing for {:?}",
+        backend_address, idle_timeout
+      );
+      METRICS.increment(
+        "arlb_backend_idle_timeouts_total",
+        &[("pool", &pool_name), ("backend", &backend_address)],
+      );
+      return Poll::Ready(Some(Err(
+        io::Error::new(io::ErrorKind::TimedOut, "The idle_timeout was exceeded").into(),
+      )));
+    }
+    Poll::Pending
+  });
+  Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// Waits up to `timeout` for a backend server of `pool` to become available
+/// for `request`, for example while all of them are restarted. Returns no
+/// addresses if none became available in time.
+async fn wait_for_backend<'p>(pool: &'p BackendPool, request: &Request<Body>, timeout: Duration) -> Vec<&'p str> {
+  let deadline = tokio::time::Instant::now() + timeout;
+  loop {
+    // Created before checking the addresses, so no notification is missed
+    let available = BACKEND_AVAILABLE.notified();
+    let working_addresses = pool.routed_addresses(request);
+    if !working_addresses.is_empty() {
+      METRICS.increment(
+        "arlb_pool_backend_waits_total",
+        &[("pool", &pool.name), ("result", "available")],
+      );
+      return working_addresses;
+    }
+    if tokio::time::timeout_at(deadline, available).await.is_err() {
+      debug!(
+        "No backend server of pool '{}' became available within {:?}",
+        pool.name, timeout
+      );
+      METRICS.increment(
+        "arlb_pool_backend_waits_total",
+        &[("pool", &pool.name), ("result", "timeout")],
+      );
+      return Vec::new();
+    }
+  }
+}
+
+/// Keeps the `slot` until the body of the response was sent to the client.
+fn release_after_body<S: Send + 'static>(response: Response<Body>, slot: S) -> Response<Body> {
+  let (parts, body) = response.into_parts();
+  let body = Body::wrap_stream(body.map(move |chunk| {
+    let _slot = &slot;
+    chunk
+  }));
+  Response::from_parts(parts, body)
+}
+
+/// Explains the decision of the strategy of `pool`, so skewed load
+/// distributions can be traced back to the connection counts and weights.
+fn log_backend_selection(pool: &BackendPool, working_addresses: &[&str], selected: &str) {
+  let describe = |address: &str| {
+    let connections = pool.connections.get(address);
+    match pool.strategy.backend_weight(address) {
+      Some(weight) => format!("{} ({} connections, weight {})", address, connections, weight),
+      None => format!("{} ({} connections)", address, connections),
+    }
+  };
+  debug!(
+    "{} of pool '{}' selected backend server {} of [{}]",
+    pool.strategy.name(),
+    pool.name,
+    describe(selected),
+    working_addresses
+      .iter()
+      .map(|it| describe(it))
+      .collect::<Vec<_>>()
+      .join(", ")
+  );
+}
+
+/// Selects a backend server of `working_addresses` and forwards the `request`
+/// to it. Returns the response and the address of the selected backend server.
+async fn forward_to_backend(
+  pool: &BackendPool,
+  request: Request<Body>,
+  working_addresses: &[&str],
+  timeouts: &Timeouts,
+  client_scheme: &Scheme,
+  client_address: &SocketAddr,
+) -> (Response<Body>, String) {
+  let context = load_balancing::Context {
+    client_address,
+    backend_addresses: working_addresses,
+  };
+  let backend = SELECTION_TIMING.time(&pool.name, pool.strategy.name(), || {
+    match pinned_draining_backend(pool, &request, working_addresses, client_address) {
+      Some(address) => RequestForwarder::new(address),
+      None => pool.strategy.select_backend(&request, &context),
+    }
+  });
+  logging::add_field("backend", backend.backend_address());
+  if log_enabled!(Level::Debug) {
+    log_backend_selection(pool, working_addresses, backend.backend_address());
+  }
+  EVENTS.emit(|| Event::BackendSelected {
+    connection: ConnectionId::of(&request),
+    pool: pool.name.clone(),
+    backend: backend.backend_address().to_string(),
+    strategy: pool.strategy.name(),
+  });
+  // The live connection shows the backend server of the request, not the one of its mirror
+  let live = match request.extensions().get::<Mirrored>() {
+    Some(Mirrored) => None,
+    None => request.extensions().get::<Arc<LiveConnection>>().cloned(),
+  };
+  if let Some(live) = &live {
+    live.connecting(backend.backend_address());
+  }
+  let slot = match pool.backend_concurrency_limiters.get(backend.backend_address()) {
+    Some(limiter) => match acquire_slot(
+      limiter,
+      pool.priority(&request),
+      &BACKEND_QUEUE_METRICS,
+      &[("pool", &pool.name), ("backend", backend.backend_address())],
+      &format!("backend server '{}' of pool '{}'", backend.backend_address(), pool.name),
+    )
+    .await
+    {
+      Ok(slot) => Some(slot),
+      Err(_) => return (service_unavailable(), backend.backend_address().to_string()),
+    },
+    None => None,
+  };
+  let in_flight = pool.connections.start_request(backend.backend_address());
+  let accepts_trailers = middleware::accepts_trailers(request.headers());
+  let start = Instant::now();
+  let forward = backend.forward_request_to_backend(
+    request,
+    &pool.chain,
+    client_scheme,
+    client_address,
+    &pool.client,
+    timeouts.response,
+  );
+  let result = http_client::scope_connect_timeout(timeouts.connect, forward).await;
+  if let Some(ResponseTimedOut(response_timeout)) = result.extensions().get::<ResponseTimedOut>() {
+    warn!(
+      "Backend server '{}' did not respond within {:?}",
+      backend.backend_address(),
+      response_timeout
+    );
+    METRICS.increment(
+      "arlb_backend_response_timeouts_total",
+      &[("pool", &pool.name), ("backend", backend.backend_address())],
+    );
+  }
+  if let Some(live) = &live {
+    live.set_phase(Phase::Piping);
+  }
+  let result = if result.extensions().get::<LocalResponse>().is_some() {
+    // sent by a middleware (like the rate limiter) without involving the backend server
+    record_local_response(pool, &result);
+    result
+  } else {
+    record_backend_response(pool, backend.backend_address(), &result, start.elapsed());
+    if let Some(outlier_detector) = &pool.outlier_detector {
+      outlier_detector.record(backend.backend_address(), !outlier_detector.is_failure(result.status()));
+    }
+    match timeouts.idle {
+      Some(idle_timeout) => abort_when_idle(pool, backend.backend_address(), result, idle_timeout),
+      None => result,
+    }
+  };
+  // The connection serves no other request until the body was read, but
+  // wrapping the body would drop its trailers
+  let in_flight = Some(in_flight).filter(|_| !accepts_trailers && !result.body().is_end_stream());
+  let result = if slot.is_some() || in_flight.is_some() {
+    release_after_body(result, (slot, in_flight))
+  } else {
+    result
+  };
+  (result, backend.backend_address().to_string())
+}
+
+/// Forwards the `request` and sends it to another backend server, if the
+/// response [is retryable](is_retryable), until no retries or backend servers
+/// are left.
+async fn forward_with_retries(
+  pool: &BackendPool,
+  retry: &RetryConfig,
+  request: &ReplayableRequest,
+  mut working_addresses: Vec<&str>,
+  timeouts: &Timeouts,
+  client_scheme: &Scheme,
+  client_address: &SocketAddr,
+) -> Response<Body> {
+  let mut retries = 0;
+  loop {
+    let (response, backend_address) = forward_to_backend(
+      pool,
+      request.request(),
+      &working_addresses,
+      timeouts,
+      client_scheme,
+      client_address,
+    )
+    .await;
+    working_addresses.retain(|address| *address != backend_address);
+    if retries == retry.max_retries || working_addresses.is_empty() || !is_retryable(response.status()) {
+      return response;
+    }
+    retries += 1;
+    METRICS.increment(
+      "arlb_backend_retries_total",
+      &[("pool", &pool.name), ("backend", &backend_address)],
+    );
+  }
+}
+
+/// The soft draining backend server, which the client of `request` is pinned
+/// to by the strategy of the pool, if any.
+fn pinned_draining_backend<'p>(
+  pool: &'p BackendPool,
+  request: &Request<Body>,
+  working_addresses: &[&str],
+  client_address: &SocketAddr,
+) -> Option<&'p str> {
+  let soft_draining = pool.soft_draining_addresses();
+  if soft_draining.is_empty() {
+    return None;
+  }
+  // Keeps the order of the pool, so strategies like IPHash pin clients like before the drain
+  let candidates = pool
+    .addresses
+    .iter()
+    .map(|(address, _)| address.as_str())
+    .filter(|address| working_addresses.contains(address) || soft_draining.contains(address))
+    .collect::<Vec<_>>();
+  let context = load_balancing::Context {
+    client_address,
+    backend_addresses: &candidates,
+  };
+  let pinned = pool.strategy.pinned_backend(request, &context)?;
+  let pinned = soft_draining.into_iter().find(|it| *it == pinned)?;
+  backend_drains::record_pinned_session(&pool.name, pinned, client_address.ip());
+  Some(pinned)
+}
+
+/// Records the status code of the response and the time until its head was
+/// received (time to first byte) per backend server and pool.
+fn record_backend_response(pool: &BackendPool, backend_address: &str, response: &Response<Body>, elapsed: Duration) {
+  let status = response.status();
+  METRICS.increment(
+    "arlb_backend_responses_total",
+    &[
+      ("pool", &pool.name),
+      ("backend", backend_address),
+      ("status", status.as_str()),
+    ],
+  );
+  METRICS.increment(
+    "arlb_pool_responses_total",
+    &[("pool", &pool.name), ("status", status.as_str())],
+  );
+  if status.is_server_error() {
+    METRICS.increment(
+      "arlb_backend_server_errors_total",
+      &[("pool", &pool.name), ("backend", backend_address)],
+    );
+  }
+  METRICS.observe(
+    "arlb_backend_time_to_first_byte_seconds",
+    &[("pool", &pool.name), ("backend", backend_address)],
+    elapsed.as_secs_f64(),
+  );
+}
+
+/// Records the status code of a response sent by the load balancer itself.
+fn record_local_response(pool: &BackendPool, response: &Response<Body>) {
+  METRICS.increment(
+    "arlb_local_responses_total",
+    &[("pool", &pool.name), ("status", response.status().as_str())],
+  );
+}
+
+/// Marks a response with the pool, whose matcher selected it, for the access
+/// log.
+struct RoutedTo(Arc<BackendPool>);
+
+fn routed_to(pool: &Arc<BackendPool>, mut response: Response<Body>) -> Response<Body> {
+  response.extensions_mut().insert(RoutedTo(pool.clone()));
+  response
+}
+
+/// The details of a request, which are written to the access log once the
+/// response is available.
+struct AccessLogEntry {
+  client_address: SocketAddr,
+  connection: u64,
+  peer_credentials: Option<PeerCredentials>,
+  host: String,
+  method: Method,
+  uri: Uri,
+  version: Version,
+  start: Instant,
+}
+
+impl AccessLogEntry {
+  fn new(client_address: &SocketAddr, request: &Request<Body>) -> AccessLogEntry {
+    AccessLogEntry {
+      client_address: *client_address,
+      connection: ConnectionId::of(request),
+      peer_credentials: request.extensions().get::<PeerCredentials>().copied(),
+      host: request
+        .headers()
+        .get(HOST)
+        .and_then(|it| it.to_str().ok())
+        .or_else(|| request.uri().host())
+        .unwrap_or("-")
+        .to_string(),
+      method: request.method().clone(),
+      uri: request.uri().clone(),
+      version: request.version(),
+      start: Instant::now(),
+    }
+  }
+
+  fn summary(&self, connection: u64, response: &Response<Body>) -> RequestSummary {
+    RequestSummary {
+      connection,
+      method: self.method.clone(),
+      host: self.host.clone(),
+      uri: self.uri.clone(),
+      status: response.status(),
+      duration: self.start.elapsed(),
+      backend: response.extensions().get::<HttpInfo>().map(|it| it.remote_addr()),
+    }
+  }
+
+  fn log(&self, response: &Response<Body>) {
+    info!(target: ACCESS_LOG_TARGET, "{}", self.message(response));
+  }
+
+  fn message(&self, response: &Response<Body>) -> String {
+    // Marks responses, which did not involve any backend server or came from a
+    // builtin one, otherwise names the address of the backend server, which a
+    // host name resolved to
+    let backend = if let Some(redirected) = response.extensions().get::<Redirected>() {
+      format!(" redirect:{}", redirected.rule)
+    } else if let Some(Synthetic(backend)) = response.extensions().get::<Synthetic>() {
+      format!(" synthetic:{}", backend)
+    } else if response.extensions().get::<LocalResponse>().is_some() {
+      " local".to_string()
+    } else {
+      response
+        .extensions()
+        .get::<HttpInfo>()
+        .map(|it| format!(" {}", it.remote_addr()))
+        .unwrap_or_default()
+    };
+    let pool = response
+      .extensions()
+      .get::<RoutedTo>()
+      .map(|it| format!(" pool:{}", it.0.name))
+      .unwrap_or_default();
+    // Clients of unix domain sockets all have the same address
+    let peer = self.peer_credentials.map(|it| format!(" {}", it)).unwrap_or_default();
+    format!(
+      "{} {} \"{} {} {:?}\" {} {}ms conn:{}{}{}{}",
+      self.client_address.ip(),
+      self.host,
+      self.method,
+      self.uri,
+      self.version,
+      response.status().as_u16(),
+      self.start.elapsed().as_millis(),
+      self.connection,
+      peer,
+      pool,
+      backend
+    )
+  }
+}
+
+/// The most specific pool matching `request` (see
+/// [`BackendPoolMatcher::specificity`]), of equally specific ones the first.
+/// Requests of a `listener` are only matched against its pools, but may still
+/// be sent to their canary pools.
+fn pool_by_req(
+  shared_data: &SharedData,
+  request: &Request<Body>,
+  scheme: &Scheme,
+  listener: Option<&ListenerConfig>,
+) -> Option<Arc<BackendPool>> {
+  let pool = shared_data
     .backend_pools
     .iter()
     .filter(|pool| pool.supports(scheme))
-    .find(|pool| pool.matcher.matches(request))
+    .filter(|pool| listener.map(|it| it.serves(&pool.name)).unwrap_or(true))
+    .filter(|pool| pool.matcher.matches(request))
+    // The first of multiple minimums is returned
+    .min_by_key(|pool| Reverse((pool.precedence, pool.matcher.specificity())))?;
+  let canary = pool
+    .canary
+    .as_ref()
+    .filter(|canary| thread_rng().gen_bool(canary.percent / 100.0))
+    .and_then(|canary| {
+      shared_data
+        .backend_pools
+        .iter()
+        .filter(|it| it.supports(scheme))
+        .find(|it| it.name == canary.pool)
+    });
+  Some(canary.unwrap_or(pool).clone())
+}
+
+/// The mirror pool of `pool`, if `request` is picked by the `percent` of its
+/// mirror and has a body, which can be buffered.
+fn mirror_pool(shared_data: &SharedData, pool: &BackendPool, request: &Request<Body>) -> Option<Arc<BackendPool>> {
+  let mirror = pool
+    .mirror
+    .as_ref()
+    .filter(|mirror| thread_rng().gen_bool(mirror.percent / 100.0))?;
+  if !retry::fits(request, mirror.max_body_size) {
+    debug!(
+      "A request of pool '{}' is not mirrored, because its body is too large or streamed",
+      pool.name
+    );
+    return None;
+  }
+  shared_data
+    .backend_pools
+    .iter()
+    .find(|it| it.name == mirror.pool)
     .cloned()
 }
 
+/// Marks the copy of a request, which is sent to a mirror pool.
+#[derive(Clone, Copy)]
+struct Mirrored;
+
+/// Buffers the body of `request` and sends a copy of it to the `mirror` pool
+/// in the background. The response of the mirror pool is discarded, so
+/// neither it nor a failure affect the response to the client. Returns the
+/// `request` to send to the backend servers of `pool`.
+async fn mirror_request(
+  pool: &BackendPool,
+  mirror: &Arc<BackendPool>,
+  request: Request<Body>,
+  client_scheme: Scheme,
+  client_address: SocketAddr,
+) -> Result<Request<Body>, hyper::Error> {
+  let (parts, body) = request.into_parts();
+  let body = hyper::body::to_bytes(body).await?;
+  let mut copy = Request::new(Body::from(body.clone()));
+  *copy.method_mut() = parts.method.clone();
+  *copy.uri_mut() = parts.uri.clone();
+  *copy.version_mut() = parts.version;
+  *copy.headers_mut() = parts.headers.clone();
+  retry::copy_extensions(&parts.extensions, copy.extensions_mut());
+  copy.extensions_mut().insert(Mirrored);
+  METRICS.increment(
+    "arlb_mirrored_requests_total",
+    &[("pool", &pool.name), ("mirror", &mirror.name)],
+  );
+  let mirror = mirror.clone();
+  tokio::spawn(async move {
+    let working_addresses = mirror.routed_addresses(&copy);
+    if working_addresses.is_empty() {
+      debug!(
+        "Could not mirror a request, because pool '{}' has no working backend server",
+        mirror.name
+      );
+      return;
+    }
+    let timeouts = mirror.timeouts(&copy);
+    let (response, _) = forward_to_backend(
+      &mirror,
+      copy,
+      &working_addresses,
+      &timeouts,
+      &client_scheme,
+      &client_address,
+    )
+    .await;
+    // Reading the body to its end allows reusing the connection to the backend server
+    let mut body = response.into_body();
+    while let Some(Ok(_)) = body.data().await {}
+  });
+  Ok(Request::from_parts(parts, Body::from(body)))
+}
+
 pub struct SharedData {
   pub backend_pools: Vec<Arc<BackendPool>>,
   pub acme_handler: Arc<AcmeHandler>,
 }
 
+/// Sends a share of the requests of a pool to another pool, for example to
+/// roll out a new version of an application progressively.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanaryConfig {
+  /// The `name` of the other pool.
+  pub pool: String,
+  /// The share of requests in percent, between 0 and 100.
+  pub percent: f64,
+}
+
+/// Sends a copy of a share of the requests of a pool to another pool, for
+/// example to test a new version of an application with real traffic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MirrorConfig {
+  /// The `name` of the other pool.
+  pub pool: String,
+  /// The share of requests in percent, between 0 and 100.
+  pub percent: f64,
+  /// Requests with larger bodies (in bytes) or bodies of unknown length are
+  /// not mirrored, so they do not have to be buffered.
+  pub max_body_size: u64,
+}
+
+/// Sends the requests matching `matcher` only to the backend servers of a pool,
+/// which have all of the `tags`. The strategy of the pool selects one of them.
+#[derive(Debug)]
+pub struct TagRoute {
+  pub matcher: BackendPoolMatcher,
+  pub tags: HashMap<String, String>,
+}
+
+impl TagRoute {
+  /// Whether a backend server with the tags `backend_tags` is selected.
+  pub fn selects(&self, backend_tags: Option<&HashMap<String, String>>) -> bool {
+    self
+      .tags
+      .iter()
+      .all(|(key, value)| backend_tags.and_then(|it| it.get(key)) == Some(value))
+  }
+}
+
+/// The timeouts of the requests of a pool. Those of a [`PriorityRoute`]
+/// override the ones of its pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timeouts {
+  /// How long establishing a new connection to a backend server may take.
+  pub connect: Option<Duration>,
+  /// How long to wait for the head of a response from a backend server.
+  pub response: Option<Duration>,
+  /// How long the body of a response from a backend server may stall.
+  pub idle: Option<Duration>,
+  /// How long the whole response may take, including retries and its body.
+  pub total: Option<Duration>,
+}
+
+impl Timeouts {
+  /// These timeouts, falling back to the `defaults` for those, which are not
+  /// set.
+  pub fn or(&self, defaults: &Timeouts) -> Timeouts {
+    Timeouts {
+      connect: self.connect.or(defaults.connect),
+      response: self.response.or(defaults.response),
+      idle: self.idle.or(defaults.idle),
+      total: self.total.or(defaults.total),
+    }
+  }
+}
+
+/// Gives the requests matching `matcher` a priority for the slots of the
+/// concurrency limits of a pool and limits how many of them are in flight, so
+/// a flood of requests of one route can not take all slots of the pool.
+#[derive(Debug)]
+pub struct PriorityRoute {
+  /// Identifies the route in metrics.
+  pub name: String,
+  pub matcher: BackendPoolMatcher,
+  pub priority: Priority,
+  /// Limits the requests of this route, which are in flight at the same time.
+  /// Requests beyond it are rejected right away.
+  pub in_flight_limiter: Option<ConcurrencyLimiter>,
+  /// Sent as `Retry-After` with the rejections of requests of this route.
+  pub retry_after: Option<Duration>,
+  /// Overrides the timeouts of the pool for the requests of this route.
+  pub timeouts: Timeouts,
+}
+
+impl PriorityRoute {
+  /// Admits a request of this route, unless too many of its requests are in
+  /// flight already.
+  fn admit(&self, pool: &str) -> Option<InFlight> {
+    let slot = match &self.in_flight_limiter {
+      Some(limiter) => match limiter.try_acquire() {
+        Some(slot) => Some(slot),
+        None => {
+          warn!(
+            "Rejected request of route '{}' of pool '{}', because its max_in_flight requests are in flight",
+            self.name, pool
+          );
+          self.reject(pool, "max_in_flight");
+          return None;
+        }
+      },
+      None => None,
+    };
+    Some(InFlight::new(pool, &self.name, slot))
+  }
+
+  /// Counts the rejection of a request of this route for `reason`.
+  fn reject(&self, pool: &str, reason: &str) {
+    METRICS.increment(
+      "arlb_route_rejected_requests_total",
+      &[("pool", pool), ("route", &self.name), ("reason", reason)],
+    );
+  }
+
+  fn rejection_response(&self) -> Response<Body> {
+    let mut response = service_unavailable();
+    if let Some(retry_after) = self.retry_after {
+      response.headers_mut().insert(RETRY_AFTER, retry_after.as_secs().into());
+    }
+    response
+  }
+}
+
+/// A request of a [`PriorityRoute`], which is counted as in flight until it is
+/// dropped.
+struct InFlight {
+  pool: String,
+  route: String,
+  _slot: Option<Slot>,
+}
+
+impl InFlight {
+  fn new(pool: &str, route: &str, slot: Option<Slot>) -> InFlight {
+    METRICS.add_gauge("arlb_route_in_flight_requests", &[("pool", pool), ("route", route)], 1);
+    InFlight {
+      pool: pool.to_string(),
+      route: route.to_string(),
+      _slot: slot,
+    }
+  }
+}
+
+impl Drop for InFlight {
+  fn drop(&mut self) {
+    METRICS.add_gauge(
+      "arlb_route_in_flight_requests",
+      &[("pool", &self.pool), ("route", &self.route)],
+      -1,
+    );
+  }
+}
+
 #[derive(Debug)]
 pub struct BackendPool {
+  /// Identifies this pool in metrics, defaults to the matcher expression.
+  pub name: String,
   pub matcher: BackendPoolMatcher,
+  /// Of multiple matching pools the one with the highest precedence wins,
+  /// before the specificity of their matchers is compared.
+  pub precedence: i32,
   pub addresses: Vec<(String, ArcSwap<Healthiness>)>,
   pub health_config: HealthConfig,
   pub strategy: Arc<Box<dyn LoadBalancingStrategy>>,
   pub chain: MiddlewareChain,
   pub client: Client<StrategyNotifyHttpConnector, Body>,
   pub schemes: HashSet<Scheme>,
+  pub outlier_detector: Option<OutlierDetector>,
+  pub connections: Arc<ActiveConnections>,
+  /// The connections established ahead of requests, see
+  /// [`min_idle_connections`](BackendPoolBuilder::min_idle_connections).
+  pub warm_connections: Option<Arc<WarmConnections>>,
+  /// The local address connections to backend servers originate from.
+  pub source_address: Option<IpAddr>,
+  /// The `SO_MARK` of connections to backend servers.
+  pub socket_mark: Option<u32>,
+  /// The DSCP of connections to backend servers.
+  pub dscp: Option<u8>,
+  /// Whether connections to backend servers use TCP Fast Open.
+  pub tcp_fast_open: bool,
+  /// The SOCKS5 proxy, through which connections to backend servers are
+  /// established.
+  pub upstream_proxy: Option<UpstreamProxy>,
+  /// Checks the addresses, which host names of backend servers resolve to.
+  pub address_filter: Arc<AddressFilter>,
+  /// Sent instead of forwarding requests to a backend server.
+  pub respond: Option<StaticResponse>,
+  /// Sent if no backend server is available, instead of `502 Bad Gateway`.
+  pub unavailable: Option<StaticResponse>,
+  /// The timeouts of requests, which do not match a [`PriorityRoute`] with
+  /// timeouts of its own.
+  pub timeouts: Timeouts,
+  /// How long requests wait for a backend server to become available, if
+  /// none is. Without it they are answered right away.
+  pub backend_wait_timeout: Option<Duration>,
+  /// Whether all requests are answered with `503 Service Unavailable`
+  /// according to the configuration, see [`in_maintenance`](Self::in_maintenance).
+  pub maintenance: bool,
+  pub retry: Option<RetryConfig>,
+  pub concurrency_limiter: Option<ConcurrencyLimiter>,
+  pub canary: Option<CanaryConfig>,
+  pub mirror: Option<MirrorConfig>,
+  /// Limits the concurrent requests of each backend server separately.
+  pub backend_concurrency_limiters: HashMap<String, ConcurrencyLimiter>,
+  /// Backend servers, which only receive requests if no other backend server
+  /// of the pool is available. They are part of `addresses` as well.
+  pub backup_addresses: HashSet<String>,
+  /// Backend servers, which were registered through the admin API instead of
+  /// the configuration file. They are part of `addresses` as well.
+  pub dynamic_addresses: HashSet<String>,
+  /// When the dynamic backend servers were registered by address. They receive
+  /// a growing share of the requests during the `slow_start`.
+  pub registered_at: HashMap<String, Instant>,
+  pub slow_start: Duration,
+  /// How long the connections of removed dynamic backend servers are given to
+  /// close, instead of the `drain_timeout`.
+  pub grace_period: Duration,
+  /// The tags of the backend servers by address, see [`TagRoute`].
+  pub tags: HashMap<String, HashMap<String, String>>,
+  /// The first matching route restricts the backend servers of a request.
+  pub tag_routes: Vec<TagRoute>,
+  /// The first matching route sets the priority of a request.
+  pub priority_routes: Vec<PriorityRoute>,
+  /// Drain backend servers at scheduled times, like for planned maintenance.
+  pub maintenance_windows: Vec<MaintenanceWindow>,
+  /// The name of the tenant, whose quotas apply to the requests of this pool.
+  pub tenant: Option<String>,
+  /// The zone of the load balancer. Backend servers with this `zone` tag are
+  /// preferred over the others, as long as one of them is available.
+  pub local_zone: Option<String>,
+  /// Backend servers, which sent this many bytes per second during the last
+  /// window, receive no new requests until their throughput drops.
+  pub max_backend_bytes_per_sec: Option<u64>,
+  /// Whether requests are currently sent to the backup servers.
+  using_backups: AtomicBool,
+  /// The `maintenance`, unless it was toggled through the admin API.
+  in_maintenance: AtomicBool,
+  /// The addresses of backend servers, which were removed from the
+  /// configuration and no longer receive new requests.
+  draining: Mutex<HashSet<String>>,
 }
 
 impl BackendPool {
   fn supports(&self, scheme: &Scheme) -> bool {
     self.schemes.contains(scheme)
   }
+
+  /// Whether all new requests are answered with `503 Service Unavailable`.
+  pub fn in_maintenance(&self) -> bool {
+    self.in_maintenance.load(Ordering::Relaxed)
+  }
+
+  /// Toggles the maintenance mode, which takes effect for new requests.
+  pub fn set_maintenance(&self, enabled: bool) {
+    self.in_maintenance.store(enabled, Ordering::Relaxed);
+  }
+
+  /// The first [`PriorityRoute`] matching `request`.
+  fn priority_route(&self, request: &Request<Body>) -> Option<&PriorityRoute> {
+    self.priority_routes.iter().find(|it| it.matcher.matches(request))
+  }
+
+  /// The timeouts of `request`, which are those of the pool, unless the first
+  /// [`PriorityRoute`] matching it overrides them.
+  fn timeouts(&self, request: &Request<Body>) -> Timeouts {
+    match self.priority_route(request) {
+      Some(route) => route.timeouts.or(&self.timeouts),
+      None => self.timeouts,
+    }
+  }
+
+  /// The priority of `request` for the slots of the concurrency limits.
+  /// Requests without a route have a low priority.
+  fn priority(&self, request: &Request<Body>) -> Priority {
+    self.priority_route(request).map(|it| it.priority).unwrap_or_default()
+  }
+
+  /// The response sent by the load balancer itself, if requests of this pool
+  /// are not forwarded to any backend server.
+  fn static_response(&self) -> Option<StaticResponse> {
+    if self.in_maintenance() {
+      Some(StaticResponse::maintenance())
+    } else {
+      self.respond.clone()
+    }
+  }
+
+  /// The addresses of the backend servers, which can receive a request. These
+  /// are the healthy (or if there are none the slow) backend servers, which are
+  /// not ejected. Backup servers are only used if none of the other backend
+  /// servers are available.
+  fn working_addresses(&self) -> Vec<&str> {
+    let primaries = self.available_addresses(false);
+    let backups = if primaries.iter().all(|address| self.is_ejected(address)) {
+      self.available_addresses(true)
+    } else {
+      Vec::new()
+    };
+    let use_backups = backups.iter().any(|address| !self.is_ejected(address));
+    self.set_using_backups(use_backups);
+    let mut working_addresses = if use_backups { backups } else { primaries };
+    // ignore ejected addresses, unless all addresses are ejected
+    if working_addresses.iter().any(|address| !self.is_ejected(address)) {
+      working_addresses.retain(|address| !self.is_ejected(address));
+    }
+    self.retain_slow_starting(&mut working_addresses);
+    working_addresses
+  }
+
+  /// Leaves out each backend server in its slow start with a probability,
+  /// which decreases from 1 to 0 during the `slow_start`, unless that would
+  /// leave out all `addresses`.
+  fn retain_slow_starting(&self, addresses: &mut Vec<&str>) {
+    let share = |address: &str| {
+      let registered_at = self.registered_at.get(address)?;
+      let share = registered_at.elapsed().as_secs_f64() / self.slow_start.as_secs_f64();
+      Some(share).filter(|it| *it < 1.0)
+    };
+    if self.slow_start == Duration::from_secs(0) || addresses.iter().all(|it| share(it).is_none()) {
+      return;
+    }
+    let mut rng = thread_rng();
+    let retained = addresses
+      .iter()
+      .copied()
+      .filter(|address| match share(address) {
+        Some(share) => rng.gen_bool(share),
+        None => true,
+      })
+      .collect::<Vec<_>>();
+    if !retained.is_empty() {
+      *addresses = retained;
+    }
+  }
+
+  /// The [`working_addresses`](BackendPool::working_addresses), which have the
+  /// tags of the first [`TagRoute`] matching `request`. Of those the ones in
+  /// the `local_zone` are used, unless none of them is available. Backend
+  /// servers at their bandwidth budget are left out.
+  fn routed_addresses(&self, request: &Request<Body>) -> Vec<&str> {
+    let mut working_addresses = self.working_addresses();
+    if let Some(route) = self.tag_routes.iter().find(|it| it.matcher.matches(request)) {
+      working_addresses.retain(|address| route.selects(self.tags.get(*address)));
+    }
+    if let Some(local_zone) = &self.local_zone {
+      let in_local_zone = |address: &&str| self.tags.get(*address).and_then(|it| it.get("zone")) == Some(local_zone);
+      if working_addresses.iter().any(in_local_zone) {
+        working_addresses.retain(in_local_zone);
+      } else if !working_addresses.is_empty() {
+        METRICS.increment("arlb_pool_zone_failovers_total", &[("pool", &self.name)]);
+      }
+    }
+    if self.max_backend_bytes_per_sec.is_some() && !working_addresses.is_empty() {
+      working_addresses.retain(|address| !BACKEND_BANDWIDTH.exceeds(address, self.max_backend_bytes_per_sec));
+      if working_addresses.is_empty() {
+        METRICS.increment("arlb_pool_bandwidth_sheds_total", &[("pool", &self.name)]);
+      }
+    }
+    working_addresses
+  }
+
+  /// The healthy (or if there are none the slow) backup or non-backup servers,
+  /// which are not draining.
+  fn available_addresses(&self, backup: bool) -> Vec<&str> {
+    let candidates = self
+      .addresses
+      .iter()
+      .filter(|(address, _)| self.backup_addresses.contains(address) == backup)
+      .collect::<Vec<_>>();
+    let mut available = candidates
+      .iter()
+      .filter(|(_, healthiness)| healthiness.load().as_ref() == &Healthiness::Healthy)
+      .map(|(address, _)| address.as_str())
+      .collect::<Vec<_>>();
+    if available.is_empty() {
+      // replace healthy addresses with slow addresses
+      available = candidates
+        .iter()
+        .filter(|(_, healthiness)| matches!(healthiness.load().as_ref(), Healthiness::Slow(_)))
+        .map(|(address, _)| address.as_str())
+        .collect::<Vec<_>>();
+    }
+    // never select backend servers, which were removed from the configuration or are drained
+    available.retain(|address| !self.is_draining(address) && backend_drains::mode(&self.name, address).is_none());
+    available
+  }
+
+  /// The backend servers, which are soft drained, so they only receive
+  /// requests of clients pinned to them, unless they are unresponsive.
+  fn soft_draining_addresses(&self) -> Vec<&str> {
+    self
+      .addresses
+      .iter()
+      .filter(|(_, healthiness)| !matches!(healthiness.load().as_ref(), Healthiness::Unresponsive(_)))
+      .map(|(address, _)| address.as_str())
+      .filter(|address| {
+        !self.is_draining(address) && backend_drains::mode(&self.name, address) == Some(DrainMode::Soft)
+      })
+      .collect()
+  }
+
+  fn set_using_backups(&self, using_backups: bool) {
+    if self.using_backups.swap(using_backups, Ordering::Relaxed) == using_backups {
+      return;
+    }
+    if using_backups {
+      warn!(
+        "No backend server of pool '{}' is available, using its backup servers",
+        self.name
+      );
+      METRICS.add_gauge("arlb_pool_using_backups", &[("pool", &self.name)], 1);
+    } else {
+      info!(
+        "Backend servers of pool '{}' are available again, no longer using its backup servers",
+        self.name
+      );
+      METRICS.add_gauge("arlb_pool_using_backups", &[("pool", &self.name)], -1);
+    }
+  }
+
+  fn is_draining(&self, address: &str) -> bool {
+    self.draining.lock().unwrap().contains(address)
+  }
+
+  fn is_ejected(&self, address: &str) -> bool {
+    match &self.outlier_detector {
+      Some(outlier_detector) => outlier_detector.is_ejected(address),
+      None => false,
+    }
+  }
+
+  /// Whether connections to the backend server at `address` are kept warm:
+  /// it has to be healthy and neither ejected nor draining.
+  pub fn accepts_warm_connections(&self, address: &str) -> bool {
+    let healthy = self
+      .addresses
+      .iter()
+      .any(|(it, healthiness)| it == address && healthiness.load().as_ref() == &Healthiness::Healthy);
+    healthy
+      && !self.is_ejected(address)
+      && !self.is_draining(address)
+      && backend_drains::mode(&self.name, address).is_none()
+  }
+}
+
+impl Drop for BackendPool {
+  fn drop(&mut self) {
+    // The gauge of a pool is shared with the pool replacing it on reload
+    if *self.using_backups.get_mut() {
+      METRICS.add_gauge("arlb_pool_using_backups", &[("pool", &self.name)], -1);
+    }
+  }
 }
 
 impl PartialEq for BackendPool {
@@ -174,7 +1573,9 @@ impl PartialEq for BackendPool {
 }
 
 pub struct BackendPoolBuilder {
+  name: String,
   matcher: BackendPoolMatcher,
+  precedence: i32,
   addresses: Vec<(String, ArcSwap<Healthiness>)>,
   health_config: HealthConfig,
   strategy: Box<dyn LoadBalancingStrategy>,
@@ -182,6 +1583,41 @@ pub struct BackendPoolBuilder {
   schemes: HashSet<Scheme>,
   pool_idle_timeout: Option<Duration>,
   pool_max_idle_per_host: Option<usize>,
+  min_idle_connections: Option<usize>,
+  outlier_detection: Option<OutlierDetectionConfig>,
+  source_address: Option<IpAddr>,
+  socket_mark: Option<u32>,
+  dscp: Option<u8>,
+  tcp_fast_open: bool,
+  upstream_proxy: Option<UpstreamProxy>,
+  address_policy: AddressPolicy,
+  tcp_keepalive: Option<TcpKeepalive>,
+  happy_eyeballs_delay: Option<Option<Duration>>,
+  preferred_ip_family: Option<IpFamily>,
+  respond: Option<StaticResponse>,
+  unavailable: Option<StaticResponse>,
+  timeouts: Timeouts,
+  backend_wait_timeout: Option<Duration>,
+  maintenance: bool,
+  retry: Option<RetryConfig>,
+  concurrency_limit: Option<ConcurrencyLimitConfig>,
+  backend_concurrency_limit: Option<ConcurrencyLimitConfig>,
+  canary: Option<CanaryConfig>,
+  mirror: Option<MirrorConfig>,
+  backup_addresses: HashSet<String>,
+  dynamic_addresses: HashSet<String>,
+  registered_at: HashMap<String, Instant>,
+  slow_start: Duration,
+  grace_period: Duration,
+  tls_backends: HashMap<String, BackendTls>,
+  tags: HashMap<String, HashMap<String, String>>,
+  tag_routes: Vec<TagRoute>,
+  priority_routes: Vec<PriorityRoute>,
+  maintenance_windows: Vec<MaintenanceWindow>,
+  tenant: Option<String>,
+  local_zone: Option<String>,
+  max_backend_bytes_per_sec: Option<u64>,
+  http2_only: bool,
 }
 
 impl BackendPoolBuilder {
@@ -194,7 +1630,9 @@ impl BackendPoolBuilder {
     schemes: HashSet<Scheme>,
   ) -> BackendPoolBuilder {
     BackendPoolBuilder {
+      name: String::new(),
       matcher,
+      precedence: 0,
       addresses,
       health_config,
       strategy,
@@ -202,9 +1640,49 @@ impl BackendPoolBuilder {
       schemes,
       pool_idle_timeout: None,
       pool_max_idle_per_host: None,
+      min_idle_connections: None,
+      outlier_detection: None,
+      source_address: None,
+      socket_mark: None,
+      dscp: None,
+      tcp_fast_open: false,
+      upstream_proxy: None,
+      address_policy: AddressPolicy::default(),
+      tcp_keepalive: None,
+      happy_eyeballs_delay: None,
+      preferred_ip_family: None,
+      respond: None,
+      unavailable: None,
+      timeouts: Timeouts::default(),
+      backend_wait_timeout: None,
+      maintenance: false,
+      retry: None,
+      concurrency_limit: None,
+      backend_concurrency_limit: None,
+      canary: None,
+      mirror: None,
+      backup_addresses: HashSet::new(),
+      dynamic_addresses: HashSet::new(),
+      registered_at: HashMap::new(),
+      slow_start: Duration::from_secs(0),
+      grace_period: Duration::from_secs(30),
+      tls_backends: HashMap::new(),
+      tags: HashMap::new(),
+      tag_routes: Vec::new(),
+      priority_routes: Vec::new(),
+      maintenance_windows: Vec::new(),
+      tenant: None,
+      local_zone: None,
+      max_backend_bytes_per_sec: None,
+      http2_only: false,
     }
   }
 
+  pub fn name(&mut self, name: String) -> &BackendPoolBuilder {
+    self.name = name;
+    self
+  }
+
   pub fn pool_idle_timeout(&mut self, duration: Duration) -> &BackendPoolBuilder {
     self.pool_idle_timeout = Some(duration);
     self
@@ -215,31 +1693,393 @@ impl BackendPoolBuilder {
     self
   }
 
-  pub fn build(self) -> BackendPool {
-    let mut client_builder = Client::builder();
-    if let Some(pool_idle_timeout) = self.pool_idle_timeout {
-      client_builder.pool_idle_timeout(pool_idle_timeout);
-    }
-    if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
-      client_builder.pool_max_idle_per_host(pool_max_idle_per_host);
-    }
+  /// Keeps `min_idle` connections to each healthy backend server established
+  /// ahead of requests.
+  pub fn min_idle_connections(&mut self, min_idle: usize) -> &BackendPoolBuilder {
+    self.min_idle_connections = Some(min_idle);
+    self
+  }
 
-    let strategy = Arc::new(self.strategy);
-    let client: Client<_, Body> = client_builder.build(StrategyNotifyHttpConnector::new(strategy.clone()));
+  pub fn outlier_detection(&mut self, config: OutlierDetectionConfig) -> &BackendPoolBuilder {
+    self.outlier_detection = Some(config);
+    self
+  }
 
-    BackendPool {
-      matcher: self.matcher,
-      addresses: self.addresses,
-      health_config: self.health_config,
-      strategy,
-      chain: self.chain,
-      client,
-      schemes: self.schemes,
-    }
+  pub fn source_address(&mut self, address: IpAddr) -> &BackendPoolBuilder {
+    self.source_address = Some(address);
+    self
   }
-}
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Hash)]
+  pub fn socket_mark(&mut self, mark: u32) -> &BackendPoolBuilder {
+    self.socket_mark = Some(mark);
+    self
+  }
+
+  pub fn dscp(&mut self, dscp: u8) -> &BackendPoolBuilder {
+    self.dscp = Some(dscp);
+    self
+  }
+
+  pub fn tcp_fast_open(&mut self, tcp_fast_open: bool) -> &BackendPoolBuilder {
+    self.tcp_fast_open = tcp_fast_open;
+    self
+  }
+
+  pub fn upstream_proxy(&mut self, proxy: UpstreamProxy) -> &BackendPoolBuilder {
+    self.upstream_proxy = Some(proxy);
+    self
+  }
+
+  /// Which addresses host names of backend servers may resolve to. By default
+  /// loopback, link-local and cloud metadata addresses are skipped.
+  pub fn address_policy(&mut self, policy: AddressPolicy) -> &BackendPoolBuilder {
+    self.address_policy = policy;
+    self
+  }
+
+  pub fn tcp_keepalive(&mut self, keepalive: TcpKeepalive) -> &BackendPoolBuilder {
+    self.tcp_keepalive = Some(keepalive);
+    self
+  }
+
+  /// `None` disables racing connection attempts, see
+  /// [`BackendConnector::set_happy_eyeballs_delay`].
+  pub fn happy_eyeballs_delay(&mut self, delay: Option<Duration>) -> &BackendPoolBuilder {
+    self.happy_eyeballs_delay = Some(delay);
+    self
+  }
+
+  pub fn preferred_ip_family(&mut self, family: IpFamily) -> &BackendPoolBuilder {
+    self.preferred_ip_family = Some(family);
+    self
+  }
+
+  pub fn respond(&mut self, response: StaticResponse) -> &BackendPoolBuilder {
+    self.respond = Some(response);
+    self
+  }
+
+  pub fn unavailable(&mut self, response: StaticResponse) -> &BackendPoolBuilder {
+    self.unavailable = Some(response);
+    self
+  }
+
+  /// Bounds establishing a new connection to a backend server, which fails
+  /// the request with `502 Bad Gateway`.
+  pub fn connect_timeout(&mut self, timeout: Duration) -> &BackendPoolBuilder {
+    self.timeouts.connect = Some(timeout);
+    self
+  }
+
+  pub fn response_timeout(&mut self, timeout: Duration) -> &BackendPoolBuilder {
+    self.timeouts.response = Some(timeout);
+    self
+  }
+
+  /// Bounds the time from receiving the head of a request until the body of
+  /// the response was sent.
+  pub fn total_timeout(&mut self, timeout: Duration) -> &BackendPoolBuilder {
+    self.timeouts.total = Some(timeout);
+    self
+  }
+
+  /// Aborts the body of a response, once the backend server sent nothing for
+  /// this long.
+  pub fn idle_timeout(&mut self, timeout: Duration) -> &BackendPoolBuilder {
+    self.timeouts.idle = Some(timeout);
+    self
+  }
+
+  pub fn backend_wait_timeout(&mut self, timeout: Duration) -> &BackendPoolBuilder {
+    self.backend_wait_timeout = Some(timeout);
+    self
+  }
+
+  pub fn precedence(&mut self, precedence: i32) -> &BackendPoolBuilder {
+    self.precedence = precedence;
+    self
+  }
+
+  pub fn maintenance(&mut self, maintenance: bool) -> &BackendPoolBuilder {
+    self.maintenance = maintenance;
+    self
+  }
+
+  pub fn retry(&mut self, config: RetryConfig) -> &BackendPoolBuilder {
+    self.retry = Some(config);
+    self
+  }
+
+  pub fn concurrency_limit(&mut self, config: ConcurrencyLimitConfig) -> &BackendPoolBuilder {
+    self.concurrency_limit = Some(config);
+    self
+  }
+
+  pub fn backend_concurrency_limit(&mut self, config: ConcurrencyLimitConfig) -> &BackendPoolBuilder {
+    self.backend_concurrency_limit = Some(config);
+    self
+  }
+
+  pub fn canary(&mut self, config: CanaryConfig) -> &BackendPoolBuilder {
+    self.canary = Some(config);
+    self
+  }
+
+  pub fn mirror(&mut self, config: MirrorConfig) -> &BackendPoolBuilder {
+    self.mirror = Some(config);
+    self
+  }
+
+  /// Marks backend servers (which must be part of the `addresses` as well) as
+  /// backup servers.
+  pub fn backup_addresses(&mut self, addresses: HashSet<String>) -> &BackendPoolBuilder {
+    self.backup_addresses = addresses;
+    self
+  }
+
+  /// Marks backend servers (which must be part of the `addresses` as well) as
+  /// registered through the admin API.
+  pub fn dynamic_addresses(&mut self, addresses: HashSet<String>) -> &BackendPoolBuilder {
+    self.dynamic_addresses = addresses;
+    self
+  }
+
+  /// Backend servers (by address), which were `registered_at` the given
+  /// instants, receive a share of the requests, which grows from none to their
+  /// full share during the `slow_start`.
+  pub fn slow_start(&mut self, slow_start: Duration, registered_at: HashMap<String, Instant>) -> &BackendPoolBuilder {
+    self.slow_start = slow_start;
+    self.registered_at = registered_at;
+    self
+  }
+
+  /// How long the connections of removed dynamic backend servers are given to
+  /// close.
+  pub fn grace_period(&mut self, grace_period: Duration) -> &BackendPoolBuilder {
+    self.grace_period = grace_period;
+    self
+  }
+
+  /// Connects to the backend servers with these addresses via TLS.
+  pub fn tls_backends(&mut self, backends: HashMap<String, BackendTls>) -> &BackendPoolBuilder {
+    self.tls_backends = backends;
+    self
+  }
+
+  /// Tags backend servers (by address) for the `tag_routes`.
+  pub fn tags(&mut self, tags: HashMap<String, HashMap<String, String>>) -> &BackendPoolBuilder {
+    self.tags = tags;
+    self
+  }
+
+  pub fn tag_routes(&mut self, routes: Vec<TagRoute>) -> &BackendPoolBuilder {
+    self.tag_routes = routes;
+    self
+  }
+
+  pub fn priority_routes(&mut self, routes: Vec<PriorityRoute>) -> &BackendPoolBuilder {
+    self.priority_routes = routes;
+    self
+  }
+
+  pub fn maintenance_windows(&mut self, windows: Vec<MaintenanceWindow>) -> &BackendPoolBuilder {
+    self.maintenance_windows = windows;
+    self
+  }
+
+  pub fn tenant(&mut self, tenant: String) -> &BackendPoolBuilder {
+    self.tenant = Some(tenant);
+    self
+  }
+
+  /// Prefers the backend servers tagged with the `zone` of the load balancer.
+  pub fn local_zone(&mut self, local_zone: String) -> &BackendPoolBuilder {
+    self.local_zone = Some(local_zone);
+    self
+  }
+
+  pub fn max_backend_bytes_per_sec(&mut self, max_backend_bytes_per_sec: u64) -> &BackendPoolBuilder {
+    self.max_backend_bytes_per_sec = Some(max_backend_bytes_per_sec);
+    self
+  }
+
+  /// Speak HTTP/2 to the backend servers without negotiating it first, so the
+  /// requests of one client connection are spread across the backend servers
+  /// and backend connections are shared by many requests at the same time.
+  pub fn http2_only(&mut self, http2_only: bool) -> &BackendPoolBuilder {
+    self.http2_only = http2_only;
+    self
+  }
+
+  pub fn build(self) -> BackendPool {
+    let mut client_builder = Client::builder();
+    client_builder.http2_only(self.http2_only);
+    if let Some(pool_idle_timeout) = self.pool_idle_timeout {
+      client_builder.pool_idle_timeout(pool_idle_timeout);
+    }
+    if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
+      client_builder.pool_max_idle_per_host(pool_max_idle_per_host);
+    }
+
+    let strategy = Arc::new(self.strategy);
+    let connections = Arc::new(ActiveConnections::new(self.name.clone()));
+    let mut backend_connector = BackendConnector::with_preferred_family(self.preferred_ip_family);
+    backend_connector.set_local_address(self.source_address);
+    backend_connector.set_socket_mark(self.socket_mark);
+    backend_connector.set_dscp(self.dscp);
+    backend_connector.set_tcp_fast_open(self.tcp_fast_open);
+    backend_connector.set_tcp_keepalive(self.tcp_keepalive);
+    backend_connector.set_upstream_proxy(self.upstream_proxy.clone());
+    let address_filter = Arc::new(AddressFilter::new(self.name.clone(), self.address_policy));
+    backend_connector.set_address_filter(Some(address_filter.clone()));
+    if let Some(delay) = self.happy_eyeballs_delay {
+      backend_connector.set_happy_eyeballs_delay(delay);
+    }
+    let name = self.name.clone();
+    let warm_connections = self
+      .min_idle_connections
+      .map(|it| Arc::new(WarmConnections::new(name.clone(), it, backend_connector.clone())));
+    backend_connector.set_warm_connections(warm_connections.clone());
+    // Warm connections are plain TCP connections, the TLS handshake happens once one is used
+    backend_connector.set_tls_backends(self.tls_backends);
+    let connector = StrategyNotifyHttpConnector::new(backend_connector, strategy.clone(), connections.clone());
+    let client: Client<_, Body> = client_builder.build(connector);
+    let backend_concurrency_limiters = match &self.backend_concurrency_limit {
+      Some(config) => self
+        .addresses
+        .iter()
+        .map(|(address, _)| (address.clone(), ConcurrencyLimiter::new(config.clone())))
+        .collect(),
+      None => HashMap::new(),
+    };
+    let name = &self.name;
+    let outlier_detector = self.outlier_detection.map(|it| OutlierDetector::new(name.clone(), it));
+
+    BackendPool {
+      name: self.name,
+      matcher: self.matcher,
+      precedence: self.precedence,
+      addresses: self.addresses,
+      health_config: self.health_config,
+      strategy,
+      chain: self.chain,
+      client,
+      schemes: self.schemes,
+      outlier_detector,
+      connections,
+      warm_connections,
+      source_address: self.source_address,
+      socket_mark: self.socket_mark,
+      dscp: self.dscp,
+      tcp_fast_open: self.tcp_fast_open,
+      upstream_proxy: self.upstream_proxy,
+      address_filter,
+      respond: self.respond,
+      unavailable: self.unavailable,
+      timeouts: self.timeouts,
+      backend_wait_timeout: self.backend_wait_timeout,
+      maintenance: self.maintenance,
+      in_maintenance: AtomicBool::new(self.maintenance),
+      retry: self.retry,
+      concurrency_limiter: self.concurrency_limit.map(ConcurrencyLimiter::new),
+      backend_concurrency_limiters,
+      canary: self.canary,
+      mirror: self.mirror,
+      backup_addresses: self.backup_addresses,
+      dynamic_addresses: self.dynamic_addresses,
+      registered_at: self.registered_at,
+      slow_start: self.slow_start,
+      grace_period: self.grace_period,
+      tags: self.tags,
+      tag_routes: self.tag_routes,
+      priority_routes: self.priority_routes,
+      maintenance_windows: self.maintenance_windows,
+      tenant: self.tenant,
+      local_zone: self.local_zone,
+      max_backend_bytes_per_sec: self.max_backend_bytes_per_sec,
+      using_backups: AtomicBool::new(false),
+      draining: Mutex::new(HashSet::new()),
+    }
+  }
+}
+
+/// Keeps the maintenance mode, which was toggled through the admin API, for
+/// the pools in `new_pools`, unless the configuration changed the
+/// `maintenance` of the pool.
+pub fn carry_over_maintenance(old_pools: &[Arc<BackendPool>], new_pools: &[Arc<BackendPool>]) {
+  for new_pool in new_pools {
+    let old_pool = old_pools.iter().find(|old_pool| old_pool.name == new_pool.name);
+    if let Some(old_pool) = old_pool.filter(|it| it.maintenance == new_pool.maintenance) {
+      new_pool.set_maintenance(old_pool.in_maintenance());
+    }
+  }
+}
+
+/// The pools of previous configurations, which connections accepted before a
+/// reload may still serve requests with.
+static SUPERSEDED_POOLS: Lazy<Mutex<Vec<Weak<BackendPool>>>> = Lazy::new(Default::default);
+
+/// Remembers the `old_pools` replaced by a reload, so the health of the
+/// backend servers and the maintenance mode toggled via the admin API keep
+/// applying to them, as long as connections use them.
+pub fn supersede_pools(old_pools: &[Arc<BackendPool>]) {
+  let mut superseded = SUPERSEDED_POOLS.lock().unwrap();
+  superseded.retain(|it| it.strong_count() > 0);
+  superseded.extend(old_pools.iter().map(Arc::downgrade));
+}
+
+/// The pools of previous configurations named `name`, which are still in use.
+pub fn superseded_pools(name: &str) -> Vec<Arc<BackendPool>> {
+  SUPERSEDED_POOLS
+    .lock()
+    .unwrap()
+    .iter()
+    .filter_map(Weak::upgrade)
+    .filter(|it| it.name == name)
+    .collect()
+}
+
+/// Drains the backend servers of `old_pools`, which are no longer part of the
+/// corresponding pool in `new_pools`. They are not selected for new requests
+/// anymore and their connections are given up to `drain_timeout` (or the
+/// `grace_period` of dynamic backend servers) to close.
+pub fn drain_removed_backends(old_pools: &[Arc<BackendPool>], new_pools: &[Arc<BackendPool>], drain_timeout: Duration) {
+  for old_pool in old_pools {
+    let new_pool = new_pools.iter().find(|new_pool| *new_pool == old_pool);
+    for (address, _) in &old_pool.addresses {
+      let removed = match new_pool {
+        Some(new_pool) => new_pool.addresses.iter().all(|(it, _)| it != address),
+        None => true,
+      };
+      if !removed {
+        continue;
+      }
+      old_pool.draining.lock().unwrap().insert(address.clone());
+      let connections = old_pool.connections.clone();
+      if connections.get(address) == 0 {
+        continue;
+      }
+      let address = address.clone();
+      let drain_timeout = if old_pool.dynamic_addresses.contains(&address) {
+        old_pool.grace_period
+      } else {
+        drain_timeout
+      };
+      tokio::spawn(async move {
+        info!("Draining connections to removed backend server {}", address);
+        match tokio::time::timeout(drain_timeout, connections.closed(&address)).await {
+          Ok(()) => info!("Drained all connections to removed backend server {}", address),
+          Err(_) => warn!(
+            "Connections to removed backend server {} were not drained within {:?}",
+            address, drain_timeout
+          ),
+        }
+      });
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Hash)]
 pub enum Scheme {
   HTTP,
   HTTPS,
@@ -255,34 +2095,109 @@ impl Display for Scheme {
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
 
   use super::*;
-  use crate::load_balancing::random::Random;
-  use std::{collections::HashMap, iter::FromIterator};
+  use crate::{
+    builtin_backends::BuiltinBackend,
+    events::CloseReason,
+    load_balancing::{peak_ewma::PeakEwma, random::Random, round_robin::RoundRobin, sticky_cookie::StickyCookie},
+    testing::{exclusive, MockBackend, TestConnection, TestProxy},
+    tls::{Certificates, TlsConfig},
+    trusted_proxies::TrustedProxies,
+  };
+  use cookie::SameSite;
+  use hyper::{body::HttpBody, header::HeaderName};
+  use std::{convert::TryFrom, iter::FromIterator};
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+  };
 
-  fn generate_config(shared_data: SharedData) -> RuntimeConfig {
+  pub fn generate_config(shared_data: SharedData) -> RuntimeConfig {
     RuntimeConfig {
       shared_data,
       http_address: "0.0.0.0:80".parse().unwrap(),
       https_address: "0.0.0.0:443".parse().unwrap(),
-      certificates: HashMap::new(),
+      unix_socket: None,
+      listeners: Vec::new(),
+      udp_services: Vec::new(),
+      tls_passthrough_services: Vec::new(),
+      tcp_router_services: Vec::new(),
+      forward_proxy_services: Vec::new(),
+      tenants: HashMap::new(),
+      redirects: Vec::new(),
+      reuse_port: false,
+      dual_stack: None,
+      listen_backlog: 1024,
+      acceptors: 1,
+      tls_on_http_port: Default::default(),
+      http_port_guard: None,
+      allow_absolute_form: false,
+      trusted_proxies: TrustedProxies::default(),
+      header_limits: HeaderLimits::default(),
+      keep_alive: KeepAliveLimits::default(),
+      readiness: Default::default(),
+      maintenance: Default::default(),
+      maintenance_response: StaticResponse::maintenance(),
+      drain_timeout: std::time::Duration::from_secs(30),
+      logging: Default::default(),
+      tls_client_auth: None,
+      tls: TlsConfig::default(),
+      metrics_address: None,
+      histogram_buckets: crate::metrics::DEFAULT_BUCKETS.to_vec(),
+      backend_selection_sample_rate: 1,
+      geoip: None,
+      certificates: Certificates::default(),
+      acme_renewal_at: None,
       health_interval: std::time::Duration::from_secs(60),
+      health_jitter: 0.0,
+      status_token: None,
+      config_hash: String::new(),
+      source: serde_json::Value::Null,
+      config_path: Default::default(),
+      embedded_config: None,
+      state_file: None,
+      event_webhook: None,
+      bans: None,
+      top_talkers: None,
+      dns: Default::default(),
+      opentelemetry: None,
+      tcp_keepalive: None,
+      dscp: None,
+      tcp_fast_open: false,
     }
   }
   fn generate_test_service(host: String, scheme: Scheme) -> MainService {
+    generate_test_service_with_backend(host, scheme, "127.0.0.1:8084".into())
+  }
+  fn generate_test_service_with_backend(host: String, scheme: Scheme, backend_address: String) -> MainService {
     MainService {
       scheme,
       client_address: "127.0.0.1:3000".parse().unwrap(),
-      config: Arc::new(ArcSwap::from_pointee(generate_config(SharedData {
+      tls_info: None,
+      peer_credentials: None,
+      geo_info: None,
+      listener: None,
+      span: None,
+      config: Arc::new(generate_config(SharedData {
         backend_pools: vec![Arc::new(
           BackendPoolBuilder::new(
             BackendPoolMatcher::Host(host),
-            vec![("127.0.0.1:8084".into(), ArcSwap::from_pointee(Healthiness::Healthy))],
+            vec![(backend_address, ArcSwap::from_pointee(Healthiness::Healthy))],
             HealthConfig {
               slow_threshold: 200,
               timeout: 500,
               path: String::from("/"),
+              expected_statuses: Vec::new(),
+              expected_body: None,
+              host: None,
+              tls: false,
+              rise: 1,
+              fall: 1,
+              backoff_multiplier: 1,
+              max_backoff_interval: Duration::from_secs(300),
+              backends: HashMap::new(),
             },
             Box::new(Random::new()),
             MiddlewareChain::Empty,
@@ -291,21 +2206,21 @@ mod tests {
           .build(),
         )],
         acme_handler: Arc::new(AcmeHandler::new()),
-      }))),
+      })),
     }
   }
 
   #[test]
   fn pool_by_req_no_matching_pool() {
     let service = generate_test_service("whoami.localhost".into(), Scheme::HTTP);
-    let config = service.config.load();
+    let config = service.config;
     let shared_data = &config.shared_data;
     let request = Request::builder()
       .header("host", "whoami.de")
       .body(Body::empty())
       .unwrap();
 
-    let pool = pool_by_req(shared_data, &request, &service.scheme);
+    let pool = pool_by_req(shared_data, &request, &service.scheme, None);
 
     assert_eq!(pool, None);
   }
@@ -313,15 +2228,2628 @@ mod tests {
   #[test]
   fn pool_by_req_matching_pool() {
     let service = generate_test_service("whoami.localhost".into(), Scheme::HTTP);
-    let config = service.config.load();
+    let config = service.config;
     let shared_data = &config.shared_data;
     let request = Request::builder()
       .header("host", "whoami.localhost")
       .body(Body::empty())
       .unwrap();
 
-    let pool = pool_by_req(shared_data, &request, &service.scheme);
+    let pool = pool_by_req(shared_data, &request, &service.scheme, None);
 
     assert_eq!(pool, Some(shared_data.backend_pools[0].clone()));
   }
+
+  #[test]
+  fn pool_by_req_prefers_most_specific_pool() {
+    // given: overlapping pools, the most specific ones listed last
+    let pool = |name: &str, matcher: &str| {
+      let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+      builder.matcher = BackendPoolMatcher::from(matcher.to_string());
+      builder.name(name.into());
+      Arc::new(builder.build())
+    };
+    let shared_data = SharedData {
+      backend_pools: vec![
+        pool("default", "Host('whoami.localhost')"),
+        pool(
+          "bots",
+          "Host('whoami.localhost') && HeaderRegexp('User-Agent', '(?i)bot')",
+        ),
+        pool("beta", "Host('whoami.localhost') && Header('X-Beta-User', 'true')"),
+        pool("beta-cookie", "Host('whoami.localhost') && Cookie('beta')"),
+      ],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let pool_name = |headers: &[(&str, &str)]| {
+      let mut request = whoami_request();
+      for (name, value) in headers {
+        request
+          .headers_mut()
+          .insert(HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+      }
+      pool_by_req(&shared_data, &request, &Scheme::HTTP, None)
+        .unwrap()
+        .name
+        .clone()
+    };
+
+    // when:
+    let plain = pool_name(&[]);
+    let bot = pool_name(&[("user-agent", "Googlebot/2.1")]);
+    let beta_with_cookie = pool_name(&[("x-beta-user", "true"), ("cookie", "beta=1")]);
+    let cookie = pool_name(&[("cookie", "session=1; beta=1")]);
+
+    // then: equally specific pools are tried in the order of the configuration
+    assert_eq!(plain, "default");
+    assert_eq!(bot, "bots");
+    assert_eq!(beta_with_cookie, "beta");
+    assert_eq!(cookie, "beta-cookie");
+  }
+
+  #[test]
+  fn pool_by_req_prefers_pools_with_a_higher_precedence() {
+    // given: a canary pool for requests with a header, which is less specific than the default pool
+    let pool = |name: &str, matcher: &str, precedence: i32| {
+      let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+      builder.matcher = BackendPoolMatcher::from(matcher.to_string());
+      builder.name(name.into());
+      builder.precedence(precedence);
+      Arc::new(builder.build())
+    };
+    let shared_data = SharedData {
+      backend_pools: vec![
+        pool("default", "Host('whoami.localhost') && PathRegexp('^/')", 0),
+        pool("canary", "Header('X-Canary', 'true')", 1),
+      ],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let pool_name = |request: Request<Body>| {
+      pool_by_req(&shared_data, &request, &Scheme::HTTP, None)
+        .unwrap()
+        .name
+        .clone()
+    };
+    let mut canary_request = whoami_request();
+    canary_request.headers_mut().insert("x-canary", "true".parse().unwrap());
+    let mut other_request = whoami_request();
+    other_request.headers_mut().insert("x-canary", "false".parse().unwrap());
+
+    // when:
+    let canary = pool_name(canary_request);
+    let other = pool_name(other_request);
+    let without_header = pool_name(whoami_request());
+
+    // then:
+    assert_eq!(canary, "canary");
+    assert_eq!(other, "default");
+    assert_eq!(without_header, "default");
+  }
+
+  /// Starts a backend server, which answers a single request with `response`.
+  /// If `byte_by_byte` is set, the response is written one byte at a time.
+  async fn start_raw_backend(response: &'static str, byte_by_byte: bool) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut request = Vec::new();
+      let mut buffer = [0; 1024];
+      while !request.windows(4).any(|it| it == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await.unwrap();
+        request.extend_from_slice(&buffer[..read]);
+      }
+      if byte_by_byte {
+        for byte in response.as_bytes() {
+          stream.write_all(&[*byte]).await.unwrap();
+          stream.flush().await.unwrap();
+          tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+      } else {
+        stream.write_all(response.as_bytes()).await.unwrap();
+      }
+      stream.shutdown().await.unwrap();
+    });
+    address
+  }
+
+  /// Starts a backend server, which answers each request with `status_line`
+  /// and the head of the request as the body.
+  async fn start_echo_backend(status_line: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+          let mut request = Vec::new();
+          let mut buffer = [0; 1024];
+          while !request.windows(4).any(|it| it == b"\r\n\r\n") {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+          }
+          let head = format!("{}\r\nContent-Length: {}\r\n\r\n", status_line, request.len());
+          stream.write_all(head.as_bytes()).await.unwrap();
+          stream.write_all(&request).await.unwrap();
+        });
+      }
+    });
+    address
+  }
+
+  async fn assert_backend_response(response: &'static str, byte_by_byte: bool, expected_status: u16) {
+    // given:
+    let backend_address = start_raw_backend(response, byte_by_byte).await;
+    let mut service =
+      generate_test_service_with_backend("whoami.localhost".into(), Scheme::HTTP, backend_address.clone());
+    let request = Request::builder()
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), expected_status);
+    let status = expected_status.to_string();
+    let labels = [
+      ("pool", ""),
+      ("backend", backend_address.as_str()),
+      ("status", status.as_str()),
+    ];
+    assert_eq!(METRICS.counter("arlb_backend_responses_total", &labels), 1);
+    let labels = [("pool", ""), ("backend", backend_address.as_str())];
+    assert_eq!(
+      METRICS.histogram_count("arlb_backend_time_to_first_byte_seconds", &labels),
+      1
+    );
+    let server_errors = METRICS.counter("arlb_backend_server_errors_total", &labels);
+    assert_eq!(server_errors, if expected_status >= 500 { 1 } else { 0 });
+  }
+
+  #[tokio::test]
+  async fn records_ok_response() {
+    assert_backend_response("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok", false, 200).await;
+  }
+
+  #[tokio::test]
+  async fn records_not_found_response() {
+    assert_backend_response("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n", false, 404).await;
+  }
+
+  #[tokio::test]
+  async fn records_service_unavailable_response() {
+    assert_backend_response(
+      "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+      false,
+      503,
+    )
+    .await;
+  }
+
+  #[tokio::test]
+  async fn records_response_sent_byte_by_byte() {
+    assert_backend_response(
+      "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+      true,
+      503,
+    )
+    .await;
+  }
+
+  #[tokio::test]
+  async fn backend_reset_before_the_response_is_a_bad_gateway() {
+    // given: a backend server, which resets the connection instead of responding
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      assert!(stream.read(&mut [0; 1024]).await.unwrap() > 0);
+      stream.set_linger(Some(Duration::from_secs(0))).unwrap();
+    });
+    let mut service = generate_test_service_with_backend("whoami.localhost".into(), Scheme::HTTP, backend_address);
+    let request = Request::builder()
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), hyper::StatusCode::BAD_GATEWAY);
+  }
+
+  pub fn generate_test_pool_builder(addresses: &[&str]) -> BackendPoolBuilder {
+    BackendPoolBuilder::new(
+      BackendPoolMatcher::Host("whoami.localhost".into()),
+      addresses
+        .iter()
+        .map(|it| (it.to_string(), ArcSwap::from_pointee(Healthiness::Healthy)))
+        .collect(),
+      HealthConfig {
+        slow_threshold: 200,
+        timeout: 500,
+        path: String::from("/"),
+        expected_statuses: Vec::new(),
+        expected_body: None,
+        host: None,
+        tls: false,
+        rise: 1,
+        fall: 1,
+        backoff_multiplier: 1,
+        max_backoff_interval: Duration::from_secs(300),
+        backends: HashMap::new(),
+      },
+      Box::new(Random::new()),
+      MiddlewareChain::Empty,
+      HashSet::from_iter(vec![Scheme::HTTP]),
+    )
+  }
+
+  pub fn generate_test_pool(addresses: &[&str]) -> Arc<BackendPool> {
+    Arc::new(generate_test_pool_builder(addresses).build())
+  }
+
+  #[tokio::test]
+  async fn drain_removed_backends_marks_removed_addresses() {
+    // given:
+    let old_pools = vec![generate_test_pool(&["127.0.0.1:8081", "127.0.0.1:8082"])];
+    let new_pools = vec![generate_test_pool(&["127.0.0.1:8082", "127.0.0.1:8083"])];
+
+    // when:
+    drain_removed_backends(&old_pools, &new_pools, Duration::from_secs(30));
+
+    // then:
+    assert!(old_pools[0].is_draining("127.0.0.1:8081"));
+    assert!(!old_pools[0].is_draining("127.0.0.1:8082"));
+    assert!(!new_pools[0].is_draining("127.0.0.1:8083"));
+  }
+
+  #[tokio::test]
+  async fn draining_backend_is_not_selected() {
+    // given:
+    let kept_address = start_raw_backend("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", false).await;
+    let backend_pools = vec![generate_test_pool(&["127.0.0.1:1", &kept_address])];
+    let new_pools = vec![generate_test_pool(&[&kept_address])];
+    drain_removed_backends(&backend_pools, &new_pools, Duration::from_secs(30));
+    let mut service = MainService {
+      scheme: Scheme::HTTP,
+      client_address: "127.0.0.1:3000".parse().unwrap(),
+      tls_info: None,
+      peer_credentials: None,
+      geo_info: None,
+      listener: None,
+      span: None,
+      config: Arc::new(generate_config(SharedData {
+        backend_pools,
+        acme_handler: Arc::new(AcmeHandler::new()),
+      })),
+    };
+    let request = Request::builder()
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 200);
+  }
+
+  fn generate_test_service_with_pool(pool: Arc<BackendPool>) -> MainService {
+    MainService {
+      scheme: Scheme::HTTP,
+      client_address: "127.0.0.1:3000".parse().unwrap(),
+      tls_info: None,
+      peer_credentials: None,
+      geo_info: None,
+      listener: None,
+      span: None,
+      config: Arc::new(generate_config(SharedData {
+        backend_pools: vec![pool],
+        acme_handler: Arc::new(AcmeHandler::new()),
+      })),
+    }
+  }
+
+  #[tokio::test]
+  async fn redirect_rules_take_precedence_over_backend_pools() {
+    // given: the pool matches all requests of the host
+    let backend = start_delayed_backend("backend", Duration::from_millis(0));
+    let mut config = generate_config(SharedData {
+      backend_pools: vec![generate_test_pool(&[&backend])],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    config.redirects = vec![redirects::RedirectRule {
+      id: "old-docs".into(),
+      host: None,
+      path: regex::Regex::new("^/old/(.*)$").unwrap(),
+      target: "https://docs.localhost/$1".into(),
+      status: hyper::StatusCode::FOUND,
+      preserve_query: true,
+    }];
+    let mut service = generate_test_service_with_pool(generate_test_pool(&[&backend]));
+    service.config = Arc::new(config);
+    let request = |uri: &str| {
+      Request::get(uri)
+        .header("host", "whoami.localhost")
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    // when:
+    let redirected = service.call(request("/old/guide?page=2")).await.unwrap();
+    let proxied = service.call(request("/new/guide")).await.unwrap();
+
+    // then:
+    assert_eq!(redirected.status(), hyper::StatusCode::FOUND);
+    assert_eq!(redirected.headers()["location"], "https://docs.localhost/guide?page=2");
+    assert_eq!(redirected.extensions().get::<Redirected>().unwrap().rule, "old-docs");
+    assert_eq!(proxied.status(), hyper::StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn builtin_backends_echo_or_swallow_requests() {
+    // given:
+    let mut echo = generate_test_pool_builder(&["builtin:echo"]).build();
+    echo.matcher = BackendPoolMatcher::Host("echo.localhost".into());
+    let mut sink = generate_test_pool_builder(&["builtin:sink"]);
+    sink.response_timeout(Duration::from_millis(100));
+    let mut sink = sink.build();
+    sink.matcher = BackendPoolMatcher::Host("sink.localhost".into());
+    let config = generate_config(SharedData {
+      backend_pools: vec![Arc::new(echo), Arc::new(sink)],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    let mut service = generate_test_service_with_pool(generate_test_pool(&[]));
+    service.config = Arc::new(config);
+    let request = |host: &str| {
+      Request::post("/ping")
+        .header("host", host)
+        .header("x-debug", "routing")
+        .body(Body::from("hello"))
+        .unwrap()
+    };
+
+    // when:
+    let echoed = service.call(request("echo.localhost")).await.unwrap();
+    let swallowed = service.call(request("sink.localhost")).await.unwrap();
+
+    // then:
+    assert_eq!(echoed.status(), hyper::StatusCode::OK);
+    assert!(matches!(
+      echoed.extensions().get::<Synthetic>(),
+      Some(Synthetic(BuiltinBackend::Echo))
+    ));
+    let body = hyper::body::to_bytes(echoed.into_body()).await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.starts_with("POST /ping HTTP/1.1\r\n"), "{}", body);
+    assert!(body.contains("x-debug: routing\r\n"), "{}", body);
+    assert!(body.ends_with("\r\n\r\nhello"), "{}", body);
+    assert_eq!(swallowed.status(), hyper::StatusCode::GATEWAY_TIMEOUT);
+  }
+
+  #[tokio::test]
+  async fn forwarded_for_of_trusted_proxies_names_the_client() {
+    // given: the client connects via a CDN on the loopback interface
+    let mut config = generate_config(SharedData {
+      backend_pools: vec![generate_test_pool(&["builtin:echo"])],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    config.trusted_proxies = TrustedProxies::new(vec!["127.0.0.0/8".parse().unwrap()]);
+    let mut service = generate_test_service_with_pool(generate_test_pool(&[]));
+    service.config = Arc::new(config);
+    let request = Request::get("/")
+      .header("host", "whoami.localhost")
+      .header("x-forwarded-for", "192.0.2.1, 203.0.113.7")
+      .body(Body::empty())
+      .unwrap();
+    let mut untrusted = generate_test_service_with_pool(generate_test_pool(&["builtin:echo"]));
+    let spoofed = Request::get("/")
+      .header("host", "whoami.localhost")
+      .header("x-forwarded-for", "203.0.113.7")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let proxied = service.call(request).await.unwrap();
+    let spoofed = untrusted.call(spoofed).await.unwrap();
+
+    // then: the chain is continued with the CDN, the spoofed one is dropped
+    let body = hyper::body::to_bytes(proxied.into_body()).await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(
+      body.contains("x-forwarded-for: 192.0.2.1, 203.0.113.7, 127.0.0.1\r\n"),
+      "{}",
+      body
+    );
+    assert!(body.contains("x-real-ip: 203.0.113.7\r\n"), "{}", body);
+    let body = hyper::body::to_bytes(spoofed.into_body()).await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.contains("x-forwarded-for: 127.0.0.1\r\n"), "{}", body);
+    assert!(body.contains("x-real-ip: 127.0.0.1\r\n"), "{}", body);
+  }
+
+  fn whoami_request() -> Request<Body> {
+    Request::builder()
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  #[cfg(feature = "geoip")]
+  #[test]
+  fn routes_by_client_country() {
+    // given: an EU pool in front of the default pool
+    let geoip = crate::geoip::tests::test_database();
+    let mut eu = generate_test_pool_builder(&["127.0.0.1:1"]);
+    eu.matcher = BackendPoolMatcher::from(String::from("Country('DE', 'FR')"));
+    eu.name("eu".into());
+    let mut default = generate_test_pool_builder(&["127.0.0.1:2"]);
+    default.name("default".into());
+    let shared_data = SharedData {
+      backend_pools: vec![Arc::new(eu.build()), Arc::new(default.build())],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let pool_name = |client_ip: &str| {
+      let mut request = whoami_request();
+      request
+        .extensions_mut()
+        .insert(geoip.lookup(client_ip.parse().unwrap()));
+      pool_by_req(&shared_data, &request, &Scheme::HTTP, None)
+        .unwrap()
+        .name
+        .clone()
+    };
+
+    // when:
+    let germany = pool_name("2.16.0.1");
+    let united_states = pool_name("8.8.8.8");
+    let private = pool_name("10.0.0.1");
+
+    // then: clients without a known country fall back to the default pool
+    assert_eq!(germany, "eu");
+    assert_eq!(united_states, "default");
+    assert_eq!(private, "default");
+  }
+
+  #[cfg(feature = "opentelemetry")]
+  #[tokio::test]
+  async fn exports_spans_of_proxied_requests() {
+    use crate::telemetry::tests::{attribute, in_memory_exporter, span_named, spans_of_trace};
+    use opentelemetry::trace::TraceId;
+
+    // given: a backend server, which sends the head of the request back
+    let exporter = in_memory_exporter();
+    let backend = start_echo_backend("HTTP/1.1 200 OK").await;
+    let mut builder = generate_test_pool_builder(&[&backend]);
+    builder.name("traced".into());
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+    service.span = TELEMETRY.connection_span(service.client_address, &Scheme::HTTP);
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+    let status = response.status();
+    let received = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    drop(service);
+
+    // then: the backend server received the span of the load balancer as its parent
+    assert_eq!(status, 200);
+    let received = String::from_utf8_lossy(&received).to_lowercase();
+    let traceparent = received
+      .lines()
+      .find_map(|it| it.strip_prefix("traceparent: "))
+      .unwrap();
+    let trace_id = TraceId::from_hex(traceparent.split('-').nth(1).unwrap()).unwrap();
+    let spans = spans_of_trace(&exporter, trace_id);
+    let connection = span_named(&spans, "connection");
+    let request = span_named(&spans, "GET");
+    let connect = span_named(&spans, "backend_connect");
+    assert_eq!(request.parent_span_id, connection.span_context.span_id());
+    assert_eq!(connect.parent_span_id, request.span_context.span_id());
+    assert_eq!(
+      traceparent.split('-').nth(2).unwrap(),
+      request.span_context.span_id().to_string()
+    );
+    assert_eq!(attribute(request, "arlb.pool"), Some("traced".into()));
+    assert_eq!(attribute(request, "arlb.backend"), Some(backend.into()));
+    assert_eq!(attribute(request, "http.response.status_code"), Some(200.into()));
+    assert_eq!(
+      attribute(request, "http.response.body.size"),
+      Some((received.len() as i64).into())
+    );
+  }
+
+  #[test]
+  fn access_log_names_the_process_of_unix_socket_clients() {
+    // given:
+    let mut request = whoami_request();
+    request.extensions_mut().insert(PeerCredentials {
+      uid: 1000,
+      pid: Some(4242),
+    });
+    let client_address = "127.0.0.1:0".parse().unwrap();
+
+    // when:
+    let entry = AccessLogEntry::new(&client_address, &request);
+    let message = entry.message(&Response::new(Body::empty()));
+
+    // then:
+    assert!(message.contains(" uid:1000 pid:4242"), "{}", message);
+  }
+
+  #[test]
+  fn canary_receives_configured_share_of_requests() {
+    // given:
+    let mut stable = generate_test_pool_builder(&["127.0.0.1:1"]);
+    stable.name("stable".into());
+    stable.canary(CanaryConfig {
+      pool: "canary".into(),
+      percent: 5.0,
+    });
+    let mut canary = generate_test_pool_builder(&["127.0.0.1:2"]);
+    canary.matcher = BackendPoolMatcher::Host("canary.localhost".into());
+    canary.name("canary".into());
+    let shared_data = SharedData {
+      backend_pools: vec![Arc::new(stable.build()), Arc::new(canary.build())],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+
+    // when:
+    let canary_requests = (0..10000)
+      .filter(|_| {
+        pool_by_req(&shared_data, &whoami_request(), &Scheme::HTTP, None)
+          .unwrap()
+          .name
+          == "canary"
+      })
+      .count();
+
+    // then:
+    assert!(
+      (350..=650).contains(&canary_requests),
+      "canary requests: {}",
+      canary_requests
+    );
+  }
+
+  /// A pool named "primary", whose requests are mirrored to the pool "shadow"
+  /// with the backend server `shadow_address`.
+  fn generate_test_service_with_mirror(shadow_address: &str) -> MainService {
+    let mut primary = generate_test_pool_builder(&["builtin:echo"]);
+    primary.name("primary".into());
+    primary.mirror(MirrorConfig {
+      pool: "shadow".into(),
+      percent: 100.0,
+      max_body_size: 1024,
+    });
+    let mut shadow = generate_test_pool_builder(&[shadow_address]);
+    shadow.matcher = BackendPoolMatcher::Host("shadow.localhost".into());
+    shadow.name("shadow".into());
+    shadow.response_timeout(Duration::from_millis(100));
+    let mut service = generate_test_service_with_pool(generate_test_pool(&[]));
+    service.config = Arc::new(generate_config(SharedData {
+      backend_pools: vec![Arc::new(primary.build()), Arc::new(shadow.build())],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    }));
+    service
+  }
+
+  fn post_request(body: &'static str) -> Request<Body> {
+    Request::post("/orders")
+      .header("host", "whoami.localhost")
+      .body(Body::from(body))
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn mirror_pool_receives_a_copy_of_the_request() {
+    // given: a shadow backend server, which fails after receiving the request
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let shadow = listener.local_addr().unwrap().to_string();
+    let (sender, received) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut request = Vec::new();
+      let mut buffer = [0; 1024];
+      while !request.ends_with(b"hello") {
+        let read = stream.read(&mut buffer).await.unwrap();
+        request.extend_from_slice(&buffer[..read]);
+      }
+      let _ = sender.send(String::from_utf8(request).unwrap());
+      let response = "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n";
+      stream.write_all(response.as_bytes()).await.unwrap();
+    });
+    let mut service = generate_test_service_with_mirror(&shadow);
+    let mut config = generate_config(SharedData {
+      backend_pools: service.config.shared_data.backend_pools.clone(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    config.trusted_proxies = TrustedProxies::new(vec!["127.0.0.0/8".parse().unwrap()]);
+    service.config = Arc::new(config);
+    let mut request = post_request("hello");
+    request
+      .headers_mut()
+      .insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+    let mirrored = || {
+      METRICS.counter(
+        "arlb_mirrored_requests_total",
+        &[("pool", "primary"), ("mirror", "shadow")],
+      )
+    };
+    let mirrored_before = mirrored();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then: the copy continues the chain of the trusted proxy like the request
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert!(body.ends_with(b"\r\n\r\nhello"));
+    let copy = received.await.unwrap();
+    assert!(copy.starts_with("POST /orders HTTP/1.1\r\n"), "{}", copy);
+    assert!(copy.contains("x-forwarded-for: 203.0.113.7, 127.0.0.1\r\n"), "{}", copy);
+    assert_eq!(mirrored(), mirrored_before + 1);
+  }
+
+  #[tokio::test]
+  async fn hung_mirror_pool_does_not_delay_the_response() {
+    // given:
+    let hung = start_hung_backend().await;
+    let mut service = generate_test_service_with_mirror(&hung);
+
+    // when:
+    let start = Instant::now();
+    let response = service.call(post_request("hello")).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    assert!(start.elapsed() < Duration::from_millis(100), "{:?}", start.elapsed());
+  }
+
+  #[test]
+  fn listeners_only_serve_their_pools() {
+    // given: a public pool in front of an internal pool for the same host
+    let mut public = generate_test_pool_builder(&["127.0.0.1:1"]);
+    public.name("public".into());
+    let mut internal = generate_test_pool_builder(&["127.0.0.1:2"]);
+    internal.name("internal".into());
+    let shared_data = SharedData {
+      backend_pools: vec![Arc::new(public.build()), Arc::new(internal.build())],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    };
+    let listener = ListenerConfig {
+      name: "admin".into(),
+      address: "127.0.0.1:8080".parse().unwrap(),
+      tls: false,
+      pools: Some(vec!["internal".into()]),
+      certificates: Certificates::default(),
+      keep_alive: KeepAliveLimits::default(),
+    };
+
+    // when:
+    let of_listener = pool_by_req(&shared_data, &whoami_request(), &Scheme::HTTP, Some(&listener));
+    let of_http_port = pool_by_req(&shared_data, &whoami_request(), &Scheme::HTTP, None);
+
+    // then:
+    assert_eq!(of_listener.unwrap().name, "internal");
+    assert_eq!(of_http_port.unwrap().name, "public");
+  }
+
+  #[tokio::test]
+  async fn local_authentication_only_protects_its_route() {
+    // given: a protected pool without a reachable backend server and an open pool
+    let middlewares: toml::value::Table = toml::from_str(
+      r#"
+      [LocalAuthentication]
+      bearer_tokens = ["s3cr3t"]
+      "#,
+    )
+    .unwrap();
+    let mut protected = generate_test_pool_builder(&["127.0.0.1:1"]);
+    protected.chain = MiddlewareChain::try_from(middlewares).unwrap();
+    let mut protected = generate_test_service_with_pool(Arc::new(protected.build()));
+    let backend = start_raw_backend("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok", false).await;
+    let mut open = generate_test_service_with_pool(generate_test_pool(&[&backend]));
+
+    // when:
+    let unauthenticated = protected.call(whoami_request()).await.unwrap();
+    let unprotected = open.call(whoami_request()).await.unwrap();
+
+    // then: the unauthenticated request never reached a backend server
+    assert_eq!(unauthenticated.status().as_u16(), 401);
+    assert_eq!(
+      unauthenticated.headers().get("www-authenticate").unwrap(),
+      "Bearer realm=\"Another Rust Load Balancer requires authentication\""
+    );
+    assert_eq!(unprotected.status().as_u16(), 200);
+  }
+
+  #[tokio::test]
+  async fn rate_limited_requests_are_counted_as_local_responses() {
+    // given:
+    let middlewares: toml::value::Table = toml::from_str(
+      r#"
+      [RateLimiter]
+      limit = 0
+      window_sec = 10
+      "#,
+    )
+    .unwrap();
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+    builder.name("rate-limited".into());
+    builder.chain = MiddlewareChain::try_from(middlewares).unwrap();
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 429);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "10");
+    let labels = [("pool", "rate-limited"), ("status", "429")];
+    assert_eq!(METRICS.counter("arlb_local_responses_total", &labels), 1);
+    assert_eq!(METRICS.counter("arlb_pool_responses_total", &labels), 0);
+  }
+
+  #[tokio::test]
+  async fn chunked_request_body_over_the_limit_is_a_local_response() {
+    // given: a request body without a Content-Length, which exceeds the limit
+    let (backend, _) = start_recording_backend();
+    let middlewares: toml::value::Table = toml::from_str("[MaxBodySize]\nlimit = 5").unwrap();
+    let mut builder = generate_test_pool_builder(&[&backend]);
+    builder.name("chunked-body-limit".into());
+    builder.chain = MiddlewareChain::try_from(middlewares).unwrap();
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+    let chunks = vec!["hello ", "world"].into_iter().map(Ok::<_, io::Error>);
+    let request = Request::post("/")
+      .header("host", "whoami.localhost")
+      .body(Body::wrap_stream(futures::stream::iter(chunks)))
+      .unwrap();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then: the backend server is not blamed for it
+    assert_eq!(response.status().as_u16(), 413);
+    let labels = [("pool", "chunked-body-limit"), ("status", "413")];
+    assert_eq!(METRICS.counter("arlb_local_responses_total", &labels), 1);
+    let labels = [
+      ("pool", "chunked-body-limit"),
+      ("backend", backend.as_str()),
+      ("status", "502"),
+    ];
+    assert_eq!(METRICS.counter("arlb_backend_responses_total", &labels), 0);
+  }
+
+  fn generate_https_service(sni_host_check: SniHostCheck) -> MainService {
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+    builder.schemes = HashSet::from_iter(vec![Scheme::HTTPS]);
+    builder.respond(StaticResponse {
+      status: hyper::StatusCode::OK,
+      headers: Default::default(),
+      body: "ok".into(),
+    });
+    let mut config = generate_config(SharedData {
+      backend_pools: vec![Arc::new(builder.build())],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    config.tls.sni_host_check = sni_host_check;
+    MainService {
+      scheme: Scheme::HTTPS,
+      client_address: "127.0.0.1:3000".parse().unwrap(),
+      tls_info: Some(TlsInfo {
+        server_name: Some("whoami.localhost".into()),
+        ..Default::default()
+      }),
+      peer_credentials: None,
+      geo_info: None,
+      listener: None,
+      span: None,
+      config: Arc::new(config),
+    }
+  }
+
+  #[tokio::test]
+  async fn strict_sni_host_check_accepts_matching_host() {
+    // given:
+    let mut service = generate_https_service(SniHostCheck::Strict);
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+  }
+
+  #[tokio::test]
+  async fn strict_sni_host_check_rejects_mismatching_host() {
+    // given:
+    let mut service = generate_https_service(SniHostCheck::Strict);
+    let request = Request::builder()
+      .header("host", "other.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), hyper::StatusCode::MISDIRECTED_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn strict_sni_host_check_rejects_missing_host() {
+    // given:
+    let mut service = generate_https_service(SniHostCheck::Strict);
+
+    // when:
+    let response = service.call(Request::new(Body::empty())).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), hyper::StatusCode::MISDIRECTED_REQUEST);
+  }
+
+  #[tokio::test]
+  async fn log_only_sni_host_check_routes_mismatching_host() {
+    // given:
+    let mut service = generate_https_service(SniHostCheck::LogOnly);
+    let request = Request::builder()
+      .header("host", "other.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then: the request is routed by host, so no pool is found
+    assert_eq!(response.status(), hyper::StatusCode::NOT_FOUND);
+    assert!(METRICS.counter("arlb_sni_host_mismatches_total", &[]) >= 1);
+  }
+
+  #[tokio::test]
+  async fn static_response_does_not_contact_backend() {
+    // given: a pool with an unreachable backend server
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+    builder.respond(StaticResponse {
+      status: hyper::StatusCode::OK,
+      headers: Default::default(),
+      body: "ok".into(),
+    });
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.extensions().get::<LocalResponse>().is_some());
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "ok");
+  }
+
+  #[tokio::test]
+  async fn unavailable_response_is_sent_without_healthy_backend() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+    let mut unavailable = StaticResponse::maintenance();
+    unavailable.headers.insert("retry-after", "30".parse().unwrap());
+    builder.unavailable(unavailable);
+    let pool = builder.build();
+    pool.addresses[0].1.store(Arc::new(Healthiness::Unresponsive(None)));
+    let mut service = generate_test_service_with_pool(Arc::new(pool));
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(response.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "30");
+    assert!(response.extensions().get::<LocalResponse>().is_some());
+  }
+
+  #[test]
+  fn tag_routes_restrict_the_backend_servers() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:8081", "127.0.0.1:8082", "127.0.0.1:8083"]);
+    let tags = |pairs: &[(&str, &str)]| {
+      pairs
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>()
+    };
+    builder.tags(HashMap::from_iter(vec![
+      (
+        "127.0.0.1:8081".to_string(),
+        tags(&[("region", "us"), ("tier", "premium")]),
+      ),
+      ("127.0.0.1:8082".to_string(), tags(&[("region", "us")])),
+    ]));
+    builder.tag_routes(vec![
+      TagRoute {
+        matcher: BackendPoolMatcher::Path("/premium".into()),
+        tags: tags(&[("tier", "premium")]),
+      },
+      TagRoute {
+        matcher: BackendPoolMatcher::Path("/us".into()),
+        tags: tags(&[("region", "us")]),
+      },
+    ]);
+    let pool = builder.build();
+    let request = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+    // when:
+    let premium = pool.routed_addresses(&request("/premium"));
+    let us = pool.routed_addresses(&request("/us"));
+    let other = pool.routed_addresses(&request("/"));
+
+    // then:
+    assert_eq!(premium, vec!["127.0.0.1:8081"]);
+    assert_eq!(us, vec!["127.0.0.1:8081", "127.0.0.1:8082"]);
+    assert_eq!(other, vec!["127.0.0.1:8081", "127.0.0.1:8082", "127.0.0.1:8083"]);
+  }
+
+  #[test]
+  fn requests_stay_in_the_local_zone_while_it_is_available() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:8081", "127.0.0.1:8082", "127.0.0.1:8083"]);
+    builder.name("zones".into());
+    let zone = |zone: &str| HashMap::from_iter(vec![("zone".to_string(), zone.to_string())]);
+    builder.tags(HashMap::from_iter(vec![
+      ("127.0.0.1:8081".to_string(), zone("eu-west-1a")),
+      ("127.0.0.1:8082".to_string(), zone("eu-west-1b")),
+      ("127.0.0.1:8083".to_string(), zone("eu-west-1c")),
+    ]));
+    builder.local_zone("eu-west-1a".into());
+    let pool = builder.build();
+    let request = Request::get("/").body(Body::empty()).unwrap();
+    let labels = [("pool", "zones")];
+
+    // when: the local backend server is healthy
+    let local = pool.routed_addresses(&request);
+
+    // then:
+    assert_eq!(local, vec!["127.0.0.1:8081"]);
+    assert_eq!(METRICS.counter("arlb_pool_zone_failovers_total", &labels), 0);
+
+    // when: the local backend server is down
+    pool.addresses[0].1.store(Arc::new(Healthiness::Unresponsive(None)));
+    let failover = pool.routed_addresses(&request);
+
+    // then:
+    assert_eq!(failover, vec!["127.0.0.1:8082", "127.0.0.1:8083"]);
+    assert_eq!(METRICS.counter("arlb_pool_zone_failovers_total", &labels), 1);
+
+    // when: the local backend server is healthy again
+    pool.addresses[0].1.store(Arc::new(Healthiness::Healthy));
+    let recovered = pool.routed_addresses(&request);
+
+    // then:
+    assert_eq!(recovered, vec!["127.0.0.1:8081"]);
+  }
+
+  #[test]
+  fn backend_servers_at_their_bandwidth_budget_are_shed() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:7101", "127.0.0.1:7102"]);
+    builder.name("metered".into());
+    builder.max_backend_bytes_per_sec(1000);
+    let pool = builder.build();
+    let request = Request::get("/").body(Body::empty()).unwrap();
+    let first = BACKEND_BANDWIDTH.meter("127.0.0.1:7101");
+    let second = BACKEND_BANDWIDTH.meter("127.0.0.1:7102");
+
+    // when: the first backend server exceeds its budget
+    first.record(5000);
+    BACKEND_BANDWIDTH.sample(Duration::from_secs(1));
+    let one_over_budget = pool.routed_addresses(&request);
+
+    // then:
+    assert_eq!(one_over_budget, vec!["127.0.0.1:7102"]);
+
+    // when: both backend servers exceed their budget
+    first.record(5000);
+    second.record(5000);
+    BACKEND_BANDWIDTH.sample(Duration::from_secs(1));
+    let all_over_budget = pool.routed_addresses(&request);
+
+    // then:
+    assert!(all_over_budget.is_empty());
+    assert_eq!(
+      METRICS.counter("arlb_pool_bandwidth_sheds_total", &[("pool", "metered")]),
+      1
+    );
+  }
+
+  #[tokio::test]
+  async fn requests_wait_for_a_backend_server_to_become_available() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:8081"]);
+    builder.name("waiting".into());
+    let pool = builder.build();
+    pool.addresses[0].1.store(Arc::new(Healthiness::Unresponsive(None)));
+    let request = Request::get("/").body(Body::empty()).unwrap();
+    let labels = |result| [("pool", "waiting"), ("result", result)];
+
+    // when: no backend server becomes available
+    let timed_out = wait_for_backend(&pool, &request, Duration::from_millis(50)).await;
+
+    // then:
+    assert!(timed_out.is_empty());
+    assert_eq!(METRICS.counter("arlb_pool_backend_waits_total", &labels("timeout")), 1);
+
+    // when: the backend server becomes healthy while waiting
+    let recover = async {
+      tokio::time::sleep(Duration::from_millis(50)).await;
+      pool.addresses[0].1.store(Arc::new(Healthiness::Healthy));
+      BACKEND_AVAILABLE.notify_waiters();
+    };
+    let start = Instant::now();
+    let (available, _) = tokio::join!(wait_for_backend(&pool, &request, Duration::from_secs(5)), recover);
+
+    // then:
+    assert_eq!(available, vec!["127.0.0.1:8081"]);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(
+      METRICS.counter("arlb_pool_backend_waits_total", &labels("available")),
+      1
+    );
+  }
+
+  #[test]
+  fn backup_servers_are_only_used_if_no_other_server_is_available() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:8081", "127.0.0.1:8082", "127.0.0.1:9000"]);
+    builder.name("with-backup".into());
+    builder.backup_addresses(HashSet::from_iter(vec!["127.0.0.1:9000".to_string()]));
+    let pool = builder.build();
+    let set_health = |index: usize, healthiness: Healthiness| pool.addresses[index].1.store(Arc::new(healthiness));
+    let labels = [("pool", "with-backup")];
+
+    // when: all primaries are up
+    let working_addresses = pool.working_addresses();
+
+    // then:
+    assert_eq!(working_addresses, vec!["127.0.0.1:8081", "127.0.0.1:8082"]);
+    assert_eq!(METRICS.gauge("arlb_pool_using_backups", &labels), 0);
+
+    // when: all primaries are down
+    set_health(0, Healthiness::Unresponsive(None));
+    set_health(1, Healthiness::Unresponsive(None));
+    let working_addresses = pool.working_addresses();
+
+    // then:
+    assert_eq!(working_addresses, vec!["127.0.0.1:9000"]);
+    assert_eq!(METRICS.gauge("arlb_pool_using_backups", &labels), 1);
+
+    // when: one primary recovers
+    set_health(1, Healthiness::Healthy);
+    let working_addresses = pool.working_addresses();
+
+    // then:
+    assert_eq!(working_addresses, vec!["127.0.0.1:8082"]);
+    assert_eq!(METRICS.gauge("arlb_pool_using_backups", &labels), 0);
+  }
+
+  #[test]
+  fn slow_starting_backend_receives_a_growing_share() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:8081", "127.0.0.1:8082", "127.0.0.1:8083"]);
+    let now = Instant::now();
+    builder.slow_start(
+      Duration::from_secs(100),
+      HashMap::from_iter(vec![
+        ("127.0.0.1:8082".to_string(), now - Duration::from_secs(25)),
+        ("127.0.0.1:8083".to_string(), now - Duration::from_secs(100)),
+      ]),
+    );
+    let pool = builder.build();
+
+    // when:
+    let mut selected = HashMap::<&str, u32>::new();
+    for _ in 0..1000 {
+      for address in pool.working_addresses() {
+        *selected.entry(address).or_default() += 1;
+      }
+    }
+
+    // then: the backend server, which is a quarter into its slow start, is left out about 3 out of 4 times
+    assert_eq!(selected["127.0.0.1:8081"], 1000);
+    assert!((150..350).contains(&selected["127.0.0.1:8082"]));
+    assert_eq!(selected["127.0.0.1:8083"], 1000);
+  }
+
+  #[test]
+  fn slow_starting_backend_is_used_if_it_is_the_only_one() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:8081"]);
+    builder.slow_start(
+      Duration::from_secs(100),
+      HashMap::from_iter(vec![("127.0.0.1:8081".to_string(), Instant::now())]),
+    );
+    let pool = builder.build();
+
+    // when:
+    let addresses = pool.working_addresses();
+
+    // then:
+    assert_eq!(addresses, vec!["127.0.0.1:8081"]);
+  }
+
+  #[test]
+  fn unhealthy_backup_servers_are_not_used() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:8081", "127.0.0.1:9000"]);
+    builder.backup_addresses(HashSet::from_iter(vec!["127.0.0.1:9000".to_string()]));
+    let pool = builder.build();
+    pool.addresses[0].1.store(Arc::new(Healthiness::Unresponsive(None)));
+    pool.addresses[1].1.store(Arc::new(Healthiness::Unresponsive(None)));
+
+    // when:
+    let working_addresses = pool.working_addresses();
+
+    // then:
+    assert!(working_addresses.is_empty());
+  }
+
+  #[tokio::test]
+  async fn pool_maintenance_does_not_affect_requests_in_flight() {
+    // given: a request in flight to a backend server, which waits for `release`
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_address = listener.local_addr().unwrap().to_string();
+    let (received_sender, received) = tokio::sync::oneshot::channel();
+    let (release, released) = tokio::sync::oneshot::channel::<()>();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut buffer = [0; 1024];
+      let read = stream.read(&mut buffer).await.unwrap();
+      assert!(read > 0);
+      received_sender.send(()).unwrap();
+      released.await.unwrap();
+      stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    });
+    let mut service = generate_test_service_with_pool(generate_test_pool(&[&backend_address]));
+    let in_flight = tokio::spawn(service.call(whoami_request()));
+    received.await.unwrap();
+
+    // when: the next request is served with a configuration, whose pool is in maintenance
+    let mut builder = generate_test_pool_builder(&[&backend_address]);
+    builder.maintenance(true);
+    service.config = Arc::new(generate_config(SharedData {
+      backend_pools: vec![Arc::new(builder.build())],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    }));
+    let response = service.call(whoami_request()).await.unwrap();
+    release.send(()).unwrap();
+
+    // then: only the new request is answered by the load balancer itself
+    assert_eq!(response.status().as_u16(), 503);
+    assert!(response.extensions().get::<LocalResponse>().is_some());
+    let in_flight_response = in_flight.await.unwrap().unwrap();
+    assert_eq!(in_flight_response.status().as_u16(), 200);
+    assert!(in_flight_response.extensions().get::<LocalResponse>().is_none());
+  }
+
+  #[tokio::test]
+  async fn global_maintenance_answers_requests_of_all_pools() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+    builder.name("global-maintenance".into());
+    let mut config = generate_config(SharedData {
+      backend_pools: vec![Arc::new(builder.build())],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    let mut maintenance_response = StaticResponse::maintenance();
+    maintenance_response.body = "planned maintenance".into();
+    config.maintenance_response = maintenance_response;
+    let mut service = generate_test_service_with_pool(generate_test_pool(&["127.0.0.1:1"]));
+    service.config = Arc::new(config);
+    let local_responses = || {
+      let labels = [("pool", "global-maintenance"), ("status", "503")];
+      METRICS.counter("arlb_local_responses_total", &labels)
+    };
+    let local_responses_before = local_responses();
+    let challenge = Request::get("/.well-known/acme-challenge/unknown-token")
+      .header("host", "whoami.localhost")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    service.config.maintenance.store(true, Ordering::Relaxed);
+    let during_maintenance = service.call(whoami_request()).await.unwrap();
+    let challenge = service.call(challenge).await.unwrap();
+    service.config.maintenance.store(false, Ordering::Relaxed);
+    let after_maintenance = service.call(whoami_request()).await.unwrap();
+
+    // then: ACME challenges are not answered by the maintenance response
+    assert_eq!(during_maintenance.status().as_u16(), 503);
+    assert!(during_maintenance.extensions().get::<LocalResponse>().is_some());
+    assert_eq!(local_responses(), local_responses_before + 1);
+    assert_eq!(challenge.status().as_u16(), 404);
+    let body = hyper::body::to_bytes(during_maintenance.into_body()).await.unwrap();
+    assert_eq!(body, "planned maintenance");
+    assert_eq!(after_maintenance.status().as_u16(), 502);
+  }
+
+  #[tokio::test]
+  async fn json_log_entries_of_failed_requests_contain_correlation_fields() {
+    // given: a pool, whose only backend server is down
+    let entries = logging::capture_json_entries();
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+    let mut request_id = toml::value::Table::new();
+    request_id.insert("trust_incoming".into(), toml::Value::Boolean(true));
+    builder.chain = MiddlewareChain::Entry {
+      middleware: Box::new(crate::middleware::request_id::RequestId::try_from(request_id).unwrap()),
+      chain: Box::new(MiddlewareChain::Empty),
+    };
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+    service.listener = Some("internal".into());
+    let mut request = whoami_request();
+    request.headers_mut().insert(
+      "x-request-id",
+      hyper::header::HeaderValue::from_static("json-log-entries"),
+    );
+    request.extensions_mut().insert(ConnectionId(42));
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 502);
+    let entries = entries.lock().unwrap();
+    let entry = entries
+      .iter()
+      .map(|it| serde_json::from_str::<serde_json::Value>(it).unwrap())
+      .find(|it| it["request_id"] == "json-log-entries")
+      .expect("The error should be logged");
+    assert_eq!(entry["level"], "ERROR");
+    assert!(entry["message"].is_string());
+    assert_eq!(entry["connection_id"], "42");
+    assert_eq!(entry["listener"], "internal");
+    assert_eq!(entry["client_ip"], "127.0.0.1");
+    assert_eq!(entry["backend"], "127.0.0.1:1");
+  }
+
+  #[tokio::test]
+  async fn headers_exceeding_the_limits_are_rejected() {
+    // given: a backend server, which responds with many headers
+    let backend = start_raw_backend(
+      "HTTP/1.1 200 OK\r\nA: 1\r\nB: 2\r\nC: 3\r\nContent-Length: 0\r\n\r\n",
+      false,
+    )
+    .await;
+    let mut builder = generate_test_pool_builder(&[&backend]);
+    builder.name("header-limits".into());
+    let pool = Arc::new(builder.build());
+    let mut service = generate_test_service_with_pool(pool.clone());
+    let mut config = generate_config(SharedData {
+      backend_pools: vec![pool],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    config.header_limits = HeaderLimits {
+      max_size: Some(64),
+      max_count: Some(3),
+    };
+    service.config = Arc::new(config);
+    let oversized = Request::builder()
+      .header("host", "whoami.localhost")
+      .header("cookie", "a".repeat(64))
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let oversized_request = service.call(oversized).await.unwrap();
+    let too_many_response_headers = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(oversized_request.status().as_u16(), 431);
+    assert_eq!(too_many_response_headers.status().as_u16(), 502);
+    assert_eq!(
+      METRICS.counter(
+        "arlb_rejected_responses_total",
+        &[("pool", "header-limits"), ("reason", "too_many_headers")]
+      ),
+      1
+    );
+  }
+
+  #[tokio::test]
+  async fn saturated_pool_rejects_requests() {
+    // given: a pool with a single slot, which is held until the body of the first response is read
+    let backend = start_raw_backend("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok", false).await;
+    let mut builder = generate_test_pool_builder(&[&backend]);
+    builder.name("saturated".into());
+    builder.concurrency_limit(ConcurrencyLimitConfig {
+      max_connections: 1,
+      queue_length: 0,
+      queue_timeout: Duration::from_secs(1),
+    });
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+    let first = service.call(whoami_request()).await.unwrap();
+
+    // when:
+    let second = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 503);
+    let labels = [("pool", "saturated"), ("reason", "queue_full")];
+    assert_eq!(METRICS.counter("arlb_pool_queue_rejections_total", &labels), 1);
+    assert_eq!(METRICS.gauge("arlb_pool_queued_requests", &[("pool", "saturated")]), 0);
+    let body = hyper::body::to_bytes(first.into_body()).await.unwrap();
+    assert_eq!(body, "ok");
+  }
+
+  #[tokio::test]
+  async fn saturated_backend_rejects_queued_requests_after_timeout() {
+    // given: a backend server with a single slot, which is held until the body of the first response is read
+    let backend = start_raw_backend("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok", false).await;
+    let mut builder = generate_test_pool_builder(&[&backend]);
+    builder.name("saturated-backend".into());
+    builder.backend_concurrency_limit(ConcurrencyLimitConfig {
+      max_connections: 1,
+      queue_length: 1,
+      queue_timeout: Duration::from_millis(50),
+    });
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+    let first = service.call(whoami_request()).await.unwrap();
+
+    // when:
+    let second = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 503);
+    let labels = [("pool", "saturated-backend"), ("backend", backend.as_str())];
+    assert_eq!(METRICS.gauge("arlb_backend_queued_requests", &labels), 0);
+    let labels = [
+      ("pool", "saturated-backend"),
+      ("backend", backend.as_str()),
+      ("reason", "timeout"),
+    ];
+    assert_eq!(METRICS.counter("arlb_backend_queue_rejections_total", &labels), 1);
+    let body = hyper::body::to_bytes(first.into_body()).await.unwrap();
+    assert_eq!(body, "ok");
+  }
+
+  #[tokio::test]
+  async fn high_priority_routes_are_served_while_low_priority_routes_are_saturated() {
+    // given: a pool with two slots and a low priority route, which may take both of them
+    let route = |name: &str, path: &str, priority: Priority, max_in_flight: usize| PriorityRoute {
+      name: name.into(),
+      matcher: BackendPoolMatcher::from(format!("PathRegexp('^{}')", path)),
+      priority,
+      in_flight_limiter: Some(ConcurrencyLimiter::new(ConcurrencyLimitConfig {
+        max_connections: max_in_flight,
+        queue_length: 0,
+        queue_timeout: Duration::from_secs(0),
+      })),
+      retry_after: Some(Duration::from_secs(5)),
+      timeouts: Timeouts::default(),
+    };
+    let mut builder = generate_test_pool_builder(&["builtin:echo"]);
+    builder.matcher = BackendPoolMatcher::Host("shop.localhost".into());
+    builder.name("shop".into());
+    builder.concurrency_limit(ConcurrencyLimitConfig {
+      max_connections: 2,
+      queue_length: 10,
+      queue_timeout: Duration::from_secs(5),
+    });
+    builder.priority_routes(vec![
+      route("search", "/search", Priority::Low, 2),
+      route("checkout", "/checkout", Priority::High, 1),
+    ]);
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+    let request = |path: &str| {
+      Request::get(path)
+        .header("host", "shop.localhost")
+        .body(Body::empty())
+        .unwrap()
+    };
+    let in_flight = |route: &str| METRICS.gauge("arlb_route_in_flight_requests", &[("pool", "shop"), ("route", route)]);
+    let queued = || METRICS.gauge("arlb_pool_queued_requests", &[("pool", "shop")]);
+
+    // when: the searches hold both slots, while another request without a route queues first
+    let first_search = service.call(request("/search?q=1")).await.unwrap();
+    let second_search = service.call(request("/search?q=2")).await.unwrap();
+    let rejected_search = service.call(request("/search?q=3")).await.unwrap();
+    let other = tokio::spawn(service.call(request("/other")));
+    while queued() < 1 {
+      tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let checkout = tokio::spawn(service.call(request("/checkout")));
+    while queued() < 2 {
+      tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    drop(first_search);
+
+    // then: the checkout takes the released slot ahead of the request without a route
+    let checkout = checkout.await.unwrap().unwrap();
+    assert_eq!(checkout.status().as_u16(), 200);
+    assert_eq!(in_flight("checkout"), 1);
+    assert_eq!(queued(), 1);
+    assert_eq!(rejected_search.status().as_u16(), 503);
+    assert_eq!(rejected_search.headers().get(RETRY_AFTER).unwrap(), "5");
+    let labels = [("pool", "shop"), ("route", "search"), ("reason", "max_in_flight")];
+    assert_eq!(METRICS.counter("arlb_route_rejected_requests_total", &labels), 1);
+    assert_eq!(in_flight("search"), 1);
+
+    drop(checkout);
+    let other = other.await.unwrap().unwrap();
+    assert_eq!(other.status().as_u16(), 200);
+    drop(second_search);
+    assert_eq!(in_flight("search"), 0);
+    assert_eq!(in_flight("checkout"), 0);
+  }
+
+  fn generate_retry_service(name: &str, addresses: &[&str]) -> MainService {
+    let mut builder = generate_test_pool_builder(addresses);
+    builder.name(name.into());
+    builder.retry(RetryConfig {
+      max_retries: 1,
+      methods: RetryConfig::default_methods(),
+      max_body_size: 1024,
+    });
+    generate_test_service_with_pool(Arc::new(builder.build()))
+  }
+
+  #[tokio::test]
+  async fn retries_idempotent_request_on_another_backend() {
+    // given:
+    let unavailable = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+    let first = start_raw_backend(unavailable, false).await;
+    let second = start_raw_backend(unavailable, false).await;
+    let mut service = generate_retry_service("retry-get", &[&first, &second]);
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+
+    // then: both backend servers were tried once
+    assert_eq!(response.status().as_u16(), 503);
+    let mut retries = 0;
+    for backend in &[first, second] {
+      let labels = [("pool", "retry-get"), ("backend", backend.as_str()), ("status", "503")];
+      assert_eq!(METRICS.counter("arlb_backend_responses_total", &labels), 1);
+      let labels = [("pool", "retry-get"), ("backend", backend.as_str())];
+      retries += METRICS.counter("arlb_backend_retries_total", &labels);
+    }
+    assert_eq!(retries, 1);
+  }
+
+  #[tokio::test]
+  async fn retried_request_keeps_forwarded_for_of_trusted_proxy() {
+    // given: the client connects via a CDN on the loopback interface
+    let unavailable = "HTTP/1.1 503 Service Unavailable";
+    let first = start_echo_backend(unavailable).await;
+    let second = start_echo_backend(unavailable).await;
+    let mut service = generate_retry_service("retry-forwarded-for", &[&first, &second]);
+    let mut config = generate_config(SharedData {
+      backend_pools: service.config.shared_data.backend_pools.clone(),
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    config.trusted_proxies = TrustedProxies::new(vec!["127.0.0.0/8".parse().unwrap()]);
+    service.config = Arc::new(config);
+    let request = Request::get("/")
+      .header("host", "whoami.localhost")
+      .header("x-forwarded-for", "203.0.113.7")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then: the retry continued the chain of the CDN like the first attempt
+    assert_eq!(response.status().as_u16(), 503);
+    let mut retries = 0;
+    for backend in &[first, second] {
+      let labels = [("pool", "retry-forwarded-for"), ("backend", backend.as_str())];
+      retries += METRICS.counter("arlb_backend_retries_total", &labels);
+    }
+    assert_eq!(retries, 1);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.contains("x-forwarded-for: 203.0.113.7, 127.0.0.1\r\n"), "{}", body);
+    assert!(body.contains("x-real-ip: 203.0.113.7\r\n"), "{}", body);
+  }
+
+  #[tokio::test]
+  async fn retries_failed_connection() {
+    // given:
+    let available = start_raw_backend("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", false).await;
+    let mut service = generate_retry_service("retry-connection", &["127.0.0.1:1", &available]);
+    let request = Request::builder()
+      .method(Method::PUT)
+      .header("host", "whoami.localhost")
+      .body(Body::from("content"))
+      .unwrap();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 200);
+  }
+
+  /// Starts a backend server, which accepts connections, but never responds.
+  async fn start_hung_backend() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      let mut streams = Vec::new();
+      loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        streams.push(stream);
+      }
+    });
+    address
+  }
+
+  #[tokio::test]
+  async fn hung_backend_times_out() {
+    // given:
+    let hung = start_hung_backend().await;
+    let mut builder = generate_test_pool_builder(&[&hung]);
+    builder.name("response-timeout".into());
+    builder.response_timeout(Duration::from_millis(50));
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 504);
+    let labels = [("pool", "response-timeout"), ("backend", hung.as_str())];
+    assert_eq!(METRICS.counter("arlb_backend_response_timeouts_total", &labels), 1);
+  }
+
+  #[tokio::test]
+  async fn response_timeout_does_not_limit_caching_the_body() {
+    // given: a backend server, which sends the head at once and the body later
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut buffer = [0; 1024];
+      assert!(stream.read(&mut buffer).await.unwrap() > 0);
+      stream
+        .write_all(b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 4\r\n\r\n")
+        .await
+        .unwrap();
+      tokio::time::sleep(Duration::from_millis(150)).await;
+      stream.write_all(b"slow").await.unwrap();
+    });
+    let middlewares: toml::value::Table = toml::from_str("[Cache]").unwrap();
+    let mut builder = generate_test_pool_builder(&[&backend_address]);
+    builder.name("response-timeout-cache".into());
+    builder.chain = MiddlewareChain::try_from(middlewares).unwrap();
+    builder.response_timeout(Duration::from_millis(50));
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 200);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "slow");
+    let labels = [
+      ("pool", "response-timeout-cache"),
+      ("backend", backend_address.as_str()),
+    ];
+    assert_eq!(METRICS.counter("arlb_backend_response_timeouts_total", &labels), 0);
+  }
+
+  #[tokio::test]
+  async fn total_timeout_before_response_head_is_a_gateway_timeout() {
+    // given:
+    let hung = start_hung_backend().await;
+    let mut builder = generate_test_pool_builder(&[&hung]);
+    builder.name("total-timeout-head".into());
+    builder.total_timeout(Duration::from_millis(50));
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 504);
+    let labels = [("pool", "total-timeout-head"), ("phase", "head")];
+    assert_eq!(METRICS.counter("arlb_pool_total_timeouts_total", &labels), 1);
+  }
+
+  #[tokio::test]
+  async fn total_timeout_during_response_body_closes_the_connections() {
+    // given: a backend server, which sends the head and then trickles the body
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_address = listener.local_addr().unwrap().to_string();
+    let (closed_sender, closed) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut buffer = [0; 1024];
+      assert!(stream.read(&mut buffer).await.unwrap() > 0);
+      stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nabc")
+        .await
+        .unwrap();
+      // The load balancer closes the connection at the deadline
+      while stream.read(&mut buffer).await.unwrap() > 0 {}
+      closed_sender.send(()).unwrap();
+    });
+    let mut builder = generate_test_pool_builder(&[&backend_address]);
+    builder.name("total-timeout-body".into());
+    builder.total_timeout(Duration::from_millis(100));
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await;
+
+    // then:
+    assert!(body.is_err());
+    tokio::time::timeout(Duration::from_secs(1), closed)
+      .await
+      .unwrap()
+      .unwrap();
+    let labels = [("pool", "total-timeout-body"), ("phase", "body")];
+    assert_eq!(METRICS.counter("arlb_pool_total_timeouts_total", &labels), 1);
+  }
+
+  #[tokio::test]
+  async fn idle_timeout_aborts_a_stalled_response_body() {
+    // given: a backend server, which sends the head and part of the body and then stalls
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut buffer = [0; 1024];
+      assert!(stream.read(&mut buffer).await.unwrap() > 0);
+      stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nabc")
+        .await
+        .unwrap();
+      while stream.read(&mut buffer).await.unwrap() > 0 {}
+    });
+    let mut builder = generate_test_pool_builder(&[&backend_address]);
+    builder.name("idle-timeout".into());
+    builder.idle_timeout(Duration::from_millis(50));
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+    let body = hyper::body::to_bytes(response.into_body()).await;
+
+    // then:
+    assert!(body.is_err());
+    let labels = [("pool", "idle-timeout"), ("backend", backend_address.as_str())];
+    assert_eq!(METRICS.counter("arlb_backend_idle_timeouts_total", &labels), 1);
+  }
+
+  #[tokio::test]
+  async fn connect_timeout_is_a_bad_gateway() {
+    // given: connections are established through a proxy, which never answers
+    let hung = start_hung_backend().await;
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+    builder.upstream_proxy(format!("socks5://{}", hung).parse().unwrap());
+    builder.connect_timeout(Duration::from_millis(50));
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when:
+    let response = tokio::time::timeout(Duration::from_secs(5), service.call(whoami_request())).await;
+
+    // then:
+    assert_eq!(response.unwrap().unwrap().status().as_u16(), 502);
+  }
+
+  #[tokio::test]
+  async fn timeouts_of_priority_routes_override_the_timeouts_of_the_pool() {
+    // given: a backend server, which takes 100 ms for each response
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      while let Ok((mut stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+          let mut buffer = [0; 1024];
+          while stream.read(&mut buffer).await.unwrap_or(0) > 0 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+            if stream.write_all(response).await.is_err() {
+              break;
+            }
+          }
+        });
+      }
+    });
+    let mut builder = generate_test_pool_builder(&[&backend_address]);
+    builder.response_timeout(Duration::from_millis(50));
+    builder.priority_routes(vec![PriorityRoute {
+      name: "download".into(),
+      matcher: BackendPoolMatcher::from("PathRegexp('^/download')".to_string()),
+      priority: Priority::Low,
+      in_flight_limiter: None,
+      retry_after: None,
+      timeouts: Timeouts {
+        response: Some(Duration::from_secs(5)),
+        ..Timeouts::default()
+      },
+    }]);
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+    let request = |path: &str| {
+      Request::get(path)
+        .header("host", "whoami.localhost")
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    // when:
+    let download = service.call(request("/download")).await.unwrap();
+    let other = service.call(request("/other")).await.unwrap();
+
+    // then:
+    assert_eq!(download.status().as_u16(), 200);
+    assert_eq!(other.status().as_u16(), 504);
+  }
+
+  #[test]
+  fn timeouts_fall_back_to_the_defaults() {
+    // given:
+    let defaults = Timeouts {
+      connect: Some(Duration::from_secs(1)),
+      response: Some(Duration::from_secs(2)),
+      idle: None,
+      total: Some(Duration::from_secs(3)),
+    };
+    let overrides = Timeouts {
+      response: Some(Duration::from_secs(20)),
+      idle: Some(Duration::from_secs(10)),
+      ..Timeouts::default()
+    };
+
+    // when:
+    let actual = overrides.or(&defaults);
+
+    // then:
+    assert_eq!(
+      actual,
+      Timeouts {
+        connect: Some(Duration::from_secs(1)),
+        response: Some(Duration::from_secs(20)),
+        idle: Some(Duration::from_secs(10)),
+        total: Some(Duration::from_secs(3)),
+      }
+    );
+  }
+
+  #[tokio::test]
+  async fn response_timeout_is_retried() {
+    // given:
+    let hung = start_hung_backend().await;
+    let available = start_raw_backend("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n", false).await;
+    let mut builder = generate_test_pool_builder(&[&hung, &available]);
+    builder.response_timeout(Duration::from_millis(50));
+    builder.retry(RetryConfig {
+      max_retries: 1,
+      methods: RetryConfig::default_methods(),
+      max_body_size: 1024,
+    });
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 200);
+  }
+
+  #[tokio::test]
+  async fn does_not_retry_non_idempotent_request() {
+    // given:
+    let unavailable = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n";
+    let first = start_raw_backend(unavailable, false).await;
+    let second = start_raw_backend(unavailable, false).await;
+    let mut service = generate_retry_service("retry-post", &[&first, &second]);
+    let request = Request::builder()
+      .method(Method::POST)
+      .header("host", "whoami.localhost")
+      .body(Body::from("order"))
+      .unwrap();
+
+    // when:
+    let response = service.call(request).await.unwrap();
+
+    // then:
+    assert_eq!(response.status().as_u16(), 503);
+    for backend in &[first, second] {
+      let labels = [("pool", "retry-post"), ("backend", backend.as_str())];
+      assert_eq!(METRICS.counter("arlb_backend_retries_total", &labels), 0);
+    }
+  }
+
+  /// A gRPC like backend server, which only speaks HTTP/2 and responds with its
+  /// `name` and a `grpc-status` trailer.
+  fn start_http2_backend(name: &'static str) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let service = make_service_fn(move |_| async move {
+      Ok::<_, hyper::Error>(hyper::service::service_fn(move |request: Request<Body>| async move {
+        if request.headers().get("te") != Some(&hyper::header::HeaderValue::from_static("trailers")) {
+          return Ok::<_, hyper::Error>(Response::builder().status(400).body(Body::empty()).unwrap());
+        }
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+          sender.send_data(name.into()).await.unwrap();
+          let mut trailers = hyper::HeaderMap::new();
+          trailers.insert("grpc-status", "0".parse().unwrap());
+          sender.send_trailers(trailers).await.unwrap();
+        });
+        Ok(Response::new(body))
+      }))
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().http2_only(true).serve(service));
+    address
+  }
+
+  #[tokio::test]
+  async fn http2_requests_are_spread_across_backends_with_trailers() {
+    // given: two HTTP/2 backends
+    let mut builder = generate_test_pool_builder(&[&start_http2_backend("first"), &start_http2_backend("second")]);
+    builder.strategy = Box::new(RoundRobin::new());
+    builder.http2_only(true);
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+
+    // when: a client sends several requests like gRPC, without a host header
+    let mut backends = HashSet::new();
+    for _ in 0..4 {
+      let request = Request::builder()
+        .method(Method::POST)
+        .version(Version::HTTP_2)
+        .uri("http://whoami.localhost/helloworld.Greeter/SayHello")
+        .header("content-type", "application/grpc")
+        .header("te", "trailers")
+        .body(Body::empty())
+        .unwrap();
+      let response = service.call(request).await.unwrap();
+
+      // then: trailers are forwarded and the requests are balanced
+      assert_eq!(response.status(), 200);
+      let mut body = response.into_body();
+      let mut content = Vec::new();
+      while let Some(chunk) = body.data().await {
+        content.extend_from_slice(&chunk.unwrap());
+      }
+      let trailers = body.trailers().await.unwrap().unwrap();
+      assert_eq!(trailers["grpc-status"], "0");
+      backends.insert(String::from_utf8(content).unwrap());
+    }
+    assert_eq!(
+      backends,
+      HashSet::from_iter(vec!["first".to_string(), "second".to_string()])
+    );
+  }
+
+  /// A backend server, which responds with its `name` after a `delay`.
+  fn start_delayed_backend(name: &'static str, delay: Duration) -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let service = make_service_fn(move |_| async move {
+      Ok::<_, hyper::Error>(hyper::service::service_fn(move |_| async move {
+        tokio::time::sleep(delay).await;
+        Ok::<_, hyper::Error>(Response::new(Body::from(name)))
+      }))
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+    address
+  }
+
+  #[tokio::test]
+  async fn connections_keep_the_configuration_they_were_accepted_with() {
+    // given: a server, whose configuration routes to the old backend server
+    let old = start_delayed_backend("old", Duration::from_millis(0));
+    let new = start_delayed_backend("new", Duration::from_millis(0));
+    let config_of = |backend: &str| {
+      generate_config(SharedData {
+        backend_pools: vec![generate_test_pool(&[backend])],
+        acme_handler: Arc::new(AcmeHandler::new()),
+      })
+    };
+    let config = Arc::new(ArcSwap::from_pointee(config_of(&old)));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let uri = format!("http://{}/", listener.local_addr().unwrap());
+    let incoming = futures::stream::unfold(listener, |listener| async move {
+      Some((listener.accept().await.map(|(stream, _)| stream), listener))
+    });
+    tokio::spawn(create(
+      hyper::server::accept::from_stream(incoming),
+      config.clone(),
+      Scheme::HTTP,
+      None,
+      futures::future::pending(),
+    ));
+    let send = |client: Client<hyper::client::HttpConnector>| {
+      let request = Request::get(&uri).header(HOST, "whoami.localhost").body(Body::empty());
+      async move {
+        let response = client.request(request.unwrap()).await.unwrap();
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+      }
+    };
+    let existing = Client::new();
+    assert_eq!(send(existing.clone()).await, "old");
+
+    // when: the configuration is reloaded
+    config.store(Arc::new(config_of(&new)));
+
+    // then: only new connections are served with the new configuration
+    assert_eq!(send(existing).await, "old");
+    assert_eq!(send(Client::new()).await, "new");
+  }
+
+  #[test]
+  fn superseded_pools_are_forgotten_once_unused() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:1"]);
+    builder.name("superseded".into());
+    let pool = Arc::new(builder.build());
+
+    // when:
+    supersede_pools(std::slice::from_ref(&pool));
+    let in_use = superseded_pools("superseded").len();
+    drop(pool);
+
+    // then:
+    assert_eq!(in_use, 1);
+    assert!(superseded_pools("superseded").is_empty());
+  }
+
+  #[tokio::test]
+  async fn soft_draining_backend_only_serves_pinned_clients() {
+    // given:
+    let drained = start_delayed_backend("drained", Duration::from_millis(1));
+    let kept = start_delayed_backend("kept", Duration::from_millis(1));
+    let mut builder = generate_test_pool_builder(&[&drained, &kept]);
+    builder.name("soft-drain".into());
+    builder.strategy = Box::new(StickyCookie::new(
+      "lb".into(),
+      Box::new(RoundRobin::new()),
+      true,
+      false,
+      SameSite::Lax,
+    ));
+    let mut service = generate_test_service_with_pool(Arc::new(builder.build()));
+    backend_drains::drain(&service.config, "soft-drain", &drained, "mode=soft").unwrap();
+    let request = |cookie: Option<&str>| {
+      let mut builder = Request::builder().header("host", "whoami.localhost");
+      if let Some(cookie) = cookie {
+        builder = builder.header("cookie", cookie);
+      }
+      builder.body(Body::empty()).unwrap()
+    };
+
+    // when:
+    let mut bodies = Vec::new();
+    for cookie in &[Some(format!("lb={}", drained)), None, None] {
+      let response = service.call(request(cookie.as_deref())).await.unwrap();
+      bodies.push(hyper::body::to_bytes(response.into_body()).await.unwrap());
+    }
+    backend_drains::drain(&service.config, "soft-drain", &drained, "mode=hard").unwrap();
+    let after_hard_drain = service.call(request(Some(&format!("lb={}", drained)))).await.unwrap();
+
+    // then:
+    assert_eq!(bodies, vec!["drained", "kept", "kept"]);
+    let body = hyper::body::to_bytes(after_hard_drain.into_body()).await.unwrap();
+    assert_eq!(body, "kept");
+    backend_drains::undrain("soft-drain", &drained).unwrap();
+  }
+
+  #[tokio::test]
+  async fn idle_connections_are_closed_after_the_pool_idle_timeout() {
+    // given:
+    let backend = start_delayed_backend("idle", Duration::from_millis(1));
+    let mut builder = generate_test_pool_builder(&[&backend]);
+    builder.name("idle-timeout".into());
+    builder.pool_idle_timeout(Duration::from_millis(100));
+    let pool = Arc::new(builder.build());
+    let mut service = generate_test_service_with_pool(pool.clone());
+    let idle = || {
+      METRICS.gauge(
+        "arlb_backend_idle_connections",
+        &[("pool", "idle-timeout"), ("backend", backend.as_str())],
+      )
+    };
+
+    // when:
+    let response = service.call(whoami_request()).await.unwrap();
+    let in_flight = idle();
+    hyper::body::to_bytes(response.into_body()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let pooled = (pool.connections.get(&backend), idle());
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    // then:
+    assert_eq!(in_flight, 0);
+    assert_eq!(pooled, (1, 1));
+    assert_eq!(pool.connections.get(&backend), 0);
+    assert_eq!(idle(), 0);
+  }
+
+  #[tokio::test]
+  async fn peak_ewma_sends_fewer_requests_to_slow_backend() {
+    // given:
+    let slow = start_delayed_backend("slow", Duration::from_millis(50));
+    let fast = start_delayed_backend("fast", Duration::from_millis(1));
+    let mut builder = generate_test_pool_builder(&[&slow, &fast]);
+    builder.strategy = Box::new(PeakEwma::new(Duration::from_secs(10)));
+    let pool = Arc::new(builder.build());
+    let mut service = generate_test_service_with_pool(pool.clone());
+
+    // when:
+    let mut slow_responses = 0;
+    for _ in 0..20 {
+      let response = service.call(whoami_request()).await.unwrap();
+      let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+      if body == "slow" {
+        slow_responses += 1;
+      }
+    }
+
+    // then:
+    assert!(slow_responses <= 3, "{}", slow_responses);
+    assert!(pool.strategy.backend_latency(&slow).unwrap() > pool.strategy.backend_latency(&fast).unwrap());
+    let labels = [("backend", slow.as_str())];
+    assert!(METRICS.gauge("arlb_backend_latency_ewma_milliseconds", &labels) >= 50);
+  }
+
+  /// A backend server, which records the paths of the requests it receives.
+  fn start_recording_backend() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let paths = Arc::new(Mutex::new(Vec::new()));
+    let recorded = paths.clone();
+    let service = make_service_fn(move |_| {
+      let paths = paths.clone();
+      async move {
+        Ok::<_, hyper::Error>(hyper::service::service_fn(move |request: Request<Body>| {
+          paths.lock().unwrap().push(request.uri().path().to_string());
+          async move {
+            hyper::body::to_bytes(request.into_body()).await?;
+            Ok::<_, hyper::Error>(Response::new(Body::from("ok")))
+          }
+        }))
+      }
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+    (address, recorded)
+  }
+
+  /// Sends the raw `payload` on a new connection and returns the status of the
+  /// first response and whether the connection was closed afterwards.
+  async fn send_raw_request(pool: Arc<BackendPool>, payload: &[u8]) -> (Option<u16>, bool) {
+    let (response, closed) = exchange_raw(pool, payload).await;
+    let status = response
+      .strip_prefix("HTTP/1.1 ")
+      .and_then(|it| it.get(..3))
+      .and_then(|it| it.parse().ok());
+    (status, closed)
+  }
+
+  /// Sends the raw `payload` on a new connection and returns the raw responses
+  /// and whether the connection was closed afterwards.
+  async fn exchange_raw(pool: Arc<BackendPool>, payload: &[u8]) -> (String, bool) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (socket, _) = listener.accept().await.unwrap();
+      let service = generate_test_service_with_pool(pool);
+      let _ = hyper::server::conn::Http::new().serve_connection(socket, service).await;
+    });
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+    client.write_all(payload).await.unwrap();
+
+    let mut response = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_millis(500), client.read_to_end(&mut response))
+      .await
+      .is_ok();
+    (String::from_utf8_lossy(&response).into_owned(), closed)
+  }
+
+  #[tokio::test]
+  async fn close_delimited_responses_are_reframed_for_the_client() {
+    // given: a legacy backend server, which ends the body by closing the connection
+    let backend = start_raw_backend("HTTP/1.0 200 OK\r\nConnection: close\r\n\r\nlegacy body", false).await;
+    let backend_10 = start_raw_backend("HTTP/1.0 200 OK\r\n\r\nlegacy body", false).await;
+
+    // when:
+    let keep_alive = "GET / HTTP/1.1\r\nHost: whoami.localhost\r\n\r\n";
+    let (response, closed) = exchange_raw(generate_test_pool(&[&backend]), keep_alive.as_bytes()).await;
+    let http_10 = "GET / HTTP/1.0\r\nHost: whoami.localhost\r\n\r\n";
+    let (response_10, closed_10) = exchange_raw(generate_test_pool(&[&backend_10]), http_10.as_bytes()).await;
+
+    // then: the whole body arrives, chunked on the kept alive connection and
+    // delimited by close for the HTTP/1.0 client
+    let response = response.to_ascii_lowercase();
+    assert!(response.starts_with("http/1.1 200 ok"), "{}", response);
+    assert!(response.contains("transfer-encoding: chunked"), "{}", response);
+    assert!(!response.contains("connection: close"), "{}", response);
+    assert!(response.ends_with("b\r\nlegacy body\r\n0\r\n\r\n"), "{}", response);
+    assert!(!closed);
+    assert!(response_10.starts_with("HTTP/1.0 200 OK"), "{}", response_10);
+    assert!(!response_10.to_ascii_lowercase().contains("transfer-encoding"));
+    assert!(response_10.ends_with("\r\n\r\nlegacy body"), "{}", response_10);
+    assert!(closed_10);
+  }
+
+  #[tokio::test]
+  async fn stray_bodies_of_no_content_responses_are_dropped() {
+    // given:
+    let backend = start_raw_backend("HTTP/1.1 204 No Content\r\nContent-Length: 5\r\n\r\nstray", false).await;
+
+    // when:
+    let request = "DELETE /item HTTP/1.1\r\nHost: whoami.localhost\r\n\r\n";
+    let (response, closed) = exchange_raw(generate_test_pool(&[&backend]), request.as_bytes()).await;
+
+    // then: hyper announces the empty body with a content-length of its own
+    assert!(response.starts_with("HTTP/1.1 204 No Content"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    assert!(!response.contains("stray"), "{}", response);
+    assert!(!response.contains("content-length: 5"), "{}", response);
+    assert!(!closed);
+  }
+
+  #[tokio::test]
+  async fn clients_waiting_for_100_continue_receive_it_before_they_send_the_body() {
+    // given:
+    let pool = Arc::new(generate_test_pool_builder(&["builtin:echo"]).build());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (socket, _) = listener.accept().await.unwrap();
+      let service = generate_test_service_with_pool(pool);
+      let _ = hyper::server::conn::Http::new().serve_connection(socket, service).await;
+    });
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+
+    // when: the client only sends the body once it was told to continue
+    let head = "PUT /upload HTTP/1.1\r\nHost: whoami.localhost\r\nContent-Length: 6\r\nExpect: 100-continue\r\n\r\n";
+    client.write_all(head.as_bytes()).await.unwrap();
+    let mut interim = Vec::new();
+    while !interim.ends_with(b"\r\n\r\n") {
+      let mut byte = [0; 1];
+      tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut byte))
+        .await
+        .expect("No 100 Continue")
+        .unwrap();
+      interim.push(byte[0]);
+    }
+    client.write_all(b"upload").await.unwrap();
+    let mut response = [0; 1024];
+    let read = tokio::time::timeout(Duration::from_secs(1), client.read(&mut response))
+      .await
+      .unwrap()
+      .unwrap();
+
+    // then: the expectation is forwarded along with the body
+    assert_eq!(String::from_utf8_lossy(&interim), "HTTP/1.1 100 Continue\r\n\r\n");
+    let response = String::from_utf8_lossy(&response[..read]);
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("expect: 100-continue\r\n"), "{}", response);
+    assert!(response.ends_with("\r\n\r\nupload"), "{}", response);
+  }
+
+  #[tokio::test]
+  async fn local_responses_do_not_invite_the_body() {
+    // given:
+    let mut builder = generate_test_pool_builder(&["builtin:echo"]);
+    builder.maintenance(true);
+    let pool = Arc::new(builder.build());
+
+    // when: the client waits for 100 Continue, which does not come
+    let head = "PUT /upload HTTP/1.1\r\nHost: whoami.localhost\r\nContent-Length: 6\r\nExpect: 100-continue\r\n\r\n";
+    let (response, closed) = exchange_raw(pool, head.as_bytes()).await;
+
+    // then: the unsent body would precede the next request, so the connection is closed
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"), "{}", response);
+    assert!(response.contains("connection: close"), "{}", response);
+    assert!(response.ends_with("\r\n\r\n503 - Service Unavailable"), "{}", response);
+    assert!(closed);
+  }
+
+  #[tokio::test]
+  async fn responses_to_head_requests_keep_their_content_length_without_a_body() {
+    // given:
+    let backend = start_raw_backend("HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n", false).await;
+
+    // when:
+    let request = "HEAD /file HTTP/1.1\r\nHost: whoami.localhost\r\n\r\n";
+    let (response, closed) = exchange_raw(generate_test_pool(&[&backend]), request.as_bytes()).await;
+
+    // then:
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(
+      response.to_ascii_lowercase().contains("content-length: 1234"),
+      "{}",
+      response
+    );
+    assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    assert!(!closed);
+  }
+
+  #[tokio::test]
+  async fn keep_alive_connection_serves_requests_of_different_backends_until_close() {
+    // given:
+    let mut builder = generate_test_pool_builder(&[
+      &start_delayed_backend("first", Duration::from_millis(0)),
+      &start_delayed_backend("second", Duration::from_millis(0)),
+    ]);
+    builder.strategy = Box::new(RoundRobin::new());
+    let pool = Arc::new(builder.build());
+    let request = "GET / HTTP/1.1\r\nHost: whoami.localhost\r\n\r\n";
+    let closing_request = "GET / HTTP/1.1\r\nHost: whoami.localhost\r\nConnection: close\r\n\r\n";
+
+    // when: two requests are pipelined on one connection, the last one closes it
+    let payload = format!("{}{}", request, closing_request);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (socket, _) = listener.accept().await.unwrap();
+      let service = generate_test_service_with_pool(pool);
+      let _ = hyper::server::conn::Http::new().serve_connection(socket, service).await;
+    });
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+    client.write_all(payload.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    let closed = tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut response))
+      .await
+      .is_ok();
+
+    // then: each request is balanced on its own and the connection is closed after the second response
+    let response = String::from_utf8_lossy(&response);
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2, "{}", response);
+    assert!(
+      response.contains("first") && response.contains("second"),
+      "{}",
+      response
+    );
+    assert!(closed);
+  }
+
+  #[tokio::test]
+  async fn events_follow_a_proxied_request_from_accept_to_close() {
+    // given:
+    let _exclusive = exclusive().await;
+    let backend = start_delayed_backend("backend", Duration::from_millis(0));
+    let pool = Arc::new(generate_test_pool_builder(&[&backend]).build());
+    let mut events = EVENTS.subscribe();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      let (socket, peer) = listener.accept().await.unwrap();
+      let service = Observed::new(generate_test_service_with_pool(pool), Scheme::HTTP, peer, None, None);
+      let _ = hyper::server::conn::Http::new().serve_connection(socket, service).await;
+    });
+
+    // when:
+    let mut client = tokio::net::TcpStream::connect(address).await.unwrap();
+    let peer = client.local_addr().unwrap();
+    let request = "GET /path HTTP/1.1\r\nHost: whoami.localhost\r\nConnection: close\r\n\r\n";
+    client.write_all(request.as_bytes()).await.unwrap();
+    client.read_to_end(&mut Vec::new()).await.unwrap();
+
+    // then: other tests emit events concurrently, so only those of this connection are checked
+    let connection = loop {
+      if let Event::ConnectionAccepted {
+        connection,
+        peer: accepted,
+        ..
+      } = events.recv().await
+      {
+        if accepted == peer {
+          break connection;
+        }
+      }
+    };
+    let mut received = Vec::new();
+    while received.len() < 3 {
+      let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+        .await
+        .unwrap();
+      match &event {
+        Event::BackendSelected { connection: id, .. } | Event::ConnectionClosed { connection: id, .. }
+          if *id == connection =>
+        {
+          received.push(event)
+        }
+        Event::RequestCompleted { summary } if summary.connection == connection => received.push(event),
+        _ => {}
+      }
+    }
+    assert!(
+      matches!(&received[0], Event::BackendSelected { backend: selected, strategy: "Random", .. }
+        if *selected == backend),
+      "{:?}",
+      received
+    );
+    assert!(
+      matches!(&received[1], Event::RequestCompleted { summary }
+        if summary.uri == "/path" && summary.status == hyper::StatusCode::OK && summary.backend.is_some()),
+      "{:?}",
+      received
+    );
+    assert!(
+      matches!(
+        &received[2],
+        Event::ConnectionClosed { reason: CloseReason::Closed, stats, .. } if stats.requests == 1
+      ),
+      "{:?}",
+      received
+    );
+  }
+
+  #[tokio::test]
+  async fn request_smuggling_payloads_are_rejected() {
+    // given:
+    let (backend, paths) = start_recording_backend();
+    let pool = generate_test_pool(&[&backend]);
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: whoami.localhost\r\n\r\n";
+    let chunk = format!("{:x}\r\n{}\r\n0\r\n\r\n", smuggled.len(), smuggled);
+    let head = "POST / HTTP/1.1\r\nHost: whoami.localhost\r\n";
+    let cases = vec![
+      (
+        "CL.TE",
+        format!(
+          "{}Content-Length: {}\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n{}",
+          head,
+          5 + smuggled.len(),
+          smuggled
+        ),
+        Some(400),
+      ),
+      // The Content-Length is dropped, so the smuggled request is sent as chunked body
+      (
+        "TE.CL",
+        format!(
+          "{}Transfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n{}",
+          head, chunk
+        ),
+        Some(200),
+      ),
+      (
+        "TE.TE with an obfuscated last encoding",
+        format!(
+          "{}Transfer-Encoding: chunked\r\nTransfer-Encoding: xchunked\r\n\r\n{}",
+          head, chunk
+        ),
+        Some(400),
+      ),
+      (
+        "TE.TE with an obfuscated first encoding",
+        format!(
+          "{}Transfer-Encoding: xchunked\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+          head, chunk
+        ),
+        Some(400),
+      ),
+      (
+        "TE.TE with a space before the colon",
+        format!("{}Transfer-Encoding : chunked\r\n\r\n{}", head, chunk),
+        Some(400),
+      ),
+      (
+        "conflicting Content-Length",
+        format!("{}Content-Length: 0\r\nContent-Length: 5\r\n\r\n{}", head, smuggled),
+        Some(400),
+      ),
+      (
+        "signed Content-Length",
+        format!("{}Content-Length: +0\r\n\r\n{}", head, smuggled),
+        Some(400),
+      ),
+      (
+        "obs-folded header",
+        format!("{}X-Folded: a\r\n Transfer-Encoding: chunked\r\n\r\n{}", head, chunk),
+        Some(400),
+      ),
+      (
+        "bare CR",
+        format!("{}X-Foo: a\rTransfer-Encoding: chunked\r\n\r\n{}", head, chunk),
+        Some(400),
+      ),
+      (
+        "NUL",
+        format!("{}X-Foo: a\0b\r\nContent-Length: 0\r\n\r\n{}", head, smuggled),
+        Some(400),
+      ),
+      (
+        "invalid chunk size",
+        format!("{}Transfer-Encoding: chunked\r\n\r\nzz\r\n{}", head, smuggled),
+        Some(400),
+      ),
+      (
+        "conflicting Host",
+        format!("{}Host: evil.localhost\r\nContent-Length: 0\r\n\r\n", head),
+        Some(400),
+      ),
+      (
+        "absolute-form",
+        "GET http://whoami.localhost/ HTTP/1.1\r\nHost: whoami.localhost\r\n\r\n".to_string(),
+        Some(400),
+      ),
+    ];
+
+    for (name, payload, expected_status) in cases {
+      // when:
+      let (status, closed) = send_raw_request(pool.clone(), payload.as_bytes()).await;
+
+      // then:
+      if expected_status == Some(200) {
+        assert_eq!(status, Some(200), "{}", name);
+      } else {
+        assert_ne!(status, Some(200), "{}", name);
+        assert!(closed, "{}", name);
+      }
+      if expected_status == Some(400) {
+        assert_eq!(status, Some(400), "{}", name);
+      }
+      assert!(!paths.lock().unwrap().contains(&"/smuggled".to_string()), "{}", name);
+    }
+    let rejected = |reason| METRICS.counter("arlb_rejected_requests_total", &[("reason", reason)]);
+    assert!(rejected("content_length_with_transfer_encoding") >= 1);
+    assert!(rejected("invalid_transfer_encoding") >= 1);
+    assert!(rejected("absolute_form") >= 1);
+    assert!(rejected("invalid_chunked_body") >= 1);
+  }
+
+  #[tokio::test]
+  async fn chunked_request_bodies_are_decoded_and_framed_anew() {
+    // given: a backend server, which echoes the headers and the body
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend = listener.local_addr().unwrap().to_string();
+    let service = make_service_fn(|_| async {
+      Ok::<_, hyper::Error>(hyper::service::service_fn(|request: Request<Body>| async move {
+        let framing = request
+          .headers()
+          .get(hyper::header::TRANSFER_ENCODING)
+          .or_else(|| request.headers().get(hyper::header::CONTENT_LENGTH))
+          .map(|it| it.to_str().unwrap().to_string())
+          .unwrap_or_default();
+        let body = hyper::body::to_bytes(request.into_body()).await?;
+        Ok::<_, hyper::Error>(Response::new(Body::from(format!(
+          "{}:{}",
+          framing,
+          String::from_utf8_lossy(&body)
+        ))))
+      }))
+    });
+    tokio::spawn(Server::from_tcp(listener).unwrap().serve(service));
+    let pool = generate_test_pool(&[&backend]);
+
+    // when: chunks with extensions and trailers
+    let (response, _) = exchange_raw(
+      pool,
+      b"POST / HTTP/1.1\r\nHost: whoami.localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+        6;name=value\r\nhello \r\n5\r\nworld\r\n0\r\nX-Checksum: 1234\r\n\r\n",
+    )
+    .await;
+
+    // then: the backend server receives the decoded body, without the trailers
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.ends_with(":hello world"), "{}", response);
+  }
+
+  #[tokio::test]
+  async fn traffic_shifts_to_registered_backends_and_away_from_deregistered_ones() {
+    // given: a pool with one configured backend server, whose new backend servers start without a slow start
+    let (configured, configured_paths) = start_recording_backend();
+    let (dynamic, dynamic_paths) = start_recording_backend();
+    let path = crate::dynamic_backends::tests::write_config("dynamic-traffic", &[&configured]);
+    let mut toml = std::fs::read_to_string(&path).unwrap();
+    toml.push_str("[backend_pools.dynamic_backends]\nslow_start_sec = 0\n");
+    std::fs::write(&path, toml).unwrap();
+    let config = crate::configuration::read_initial_config(&path).await.unwrap();
+    let admin = |method: Method, path: &str, body: String| {
+      let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(hyper::header::AUTHORIZATION, "Bearer s3cr3t")
+        .body(Body::from(body))
+        .unwrap();
+      crate::metrics::handle_request(request, config.clone())
+    };
+    // each request is sent on a new connection, which sees the current configuration
+    let send = |path: &'static str| {
+      let request = Request::get(path)
+        .header(HOST, "dynamic-traffic.localhost")
+        .body(Body::empty())
+        .unwrap();
+      let mut service = MainService {
+        scheme: Scheme::HTTP,
+        client_address: "127.0.0.1:3000".parse().unwrap(),
+        tls_info: None,
+        peer_credentials: None,
+        geo_info: None,
+        listener: None,
+        span: None,
+        config: config.load_full(),
+      };
+      service.call(request)
+    };
+
+    // when: a backend server is registered
+    let body = format!(r#"{{"address": "{}"}}"#, dynamic);
+    let registered = admin(Method::PUT, "/pools/dynamic-traffic/backends/b", body)
+      .await
+      .unwrap();
+    for _ in 0..4 {
+      assert_eq!(send("/registered").await.unwrap().status(), hyper::StatusCode::OK);
+    }
+
+    // then: it receives its share of the requests
+    assert_eq!(registered.status(), hyper::StatusCode::CREATED);
+    assert_eq!(*configured_paths.lock().unwrap(), vec!["/registered"; 2]);
+    assert_eq!(*dynamic_paths.lock().unwrap(), vec!["/registered"; 2]);
+
+    // when: it is deregistered
+    let deregistered = admin(Method::DELETE, "/pools/dynamic-traffic/backends/b", String::new())
+      .await
+      .unwrap();
+    for _ in 0..2 {
+      assert_eq!(send("/deregistered").await.unwrap().status(), hyper::StatusCode::OK);
+    }
+    std::fs::remove_file(&path).unwrap();
+
+    // then: it does not receive new requests
+    assert_eq!(deregistered.status(), hyper::StatusCode::NO_CONTENT);
+    assert_eq!(configured_paths.lock().unwrap()[2..], ["/deregistered"; 2]);
+    assert_eq!(dynamic_paths.lock().unwrap().len(), 2);
+  }
+
+  #[tokio::test]
+  async fn requests_are_routed_to_the_pool_matching_host_and_path() {
+    // given:
+    let backends = MockBackend::http_fleet(3).await.unwrap();
+    let proxy = TestProxy::start(&format!(
+      r#"
+      [[backend_pools]]
+      matcher = "Host('shop.localhost') && Path('/admin')"
+      addresses = ["{}"]
+      schemes = ["HTTP"]
+      strategy = {{ RoundRobin = {{}} }}
+
+      [[backend_pools]]
+      matcher = "Host('shop.localhost')"
+      addresses = ["{}"]
+      schemes = ["HTTP"]
+      strategy = {{ RoundRobin = {{}} }}
+
+      [[backend_pools]]
+      matcher = "Host('blog.localhost')"
+      addresses = ["{}"]
+      schemes = ["HTTP"]
+      strategy = {{ RoundRobin = {{}} }}
+      "#,
+      backends[0].address(),
+      backends[1].address(),
+      backends[2].address()
+    ))
+    .await
+    .unwrap();
+    let mut connection = TestConnection::plain(proxy.http_address()).await.unwrap();
+
+    // when:
+    let admin = connection.distribution("shop.localhost", "/admin", 2).await.unwrap();
+    let shop = connection.distribution("shop.localhost", "/cart", 2).await.unwrap();
+    let blog = connection.distribution("blog.localhost", "/admin", 2).await.unwrap();
+    let unknown = connection.distribution("other.localhost", "/", 2).await.unwrap();
+
+    // then:
+    let only = |body: &str| vec![(body.to_string(), 2)].into_iter().collect();
+    assert_eq!(admin, only("backend-0"));
+    assert_eq!(shop, only("backend-1"));
+    assert_eq!(blog, only("backend-2"));
+    assert_eq!(unknown, only("404"));
+    proxy.stop().await.unwrap();
+  }
 }
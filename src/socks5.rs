@@ -0,0 +1,274 @@
+use crate::upstream_proxy::{ProxyError, Target, UpstreamProxy};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const IPV4: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+/// Authenticates with the proxy on the already established `stream` and asks
+/// it to connect to the `target`. Afterwards the stream is relayed to the
+/// target by the proxy.
+pub async fn handshake<S>(stream: &mut S, proxy: &UpstreamProxy, target: &Target) -> Result<(), ProxyError>
+where
+  S: AsyncRead + AsyncWrite + Unpin,
+{
+  let method = match proxy.credentials() {
+    Some(_) => USERNAME_PASSWORD,
+    None => NO_AUTHENTICATION,
+  };
+  stream.write_all(&[VERSION, 1, method]).await?;
+  let mut selected = [0; 2];
+  stream.read_exact(&mut selected).await?;
+  if selected[0] != VERSION {
+    return Err(ProxyError::Handshake(format!("unsupported version {}", selected[0])));
+  }
+  if selected[1] != method {
+    return Err(ProxyError::Handshake(match selected[1] {
+      NO_ACCEPTABLE_METHODS if method == NO_AUTHENTICATION => "the proxy requires credentials".into(),
+      NO_ACCEPTABLE_METHODS => "the proxy does not accept credentials".into(),
+      other => format!("the proxy selected the unoffered method {}", other),
+    }));
+  }
+  if let Some((username, password)) = proxy.credentials() {
+    let mut request = vec![0x01, username.len() as u8];
+    request.extend_from_slice(username.as_bytes());
+    request.push(password.len() as u8);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await?;
+    let mut status = [0; 2];
+    stream.read_exact(&mut status).await?;
+    if status[1] != 0 {
+      return Err(ProxyError::Handshake("the credentials were rejected".into()));
+    }
+  }
+
+  let mut request = vec![VERSION, CONNECT, 0];
+  let port = match target {
+    Target::Address(SocketAddr::V4(address)) => {
+      request.push(IPV4);
+      request.extend_from_slice(&address.ip().octets());
+      address.port()
+    }
+    Target::Address(SocketAddr::V6(address)) => {
+      request.push(IPV6);
+      request.extend_from_slice(&address.ip().octets());
+      address.port()
+    }
+    Target::Domain(host, port) => {
+      if host.len() > 255 {
+        return Err(ProxyError::Handshake(format!("the hostname '{}' is too long", host)));
+      }
+      request.push(DOMAIN_NAME);
+      request.push(host.len() as u8);
+      request.extend_from_slice(host.as_bytes());
+      *port
+    }
+  };
+  request.extend_from_slice(&port.to_be_bytes());
+  stream.write_all(&request).await?;
+
+  let mut reply = [0; 4];
+  stream.read_exact(&mut reply).await?;
+  if reply[0] != VERSION {
+    return Err(ProxyError::Handshake(format!("unsupported version {}", reply[0])));
+  }
+  if reply[1] != 0 {
+    return Err(ProxyError::Refused(reply[1]));
+  }
+  // The address the proxy bound is not needed, but has to be consumed
+  let len = match reply[3] {
+    IPV4 => 4,
+    IPV6 => 16,
+    DOMAIN_NAME => stream.read_u8().await? as usize,
+    other => return Err(ProxyError::Handshake(format!("unsupported address type {}", other))),
+  };
+  let mut bound = vec![0; len + 2];
+  stream.read_exact(&mut bound).await?;
+  Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use std::{
+    io,
+    sync::{Arc, Mutex},
+  };
+  use tokio::net::{TcpListener, TcpStream};
+
+  /// Starts a SOCKS5 proxy, which connects to `127.0.0.1` for any hostname.
+  /// Returns its address and the targets it was asked to connect to.
+  pub async fn start_proxy(credentials: Option<(&'static str, &'static str)>) -> (SocketAddr, Arc<Mutex<Vec<Target>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let targets = Arc::new(Mutex::new(Vec::new()));
+    let targets_clone = targets.clone();
+    tokio::spawn(async move {
+      loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        let targets = targets_clone.clone();
+        tokio::spawn(async move {
+          let _ = serve(stream, credentials, targets).await;
+        });
+      }
+    });
+    (address, targets)
+  }
+
+  async fn serve(
+    mut client: TcpStream,
+    credentials: Option<(&str, &str)>,
+    targets: Arc<Mutex<Vec<Target>>>,
+  ) -> io::Result<()> {
+    let mut greeting = [0; 2];
+    client.read_exact(&mut greeting).await?;
+    let mut methods = vec![0; greeting[1] as usize];
+    client.read_exact(&mut methods).await?;
+    let method = match credentials {
+      Some(_) => USERNAME_PASSWORD,
+      None => NO_AUTHENTICATION,
+    };
+    if !methods.contains(&method) {
+      return client.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await;
+    }
+    client.write_all(&[VERSION, method]).await?;
+    if let Some((username, password)) = credentials {
+      let mut version_len = [0; 2];
+      client.read_exact(&mut version_len).await?;
+      let mut given_username = vec![0; version_len[1] as usize];
+      client.read_exact(&mut given_username).await?;
+      let mut given_password = vec![0; client.read_u8().await? as usize];
+      client.read_exact(&mut given_password).await?;
+      let accepted = given_username == username.as_bytes() && given_password == password.as_bytes();
+      client.write_all(&[0x01, if accepted { 0 } else { 1 }]).await?;
+      if !accepted {
+        return Ok(());
+      }
+    }
+    let mut request = [0; 4];
+    client.read_exact(&mut request).await?;
+    let target = match request[3] {
+      IPV4 => {
+        let mut ip = [0; 4];
+        client.read_exact(&mut ip).await?;
+        Target::Address(SocketAddr::from((ip, client.read_u16().await?)))
+      }
+      IPV6 => {
+        let mut ip = [0; 16];
+        client.read_exact(&mut ip).await?;
+        Target::Address(SocketAddr::from((ip, client.read_u16().await?)))
+      }
+      _ => {
+        let mut host = vec![0; client.read_u8().await? as usize];
+        client.read_exact(&mut host).await?;
+        Target::Domain(String::from_utf8(host).unwrap(), client.read_u16().await?)
+      }
+    };
+    targets.lock().unwrap().push(target.clone());
+    let connect_to = match target {
+      Target::Address(address) => address,
+      Target::Domain(_, port) => SocketAddr::from(([127, 0, 0, 1], port)),
+    };
+    match TcpStream::connect(connect_to).await {
+      Ok(mut backend) => {
+        client.write_all(&[VERSION, 0, 0, IPV4, 127, 0, 0, 1, 0, 0]).await?;
+        let (mut client_read, mut client_write) = client.split();
+        let (mut backend_read, mut backend_write) = backend.split();
+        futures::future::try_join(
+          tokio::io::copy(&mut client_read, &mut backend_write),
+          tokio::io::copy(&mut backend_read, &mut client_write),
+        )
+        .await?;
+        Ok(())
+      }
+      Err(_) => client.write_all(&[VERSION, 0x05, 0, IPV4, 0, 0, 0, 0, 0, 0]).await,
+    }
+  }
+
+  /// A backend server, which answers `ping` with `pong`.
+  async fn start_backend() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      loop {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+          let mut ping = [0; 4];
+          stream.read_exact(&mut ping).await.unwrap();
+          stream.write_all(b"pong").await.unwrap();
+        });
+      }
+    });
+    address
+  }
+
+  async fn ping_through(proxy: &str, proxy_address: SocketAddr, target: &Target) -> Result<[u8; 4], ProxyError> {
+    let proxy = proxy.replace("{}", &proxy_address.to_string()).parse().unwrap();
+    let mut stream = TcpStream::connect(proxy_address).await.unwrap();
+    handshake(&mut stream, &proxy, target).await?;
+    stream.write_all(b"ping").await.unwrap();
+    let mut pong = [0; 4];
+    stream.read_exact(&mut pong).await.unwrap();
+    Ok(pong)
+  }
+
+  #[tokio::test]
+  async fn test_handshake_without_authentication() {
+    // given:
+    let (proxy_address, targets) = start_proxy(None).await;
+    let backend_address = start_backend().await;
+
+    // when:
+    let address = ping_through("socks5://{}", proxy_address, &Target::Address(backend_address)).await;
+    let domain = Target::Domain("backend.internal".into(), backend_address.port());
+    let hostname = ping_through("socks5h://{}", proxy_address, &domain).await;
+
+    // then:
+    assert_eq!(&address.unwrap(), b"pong");
+    assert_eq!(&hostname.unwrap(), b"pong");
+    assert_eq!(*targets.lock().unwrap(), vec![Target::Address(backend_address), domain]);
+  }
+
+  #[tokio::test]
+  async fn test_handshake_with_password_authentication() {
+    // given:
+    let (proxy_address, _) = start_proxy(Some(("user", "secret"))).await;
+    let target = Target::Address(start_backend().await);
+
+    // when:
+    let accepted = ping_through("socks5://user:secret@{}", proxy_address, &target).await;
+    let rejected = ping_through("socks5://user:wrong@{}", proxy_address, &target).await;
+    let missing = ping_through("socks5://{}", proxy_address, &target).await;
+
+    // then:
+    assert_eq!(&accepted.unwrap(), b"pong");
+    assert_eq!(rejected.unwrap_err().reason(), "handshake");
+    assert_eq!(missing.unwrap_err().reason(), "handshake");
+  }
+
+  #[tokio::test]
+  async fn test_handshake_fails_if_the_proxy_can_not_connect() {
+    // given: a port, on which nothing listens
+    let (proxy_address, _) = start_proxy(None).await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = Target::Address(listener.local_addr().unwrap());
+    drop(listener);
+
+    // when:
+    let result = ping_through("socks5://{}", proxy_address, &target).await;
+
+    // then:
+    let error = result.unwrap_err();
+    assert_eq!(error.reason(), "refused");
+    assert_eq!(
+      error.to_string(),
+      "The upstream proxy could not connect: connection refused"
+    );
+  }
+}
@@ -0,0 +1,445 @@
+use crate::{
+  configuration::RuntimeConfig, dynamic_backends, health::Healthiness, outlier_detection::Ejection, server::BackendPool,
+};
+use arc_swap::ArcSwap;
+use hyper::StatusCode;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+  fs, io,
+  path::PathBuf,
+  sync::Arc,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Incremented on incompatible changes of the format. Compatible additions
+/// must have a default value, so older files can still be read.
+const VERSION: u32 = 1;
+
+/// Where the state of backend servers is kept across restarts, so a restart
+/// does not send requests to backend servers, which were known to be
+/// unhealthy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateFileConfig {
+  pub path: PathBuf,
+  /// How often the state is written.
+  pub interval: Duration,
+  /// Older files are ignored on startup.
+  pub max_age: Duration,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+  version: u32,
+  /// Milliseconds since the unix epoch.
+  saved_at: u64,
+  #[serde(default)]
+  backend_pools: Vec<PoolState>,
+  /// The backend servers registered through the admin API by pool name and id.
+  #[serde(default)]
+  dynamic_backends: dynamic_backends::Registry,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PoolState {
+  name: String,
+  #[serde(default)]
+  backends: Vec<BackendState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackendState {
+  address: String,
+  #[serde(default)]
+  healthiness: Option<HealthinessState>,
+  /// Milliseconds since the unix epoch, so the ejection time elapses while
+  /// the load balancer is not running.
+  #[serde(default)]
+  ejected_until: Option<u64>,
+  #[serde(default)]
+  ejections: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum HealthinessState {
+  Healthy,
+  Slow(i64),
+  Unresponsive(Option<u16>),
+}
+
+impl From<&Healthiness> for HealthinessState {
+  fn from(other: &Healthiness) -> Self {
+    match other {
+      Healthiness::Healthy => HealthinessState::Healthy,
+      Healthiness::Slow(response_time) => HealthinessState::Slow(*response_time),
+      Healthiness::Unresponsive(status) => HealthinessState::Unresponsive(status.map(|it| it.as_u16())),
+    }
+  }
+}
+
+impl From<HealthinessState> for Healthiness {
+  fn from(other: HealthinessState) -> Self {
+    match other {
+      HealthinessState::Healthy => Healthiness::Healthy,
+      HealthinessState::Slow(response_time) => Healthiness::Slow(response_time),
+      HealthinessState::Unresponsive(status) => {
+        Healthiness::Unresponsive(status.and_then(|it| StatusCode::from_u16(it).ok()))
+      }
+    }
+  }
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Periodically writes the state of the backend servers to the `state_file`
+/// of the current configuration.
+pub async fn persist(config: Arc<ArcSwap<RuntimeConfig>>) {
+  loop {
+    let interval = match &config.load().state_file {
+      Some(state_file) => state_file.interval,
+      None => Duration::from_secs(5),
+    };
+    tokio::time::sleep(interval).await;
+    if let Err(e) = save(&config.load()) {
+      warn!("Could not write the state file due to: {}", e);
+    }
+  }
+}
+
+/// Writes the health and outlier ejections of all backend servers and the
+/// dynamic backend servers to the `state_file`, if there is one.
+pub fn save(config: &RuntimeConfig) -> Result<(), io::Error> {
+  let state_file = match &config.state_file {
+    Some(state_file) => state_file,
+    None => return Ok(()),
+  };
+  let now = (Instant::now(), SystemTime::now());
+  let state = State {
+    version: VERSION,
+    saved_at: millis_since_epoch(now.1),
+    backend_pools: config
+      .shared_data
+      .backend_pools
+      .iter()
+      .map(|pool| pool_state(pool, now))
+      .collect(),
+    dynamic_backends: dynamic_backends::all(),
+  };
+  let json = serde_json::to_vec_pretty(&state).map_err(io::Error::from)?;
+  // Write to a temporary file first, so a crash can not leave a partial file behind
+  let temporary_path = state_file.path.with_extension("tmp");
+  fs::write(&temporary_path, json)?;
+  fs::rename(&temporary_path, &state_file.path)
+}
+
+fn pool_state(pool: &BackendPool, (now, system_now): (Instant, SystemTime)) -> PoolState {
+  let ejections = match &pool.outlier_detector {
+    Some(outlier_detector) => outlier_detector.ejections(),
+    None => Default::default(),
+  };
+  let backends = pool
+    .addresses
+    .iter()
+    .map(|(address, healthiness)| {
+      let ejection = ejections.get(address);
+      let ejected_until = ejection
+        .and_then(|it| it.ejected_until)
+        .map(|it| millis_since_epoch(system_now + it.saturating_duration_since(now)));
+      BackendState {
+        address: address.clone(),
+        healthiness: Some(healthiness.load().as_ref().into()),
+        ejected_until,
+        ejections: ejection.map_or(0, |it| it.ejections),
+      }
+    })
+    .collect();
+  PoolState {
+    name: pool.name.clone(),
+    backends,
+  }
+}
+
+/// Restores the state of the backend servers from the `state_file`, if there
+/// is one. Missing, stale or invalid files are ignored, because they must not
+/// prevent starting the load balancer.
+pub fn restore(config: &RuntimeConfig) {
+  let state_file = match &config.state_file {
+    Some(state_file) => state_file,
+    None => return,
+  };
+  let state = match read(state_file) {
+    Ok(Some(state)) => state,
+    Ok(None) => return,
+    Err(e) => {
+      warn!("Ignoring the state file '{}' due to: {}", state_file.path.display(), e);
+      return;
+    }
+  };
+  let now = (Instant::now(), millis_since_epoch(SystemTime::now()));
+  // Health checks override the restored healthiness, without them it would never change again
+  let restore_healthiness = config.health_interval != Duration::from_secs(0);
+  for pool_state in state.backend_pools {
+    let pool = match config
+      .shared_data
+      .backend_pools
+      .iter()
+      .find(|it| it.name == pool_state.name)
+    {
+      Some(pool) => pool,
+      None => continue,
+    };
+    for backend_state in pool_state.backends {
+      restore_backend(pool, backend_state, restore_healthiness, now);
+    }
+  }
+  info!(
+    "Restored the state of backend servers from '{}'",
+    state_file.path.display()
+  );
+}
+
+/// Restores the dynamic backend servers from the `state_file`, if there is
+/// one. Unlike their health they are restored regardless of the `max_age`.
+/// Returns whether there were any, because then the backend pools have to be
+/// built again.
+pub fn restore_dynamic_backends(config: &RuntimeConfig) -> bool {
+  let state_file = match &config.state_file {
+    Some(state_file) => state_file,
+    None => return false,
+  };
+  let state = match read_unchecked(state_file) {
+    Ok(Some(state)) => state,
+    Ok(None) => return false,
+    Err(e) => {
+      warn!("Ignoring the state file '{}' due to: {}", state_file.path.display(), e);
+      return false;
+    }
+  };
+  if state.dynamic_backends.is_empty() {
+    return false;
+  }
+  dynamic_backends::restore(state.dynamic_backends);
+  info!("Restored dynamic backend servers from '{}'", state_file.path.display());
+  true
+}
+
+fn read(state_file: &StateFileConfig) -> Result<Option<State>, io::Error> {
+  let state = match read_unchecked(state_file)? {
+    Some(state) => state,
+    None => return Ok(None),
+  };
+  let age = Duration::from_millis(millis_since_epoch(SystemTime::now()).saturating_sub(state.saved_at));
+  if age > state_file.max_age {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("It was written {:?} ago, which exceeds the max_age", age),
+    ));
+  }
+  Ok(Some(state))
+}
+
+/// Reads the `state_file` without checking its age.
+fn read_unchecked(state_file: &StateFileConfig) -> Result<Option<State>, io::Error> {
+  let json = match fs::read(&state_file.path) {
+    Ok(json) => json,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e),
+  };
+  let state: State = serde_json::from_slice(&json).map_err(io::Error::from)?;
+  if state.version > VERSION {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!("The version {} is not supported", state.version),
+    ));
+  }
+  Ok(Some(state))
+}
+
+fn restore_backend(
+  pool: &BackendPool,
+  state: BackendState,
+  restore_healthiness: bool,
+  (now, epoch_now): (Instant, u64),
+) {
+  let healthiness = match pool.addresses.iter().find(|(address, _)| *address == state.address) {
+    Some((_, healthiness)) => healthiness,
+    None => return,
+  };
+  if let (true, Some(restored)) = (restore_healthiness, state.healthiness) {
+    healthiness.store(Arc::new(restored.into()));
+  }
+  if let (Some(outlier_detector), true) = (&pool.outlier_detector, state.ejections > 0) {
+    let ejected_until = state
+      .ejected_until
+      .filter(|it| *it > epoch_now)
+      .map(|it| now + Duration::from_millis(it - epoch_now));
+    let ejection = Ejection {
+      ejected_until,
+      ejections: state.ejections,
+    };
+    outlier_detector.restore(&state.address, ejection);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    acme::AcmeHandler,
+    outlier_detection::OutlierDetectionConfig,
+    server::{
+      tests::{generate_config, generate_test_pool_builder},
+      SharedData,
+    },
+  };
+
+  fn config(pool: BackendPool, path: PathBuf) -> RuntimeConfig {
+    let mut config = generate_config(SharedData {
+      backend_pools: vec![Arc::new(pool)],
+      acme_handler: Arc::new(AcmeHandler::new()),
+    });
+    config.state_file = Some(StateFileConfig {
+      path,
+      interval: Duration::from_secs(10),
+      max_age: Duration::from_secs(300),
+    });
+    config
+  }
+
+  fn quarantining_pool() -> BackendPool {
+    let mut builder = generate_test_pool_builder(&["127.0.0.1:8081", "127.0.0.1:8082"]);
+    builder.outlier_detection(OutlierDetectionConfig {
+      factor: 1.5,
+      window: Duration::from_millis(50),
+      min_requests: 5,
+      base_ejection_time: Duration::from_millis(300),
+      max_ejection_time: Duration::from_secs(60),
+      failure_status_codes: OutlierDetectionConfig::default_failure_status_codes(),
+    });
+    builder.build()
+  }
+
+  fn state_file_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("arlb-state-{}-{}.json", name, std::process::id()))
+  }
+
+  #[tokio::test]
+  async fn test_quarantined_backend_remains_quarantined_after_restart() {
+    // given: a backend server, which was ejected before the load balancer was killed
+    let path = state_file_path("quarantine");
+    let old_config = config(quarantining_pool(), path.clone());
+    let detector = old_config.shared_data.backend_pools[0]
+      .outlier_detector
+      .as_ref()
+      .unwrap();
+    for _ in 0..5 {
+      detector.record("127.0.0.1:8081", false);
+      detector.record("127.0.0.1:8082", true);
+    }
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    detector.record("127.0.0.1:8082", true);
+    assert!(detector.is_ejected("127.0.0.1:8081"));
+    save(&old_config).unwrap();
+    drop(old_config);
+
+    // when:
+    let new_config = config(quarantining_pool(), path.clone());
+    restore(&new_config);
+
+    // then:
+    let detector = new_config.shared_data.backend_pools[0]
+      .outlier_detector
+      .as_ref()
+      .unwrap();
+    assert!(detector.is_ejected("127.0.0.1:8081"));
+    assert!(!detector.is_ejected("127.0.0.1:8082"));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!detector.is_ejected("127.0.0.1:8081"));
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_healthiness_is_restored() {
+    // given:
+    let path = state_file_path("healthiness");
+    let old_config = config(generate_test_pool_builder(&["127.0.0.1:8081"]).build(), path.clone());
+    let status = Some(StatusCode::SERVICE_UNAVAILABLE);
+    old_config.shared_data.backend_pools[0].addresses[0]
+      .1
+      .store(Arc::new(Healthiness::Unresponsive(status)));
+    save(&old_config).unwrap();
+
+    // when:
+    let new_config = config(generate_test_pool_builder(&["127.0.0.1:8081"]).build(), path.clone());
+    restore(&new_config);
+
+    // then:
+    let healthiness = new_config.shared_data.backend_pools[0].addresses[0].1.load();
+    assert_eq!(healthiness.as_ref(), &Healthiness::Unresponsive(status));
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_invalid_and_stale_files_are_ignored() {
+    let path = state_file_path("invalid");
+    let new_config = config(generate_test_pool_builder(&["127.0.0.1:8081"]).build(), path.clone());
+    let state_file = new_config.state_file.as_ref().unwrap();
+
+    fs::write(&path, "{\"version\":1,\"saved_\"").unwrap();
+    assert!(read(state_file).is_err());
+    restore(&new_config);
+
+    fs::write(&path, "{\"version\":1,\"saved_at\":0}").unwrap();
+    assert!(read(state_file).is_err());
+
+    fs::write(&path, "{\"version\":2,\"saved_at\":18446744073709551615}").unwrap();
+    assert!(read(state_file).is_err());
+
+    fs::remove_file(&path).unwrap();
+    assert!(read(state_file).unwrap().is_none());
+  }
+
+  #[test]
+  fn test_unknown_and_missing_fields_are_tolerated() {
+    // given: a file with a field of a future version and without optional fields
+    let path = state_file_path("fields");
+    let new_config = config(generate_test_pool_builder(&["127.0.0.1:8081"]).build(), path.clone());
+    let saved_at = millis_since_epoch(SystemTime::now());
+    let json = format!(
+      "{{\"version\":1,\"saved_at\":{},\"future\":true,\"backend_pools\":[{{\"name\":\"\",\"backends\":[{{\"address\":\"127.0.0.1:8081\",\"healthiness\":{{\"Slow\":300}}}}]}}]}}",
+      saved_at
+    );
+    fs::write(&path, json).unwrap();
+
+    // when:
+    restore(&new_config);
+
+    // then:
+    let healthiness = new_config.shared_data.backend_pools[0].addresses[0].1.load();
+    assert_eq!(healthiness.as_ref(), &Healthiness::Slow(300));
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_dynamic_backends_are_restored_regardless_of_age() {
+    // given: a stale file with a dynamic backend server
+    let path = state_file_path("dynamic");
+    let new_config = config(generate_test_pool_builder(&["127.0.0.1:8081"]).build(), path.clone());
+    let json =
+      "{\"version\":1,\"saved_at\":0,\"dynamic_backends\":{\"state-file\":{\"a\":{\"address\":\"127.0.0.1:8082\"}}}}";
+    fs::write(&path, json).unwrap();
+
+    // when:
+    let restored = restore_dynamic_backends(&new_config);
+    save(&new_config).unwrap();
+
+    // then: they are written again as well
+    assert!(restored);
+    assert_eq!(dynamic_backends::of_pool("state-file")["a"].address, "127.0.0.1:8082");
+    let state = read(new_config.state_file.as_ref().unwrap()).unwrap().unwrap();
+    assert_eq!(state.dynamic_backends["state-file"]["a"].address, "127.0.0.1:8082");
+    fs::remove_file(path).unwrap();
+  }
+}
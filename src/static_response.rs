@@ -0,0 +1,150 @@
+use crate::configuration::StaticResponseConfig;
+use hyper::{
+  body::Bytes,
+  header::{HeaderName, HeaderValue, CONTENT_TYPE},
+  Body, HeaderMap, Response, StatusCode,
+};
+use std::{convert::TryFrom, error::Error, fs, io};
+
+/// A response, which is sent by the load balancer itself without contacting
+/// any backend server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaticResponse {
+  pub status: StatusCode,
+  pub headers: HeaderMap,
+  pub body: Bytes,
+}
+
+/// Inserted into the extensions of responses, which were sent by the load
+/// balancer itself, so they can be told apart in the access log.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalResponse;
+
+impl StaticResponse {
+  /// The response of backend pools in maintenance mode.
+  pub fn maintenance() -> StaticResponse {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    StaticResponse {
+      status: StatusCode::SERVICE_UNAVAILABLE,
+      headers,
+      body: Bytes::from_static(b"503 - Service Unavailable"),
+    }
+  }
+
+  pub fn response(&self) -> Response<Body> {
+    let mut response = Response::new(Body::from(self.body.clone()));
+    *response.status_mut() = self.status;
+    *response.headers_mut() = self.headers.clone();
+    response.extensions_mut().insert(LocalResponse);
+    response
+  }
+}
+
+impl TryFrom<StaticResponseConfig> for StaticResponse {
+  type Error = io::Error;
+
+  fn try_from(other: StaticResponseConfig) -> Result<Self, Self::Error> {
+    let status = StatusCode::from_u16(other.status).map_err(invalid_data)?;
+    let mut headers = HeaderMap::new();
+    for (name, value) in other.headers {
+      let name = HeaderName::try_from(name.as_str()).map_err(invalid_data)?;
+      let value = HeaderValue::try_from(value).map_err(invalid_data)?;
+      headers.append(name, value);
+    }
+    let body = match (other.body, other.body_path) {
+      (Some(_), Some(_)) => return Err(invalid_data("Only one of body and body_path can be configured")),
+      (Some(body), None) => Bytes::from(body),
+      (None, Some(body_path)) => fs::read(&body_path).map(Bytes::from).map_err(|e| {
+        io::Error::new(
+          e.kind(),
+          format!("Could not read body_path '{}' due to: {}", body_path.display(), e),
+        )
+      })?,
+      (None, None) => Bytes::new(),
+    };
+    Ok(StaticResponse { status, headers, body })
+  }
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+  E: Into<Box<dyn Error + Send + Sync>>,
+{
+  io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  fn config(toml: &str) -> StaticResponseConfig {
+    toml::from_str(toml).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_inline_body() {
+    // given:
+    let config = config(
+      r#"status = 200
+body = "ok"
+headers = { Content-Type = "text/plain" }"#,
+    );
+
+    // when:
+    let response = StaticResponse::try_from(config).unwrap().response();
+
+    // then:
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get(CONTENT_TYPE).unwrap(), "text/plain");
+    assert!(response.extensions().get::<LocalResponse>().is_some());
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "ok");
+  }
+
+  #[tokio::test]
+  async fn test_file_body() {
+    // given:
+    let path = std::env::temp_dir().join(format!("arlb-static-response-{}.html", std::process::id()));
+    fs::write(&path, "<h1>Down for maintenance</h1>").unwrap();
+    let config = StaticResponseConfig {
+      status: 503,
+      headers: HashMap::new(),
+      body: None,
+      body_path: Some(path.clone()),
+    };
+
+    // when:
+    let static_response = StaticResponse::try_from(config);
+    fs::remove_file(&path).unwrap();
+
+    // then:
+    let response = static_response.unwrap().response();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "<h1>Down for maintenance</h1>");
+  }
+
+  #[test]
+  fn test_missing_body_file() {
+    let config = config(r#"body_path = "/does/not/exist.html""#);
+
+    let error = StaticResponse::try_from(config).unwrap_err();
+
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    assert!(error.to_string().contains("/does/not/exist.html"));
+  }
+
+  #[test]
+  fn test_body_and_body_path() {
+    let config = config(
+      r#"body = "ok"
+body_path = "ok.txt""#,
+    );
+
+    let error = StaticResponse::try_from(config).unwrap_err();
+
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+}
@@ -0,0 +1,511 @@
+use crate::{
+  bandwidth::BACKEND_BANDWIDTH,
+  connections::{Phase, Tracked, CONNECTIONS},
+  fault_injection::{self, FaultInjectionConfig, Faults},
+  http_client::{backend_uri, connect_relay_backend},
+  listeners::{bind_with_backlog, AcceptRetry},
+  load_balancing::LoadBalancingStrategy,
+  metrics::METRICS,
+  proxy_protocol::{self, ProxyProtocolVersion},
+  tls_passthrough::{select_backend_within_budget, splice, Ending},
+  top_talkers::TOP_TALKERS,
+};
+use hyper::http::uri::PathAndQuery;
+use log::{debug, info};
+use std::{
+  io,
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  time::{timeout, timeout_at},
+};
+
+/// The longest prefix a route can match.
+pub const MAX_PREFIX_LEN: usize = 32;
+
+/// A TCP listener, whose connections are relayed to backend servers by the
+/// first bytes the client sends, so several protocols can share a port (like
+/// SSH and HTTPS on port 443).
+#[derive(Debug)]
+pub struct TcpRouterService {
+  pub listen_address: SocketAddr,
+  /// The first route, whose prefix the first bytes start with, is used.
+  pub routes: Vec<TcpRoute>,
+  /// How long to wait for the first bytes before routing by the bytes
+  /// received so far, which suits protocols where the server speaks first.
+  pub peek_timeout: Duration,
+  /// The most bytes, which are read before routing.
+  pub max_peek_len: usize,
+  /// The backend server has to accept the connection within this time.
+  pub connect_timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct TcpRoute {
+  /// A route with an empty prefix matches all connections, including those
+  /// which sent nothing within the `peek_timeout`.
+  pub prefix: Vec<u8>,
+  pub addresses: Vec<String>,
+  pub strategy: Box<dyn LoadBalancingStrategy>,
+  /// Limits the bytes per second of each direction of a connection.
+  pub max_connection_bytes_per_sec: Option<u64>,
+  /// Backend servers, which sent this many bytes per second across all their
+  /// connections, receive no new connections.
+  pub max_backend_bytes_per_sec: Option<u64>,
+  /// Sends a PROXY protocol header of this version ahead of the connection,
+  /// so backend servers know the address of the client.
+  pub proxy_protocol: Option<ProxyProtocolVersion>,
+  /// Delays, resets or throttles a share of the connections.
+  pub fault_injection: Option<FaultInjectionConfig>,
+}
+
+/// Whether the route of the first bytes of a connection is known.
+#[derive(Debug, PartialEq)]
+enum Decision {
+  /// The index of the matching route.
+  Route(usize),
+  /// None of the routes can match anymore.
+  Unmatched,
+  /// A route, which is preferred to all matching ones, could still match.
+  Undecided,
+}
+
+impl TcpRouterService {
+  /// Finds the route of the `peeked` bytes. If `complete`, no more bytes will
+  /// arrive, so routes, whose prefix is longer, can not match anymore.
+  fn route(&self, peeked: &[u8], complete: bool) -> Decision {
+    for (index, route) in self.routes.iter().enumerate() {
+      if peeked.starts_with(&route.prefix) {
+        return Decision::Route(index);
+      }
+      // Respects the order, a later route is only used once this one can not match
+      if !complete && route.prefix.starts_with(peeked) {
+        return Decision::Undecided;
+      }
+    }
+    Decision::Unmatched
+  }
+}
+
+pub struct TcpRouterProxy {
+  listener: TcpListener,
+  service: Arc<TcpRouterService>,
+  /// New connections are closed right away while this is set.
+  maintenance: Arc<AtomicBool>,
+}
+
+impl TcpRouterProxy {
+  pub async fn bind(
+    service: Arc<TcpRouterService>,
+    maintenance: Arc<AtomicBool>,
+    backlog: u32,
+  ) -> io::Result<TcpRouterProxy> {
+    let listener = bind_with_backlog(service.listen_address, backlog)?;
+    info!(
+      "Started listening for routed TCP connections on {}",
+      listener.local_addr()?
+    );
+    Ok(TcpRouterProxy {
+      listener,
+      service,
+      maintenance,
+    })
+  }
+
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.listener.local_addr()
+  }
+
+  /// Accepts connections and relays each of them in a separate task.
+  pub async fn run(self) -> io::Result<()> {
+    let mut retry = AcceptRetry::new();
+    loop {
+      let (socket, peer) = retry.accept(|| self.listener.accept()).await?;
+      if self.maintenance.load(Ordering::Relaxed) {
+        METRICS.increment("arlb_tcp_router_connections_total", &[("result", "maintenance")]);
+        debug!("Closed routed TCP connection of {} due to maintenance", peer);
+        continue;
+      }
+      let service = self.service.clone();
+      let client = CONNECTIONS.track(socket, "tcp_router".into(), peer, Phase::Handshaking);
+      tokio::spawn(async move {
+        let connection = client.connection().id;
+        if let Err(e) = relay(&service, client, peer).await {
+          debug!(
+            "Could not relay routed TCP connection {} of {}: {}",
+            connection, peer, e
+          );
+        }
+      });
+    }
+  }
+}
+
+/// Reads the first bytes of `client` to select a route, replays them to a
+/// backend server of the route and then copies the connection in both
+/// directions until both sides closed it.
+async fn relay(service: &TcpRouterService, mut client: Tracked<TcpStream>, peer: SocketAddr) -> io::Result<()> {
+  let (peeked, decision) = peek(service, &mut client).await?;
+  let route = match decision {
+    Decision::Route(index) => &service.routes[index],
+    _ => {
+      METRICS.increment("arlb_tcp_router_connections_total", &[("result", "unmatched")]);
+      debug!("Closed routed TCP connection of {} without a matching route", peer);
+      return Ok(());
+    }
+  };
+  let backend_address = match select_backend_within_budget(
+    &route.addresses,
+    route.max_backend_bytes_per_sec,
+    route.strategy.as_ref(),
+    &peer,
+  ) {
+    Some(backend_address) => backend_address,
+    None => {
+      METRICS.increment("arlb_tcp_router_connections_total", &[("result", "over_budget")]);
+      debug!(
+        "Closed routed TCP connection of {}, because all backend servers are at their bandwidth budget",
+        peer
+      );
+      return Ok(());
+    }
+  };
+  METRICS.increment("arlb_tcp_router_connections_total", &[("result", "routed")]);
+  let faults = match &route.fault_injection {
+    Some(config) if fault_injection::enabled() => config.decide(&mut rand::thread_rng()),
+    _ => Faults::default(),
+  };
+  let connection = client.connection().id;
+  if let Some(delay) = faults.delay {
+    METRICS.increment("arlb_tcp_router_injected_faults_total", &[("fault", "delay")]);
+    info!(
+      "Injected a delay of {}ms into routed TCP connection {} of {}",
+      delay.as_millis(),
+      connection,
+      peer
+    );
+    tokio::time::sleep(delay).await;
+  }
+  if faults.abort.is_some() {
+    METRICS.increment("arlb_tcp_router_injected_faults_total", &[("fault", "abort")]);
+    info!("Injected a reset into routed TCP connection {} of {}", connection, peer);
+    // Without lingering, closing sends a RST instead of a FIN
+    return client.get_ref().set_linger(Some(Duration::from_secs(0)));
+  }
+  let max_connection_bytes_per_sec = match (faults.throttle, route.max_connection_bytes_per_sec) {
+    (Some(throttle), Some(limit)) => Some(throttle.min(limit)),
+    (throttle, limit) => throttle.or(limit),
+  };
+  if let Some(throttle) = faults.throttle {
+    METRICS.increment("arlb_tcp_router_injected_faults_total", &[("fault", "throttle")]);
+    info!(
+      "Injected a throttle of {} bytes per second into routed TCP connection {} of {}",
+      throttle, connection, peer
+    );
+  }
+
+  let backend_uri = backend_uri(&backend_address, PathAndQuery::from_static("/"))
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  client.connection().connecting(&backend_address);
+  let mut backend = timeout(service.connect_timeout, connect_relay_backend(&backend_address))
+    .await
+    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "The connection timed out")))?;
+  debug!("Relaying routed TCP connection of {} to {}", peer, backend_address);
+  route.strategy.on_tcp_open(&backend_uri);
+  let result = async {
+    if let Some(version) = route.proxy_protocol {
+      let header = proxy_protocol::header(version, peer, client.get_ref().local_addr()?);
+      backend.write_all(&header).await?;
+    }
+    backend.write_all(&peeked).await?;
+    let meter = BACKEND_BANDWIDTH.meter(&backend_address);
+    client.connection().set_phase(Phase::Piping);
+    let client = TOP_TALKERS.observe(client, peer.ip());
+    let ending = splice(client, backend, max_connection_bytes_per_sec, Some(meter)).await?;
+    if ending == Ending::BackendReset {
+      debug!(
+        "Backend server {} reset the routed TCP connection of {}",
+        backend_address, peer
+      );
+    }
+    Ok(())
+  }
+  .await;
+  route.strategy.on_tcp_close(&backend_uri);
+  result
+}
+
+/// Reads from `client` until a route is decided, the client stops sending or
+/// the `max_peek_len` is reached. Returns the bytes read, which have to be
+/// replayed to the backend server.
+async fn peek<C>(service: &TcpRouterService, client: &mut C) -> io::Result<(Vec<u8>, Decision)>
+where
+  C: AsyncRead + Unpin,
+{
+  let deadline = Instant::now() + service.peek_timeout;
+  let mut peeked = Vec::with_capacity(service.max_peek_len);
+  loop {
+    let decision = service.route(&peeked, peeked.len() >= service.max_peek_len);
+    if decision != Decision::Undecided {
+      return Ok((peeked, decision));
+    }
+    let mut buffer = [0; MAX_PREFIX_LEN];
+    let wanted = (service.max_peek_len - peeked.len()).min(buffer.len());
+    match timeout_at(deadline.into(), client.read(&mut buffer[..wanted])).await {
+      Ok(Ok(0)) | Err(_) => {
+        let decision = service.route(&peeked, true);
+        return Ok((peeked, decision));
+      }
+      Ok(Ok(read)) => peeked.extend_from_slice(&buffer[..read]),
+      Ok(Err(e)) => return Err(e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{connections, load_balancing::round_robin::RoundRobin};
+  use hyper::{Body, Request, StatusCode};
+  use serde_json::Value;
+
+  /// Greets each connection with its `name` and then echoes what the client
+  /// sends.
+  async fn start_backend(name: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      loop {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+          let (mut read, mut write) = socket.split();
+          write.write_all(format!("{} ", name).as_bytes()).await.unwrap();
+          tokio::io::copy(&mut read, &mut write).await.unwrap();
+          write.shutdown().await.unwrap();
+        });
+      }
+    });
+    address
+  }
+
+  fn route(prefix: &[u8], backend: SocketAddr) -> TcpRoute {
+    TcpRoute {
+      prefix: prefix.to_vec(),
+      addresses: vec![backend.to_string()],
+      strategy: Box::new(RoundRobin::new()),
+      max_connection_bytes_per_sec: None,
+      max_backend_bytes_per_sec: None,
+      proxy_protocol: None,
+      fault_injection: None,
+    }
+  }
+
+  fn service(routes: Vec<TcpRoute>) -> TcpRouterService {
+    TcpRouterService {
+      listen_address: "127.0.0.1:0".parse().unwrap(),
+      routes,
+      peek_timeout: Duration::from_millis(200),
+      max_peek_len: MAX_PREFIX_LEN,
+      connect_timeout: Duration::from_secs(1),
+    }
+  }
+
+  async fn start_proxy(routes: Vec<TcpRoute>) -> SocketAddr {
+    let proxy = TcpRouterProxy::bind(Arc::new(service(routes)), Default::default(), 1024)
+      .await
+      .unwrap();
+    let address = proxy.local_addr().unwrap();
+    tokio::spawn(proxy.run());
+    address
+  }
+
+  /// Sends `bytes` after `delay` and returns the whole response.
+  async fn request_after(proxy: SocketAddr, delay: Duration, bytes: &[u8]) -> io::Result<String> {
+    let mut socket = TcpStream::connect(proxy).await?;
+    tokio::time::sleep(delay).await;
+    socket.write_all(bytes).await?;
+    socket.shutdown().await?;
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await?;
+    Ok(response)
+  }
+
+  async fn request(proxy: SocketAddr, bytes: &[u8]) -> io::Result<String> {
+    request_after(proxy, Duration::from_secs(0), bytes).await
+  }
+
+  #[tokio::test]
+  async fn test_connections_are_routed_by_their_first_bytes() {
+    // given:
+    let ssh = start_backend("ssh").await;
+    let tls = start_backend("tls").await;
+    let default = start_backend("default").await;
+    let proxy = start_proxy(vec![
+      route(b"SSH-", ssh),
+      route(&[0x16, 0x03], tls),
+      route(b"", default),
+    ])
+    .await;
+
+    // when:
+    let ssh_response = request(proxy, b"SSH-2.0-OpenSSH_8.9\r\n").await;
+    let tls_response = request(proxy, &[0x16, 0x03, 0x01, 0x02, 0x00]).await;
+    let other_response = request(proxy, b"GET / HTTP/1.1\r\n\r\n").await;
+    // sends nothing within the peek_timeout, like clients of protocols where the server speaks first
+    let silent_response = request_after(proxy, Duration::from_millis(400), b"SSH-2.0").await;
+
+    // then: the peeked bytes are replayed to the backend server
+    assert_eq!(ssh_response.unwrap(), "ssh SSH-2.0-OpenSSH_8.9\r\n");
+    assert_eq!(tls_response.unwrap(), "tls \u{16}\u{3}\u{1}\u{2}\u{0}");
+    assert_eq!(other_response.unwrap(), "default GET / HTTP/1.1\r\n\r\n");
+    assert_eq!(silent_response.unwrap(), "default SSH-2.0");
+  }
+
+  #[tokio::test]
+  async fn test_proxy_protocol_header_precedes_the_peeked_bytes() {
+    // given:
+    let backend = start_backend("ssh").await;
+    let proxy = start_proxy(vec![TcpRoute {
+      proxy_protocol: Some(ProxyProtocolVersion::V1),
+      ..route(b"SSH-", backend)
+    }])
+    .await;
+
+    // when:
+    let mut socket = TcpStream::connect(proxy).await.unwrap();
+    let client_address = socket.local_addr().unwrap();
+    socket.write_all(b"SSH-2.0").await.unwrap();
+    socket.shutdown().await.unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+
+    // then:
+    let expected = format!(
+      "ssh PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nSSH-2.0",
+      client_address.port(),
+      proxy.port()
+    );
+    assert_eq!(response, expected);
+  }
+
+  #[tokio::test]
+  async fn test_unmatched_connections_are_closed() {
+    // given:
+    let ssh = start_backend("ssh").await;
+    let proxy = start_proxy(vec![route(b"SSH-", ssh)]).await;
+
+    // when:
+    let response = request(proxy, b"GET / HTTP/1.1\r\n\r\n").await;
+
+    // then: the connection may also be reset, if not all bytes were read
+    assert_eq!(response.unwrap_or_default(), "");
+  }
+
+  #[tokio::test]
+  async fn test_aborted_connections_are_reset() {
+    // given:
+    let ssh = start_backend("ssh").await;
+    let proxy = start_proxy(vec![TcpRoute {
+      fault_injection: Some(FaultInjectionConfig {
+        delay: None,
+        abort: Some(crate::fault_injection::AbortFault {
+          percent: 100.0,
+          status: hyper::StatusCode::SERVICE_UNAVAILABLE,
+        }),
+        throttle: None,
+      }),
+      ..route(b"SSH-", ssh)
+    }])
+    .await;
+
+    // when:
+    let response = request(proxy, b"SSH-2.0").await;
+
+    // then: the shutdown of the client fails, if the reset arrived before
+    let error = response.unwrap_err();
+    assert!(
+      matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::NotConnected
+      ),
+      "{:?}",
+      error
+    );
+    assert!(METRICS.counter("arlb_tcp_router_injected_faults_total", &[("fault", "abort")]) >= 1);
+  }
+
+  #[test]
+  fn test_first_matching_route_wins() {
+    let backend = "127.0.0.1:1".parse().unwrap();
+    let service = service(vec![route(b"SSH-2", backend), route(b"SSH-", backend)]);
+
+    // A later, shorter prefix must not win while an earlier one can still match
+    assert_eq!(service.route(b"SSH-", false), Decision::Undecided);
+    assert_eq!(service.route(b"SSH-", true), Decision::Route(1));
+    assert_eq!(service.route(b"SSH-2.0", false), Decision::Route(0));
+    assert_eq!(service.route(b"SSH-1.99", false), Decision::Route(1));
+    assert_eq!(service.route(b"HTTP", false), Decision::Unmatched);
+  }
+
+  async fn admin_request(request: Request<Body>) -> (StatusCode, Value) {
+    let response = connections::handle_request(&request);
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+  }
+
+  #[tokio::test]
+  async fn test_live_connections_can_be_found_and_killed_via_the_admin_api() {
+    // given: a backend server, which greets and then waits for the close
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend = listener.local_addr().unwrap();
+    let backend_closed = tokio::spawn(async move {
+      let (mut socket, _) = listener.accept().await.unwrap();
+      socket.write_all(b"hello ").await.unwrap();
+      let mut buffer = [0; 64];
+      while let Ok(read) = socket.read(&mut buffer).await {
+        if read == 0 {
+          break;
+        }
+      }
+    });
+    let proxy = start_proxy(vec![route(b"", backend)]).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let mut greeting = [0; 6];
+    client.read_exact(&mut greeting).await.unwrap();
+
+    // when:
+    let (_, list) = admin_request(Request::get("/connections").body(Body::empty()).unwrap()).await;
+    let peer = client.local_addr().unwrap().to_string();
+    let summary = list["connections"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .find(|it| it["peer"] == peer.as_str())
+      .cloned()
+      .unwrap();
+    let path = format!("/connections/{}", summary["id"]);
+    let (_, detail) = admin_request(Request::get(&path).body(Body::empty()).unwrap()).await;
+    let (killed, _) = admin_request(Request::delete(&path).body(Body::empty()).unwrap()).await;
+
+    // then:
+    assert_eq!(summary["listener"], "tcp_router");
+    assert_eq!(summary["backend"], backend.to_string().as_str());
+    assert_eq!(detail["phase"], "piping");
+    assert_eq!(detail["bytes_sent"], 6);
+    assert_eq!(killed, StatusCode::NO_CONTENT);
+    let client_closed = timeout(Duration::from_secs(1), client.read_to_end(&mut Vec::new())).await;
+    assert!(client_closed.is_ok(), "the client connection is still open");
+    let backend_closed = timeout(Duration::from_secs(1), backend_closed).await;
+    assert!(backend_closed.is_ok(), "the backend connection is still open");
+    let (status, _) = admin_request(Request::get(&path).body(Body::empty()).unwrap()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+  }
+}
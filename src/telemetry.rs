@@ -0,0 +1,615 @@
+#[cfg(feature = "opentelemetry")]
+use futures::StreamExt;
+#[cfg(feature = "opentelemetry")]
+use hyper::{
+  body::HttpBody,
+  client::connect::HttpInfo,
+  header::{HeaderName, HeaderValue, HOST},
+  HeaderMap,
+};
+use hyper::{Body, Request, Response, Uri};
+#[cfg(feature = "opentelemetry")]
+use log::warn;
+use once_cell::sync::Lazy;
+#[cfg(feature = "opentelemetry")]
+use once_cell::sync::OnceCell;
+#[cfg(feature = "opentelemetry")]
+use opentelemetry::{
+  propagation::{Extractor, Injector, TextMapPropagator},
+  trace::{Link, Span, SpanKind, Status, TraceContextExt, Tracer, TracerProvider},
+  Context, KeyValue,
+};
+#[cfg(feature = "opentelemetry")]
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+#[cfg(feature = "opentelemetry")]
+use opentelemetry_sdk::{
+  propagation::TraceContextPropagator,
+  trace::{Sampler, SdkTracer, SdkTracerProvider},
+  Resource,
+};
+#[cfg(feature = "opentelemetry")]
+use std::{
+  collections::HashMap,
+  convert::TryFrom,
+  sync::Mutex,
+  time::{Instant, SystemTime},
+};
+use std::{fmt::Display, future::Future, io, net::SocketAddr, time::Duration};
+
+/// Where and how many of the spans of connections and proxied requests are
+/// exported via OTLP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenTelemetryConfig {
+  /// The OTLP/HTTP endpoint of the collector, like
+  /// `http://localhost:4318/v1/traces`.
+  pub endpoint: String,
+  /// The ratio of traces, which are sampled. Requests with a `traceparent`
+  /// header follow the sampling decision of the client instead.
+  pub sample_ratio: f64,
+  /// The `service.name` of the exported spans.
+  pub service_name: String,
+}
+
+impl OpenTelemetryConfig {
+  pub fn default_endpoint() -> String {
+    "http://localhost:4318/v1/traces".to_string()
+  }
+
+  pub fn default_service_name() -> String {
+    "another-rust-load-balancer".to_string()
+  }
+
+  /// Fails if the load balancer was built without the `opentelemetry`
+  /// feature, instead of silently exporting nothing.
+  pub fn check_support() -> io::Result<()> {
+    if cfg!(feature = "opentelemetry") {
+      Ok(())
+    } else {
+      Err(io::Error::other("OpenTelemetry support is not enabled in this build"))
+    }
+  }
+}
+
+/// The exporter, which is installed once on startup. Until then, or if no
+/// exporter is configured, no spans are created, so requests are served
+/// without any allocations for tracing.
+pub static TELEMETRY: Lazy<Telemetry> = Lazy::new(Telemetry::default);
+
+#[derive(Default)]
+pub struct Telemetry {
+  #[cfg(feature = "opentelemetry")]
+  exporter: OnceCell<Exporter>,
+  /// The TLS handshakes of connections, which were not served yet.
+  #[cfg(feature = "opentelemetry")]
+  handshakes: Mutex<HashMap<SocketAddr, Handshake>>,
+}
+
+#[cfg(feature = "opentelemetry")]
+struct Exporter {
+  provider: SdkTracerProvider,
+  tracer: SdkTracer,
+}
+
+#[cfg(feature = "opentelemetry")]
+#[derive(Clone, Copy)]
+struct Handshake {
+  started: SystemTime,
+  finished: SystemTime,
+  recorded: Instant,
+}
+
+/// Handshakes of connections, which are closed before they are served, are
+/// forgotten once there are this many handshakes and they are older than
+/// [`STALE_HANDSHAKE`].
+#[cfg(feature = "opentelemetry")]
+const MAX_HANDSHAKES: usize = 1024;
+
+#[cfg(feature = "opentelemetry")]
+const STALE_HANDSHAKE: Duration = Duration::from_secs(10);
+
+impl Telemetry {
+  /// Installs the exporter of `config`, if any. Later calls are ignored, so
+  /// changes of the configuration require a restart.
+  #[cfg(feature = "opentelemetry")]
+  pub fn configure(&self, config: Option<&OpenTelemetryConfig>) -> io::Result<()> {
+    let config = match config {
+      Some(config) if self.exporter.get().is_none() => config,
+      _ => return Ok(()),
+    };
+    let exporter = SpanExporter::builder()
+      .with_http()
+      .with_endpoint(config.endpoint.clone())
+      .build()
+      .map_err(|e| {
+        io::Error::other(format!(
+          "Could not create the OTLP exporter for '{}' due to: {}",
+          config.endpoint, e
+        ))
+      })?;
+    let provider = SdkTracerProvider::builder()
+      .with_batch_exporter(exporter)
+      .with_sampler(sampler(config.sample_ratio))
+      .with_resource(
+        Resource::builder()
+          .with_service_name(config.service_name.clone())
+          .build(),
+      )
+      .build();
+    self.install(provider);
+    Ok(())
+  }
+
+  #[cfg(not(feature = "opentelemetry"))]
+  pub fn configure(&self, config: Option<&OpenTelemetryConfig>) -> io::Result<()> {
+    match config {
+      Some(_) => OpenTelemetryConfig::check_support(),
+      None => Ok(()),
+    }
+  }
+
+  #[cfg(feature = "opentelemetry")]
+  fn install(&self, provider: SdkTracerProvider) {
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    if self.exporter.set(Exporter { provider, tracer }).is_err() {
+      warn!("The OpenTelemetry exporter was already installed");
+    }
+  }
+
+  /// Whether spans are exported.
+  #[cfg(feature = "opentelemetry")]
+  fn is_enabled(&self) -> bool {
+    self.exporter.get().is_some()
+  }
+
+  /// Exports the remaining spans. The exporter is kept, so an embedding
+  /// application can run the load balancer again.
+  pub fn flush(&self) {
+    #[cfg(feature = "opentelemetry")]
+    if let Some(exporter) = self.exporter.get() {
+      if let Err(e) = exporter.provider.force_flush() {
+        warn!("Could not export the remaining spans due to: {}", e);
+      }
+    }
+  }
+
+  /// Remembers the TLS handshake of the connection of `peer`, which completed
+  /// just now after `duration`, for its [`ConnectionSpan`].
+  #[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+  pub fn record_handshake(&self, peer: SocketAddr, duration: Duration) {
+    #[cfg(feature = "opentelemetry")]
+    if self.is_enabled() {
+      let finished = SystemTime::now();
+      let recorded = Instant::now();
+      let mut handshakes = self.handshakes.lock().unwrap();
+      if handshakes.len() >= MAX_HANDSHAKES {
+        handshakes.retain(|_, it| recorded.duration_since(it.recorded) < STALE_HANDSHAKE);
+      }
+      let started = finished.checked_sub(duration).unwrap_or(finished);
+      handshakes.insert(
+        peer,
+        Handshake {
+          started,
+          finished,
+          recorded,
+        },
+      );
+    }
+  }
+
+  /// Starts the span of a connection of `peer`, which was accepted by
+  /// `listener`. It starts with the TLS handshake, if any, and ends once the
+  /// connection is closed.
+  #[cfg(feature = "opentelemetry")]
+  pub fn connection_span(&self, peer: SocketAddr, listener: &dyn Display) -> Option<ConnectionSpan> {
+    let exporter = self.exporter.get()?;
+    let handshake = self.handshakes.lock().unwrap().remove(&peer);
+    let mut builder = exporter
+      .tracer
+      .span_builder("connection")
+      .with_kind(SpanKind::Server)
+      .with_attributes(vec![
+        KeyValue::new("network.peer.address", peer.ip().to_string()),
+        KeyValue::new("network.peer.port", i64::from(peer.port())),
+        KeyValue::new("arlb.listener", listener.to_string()),
+      ]);
+    if let Some(handshake) = handshake {
+      builder = builder.with_start_time(handshake.started);
+    }
+    let context = Context::new().with_span(builder.start_with_context(&exporter.tracer, &Context::new()));
+    if let Some(handshake) = handshake {
+      exporter
+        .tracer
+        .span_builder("tls_handshake")
+        .with_start_time(handshake.started)
+        .start_with_context(&exporter.tracer, &context)
+        .end_with_timestamp(handshake.finished);
+    }
+    Some(ConnectionSpan(context))
+  }
+
+  #[cfg(not(feature = "opentelemetry"))]
+  pub fn connection_span(&self, _peer: SocketAddr, _listener: &dyn Display) -> Option<ConnectionSpan> {
+    None
+  }
+}
+
+#[cfg(feature = "opentelemetry")]
+fn sampler(sample_ratio: f64) -> Sampler {
+  Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(sample_ratio)))
+}
+
+/// The span of a client connection, which is the parent of the spans of its
+/// requests. It ends once the last clone is dropped.
+#[cfg(feature = "opentelemetry")]
+#[derive(Clone)]
+pub struct ConnectionSpan(Context);
+
+/// Without the `opentelemetry` feature there are no spans.
+#[cfg(not(feature = "opentelemetry"))]
+#[derive(Clone)]
+pub enum ConnectionSpan {}
+
+impl ConnectionSpan {
+  /// Starts the span of `request`, which is forwarded to a backend server of
+  /// `pool`, and propagates it to the backend server via the `traceparent`
+  /// header. A `traceparent` of the client becomes the parent of the span,
+  /// which is then linked to the connection instead.
+  #[cfg(feature = "opentelemetry")]
+  pub fn request(&self, request: &mut Request<Body>, pool: &str) -> RequestSpan {
+    let exporter = TELEMETRY.exporter.get().expect("Spans are only created by an exporter");
+    let propagator = TraceContextPropagator::new();
+    let remote = propagator.extract(&HeaderExtractor(request.headers()));
+    let connection = self.0.span().span_context().clone();
+    let mut attributes = vec![
+      KeyValue::new("http.request.method", request.method().to_string()),
+      KeyValue::new("server.address", host(request)),
+      KeyValue::new("url.path", request.uri().path().to_string()),
+      KeyValue::new("arlb.pool", pool.to_string()),
+    ];
+    if let Some(size) = request.body().size_hint().exact() {
+      attributes.push(body_size("http.request.body.size", size));
+    }
+    let mut builder = exporter
+      .tracer
+      .span_builder(request.method().to_string())
+      .with_kind(SpanKind::Server)
+      .with_attributes(attributes);
+    let parent = if remote.span().span_context().is_valid() {
+      builder = builder.with_links(vec![Link::with_context(connection)]);
+      remote
+    } else {
+      self.0.clone()
+    };
+    let context = parent.with_span(builder.start_with_context(&exporter.tracer, &parent));
+    propagator.inject_context(&context, &mut HeaderInjector(request.headers_mut()));
+    RequestSpan(context)
+  }
+
+  #[cfg(not(feature = "opentelemetry"))]
+  pub fn request(&self, _request: &mut Request<Body>, _pool: &str) -> RequestSpan {
+    match *self {}
+  }
+}
+
+/// The span of a request, which is proxied to a backend server.
+#[cfg(feature = "opentelemetry")]
+pub struct RequestSpan(Context);
+
+#[cfg(not(feature = "opentelemetry"))]
+pub enum RequestSpan {}
+
+impl RequestSpan {
+  /// Records the backend server and status of `response` and ends the span
+  /// once its body was sent. Bodies of unknown length are counted, unless the
+  /// client accepts trailers, which would be lost by wrapping the body.
+  #[cfg(feature = "opentelemetry")]
+  pub fn finish(self, response: Response<Body>, accepts_trailers: bool) -> Response<Body> {
+    let span = self.0.span();
+    let status = response.status();
+    span.set_attribute(KeyValue::new("http.response.status_code", i64::from(status.as_u16())));
+    if let Some(info) = response.extensions().get::<HttpInfo>() {
+      span.set_attribute(KeyValue::new("arlb.backend", info.remote_addr().to_string()));
+    }
+    if status.is_server_error() {
+      span.set_status(Status::error(status.to_string()));
+    }
+    if let Some(size) = response.body().size_hint().exact() {
+      span.set_attribute(body_size("http.response.body.size", size));
+      span.end();
+      return response;
+    }
+    if accepts_trailers {
+      span.end();
+      return response;
+    }
+    let (parts, body) = response.into_parts();
+    let mut sent = SentBytes {
+      context: self.0,
+      bytes: 0,
+    };
+    let body = Body::wrap_stream(body.map(move |chunk| {
+      if let Ok(chunk) = &chunk {
+        sent.count(chunk.len());
+      }
+      chunk
+    }));
+    Response::from_parts(parts, body)
+  }
+
+  #[cfg(not(feature = "opentelemetry"))]
+  pub fn finish(self, _response: Response<Body>, _accepts_trailers: bool) -> Response<Body> {
+    match self {}
+  }
+
+  /// Records the connections to backend servers, which are established while
+  /// `future` is polled, as children of the span of the request.
+  pub async fn scope<F: Future>(span: Option<&RequestSpan>, future: F) -> F::Output {
+    match span {
+      #[cfg(feature = "opentelemetry")]
+      Some(span) => CURRENT_REQUEST.scope(span.0.clone(), future).await,
+      _ => future.await,
+    }
+  }
+}
+
+/// Records the bytes of a response body, which were sent, once the body is
+/// dropped.
+#[cfg(feature = "opentelemetry")]
+struct SentBytes {
+  context: Context,
+  bytes: u64,
+}
+
+#[cfg(feature = "opentelemetry")]
+impl SentBytes {
+  fn count(&mut self, bytes: usize) {
+    self.bytes += bytes as u64;
+  }
+}
+
+#[cfg(feature = "opentelemetry")]
+impl Drop for SentBytes {
+  fn drop(&mut self) {
+    let span = self.context.span();
+    span.set_attribute(body_size("http.response.body.size", self.bytes));
+    span.end();
+  }
+}
+
+#[cfg(feature = "opentelemetry")]
+tokio::task_local! {
+  /// The span of the request, which the current task forwards.
+  static CURRENT_REQUEST: Context;
+}
+
+/// The span of establishing a connection to a backend server.
+#[cfg(feature = "opentelemetry")]
+pub struct ConnectSpan(Context);
+
+#[cfg(not(feature = "opentelemetry"))]
+pub enum ConnectSpan {}
+
+impl ConnectSpan {
+  /// Starts the span of connecting to `backend` for the request, which the
+  /// current task forwards. hyper calls the connector while the request is
+  /// polled, so connections established in the background have no span.
+  #[cfg(feature = "opentelemetry")]
+  pub fn start(backend: &Uri) -> Option<ConnectSpan> {
+    let exporter = TELEMETRY.exporter.get()?;
+    let parent = CURRENT_REQUEST.try_with(Context::clone).ok()?;
+    let span = exporter
+      .tracer
+      .span_builder("backend_connect")
+      .with_kind(SpanKind::Client)
+      .with_attributes(vec![KeyValue::new(
+        "arlb.backend",
+        backend.authority().map(|it| it.to_string()).unwrap_or_default(),
+      )])
+      .start_with_context(&exporter.tracer, &parent);
+    Some(ConnectSpan(parent.with_span(span)))
+  }
+
+  #[cfg(not(feature = "opentelemetry"))]
+  pub fn start(_backend: &Uri) -> Option<ConnectSpan> {
+    None
+  }
+
+  #[cfg(feature = "opentelemetry")]
+  pub fn finish<T, E: Display>(self, result: &Result<T, E>) {
+    let span = self.0.span();
+    if let Err(e) = result {
+      span.set_status(Status::error(e.to_string()));
+    }
+    span.end();
+  }
+
+  #[cfg(not(feature = "opentelemetry"))]
+  pub fn finish<T, E: Display>(self, _result: &Result<T, E>) {
+    match self {}
+  }
+}
+
+#[cfg(feature = "opentelemetry")]
+fn host(request: &Request<Body>) -> String {
+  request
+    .headers()
+    .get(HOST)
+    .and_then(|it| it.to_str().ok())
+    .or_else(|| request.uri().host())
+    .unwrap_or_default()
+    .to_string()
+}
+
+#[cfg(feature = "opentelemetry")]
+fn body_size(key: &'static str, size: u64) -> KeyValue {
+  KeyValue::new(key, i64::try_from(size).unwrap_or(i64::MAX))
+}
+
+#[cfg(feature = "opentelemetry")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "opentelemetry")]
+impl Extractor for HeaderExtractor<'_> {
+  fn get(&self, key: &str) -> Option<&str> {
+    self.0.get(key).and_then(|it| it.to_str().ok())
+  }
+
+  fn keys(&self) -> Vec<&str> {
+    self.0.keys().map(HeaderName::as_str).collect()
+  }
+}
+
+#[cfg(feature = "opentelemetry")]
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+#[cfg(feature = "opentelemetry")]
+impl Injector for HeaderInjector<'_> {
+  fn set(&mut self, key: &str, value: String) {
+    if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+      self.0.insert(name, value);
+    }
+  }
+}
+
+#[cfg(all(test, feature = "opentelemetry"))]
+pub mod tests {
+  use super::*;
+  use opentelemetry::{trace::TraceId, Value};
+  use opentelemetry_sdk::trace::{InMemorySpanExporter, SpanData};
+
+  /// Installs an exporter, which keeps the spans in memory instead of sending
+  /// them to a collector. The exporter is installed once, so all tests share
+  /// it and only look at the spans of their own trace.
+  pub fn in_memory_exporter() -> InMemorySpanExporter {
+    static EXPORTER: Lazy<InMemorySpanExporter> = Lazy::new(|| {
+      let exporter = InMemorySpanExporter::default();
+      let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .with_sampler(sampler(1.0))
+        .build();
+      TELEMETRY.install(provider);
+      exporter
+    });
+    EXPORTER.clone()
+  }
+
+  /// The finished spans of the trace with `trace_id`.
+  pub fn spans_of_trace(exporter: &InMemorySpanExporter, trace_id: TraceId) -> Vec<SpanData> {
+    exporter
+      .get_finished_spans()
+      .unwrap()
+      .into_iter()
+      .filter(|it| it.span_context.trace_id() == trace_id)
+      .collect()
+  }
+
+  pub fn span_named<'s>(spans: &'s [SpanData], name: &str) -> &'s SpanData {
+    spans
+      .iter()
+      .find(|it| it.name == name)
+      .unwrap_or_else(|| panic!("No span {}", name))
+  }
+
+  pub fn attribute(span: &SpanData, key: &str) -> Option<Value> {
+    span
+      .attributes
+      .iter()
+      .find(|it| it.key.as_str() == key)
+      .map(|it| it.value.clone())
+  }
+
+  fn trace_id(connection: &ConnectionSpan) -> TraceId {
+    connection.0.span().span_context().trace_id()
+  }
+
+  #[tokio::test]
+  async fn test_spans_of_connection_and_request() {
+    // given:
+    let exporter = in_memory_exporter();
+    let peer = "127.0.0.1:40001".parse().unwrap();
+    TELEMETRY.record_handshake(peer, Duration::from_millis(5));
+    let connection = TELEMETRY.connection_span(peer, &"https").unwrap();
+    let trace_id = trace_id(&connection);
+    let mut request = Request::post("/orders")
+      .header(HOST, "shop.localhost")
+      .body(Body::from("order"))
+      .unwrap();
+
+    // when:
+    let span = connection.request(&mut request, "shop");
+    let backend = "http://127.0.0.1:8080/".parse().unwrap();
+    RequestSpan::scope(Some(&span), async {
+      ConnectSpan::start(&backend).unwrap().finish::<(), String>(&Ok(()));
+    })
+    .await;
+    let (mut sender, body) = Body::channel();
+    let response = span.finish(Response::new(body), false);
+    sender.send_data("hello".into()).await.unwrap();
+    drop(sender);
+    hyper::body::to_bytes(response.into_body()).await.unwrap();
+    drop(connection);
+
+    // then:
+    let spans = spans_of_trace(&exporter, trace_id);
+    let connection = span_named(&spans, "connection");
+    let handshake = span_named(&spans, "tls_handshake");
+    let request_span = span_named(&spans, "POST");
+    let connect = span_named(&spans, "backend_connect");
+    assert_eq!(spans.len(), 4);
+    assert_eq!(handshake.parent_span_id, connection.span_context.span_id());
+    assert_eq!(connection.start_time, handshake.start_time);
+    assert_eq!(request_span.parent_span_id, connection.span_context.span_id());
+    assert_eq!(connect.parent_span_id, request_span.span_context.span_id());
+    assert_eq!(attribute(connection, "arlb.listener"), Some("https".into()));
+    assert_eq!(attribute(request_span, "http.request.method"), Some("POST".into()));
+    assert_eq!(attribute(request_span, "server.address"), Some("shop.localhost".into()));
+    assert_eq!(attribute(request_span, "url.path"), Some("/orders".into()));
+    assert_eq!(attribute(request_span, "arlb.pool"), Some("shop".into()));
+    assert_eq!(attribute(request_span, "http.request.body.size"), Some(5.into()));
+    assert_eq!(attribute(request_span, "http.response.status_code"), Some(200.into()));
+    assert_eq!(attribute(request_span, "http.response.body.size"), Some(5.into()));
+    assert_eq!(attribute(connect, "arlb.backend"), Some("127.0.0.1:8080".into()));
+    let traceparent = request.headers().get("traceparent").unwrap().to_str().unwrap();
+    assert_eq!(
+      traceparent,
+      format!("00-{}-{}-01", trace_id, request_span.span_context.span_id())
+    );
+  }
+
+  #[tokio::test]
+  async fn test_traceparent_of_client_becomes_parent_of_request_span() {
+    // given:
+    let exporter = in_memory_exporter();
+    let connection = TELEMETRY
+      .connection_span("127.0.0.1:40002".parse().unwrap(), &"http")
+      .unwrap();
+    let connection_span_id = connection.0.span().span_context().span_id();
+    let mut request = Request::get("/")
+      .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+      .body(Body::empty())
+      .unwrap();
+
+    // when:
+    let span = connection.request(&mut request, "shop");
+    span.finish(Response::new(Body::empty()), false);
+
+    // then:
+    let spans = spans_of_trace(
+      &exporter,
+      TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+    );
+    let request_span = span_named(&spans, "GET");
+    assert_eq!(request_span.parent_span_id.to_string(), "00f067aa0ba902b7");
+    assert!(request_span.parent_span_is_remote);
+    assert_eq!(request_span.links.links[0].span_context.span_id(), connection_span_id);
+  }
+
+  #[test]
+  fn test_connections_have_no_span_without_exporter() {
+    let telemetry = Telemetry::default();
+
+    assert!(telemetry
+      .connection_span("127.0.0.1:40003".parse().unwrap(), &"http")
+      .is_none());
+  }
+}
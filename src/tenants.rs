@@ -0,0 +1,302 @@
+use crate::{
+  error_response::{service_unavailable, too_many_requests},
+  metrics::METRICS,
+  static_response::LocalResponse,
+};
+use futures::StreamExt;
+use hyper::{
+  header::{HeaderValue, RETRY_AFTER},
+  Body, Response,
+};
+use serde_json::json;
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+use tokio::time::Instant;
+
+/// The limits of a tenant. Each of them is optional.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TenantQuotas {
+  /// How many requests of the tenant can be served at the same time, until
+  /// their response bodies were sent.
+  pub max_concurrent_requests: Option<usize>,
+  /// How many requests of the tenant are accepted within each second.
+  pub max_requests_per_sec: Option<u64>,
+  /// How many bytes of response bodies are sent to the clients of the tenant
+  /// per second. Faster responses are delayed.
+  pub max_bytes_per_sec: Option<u64>,
+}
+
+/// A group of backend pools (like of one team), whose requests share quotas,
+/// so a traffic spike of one tenant can not starve the others.
+#[derive(Debug)]
+pub struct Tenant {
+  pub name: String,
+  pub quotas: TenantQuotas,
+  active_requests: AtomicUsize,
+  /// The start of the current second and the requests accepted within it.
+  window: Mutex<(Instant, u64)>,
+  /// The bytes, which can be sent right away. Negative while responses wait.
+  bandwidth: Mutex<(Instant, f64)>,
+}
+
+/// Why a request of a tenant was rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+  Concurrency,
+  Rate,
+}
+
+impl Rejection {
+  fn as_str(self) -> &'static str {
+    match self {
+      Rejection::Concurrency => "concurrency",
+      Rejection::Rate => "rate",
+    }
+  }
+
+  /// The response to a rejected request.
+  pub fn response(self) -> Response<Body> {
+    let mut response = match self {
+      Rejection::Concurrency => service_unavailable(),
+      Rejection::Rate => {
+        let mut response = too_many_requests();
+        response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(1));
+        response
+      }
+    };
+    response.extensions_mut().insert(LocalResponse);
+    response
+  }
+}
+
+/// A request of a tenant, which counts towards its concurrent requests until
+/// it is dropped.
+#[derive(Debug)]
+pub struct TenantSlot {
+  tenant: Arc<Tenant>,
+}
+
+impl Drop for TenantSlot {
+  fn drop(&mut self) {
+    self.tenant.active_requests.fetch_sub(1, Ordering::Relaxed);
+    METRICS.add_gauge("arlb_tenant_active_requests", &[("tenant", &self.tenant.name)], -1);
+  }
+}
+
+impl Tenant {
+  pub fn new(name: String, quotas: TenantQuotas) -> Tenant {
+    let now = Instant::now();
+    Tenant {
+      name,
+      bandwidth: Mutex::new((now, quotas.max_bytes_per_sec.unwrap_or_default() as f64)),
+      quotas,
+      active_requests: AtomicUsize::new(0),
+      window: Mutex::new((now, 0)),
+    }
+  }
+
+  /// Accepts a request of this tenant, if it does not exceed its quotas.
+  pub fn admit(self: &Arc<Self>) -> Result<TenantSlot, Rejection> {
+    let labels = [("tenant", self.name.as_str())];
+    METRICS.increment("arlb_tenant_requests_total", &labels);
+    let result = self.try_admit(Instant::now());
+    if let Err(rejection) = result {
+      METRICS.increment(
+        "arlb_tenant_rejections_total",
+        &[("tenant", &self.name), ("reason", rejection.as_str())],
+      );
+    }
+    result
+  }
+
+  fn try_admit(self: &Arc<Self>, now: Instant) -> Result<TenantSlot, Rejection> {
+    let active = self.active_requests.fetch_add(1, Ordering::Relaxed);
+    METRICS.add_gauge("arlb_tenant_active_requests", &[("tenant", &self.name)], 1);
+    let slot = TenantSlot { tenant: self.clone() };
+    if matches!(self.quotas.max_concurrent_requests, Some(max) if active >= max) {
+      return Err(Rejection::Concurrency);
+    }
+    if let Some(max) = self.quotas.max_requests_per_sec {
+      let mut window = self.window.lock().unwrap();
+      if now.duration_since(window.0) >= Duration::from_secs(1) {
+        *window = (now, 0);
+      }
+      if window.1 >= max {
+        return Err(Rejection::Rate);
+      }
+      window.1 += 1;
+    }
+    Ok(slot)
+  }
+
+  /// Reserves `bytes` of the bandwidth and returns how long to wait until they
+  /// may be sent. Up to one second of bandwidth can be used at once.
+  fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+    let rate = match self.quotas.max_bytes_per_sec {
+      Some(rate) => rate as f64,
+      None => return Duration::from_secs(0),
+    };
+    let mut bandwidth = self.bandwidth.lock().unwrap();
+    let (refilled_at, available) = *bandwidth;
+    let available = (available + now.duration_since(refilled_at).as_secs_f64() * rate).min(rate) - bytes as f64;
+    *bandwidth = (now, available);
+    if available >= 0.0 {
+      Duration::from_secs(0)
+    } else {
+      Duration::from_secs_f64(-available / rate)
+    }
+  }
+
+  /// Counts the body of `response` towards the bandwidth of this tenant and
+  /// keeps the `slot` until it was sent.
+  pub fn limit_response(self: &Arc<Self>, response: Response<Body>, slot: TenantSlot) -> Response<Body> {
+    let tenant = self.clone();
+    let (parts, body) = response.into_parts();
+    let body = Body::wrap_stream(body.then(move |chunk| {
+      let _slot = &slot;
+      let tenant = tenant.clone();
+      async move {
+        if let Ok(chunk) = &chunk {
+          METRICS.add(
+            "arlb_tenant_response_bytes_total",
+            &[("tenant", &tenant.name)],
+            chunk.len() as u64,
+          );
+          let delay = tenant.reserve(chunk.len(), Instant::now());
+          if delay > Duration::from_secs(0) {
+            tokio::time::sleep(delay).await;
+          }
+        }
+        chunk
+      }
+    }));
+    Response::from_parts(parts, body)
+  }
+
+  /// Describes the current usage and the quotas for the admin API.
+  pub fn describe(&self) -> serde_json::Value {
+    let window = *self.window.lock().unwrap();
+    let requests_this_second = if window.0.elapsed() < Duration::from_secs(1) {
+      window.1
+    } else {
+      0
+    };
+    json!({
+      "name": self.name,
+      "active_requests": self.active_requests.load(Ordering::Relaxed),
+      "max_concurrent_requests": self.quotas.max_concurrent_requests,
+      "requests_this_second": requests_this_second,
+      "max_requests_per_sec": self.quotas.max_requests_per_sec,
+      "max_bytes_per_sec": self.quotas.max_bytes_per_sec,
+    })
+  }
+}
+
+/// Keeps the tenants of `old`, whose quotas did not change, so their usage is
+/// kept across reloads of the configuration.
+pub fn carry_over(old: &HashMap<String, Arc<Tenant>>, new: &mut HashMap<String, Arc<Tenant>>) {
+  for (name, tenant) in new.iter_mut() {
+    if let Some(old) = old.get(name).filter(|it| it.quotas == tenant.quotas) {
+      *tenant = old.clone();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn tenant(quotas: TenantQuotas) -> Arc<Tenant> {
+    Arc::new(Tenant::new("test".into(), quotas))
+  }
+
+  #[test]
+  fn test_concurrent_requests_are_limited() {
+    // given:
+    let tenant = tenant(TenantQuotas {
+      max_concurrent_requests: Some(2),
+      max_requests_per_sec: None,
+      max_bytes_per_sec: None,
+    });
+    let now = Instant::now();
+    let first = tenant.try_admit(now).unwrap();
+    let _second = tenant.try_admit(now).unwrap();
+
+    // when:
+    let rejected = tenant.try_admit(now);
+    drop(first);
+    let admitted = tenant.try_admit(now);
+
+    // then:
+    assert_eq!(rejected.err(), Some(Rejection::Concurrency));
+    assert!(admitted.is_ok());
+    assert_eq!(tenant.active_requests.load(Ordering::Relaxed), 2);
+  }
+
+  #[test]
+  fn test_requests_per_second_are_limited() {
+    // given:
+    let tenant = tenant(TenantQuotas {
+      max_concurrent_requests: None,
+      max_requests_per_sec: Some(2),
+      max_bytes_per_sec: None,
+    });
+    let now = Instant::now();
+
+    // when:
+    let admitted = (0..3).map(|_| tenant.try_admit(now).is_ok()).collect::<Vec<_>>();
+    let next_second = tenant.try_admit(now + Duration::from_secs(1));
+
+    // then:
+    assert_eq!(admitted, vec![true, true, false]);
+    assert!(next_second.is_ok());
+  }
+
+  #[test]
+  fn test_saturated_tenant_does_not_affect_others() {
+    // given:
+    let quotas = TenantQuotas {
+      max_concurrent_requests: Some(1),
+      max_requests_per_sec: Some(10),
+      max_bytes_per_sec: None,
+    };
+    let noisy = Arc::new(Tenant::new("noisy".into(), quotas.clone()));
+    let quiet = Arc::new(Tenant::new("quiet".into(), quotas));
+    let _busy = noisy.admit().unwrap();
+
+    // when:
+    let noisy_results = (0..20).map(|_| noisy.admit().err()).collect::<Vec<_>>();
+    let quiet_result = quiet.admit();
+
+    // then:
+    assert!(noisy_results.iter().all(|it| *it == Some(Rejection::Concurrency)));
+    assert!(quiet_result.is_ok());
+  }
+
+  #[test]
+  fn test_bandwidth_is_limited() {
+    // given:
+    let tenant = tenant(TenantQuotas {
+      max_concurrent_requests: None,
+      max_requests_per_sec: None,
+      max_bytes_per_sec: Some(1000),
+    });
+    let now = Instant::now();
+
+    // when:
+    let burst = tenant.reserve(1000, now);
+    let exceeding = tenant.reserve(500, now);
+    let later = tenant.reserve(500, now + Duration::from_secs(2));
+
+    // then:
+    assert_eq!(burst, Duration::from_secs(0));
+    assert_eq!(exceeding, Duration::from_millis(500));
+    assert_eq!(later, Duration::from_secs(0));
+  }
+}
@@ -0,0 +1,684 @@
+//! Scaffolding for end-to-end tests, which run the load balancer, its backend
+//! servers and its clients in one process, on ephemeral ports of the loopback
+//! interface. It is part of the library with the `test-util` feature, so
+//! applications embedding the load balancer can test their configurations.
+//! Since every listener is bound to port `0`, tests running in parallel (also
+//! in other processes) never compete for a port.
+//!
+//! To test how requests are distributed, [`MockBackend::http_fleet`] starts
+//! backend servers, which tell apart their responses, and
+//! [`TestConnection::distribution`] counts them.
+//!
+//! ```no_run
+//! use another_rust_load_balancer::testing::{MockBackend, MockResponse, TestConnection, TestProxy};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let backend = MockBackend::http(MockResponse::ok("hello")).await?;
+//! let proxy = TestProxy::start(&format!(
+//!   r#"
+//!   [[backend_pools]]
+//!   matcher = "Host('localhost')"
+//!   addresses = ["{}"]
+//!   schemes = ["HTTP"]
+//!   strategy = {{ RoundRobin = {{}} }}
+//!   "#,
+//!   backend.address()
+//! ))
+//! .await?;
+//! let mut connection = TestConnection::plain(proxy.http_address()).await?;
+//! let (status, body) = connection.get("localhost", "/").await?;
+//! assert_eq!((status.as_u16(), &body[..]), (200, &b"hello"[..]));
+//! proxy.stop().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{
+  drain,
+  error::Error,
+  events::{EventSubscriber, EVENTS},
+  load_balancer::LoadBalancer,
+  readiness::{BoundAddress, READINESS},
+  tls::self_signed,
+};
+use hyper::{
+  body::Bytes,
+  client::conn::{handshake, SendRequest},
+  server::conn::Http,
+  service::service_fn,
+  Body, Request, Response, StatusCode,
+};
+use log::debug;
+use once_cell::sync::Lazy;
+use openssl::x509::X509;
+use std::{
+  collections::HashMap,
+  convert::Infallible,
+  fs, io,
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  sync::{oneshot, Mutex, OwnedMutexGuard},
+};
+use tokio_rustls::{
+  rustls::{Certificate, ClientConfig},
+  webpki::DNSNameRef,
+  TlsConnector,
+};
+use tokio_util::sync::CancellationToken;
+
+/// The self-signed certificate of `localhost`, which [`TestProxy`] writes
+/// next to its configuration, so the `[certificates]` can refer to it.
+/// [`TestConnection::tls`] trusts it.
+pub const TEST_CERTIFICATE_PATH: &str = "test.cer";
+/// The private key of the [`TEST_CERTIFICATE_PATH`].
+pub const TEST_PRIVATE_KEY_PATH: &str = "test.key";
+
+struct TestCertificate {
+  certificate: Certificate,
+  certificate_pem: Vec<u8>,
+  private_key_pem: Vec<u8>,
+}
+
+/// Generating an RSA key takes a while, so all tests of a process share one.
+/// It is generated via OpenSSL like [`self_signed`] certificates elsewhere,
+/// because certificates are only loaded with PKCS#1 RSA keys, which rcgen can
+/// not generate.
+static TEST_CERTIFICATE: Lazy<TestCertificate> = Lazy::new(|| {
+  let (certificate, key) = self_signed("localhost").expect("Could not generate the test certificate");
+  TestCertificate {
+    certificate_pem: X509::from_der(&certificate.0).and_then(|it| it.to_pem()).unwrap(),
+    private_key_pem: key.rsa().and_then(|it| it.private_key_to_pem()).unwrap(),
+    certificate,
+  }
+});
+
+/// The response of a [`MockBackend`] to each HTTP request.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+  pub status: StatusCode,
+  pub headers: Vec<(String, String)>,
+  pub body: Bytes,
+  /// How long the backend server waits, before it responds.
+  pub delay: Duration,
+}
+
+impl MockResponse {
+  pub fn ok<B: Into<Bytes>>(body: B) -> MockResponse {
+    MockResponse {
+      status: StatusCode::OK,
+      headers: Vec::new(),
+      body: body.into(),
+      delay: Duration::from_secs(0),
+    }
+  }
+
+  pub fn with_status(mut self, status: StatusCode) -> MockResponse {
+    self.status = status;
+    self
+  }
+
+  pub fn with_header(mut self, name: &str, value: &str) -> MockResponse {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  pub fn with_delay(mut self, delay: Duration) -> MockResponse {
+    self.delay = delay;
+    self
+  }
+}
+
+/// What a [`MockBackend`] does with each raw TCP connection.
+#[derive(Debug, Clone)]
+pub enum TcpBehavior {
+  /// Sends back everything it receives and closes its side of the connection
+  /// once the client closed its side.
+  Echo,
+  /// Waits for the first bytes of the client and then for the `delay`, sends
+  /// the `bytes` and closes the connection cleanly, or resets it (RST), if
+  /// `reset` is set. HTTP backend servers, which fail mid-response, send the
+  /// start of an HTTP response and reset the connection.
+  Reply {
+    bytes: Vec<u8>,
+    delay: Duration,
+    reset: bool,
+  },
+}
+
+#[derive(Debug, Clone)]
+enum Behavior {
+  Http(MockResponse),
+  Tcp(TcpBehavior),
+}
+
+/// A backend server on an ephemeral port, which stops listening when it is
+/// dropped.
+#[derive(Debug)]
+pub struct MockBackend {
+  address: SocketAddr,
+  connections: Arc<AtomicUsize>,
+  requests: Arc<AtomicUsize>,
+  shutdown: CancellationToken,
+}
+
+impl MockBackend {
+  /// Answers each HTTP/1 request with the `response`.
+  pub async fn http(response: MockResponse) -> io::Result<MockBackend> {
+    MockBackend::start(Behavior::Http(response)).await
+  }
+
+  pub async fn tcp(behavior: TcpBehavior) -> io::Result<MockBackend> {
+    MockBackend::start(Behavior::Tcp(behavior)).await
+  }
+
+  /// Starts `count` HTTP backend servers, which answer each request with
+  /// their index like `backend-0`.
+  pub async fn http_fleet(count: usize) -> io::Result<Vec<MockBackend>> {
+    let mut backends = Vec::with_capacity(count);
+    for index in 0..count {
+      backends.push(MockBackend::http(MockResponse::ok(format!("backend-{}", index))).await?);
+    }
+    Ok(backends)
+  }
+
+  async fn start(behavior: Behavior) -> io::Result<MockBackend> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let backend = MockBackend {
+      address: listener.local_addr()?,
+      connections: Arc::default(),
+      requests: Arc::default(),
+      shutdown: CancellationToken::new(),
+    };
+    let connections = backend.connections.clone();
+    let requests = backend.requests.clone();
+    let shutdown = backend.shutdown.clone();
+    tokio::spawn(async move {
+      loop {
+        let socket = tokio::select! {
+          result = listener.accept() => match result {
+            Ok((socket, _)) => socket,
+            Err(_) => continue,
+          },
+          _ = shutdown.cancelled() => return,
+        };
+        connections.fetch_add(1, Ordering::Relaxed);
+        let behavior = behavior.clone();
+        let requests = requests.clone();
+        tokio::spawn(async move {
+          let result = match behavior {
+            Behavior::Http(response) => serve_http(socket, response, requests).await,
+            Behavior::Tcp(behavior) => serve_tcp(socket, behavior).await,
+          };
+          if let Err(e) = result {
+            debug!("Mock backend connection failed: {}", e);
+          }
+        });
+      }
+    });
+    Ok(backend)
+  }
+
+  pub fn address(&self) -> SocketAddr {
+    self.address
+  }
+
+  /// The connections accepted so far.
+  pub fn connections(&self) -> usize {
+    self.connections.load(Ordering::Relaxed)
+  }
+
+  /// The HTTP requests received so far, including health checks.
+  pub fn requests(&self) -> usize {
+    self.requests.load(Ordering::Relaxed)
+  }
+}
+
+/// The addresses of the `backends` as a TOML array for the `addresses` of a
+/// backend pool.
+pub fn toml_addresses(backends: &[MockBackend]) -> String {
+  let addresses = backends
+    .iter()
+    .map(|it| format!("\"{}\"", it.address()))
+    .collect::<Vec<_>>();
+  format!("[{}]", addresses.join(", "))
+}
+
+impl Drop for MockBackend {
+  fn drop(&mut self) {
+    self.shutdown.cancel();
+  }
+}
+
+async fn serve_http(socket: TcpStream, response: MockResponse, requests: Arc<AtomicUsize>) -> io::Result<()> {
+  let service = service_fn(move |request: Request<Body>| {
+    let response = response.clone();
+    requests.fetch_add(1, Ordering::Relaxed);
+    async move {
+      let _ = hyper::body::to_bytes(request.into_body()).await;
+      tokio::time::sleep(response.delay).await;
+      let mut builder = Response::builder().status(response.status);
+      for (name, value) in &response.headers {
+        builder = builder.header(name.as_str(), value.as_str());
+      }
+      Ok::<_, Infallible>(builder.body(Body::from(response.body)).unwrap())
+    }
+  });
+  Http::new()
+    .serve_connection(socket, service)
+    .await
+    .map_err(io::Error::other)
+}
+
+async fn serve_tcp(mut socket: TcpStream, behavior: TcpBehavior) -> io::Result<()> {
+  match behavior {
+    TcpBehavior::Echo => {
+      let (mut read, mut write) = socket.split();
+      tokio::io::copy(&mut read, &mut write).await?;
+      write.shutdown().await
+    }
+    TcpBehavior::Reply { bytes, delay, reset } => {
+      if socket.read(&mut [0; 1024]).await? == 0 {
+        return Ok(());
+      }
+      tokio::time::sleep(delay).await;
+      socket.write_all(&bytes).await?;
+      if reset {
+        // Without lingering, closing sends a RST instead of a FIN
+        socket.set_linger(Some(Duration::from_secs(0)))
+      } else {
+        socket.shutdown().await
+      }
+    }
+  }
+}
+
+/// Only one load balancer can run per process at a time, see [`LoadBalancer`].
+static RUNNING: Lazy<Arc<Mutex<()>>> = Lazy::new(Arc::default);
+
+/// Keeps other load balancers of this process from running, until it is
+/// dropped. Afterwards the process is no longer draining.
+pub(crate) struct Exclusive {
+  _running: OwnedMutexGuard<()>,
+}
+
+impl Drop for Exclusive {
+  fn drop(&mut self) {
+    drain::reset();
+  }
+}
+
+/// Waits until no load balancer runs in this process. Tests, which run a load
+/// balancer without a [`TestProxy`] or depend on process-wide state like
+/// [`drain::is_draining`], hold the returned guard.
+pub(crate) async fn exclusive() -> Exclusive {
+  Exclusive {
+    _running: RUNNING.clone().lock_owned().await,
+  }
+}
+
+/// A load balancer running in this process. It is shut down, when it is
+/// dropped.
+pub struct TestProxy {
+  dir: PathBuf,
+  addresses: Vec<BoundAddress>,
+  shutdown: CancellationToken,
+  stopped: Option<oneshot::Receiver<Result<(), Error>>>,
+}
+
+impl TestProxy {
+  /// Starts a load balancer with the `config` snippet, which is written to a
+  /// temporary directory next to the [`TEST_CERTIFICATE_PATH`]. Unless the
+  /// snippet configures them, the `http_address` and `https_address` are
+  /// bound to ephemeral ports and the `drain_timeout_sec` is `1`. Other
+  /// listeners should use port `0` as well. Waits until all listeners are
+  /// bound and, if another test proxy runs, until it stopped.
+  pub async fn start(config: &str) -> Result<TestProxy, Error> {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    let running = exclusive().await;
+    let run = RUNS.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("arlb-test-{}-{}", std::process::id(), run));
+    fs::create_dir_all(&dir)?;
+    let load_balancer = match create_load_balancer(&dir, config).await {
+      Ok(load_balancer) => load_balancer,
+      Err(e) => {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(e);
+      }
+    };
+    let shutdown = CancellationToken::new();
+    let (sender, stopped) = oneshot::channel();
+    tokio::spawn({
+      let shutdown = shutdown.clone();
+      async move {
+        let result = load_balancer.run_with_shutdown(shutdown).await;
+        drop(running);
+        let _ = sender.send(result);
+      }
+    });
+    let mut proxy = TestProxy {
+      dir,
+      addresses: Vec::new(),
+      shutdown,
+      stopped: Some(stopped),
+    };
+    proxy.addresses = loop {
+      if let Some(addresses) = READINESS.bound_addresses().await {
+        break addresses;
+      }
+      // The previous test proxy is recorded as stopped, until this one started
+      match proxy.stopped.as_mut().unwrap().try_recv() {
+        Ok(result) => {
+          proxy.stopped = None;
+          result?;
+          return Err(io::Error::other("The load balancer stopped before its listeners were bound").into());
+        }
+        Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+      }
+    };
+    Ok(proxy)
+  }
+
+  /// The directory of the configuration file.
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// The bound address of a `listener`, which is named like in
+  /// [`BoundAddress`].
+  pub fn address(&self, listener: &str) -> Option<SocketAddr> {
+    self
+      .addresses
+      .iter()
+      .find(|it| it.listener == listener)
+      .map(|it| it.address)
+  }
+
+  pub fn http_address(&self) -> SocketAddr {
+    self.address("http_address").expect("The http_address is not bound")
+  }
+
+  pub fn https_address(&self) -> SocketAddr {
+    self.address("https_address").expect("The https_address is not bound")
+  }
+
+  /// Subscribes to the events of the client connections from now on, see
+  /// [`LoadBalancer::events`].
+  pub fn events(&self) -> EventSubscriber {
+    EVENTS.subscribe()
+  }
+
+  /// Drains the connections like [`LoadBalancer::run_with_shutdown`] and
+  /// returns, how the load balancer stopped.
+  pub async fn stop(mut self) -> Result<(), Error> {
+    self.shutdown.cancel();
+    match self.stopped.take() {
+      Some(stopped) => stopped
+        .await
+        .unwrap_or_else(|_| Err(io::Error::other("The load balancer panicked").into())),
+      None => Ok(()),
+    }
+  }
+}
+
+impl Drop for TestProxy {
+  fn drop(&mut self) {
+    self.shutdown.cancel();
+    let _ = fs::remove_dir_all(&self.dir);
+  }
+}
+
+async fn create_load_balancer(dir: &Path, config: &str) -> Result<LoadBalancer, Error> {
+  fs::write(dir.join(TEST_CERTIFICATE_PATH), &TEST_CERTIFICATE.certificate_pem)?;
+  fs::write(dir.join(TEST_PRIVATE_KEY_PATH), &TEST_CERTIFICATE.private_key_pem)?;
+  let config_path = dir.join("config.toml");
+  fs::write(&config_path, with_test_defaults(config).map_err(Error::Config)?)?;
+  LoadBalancer::new(config_path).await
+}
+
+/// Prepends the defaults, which the `config` does not set, to it. The `config`
+/// is only parsed to find them, re-serializing it would turn inline tables of
+/// enums (like `strategy = { RoundRobin = {} }`) into sections, which can not
+/// be deserialized anymore.
+fn with_test_defaults(config: &str) -> io::Result<String> {
+  let invalid_data = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+  let table = match config.parse::<toml::Value>() {
+    Ok(toml::Value::Table(table)) => table,
+    Ok(_) => return Err(invalid_data("The configuration is not a table".to_string())),
+    Err(e) => return Err(invalid_data(e.to_string())),
+  };
+  let mut defaults = String::new();
+  for (key, default) in &[
+    ("http_address", "\"127.0.0.1:0\""),
+    ("https_address", "\"127.0.0.1:0\""),
+    ("drain_timeout_sec", "1"),
+  ] {
+    if !table.contains_key(*key) {
+      defaults.push_str(&format!("{} = {}\n", key, default));
+    }
+  }
+  Ok(defaults + config)
+}
+
+/// A client connection to a listener of the load balancer, which sends its
+/// requests one after another (with keep-alive).
+pub struct TestConnection {
+  sender: SendRequest<Body>,
+}
+
+impl TestConnection {
+  pub async fn plain(address: SocketAddr) -> io::Result<TestConnection> {
+    TestConnection::handshake(TcpStream::connect(address).await?).await
+  }
+
+  /// Connects via TLS with the `server_name`, trusting only the
+  /// [`TEST_CERTIFICATE_PATH`].
+  pub async fn tls(address: SocketAddr, server_name: &str) -> io::Result<TestConnection> {
+    let socket = TcpStream::connect(address).await?;
+    let mut client_config = ClientConfig::new();
+    client_config
+      .root_store
+      .add(&TEST_CERTIFICATE.certificate)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let server_name = DNSNameRef::try_from_ascii_str(server_name)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let tls_stream = TlsConnector::from(Arc::new(client_config))
+      .connect(server_name, socket)
+      .await?;
+    TestConnection::handshake(tls_stream).await
+  }
+
+  async fn handshake<S>(stream: S) -> io::Result<TestConnection>
+  where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+  {
+    let (sender, connection) = handshake(stream).await.map_err(io::Error::other)?;
+    tokio::spawn(connection);
+    Ok(TestConnection { sender })
+  }
+
+  pub async fn send(&mut self, request: Request<Body>) -> hyper::Result<Response<Body>> {
+    self.sender.send_request(request).await
+  }
+
+  /// Sends a `GET` request of `path` to the `host` and returns the status and
+  /// the body of the response.
+  pub async fn get(&mut self, host: &str, path: &str) -> hyper::Result<(StatusCode, Bytes)> {
+    let request = Request::builder()
+      .uri(path)
+      .header("host", host)
+      .body(Body::empty())
+      .unwrap();
+    let response = self.send(request).await?;
+    let status = response.status();
+    Ok((status, hyper::body::to_bytes(response.into_body()).await?))
+  }
+
+  /// Sends `count` `GET` requests of `path` to the `host` and counts how often
+  /// each body was received, like the names of a [`MockBackend::http_fleet`].
+  /// Failed responses are counted by their status instead.
+  pub async fn distribution(&mut self, host: &str, path: &str, count: usize) -> hyper::Result<HashMap<String, usize>> {
+    let mut distribution = HashMap::new();
+    for _ in 0..count {
+      let (status, body) = self.get(host, path).await?;
+      let key = if status.is_success() {
+        String::from_utf8_lossy(&body).into_owned()
+      } else {
+        status.as_u16().to_string()
+      };
+      *distribution.entry(key).or_default() += 1;
+    }
+    Ok(distribution)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::events::Event;
+  use tokio::time::timeout;
+
+  fn pool(backend: &MockBackend) -> String {
+    format!(
+      r#"
+      [[backend_pools]]
+      matcher = "Host('localhost')"
+      addresses = ["{}"]
+      schemes = ["HTTP", "HTTPS"]
+      strategy = {{ RoundRobin = {{}} }}
+
+      [certificates]
+      "localhost" = {{ Local = {{ certificate_path = "{}", private_key_path = "{}" }} }}
+      "#,
+      backend.address(),
+      TEST_CERTIFICATE_PATH,
+      TEST_PRIVATE_KEY_PATH
+    )
+  }
+
+  fn tcp_router(backend: &MockBackend) -> String {
+    format!(
+      r#"
+      [[tcp_router_services]]
+      listen_address = "127.0.0.1:0"
+      [[tcp_router_services.routes]]
+      addresses = ["{}"]
+      strategy = {{ RoundRobin = {{}} }}
+      "#,
+      backend.address()
+    )
+  }
+
+  /// Sends `request` via a routed TCP connection, closes the sending side and
+  /// reads until the load balancer closed the connection.
+  async fn exchange_routed(proxy: &TestProxy, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut client = TcpStream::connect(proxy.address("tcp_router_services[0]").unwrap()).await?;
+    client.write_all(request).await?;
+    client.shutdown().await?;
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut response)).await??;
+    Ok(response)
+  }
+
+  #[tokio::test]
+  async fn test_requests_share_a_kept_alive_connection() {
+    // given:
+    let backend = MockBackend::http(MockResponse::ok("hello")).await.unwrap();
+    let proxy = TestProxy::start(&pool(&backend)).await.unwrap();
+    let mut events = proxy.events();
+    let mut connection = TestConnection::plain(proxy.http_address()).await.unwrap();
+
+    // when:
+    let first = connection.get("localhost", "/first").await.unwrap();
+    let second = connection.get("localhost", "/second").await.unwrap();
+
+    // then:
+    assert_eq!((first.0, &first.1[..]), (StatusCode::OK, &b"hello"[..]));
+    assert_eq!((second.0, &second.1[..]), (StatusCode::OK, &b"hello"[..]));
+    let mut connections = Vec::new();
+    while connections.len() < 2 {
+      match timeout(Duration::from_secs(5), events.recv()).await.unwrap() {
+        Event::RequestCompleted { summary } if summary.host == "localhost" => connections.push(summary.connection),
+        _ => {}
+      }
+    }
+    assert_eq!(connections[0], connections[1]);
+    proxy.stop().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_tls_is_terminated_with_the_test_certificate() {
+    // given:
+    let backend = MockBackend::http(MockResponse::ok("over tls")).await.unwrap();
+    let proxy = TestProxy::start(&pool(&backend)).await.unwrap();
+
+    // when:
+    let mut connection = TestConnection::tls(proxy.https_address(), "localhost").await.unwrap();
+    let (status, body) = connection.get("localhost", "/").await.unwrap();
+
+    // then:
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(&body[..], b"over tls");
+    proxy.stop().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_routed_connections_are_piped_until_both_sides_closed() {
+    // given:
+    let backend = MockBackend::tcp(TcpBehavior::Echo).await.unwrap();
+    let proxy = TestProxy::start(&tcp_router(&backend)).await.unwrap();
+
+    // when:
+    let response = exchange_routed(&proxy, b"ping").await.unwrap();
+
+    // then:
+    assert_eq!(response, b"ping");
+    assert_eq!(backend.connections(), 1);
+    proxy.stop().await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_backend_reset_closes_routed_connections_after_the_data_sent_before() {
+    // given:
+    let backend = MockBackend::tcp(TcpBehavior::Reply {
+      bytes: b"partial".to_vec(),
+      delay: Duration::from_millis(10),
+      reset: true,
+    })
+    .await
+    .unwrap();
+    let proxy = TestProxy::start(&tcp_router(&backend)).await.unwrap();
+
+    // when:
+    let response = exchange_routed(&proxy, b"ping").await.unwrap();
+
+    // then: the client sees a clean close
+    assert_eq!(response, b"partial");
+    proxy.stop().await.unwrap();
+  }
+
+  #[test]
+  fn test_defaults_keep_inline_tables() {
+    let config = with_test_defaults("drain_timeout_sec = 5\nstrategy = { RoundRobin = {} }").unwrap();
+
+    assert_eq!(
+      config,
+      "http_address = \"127.0.0.1:0\"\nhttps_address = \"127.0.0.1:0\"\ndrain_timeout_sec = 5\nstrategy = { RoundRobin = {} }"
+    );
+  }
+
+  #[tokio::test]
+  async fn test_invalid_config_snippets_are_rejected() {
+    let error = TestProxy::start("backend_pools = 1").await.err().unwrap();
+
+    assert!(matches!(error, Error::Config(_)), "{:?}", error);
+  }
+}
@@ -0,0 +1,152 @@
+use std::{
+  future::Future,
+  io,
+  pin::Pin,
+  task::{Context, Poll},
+  time::Duration,
+};
+use tokio::{
+  io::AsyncWrite,
+  time::{sleep_until, Instant, Sleep},
+};
+
+/// Allows `rate` bytes per second. Up to a tenth of a second of the rate can
+/// be sent at once, so a throttled connection does not send in large bursts.
+#[derive(Debug)]
+struct TokenBucket {
+  rate: f64,
+  burst: f64,
+  tokens: f64,
+  refilled_at: Instant,
+}
+
+impl TokenBucket {
+  fn new(rate: u64, now: Instant) -> TokenBucket {
+    let burst = (rate as f64 / 10.0).max(1.0);
+    TokenBucket {
+      rate: rate as f64,
+      burst,
+      tokens: burst,
+      refilled_at: now,
+    }
+  }
+
+  /// How many of `wanted` bytes can be sent right away. Otherwise returns when
+  /// enough tokens for the next write are available.
+  fn available(&mut self, wanted: usize, now: Instant) -> Result<usize, Instant> {
+    self.tokens = (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * self.rate).min(self.burst);
+    self.refilled_at = now;
+    let needed = (wanted as f64).min(self.burst);
+    if self.tokens >= needed {
+      Ok((self.tokens as usize).min(wanted))
+    } else {
+      Err(now + Duration::from_secs_f64((needed - self.tokens) / self.rate))
+    }
+  }
+
+  fn consume(&mut self, bytes: usize) {
+    self.tokens -= bytes as f64;
+  }
+}
+
+/// Limits the bytes per second written to `inner`. Writes are delayed rather
+/// than rejected, so no data is lost.
+pub struct Throttled<W> {
+  inner: W,
+  bucket: Option<TokenBucket>,
+  delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W> Throttled<W> {
+  /// Writes to `inner` at up to `max_bytes_per_sec`, or unlimited with `None`.
+  pub fn new(inner: W, max_bytes_per_sec: Option<u64>) -> Throttled<W> {
+    Throttled {
+      inner,
+      bucket: max_bytes_per_sec.map(|rate| TokenBucket::new(rate, Instant::now())),
+      delay: None,
+    }
+  }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttled<W> {
+  fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    let this = &mut *self;
+    let bucket = match &mut this.bucket {
+      Some(bucket) => bucket,
+      None => return Pin::new(&mut this.inner).poll_write(cx, buf),
+    };
+    loop {
+      if let Some(delay) = &mut this.delay {
+        futures::ready!(delay.as_mut().poll(cx));
+        this.delay = None;
+      }
+      match bucket.available(buf.len(), Instant::now()) {
+        Ok(len) => {
+          let result = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]));
+          if let Ok(written) = &result {
+            bucket.consume(*written);
+          }
+          return Poll::Ready(result);
+        }
+        Err(until) => this.delay = Some(Box::pin(sleep_until(until))),
+      }
+    }
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.inner).poll_shutdown(cx)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  #[test]
+  fn test_token_bucket_delays_writes_beyond_the_rate() {
+    // given:
+    let now = Instant::now();
+    let mut bucket = TokenBucket::new(1000, now);
+
+    // when:
+    let burst = bucket.available(500, now);
+    bucket.consume(100);
+    let exhausted = bucket.available(500, now);
+    let refilled = bucket.available(500, now + Duration::from_millis(100));
+
+    // then:
+    assert_eq!(burst, Ok(100));
+    assert_eq!(exhausted, Err(now + Duration::from_millis(100)));
+    assert_eq!(refilled, Ok(100));
+  }
+
+  #[tokio::test]
+  async fn test_capped_transfer_takes_the_expected_time() {
+    // given:
+    let (client, mut server) = tokio::io::duplex(64 * 1024);
+    let mut throttled = Throttled::new(client, Some(100_000));
+    let payload = vec![7; 100_000];
+    let start = std::time::Instant::now();
+
+    // when:
+    let write = async {
+      throttled.write_all(&payload).await.unwrap();
+      throttled.shutdown().await.unwrap();
+    };
+    let mut received = Vec::new();
+    let read = server.read_to_end(&mut received);
+    let (_, read) = tokio::join!(write, read);
+    let elapsed = start.elapsed();
+
+    // then: a tenth of a second is sent right away
+    read.unwrap();
+    assert_eq!(received, payload);
+    assert!(elapsed >= Duration::from_millis(850), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+  }
+}
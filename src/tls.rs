@@ -1,20 +1,548 @@
+use crate::{configuration::TlsClientAuthConfig, metrics::METRICS};
 use arc_swap::access::Access;
+use log::{debug, warn};
+use openssl::{
+  asn1::Asn1Time,
+  bn::BigNum,
+  error::ErrorStack,
+  hash::MessageDigest,
+  nid::Nid,
+  pkey::{PKey, Private},
+  rand::rand_bytes,
+  rsa::Rsa,
+  symm::{decrypt_aead, encrypt_aead, Cipher},
+  x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder, X509},
+};
+use serde::Deserialize;
 use std::{
   collections::HashMap,
+  convert::TryInto,
+  fmt,
   fs::File,
   io::{self, BufReader, ErrorKind::InvalidData},
   path::Path,
   sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio_rustls::{
-  rustls::{
-    internal::pemfile::{certs, rsa_private_keys},
-    sign::{CertifiedKey, RSASigningKey},
-    Certificate, ClientHello, PrivateKey, ResolvesServerCert,
-  },
-  webpki::DNSName,
+use tokio_rustls::rustls::{
+  internal::pemfile::{certs, rsa_private_keys},
+  sign::{CertifiedKey, RSASigningKey},
+  AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, ClientHello, NoClientAuth,
+  NoServerSessionStorage, PrivateKey, ProducesTickets, ProtocolVersion, ResolvesServerCert, RootCertStore,
+  ServerConfig, ServerSession, ServerSessionMemoryCache, Session, StoresServerSessions, SupportedCipherSuite,
+  ALL_CIPHERSUITES,
 };
 
+/// Details of the TLS session of a client, which are available after the
+/// handshake.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TlsInfo {
+  /// The server name sent by the client via SNI.
+  pub server_name: Option<String>,
+  /// The common name of the subject of the client certificate, if the client
+  /// authenticated itself.
+  pub client_common_name: Option<String>,
+  /// The negotiated protocol version, like `TLSv1.3`.
+  pub protocol_version: Option<String>,
+  /// The negotiated cipher suite, named like in the `cipher_suites` of `[tls]`.
+  pub cipher_suite: Option<String>,
+  /// The client certificate in PEM, if the client authenticated itself.
+  pub client_certificate: Option<String>,
+  /// The intermediate certificates, which the client sent after its
+  /// certificate, in PEM.
+  pub client_certificate_chain: Vec<String>,
+}
+
+impl TlsInfo {
+  pub fn from_session(session: &ServerSession) -> TlsInfo {
+    let peer_certificates = session.get_peer_certificates().unwrap_or_default();
+    let client_certificate = peer_certificates.first();
+    TlsInfo {
+      server_name: session.get_sni_hostname().map(str::to_string),
+      client_common_name: client_certificate.and_then(common_name),
+      protocol_version: session.get_protocol_version().map(protocol_version_name),
+      cipher_suite: session.get_negotiated_ciphersuite().map(|it| format!("{:?}", it.suite)),
+      client_certificate: client_certificate.and_then(pem),
+      client_certificate_chain: peer_certificates.iter().skip(1).filter_map(pem).collect(),
+    }
+  }
+}
+
+fn protocol_version_name(version: ProtocolVersion) -> String {
+  match version {
+    ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+    ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+    other => format!("{:?}", other),
+  }
+}
+
+fn pem(certificate: &Certificate) -> Option<String> {
+  let certificate = X509::from_der(&certificate.0).ok()?;
+  String::from_utf8(certificate.to_pem().ok()?).ok()
+}
+
+fn common_name(certificate: &Certificate) -> Option<String> {
+  let certificate = X509::from_der(&certificate.0).ok()?;
+  let entry = certificate.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+  entry.data().as_utf8().ok().map(|it| it.to_string())
+}
+
+/// The protocol versions and cipher suites of the HTTPS listener.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+  pub versions: Vec<ProtocolVersion>,
+  pub cipher_suites: Vec<&'static SupportedCipherSuite>,
+  /// Session tickets are disabled if this is `None`.
+  pub session_tickets: Option<SessionTicketConfig>,
+  /// The number of sessions kept in memory for resumption via session ids,
+  /// `0` disables the cache.
+  pub session_cache_size: usize,
+  pub sni_host_check: SniHostCheck,
+  pub handshake_limits: HandshakeLimits,
+  /// Offers HTTP/2 to clients via ALPN.
+  pub http2: bool,
+}
+
+impl Default for TlsConfig {
+  fn default() -> Self {
+    TlsConfig {
+      versions: vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+      cipher_suites: ALL_CIPHERSUITES.to_vec(),
+      session_tickets: None,
+      session_cache_size: 256,
+      sni_host_check: SniHostCheck::default(),
+      handshake_limits: HandshakeLimits::default(),
+      http2: false,
+    }
+  }
+}
+
+/// Protects the HTTPS listener against clients, which flood it with
+/// handshakes or stall them, because handshakes are much more expensive than
+/// accepting a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeLimits {
+  /// Connections, whose handshake takes longer, are closed.
+  pub timeout: Duration,
+  /// The number of handshakes in progress at the same time.
+  pub max_in_progress: usize,
+  /// What happens to connections, while `max_in_progress` handshakes are in
+  /// progress.
+  pub excess: ExcessHandshakes,
+  /// The number of handshakes a single IP address may start per window.
+  pub per_client: Option<HandshakeRateLimit>,
+}
+
+impl Default for HandshakeLimits {
+  fn default() -> Self {
+    HandshakeLimits {
+      timeout: Duration::from_secs(10),
+      max_in_progress: 1024,
+      excess: ExcessHandshakes::default(),
+      per_client: None,
+    }
+  }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExcessHandshakes {
+  /// No further connections are accepted, until a handshake completes.
+  #[default]
+  Wait,
+  /// Further connections are accepted and closed right away.
+  Reject,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HandshakeRateLimit {
+  pub limit: u64,
+  pub window: Duration,
+}
+
+/// How requests are handled, whose host differs from the server name the
+/// client sent via SNI during the handshake. Without a check a client could
+/// complete the handshake for one virtual host and then reach the backend pool
+/// of another one.
+#[derive(Debug, Default, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum SniHostCheck {
+  /// Requests are routed by their host regardless of the server name.
+  #[default]
+  RouteByHost,
+  /// Mismatches are logged, but requests are still routed by their host.
+  LogOnly,
+  /// Mismatches are rejected with `421 Misdirected Request`.
+  Strict,
+}
+
+/// Whether the `host` of a request (optionally with a port) refers to the
+/// `server_name` sent via SNI. DNS names are compared case-insensitively.
+pub fn host_matches_server_name(host: &str, server_name: &str) -> bool {
+  let host = host.rsplitn(2, ':').last().unwrap_or(host);
+  host
+    .trim_end_matches('.')
+    .eq_ignore_ascii_case(server_name.trim_end_matches('.'))
+}
+
+/// Reads the server name of the SNI extension from the first TLS record of a
+/// connection, if it contains a ClientHello. Records, which were cut off after
+/// the server name, are fine.
+pub fn client_hello_server_name(record: &[u8]) -> Option<String> {
+  let mut reader = Reader(record);
+  // A handshake record of any version
+  if reader.u8()? != 0x16 || reader.u8()? != 0x03 {
+    return None;
+  }
+  reader.skip(3)?; // minor version and length of the record
+  if reader.u8()? != 0x01 {
+    return None; // not a ClientHello
+  }
+  reader.skip(3 + 2 + 32)?; // length, version and random
+  let session_id_length = reader.u8()? as usize;
+  reader.skip(session_id_length)?;
+  let cipher_suites_length = reader.u16()? as usize;
+  reader.skip(cipher_suites_length)?;
+  let compression_methods_length = reader.u8()? as usize;
+  reader.skip(compression_methods_length)?;
+  reader.skip(2)?; // length of the extensions
+  loop {
+    let extension_type = reader.u16()?;
+    let extension_length = reader.u16()? as usize;
+    if extension_type != 0x0000 {
+      reader.skip(extension_length)?;
+      continue;
+    }
+    reader.skip(2)?; // length of the server name list
+    if reader.u8()? != 0x00 {
+      return None; // not a host name
+    }
+    let name_length = reader.u16()? as usize;
+    let name = reader.take(name_length)?;
+    return String::from_utf8(name.to_vec()).ok();
+  }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+    if self.0.len() < count {
+      return None;
+    }
+    let (taken, rest) = self.0.split_at(count);
+    self.0 = rest;
+    Some(taken)
+  }
+
+  fn skip(&mut self, count: usize) -> Option<()> {
+    self.take(count).map(|_| ())
+  }
+
+  fn u8(&mut self) -> Option<u8> {
+    self.take(1).map(|it| it[0])
+  }
+
+  fn u16(&mut self) -> Option<u16> {
+    self.take(2).map(|it| u16::from_be_bytes([it[0], it[1]]))
+  }
+}
+
+impl TlsConfig {
+  /// Parses a minimum protocol version like `"1.2"`.
+  pub fn versions_from(min_version: &str) -> Result<Vec<ProtocolVersion>, io::Error> {
+    match min_version {
+      "1.2" => Ok(vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]),
+      "1.3" => Ok(vec![ProtocolVersion::TLSv1_3]),
+      _ => Err(io::Error::new(
+        InvalidData,
+        format!(
+          "Unsupported TLS min_version '{}', expected \"1.2\" or \"1.3\"",
+          min_version
+        ),
+      )),
+    }
+  }
+
+  /// Looks up cipher suites by their IANA name like
+  /// `"TLS13_AES_256_GCM_SHA384"` and checks that each protocol version can
+  /// use at least one of them.
+  pub fn cipher_suites_from(
+    names: &[String],
+    versions: &[ProtocolVersion],
+  ) -> Result<Vec<&'static SupportedCipherSuite>, io::Error> {
+    if names.is_empty() {
+      return Err(io::Error::new(InvalidData, "The list of TLS cipher_suites is empty"));
+    }
+    let cipher_suites = names
+      .iter()
+      .map(|name| {
+        ALL_CIPHERSUITES
+          .iter()
+          .copied()
+          .find(|suite| format!("{:?}", suite.suite) == *name)
+          .ok_or_else(|| {
+            let supported: Vec<_> = ALL_CIPHERSUITES.iter().map(|it| format!("{:?}", it.suite)).collect();
+            io::Error::new(
+              InvalidData,
+              format!(
+                "Unsupported TLS cipher suite '{}', expected one of: {}",
+                name,
+                supported.join(", ")
+              ),
+            )
+          })
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    for version in versions {
+      if !cipher_suites.iter().any(|suite| suite.usable_for_version(*version)) {
+        return Err(io::Error::new(
+          InvalidData,
+          format!("None of the TLS cipher_suites can be used for {:?}", version),
+        ));
+      }
+    }
+    Ok(cipher_suites)
+  }
+}
+
+/// A key to encrypt session tickets with AES-256-GCM.
+#[derive(Clone, PartialEq)]
+pub struct TicketKey([u8; 32]);
+
+impl fmt::Debug for TicketKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // Never log the secret key
+    f.write_str("TicketKey(..)")
+  }
+}
+
+impl TicketKey {
+  fn random() -> TicketKey {
+    let mut key = [0; 32];
+    rand_bytes(&mut key).expect("Generating a random ticket key should not fail");
+    TicketKey(key)
+  }
+
+  /// Reads the concatenated raw 32 byte keys in `path`, for example generated
+  /// by `openssl rand 32`.
+  pub fn read_all<P: AsRef<Path>>(path: P) -> Result<Vec<TicketKey>, io::Error> {
+    let bytes = std::fs::read(&path).map_err(|e| {
+      io::Error::new(
+        e.kind(),
+        format!("Could not read '{}' due to: {}", path.as_ref().display(), e),
+      )
+    })?;
+    if bytes.is_empty() || bytes.len() % 32 != 0 {
+      return Err(io::Error::new(
+        InvalidData,
+        format!(
+          "Invalid ticket key file '{}': expected a multiple of 32 bytes, but got {}",
+          path.as_ref().display(),
+          bytes.len()
+        ),
+      ));
+    }
+    Ok(bytes.chunks(32).map(|it| TicketKey(it.try_into().unwrap())).collect())
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionTicketConfig {
+  pub lifetime: Duration,
+  /// The first key encrypts new tickets, all keys decrypt tickets. If no keys
+  /// are configured, a random key is used, so tickets can only be resumed by
+  /// the same process.
+  pub keys: Vec<TicketKey>,
+}
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Encrypts session tickets with the keys of the current configuration, so
+/// enabling, disabling and rotating keys takes effect on reload. Instances
+/// sharing the same keys can resume each others sessions.
+pub struct ReconfigurableTicketer<A>
+where
+  A: Access<Option<SessionTicketConfig>>,
+{
+  config: A,
+  random_key: TicketKey,
+}
+
+impl<A> ReconfigurableTicketer<A>
+where
+  A: Access<Option<SessionTicketConfig>>,
+{
+  pub fn new(config: A) -> ReconfigurableTicketer<A> {
+    ReconfigurableTicketer {
+      config,
+      random_key: TicketKey::random(),
+    }
+  }
+}
+
+fn unix_time() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|it| it.as_secs())
+    .unwrap_or_default()
+}
+
+/// A ticket consists of the nonce, the encrypted time of issue and session
+/// state, and the authentication tag.
+fn encrypt_ticket(key: &TicketKey, plain: &[u8]) -> Option<Vec<u8>> {
+  let mut nonce = [0; NONCE_LEN];
+  rand_bytes(&mut nonce).ok()?;
+  let mut data = unix_time().to_be_bytes().to_vec();
+  data.extend_from_slice(plain);
+  let mut tag = [0; TAG_LEN];
+  let encrypted = encrypt_aead(Cipher::aes_256_gcm(), &key.0, Some(&nonce), &[], &data, &mut tag).ok()?;
+  let mut ticket = nonce.to_vec();
+  ticket.extend(encrypted);
+  ticket.extend_from_slice(&tag);
+  Some(ticket)
+}
+
+fn decrypt_ticket(key: &TicketKey, lifetime: Duration, ticket: &[u8]) -> Option<Vec<u8>> {
+  if ticket.len() < NONCE_LEN + 8 + TAG_LEN {
+    return None;
+  }
+  let (nonce, rest) = ticket.split_at(NONCE_LEN);
+  let (encrypted, tag) = rest.split_at(rest.len() - TAG_LEN);
+  let data = decrypt_aead(Cipher::aes_256_gcm(), &key.0, Some(nonce), &[], encrypted, tag).ok()?;
+  let (issued_at, plain) = data.split_at(8);
+  let issued_at = u64::from_be_bytes(issued_at.try_into().ok()?);
+  if unix_time().saturating_sub(issued_at) > lifetime.as_secs() {
+    return None;
+  }
+  Some(plain.to_vec())
+}
+
+impl<A> ProducesTickets for ReconfigurableTicketer<A>
+where
+  A: Access<Option<SessionTicketConfig>> + Send + Sync,
+{
+  fn enabled(&self) -> bool {
+    self.config.load().is_some()
+  }
+
+  fn get_lifetime(&self) -> u32 {
+    let config = self.config.load();
+    let lifetime = config.as_ref().map(|it| it.lifetime.as_secs()).unwrap_or_default();
+    lifetime.try_into().unwrap_or(u32::MAX)
+  }
+
+  fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+    let config = self.config.load();
+    let config = config.as_ref()?;
+    let key = config.keys.first().unwrap_or(&self.random_key);
+    let ticket = encrypt_ticket(key, plain);
+    if ticket.is_none() {
+      warn!("Could not encrypt session ticket");
+    }
+    ticket
+  }
+
+  fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+    let config = self.config.load();
+    let config = config.as_ref()?;
+    let random_key = [self.random_key.clone()];
+    let keys = if config.keys.is_empty() {
+      &random_key[..]
+    } else {
+      &config.keys[..]
+    };
+    let plain = keys.iter().find_map(|key| decrypt_ticket(key, config.lifetime, cipher));
+    record_resumption("ticket", plain.is_some());
+    plain
+  }
+}
+
+fn record_resumption(mechanism: &str, resumed: bool) {
+  let result = if resumed { "hit" } else { "miss" };
+  METRICS.increment(
+    "arlb_tls_session_resumptions_total",
+    &[("mechanism", mechanism), ("result", result)],
+  );
+}
+
+/// Keeps sessions in memory, so clients can resume them via their session id
+/// (TLS 1.2) or a ticket referring to the cached session (TLS 1.3).
+pub struct MeasuredSessionCache {
+  cache: Arc<ServerSessionMemoryCache>,
+}
+
+impl MeasuredSessionCache {
+  pub fn new(size: usize) -> MeasuredSessionCache {
+    MeasuredSessionCache {
+      cache: ServerSessionMemoryCache::new(size),
+    }
+  }
+}
+
+impl StoresServerSessions for MeasuredSessionCache {
+  fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+    self.cache.put(key, value)
+  }
+
+  fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+    let value = self.cache.get(key);
+    record_resumption("cache", value.is_some());
+    value
+  }
+
+  fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+    let value = self.cache.take(key);
+    record_resumption("cache", value.is_some());
+    value
+  }
+}
+
+/// Creates the TLS configuration of the HTTPS listener, which verifies client
+/// certificates against the CA certificates of `client_auth`. Without
+/// `client_auth` clients are not asked for a certificate.
+pub fn server_config(client_auth: Option<&TlsClientAuthConfig>, tls: &TlsConfig) -> Result<ServerConfig, io::Error> {
+  let mut config = client_auth_config(client_auth)?;
+  config.versions = tls.versions.clone();
+  config.ciphersuites = tls.cipher_suites.clone();
+  config.session_storage = if tls.session_cache_size == 0 {
+    Arc::new(NoServerSessionStorage {})
+  } else {
+    Arc::new(MeasuredSessionCache::new(tls.session_cache_size))
+  };
+  if tls.http2 {
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+  }
+  Ok(config)
+}
+
+fn client_auth_config(client_auth: Option<&TlsClientAuthConfig>) -> Result<ServerConfig, io::Error> {
+  let client_auth = match client_auth {
+    Some(client_auth) => client_auth,
+    None => return Ok(ServerConfig::new(NoClientAuth::new())),
+  };
+  let mut roots = RootCertStore::empty();
+  for certificate in load_certs(&client_auth.ca_certificate_path)? {
+    roots.add(&certificate).map_err(|e| {
+      io::Error::new(
+        InvalidData,
+        format!(
+          "Invalid CA certificate in '{}': {}",
+          client_auth.ca_certificate_path.display(),
+          e
+        ),
+      )
+    })?;
+  }
+  if client_auth.required {
+    Ok(ServerConfig::new(AllowAnyAuthenticatedClient::new(roots)))
+  } else {
+    Ok(ServerConfig::new(AllowAnyAnonymousOrAuthenticatedClient::new(roots)))
+  }
+}
+
+#[cfg(feature = "acme")]
 pub fn certified_key_from_acme_certificate(certificate: acme_lib::Certificate) -> Result<CertifiedKey, io::Error> {
   let certificates =
     certs(&mut certificate.certificate().as_bytes()).map_err(|_| io::Error::new(InvalidData, "Invalid certificate"))?;
@@ -23,13 +551,59 @@ pub fn certified_key_from_acme_certificate(certificate: acme_lib::Certificate) -
   Ok(CertifiedKey::new(certificates, Arc::new(Box::new(private_key))))
 }
 
+/// Creates a certificate for `common_name`, which is valid for a day and
+/// signed by its own RSA key, like for tests.
+pub fn self_signed(common_name: &str) -> Result<(Certificate, PKey<Private>), ErrorStack> {
+  let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+  let mut name = X509NameBuilder::new()?;
+  name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "arlb")?;
+  name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+  let name = name.build();
+  let mut builder = X509Builder::new()?;
+  builder.set_version(2)?;
+  let serial_number = BigNum::from_u32(1)?.to_asn1_integer()?;
+  builder.set_serial_number(&serial_number)?;
+  let not_before = Asn1Time::days_from_now(0)?;
+  let not_after = Asn1Time::days_from_now(1)?;
+  builder.set_not_before(&not_before)?;
+  builder.set_not_after(&not_after)?;
+  builder.set_subject_name(&name)?;
+  builder.set_issuer_name(&name)?;
+  builder.set_pubkey(&key)?;
+  let subject_alternative_name = SubjectAlternativeName::new()
+    .dns(common_name)
+    .build(&builder.x509v3_context(None, None))?;
+  builder.append_extension(subject_alternative_name)?;
+  builder.sign(&key, MessageDigest::sha256())?;
+  Ok((Certificate(builder.build().to_der()?), key))
+}
+
+/// Loads the certificate chain of `certificate_path` and the key of
+/// `private_key_path`. Both can be the same PEM file, which contains the
+/// certificates as well as the key.
 pub fn load_certified_key<P1, P2>(certificate_path: P1, private_key_path: P2) -> Result<CertifiedKey, io::Error>
 where
   P1: AsRef<Path>,
   P2: AsRef<Path>,
 {
-  let certificates = load_certs(certificate_path)?;
+  let certificates = load_certs(&certificate_path)?;
+  if certificates.is_empty() {
+    return Err(io::Error::new(
+      InvalidData,
+      format!("No certificate in '{}'", certificate_path.as_ref().display()),
+    ));
+  }
   let private_key = load_key(&private_key_path)?;
+  if !key_matches_certificate(&private_key, certificates.first()) {
+    return Err(io::Error::new(
+      InvalidData,
+      format!(
+        "The key in '{}' does not belong to the certificate in '{}'",
+        private_key_path.as_ref().display(),
+        certificate_path.as_ref().display()
+      ),
+    ));
+  }
   let private_key = RSASigningKey::new(&private_key).map_err(|_| {
     io::Error::new(
       InvalidData,
@@ -39,6 +613,20 @@ where
   Ok(CertifiedKey::new(certificates, Arc::new(Box::new(private_key))))
 }
 
+/// Checks that `private_key` belongs to the public key of the end-entity
+/// `certificate`, which is otherwise only noticed by clients failing the
+/// handshake.
+fn key_matches_certificate(private_key: &PrivateKey, certificate: Option<&Certificate>) -> bool {
+  let private_key = match Rsa::private_key_from_der(&private_key.0).and_then(PKey::from_rsa) {
+    Ok(private_key) => private_key,
+    Err(_) => return false,
+  };
+  match certificate.map(|it| X509::from_der(&it.0).and_then(|it| it.public_key())) {
+    Some(Ok(public_key)) => public_key.public_eq(&private_key),
+    _ => false,
+  }
+}
+
 fn load_certs<P>(path: P) -> io::Result<Vec<Certificate>>
 where
   P: AsRef<Path>,
@@ -62,7 +650,13 @@ fn load_key<P>(path: P) -> io::Result<PrivateKey>
 where
   P: AsRef<Path>,
 {
-  let mut keys = load_keys(path)?;
+  let mut keys = load_keys(&path)?;
+  if keys.is_empty() {
+    return Err(io::Error::new(
+      InvalidData,
+      format!("No RSA key in '{}'", path.as_ref().display()),
+    ));
+  }
   Ok(keys.remove(0))
 }
 
@@ -70,22 +664,209 @@ fn load_keys<P>(path: P) -> io::Result<Vec<PrivateKey>>
 where
   P: AsRef<Path>,
 {
-  let file = File::open(&path)?;
+  let file = File::open(&path).map_err(|e| {
+    io::Error::new(
+      e.kind(),
+      format!("Could not open '{}' due to: {}", path.as_ref().display(), e),
+    )
+  })?;
   let mut reader = BufReader::new(file);
   rsa_private_keys(&mut reader)
     .map_err(|_| io::Error::new(InvalidData, format!("Invalid RSA key in '{}'", path.as_ref().display())))
 }
 
+/// A certificate chain with its key, which was configured as `name`.
+struct NamedCertificate {
+  name: String,
+  certified_key: CertifiedKey,
+}
+
+/// How the certificate for a server name was selected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CertificateMatch {
+  Exact,
+  Wildcard,
+  Default,
+}
+
+/// The certificates of a listener, indexed by the DNS names of the subject
+/// alternative names of their end-entity certificate (and the name they are
+/// configured as). An exact name beats a wildcard like `*.example.com`, which
+/// beats the default certificate. No two certificates may claim the same
+/// name, so the selection does not depend on the order of the configuration.
+#[derive(Default)]
+pub struct Certificates {
+  all: Vec<Arc<NamedCertificate>>,
+  exact: HashMap<String, Arc<NamedCertificate>>,
+  /// Keyed by the parent domain of the wildcard, `example.com` for
+  /// `*.example.com`.
+  wildcards: HashMap<String, Arc<NamedCertificate>>,
+  default: Option<Arc<NamedCertificate>>,
+}
+
+impl fmt::Debug for Certificates {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_list().entries(self.all.iter().map(|it| &it.name)).finish()
+  }
+}
+
+fn normalize_dns_name(name: &str) -> String {
+  name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The DNS names of the subject alternative names of the end-entity
+/// certificate of `certified_key`.
+fn subject_alternative_dns_names(certified_key: &CertifiedKey) -> Vec<String> {
+  let certificate = certified_key
+    .end_entity_cert()
+    .ok()
+    .and_then(|it| X509::from_der(&it.0).ok());
+  certificate
+    .and_then(|it| it.subject_alt_names())
+    .map(|names| {
+      names
+        .iter()
+        .filter_map(|it| it.dnsname().map(normalize_dns_name))
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Whether one of the `dns_names` of a certificate is the `name` or a
+/// wildcard, which covers it. A wildcard only covers a single label, so
+/// `*.example.com` covers `www.example.com`, but neither `example.com` nor
+/// `a.b.example.com`.
+fn covers(dns_names: &[String], name: &str) -> bool {
+  dns_names.iter().any(|dns_name| {
+    dns_name == name
+      || match (dns_name.strip_prefix("*."), name.split_once('.')) {
+        (Some(parent), Some((label, rest))) => rest == parent && !label.is_empty() && !label.contains('*'),
+        _ => false,
+      }
+  })
+}
+
+impl Certificates {
+  /// Adds the certificate configured as `name`, which has to be one of the
+  /// DNS names it is valid for (or covered by one of its wildcards). Fails,
+  /// if another certificate claims one of its names.
+  pub fn insert(&mut self, name: &str, certified_key: CertifiedKey) -> io::Result<()> {
+    let mut names = subject_alternative_dns_names(&certified_key);
+    if !covers(&names, &normalize_dns_name(name)) {
+      return Err(io::Error::new(
+        InvalidData,
+        format!("The certificate '{}' is only valid for {:?}", name, names),
+      ));
+    }
+    names.sort();
+    names.dedup();
+    let mut exact = Vec::new();
+    let mut wildcards = Vec::new();
+    for dns_name in names {
+      match dns_name.strip_prefix("*.") {
+        Some(parent) => wildcards.push(parent.to_string()),
+        // Partial wildcards like `w*.example.com` are not supported by clients
+        None if dns_name.contains('*') => {}
+        None => exact.push(dns_name),
+      }
+    }
+    let claimed =
+      |index: &HashMap<String, Arc<NamedCertificate>>, dns_name: &str, display: String| match index.get(dns_name) {
+        Some(other) => Err(io::Error::new(
+          InvalidData,
+          format!(
+            "The certificate '{}' claims {}, like the certificate '{}'",
+            name, display, other.name
+          ),
+        )),
+        None => Ok(()),
+      };
+    for dns_name in &exact {
+      claimed(&self.exact, dns_name, format!("'{}'", dns_name))?;
+    }
+    for parent in &wildcards {
+      claimed(&self.wildcards, parent, format!("'*.{}'", parent))?;
+    }
+    let certificate = Arc::new(NamedCertificate {
+      name: name.to_string(),
+      certified_key,
+    });
+    for dns_name in exact {
+      self.exact.insert(dns_name, certificate.clone());
+    }
+    for parent in wildcards {
+      self.wildcards.insert(parent, certificate.clone());
+    }
+    self.all.push(certificate);
+    Ok(())
+  }
+
+  /// Serves the certificate configured as `name` to clients, whose server
+  /// name (if any) matches no certificate. Without it, a single certificate
+  /// is the default.
+  pub fn set_default(&mut self, name: &str) -> io::Result<()> {
+    match self.all.iter().find(|it| it.name == name) {
+      Some(certificate) => {
+        self.default = Some(certificate.clone());
+        Ok(())
+      }
+      None => Err(io::Error::new(
+        InvalidData,
+        format!("The default certificate '{}' is not configured", name),
+      )),
+    }
+  }
+
+  /// Whether a certificate is configured as `name`.
+  pub fn contains(&self, name: &str) -> bool {
+    self.all.iter().any(|it| it.name == name)
+  }
+
+  pub fn len(&self) -> usize {
+    self.all.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.all.is_empty()
+  }
+
+  /// Selects the certificate for the `server_name` of a client, returning
+  /// the name it is configured as.
+  pub fn select(&self, server_name: Option<&str>) -> Option<(&str, &CertifiedKey, CertificateMatch)> {
+    let server_name = server_name.map(normalize_dns_name);
+    let exact = server_name
+      .as_ref()
+      .and_then(|it| self.exact.get(it))
+      .map(|it| (it, CertificateMatch::Exact));
+    let wildcard = || {
+      let (_, parent) = server_name.as_ref()?.split_once('.')?;
+      self.wildcards.get(parent).map(|it| (it, CertificateMatch::Wildcard))
+    };
+    let default = || {
+      let single = Some(&self.all).filter(|it| it.len() == 1).and_then(|it| it.first());
+      self
+        .default
+        .as_ref()
+        .or(single)
+        .map(|it| (it, CertificateMatch::Default))
+    };
+    exact
+      .or_else(wildcard)
+      .or_else(default)
+      .map(|(it, kind)| (it.name.as_str(), &it.certified_key, kind))
+  }
+}
+
 pub struct ReconfigurableCertificateResolver<A>
 where
-  A: Access<HashMap<DNSName, CertifiedKey>>,
+  A: Access<Certificates>,
 {
   certificates: A,
 }
 
 impl<A> ReconfigurableCertificateResolver<A>
 where
-  A: Access<HashMap<DNSName, CertifiedKey>>,
+  A: Access<Certificates>,
 {
   pub fn new(certificates: A) -> ReconfigurableCertificateResolver<A> {
     ReconfigurableCertificateResolver { certificates }
@@ -94,15 +875,522 @@ where
 
 impl<A> ResolvesServerCert for ReconfigurableCertificateResolver<A>
 where
-  A: Access<HashMap<DNSName, CertifiedKey>> + Send + Sync,
+  A: Access<Certificates> + Send + Sync,
 {
   fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
-    if let Some(name) = client_hello.server_name() {
-      let certificates = self.certificates.load();
-      certificates.get(&name.to_owned()).cloned()
-    } else {
-      // This kind of resolver requires SNI
-      None
+    let server_name = client_hello.server_name().map(|it| Into::<&str>::into(it).to_string());
+    let certificates = self.certificates.load();
+    match certificates.select(server_name.as_deref()) {
+      Some((name, certified_key, kind)) => {
+        debug!(
+          "Selected the certificate '{}' for {} ({:?} match)",
+          name,
+          server_name.as_deref().unwrap_or("a client without SNI"),
+          kind
+        );
+        Some(certified_key.clone())
+      }
+      None => {
+        debug!(
+          "No certificate for {}",
+          server_name.as_deref().unwrap_or("a client without SNI")
+        );
+        None
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use arc_swap::ArcSwap;
+  use openssl::pkey::{PKey, Private};
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+  };
+  use tokio_rustls::{
+    rustls::{ClientConfig, ClientSession},
+    webpki::DNSNameRef,
+    TlsAcceptor, TlsConnector,
+  };
+
+  fn self_signed_certificate(common_name: &str) -> Certificate {
+    self_signed(common_name).unwrap().0
+  }
+
+  /// Counts the tickets, which were successfully decrypted, so the number of
+  /// resumed sessions.
+  struct CountingTicketer {
+    ticketer: ReconfigurableTicketer<Arc<ArcSwap<Option<SessionTicketConfig>>>>,
+    resumed: AtomicUsize,
+  }
+
+  impl ProducesTickets for CountingTicketer {
+    fn enabled(&self) -> bool {
+      self.ticketer.enabled()
+    }
+
+    fn get_lifetime(&self) -> u32 {
+      self.ticketer.get_lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+      self.ticketer.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+      let plain = self.ticketer.decrypt(cipher);
+      if plain.is_some() {
+        self.resumed.fetch_add(1, Ordering::SeqCst);
+      }
+      plain
+    }
+  }
+
+  fn ticketer(keys: Vec<TicketKey>) -> ReconfigurableTicketer<Arc<ArcSwap<Option<SessionTicketConfig>>>> {
+    ReconfigurableTicketer::new(Arc::new(ArcSwap::from_pointee(Some(SessionTicketConfig {
+      lifetime: Duration::from_secs(60),
+      keys,
+    }))))
+  }
+
+  /// A server with a self-signed certificate for `localhost` and a client,
+  /// which trusts it.
+  pub fn test_configs(tls: &TlsConfig) -> (ServerConfig, ClientConfig) {
+    test_configs_for("localhost", tls)
+  }
+
+  /// Like [`test_configs`], but with a certificate for `common_name`.
+  pub fn test_configs_for(common_name: &str, tls: &TlsConfig) -> (ServerConfig, ClientConfig) {
+    let (certificate, key) = self_signed(common_name).unwrap();
+    let mut server_config = server_config(None, tls).unwrap();
+    let private_key = PrivateKey(key.rsa().unwrap().private_key_to_der().unwrap());
+    server_config
+      .set_single_cert(vec![certificate.clone()], private_key)
+      .unwrap();
+    let mut client_config = ClientConfig::new();
+    client_config.root_store.add(&certificate).unwrap();
+    (server_config, client_config)
+  }
+
+  async fn handshake(server_config: Arc<ServerConfig>, client_config: Arc<ClientConfig>) -> io::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let server = tokio::spawn(async move {
+      let (stream, _) = listener.accept().await?;
+      let mut stream = TlsAcceptor::from(server_config).accept(stream).await?;
+      stream.write_all(b"hello").await?;
+      stream.shutdown().await
+    });
+    let stream = TcpStream::connect(address).await?;
+    let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(client_config).connect(name, stream).await?;
+    let mut content = Vec::new();
+    stream.read_to_end(&mut content).await?;
+    assert_eq!(content, b"hello");
+    server.await.unwrap()
+  }
+
+  /// The [`TlsInfo`] of a session with a client, which optionally
+  /// authenticates itself with a certificate for `client.example.com`.
+  async fn accepted_tls_info(client_certificate: bool) -> io::Result<TlsInfo> {
+    let (certificate, key) = self_signed("localhost").unwrap();
+    let (client_ca, client_key) = self_signed("client.example.com").unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(&client_ca).unwrap();
+    let mut server_config = ServerConfig::new(AllowAnyAnonymousOrAuthenticatedClient::new(roots));
+    let private_key = |key: PKey<Private>| PrivateKey(key.rsa().unwrap().private_key_to_der().unwrap());
+    server_config
+      .set_single_cert(vec![certificate.clone()], private_key(key))
+      .unwrap();
+    let mut client_config = ClientConfig::new();
+    client_config.root_store.add(&certificate).unwrap();
+    if client_certificate {
+      client_config
+        .set_single_client_cert(vec![client_ca], private_key(client_key))
+        .unwrap();
     }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    let server = tokio::spawn(async move {
+      let (stream, _) = listener.accept().await?;
+      let mut stream = TlsAcceptor::from(Arc::new(server_config)).accept(stream).await?;
+      let tls_info = TlsInfo::from_session(stream.get_ref().1);
+      stream.shutdown().await?;
+      Ok::<_, io::Error>(tls_info)
+    });
+    let stream = TcpStream::connect(address).await?;
+    let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut stream = TlsConnector::from(Arc::new(client_config))
+      .connect(name, stream)
+      .await?;
+    stream.read_to_end(&mut Vec::new()).await?;
+    server.await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_tls_info_with_client_certificate() {
+    // when:
+    let tls_info = accepted_tls_info(true).await.unwrap();
+
+    // then:
+    assert_eq!(tls_info.server_name, Some("localhost".into()));
+    assert_eq!(tls_info.protocol_version, Some("TLSv1.3".into()));
+    assert_eq!(tls_info.cipher_suite, Some("TLS13_CHACHA20_POLY1305_SHA256".into()));
+    assert_eq!(tls_info.client_common_name, Some("client.example.com".into()));
+    let pem = tls_info.client_certificate.unwrap();
+    assert!(pem.starts_with("-----BEGIN CERTIFICATE-----\n"), "{}", pem);
+    let certificate = X509::from_pem(pem.as_bytes()).unwrap();
+    assert_eq!(
+      common_name(&Certificate(certificate.to_der().unwrap())),
+      tls_info.client_common_name
+    );
+  }
+
+  #[tokio::test]
+  async fn test_tls_info_without_client_certificate() {
+    // when:
+    let tls_info = accepted_tls_info(false).await.unwrap();
+
+    // then:
+    assert_eq!(tls_info.protocol_version, Some("TLSv1.3".into()));
+    assert!(tls_info.cipher_suite.is_some());
+    assert_eq!(tls_info.client_common_name, None);
+    assert_eq!(tls_info.client_certificate, None);
+    assert!(tls_info.client_certificate_chain.is_empty());
+  }
+
+  #[test]
+  fn test_client_hello_server_name() {
+    let mut session = ClientSession::new(
+      &Arc::new(ClientConfig::new()),
+      DNSNameRef::try_from_ascii_str("whoami.localhost").unwrap(),
+    );
+    let mut client_hello = Vec::new();
+    session.write_tls(&mut client_hello).unwrap();
+
+    assert_eq!(
+      client_hello_server_name(&client_hello),
+      Some("whoami.localhost".to_string())
+    );
+    assert_eq!(client_hello_server_name(&client_hello[..100]), None);
+    assert_eq!(client_hello_server_name(b"GET / HTTP/1.1\r\n\r\n"), None);
+  }
+
+  #[test]
+  fn test_http2_is_offered_via_alpn() {
+    let http2 = TlsConfig {
+      http2: true,
+      ..TlsConfig::default()
+    };
+
+    assert_eq!(
+      server_config(None, &http2).unwrap().alpn_protocols,
+      vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    );
+    assert!(server_config(None, &TlsConfig::default())
+      .unwrap()
+      .alpn_protocols
+      .is_empty());
+  }
+
+  #[test]
+  fn test_load_certified_key_from_combined_pem() {
+    // given:
+    let dir = std::env::temp_dir().join(format!("arlb-combined-pem-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (certificate, key) = self_signed("localhost").unwrap();
+    let certificate = X509::from_der(&certificate.0).unwrap().to_pem().unwrap();
+    let key = key.rsa().unwrap().private_key_to_pem().unwrap();
+    let combined = dir.join("combined.pem");
+    std::fs::write(&combined, [certificate.clone(), key.clone()].concat()).unwrap();
+    let certificate_only = dir.join("certificate.pem");
+    std::fs::write(&certificate_only, &certificate).unwrap();
+    let key_only = dir.join("key.pem");
+    std::fs::write(&key_only, &key).unwrap();
+
+    // when:
+    let loaded = load_certified_key(&combined, &combined);
+    let without_key = load_certified_key(&certificate_only, &certificate_only);
+    let without_certificate = load_certified_key(&key_only, &key_only);
+
+    // then:
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(loaded.unwrap().cert.len(), 1);
+    assert!(without_key.err().unwrap().to_string().starts_with("No RSA key in"));
+    assert!(without_certificate
+      .err()
+      .unwrap()
+      .to_string()
+      .starts_with("No certificate in"));
+  }
+
+  #[test]
+  fn test_key_must_match_certificate() {
+    let (certificate, key) = self_signed("localhost").unwrap();
+    let (_, other_key) = self_signed("localhost").unwrap();
+    let private_key = |key: PKey<Private>| PrivateKey(key.rsa().unwrap().private_key_to_der().unwrap());
+
+    assert!(key_matches_certificate(&private_key(key), Some(&certificate)));
+    assert!(!key_matches_certificate(&private_key(other_key), Some(&certificate)));
+    assert!(!key_matches_certificate(&PrivateKey(vec![1, 2, 3]), Some(&certificate)));
+  }
+
+  #[tokio::test]
+  async fn test_min_version_refuses_older_clients() {
+    // given:
+    let tls = TlsConfig {
+      versions: TlsConfig::versions_from("1.3").unwrap(),
+      ..TlsConfig::default()
+    };
+    let (server_config, mut client_config) = test_configs(&tls);
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+
+    // when:
+    let result = handshake(Arc::new(server_config), Arc::new(client_config)).await;
+
+    // then:
+    assert!(result.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_session_resumption_with_tickets() {
+    // given:
+    let (mut server_config, client_config) = test_configs(&TlsConfig::default());
+    let ticketer = Arc::new(CountingTicketer {
+      ticketer: ticketer(Vec::new()),
+      resumed: AtomicUsize::new(0),
+    });
+    server_config.ticketer = ticketer.clone();
+    let server_config = Arc::new(server_config);
+    let client_config = Arc::new(client_config);
+
+    // when:
+    handshake(server_config.clone(), client_config.clone()).await.unwrap();
+    handshake(server_config, client_config).await.unwrap();
+
+    // then:
+    assert_eq!(ticketer.resumed.load(Ordering::SeqCst), 1);
+  }
+
+  #[tokio::test]
+  async fn test_session_resumption_with_session_ids() {
+    // given:
+    let (server_config, mut client_config) = test_configs(&TlsConfig::default());
+    client_config.versions = vec![ProtocolVersion::TLSv1_2];
+    let server_config = Arc::new(server_config);
+    let client_config = Arc::new(client_config);
+    let labels = [("mechanism", "cache"), ("result", "hit")];
+    let hits = METRICS.counter("arlb_tls_session_resumptions_total", &labels);
+
+    // when:
+    handshake(server_config.clone(), client_config.clone()).await.unwrap();
+    handshake(server_config, client_config).await.unwrap();
+
+    // then:
+    assert!(METRICS.counter("arlb_tls_session_resumptions_total", &labels) > hits);
+  }
+
+  #[test]
+  fn test_session_cache_can_be_disabled() {
+    let disabled = TlsConfig {
+      session_cache_size: 0,
+      ..Default::default()
+    };
+
+    let disabled = server_config(None, &disabled).unwrap();
+    let enabled = server_config(None, &TlsConfig::default()).unwrap();
+
+    assert!(!disabled.session_storage.put(vec![1], vec![2]));
+    assert!(enabled.session_storage.put(vec![1], vec![2]));
+  }
+
+  #[test]
+  fn test_tickets_are_shared_between_instances_with_the_same_key() {
+    // given:
+    let key = TicketKey::random();
+    let first = ticketer(vec![key.clone()]);
+    let second = ticketer(vec![key]);
+
+    // when:
+    let ticket = first.encrypt(b"session").unwrap();
+
+    // then:
+    assert_eq!(second.decrypt(&ticket), Some(b"session".to_vec()));
+    assert_eq!(ticketer(Vec::new()).decrypt(&ticket), None);
+  }
+
+  #[test]
+  fn test_tickets_of_rotated_keys_are_accepted() {
+    // given:
+    let old_key = TicketKey::random();
+    let ticket = ticketer(vec![old_key.clone()]).encrypt(b"session").unwrap();
+
+    // when:
+    let rotated = ticketer(vec![TicketKey::random(), old_key]);
+
+    // then:
+    assert_eq!(rotated.decrypt(&ticket), Some(b"session".to_vec()));
+  }
+
+  #[test]
+  fn test_tampered_tickets_are_rejected() {
+    let ticketer = ticketer(Vec::new());
+    let mut ticket = ticketer.encrypt(b"session").unwrap();
+    ticket[NONCE_LEN] ^= 1;
+
+    assert_eq!(ticketer.decrypt(&ticket), None);
+    assert_eq!(ticketer.decrypt(&[]), None);
+  }
+
+  #[test]
+  fn test_cipher_suites_from() {
+    let versions = TlsConfig::versions_from("1.2").unwrap();
+    let names = vec![
+      "TLS13_AES_256_GCM_SHA384".into(),
+      "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".into(),
+    ];
+
+    let cipher_suites = TlsConfig::cipher_suites_from(&names, &versions).unwrap();
+
+    assert_eq!(cipher_suites.len(), 2);
+  }
+
+  #[test]
+  fn test_invalid_cipher_suites() {
+    let tls13 = TlsConfig::versions_from("1.3").unwrap();
+
+    assert!(TlsConfig::cipher_suites_from(&[], &tls13).is_err());
+    assert!(TlsConfig::cipher_suites_from(&["TLS_RSA_WITH_RC4_128_MD5".into()], &tls13).is_err());
+    let tls12_only = vec!["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".into()];
+    let error = TlsConfig::cipher_suites_from(&tls12_only, &tls13).unwrap_err();
+    assert!(error.to_string().contains("TLSv1_3"), "{}", error);
+  }
+
+  #[test]
+  fn test_host_matches_server_name() {
+    assert!(host_matches_server_name("a.example.com", "a.example.com"));
+    assert!(host_matches_server_name("A.Example.com:443", "a.example.com"));
+    assert!(host_matches_server_name("a.example.com.", "a.example.com"));
+    assert!(!host_matches_server_name("b.internal", "a.example.com"));
+    assert!(!host_matches_server_name("a.example.com.evil", "a.example.com"));
+  }
+
+  #[test]
+  fn test_invalid_min_version() {
+    assert!(TlsConfig::versions_from("1.1").is_err());
+  }
+
+  #[test]
+  fn test_common_name() {
+    let certificate = self_signed_certificate("client.example.com");
+
+    assert_eq!(common_name(&certificate), Some("client.example.com".into()));
+  }
+
+  #[test]
+  fn test_common_name_invalid_certificate() {
+    assert_eq!(common_name(&Certificate(vec![1, 2, 3])), None);
+  }
+
+  fn certified_key(common_name: &str) -> CertifiedKey {
+    let (certificate, key) = self_signed(common_name).unwrap();
+    let private_key = PrivateKey(key.rsa().unwrap().private_key_to_der().unwrap());
+    CertifiedKey::new(
+      vec![certificate],
+      Arc::new(Box::new(RSASigningKey::new(&private_key).unwrap())),
+    )
+  }
+
+  fn selected(certificates: &Certificates, server_name: Option<&str>) -> Option<(String, CertificateMatch)> {
+    certificates
+      .select(server_name)
+      .map(|(name, _, kind)| (name.to_string(), kind))
+  }
+
+  #[test]
+  fn test_exact_names_beat_wildcards_beat_the_default() {
+    // given: the wildcard certificate is configured as one of the names it covers
+    let mut certificates = Certificates::default();
+    certificates
+      .insert("wildcard.example.com", certified_key("*.example.com"))
+      .unwrap();
+    certificates
+      .insert("api.example.com", certified_key("api.example.com"))
+      .unwrap();
+    certificates.set_default("api.example.com").unwrap();
+
+    // then:
+    let exact = Some(("api.example.com".to_string(), CertificateMatch::Exact));
+    assert_eq!(selected(&certificates, Some("api.example.com")), exact);
+    assert_eq!(selected(&certificates, Some("API.Example.com.")), exact);
+    let wildcard = Some(("wildcard.example.com".to_string(), CertificateMatch::Wildcard));
+    assert_eq!(selected(&certificates, Some("www.example.com")), wildcard);
+    let default = Some(("api.example.com".to_string(), CertificateMatch::Default));
+    assert_eq!(selected(&certificates, Some("a.b.example.com")), default);
+    assert_eq!(selected(&certificates, Some("example.com")), default);
+    assert_eq!(selected(&certificates, None), default);
+  }
+
+  #[test]
+  fn test_default_certificate() {
+    let mut certificates = Certificates::default();
+    assert_eq!(selected(&certificates, Some("localhost")), None);
+
+    // A single certificate is the default
+    certificates.insert("localhost", certified_key("localhost")).unwrap();
+    let single = Some(("localhost".to_string(), CertificateMatch::Default));
+    assert_eq!(selected(&certificates, Some("other.localhost")), single);
+
+    // Otherwise clients without a matching certificate fail the handshake
+    certificates
+      .insert("example.com", certified_key("example.com"))
+      .unwrap();
+    assert_eq!(selected(&certificates, Some("other.localhost")), None);
+    assert!(certificates.set_default("unknown").is_err());
+  }
+
+  #[test]
+  fn test_certificate_must_be_valid_for_its_name() {
+    // given:
+    let mut certificates = Certificates::default();
+
+    // when:
+    let other_name = certificates.insert("example.com", certified_key("localhost"));
+    let parent_of_wildcard = certificates.insert("example.com", certified_key("*.example.com"));
+    let below_wildcard = certificates.insert("a.b.example.com", certified_key("*.example.com"));
+    let covered_by_wildcard = certificates.insert("www.example.com", certified_key("*.example.com"));
+
+    // then:
+    assert_eq!(
+      other_name.unwrap_err().to_string(),
+      "The certificate 'example.com' is only valid for [\"localhost\"]"
+    );
+    assert!(parent_of_wildcard.is_err());
+    assert!(below_wildcard.is_err());
+    assert!(covered_by_wildcard.is_ok());
+    assert_eq!(certificates.len(), 1);
+  }
+
+  #[test]
+  fn test_two_certificates_must_not_claim_the_same_name() {
+    // given:
+    let mut certificates = Certificates::default();
+    certificates.insert("localhost", certified_key("localhost")).unwrap();
+
+    // when:
+    let result = certificates.insert("localhost.", certified_key("localhost"));
+
+    // then:
+    let message = result.unwrap_err().to_string();
+    assert_eq!(
+      message,
+      "The certificate 'localhost.' claims 'localhost', like the certificate 'localhost'"
+    );
+    assert_eq!(certificates.len(), 1);
   }
 }
@@ -0,0 +1,723 @@
+use crate::{
+  bandwidth::{Meter, Metered, BACKEND_BANDWIDTH},
+  connections::{Phase, Tracked, CONNECTIONS},
+  flow_sampling::sample_connection,
+  http_client::{backend_uri, connect_relay_backend},
+  listeners::{bind_with_backlog, AcceptRetry},
+  load_balancing::{self, LoadBalancingStrategy},
+  metrics::METRICS,
+  proxy_protocol::{self, ProxyProtocolVersion},
+  throttle::Throttled,
+  tls::client_hello_server_name,
+  top_talkers::TOP_TALKERS,
+};
+use futures::future::{self, Either};
+use hyper::{http::uri::PathAndQuery, Body, Request};
+use log::{debug, info};
+use std::{
+  io,
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  time::timeout_at,
+};
+
+/// The length of the header of a TLS record.
+const RECORD_HEADER_LEN: usize = 5;
+
+/// A TCP listener whose TLS connections are relayed to backend servers
+/// without terminating them. The backend servers are selected by the server
+/// name (SNI) of the ClientHello, so clients keep end-to-end TLS.
+#[derive(Debug)]
+pub struct TlsPassthroughService {
+  pub listen_address: SocketAddr,
+  /// The first route, which matches the server name, is used.
+  pub routes: Vec<TlsPassthroughRoute>,
+  /// Used for connections without a server name, which match none of the
+  /// `routes`.
+  pub no_sni_route: Option<TlsPassthroughRoute>,
+  /// Used for connections, which match none of the `routes` (and, if they
+  /// have no server name, have no `no_sni_route`). Without it they are
+  /// closed.
+  pub default_route: Option<TlsPassthroughRoute>,
+  /// The ClientHello has to arrive and the backend server has to accept the
+  /// connection within this time.
+  pub handshake_timeout: Duration,
+}
+
+impl TlsPassthroughService {
+  /// The route of a connection with the `server_name`: the first matching of
+  /// the `routes`, then the `no_sni_route` and then the `default_route`.
+  fn route(&self, server_name: Option<&str>) -> Option<&TlsPassthroughRoute> {
+    self
+      .routes
+      .iter()
+      .find(|it| it.matches(server_name))
+      .or_else(|| self.no_sni_route.as_ref().filter(|_| server_name.is_none()))
+      .or(self.default_route.as_ref())
+  }
+}
+
+#[derive(Debug)]
+pub struct TlsPassthroughRoute {
+  /// Exact server names, wildcards like `*.example.com` (matching a single
+  /// label) or `*`, which matches all connections, even without a server name.
+  pub server_names: Vec<String>,
+  pub addresses: Vec<String>,
+  pub strategy: Box<dyn LoadBalancingStrategy>,
+  /// Limits the bytes per second of each direction of a connection.
+  pub max_connection_bytes_per_sec: Option<u64>,
+  /// Backend servers, which sent this many bytes per second across all their
+  /// connections, receive no new connections.
+  pub max_backend_bytes_per_sec: Option<u64>,
+  /// Sends a PROXY protocol header of this version ahead of the connection,
+  /// so backend servers know the address of the client.
+  pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+impl TlsPassthroughRoute {
+  fn matches(&self, server_name: Option<&str>) -> bool {
+    self
+      .server_names
+      .iter()
+      .any(|pattern| match (pattern.as_str(), server_name) {
+        ("*", _) => true,
+        (_, None) => false,
+        (pattern, Some(server_name)) => match pattern.strip_prefix("*.") {
+          Some(domain) => {
+            matches!(server_name.split_once('.'), Some((_, parent)) if parent.eq_ignore_ascii_case(domain))
+          }
+          None => pattern.eq_ignore_ascii_case(server_name),
+        },
+      })
+  }
+
+  fn select_backend(&self, client_address: &SocketAddr) -> Option<String> {
+    select_backend_within_budget(
+      &self.addresses,
+      self.max_backend_bytes_per_sec,
+      self.strategy.as_ref(),
+      client_address,
+    )
+  }
+}
+
+/// Selects a backend server like [`select_backend`] of those, which did not
+/// use up their `max_backend_bytes_per_sec` during the last window. Returns
+/// `None` if all of them did, so the connection is shed.
+pub(crate) fn select_backend_within_budget(
+  addresses: &[String],
+  max_backend_bytes_per_sec: Option<u64>,
+  strategy: &dyn LoadBalancingStrategy,
+  client_address: &SocketAddr,
+) -> Option<String> {
+  if max_backend_bytes_per_sec.is_none() {
+    return Some(select_backend(addresses, strategy, client_address));
+  }
+  let within_budget = addresses
+    .iter()
+    .filter(|it| !BACKEND_BANDWIDTH.exceeds(it, max_backend_bytes_per_sec))
+    .cloned()
+    .collect::<Vec<_>>();
+  if within_budget.is_empty() {
+    return None;
+  }
+  Some(select_backend(&within_budget, strategy, client_address))
+}
+
+/// Selects one of the backend `addresses` of a TCP connection via `strategy`.
+pub(crate) fn select_backend(
+  addresses: &[String],
+  strategy: &dyn LoadBalancingStrategy,
+  client_address: &SocketAddr,
+) -> String {
+  let backend_addresses = addresses.iter().map(String::as_str).collect::<Vec<_>>();
+  let context = load_balancing::Context {
+    client_address,
+    backend_addresses: &backend_addresses,
+  };
+  // Strategies are designed for HTTP, so they get an empty request to select a backend server
+  let request = Request::new(Body::empty());
+  let forwarder = strategy.select_backend(&request, &context);
+  forwarder.backend_address().to_string()
+}
+
+pub struct TlsPassthroughProxy {
+  listener: TcpListener,
+  service: Arc<TlsPassthroughService>,
+  /// New connections are closed right away while this is set.
+  maintenance: Arc<AtomicBool>,
+}
+
+impl TlsPassthroughProxy {
+  pub async fn bind(
+    service: Arc<TlsPassthroughService>,
+    maintenance: Arc<AtomicBool>,
+    backlog: u32,
+  ) -> io::Result<TlsPassthroughProxy> {
+    let listener = bind_with_backlog(service.listen_address, backlog)?;
+    info!(
+      "Started listening for TLS passthrough connections on {}",
+      listener.local_addr()?
+    );
+    Ok(TlsPassthroughProxy {
+      listener,
+      service,
+      maintenance,
+    })
+  }
+
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.listener.local_addr()
+  }
+
+  /// Accepts connections and relays each of them in a separate task.
+  pub async fn run(self) -> io::Result<()> {
+    let mut retry = AcceptRetry::new();
+    loop {
+      let (socket, peer) = retry.accept(|| self.listener.accept()).await?;
+      if self.maintenance.load(Ordering::Relaxed) {
+        METRICS.increment("arlb_tls_passthrough_connections_total", &[("result", "maintenance")]);
+        debug!("Closed TLS passthrough connection of {} due to maintenance", peer);
+        continue;
+      }
+      let service = self.service.clone();
+      let client = CONNECTIONS.track(socket, "tls_passthrough".into(), peer, Phase::Handshaking);
+      tokio::spawn(async move {
+        let connection = client.connection().id;
+        if let Err(e) = relay(&service, client, peer).await {
+          debug!(
+            "Could not relay TLS passthrough connection {} of {}: {}",
+            connection, peer, e
+          );
+        }
+      });
+    }
+  }
+}
+
+/// Reads the ClientHello of the `client` to select a backend server, replays
+/// the bytes read so far to it and then copies the connection in both
+/// directions until both sides closed it.
+async fn relay(service: &TlsPassthroughService, mut client: Tracked<TcpStream>, peer: SocketAddr) -> io::Result<()> {
+  let deadline = Instant::now() + service.handshake_timeout;
+  let client_hello = match timeout_at(deadline.into(), read_client_hello(&mut client)).await {
+    Ok(Ok(client_hello)) => client_hello,
+    Ok(Err(e)) => return Err(invalid_client_hello(e)),
+    Err(_) => return Err(invalid_client_hello(io::ErrorKind::TimedOut.into())),
+  };
+  let server_name = client_hello_server_name(&client_hello);
+  let route = match service.route(server_name.as_deref()) {
+    Some(route) => route,
+    None => {
+      METRICS.increment("arlb_tls_passthrough_connections_total", &[("result", "unknown_sni")]);
+      debug!(
+        "Closed TLS passthrough connection of {} to unknown {:?}",
+        peer, server_name
+      );
+      return Ok(());
+    }
+  };
+  let backend_address = match route.select_backend(&peer) {
+    Some(backend_address) => backend_address,
+    None => {
+      METRICS.increment("arlb_tls_passthrough_connections_total", &[("result", "over_budget")]);
+      debug!(
+        "Closed TLS passthrough connection of {} for {:?}, because all backend servers are at their bandwidth budget",
+        peer, server_name
+      );
+      return Ok(());
+    }
+  };
+  METRICS.increment("arlb_tls_passthrough_connections_total", &[("result", "routed")]);
+
+  let backend_uri = backend_uri(&backend_address, PathAndQuery::from_static("/"))
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+  client.connection().connecting(&backend_address);
+  let mut backend = timeout_at(deadline.into(), connect_relay_backend(&backend_address))
+    .await
+    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "The connection timed out")))?;
+  debug!(
+    "Relaying TLS passthrough connection of {} for {:?} to {}",
+    peer, server_name, backend_address
+  );
+  route.strategy.on_tcp_open(&backend_uri);
+  let result = async {
+    if let Some(version) = route.proxy_protocol {
+      let header = proxy_protocol::header(version, peer, client.get_ref().local_addr()?);
+      backend.write_all(&header).await?;
+    }
+    backend.write_all(&client_hello).await?;
+    let meter = BACKEND_BANDWIDTH.meter(&backend_address);
+    client.connection().set_phase(Phase::Piping);
+    let client = TOP_TALKERS.observe(client, peer.ip());
+    let ending = splice(client, backend, route.max_connection_bytes_per_sec, Some(meter)).await?;
+    if ending == Ending::BackendReset {
+      debug!(
+        "Backend server {} reset the TLS passthrough connection of {}",
+        backend_address, peer
+      );
+    }
+    Ok(())
+  }
+  .await;
+  route.strategy.on_tcp_close(&backend_uri);
+  result
+}
+
+fn invalid_client_hello(error: io::Error) -> io::Error {
+  METRICS.increment("arlb_tls_passthrough_connections_total", &[("result", "invalid")]);
+  error
+}
+
+/// Reads the first TLS record of `client`, which contains the ClientHello.
+/// Bytes, which the client sent right after the record, are included, because
+/// they are read as well.
+async fn read_client_hello<C>(client: &mut C) -> io::Result<Vec<u8>>
+where
+  C: AsyncRead + Unpin,
+{
+  let mut bytes = Vec::with_capacity(1024);
+  loop {
+    if bytes.len() >= RECORD_HEADER_LEN {
+      if bytes[0] != 0x16 || bytes[1] != 0x03 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a TLS handshake"));
+      }
+      let record_len = RECORD_HEADER_LEN + u16::from_be_bytes([bytes[3], bytes[4]]) as usize;
+      if bytes.len() >= record_len {
+        return Ok(bytes);
+      }
+    }
+    if client.read_buf(&mut bytes).await? == 0 {
+      return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+  }
+}
+
+/// How a spliced connection ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Ending {
+  /// Both sides closed their connection.
+  Closed,
+  /// The client reset its connection, so the connection to the backend
+  /// server was closed right away.
+  ClientReset,
+  /// The backend server reset its connection. Everything it sent before was
+  /// forwarded to the client, whose connection was then closed like after a
+  /// clean close.
+  BackendReset,
+}
+
+impl Ending {
+  fn side(self) -> Option<&'static str> {
+    match self {
+      Ending::Closed => None,
+      Ending::ClientReset => Some("client"),
+      Ending::BackendReset => Some("backend"),
+    }
+  }
+}
+
+/// Whether reading or writing failed while copying one direction.
+enum PipeError {
+  Read(io::Error),
+  Write(io::Error),
+}
+
+fn is_reset(error: &io::Error) -> bool {
+  matches!(
+    error.kind(),
+    io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+  )
+}
+
+/// Turns a reset into the `ending` of a spliced connection and keeps all
+/// other errors.
+fn reset_of(error: io::Error, ending: Ending) -> io::Result<Ending> {
+  if is_reset(&error) {
+    Ok(ending)
+  } else {
+    Err(error)
+  }
+}
+
+/// Copies `reader` to `writer`, until `reader` is closed, and then closes
+/// `writer`. Each chunk is written completely before the next one is read, so
+/// nothing that was read is lost, when reading fails.
+async fn pipe<R, W>(reader: &mut R, writer: &mut W) -> Result<(), PipeError>
+where
+  R: AsyncRead + Unpin,
+  W: AsyncWrite + Unpin,
+{
+  let mut buffer = vec![0; 8 * 1024];
+  loop {
+    let len = reader.read(&mut buffer).await.map_err(PipeError::Read)?;
+    if len == 0 {
+      return writer.shutdown().await.map_err(PipeError::Write);
+    }
+    writer.write_all(&buffer[..len]).await.map_err(PipeError::Write)?;
+  }
+}
+
+/// Copies data between `client` and `backend` in both directions. Once one
+/// side finished sending, this is forwarded to the other side, which can
+/// still respond. If copying fails in either direction, for example because
+/// the client disconnected mid-transfer, the other direction is cancelled
+/// right away and both connections are closed, so a slow read from the
+/// backend server does not keep them open. Only if the backend server reset
+/// its connection, the data it sent before is still forwarded, before the
+/// connection of the client is closed cleanly. Resets are counted by side and
+/// returned as the [`Ending`] instead of an error. Each direction is
+/// throttled to `max_bytes_per_sec`, if it is set. The bytes sent by the
+/// backend server are counted with `backend_meter`.
+pub(crate) async fn splice<C, B>(
+  client: C,
+  backend: B,
+  max_bytes_per_sec: Option<u64>,
+  backend_meter: Option<Arc<Meter>>,
+) -> io::Result<Ending>
+where
+  C: AsyncRead + AsyncWrite,
+  B: AsyncRead + AsyncWrite,
+{
+  let (client_read, client_write) = tokio::io::split(client);
+  let (backend_read, backend_write) = tokio::io::split(backend);
+  let backend_read = Metered::new(backend_read, backend_meter);
+  let mut client_write = Throttled::new(client_write, max_bytes_per_sec);
+  let mut backend_write = Throttled::new(backend_write, max_bytes_per_sec);
+  let (mut client_read, mut backend_read) = sample_connection(client_read, backend_read);
+  let upstream = async {
+    match pipe(&mut client_read, &mut backend_write).await {
+      Ok(()) => Ok(Ending::Closed),
+      Err(PipeError::Read(e)) => reset_of(e, Ending::ClientReset),
+      Err(PipeError::Write(e)) => reset_of(e, Ending::BackendReset),
+    }
+  };
+  let downstream = async {
+    match pipe(&mut backend_read, &mut client_write).await {
+      Ok(()) => Ok(Ending::Closed),
+      Err(PipeError::Read(e)) => {
+        reset_of(e, Ending::BackendReset)?;
+        client_write.shutdown().await?;
+        Ok(Ending::BackendReset)
+      }
+      Err(PipeError::Write(e)) => reset_of(e, Ending::ClientReset),
+    }
+  };
+  futures::pin_mut!(upstream, downstream);
+  let ending = match future::select(upstream, downstream).await {
+    Either::Left((Ok(Ending::Closed), downstream)) => downstream.await?,
+    Either::Right((Ok(Ending::Closed), upstream)) => upstream.await?,
+    // What the backend server sent before its reset may still be on its way
+    // to the client
+    Either::Left((Ok(Ending::BackendReset), downstream)) => match downstream.await? {
+      Ending::ClientReset => Ending::ClientReset,
+      _ => Ending::BackendReset,
+    },
+    Either::Left((ending, _)) | Either::Right((ending, _)) => ending?,
+  };
+  if let Some(side) = ending.side() {
+    METRICS.increment("arlb_relay_resets_total", &[("side", side)]);
+  }
+  Ok(ending)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    load_balancing::round_robin::RoundRobin,
+    tls::{
+      tests::{test_configs, test_configs_for},
+      TlsConfig,
+    },
+  };
+  use tokio::{task::JoinHandle, time::timeout};
+  use tokio_rustls::{
+    rustls::{ClientConfig, ServerConfig},
+    webpki::DNSNameRef,
+    TlsAcceptor, TlsConnector,
+  };
+
+  /// Terminates TLS like a backend server with end-to-end TLS and responds
+  /// with its `name`.
+  async fn start_tls_backend(server_config: ServerConfig, name: &'static str) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+    tokio::spawn(async move {
+      loop {
+        let (socket, _) = listener.accept().await.unwrap();
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+          let mut tls_stream = tls_acceptor.accept(socket).await.unwrap();
+          tls_stream.write_all(name.as_bytes()).await.unwrap();
+          tls_stream.shutdown().await.unwrap();
+        });
+      }
+    });
+    address
+  }
+
+  fn route(server_names: &[&str], backend: SocketAddr) -> TlsPassthroughRoute {
+    TlsPassthroughRoute {
+      server_names: server_names.iter().map(|it| it.to_string()).collect(),
+      addresses: vec![backend.to_string()],
+      strategy: Box::new(RoundRobin::new()),
+      max_connection_bytes_per_sec: None,
+      max_backend_bytes_per_sec: None,
+      proxy_protocol: None,
+    }
+  }
+
+  async fn start_proxy(routes: Vec<TlsPassthroughRoute>) -> SocketAddr {
+    start_proxy_with_maintenance(routes, Default::default()).await
+  }
+
+  fn service(routes: Vec<TlsPassthroughRoute>) -> TlsPassthroughService {
+    TlsPassthroughService {
+      listen_address: "127.0.0.1:0".parse().unwrap(),
+      routes,
+      no_sni_route: None,
+      default_route: None,
+      handshake_timeout: Duration::from_secs(1),
+    }
+  }
+
+  async fn start_proxy_with_maintenance(routes: Vec<TlsPassthroughRoute>, maintenance: Arc<AtomicBool>) -> SocketAddr {
+    let service = service(routes);
+    let proxy = TlsPassthroughProxy::bind(Arc::new(service), maintenance, 1024)
+      .await
+      .unwrap();
+    let address = proxy.local_addr().unwrap();
+    tokio::spawn(proxy.run());
+    address
+  }
+
+  async fn request(proxy: SocketAddr, client_config: &Arc<ClientConfig>, server_name: &str) -> io::Result<String> {
+    let socket = TcpStream::connect(proxy).await?;
+    let connector = TlsConnector::from(client_config.clone());
+    let server_name = DNSNameRef::try_from_ascii_str(server_name).unwrap();
+    let mut tls_stream = connector.connect(server_name, socket).await?;
+    let mut response = String::new();
+    tls_stream.read_to_string(&mut response).await?;
+    Ok(response)
+  }
+
+  #[tokio::test]
+  async fn test_connections_are_routed_by_server_name() {
+    // given: two backend servers, whose certificates are only trusted by their own client
+    let (first_server_config, first_client_config) = test_configs_for("localhost", &TlsConfig::default());
+    let (second_server_config, second_client_config) = test_configs_for("second.localhost", &TlsConfig::default());
+    let first = start_tls_backend(first_server_config, "first").await;
+    let second = start_tls_backend(second_server_config, "second").await;
+    let proxy = start_proxy(vec![route(&["localhost"], first), route(&["*.localhost"], second)]).await;
+
+    // when:
+    let first_response = request(proxy, &Arc::new(first_client_config), "localhost").await;
+    let second_response = request(proxy, &Arc::new(second_client_config), "second.localhost").await;
+
+    // then:
+    assert_eq!(first_response.unwrap(), "first");
+    assert_eq!(second_response.unwrap(), "second");
+  }
+
+  #[tokio::test]
+  async fn test_unknown_server_name_is_closed() {
+    // given:
+    let (server_config, client_config) = test_configs(&TlsConfig::default());
+    let backend = start_tls_backend(server_config, "backend").await;
+    let proxy = start_proxy(vec![route(&["example.com"], backend)]).await;
+
+    // when:
+    let response = request(proxy, &Arc::new(client_config), "localhost").await;
+
+    // then:
+    assert!(response.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_unknown_server_name_uses_the_default_route() {
+    // given:
+    let (server_config, client_config) = test_configs(&TlsConfig::default());
+    let backend = start_tls_backend(server_config, "default").await;
+    let service = TlsPassthroughService {
+      default_route: Some(route(&[], backend)),
+      ..service(vec![route(&["example.com"], "127.0.0.1:1".parse().unwrap())])
+    };
+    let proxy = TlsPassthroughProxy::bind(Arc::new(service), Default::default(), 1024)
+      .await
+      .unwrap();
+    let address = proxy.local_addr().unwrap();
+    tokio::spawn(proxy.run());
+
+    // when:
+    let response = request(address, &Arc::new(client_config), "localhost").await;
+
+    // then:
+    assert_eq!(response.unwrap(), "default");
+  }
+
+  #[tokio::test]
+  async fn test_new_connections_are_refused_during_maintenance() {
+    // given:
+    let (server_config, client_config) = test_configs(&TlsConfig::default());
+    let client_config = Arc::new(client_config);
+    let backend = start_tls_backend(server_config, "backend").await;
+    let maintenance = Arc::new(AtomicBool::new(true));
+    let proxy = start_proxy_with_maintenance(vec![route(&["*"], backend)], maintenance.clone()).await;
+
+    // when:
+    let during_maintenance = request(proxy, &client_config, "localhost").await;
+    maintenance.store(false, Ordering::Relaxed);
+    let after_maintenance = request(proxy, &client_config, "localhost").await;
+
+    // then:
+    assert!(during_maintenance.is_err());
+    assert_eq!(after_maintenance.unwrap(), "backend");
+  }
+
+  /// Two ends of a TCP connection.
+  async fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let connect = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connect, listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+  }
+
+  /// Splices a client with a backend server, which slowly streams a download
+  /// until writing fails, and returns the client after it received the first
+  /// part of the download.
+  async fn start_download() -> (TcpStream, JoinHandle<()>) {
+    let (mut client, proxy_client) = socket_pair().await;
+    let (proxy_backend, mut backend) = socket_pair().await;
+    tokio::spawn(splice(proxy_client, proxy_backend, None, None));
+    let download = tokio::spawn(async move {
+      loop {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if backend.write_all(&[0; 1024]).await.is_err() {
+          return;
+        }
+      }
+    });
+    client.read_exact(&mut [0; 1024]).await.unwrap();
+    (client, download)
+  }
+
+  #[tokio::test]
+  async fn test_backend_connection_closes_when_the_client_is_killed_mid_download() {
+    // given:
+    let (client, download) = start_download().await;
+
+    // when: the connection of the client is reset
+    client.set_linger(Some(Duration::from_secs(0))).unwrap();
+    drop(client);
+
+    // then:
+    timeout(Duration::from_secs(1), download).await.unwrap().unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_backend_connection_closes_when_the_client_closes_mid_download() {
+    // given:
+    let (client, download) = start_download().await;
+
+    // when: the client closes its connection without reading the rest
+    drop(client);
+
+    // then:
+    timeout(Duration::from_secs(1), download).await.unwrap().unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_clean_close_of_the_backend_server_is_forwarded() {
+    // given:
+    let (mut client, proxy_client) = socket_pair().await;
+    let (proxy_backend, mut backend) = socket_pair().await;
+    let splice = tokio::spawn(splice(proxy_client, proxy_backend, None, None));
+
+    // when:
+    backend.write_all(b"response").await.unwrap();
+    backend.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    client.shutdown().await.unwrap();
+
+    // then:
+    assert_eq!(response, b"response");
+    let ending = timeout(Duration::from_secs(1), splice).await.unwrap().unwrap();
+    assert_eq!(ending.unwrap(), Ending::Closed);
+  }
+
+  #[tokio::test]
+  async fn test_reset_of_the_backend_server_closes_the_client_after_the_data_sent_before() {
+    // given:
+    let (mut client, proxy_client) = socket_pair().await;
+    let (proxy_backend, mut backend) = socket_pair().await;
+    let splice = tokio::spawn(splice(proxy_client, proxy_backend, None, None));
+    backend.write_all(b"partial").await.unwrap();
+    let mut partial = [0; 7];
+    client.read_exact(&mut partial).await.unwrap();
+
+    // when:
+    backend.set_linger(Some(Duration::from_secs(0))).unwrap();
+    drop(backend);
+
+    // then: the client sees a clean close instead of a truncated connection
+    assert_eq!(&partial, b"partial");
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(1), client.read_to_end(&mut rest))
+      .await
+      .unwrap()
+      .unwrap();
+    assert!(rest.is_empty());
+    let ending = timeout(Duration::from_secs(1), splice).await.unwrap().unwrap();
+    assert_eq!(ending.unwrap(), Ending::BackendReset);
+    assert!(METRICS.counter("arlb_relay_resets_total", &[("side", "backend")]) >= 1);
+  }
+
+  #[test]
+  fn test_route_matches() {
+    let backend = "127.0.0.1:1".parse().unwrap();
+
+    let exact = route(&["Example.com"], backend);
+    assert!(exact.matches(Some("example.com")));
+    assert!(!exact.matches(Some("www.example.com")));
+    assert!(!exact.matches(None));
+
+    let wildcard = route(&["*.example.com"], backend);
+    assert!(wildcard.matches(Some("www.example.com")));
+    assert!(!wildcard.matches(Some("example.com")));
+    assert!(!wildcard.matches(Some("a.b.example.com")));
+
+    let any = route(&["*"], backend);
+    assert!(any.matches(None));
+  }
+
+  #[test]
+  fn test_route_precedence() {
+    let route = |name: &str| route(&[name], format!("127.0.0.1:{}", name.len()).parse().unwrap());
+    let backend = |route: Option<&TlsPassthroughRoute>| route.map(|it| it.addresses[0].clone());
+    let mut service = service(vec![route("example.com")]);
+
+    assert_eq!(backend(service.route(Some("example.com"))), Some("127.0.0.1:11".into()));
+    assert_eq!(backend(service.route(Some("localhost"))), None);
+    assert_eq!(backend(service.route(None)), None);
+
+    service.default_route = Some(route("default"));
+    assert_eq!(backend(service.route(Some("localhost"))), Some("127.0.0.1:7".into()));
+    assert_eq!(backend(service.route(None)), Some("127.0.0.1:7".into()));
+
+    service.no_sni_route = Some(route("no-sni"));
+    assert_eq!(backend(service.route(Some("localhost"))), Some("127.0.0.1:7".into()));
+    assert_eq!(backend(service.route(None)), Some("127.0.0.1:6".into()));
+    assert_eq!(backend(service.route(Some("example.com"))), Some("127.0.0.1:11".into()));
+  }
+}
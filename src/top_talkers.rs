@@ -0,0 +1,469 @@
+use crate::{
+  connections::LiveConnection,
+  listeners::{PeerCredentials, RemoteAddress},
+  tls::TlsInfo,
+};
+use arc_swap::ArcSwap;
+use hyper::{header::CONTENT_TYPE, server::accept::Accept, Body, Method, Request, Response, StatusCode};
+use linked_hash_map::LinkedHashMap;
+use once_cell::sync::Lazy;
+use pin_project::pin_project;
+use serde_json::json;
+use std::{
+  collections::VecDeque,
+  io,
+  net::{IpAddr, SocketAddr},
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+  time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// How often a connection adds the bytes it transferred to the leaderboard.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of clients returned by `GET /top-talkers` without a `limit`.
+const DEFAULT_LIMIT: usize = 10;
+
+/// The connections and bytes of the clients of all listeners.
+pub static TOP_TALKERS: Lazy<TopTalkers> = Lazy::new(TopTalkers::default);
+
+/// Ranks client IP addresses by their connections and bytes during a sliding
+/// `window`, which is divided into `slots`: the counts of the oldest slot are
+/// dropped at once, so a single slot resets all counts after each window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopTalkersConfig {
+  pub window: Duration,
+  pub slots: u32,
+  /// Bounds the memory: beyond this many clients, the one, which was inactive
+  /// the longest, is forgotten.
+  pub max_clients: usize,
+}
+
+impl TopTalkersConfig {
+  fn slot_duration(&self) -> Duration {
+    self.window / self.slots
+  }
+}
+
+/// What the leaderboard is sorted by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ranking {
+  Connections,
+  Bytes,
+}
+
+/// A client with its counts during the window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Talker {
+  pub ip: IpAddr,
+  pub connections: u64,
+  pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+  index: u64,
+  connections: u64,
+  bytes: u64,
+}
+
+#[derive(Debug)]
+struct State {
+  started_at: Instant,
+  /// The slots of each client within the window, oldest first. The client,
+  /// which was inactive the longest, comes first.
+  clients: LinkedHashMap<IpAddr, VecDeque<Slot>>,
+}
+
+impl Default for State {
+  fn default() -> Self {
+    State {
+      started_at: Instant::now(),
+      clients: LinkedHashMap::new(),
+    }
+  }
+}
+
+/// Tracks the top talkers. Without a configuration nothing is tracked.
+#[derive(Default)]
+pub struct TopTalkers {
+  config: ArcSwap<Option<TopTalkersConfig>>,
+  state: Mutex<State>,
+}
+
+impl TopTalkers {
+  /// Applies a new configuration. The counts are kept, unless the window
+  /// changed or tracking is disabled.
+  pub fn configure(&self, config: Option<TopTalkersConfig>) {
+    let changed = match (&**self.config.load(), &config) {
+      (Some(old), Some(new)) => old.window != new.window || old.slots != new.slots,
+      _ => true,
+    };
+    if changed {
+      self.reset();
+    }
+    self.config.store(config.into());
+  }
+
+  /// Forgets all counts.
+  pub fn reset(&self) {
+    *self.state.lock().unwrap() = State::default();
+  }
+
+  fn is_enabled(&self) -> bool {
+    self.config.load().is_some()
+  }
+
+  /// Adds `connections` and `bytes` to the counts of the client `ip`.
+  pub fn record(&self, ip: IpAddr, connections: u64, bytes: u64) {
+    let config = self.config.load();
+    let config = match &**config {
+      Some(config) => config,
+      None => return,
+    };
+    let mut state = self.state.lock().unwrap();
+    let index = slot_index(&state, config);
+    // Remove and reinsert to keep the client, which was inactive the longest, first
+    let mut slots = state.clients.remove(&ip).unwrap_or_default();
+    remove_expired_slots(&mut slots, index, config);
+    match slots.back_mut() {
+      Some(slot) if slot.index == index => {
+        slot.connections += connections;
+        slot.bytes += bytes;
+      }
+      _ => slots.push_back(Slot {
+        index,
+        connections,
+        bytes,
+      }),
+    }
+    state.clients.insert(ip, slots);
+    while state.clients.len() > config.max_clients {
+      state.clients.pop_front();
+    }
+  }
+
+  /// The `limit` clients with the most connections or bytes during the window.
+  pub fn top(&self, ranking: Ranking, limit: usize) -> Vec<Talker> {
+    let config = self.config.load();
+    let config = match &**config {
+      Some(config) => config,
+      None => return Vec::new(),
+    };
+    let mut state = self.state.lock().unwrap();
+    let index = slot_index(&state, config);
+    let mut talkers = Vec::new();
+    let mut inactive = Vec::new();
+    for (ip, slots) in state.clients.iter_mut() {
+      remove_expired_slots(slots, index, config);
+      if slots.is_empty() {
+        inactive.push(*ip);
+        continue;
+      }
+      talkers.push(Talker {
+        ip: *ip,
+        connections: slots.iter().map(|it| it.connections).sum(),
+        bytes: slots.iter().map(|it| it.bytes).sum(),
+      });
+    }
+    for ip in inactive {
+      state.clients.remove(&ip);
+    }
+    talkers.sort_by_key(|it| {
+      std::cmp::Reverse(match ranking {
+        Ranking::Connections => it.connections,
+        Ranking::Bytes => it.bytes,
+      })
+    });
+    talkers.truncate(limit);
+    talkers
+  }
+
+  /// Counts the connection of the client `ip` and the bytes sent and
+  /// received via `stream`.
+  pub fn observe<IO>(&'static self, stream: IO, ip: IpAddr) -> Talking<IO> {
+    let enabled = self.is_enabled();
+    if enabled {
+      self.record(ip, 1, 0);
+    }
+    Talking {
+      inner: stream,
+      talkers: self,
+      ip,
+      enabled,
+      bytes: 0,
+      flushed_at: Instant::now(),
+    }
+  }
+}
+
+fn slot_index(state: &State, config: &TopTalkersConfig) -> u64 {
+  (state.started_at.elapsed().as_nanos() / config.slot_duration().as_nanos().max(1)) as u64
+}
+
+fn remove_expired_slots(slots: &mut VecDeque<Slot>, index: u64, config: &TopTalkersConfig) {
+  let oldest = (index + 1).saturating_sub(config.slots as u64);
+  while matches!(slots.front(), Some(slot) if slot.index < oldest) {
+    slots.pop_front();
+  }
+}
+
+/// A client connection, whose bytes are added to the [`TopTalkers`] once per
+/// [`FLUSH_INTERVAL`] and when it is closed.
+pub struct Talking<IO> {
+  inner: IO,
+  talkers: &'static TopTalkers,
+  ip: IpAddr,
+  /// Whether the top talkers were enabled, when the connection was accepted.
+  enabled: bool,
+  /// The bytes, which were not added yet.
+  bytes: u64,
+  flushed_at: Instant,
+}
+
+impl<IO> Talking<IO> {
+  fn count(&mut self, bytes: usize) {
+    if !self.enabled {
+      return;
+    }
+    self.bytes += bytes as u64;
+    if self.flushed_at.elapsed() >= FLUSH_INTERVAL {
+      self.flush();
+    }
+  }
+
+  fn flush(&mut self) {
+    if self.bytes > 0 {
+      self.talkers.record(self.ip, 0, self.bytes);
+      self.bytes = 0;
+    }
+    self.flushed_at = Instant::now();
+  }
+}
+
+impl<IO> Drop for Talking<IO> {
+  fn drop(&mut self) {
+    self.flush();
+  }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Talking<IO> {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let this = self.get_mut();
+    let filled = buf.filled().len();
+    let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+    this.count(buf.filled().len() - filled);
+    result
+  }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Talking<IO> {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, io::Error>> {
+    let this = self.get_mut();
+    let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+    if let Poll::Ready(Ok(written)) = result {
+      this.count(written);
+    }
+    result
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+  }
+}
+
+impl<IO: RemoteAddress> RemoteAddress for Talking<IO> {
+  fn remote_addr(&self) -> io::Result<SocketAddr> {
+    self.inner.remote_addr()
+  }
+
+  fn tls_info(&self) -> Option<TlsInfo> {
+    self.inner.tls_info()
+  }
+
+  fn connection(&self) -> Option<Arc<LiveConnection>> {
+    self.inner.connection()
+  }
+
+  fn peer_credentials(&self) -> Option<PeerCredentials> {
+    self.inner.peer_credentials()
+  }
+}
+
+/// Observes the connections of `inner` via [`TOP_TALKERS`].
+#[pin_project]
+pub struct TalkingAcceptor<I> {
+  #[pin]
+  inner: I,
+}
+
+impl<I> TalkingAcceptor<I> {
+  pub fn new(inner: I) -> TalkingAcceptor<I> {
+    TalkingAcceptor { inner }
+  }
+}
+
+impl<I> Accept for TalkingAcceptor<I>
+where
+  I: Accept,
+  I::Conn: RemoteAddress,
+{
+  type Conn = Talking<I::Conn>;
+  type Error = I::Error;
+
+  fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+    self.project().inner.poll_accept(cx).map(|connection| {
+      connection.map(|connection| {
+        connection.map(|it| {
+          let ip = it
+            .remote_addr()
+            .map_or(IpAddr::from([0, 0, 0, 0]), |address| address.ip());
+          TOP_TALKERS.observe(it, ip)
+        })
+      })
+    })
+  }
+}
+
+/// Handles `GET /top-talkers` (with the optional query parameters `by` being
+/// `connections` or `bytes` and `limit`) and `DELETE /top-talkers`.
+pub fn handle_request(request: &Request<Body>) -> Response<Body> {
+  match *request.method() {
+    Method::GET => {
+      let mut ranking = Ranking::Connections;
+      let mut limit = DEFAULT_LIMIT;
+      let query = request.uri().query().unwrap_or_default();
+      for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match (key.as_ref(), value.as_ref()) {
+          ("by", "connections") => ranking = Ranking::Connections,
+          ("by", "bytes") => ranking = Ranking::Bytes,
+          ("limit", value) if value.parse::<usize>().is_ok() => limit = value.parse().unwrap(),
+          _ => return status(StatusCode::BAD_REQUEST),
+        }
+      }
+      if !TOP_TALKERS.is_enabled() {
+        return status(StatusCode::NOT_FOUND);
+      }
+      let talkers = TOP_TALKERS
+        .top(ranking, limit)
+        .into_iter()
+        .map(|it| json!({"ip": it.ip.to_string(), "connections": it.connections, "bytes": it.bytes}))
+        .collect::<Vec<_>>();
+      Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "top_talkers": talkers }).to_string()))
+        .unwrap()
+    }
+    Method::DELETE => {
+      TOP_TALKERS.reset();
+      status(StatusCode::NO_CONTENT)
+    }
+    _ => status(StatusCode::METHOD_NOT_ALLOWED),
+  }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+  Response::builder().status(status).body(Body::empty()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn talkers(window: Duration, slots: u32, max_clients: usize) -> TopTalkers {
+    let talkers = TopTalkers::default();
+    talkers.configure(Some(TopTalkersConfig {
+      window,
+      slots,
+      max_clients,
+    }));
+    talkers
+  }
+
+  fn ip(last: u8) -> IpAddr {
+    IpAddr::from([10, 0, 0, last])
+  }
+
+  #[test]
+  fn test_clients_are_ranked_by_connections_or_bytes() {
+    // given:
+    let talkers = talkers(Duration::from_secs(60), 6, 100);
+
+    // when:
+    talkers.record(ip(1), 1, 5000);
+    talkers.record(ip(2), 3, 10);
+    talkers.record(ip(2), 0, 10);
+    talkers.record(ip(3), 2, 100);
+
+    // then:
+    let by_connections = talkers.top(Ranking::Connections, 2);
+    assert_eq!(
+      by_connections,
+      vec![
+        Talker {
+          ip: ip(2),
+          connections: 3,
+          bytes: 20
+        },
+        Talker {
+          ip: ip(3),
+          connections: 2,
+          bytes: 100
+        },
+      ]
+    );
+    assert_eq!(talkers.top(Ranking::Bytes, 1)[0].ip, ip(1));
+  }
+
+  #[test]
+  fn test_counts_expire_with_the_window() {
+    // given:
+    let talkers = talkers(Duration::from_millis(40), 2, 100);
+    talkers.record(ip(1), 1, 0);
+
+    // when:
+    std::thread::sleep(Duration::from_millis(60));
+    talkers.record(ip(2), 1, 0);
+
+    // then:
+    let top = talkers.top(Ranking::Connections, 10);
+    assert_eq!(top.iter().map(|it| it.ip).collect::<Vec<_>>(), vec![ip(2)]);
+  }
+
+  #[test]
+  fn test_the_longest_inactive_clients_are_forgotten() {
+    // given:
+    let talkers = talkers(Duration::from_secs(60), 6, 2);
+
+    // when:
+    talkers.record(ip(1), 5, 0);
+    talkers.record(ip(2), 1, 0);
+    talkers.record(ip(1), 1, 0);
+    talkers.record(ip(3), 1, 0);
+
+    // then:
+    let mut ips = talkers
+      .top(Ranking::Connections, 10)
+      .iter()
+      .map(|it| it.ip)
+      .collect::<Vec<_>>();
+    ips.sort();
+    assert_eq!(ips, vec![ip(1), ip(3)]);
+  }
+
+  #[test]
+  fn test_nothing_is_tracked_without_config() {
+    let talkers = TopTalkers::default();
+
+    talkers.record(ip(1), 1, 0);
+
+    assert!(talkers.top(Ranking::Connections, 10).is_empty());
+    assert!(talkers.state.lock().unwrap().clients.is_empty());
+  }
+}
@@ -0,0 +1,185 @@
+use crate::address_policy::IpRange;
+use hyper::{header::HeaderValue, Body, Request};
+use log::debug;
+use std::net::{IpAddr, SocketAddr};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// The address of the trusted proxy, which sent a request on behalf of the
+/// client named in its `X-Forwarded-For`. It is appended to the chain sent to
+/// the backend server instead of the address of the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ForwardedBy(pub SocketAddr);
+
+/// The proxies in front of the load balancer (like a CDN or a corporate load
+/// balancer), whose `X-Forwarded-For` names the actual client. The
+/// `X-Forwarded-For` of all other peers is dropped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrustedProxies {
+  ranges: Vec<IpRange>,
+}
+
+impl TrustedProxies {
+  pub fn new(ranges: Vec<IpRange>) -> TrustedProxies {
+    TrustedProxies { ranges }
+  }
+
+  pub fn trusts(&self, ip: IpAddr) -> bool {
+    self.ranges.iter().any(|it| it.contains(ip))
+  }
+
+  /// Determines the effective client address of a `request` from `peer`: the
+  /// rightmost entry of its `X-Forwarded-For`, which is not a trusted proxy
+  /// itself (or the leftmost, if all are). The `X-Forwarded-For` of untrusted
+  /// peers and malformed ones are dropped, so the peer is the client.
+  /// Several `X-Forwarded-For` headers are joined into one chain.
+  pub fn apply(&self, peer: SocketAddr, request: &mut Request<Body>) -> SocketAddr {
+    let headers = request.headers_mut();
+    if !headers.contains_key(X_FORWARDED_FOR) {
+      return peer;
+    }
+    if !self.trusts(peer.ip()) {
+      debug!("Dropped the X-Forwarded-For of {}, which is not a trusted proxy", peer);
+      headers.remove(X_FORWARDED_FOR);
+      return peer;
+    }
+    let values = headers.get_all(X_FORWARDED_FOR).iter().collect::<Vec<_>>();
+    let chain = match parse_chain(&values) {
+      Some(chain) => chain,
+      None => {
+        debug!("Dropped the malformed X-Forwarded-For {:?} of {}", values, peer);
+        headers.remove(X_FORWARDED_FOR);
+        return peer;
+      }
+    };
+    if values.len() > 1 {
+      let joined = values
+        .iter()
+        .map(|it| it.to_str().unwrap())
+        .collect::<Vec<_>>()
+        .join(", ");
+      headers.insert(X_FORWARDED_FOR, HeaderValue::from_str(&joined).unwrap());
+    }
+    request.extensions_mut().insert(ForwardedBy(peer));
+    let client = chain.iter().rev().find(|it| !self.trusts(it.ip()));
+    *client.unwrap_or(&chain[0])
+  }
+}
+
+/// The addresses of an `X-Forwarded-For` chain, from the client to the last
+/// proxy, or `None` if any of them is not an IP address (optionally with a
+/// port). Addresses without a port get port 0.
+fn parse_chain(values: &[&HeaderValue]) -> Option<Vec<SocketAddr>> {
+  let mut chain = Vec::new();
+  for value in values {
+    for entry in value.to_str().ok()?.split(',') {
+      let entry = entry.trim();
+      let address = match entry.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 0),
+        Err(_) => entry.parse().ok()?,
+      };
+      chain.push(address);
+    }
+  }
+  Some(chain)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PEER: &str = "10.0.0.1:3000";
+
+  fn cdn() -> TrustedProxies {
+    TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()])
+  }
+
+  fn request(forwarded_for: &[&str]) -> Request<Body> {
+    forwarded_for
+      .iter()
+      .fold(Request::builder(), |builder, it| builder.header(X_FORWARDED_FOR, *it))
+      .body(Body::empty())
+      .unwrap()
+  }
+
+  fn forwarded_for(request: &Request<Body>) -> Vec<&str> {
+    request
+      .headers()
+      .get_all(X_FORWARDED_FOR)
+      .iter()
+      .map(|it| it.to_str().unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn test_trusted_cdn_hop() {
+    // given:
+    let mut request = request(&["203.0.113.7"]);
+
+    // when:
+    let client = cdn().apply(PEER.parse().unwrap(), &mut request);
+
+    // then: the chain is kept, so the CDN is appended to it
+    assert_eq!(client, "203.0.113.7:0".parse().unwrap());
+    assert_eq!(forwarded_for(&request), vec!["203.0.113.7"]);
+    assert_eq!(
+      request.extensions().get::<ForwardedBy>(),
+      Some(&ForwardedBy(PEER.parse().unwrap()))
+    );
+  }
+
+  #[test]
+  fn test_untrusted_client_spoofing_forwarded_for() {
+    // given: a client, which connects directly and pretends to be another one
+    let mut request = request(&["10.0.0.2"]);
+
+    // when:
+    let client = cdn().apply("198.51.100.1:3000".parse().unwrap(), &mut request);
+
+    // then:
+    assert_eq!(client, "198.51.100.1:3000".parse().unwrap());
+    assert!(forwarded_for(&request).is_empty());
+    assert_eq!(request.extensions().get::<ForwardedBy>(), None);
+  }
+
+  #[test]
+  fn test_multi_hop_chains() {
+    let trusted = cdn();
+    let peer = PEER.parse().unwrap();
+
+    // The client spoofs an address in front of its own, which the CDN appended
+    let mut spoofed = request(&["192.0.2.1, 203.0.113.7, 10.1.0.1"]);
+    assert_eq!(trusted.apply(peer, &mut spoofed), "203.0.113.7:0".parse().unwrap());
+
+    // Several headers form one chain
+    let mut split = request(&["203.0.113.7", "[2001:db8::1]:8080, 10.1.0.1"]);
+    assert_eq!(trusted.apply(peer, &mut split), "203.0.113.7:0".parse().unwrap());
+    assert_eq!(forwarded_for(&split), vec!["203.0.113.7, [2001:db8::1]:8080, 10.1.0.1"]);
+
+    // Only proxies are known, so the first one sent the request
+    let mut internal = request(&["10.2.0.1, 10.1.0.1"]);
+    assert_eq!(trusted.apply(peer, &mut internal), "10.2.0.1:0".parse().unwrap());
+
+    let mut with_port = request(&["203.0.113.7:4711"]);
+    assert_eq!(trusted.apply(peer, &mut with_port), "203.0.113.7:4711".parse().unwrap());
+  }
+
+  #[test]
+  fn test_malformed_forwarded_for_falls_back_to_the_peer() {
+    let peer = PEER.parse().unwrap();
+    for malformed in &["unknown", "203.0.113.7,", "203.0.113.7 10.1.0.1", ""] {
+      let mut request = request(&[malformed]);
+
+      assert_eq!(cdn().apply(peer, &mut request), peer, "{}", malformed);
+      assert!(forwarded_for(&request).is_empty(), "{}", malformed);
+    }
+  }
+
+  #[test]
+  fn test_requests_without_forwarded_for() {
+    let mut request = request(&[]);
+
+    assert_eq!(cdn().apply(PEER.parse().unwrap(), &mut request), PEER.parse().unwrap());
+    assert_eq!(request.extensions().get::<ForwardedBy>(), None);
+  }
+}
@@ -0,0 +1,456 @@
+use crate::{
+  http_client::backend_uri,
+  load_balancing::{self, LoadBalancingStrategy},
+  metrics::METRICS,
+};
+use hyper::{http::uri::PathAndQuery, Body, Request, Uri};
+use log::{debug, error, info};
+use std::{
+  collections::HashMap,
+  io,
+  net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+use tokio::{
+  net::{lookup_host, UdpSocket},
+  time::timeout,
+};
+
+/// The maximum payload of a UDP datagram.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// A UDP listener whose datagrams are relayed to a set of backend servers. All
+/// datagrams of a client (identified by its address) are relayed to the same
+/// backend server until the session of the client is idle for `idle_timeout`.
+#[derive(Debug)]
+pub struct UdpService {
+  pub listen_address: SocketAddr,
+  pub addresses: Vec<String>,
+  pub strategy: Box<dyn LoadBalancingStrategy>,
+  pub idle_timeout: Duration,
+  pub max_sessions: usize,
+}
+
+/// The counters of a [`UdpProxy`], which are exported as metrics labeled
+/// with the `service` (its bound address).
+#[derive(Debug)]
+pub struct UdpStats {
+  service: String,
+}
+
+impl UdpStats {
+  fn received(&self) {
+    METRICS.increment("arlb_udp_datagrams_received_total", &[("service", &self.service)]);
+  }
+
+  fn sent(&self) {
+    METRICS.increment("arlb_udp_datagrams_sent_total", &[("service", &self.service)]);
+  }
+
+  fn dropped(&self, datagrams: usize) {
+    METRICS.add(
+      "arlb_udp_datagrams_dropped_total",
+      &[("service", &self.service)],
+      datagrams as u64,
+    );
+  }
+
+  fn opened_session(&self) {
+    METRICS.add_gauge("arlb_udp_sessions", &[("service", &self.service)], 1);
+  }
+
+  fn evicted_session(&self) {
+    METRICS.add_gauge("arlb_udp_sessions", &[("service", &self.service)], -1);
+    METRICS.increment("arlb_udp_session_evictions_total", &[("service", &self.service)]);
+  }
+}
+
+#[derive(Debug)]
+struct Session {
+  upstream: UdpSocket,
+  backend_uri: Uri,
+  last_activity: Mutex<Instant>,
+}
+
+impl Session {
+  fn touch(&self) {
+    *self.last_activity.lock().unwrap() = Instant::now();
+  }
+
+  fn idle_time(&self) -> Duration {
+    self.last_activity.lock().unwrap().elapsed()
+  }
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Arc<Session>>>>;
+
+/// The datagrams of clients, whose session is still being created.
+type Pending = Arc<Mutex<HashMap<SocketAddr, Vec<Vec<u8>>>>>;
+
+/// How many datagrams of a client are buffered, while its session is created.
+const MAX_PENDING_DATAGRAMS: usize = 16;
+
+#[derive(Clone)]
+pub struct UdpProxy {
+  socket: Arc<UdpSocket>,
+  service: Arc<UdpService>,
+  sessions: Sessions,
+  pending: Pending,
+  stats: Arc<UdpStats>,
+}
+
+impl UdpProxy {
+  pub async fn bind(service: Arc<UdpService>) -> io::Result<UdpProxy> {
+    let socket = UdpSocket::bind(service.listen_address).await?;
+    let local_address = socket.local_addr()?;
+    info!("Started listening for UDP datagrams on {}", local_address);
+    Ok(UdpProxy {
+      socket: Arc::new(socket),
+      service,
+      sessions: Default::default(),
+      pending: Default::default(),
+      stats: Arc::new(UdpStats {
+        service: local_address.to_string(),
+      }),
+    })
+  }
+
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.socket.local_addr()
+  }
+
+  /// Receives datagrams from clients and relays them to the backend server of
+  /// the client's session. Sessions are created and their responses relayed by
+  /// a separate task per session, which also evicts the session once it is
+  /// idle, so resolving the backend server of a new client does not delay the
+  /// datagrams of other clients.
+  pub async fn run(self) -> io::Result<()> {
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+      let (length, client_address) = match self.socket.recv_from(&mut buffer).await {
+        Ok(received) => received,
+        Err(e) => {
+          // Errors of a single datagram (like ICMP port unreachable) must not stop the listener
+          error!("Could not receive UDP datagram: {}", e);
+          continue;
+        }
+      };
+      self.stats.received();
+
+      let session = self.sessions.lock().unwrap().get(&client_address).cloned();
+      match session {
+        Some(session) => {
+          session.touch();
+          if let Err(e) = session.upstream.send(&buffer[..length]).await {
+            debug!("Could not relay UDP datagram to {}: {}", session.backend_uri, e);
+          }
+        }
+        None => self.start_session(client_address, &buffer[..length]),
+      }
+    }
+  }
+
+  /// Buffers the `datagram` of a client without a session and starts to create
+  /// its session, unless that already happens. Drops the `datagram` if the
+  /// maximum number of sessions is reached.
+  fn start_session(&self, client_address: SocketAddr, datagram: &[u8]) {
+    let mut pending = self.pending.lock().unwrap();
+    if let Some(datagrams) = pending.get_mut(&client_address) {
+      if datagrams.len() < MAX_PENDING_DATAGRAMS {
+        datagrams.push(datagram.to_vec());
+      } else {
+        self.stats.dropped(1);
+      }
+      return;
+    }
+    let sessions = self.sessions.lock().unwrap();
+    if let Some(session) = sessions.get(&client_address) {
+      // The session was created since the datagram was received
+      if let Err(e) = session.upstream.try_send(datagram) {
+        debug!("Could not relay UDP datagram to {}: {}", session.backend_uri, e);
+      }
+      return;
+    }
+    if sessions.len() + pending.len() >= self.service.max_sessions {
+      debug!(
+        "Dropped UDP datagram of {}, because the maximum of {} sessions is reached",
+        client_address, self.service.max_sessions
+      );
+      self.stats.dropped(1);
+      return;
+    }
+    pending.insert(client_address, vec![datagram.to_vec()]);
+    let backend_address = self.select_backend(&client_address);
+    tokio::spawn(self.clone().serve_session(client_address, backend_address));
+  }
+
+  /// Creates the session of the client, relays its buffered datagrams and then
+  /// the responses of the backend server until the session is idle.
+  async fn serve_session(self, client_address: SocketAddr, backend_address: String) {
+    let session = match connect_session(&backend_address).await {
+      Ok(session) => Arc::new(session),
+      Err(e) => {
+        error!("Could not create UDP session for {}: {}", client_address, e);
+        let datagrams = self.pending.lock().unwrap().remove(&client_address).unwrap_or_default();
+        self.stats.dropped(datagrams.len());
+        return;
+      }
+    };
+    let datagrams = {
+      let mut pending = self.pending.lock().unwrap();
+      self.sessions.lock().unwrap().insert(client_address, session.clone());
+      pending.remove(&client_address).unwrap_or_default()
+    };
+    debug!("Created UDP session for {} to {}", client_address, backend_address);
+    self.service.strategy.on_tcp_open(&session.backend_uri);
+    self.stats.opened_session();
+    for datagram in datagrams {
+      if let Err(e) = session.upstream.send(&datagram).await {
+        debug!("Could not relay UDP datagram to {}: {}", session.backend_uri, e);
+      }
+    }
+    relay_responses(
+      self.socket,
+      client_address,
+      session,
+      self.service,
+      self.sessions,
+      self.stats,
+    )
+    .await;
+  }
+
+  fn select_backend(&self, client_address: &SocketAddr) -> String {
+    let backend_addresses = self.service.addresses.iter().map(String::as_str).collect::<Vec<_>>();
+    let context = load_balancing::Context {
+      client_address,
+      backend_addresses: &backend_addresses,
+    };
+    // Strategies are designed for HTTP, so they get an empty request to select a backend server
+    let request = Request::new(Body::empty());
+    let forwarder = self.service.strategy.select_backend(&request, &context);
+    forwarder.backend_address().to_string()
+  }
+}
+
+async fn connect_session(backend_address: &str) -> io::Result<Session> {
+  let backend_uri = backend_uri(backend_address, PathAndQuery::from_static("/")).map_err(invalid_input)?;
+  Ok(Session {
+    upstream: connect_upstream(backend_address).await?,
+    backend_uri,
+    last_activity: Mutex::new(Instant::now()),
+  })
+}
+
+async fn connect_upstream(backend_address: &str) -> io::Result<UdpSocket> {
+  let backend_address = lookup_host(backend_address)
+    .await?
+    .next()
+    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Could not resolve backend address"))?;
+  let local_address: SocketAddr = match backend_address {
+    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+  };
+  let upstream = UdpSocket::bind(local_address).await?;
+  upstream.connect(backend_address).await?;
+  Ok(upstream)
+}
+
+async fn relay_responses(
+  socket: Arc<UdpSocket>,
+  client_address: SocketAddr,
+  session: Arc<Session>,
+  service: Arc<UdpService>,
+  sessions: Sessions,
+  stats: Arc<UdpStats>,
+) {
+  let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+  loop {
+    let remaining = match service.idle_timeout.checked_sub(session.idle_time()) {
+      Some(remaining) if remaining > Duration::from_secs(0) => remaining,
+      _ => break,
+    };
+    match timeout(remaining, session.upstream.recv(&mut buffer)).await {
+      Ok(Ok(length)) => {
+        session.touch();
+        match socket.send_to(&buffer[..length], client_address).await {
+          Ok(_) => stats.sent(),
+          Err(e) => {
+            debug!("Could not relay UDP datagram to {}: {}", client_address, e);
+            stats.dropped(1)
+          }
+        };
+      }
+      Ok(Err(e)) => {
+        debug!("Could not receive UDP datagram from {}: {}", session.backend_uri, e);
+        break;
+      }
+      // Check again, the client might have sent datagrams in the meantime
+      Err(_) => continue,
+    }
+  }
+
+  sessions.lock().unwrap().remove(&client_address);
+  service.strategy.on_tcp_close(&session.backend_uri);
+  stats.evicted_session();
+  debug!("Evicted UDP session of {}", client_address);
+}
+
+fn invalid_input<E: std::error::Error + Send + Sync + 'static>(error: E) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::load_balancing::round_robin::RoundRobin;
+
+  async fn start_echo_backend() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+      let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+      loop {
+        let (length, peer) = socket.recv_from(&mut buffer).await.unwrap();
+        socket.send_to(&buffer[..length], peer).await.unwrap();
+      }
+    });
+    address
+  }
+
+  async fn start_proxy(backend: SocketAddr, max_sessions: usize) -> (SocketAddr, Sessions) {
+    start_proxy_of(vec![backend.to_string()], max_sessions).await
+  }
+
+  async fn start_proxy_of(addresses: Vec<String>, max_sessions: usize) -> (SocketAddr, Sessions) {
+    let service = UdpService {
+      listen_address: "127.0.0.1:0".parse().unwrap(),
+      addresses,
+      strategy: Box::new(RoundRobin::new()),
+      idle_timeout: Duration::from_millis(200),
+      max_sessions,
+    };
+    let proxy = UdpProxy::bind(Arc::new(service)).await.unwrap();
+    let address = proxy.local_addr().unwrap();
+    let sessions = proxy.sessions.clone();
+    tokio::spawn(proxy.run());
+    (address, sessions)
+  }
+
+  fn counter(proxy: SocketAddr, name: &'static str) -> u64 {
+    METRICS.counter(name, &[("service", &proxy.to_string())])
+  }
+
+  #[tokio::test]
+  async fn test_round_trip_and_session_expiry() {
+    // given:
+    let backend = start_echo_backend().await;
+    let (proxy, sessions) = start_proxy(backend, 16).await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(proxy).await.unwrap();
+
+    // when:
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    for size in &[12, 512, 1232] {
+      let datagram = (0..*size).map(|it| it as u8).collect::<Vec<u8>>();
+      client.send(&datagram).await.unwrap();
+      let length = timeout(Duration::from_secs(1), client.recv(&mut buffer))
+        .await
+        .unwrap()
+        .unwrap();
+
+      // then:
+      assert_eq!(&buffer[..length], datagram.as_slice());
+    }
+    assert_eq!(sessions.lock().unwrap().len(), 1);
+
+    tokio::time::sleep(Duration::from_millis(400)).await;
+
+    assert!(sessions.lock().unwrap().is_empty());
+    assert_eq!(counter(proxy, "arlb_udp_session_evictions_total"), 1);
+    assert_eq!(counter(proxy, "arlb_udp_datagrams_received_total"), 3);
+    assert_eq!(counter(proxy, "arlb_udp_datagrams_sent_total"), 3);
+    assert_eq!(
+      METRICS.gauge("arlb_udp_sessions", &[("service", &proxy.to_string())]),
+      0
+    );
+  }
+
+  #[tokio::test]
+  async fn test_datagrams_are_relayed_once_the_session_is_created() {
+    // given:
+    let backend = start_echo_backend().await;
+    let (proxy, _) = start_proxy(backend, 16).await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(proxy).await.unwrap();
+
+    // when: the datagrams arrive before the session of the client exists
+    for datagram in &[b"first", b"other", b"third"] {
+      client.send(*datagram).await.unwrap();
+    }
+
+    // then:
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    let mut received = Vec::new();
+    for _ in 0..3 {
+      let length = timeout(Duration::from_secs(1), client.recv(&mut buffer))
+        .await
+        .unwrap()
+        .unwrap();
+      received.push(buffer[..length].to_vec());
+    }
+    assert_eq!(received, vec![b"first".to_vec(), b"other".to_vec(), b"third".to_vec()]);
+  }
+
+  #[tokio::test]
+  async fn test_failed_session_does_not_affect_other_clients() {
+    // given: round robin assigns one of the clients to a backend server, which can not be resolved
+    let backend = start_echo_backend().await;
+    let addresses = vec!["unresolvable.invalid:53".to_string(), backend.to_string()];
+    let (proxy, sessions) = start_proxy_of(addresses, 16).await;
+    let client_1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    // when:
+    client_1.send_to(b"first", proxy).await.unwrap();
+    client_2.send_to(b"second", proxy).await.unwrap();
+    let (mut buffer_1, mut buffer_2) = (vec![0; MAX_DATAGRAM_SIZE], vec![0; MAX_DATAGRAM_SIZE]);
+    let received = timeout(Duration::from_secs(1), async {
+      tokio::select! {
+        length = client_1.recv(&mut buffer_1) => buffer_1[..length.unwrap()].to_vec(),
+        length = client_2.recv(&mut buffer_2) => buffer_2[..length.unwrap()].to_vec(),
+      }
+    })
+    .await
+    .unwrap();
+
+    // then:
+    assert!(received == b"first" || received == b"second", "{:?}", received);
+    assert_eq!(sessions.lock().unwrap().len(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_max_sessions() {
+    // given:
+    let backend = start_echo_backend().await;
+    let (proxy, sessions) = start_proxy(backend, 1).await;
+    let client_1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    // when:
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    client_1.send_to(b"first", proxy).await.unwrap();
+    timeout(Duration::from_secs(1), client_1.recv(&mut buffer))
+      .await
+      .unwrap()
+      .unwrap();
+    client_2.send_to(b"second", proxy).await.unwrap();
+    let result = timeout(Duration::from_millis(100), client_2.recv(&mut buffer)).await;
+
+    // then:
+    assert!(result.is_err());
+    assert_eq!(sessions.lock().unwrap().len(), 1);
+    assert_eq!(counter(proxy, "arlb_udp_datagrams_dropped_total"), 1);
+  }
+}
@@ -0,0 +1,239 @@
+use crate::{
+  events::{CloseReason, Event, EventSubscriber},
+  metrics::METRICS,
+};
+use chrono::Utc;
+use hyper::{
+  client::HttpConnector,
+  header::{HeaderValue, CONTENT_TYPE},
+  Body, Client, Request, Uri,
+};
+use hyper_rustls::HttpsConnector;
+use log::debug;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Posts connection lifecycle events as JSON to an HTTP endpoint, like an
+/// external dashboard or audit system.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventWebhookConfig {
+  pub url: Uri,
+  /// How many events wait for delivery at most. Further events are dropped.
+  pub queue_length: usize,
+  /// How long a single delivery may take.
+  pub timeout: Duration,
+}
+
+/// Delivers the events of `subscriber` to the webhook one after another. The
+/// listeners never wait for the webhook: if it falls behind the events are
+/// dropped and counted instead.
+pub async fn deliver(mut subscriber: EventSubscriber, config: EventWebhookConfig) {
+  let (sender, mut receiver) = mpsc::channel(config.queue_length);
+  let enqueue = async move {
+    loop {
+      let event = subscriber.recv().await;
+      enqueue(&sender, &event);
+    }
+  };
+  let client = Client::builder().build::<_, Body>(HttpsConnector::with_native_roots());
+  let post = async {
+    while let Some(event) = receiver.recv().await {
+      post(&client, &config, event).await;
+    }
+  };
+  futures::join!(enqueue, post);
+}
+
+/// Queues the JSON of `event`, if it is delivered to webhooks.
+fn enqueue(sender: &mpsc::Sender<Value>, event: &Event) {
+  let json = match to_json(event) {
+    Some(json) => json,
+    None => return,
+  };
+  if let Err(TrySendError::Full(_)) = sender.try_send(json) {
+    METRICS.increment("arlb_event_webhook_events_total", &[("result", "dropped")]);
+  }
+}
+
+async fn post(client: &Client<HttpsConnector<HttpConnector>>, config: &EventWebhookConfig, event: Value) {
+  let mut request = Request::post(config.url.clone())
+    .body(Body::from(event.to_string()))
+    .unwrap();
+  request
+    .headers_mut()
+    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+  let result = match tokio::time::timeout(config.timeout, client.request(request)).await {
+    Ok(Ok(response)) if response.status().is_success() => "delivered",
+    Ok(Ok(response)) => {
+      debug!("The event webhook responded with {}", response.status());
+      "failed"
+    }
+    Ok(Err(e)) => {
+      debug!("Could not deliver an event to the webhook due to: {}", e);
+      "failed"
+    }
+    Err(_) => {
+      debug!("The event webhook did not respond within {:?}", config.timeout);
+      "failed"
+    }
+  };
+  METRICS.increment("arlb_event_webhook_events_total", &[("result", result)]);
+}
+
+/// The JSON of connection accepts, backend selections and connection closes.
+fn to_json(event: &Event) -> Option<Value> {
+  let timestamp = Utc::now().to_rfc3339();
+  match event {
+    Event::ConnectionAccepted {
+      connection,
+      listener,
+      peer,
+    } => Some(json!({
+      "event": "connection_accepted",
+      "timestamp": timestamp,
+      "connection": connection,
+      "listener": listener.to_string(),
+      "peer": peer.to_string(),
+    })),
+    Event::BackendSelected {
+      connection,
+      pool,
+      backend,
+      strategy,
+    } => Some(json!({
+      "event": "backend_selected",
+      "timestamp": timestamp,
+      "connection": connection,
+      "pool": pool,
+      "backend": backend,
+      "strategy": strategy,
+    })),
+    Event::ConnectionClosed {
+      connection,
+      reason,
+      stats,
+    } => Some(json!({
+      "event": "connection_closed",
+      "timestamp": timestamp,
+      "connection": connection,
+      "reason": match reason {
+        CloseReason::Closed => "closed",
+        CloseReason::Drained => "drained",
+      },
+      "requests": stats.requests,
+      "duration_ms": stats.duration.as_millis() as u64,
+    })),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    events::{ConnectionStats, EVENTS},
+    server::Scheme,
+  };
+  use futures::FutureExt;
+  use hyper::{
+    body::to_bytes,
+    service::{make_service_fn, service_fn},
+    Response, Server,
+  };
+  use std::{convert::Infallible, net::SocketAddr};
+
+  /// An id, which no real connection of other tests gets.
+  const CONNECTION: u64 = u64::MAX - 104;
+
+  fn accepted() -> Event {
+    Event::ConnectionAccepted {
+      connection: CONNECTION,
+      listener: Scheme::HTTPS,
+      peer: "127.0.0.1:4000".parse().unwrap(),
+    }
+  }
+
+  #[test]
+  fn test_events_are_dropped_when_the_queue_is_full() {
+    // given:
+    let (sender, mut receiver) = mpsc::channel(1);
+    let dropped = || METRICS.counter("arlb_event_webhook_events_total", &[("result", "dropped")]);
+    let dropped_before = dropped();
+
+    // when:
+    enqueue(&sender, &accepted());
+    enqueue(&sender, &accepted());
+
+    // then:
+    let first = receiver.recv().now_or_never().unwrap().unwrap();
+    assert_eq!(first["event"], "connection_accepted");
+    assert!(receiver.recv().now_or_never().is_none());
+    assert!(dropped() > dropped_before);
+  }
+
+  #[test]
+  fn test_json_of_closed_connections() {
+    // given:
+    let event = Event::ConnectionClosed {
+      connection: CONNECTION,
+      reason: CloseReason::Drained,
+      stats: ConnectionStats {
+        requests: 3,
+        duration: Duration::from_millis(1500),
+      },
+    };
+
+    // when:
+    let json = to_json(&event).unwrap();
+
+    // then:
+    assert_eq!(json["event"], "connection_closed");
+    assert_eq!(json["connection"], CONNECTION);
+    assert_eq!(json["reason"], "drained");
+    assert_eq!(json["requests"], 3);
+    assert_eq!(json["duration_ms"], 1500);
+  }
+
+  #[tokio::test]
+  async fn test_events_are_posted_to_the_webhook() {
+    // given:
+    let (received, mut bodies) = mpsc::unbounded_channel();
+    let make_service = make_service_fn(move |_| {
+      let received = received.clone();
+      async move {
+        Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+          let received = received.clone();
+          async move {
+            let body = to_bytes(request.into_body()).await.unwrap();
+            received.send(serde_json::from_slice::<Value>(&body).unwrap()).unwrap();
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+          }
+        }))
+      }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let url = format!("http://{}/events", server.local_addr()).parse().unwrap();
+    tokio::spawn(server);
+    let config = EventWebhookConfig {
+      url,
+      queue_length: 16,
+      timeout: Duration::from_secs(5),
+    };
+    tokio::spawn(deliver(EVENTS.subscribe(), config));
+
+    // when:
+    EVENTS.emit(accepted);
+
+    // then: events of other tests may arrive as well
+    loop {
+      let body = bodies.recv().await.unwrap();
+      if body["connection"] == CONNECTION {
+        assert_eq!(body["event"], "connection_accepted");
+        assert_eq!(body["listener"], "https");
+        assert_eq!(body["peer"], "127.0.0.1:4000");
+        break;
+      }
+    }
+  }
+}